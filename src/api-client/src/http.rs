@@ -0,0 +1,184 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The subset of HTTP/1.1 spoken by the Firecracker API server: one request per connection,
+//! with bodies framed by their `Content-Length`.
+
+use std::path::Path;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+use crate::{Error, Result};
+
+const HEAD_END: &[u8] = b"\r\n\r\n";
+// Upper bound of the status line and headers of a response.
+const MAX_HEAD_LEN: usize = 16 << 10;
+
+/// Status line and headers of a response.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ResponseHead {
+    pub status: u16,
+    pub content_length: usize,
+    // Length of the head, including the empty line ending it.
+    pub len: usize,
+}
+
+/// Returns the bytes of a `method` request to `path`, with a JSON `body`.
+pub(crate) fn encode_request(method: &str, path: &str, body: Option<&[u8]>) -> Vec<u8> {
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, path).into_bytes();
+    if let Some(body) = body {
+        request.extend_from_slice(
+            format!(
+                "Content-Type: application/json\r\nContent-Length: {}\r\n",
+                body.len()
+            )
+            .as_bytes(),
+        );
+    }
+    request.extend_from_slice(b"\r\n");
+    if let Some(body) = body {
+        request.extend_from_slice(body);
+    }
+    request
+}
+
+/// Parses the head of the response at the start of `buf`, or returns `None` if it is not
+/// complete yet.
+pub(crate) fn parse_response_head(buf: &[u8]) -> Result<Option<ResponseHead>> {
+    let head_len = match buf
+        .windows(HEAD_END.len())
+        .position(|window| window == HEAD_END)
+    {
+        Some(position) => position + HEAD_END.len(),
+        None if buf.len() > MAX_HEAD_LEN => return Err(Error::InvalidResponse("headers too long")),
+        None => return Ok(None),
+    };
+    let head = std::str::from_utf8(&buf[..head_len])
+        .map_err(|_| Error::InvalidResponse("headers are not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+
+    // The status line is `HTTP/1.1 <status> <reason>`.
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or(Error::InvalidResponse("bad status line"))?;
+
+    let mut content_length = 0;
+    for line in lines {
+        let mut header = line.splitn(2, ':');
+        let (name, value) = match (header.next(), header.next()) {
+            (Some(name), Some(value)) => (name, value.trim()),
+            _ => continue,
+        };
+        if name.eq_ignore_ascii_case("Content-Length") {
+            content_length = value
+                .parse::<usize>()
+                .map_err(|_| Error::InvalidResponse("bad Content-Length"))?;
+        }
+    }
+
+    Ok(Some(ResponseHead {
+        status,
+        content_length,
+        len: head_len,
+    }))
+}
+
+/// Sends a `method` request to `path` on the API socket at `socket_path`, and returns the status
+/// and the body of the response.
+pub(crate) async fn send(
+    socket_path: &Path,
+    method: &str,
+    path: &str,
+    body: Option<&[u8]>,
+) -> Result<(u16, Vec<u8>)> {
+    let mut stream = UnixStream::connect(socket_path)
+        .await
+        .map_err(Error::Connect)?;
+    stream
+        .write_all(&encode_request(method, path, body))
+        .await
+        .map_err(Error::Io)?;
+
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let head = loop {
+        let len = stream.read(&mut chunk).await.map_err(Error::Io)?;
+        if len == 0 {
+            return Err(Error::InvalidResponse(
+                "connection closed before the headers",
+            ));
+        }
+        buf.extend_from_slice(&chunk[..len]);
+        if let Some(head) = parse_response_head(&buf)? {
+            break head;
+        }
+    };
+
+    let body_end = head.len + head.content_length;
+    while buf.len() < body_end {
+        let len = stream.read(&mut chunk).await.map_err(Error::Io)?;
+        if len == 0 {
+            return Err(Error::InvalidResponse("connection closed before the body"));
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+    buf.truncate(body_end);
+    Ok((head.status, buf.split_off(head.len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_request() {
+        assert_eq!(
+            encode_request("GET", "/", None),
+            b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec()
+        );
+        assert_eq!(
+            encode_request("PATCH", "/vm", Some(br#"{"state":"Paused"}"#)),
+            b"PATCH /vm HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+              Content-Length: 18\r\n\r\n{\"state\":\"Paused\"}"
+                .to_vec()
+        );
+    }
+
+    #[test]
+    fn test_parse_response_head() {
+        let response = b"HTTP/1.1 400 \r\nServer: Firecracker API\r\ncontent-length: 2\r\n\r\n{}";
+        assert_eq!(
+            parse_response_head(response).unwrap(),
+            Some(ResponseHead {
+                status: 400,
+                content_length: 2,
+                len: response.len() - 2,
+            })
+        );
+
+        // Responses without a body have no Content-Length.
+        let response = b"HTTP/1.1 204 \r\nServer: Firecracker API\r\n\r\n";
+        assert_eq!(
+            parse_response_head(response)
+                .unwrap()
+                .unwrap()
+                .content_length,
+            0
+        );
+
+        // Incomplete heads are read further.
+        assert_eq!(parse_response_head(b"HTTP/1.1 200 \r\n").unwrap(), None);
+
+        match parse_response_head(b"HTTP/1.1 OK\r\n\r\n") {
+            Err(Error::InvalidResponse(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match parse_response_head(b"HTTP/1.1 200 \r\nContent-Length: x\r\n\r\n") {
+            Err(Error::InvalidResponse(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,338 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Typed async client of the Firecracker API.
+//!
+//! The requests and responses are the types the API server parses and serializes, so the
+//! client follows the server as it changes. Each call opens its own connection to the API
+//! socket, so a `Client` can be shared by concurrent tasks. The calls run on a tokio runtime
+//! with the IO driver enabled.
+#![deny(missing_docs)]
+
+mod http;
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+pub use api_server::{ActionBody, ActionType, FaultMessage};
+use vmm::page_cache::PrewarmStats;
+use vmm::vmm_config::boot_source::BootSourceConfig;
+use vmm::vmm_config::drive::BlockDeviceConfig;
+use vmm::vmm_config::instance_info::InstanceInfo;
+use vmm::vmm_config::logger::{LoggerConfig, LoggerUpdateConfig};
+use vmm::vmm_config::machine_config::VmConfig;
+use vmm::vmm_config::metrics::{MetricsConfig, MetricsUpdateConfig};
+use vmm::vmm_config::mmds::MmdsConfig;
+use vmm::vmm_config::net::{NetworkInterfaceConfig, NetworkInterfaceUpdateConfig};
+use vmm::vmm_config::snapshot::{
+    CreateSnapshotParams, LoadSnapshotParams, ScrubRangesConfig, Vm, VmState,
+};
+use vmm::vmm_config::vsock::VsockDeviceConfig;
+use vmm::ws_accounting::WsStats;
+
+/// Errors of the API calls.
+#[derive(Debug)]
+pub enum Error {
+    /// The API server rejected the request, with this status and fault message.
+    Api(u16, String),
+    /// Failed to connect to the API socket.
+    Connect(io::Error),
+    /// The response is not the HTTP the API server sends.
+    InvalidResponse(&'static str),
+    /// Failed to send the request or to receive the response.
+    Io(io::Error),
+    /// Failed to serialize the request body, or to deserialize the response body.
+    Json(serde_json::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Api(status, fault) => write!(f, "The API returned {}: {}", status, fault),
+            Connect(err) => write!(f, "Cannot connect to the API socket: {}", err),
+            InvalidResponse(err) => write!(f, "Invalid API response: {}", err),
+            Io(err) => write!(f, "Cannot talk to the API server: {}", err),
+            Json(err) => write!(f, "Invalid JSON body: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Result of the API calls.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Client of the API served on a Unix socket.
+#[derive(Clone, Debug)]
+pub struct Client {
+    socket_path: PathBuf,
+}
+
+impl Client {
+    /// Returns a client of the API served on `socket_path`.
+    pub fn new<P: Into<PathBuf>>(socket_path: P) -> Self {
+        Client {
+            socket_path: socket_path.into(),
+        }
+    }
+
+    /// Returns the path to the API socket.
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    /// Returns the general information about the microVM.
+    pub async fn describe_instance(&self) -> Result<InstanceInfo> {
+        self.get("/").await
+    }
+
+    /// Returns the microVM configuration.
+    pub async fn get_machine_config(&self) -> Result<VmConfig> {
+        self.get("/machine-config").await
+    }
+
+    /// Sets the microVM configuration, before boot.
+    pub async fn put_machine_config(&self, config: &VmConfig) -> Result<()> {
+        self.send("PUT", "/machine-config", Some(config)).await
+    }
+
+    /// Updates the microVM configuration, before boot.
+    pub async fn patch_machine_config(&self, config: &VmConfig) -> Result<()> {
+        self.send("PATCH", "/machine-config", Some(config)).await
+    }
+
+    /// Sets the kernel and the boot arguments, before boot.
+    pub async fn put_boot_source(&self, config: &BootSourceConfig) -> Result<()> {
+        self.send("PUT", "/boot-source", Some(config)).await
+    }
+
+    /// Adds or replaces a block device, before boot.
+    pub async fn put_drive(&self, config: &BlockDeviceConfig) -> Result<()> {
+        let path = format!("/drives/{}", config.drive_id);
+        self.send("PUT", &path, Some(config)).await
+    }
+
+    /// Replaces the host file backing the `drive_id` block device, after boot.
+    pub async fn patch_drive(&self, drive_id: &str, path_on_host: &str) -> Result<()> {
+        let body = serde_json::json!({
+            "drive_id": drive_id,
+            "path_on_host": path_on_host,
+        });
+        let path = format!("/drives/{}", drive_id);
+        self.send("PATCH", &path, Some(&body)).await
+    }
+
+    /// Adds or replaces a network interface, before boot.
+    pub async fn put_network_interface(&self, config: &NetworkInterfaceConfig) -> Result<()> {
+        let path = format!("/network-interfaces/{}", config.iface_id);
+        self.send("PUT", &path, Some(config)).await
+    }
+
+    /// Updates the rate limiters of a network interface, after boot.
+    pub async fn patch_network_interface(
+        &self,
+        config: &NetworkInterfaceUpdateConfig,
+    ) -> Result<()> {
+        let path = format!("/network-interfaces/{}", config.iface_id);
+        self.send("PATCH", &path, Some(config)).await
+    }
+
+    /// Sets the vsock device, before boot.
+    pub async fn put_vsock(&self, config: &VsockDeviceConfig) -> Result<()> {
+        self.send("PUT", "/vsock", Some(config)).await
+    }
+
+    /// Configures the logger.
+    pub async fn put_logger(&self, config: &LoggerConfig) -> Result<()> {
+        self.send("PUT", "/logger", Some(config)).await
+    }
+
+    /// Updates the logger configuration.
+    pub async fn patch_logger(&self, config: &LoggerUpdateConfig) -> Result<()> {
+        self.send("PATCH", "/logger", Some(config)).await
+    }
+
+    /// Configures the metrics.
+    pub async fn put_metrics(&self, config: &MetricsConfig) -> Result<()> {
+        self.send("PUT", "/metrics", Some(config)).await
+    }
+
+    /// Updates the metrics configuration.
+    pub async fn patch_metrics(&self, config: &MetricsUpdateConfig) -> Result<()> {
+        self.send("PATCH", "/metrics", Some(config)).await
+    }
+
+    /// Returns the metrics in the Prometheus text format.
+    pub async fn get_metrics(&self) -> Result<String> {
+        let body = self.request("GET", "/metrics", None).await?;
+        String::from_utf8(body).map_err(|_| Error::InvalidResponse("metrics are not UTF-8"))
+    }
+
+    /// Returns the contents of the MMDS.
+    pub async fn get_mmds(&self) -> Result<Value> {
+        self.get("/mmds").await
+    }
+
+    /// Replaces the contents of the MMDS.
+    pub async fn put_mmds(&self, data: &Value) -> Result<()> {
+        self.send("PUT", "/mmds", Some(data)).await
+    }
+
+    /// Merges `data` into the contents of the MMDS.
+    pub async fn patch_mmds(&self, data: &Value) -> Result<()> {
+        self.send("PATCH", "/mmds", Some(data)).await
+    }
+
+    /// Configures the MMDS network stack.
+    pub async fn put_mmds_config(&self, config: &MmdsConfig) -> Result<()> {
+        self.send("PUT", "/mmds/config", Some(config)).await
+    }
+
+    /// Runs an instance action, such as starting the microVM.
+    pub async fn put_action(&self, action_type: ActionType) -> Result<()> {
+        self.send("PUT", "/actions", Some(&ActionBody { action_type }))
+            .await
+    }
+
+    /// Pauses the microVM.
+    pub async fn pause(&self) -> Result<()> {
+        self.patch_vm_state(VmState::Paused).await
+    }
+
+    /// Resumes the microVM.
+    pub async fn resume(&self) -> Result<()> {
+        self.patch_vm_state(VmState::Resumed).await
+    }
+
+    /// Creates a snapshot of the paused microVM.
+    pub async fn create_snapshot(&self, params: &CreateSnapshotParams) -> Result<()> {
+        self.send("PUT", "/snapshot/create", Some(params)).await
+    }
+
+    /// Loads a snapshot, before boot. Returns the working set prefetch accounting, when
+    /// `ws_accounting` is set.
+    pub async fn load_snapshot(&self, params: &LoadSnapshotParams) -> Result<Option<WsStats>> {
+        let body = self.request("PUT", "/snapshot/load", Some(params)).await?;
+        Self::parse_optional(&body)
+    }
+
+    /// Cancels the working set load of the snapshot load in progress, from another task than
+    /// the one waiting for the load.
+    pub async fn cancel_ws_load(&self) -> Result<()> {
+        self.send::<()>("PUT", "/snapshot/cancel-ws-load", None).await
+    }
+
+    /// Reads the working set of a snapshot into the page cache, ahead of its load.
+    pub async fn prewarm_snapshot(&self, params: &LoadSnapshotParams) -> Result<PrewarmStats> {
+        let body = self
+            .request("PUT", "/snapshot/prewarm", Some(params))
+            .await?;
+        serde_json::from_slice(&body).map_err(Error::Json)
+    }
+
+    /// Replaces the guest memory ranges zeroed in the memory file of the snapshots.
+    pub async fn set_scrub_ranges(&self, config: &ScrubRangesConfig) -> Result<()> {
+        self.send("PUT", "/snapshot/scrub", Some(config)).await
+    }
+
+    async fn patch_vm_state(&self, state: VmState) -> Result<()> {
+        self.send("PATCH", "/vm", Some(&Vm { state })).await
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let body = self.request::<()>("GET", path, None).await?;
+        serde_json::from_slice(&body).map_err(Error::Json)
+    }
+
+    async fn send<B: Serialize>(&self, method: &str, path: &str, body: Option<&B>) -> Result<()> {
+        self.request(method, path, body).await.map(|_| ())
+    }
+
+    // Sends the request, and returns the body of a successful response.
+    async fn request<B: Serialize>(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&B>,
+    ) -> Result<Vec<u8>> {
+        let body = match body {
+            Some(body) => Some(serde_json::to_vec(body).map_err(Error::Json)?),
+            None => None,
+        };
+        let (status, response) =
+            http::send(&self.socket_path, method, path, body.as_deref()).await?;
+        if status >= 300 {
+            return Err(Self::api_error(status, &response));
+        }
+        Ok(response)
+    }
+
+    // Parses a body that is only sent for some requests.
+    fn parse_optional<T: DeserializeOwned>(body: &[u8]) -> Result<Option<T>> {
+        if body.is_empty() {
+            return Ok(None);
+        }
+        serde_json::from_slice(body).map(Some).map_err(Error::Json)
+    }
+
+    fn api_error(status: u16, body: &[u8]) -> Error {
+        let fault_message = match serde_json::from_slice::<FaultMessage>(body) {
+            Ok(fault) => fault.fault_message,
+            Err(_) => String::from_utf8_lossy(body).into_owned(),
+        };
+        Error::Api(status, fault_message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_display() {
+        let err = Error::Api(400, "The ID cannot be empty.".to_string());
+        assert_eq!(
+            err.to_string(),
+            "The API returned 400: The ID cannot be empty."
+        );
+
+        let err = Error::Connect(io::Error::from(io::ErrorKind::NotFound));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = Error::Json(serde_json::from_str::<Value>("{").unwrap_err());
+        let _ = format!("{}{:?}", err, err);
+
+        let err = Error::InvalidResponse("bad status line");
+        let _ = format!("{}{:?}", err, err);
+    }
+
+    #[test]
+    fn test_api_error() {
+        match Client::api_error(400, br#"{"fault_message":"Invalid request."}"#) {
+            Error::Api(400, fault) => assert_eq!(fault, "Invalid request."),
+            err => panic!("Unexpected error: {:?}", err),
+        }
+        // Bodies that are not fault messages are kept as they are.
+        match Client::api_error(500, b"oops") {
+            Error::Api(500, fault) => assert_eq!(fault, "oops"),
+            err => panic!("Unexpected error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_parse_optional() {
+        let stats: Option<Value> = Client::parse_optional(b"").unwrap();
+        assert!(stats.is_none());
+        let stats: Option<Value> = Client::parse_optional(br#"{"hit_ratio":0.5}"#).unwrap();
+        assert_eq!(stats.unwrap()["hit_ratio"], 0.5);
+    }
+}
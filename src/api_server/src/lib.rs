@@ -3,25 +3,40 @@
 mod parsed_request;
 mod request;
 
-use serde_json::json;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use std::path::PathBuf;
-use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use std::{fmt, io};
 
 use crate::parsed_request::ParsedRequest;
-use logger::{debug, error, info, update_metric_with_elapsed_time, Metric, METRICS};
+pub use crate::request::actions::{ActionBody, ActionType};
+use logger::{debug, error, info, update_metric_with_elapsed_time, warn, Metric, METRICS};
 pub use micro_http::{
-    Body, HttpServer, Method, Request, RequestError, Response, ServerError, ServerRequest,
-    ServerResponse, StatusCode, Version,
+    Body, HttpServer, MediaType, Method, Request, RequestError, Response, ServerError,
+    ServerRequest, ServerResponse, StatusCode, Version,
 };
 use mmds::data_store;
 use mmds::data_store::Mmds;
 use seccomp::{BpfProgram, SeccompFilter};
 use utils::eventfd::EventFd;
+use vmm::guest_agent::{self, Message};
+use vmm::otel::{SpanContext, OTEL};
 use vmm::rpc_interface::{VmmAction, VmmActionError, VmmData};
 use vmm::vmm_config::instance_info::InstanceInfo;
+use vmm::vmm_config::snapshot::GuestAgentConfig;
 #[cfg(target_arch = "x86_64")]
 use vmm::vmm_config::snapshot::SnapshotType;
+use vmm::ws_load_control::WS_LOAD;
+
+/// Body of the responses to the failed requests.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct FaultMessage {
+    /// Description of the failure.
+    pub fault_message: String,
+}
 
 /// Shorthand type for a request containing a boxed VmmAction.
 pub type ApiRequest = Box<VmmAction>;
@@ -53,6 +68,9 @@ impl fmt::Debug for Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+// Period of the polls for cancellations of the working set load, in milliseconds.
+const CANCEL_POLL_MS: u64 = 10;
+
 pub struct ApiServer {
     /// MMDS info directly accessible from the API thread.
     mmds_info: Arc<Mutex<Mmds>>,
@@ -65,6 +83,12 @@ pub struct ApiServer {
     /// FD on which we notify the VMM that we have sent at least one
     /// `VmmRequest`.
     to_vmm_fd: EventFd,
+    /// Guest agent told once the microVM next resumes.
+    resumed_agent: RefCell<Option<GuestAgentConfig>>,
+    /// HTTP server, polled for cancellations while a snapshot load is in progress.
+    server: RefCell<Option<HttpServer>>,
+    /// Requests received while a snapshot load was in progress, served once it is done.
+    deferred: RefCell<Vec<ServerRequest>>,
 }
 
 impl ApiServer {
@@ -81,9 +105,18 @@ impl ApiServer {
             api_request_sender,
             vmm_response_receiver,
             to_vmm_fd,
+            resumed_agent: RefCell::new(None),
+            server: RefCell::new(None),
+            deferred: RefCell::new(Vec::new()),
         })
     }
 
+    /// Sets the guest agent told once the microVM next resumes, such as the one of a snapshot
+    /// loaded at start.
+    pub fn set_resumed_agent(&mut self, config: GuestAgentConfig) {
+        self.resumed_agent.replace(Some(config));
+    }
+
     pub fn bind_and_run(
         &mut self,
         path: PathBuf,
@@ -91,11 +124,22 @@ impl ApiServer {
         start_time_cpu_us: Option<u64>,
         seccomp_filter: BpfProgram,
     ) -> Result<()> {
-        let mut server = HttpServer::new(path).unwrap_or_else(|e| {
+        let server = HttpServer::new(path).unwrap_or_else(|e| {
             error!("Error creating the HTTP server: {}", e);
             std::process::exit(i32::from(vmm::FC_EXIT_CODE_GENERIC_ERROR));
         });
+        self.run(server, start_time_us, start_time_cpu_us, seccomp_filter)
+    }
 
+    /// Serves the API on `server`, whose socket is already bound, such as one passed by systemd
+    /// socket activation.
+    pub fn run(
+        &mut self,
+        mut server: HttpServer,
+        start_time_us: Option<u64>,
+        start_time_cpu_us: Option<u64>,
+        seccomp_filter: BpfProgram,
+    ) -> Result<()> {
         if let Some(start_time) = start_time_us {
             let delta_us = utils::time::get_time_us(utils::time::ClockType::Monotonic) - start_time;
             METRICS
@@ -124,26 +168,28 @@ impl ApiServer {
         }
 
         server.start_server().expect("Cannot start HTTP server");
+        self.server.replace(Some(server));
         loop {
-            match server.requests() {
+            let requests = self
+                .server
+                .borrow_mut()
+                .as_mut()
+                .expect("The HTTP server is gone")
+                .requests();
+            match requests {
                 Ok(request_vec) => {
                     for server_request in request_vec {
-                        let request_processing_start_us =
-                            utils::time::get_time_us(utils::time::ClockType::Monotonic);
-                        server
-                            .respond(
-                                // Use `self.handle_request()` as the processing callback.
-                                server_request.process(|request| {
-                                    self.handle_request(request, request_processing_start_us)
-                                }),
-                            )
-                            .or_else(|e| {
-                                error!("API Server encountered an error on response: {}", e);
-                                Ok(())
-                            })?;
-                        let delta_us = utils::time::get_time_us(utils::time::ClockType::Monotonic)
-                            - request_processing_start_us;
-                        debug!("Total previous API call duration: {} us.", delta_us);
+                        self.serve_request(server_request);
+                    }
+                    // The requests received during a snapshot load, in order.
+                    loop {
+                        let deferred = self.deferred.replace(Vec::new());
+                        if deferred.is_empty() {
+                            break;
+                        }
+                        for server_request in deferred {
+                            self.serve_request(server_request);
+                        }
                     }
                 }
                 Err(e) => {
@@ -156,12 +202,32 @@ impl ApiServer {
         }
     }
 
+    fn serve_request(&self, server_request: ServerRequest) {
+        let request_processing_start_us =
+            utils::time::get_time_us(utils::time::ClockType::Monotonic);
+        // Use `self.handle_request()` as the processing callback.
+        let response = server_request
+            .process(|request| self.handle_request(request, request_processing_start_us));
+        if let Some(server) = self.server.borrow_mut().as_mut() {
+            if let Err(e) = server.respond(response) {
+                error!("API Server encountered an error on response: {}", e);
+            }
+        }
+        let delta_us = utils::time::get_time_us(utils::time::ClockType::Monotonic)
+            - request_processing_start_us;
+        debug!("Total previous API call duration: {} us.", delta_us);
+    }
+
     fn handle_request(&self, request: &Request, request_processing_start_us: u64) -> Response {
         match ParsedRequest::try_from_request(request) {
-            Ok(ParsedRequest::Sync(vmm_action)) => {
-                self.serve_vmm_action_request(vmm_action, request_processing_start_us)
-            }
+            Ok(ParsedRequest::Sync(vmm_action)) => self.serve_with_guest_agent(
+                vmm_action,
+                request_processing_start_us,
+                request.headers.trace_parent(),
+            ),
+            Ok(ParsedRequest::CancelWsLoad) => self.cancel_ws_load(),
             Ok(ParsedRequest::GetInstanceInfo) => self.get_instance_info(),
+            Ok(ParsedRequest::GetMetrics) => self.get_metrics(),
             Ok(ParsedRequest::GetMMDS) => self.get_mmds(),
             Ok(ParsedRequest::PatchMMDS(value)) => self.patch_mmds(value),
             Ok(ParsedRequest::PutMMDS(value)) => self.put_mmds(value),
@@ -172,10 +238,83 @@ impl ApiServer {
         }
     }
 
+    // Coordinates the snapshot requests with the guest agent: it prepares before the microVM
+    // pauses for a snapshot, and is told once the microVM resumes after it or after a load. A
+    // background snapshot is taken of the running microVM, which keeps running.
+    fn serve_with_guest_agent(
+        &self,
+        vmm_action: Box<VmmAction>,
+        request_processing_start_us: u64,
+        trace_parent: Option<&str>,
+    ) -> Response {
+        let (prepare, agent, resumes) = match *vmm_action {
+            #[cfg(target_arch = "x86_64")]
+            VmmAction::CreateSnapshot(ref params) => (
+                params.guest_agent.clone(),
+                params.guest_agent.clone(),
+                params.background,
+            ),
+            #[cfg(target_arch = "x86_64")]
+            VmmAction::LoadSnapshot(ref params) => {
+                (None, params.guest_agent.clone(), params.resume_vm)
+            }
+            VmmAction::Resume => (None, None, true),
+            _ => (None, None, false),
+        };
+        if let Some(config) = prepare {
+            if let Err(err) = guest_agent::send(&config, &Message::PrepareForSnapshot) {
+                let msg = format!("The guest agent did not prepare for the snapshot: {}", err);
+                if config.required {
+                    error!("{}", msg);
+                    return ApiServer::json_response(
+                        StatusCode::BadRequest,
+                        ApiServer::json_fault_message(msg),
+                    );
+                }
+                warn!("{}", msg);
+            }
+            if !resumes {
+                let response = self.serve_vmm_action_request(
+                    Box::new(VmmAction::Pause),
+                    request_processing_start_us,
+                    trace_parent,
+                );
+                if !Self::is_success(&response) {
+                    return response;
+                }
+            }
+        }
+
+        let response =
+            self.serve_vmm_action_request(vmm_action, request_processing_start_us, trace_parent);
+        if !Self::is_success(&response) {
+            return response;
+        }
+        if agent.is_some() {
+            self.resumed_agent.replace(agent);
+        }
+        if resumes {
+            if let Some(config) = self.resumed_agent.borrow_mut().take() {
+                // The microVM already runs, failures are only reported.
+                if let Err(err) =
+                    Message::resumed().and_then(|msg| guest_agent::send(&config, &msg))
+                {
+                    warn!("The guest agent did not handle the resume: {}", err);
+                }
+            }
+        }
+        response
+    }
+
+    fn is_success(response: &Response) -> bool {
+        matches!(response.status(), StatusCode::OK | StatusCode::NoContent)
+    }
+
     fn serve_vmm_action_request(
         &self,
         vmm_action: Box<VmmAction>,
         request_processing_start_us: u64,
+        trace_parent: Option<&str>,
     ) -> Response {
         let metric_with_action = match *vmm_action {
             #[cfg(target_arch = "x86_64")]
@@ -198,11 +337,21 @@ impl ApiServer {
             _ => None,
         };
 
+        let cancellable = match *vmm_action {
+            #[cfg(target_arch = "x86_64")]
+            VmmAction::LoadSnapshot(_) => true,
+            _ => false,
+        };
+
+        // Requests are served one at a time, the VMM thread spans parent to this request until
+        // it gets its response.
+        OTEL.set_remote_parent(trace_parent.and_then(SpanContext::from_trace_parent));
         self.api_request_sender
             .send(vmm_action)
             .expect("Failed to send VMM message");
         self.to_vmm_fd.write(1).expect("Cannot update send VMM fd");
-        let vmm_outcome = *(self.vmm_response_receiver.recv().expect("VMM disconnected"));
+        let vmm_outcome = *self.recv_vmm_response(cancellable);
+        OTEL.set_remote_parent(None);
         let response = ParsedRequest::convert_to_response(&vmm_outcome);
 
         if vmm_outcome.is_ok() {
@@ -215,6 +364,60 @@ impl ApiServer {
         response
     }
 
+    // Waits for the response of the VMM thread. While a `cancellable` request is served, the
+    // cancellations of the working set load are served as they come, and the other requests are
+    // served once it is done.
+    fn recv_vmm_response(&self, cancellable: bool) -> ApiResponse {
+        if !cancellable {
+            return self.vmm_response_receiver.recv().expect("VMM disconnected");
+        }
+        loop {
+            match self
+                .vmm_response_receiver
+                .recv_timeout(Duration::from_millis(CANCEL_POLL_MS))
+            {
+                Ok(response) => return response,
+                Err(RecvTimeoutError::Timeout) => self.poll_cancellations(),
+                Err(RecvTimeoutError::Disconnected) => panic!("VMM disconnected"),
+            }
+        }
+    }
+
+    fn poll_cancellations(&self) {
+        let requests = match self.server.borrow_mut().as_mut() {
+            Some(server) => server.try_requests(),
+            None => return,
+        };
+        let request_vec = match requests {
+            Ok(request_vec) => request_vec,
+            Err(e) => {
+                error!(
+                    "API Server error on polling incoming requests. Error: {}",
+                    e
+                );
+                return;
+            }
+        };
+        for server_request in request_vec {
+            let request = server_request.inner();
+            match (request.method(), request.uri().get_abs_path()) {
+                (Method::Put, "/snapshot/cancel-ws-load") => self.serve_request(server_request),
+                _ => self.deferred.borrow_mut().push(server_request),
+            }
+        }
+    }
+
+    fn cancel_ws_load(&self) -> Response {
+        if WS_LOAD.cancel() {
+            info!("Cancelled the working set load.");
+            return Response::new(Version::Http11, StatusCode::NoContent);
+        }
+        ApiServer::json_response(
+            StatusCode::BadRequest,
+            ApiServer::json_fault_message("No working set load is in progress."),
+        )
+    }
+
     fn get_instance_info(&self) -> Response {
         let shared_info_lock = self.vmm_shared_info.clone();
         // expect() to crash if the other thread poisoned this lock
@@ -234,6 +437,24 @@ impl ApiServer {
         }
     }
 
+    fn get_metrics(&self) -> Response {
+        match METRICS.prometheus() {
+            Ok(body) => {
+                let mut response = Response::new(Version::Http11, StatusCode::OK);
+                response.set_content_type(MediaType::PlainText);
+                response.set_body(Body::new(body));
+                response
+            }
+            Err(e) => {
+                METRICS.get_api_requests.metrics_fails.inc();
+                ApiServer::json_response(
+                    StatusCode::BadRequest,
+                    ApiServer::json_fault_message(e.to_string()),
+                )
+            }
+        }
+    }
+
     fn get_mmds(&self) -> Response {
         ApiServer::json_response(
             StatusCode::OK,
@@ -286,8 +507,12 @@ impl ApiServer {
         response
     }
 
-    fn json_fault_message<T: AsRef<str> + serde::Serialize>(msg: T) -> String {
-        json!({ "fault_message": msg }).to_string()
+    fn json_fault_message<T: AsRef<str>>(msg: T) -> String {
+        let fault = FaultMessage {
+            fault_message: msg.as_ref().to_string(),
+        };
+        // Safe to unwrap because a struct holding a string always serializes.
+        serde_json::to_string(&fault).unwrap()
     }
 }
 
@@ -367,14 +592,18 @@ mod tests {
                 StartMicrovmError::MicroVMAlreadyRunning,
             ))))
             .unwrap();
-        let response = api_server.serve_vmm_action_request(Box::new(VmmAction::StartMicroVm), 0);
+        let response =
+            api_server.serve_vmm_action_request(Box::new(VmmAction::StartMicroVm), 0, None);
         assert_eq!(response.status(), StatusCode::BadRequest);
 
         let start_time_us = utils::time::get_time_us(ClockType::Monotonic);
         assert_eq!(METRICS.latencies_us.pause_vm.count(), 0);
         to_api.send(Box::new(Ok(VmmData::Empty))).unwrap();
-        let response =
-            api_server.serve_vmm_action_request(Box::new(VmmAction::Pause), start_time_us);
+        let response = api_server.serve_vmm_action_request(
+            Box::new(VmmAction::Pause),
+            start_time_us,
+            Some("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+        );
         assert_eq!(response.status(), StatusCode::NoContent);
         assert_ne!(METRICS.latencies_us.pause_vm.count(), 0);
 
@@ -390,8 +619,12 @@ mod tests {
                     snapshot_path: PathBuf::new(),
                     mem_file_path: PathBuf::new(),
                     version: None,
+                    guest_agent: None,
+                    background: false,
+                    preallocate: false,
                 })),
                 start_time_us,
+                None,
             );
             assert_eq!(response.status(), StatusCode::BadRequest);
             // The metric should not be updated if the request wasn't successful.
@@ -404,8 +637,12 @@ mod tests {
                     snapshot_path: PathBuf::new(),
                     mem_file_path: PathBuf::new(),
                     version: None,
+                    guest_agent: None,
+                    background: false,
+                    preallocate: false,
                 })),
                 start_time_us,
+                None,
             );
             assert_eq!(response.status(), StatusCode::NoContent);
             assert_ne!(METRICS.latencies_us.diff_create_snapshot.count(), 0);
@@ -413,6 +650,99 @@ mod tests {
         }
     }
 
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_serve_with_guest_agent() {
+        let vmm_shared_info = Arc::new(RwLock::new(InstanceInfo {
+            started: true,
+            id: "test_serve_with_guest_agent".to_string(),
+            vmm_version: "version 0.1.0".to_string(),
+            app_name: "app name".to_string(),
+        }));
+        let to_vmm_fd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let (api_request_sender, from_api) = channel();
+        let (to_api, vmm_response_receiver) = channel();
+        let mut api_server = ApiServer::new(
+            MMDS.clone(),
+            vmm_shared_info,
+            api_request_sender,
+            vmm_response_receiver,
+            to_vmm_fd,
+        )
+        .unwrap();
+        // No agent listens on the vsock socket.
+        let agent = GuestAgentConfig {
+            uds_path: PathBuf::from("/no/such/v.sock"),
+            port: 52,
+            timeout_ms: 100,
+            required: true,
+        };
+        let create = |agent: GuestAgentConfig| {
+            Box::new(VmmAction::CreateSnapshot(CreateSnapshotParams {
+                snapshot_type: SnapshotType::Full,
+                snapshot_path: PathBuf::new(),
+                mem_file_path: PathBuf::new(),
+                version: None,
+                guest_agent: Some(agent),
+                background: false,
+                preallocate: false,
+            }))
+        };
+
+        // A required agent that does not prepare fails the snapshot, before the pause.
+        let response = api_server.serve_with_guest_agent(create(agent.clone()), 0, None);
+        assert_eq!(response.status(), StatusCode::BadRequest);
+        assert!(from_api.try_recv().is_err());
+
+        // Otherwise, the microVM is paused and the snapshot created.
+        let mut optional = agent.clone();
+        optional.required = false;
+        to_api.send(Box::new(Ok(VmmData::Empty))).unwrap();
+        to_api.send(Box::new(Ok(VmmData::Empty))).unwrap();
+        let response = api_server.serve_with_guest_agent(create(optional), 0, None);
+        assert_eq!(response.status(), StatusCode::NoContent);
+        assert!(*from_api.try_recv().unwrap() == VmmAction::Pause);
+        match *from_api.try_recv().unwrap() {
+            VmmAction::CreateSnapshot(_) => (),
+            _ => panic!("The snapshot was not created."),
+        }
+
+        // The agent is told on the next resume, which goes on even if it fails.
+        assert!(api_server.resumed_agent.borrow().is_some());
+        to_api.send(Box::new(Ok(VmmData::Empty))).unwrap();
+        let response = api_server.serve_with_guest_agent(Box::new(VmmAction::Resume), 0, None);
+        assert_eq!(response.status(), StatusCode::NoContent);
+        assert!(api_server.resumed_agent.borrow().is_none());
+
+        // A background snapshot is taken without pausing the microVM, which runs on, so the
+        // agent is told right away.
+        let mut background = agent.clone();
+        background.required = false;
+        to_api.send(Box::new(Ok(VmmData::Empty))).unwrap();
+        let response = api_server.serve_with_guest_agent(
+            Box::new(VmmAction::CreateSnapshot(CreateSnapshotParams {
+                snapshot_type: SnapshotType::Full,
+                snapshot_path: PathBuf::new(),
+                mem_file_path: PathBuf::new(),
+                version: None,
+                guest_agent: Some(background),
+                background: true,
+                preallocate: false,
+            })),
+            0,
+            None,
+        );
+        assert_eq!(response.status(), StatusCode::NoContent);
+        match *from_api.try_recv().unwrap() {
+            VmmAction::CreateSnapshot(_) => (),
+            _ => panic!("The microVM was paused."),
+        }
+        assert!(api_server.resumed_agent.borrow().is_none());
+
+        api_server.set_resumed_agent(agent);
+        assert!(api_server.resumed_agent.borrow().is_some());
+    }
+
     #[test]
     fn test_get_instance_info() {
         let vmm_shared_info = Arc::new(RwLock::new(InstanceInfo {
@@ -440,6 +770,73 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
     }
 
+    #[test]
+    fn test_get_metrics() {
+        let vmm_shared_info = Arc::new(RwLock::new(InstanceInfo {
+            started: false,
+            id: "test_get_metrics".to_string(),
+            vmm_version: "version 0.1.0".to_string(),
+            app_name: "app name".to_string(),
+        }));
+
+        let to_vmm_fd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let (api_request_sender, _from_api) = channel();
+        let (_to_api, vmm_response_receiver) = channel();
+        let mmds_info = MMDS.clone();
+
+        let api_server = ApiServer::new(
+            mmds_info,
+            vmm_shared_info,
+            api_request_sender,
+            vmm_response_receiver,
+            to_vmm_fd,
+        )
+        .unwrap();
+
+        let response = api_server.get_metrics();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut buf: Vec<u8> = Vec::new();
+        response.write_all(&mut buf).unwrap();
+        let response = String::from_utf8(buf).unwrap();
+        assert!(response.contains("Content-Type: text/plain"));
+        assert!(response.contains("firecracker_get_api_requests_metrics_count "));
+    }
+
+    #[test]
+    fn test_cancel_ws_load() {
+        let vmm_shared_info = Arc::new(RwLock::new(InstanceInfo {
+            started: false,
+            id: "test_cancel_ws_load".to_string(),
+            vmm_version: "version 0.1.0".to_string(),
+            app_name: "app name".to_string(),
+        }));
+
+        let to_vmm_fd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let (api_request_sender, _from_api) = channel();
+        let (_to_api, vmm_response_receiver) = channel();
+        let mmds_info = MMDS.clone();
+
+        let api_server = ApiServer::new(
+            mmds_info,
+            vmm_shared_info,
+            api_request_sender,
+            vmm_response_receiver,
+            to_vmm_fd,
+        )
+        .unwrap();
+
+        let response = api_server.cancel_ws_load();
+        assert_eq!(response.status(), StatusCode::BadRequest);
+
+        let _load = WS_LOAD.begin(None);
+        let response = api_server.cancel_ws_load();
+        assert_eq!(response.status(), StatusCode::NoContent);
+        assert_eq!(
+            WS_LOAD.stop_reason(),
+            Some(vmm::ws_load_control::StopReason::Cancelled)
+        );
+    }
+
     #[test]
     fn test_get_mmds() {
         let vmm_shared_info = Arc::new(RwLock::new(InstanceInfo {
@@ -6,18 +6,20 @@ use serde_json::Value;
 use super::VmmData;
 use crate::request::actions::parse_put_actions;
 use crate::request::boot_source::parse_put_boot_source;
+use crate::request::debug::parse_put_debug;
 use crate::request::drive::{parse_patch_drive, parse_put_drive};
 use crate::request::instance_info::parse_get_instance_info;
-use crate::request::logger::parse_put_logger;
+use crate::request::logger::{parse_patch_logger, parse_put_logger};
 use crate::request::machine_configuration::{
     parse_get_machine_config, parse_patch_machine_config, parse_put_machine_config,
 };
-use crate::request::metrics::parse_put_metrics;
+use crate::request::memory::parse_put_memory;
+use crate::request::metrics::{parse_get_metrics, parse_patch_metrics, parse_put_metrics};
 use crate::request::mmds::{parse_get_mmds, parse_patch_mmds, parse_put_mmds};
 use crate::request::net::{parse_patch_net, parse_put_net};
-use crate::request::snapshot::parse_patch_vm_state;
 #[cfg(target_arch = "x86_64")]
 use crate::request::snapshot::parse_put_snapshot;
+use crate::request::snapshot::{parse_patch_vm_state, parse_put_cancel_ws_load};
 use crate::request::vsock::parse_put_vsock;
 use crate::ApiServer;
 use micro_http::{Body, Method, Request, Response, StatusCode, Version};
@@ -26,7 +28,9 @@ use logger::{error, info};
 use vmm::rpc_interface::{VmmAction, VmmActionError};
 
 pub enum ParsedRequest {
+    CancelWsLoad,
     GetInstanceInfo,
+    GetMetrics,
     GetMMDS,
     PatchMMDS(Value),
     PutMMDS(Value),
@@ -51,13 +55,18 @@ impl ParsedRequest {
         match (request.method(), path, request.body.as_ref()) {
             (Method::Get, "", None) => parse_get_instance_info(),
             (Method::Get, "machine-config", None) => parse_get_machine_config(),
+            (Method::Get, "metrics", None) => parse_get_metrics(),
             (Method::Get, "mmds", None) => parse_get_mmds(),
             (Method::Get, _, Some(_)) => method_to_error(Method::Get),
             (Method::Put, "actions", Some(body)) => parse_put_actions(body),
             (Method::Put, "boot-source", Some(body)) => parse_put_boot_source(body),
+            (Method::Put, "debug", Some(body)) => {
+                parse_put_debug(body, path_tokens.get(1), request.headers.authorization())
+            }
             (Method::Put, "drives", Some(body)) => parse_put_drive(body, path_tokens.get(1)),
             (Method::Put, "logger", Some(body)) => parse_put_logger(body),
             (Method::Put, "machine-config", Some(body)) => parse_put_machine_config(body),
+            (Method::Put, "memory", Some(body)) => parse_put_memory(body, path_tokens.get(1)),
             (Method::Put, "metrics", Some(body)) => parse_put_metrics(body),
             (Method::Put, "mmds", Some(body)) => parse_put_mmds(body, path_tokens.get(1)),
             (Method::Put, "network-interfaces", Some(body)) => {
@@ -66,9 +75,14 @@ impl ParsedRequest {
             #[cfg(target_arch = "x86_64")]
             (Method::Put, "snapshot", Some(body)) => parse_put_snapshot(body, path_tokens.get(1)),
             (Method::Put, "vsock", Some(body)) => parse_put_vsock(body),
+            (Method::Put, "snapshot", None) if path_tokens.get(1) == Some(&"cancel-ws-load") => {
+                parse_put_cancel_ws_load()
+            }
             (Method::Put, _, None) => method_to_error(Method::Put),
             (Method::Patch, "drives", Some(body)) => parse_patch_drive(body, path_tokens.get(1)),
+            (Method::Patch, "logger", Some(body)) => parse_patch_logger(body),
             (Method::Patch, "machine-config", Some(body)) => parse_patch_machine_config(body),
+            (Method::Patch, "metrics", Some(body)) => parse_patch_metrics(body),
             (Method::Patch, "mmds", Some(body)) => parse_patch_mmds(body),
             (Method::Patch, "network-interfaces", Some(body)) => {
                 parse_patch_net(body, path_tokens.get(1))
@@ -90,12 +104,45 @@ impl ParsedRequest {
                     info!("The request was executed successfully. Status code: 204 No Content.");
                     Response::new(Version::Http11, StatusCode::NoContent)
                 }
+                VmmData::GuestMemory(content) => {
+                    info!("The request was executed successfully. Status code: 200 OK.");
+                    let mut response = Response::new(Version::Http11, StatusCode::OK);
+                    // Serializing plain numbers and a base64 string cannot fail.
+                    let body = serde_json::to_string(content).unwrap_or_default();
+                    response.set_body(Body::new(body));
+                    response
+                }
                 VmmData::MachineConfiguration(vm_config) => {
                     info!("The request was executed successfully. Status code: 200 OK.");
                     let mut response = Response::new(Version::Http11, StatusCode::OK);
                     response.set_body(Body::new(vm_config.to_string()));
                     response
                 }
+                VmmData::MemoryReclaim(reclaim_stats) => {
+                    info!("The request was executed successfully. Status code: 200 OK.");
+                    let mut response = Response::new(Version::Http11, StatusCode::OK);
+                    // Serializing plain numbers cannot fail.
+                    let body = serde_json::to_string(reclaim_stats).unwrap_or_default();
+                    response.set_body(Body::new(body));
+                    response
+                }
+                #[cfg(target_arch = "x86_64")]
+                VmmData::Prewarm(prewarm_stats) => {
+                    info!("The request was executed successfully. Status code: 200 OK.");
+                    let mut response = Response::new(Version::Http11, StatusCode::OK);
+                    // Serializing plain numbers cannot fail.
+                    let body = serde_json::to_string(prewarm_stats).unwrap_or_default();
+                    response.set_body(Body::new(body));
+                    response
+                }
+                VmmData::WsPrefetch(ws_stats) => {
+                    info!("The request was executed successfully. Status code: 200 OK.");
+                    let mut response = Response::new(Version::Http11, StatusCode::OK);
+                    // Serializing plain numbers cannot fail.
+                    let body = serde_json::to_string(ws_stats).unwrap_or_default();
+                    response.set_body(Body::new(body));
+                    response
+                }
             },
             Err(vmm_action_error) => {
                 error!(
@@ -243,6 +290,7 @@ pub(crate) mod tests {
     use vmm::builder::StartMicrovmError;
     use vmm::rpc_interface::VmmActionError;
     use vmm::vmm_config::machine_config::VmConfig;
+    use vmm::ws_accounting::WsStats;
 
     impl PartialEq for ParsedRequest {
         fn eq(&self, other: &ParsedRequest) -> bool {
@@ -250,7 +298,9 @@ pub(crate) mod tests {
                 (&ParsedRequest::Sync(ref sync_req), &ParsedRequest::Sync(ref other_sync_req)) => {
                     sync_req == other_sync_req
                 }
+                (&ParsedRequest::CancelWsLoad, &ParsedRequest::CancelWsLoad) => true,
                 (&ParsedRequest::GetInstanceInfo, &ParsedRequest::GetInstanceInfo) => true,
+                (&ParsedRequest::GetMetrics, &ParsedRequest::GetMetrics) => true,
                 (&ParsedRequest::GetMMDS, &ParsedRequest::GetMMDS) => true,
                 (&ParsedRequest::PutMMDS(ref val), &ParsedRequest::PutMMDS(ref other_val)) => {
                     val == other_val
@@ -494,6 +544,23 @@ pub(crate) mod tests {
         );
         assert_eq!(buf.into_inner(), expected_response.as_bytes());
 
+        // With the working set prefetch accounting.
+        let mut buf = Cursor::new(vec![0]);
+        let response =
+            ParsedRequest::convert_to_response(&Ok(VmmData::WsPrefetch(WsStats::default())));
+        assert!(response.write_all(&mut buf).is_ok());
+        let body = serde_json::to_string(&WsStats::default()).unwrap();
+        let expected_response = format!(
+            "HTTP/1.1 200 \r\n\
+             Server: Firecracker API\r\n\
+             Connection: keep-alive\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        assert_eq!(buf.into_inner(), expected_response.as_bytes());
+
         // Error.
         let error = VmmActionError::StartMicrovm(StartMicrovmError::MissingKernelConfig);
         let mut buf = Cursor::new(vec![0]);
@@ -535,6 +602,18 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_get_metrics() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req)
+            .unwrap()
+            .eq(&ParsedRequest::GetMetrics));
+    }
+
     #[test]
     fn test_try_from_get_mmds() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -582,6 +661,31 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_put_debug() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(
+                b"PUT /debug/memory HTTP/1.1\r\n\
+                Authorization: Bearer wrong\r\n\
+                Content-Type: application/json\r\n\
+                Content-Length: 29\r\n\r\n{ \
+                \"guest_addr\": 0, \
+                \"len\": 8 \
+                }",
+            )
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert_eq!(req.headers.authorization(), Some("Bearer wrong"));
+        // No test of this crate sets the token of `MEMORY_DEBUG`, so the reads are disabled.
+        match ParsedRequest::try_from_request(&req) {
+            Err(Error::Generic(StatusCode::NotFound, _)) => {}
+            _ => panic!("Test failed."),
+        }
+    }
+
     #[test]
     fn test_try_from_put_drives() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -657,6 +761,24 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_put_memory() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(
+                b"PUT /memory/reclaim HTTP/1.1\r\n\
+                Content-Type: application/json\r\n\
+                Content-Length: 20\r\n\r\n{ \
+                \"advice\": \"Cold\" \
+                }",
+            )
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_put_metrics() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -784,6 +906,30 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_put_cancel_ws_load() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+
+        sender
+            .write_all(b"PUT /snapshot/cancel-ws-load HTTP/1.1\r\n\r\n")
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        match ParsedRequest::try_from_request(&req) {
+            Ok(ParsedRequest::CancelWsLoad) => {}
+            _ => panic!("Test failed."),
+        }
+
+        // The other snapshot operations still need a body.
+        sender
+            .write_all(b"PUT /snapshot/load HTTP/1.1\r\n\r\n")
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
     #[test]
     fn test_try_from_patch_vm() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -842,6 +988,23 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_patch_logger() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(
+                b"PATCH /logger HTTP/1.1\r\n\
+                Content-Type: application/json\r\n\
+                Content-Length: 50\r\n\r\n\
+                { \"level\": \"Debug\", \"debug_categories\": [\"uffd\"] }",
+            )
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_patch_machine_config() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -863,6 +1026,23 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_patch_metrics() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(
+                b"PATCH /metrics HTTP/1.1\r\n\
+                Content-Type: application/json\r\n\
+                Content-Length: 29\r\n\r\n\
+                { \"metrics_path\": \"metrics\" }",
+            )
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_patch_mmds() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
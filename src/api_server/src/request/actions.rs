@@ -10,22 +10,24 @@ use logger::{Metric, METRICS};
 
 use serde::{Deserialize, Serialize};
 
+/// Actions of the `PUT /actions` request.
 // The names of the members from this enum must precisely correspond (as a string) to the possible
 // values of "action_type" from the json request body. This is useful to get a strongly typed
 // struct from the Serde deserialization process.
 #[derive(Debug, Deserialize, Serialize)]
-enum ActionType {
+pub enum ActionType {
     FlushMetrics,
     InstanceStart,
     SendCtrlAltDel,
 }
 
+/// Body of the `PUT /actions` request.
 // The model of the json body from a sync request. We use Serde to transform each associated
 // json body into this.
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
-struct ActionBody {
-    action_type: ActionType,
+pub struct ActionBody {
+    pub action_type: ActionType,
 }
 
 pub fn parse_put_actions(body: &Body) -> Result<ParsedRequest, Error> {
@@ -0,0 +1,107 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::super::VmmAction;
+use crate::parsed_request::{Error, ParsedRequest};
+use crate::request::{Body, Method, StatusCode};
+use vmm::memory_debug::{self, GuestMemoryReadParams, MemoryDebug, MEMORY_DEBUG};
+
+pub fn parse_put_debug(
+    body: &Body,
+    request_type_from_path: Option<&&str>,
+    authorization: Option<&str>,
+) -> Result<ParsedRequest, Error> {
+    parse_put_debug_with(&MEMORY_DEBUG, body, request_type_from_path, authorization)
+}
+
+// Parses a debug request authorized by `memory_debug`.
+fn parse_put_debug_with(
+    memory_debug: &MemoryDebug,
+    body: &Body,
+    request_type_from_path: Option<&&str>,
+    authorization: Option<&str>,
+) -> Result<ParsedRequest, Error> {
+    // The credentials are checked before anything else, not to tell the debug requests apart.
+    memory_debug
+        .authorize(authorization)
+        .map_err(authorization_to_error)?;
+    match request_type_from_path {
+        Some(&"memory") => Ok(ParsedRequest::new_sync(VmmAction::ReadGuestMemory(
+            serde_json::from_slice::<GuestMemoryReadParams>(body.raw())
+                .map_err(Error::SerdeJson)?,
+        ))),
+        Some(&request_type) => Err(Error::InvalidPathMethod(
+            format!("/debug/{}", request_type),
+            Method::Put,
+        )),
+        None => Err(Error::Generic(
+            StatusCode::BadRequest,
+            "Missing debug operation type.".to_string(),
+        )),
+    }
+}
+
+fn authorization_to_error(err: memory_debug::Error) -> Error {
+    match err {
+        memory_debug::Error::Disabled => Error::Generic(StatusCode::NotFound, err.to_string()),
+        _ => Error::Generic(StatusCode::Unauthorized, err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsed_request::tests::vmm_action_from_request;
+
+    #[test]
+    fn test_parse_put_debug() {
+        let body = r#"{
+                "guest_addr": 4096,
+                "len": 512
+              }"#;
+        // The token is set on a local instance, so that `MEMORY_DEBUG` stays disabled for the
+        // other tests of this crate.
+        let memory_debug = MemoryDebug::default();
+        let parse = |body: &str, request_type: Option<&&str>, authorization: Option<&str>| {
+            parse_put_debug_with(&memory_debug, &Body::new(body), request_type, authorization)
+        };
+
+        // The reads are disabled until a token is set.
+        match parse(body, Some(&"memory"), Some("Bearer secret")) {
+            Err(Error::Generic(StatusCode::NotFound, _)) => (),
+            _ => panic!("Test failed."),
+        }
+
+        let token = utils::tempfile::TempFile::new().unwrap();
+        std::fs::write(token.as_path(), "secret").unwrap();
+        memory_debug.init(token.as_path()).unwrap();
+
+        match parse(body, Some(&"memory"), None) {
+            Err(Error::Generic(StatusCode::Unauthorized, _)) => (),
+            _ => panic!("Test failed."),
+        }
+        match parse(body, Some(&"memory"), Some("Bearer other")) {
+            Err(Error::Generic(StatusCode::Unauthorized, _)) => (),
+            _ => panic!("Test failed."),
+        }
+
+        let expected_params = GuestMemoryReadParams {
+            guest_addr: 4096,
+            len: 512,
+        };
+        match vmm_action_from_request(parse(body, Some(&"memory"), Some("Bearer secret")).unwrap())
+        {
+            VmmAction::ReadGuestMemory(params) => assert_eq!(params, expected_params),
+            _ => panic!("Test failed."),
+        }
+
+        assert!(parse(body, Some(&"registers"), Some("Bearer secret")).is_err());
+        assert!(parse(body, None, Some("Bearer secret")).is_err());
+        let body = r#"{
+                "guest_addr": 4096,
+                "len": 512,
+                "dump": true
+              }"#;
+        assert!(parse(body, Some(&"memory"), Some("Bearer secret")).is_err());
+    }
+}
@@ -5,7 +5,7 @@ use super::super::VmmAction;
 use crate::parsed_request::{Error, ParsedRequest};
 use crate::request::Body;
 use logger::{Metric, METRICS};
-use vmm::vmm_config::logger::LoggerConfig;
+use vmm::vmm_config::logger::{LoggerConfig, LoggerUpdateConfig};
 
 pub fn parse_put_logger(body: &Body) -> Result<ParsedRequest, Error> {
     METRICS.put_api_requests.logger_count.inc();
@@ -17,12 +17,23 @@ pub fn parse_put_logger(body: &Body) -> Result<ParsedRequest, Error> {
     )))
 }
 
+pub fn parse_patch_logger(body: &Body) -> Result<ParsedRequest, Error> {
+    METRICS.patch_api_requests.logger_count.inc();
+    Ok(ParsedRequest::new_sync(VmmAction::UpdateLogger(
+        serde_json::from_slice::<LoggerUpdateConfig>(body.raw()).map_err(|e| {
+            METRICS.patch_api_requests.logger_fails.inc();
+            Error::SerdeJson(e)
+        })?,
+    )))
+}
+
 #[cfg(test)]
 mod tests {
     use std::path::PathBuf;
 
     use super::*;
     use crate::parsed_request::tests::vmm_action_from_request;
+    use logger::DebugCategory;
     use vmm::vmm_config::logger::LoggerLevel;
 
     #[test]
@@ -72,4 +83,45 @@ mod tests {
 
         assert!(parse_put_logger(&Body::new(invalid_body)).is_err());
     }
+
+    #[test]
+    fn test_parse_patch_logger_request() {
+        let body = r#"{
+                "level": "info",
+                "debug_categories": ["ws-loader", "overlay"]
+              }"#;
+
+        let expected_cfg = LoggerUpdateConfig {
+            level: Some(LoggerLevel::Info),
+            debug_categories: Some(vec![DebugCategory::WsLoader, DebugCategory::Overlay]),
+            ..Default::default()
+        };
+        match vmm_action_from_request(parse_patch_logger(&Body::new(body)).unwrap()) {
+            VmmAction::UpdateLogger(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        let body = r#"{
+                "log_path": "log"
+              }"#;
+
+        let expected_cfg = LoggerUpdateConfig {
+            log_path: Some(PathBuf::from("log")),
+            ..Default::default()
+        };
+        match vmm_action_from_request(parse_patch_logger(&Body::new(body)).unwrap()) {
+            VmmAction::UpdateLogger(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        let invalid_body = r#"{
+                "debug_categories": ["vcpu"]
+              }"#;
+        assert!(parse_patch_logger(&Body::new(invalid_body)).is_err());
+
+        let invalid_body = r#"{
+                "level": "verbose"
+              }"#;
+        assert!(parse_patch_logger(&Body::new(invalid_body)).is_err());
+    }
 }
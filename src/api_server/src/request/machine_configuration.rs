@@ -41,7 +41,10 @@ pub fn parse_patch_machine_config(body: &Body) -> Result<ParsedRequest, Error> {
     if vm_config.vcpu_count.is_none()
         && vm_config.mem_size_mib.is_none()
         && vm_config.cpu_template.is_none()
+        && vm_config.hyperv.is_none()
+        && vm_config.cpu_topology.is_none()
         && vm_config.ht_enabled.is_none()
+        && vm_config.nested_virt.is_none()
     {
         return method_to_error(Method::Patch);
     }
@@ -55,7 +58,7 @@ mod tests {
     use super::*;
     use crate::parsed_request::tests::vmm_action_from_request;
 
-    use vmm::vmm_config::machine_config::CpuFeaturesTemplate;
+    use vmm::vmm_config::machine_config::{CpuFeaturesTemplate, CpuTopology};
 
     #[test]
     fn test_parse_get_machine_config_request() {
@@ -71,7 +74,8 @@ mod tests {
                 "mem_size_mib": 1024,
                 "ht_enabled": true,
                 "cpu_template": "T2",
-                "track_dirty_pages": true
+                "track_dirty_pages": true,
+                "nested_virt": true
               }"#;
 
         let mut expected_config = VmConfig {
@@ -79,7 +83,10 @@ mod tests {
             mem_size_mib: Some(1024),
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            hyperv: None,
+            cpu_topology: None,
             track_dirty_pages: true,
+            nested_virt: Some(true),
         };
         match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
             VmmAction::SetVmConfiguration(config) => assert_eq!(config, expected_config),
@@ -96,7 +103,10 @@ mod tests {
             mem_size_mib: Some(1024),
             ht_enabled: Some(true),
             cpu_template: None,
+            hyperv: None,
+            cpu_topology: None,
             track_dirty_pages: false,
+            nested_virt: None,
         };
         match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
             VmmAction::SetVmConfiguration(config) => assert_eq!(config, expected_config),
@@ -128,5 +138,39 @@ mod tests {
                 "ht_enabled": false
               }"#;
         assert!(parse_patch_machine_config(&Body::new(body)).is_ok());
+
+        let body = r#"{
+                "nested_virt": true
+              }"#;
+        match vmm_action_from_request(parse_patch_machine_config(&Body::new(body)).unwrap()) {
+            VmmAction::SetVmConfiguration(config) => assert_eq!(config.nested_virt, Some(true)),
+            _ => panic!("Test failed."),
+        }
+
+        let body = r#"{
+                "cpu_topology": {
+                    "sockets": 2,
+                    "cores_per_socket": 2,
+                    "threads_per_core": 2
+                }
+              }"#;
+        let expected_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            hyperv: None,
+            cpu_topology: Some(CpuTopology {
+                sockets: 2,
+                cores_per_socket: 2,
+                threads_per_core: 2,
+            }),
+            track_dirty_pages: false,
+            nested_virt: None,
+        };
+        match vmm_action_from_request(parse_patch_machine_config(&Body::new(body)).unwrap()) {
+            VmmAction::SetVmConfiguration(config) => assert_eq!(config, expected_config),
+            _ => panic!("Test failed."),
+        }
     }
 }
@@ -0,0 +1,70 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::super::VmmAction;
+use crate::parsed_request::{Error, ParsedRequest};
+use crate::request::{Body, Method, StatusCode};
+use vmm::memory_reclaim::MemoryReclaimParams;
+
+pub fn parse_put_memory(
+    body: &Body,
+    request_type_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    match request_type_from_path {
+        Some(&"reclaim") => Ok(ParsedRequest::new_sync(VmmAction::ReclaimMemory(
+            serde_json::from_slice::<MemoryReclaimParams>(body.raw()).map_err(Error::SerdeJson)?,
+        ))),
+        Some(&request_type) => Err(Error::InvalidPathMethod(
+            format!("/memory/{}", request_type),
+            Method::Put,
+        )),
+        None => Err(Error::Generic(
+            StatusCode::BadRequest,
+            "Missing memory operation type.".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsed_request::tests::vmm_action_from_request;
+    use vmm::memory_reclaim::ReclaimAdvice;
+
+    #[test]
+    fn test_parse_put_memory() {
+        let body = r#"{
+                "advice": "PageOut"
+              }"#;
+        let expected_params = MemoryReclaimParams {
+            advice: ReclaimAdvice::PageOut,
+            include_ws: false,
+        };
+        match vmm_action_from_request(parse_put_memory(&Body::new(body), Some(&"reclaim")).unwrap())
+        {
+            VmmAction::ReclaimMemory(params) => assert_eq!(params, expected_params),
+            _ => panic!("Test failed."),
+        }
+
+        let body = r#"{
+                "advice": "DontNeed",
+                "include_ws": true
+              }"#;
+        let expected_params = MemoryReclaimParams {
+            advice: ReclaimAdvice::DontNeed,
+            include_ws: true,
+        };
+        match vmm_action_from_request(parse_put_memory(&Body::new(body), Some(&"reclaim")).unwrap())
+        {
+            VmmAction::ReclaimMemory(params) => assert_eq!(params, expected_params),
+            _ => panic!("Test failed."),
+        }
+
+        assert!(parse_put_memory(&Body::new(body), Some(&"compact")).is_err());
+        assert!(parse_put_memory(&Body::new(body), None).is_err());
+        let body = r#"{
+                "advice": "Free"
+              }"#;
+        assert!(parse_put_memory(&Body::new(body), Some(&"reclaim")).is_err());
+    }
+}
@@ -5,7 +5,12 @@ use super::super::VmmAction;
 use crate::parsed_request::{Error, ParsedRequest};
 use crate::request::Body;
 use logger::{Metric, METRICS};
-use vmm::vmm_config::metrics::MetricsConfig;
+use vmm::vmm_config::metrics::{MetricsConfig, MetricsUpdateConfig};
+
+pub fn parse_get_metrics() -> Result<ParsedRequest, Error> {
+    METRICS.get_api_requests.metrics_count.inc();
+    Ok(ParsedRequest::GetMetrics)
+}
 
 pub fn parse_put_metrics(body: &Body) -> Result<ParsedRequest, Error> {
     METRICS.put_api_requests.metrics_count.inc();
@@ -17,6 +22,16 @@ pub fn parse_put_metrics(body: &Body) -> Result<ParsedRequest, Error> {
     )))
 }
 
+pub fn parse_patch_metrics(body: &Body) -> Result<ParsedRequest, Error> {
+    METRICS.patch_api_requests.metrics_count.inc();
+    Ok(ParsedRequest::new_sync(VmmAction::UpdateMetrics(
+        serde_json::from_slice::<MetricsUpdateConfig>(body.raw()).map_err(|e| {
+            METRICS.patch_api_requests.metrics_fails.inc();
+            Error::SerdeJson(e)
+        })?,
+    )))
+}
+
 #[cfg(test)]
 mod tests {
     use std::path::PathBuf;
@@ -24,6 +39,14 @@ mod tests {
     use super::*;
     use crate::parsed_request::tests::vmm_action_from_request;
 
+    #[test]
+    fn test_parse_get_metrics_request() {
+        match parse_get_metrics() {
+            Ok(ParsedRequest::GetMetrics) => {}
+            _ => panic!("Test failed."),
+        }
+    }
+
     #[test]
     fn test_parse_put_metrics_request() {
         let body = r#"{
@@ -44,4 +67,26 @@ mod tests {
 
         assert!(parse_put_metrics(&Body::new(invalid_body)).is_err());
     }
+
+    #[test]
+    fn test_parse_patch_metrics_request() {
+        let body = r#"{
+                "metrics_path": "metrics"
+              }"#;
+
+        let expected_cfg = MetricsUpdateConfig {
+            metrics_path: PathBuf::from("metrics"),
+        };
+        match vmm_action_from_request(parse_patch_metrics(&Body::new(body)).unwrap()) {
+            VmmAction::UpdateMetrics(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        let invalid_body = r#"{
+                "metrics_path": "metrics",
+                "invalid_field": "metrics"
+              }"#;
+
+        assert!(parse_patch_metrics(&Body::new(invalid_body)).is_err());
+    }
 }
@@ -3,10 +3,12 @@
 
 pub mod actions;
 pub mod boot_source;
+pub mod debug;
 pub mod drive;
 pub mod instance_info;
 pub mod logger;
 pub mod machine_configuration;
+pub mod memory;
 pub mod metrics;
 pub mod mmds;
 pub mod net;
@@ -7,7 +7,7 @@ use crate::request::Body;
 #[cfg(target_arch = "x86_64")]
 use crate::request::{Method, StatusCode};
 #[cfg(target_arch = "x86_64")]
-use vmm::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams};
+use vmm::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams, ScrubRangesConfig};
 use vmm::vmm_config::snapshot::{Vm, VmState};
 
 #[cfg(target_arch = "x86_64")]
@@ -25,6 +25,14 @@ pub fn parse_put_snapshot(
                 serde_json::from_slice::<LoadSnapshotParams>(body.raw())
                     .map_err(Error::SerdeJson)?,
             ))),
+            "prewarm" => Ok(ParsedRequest::new_sync(VmmAction::PrewarmSnapshot(
+                serde_json::from_slice::<LoadSnapshotParams>(body.raw())
+                    .map_err(Error::SerdeJson)?,
+            ))),
+            "scrub" => Ok(ParsedRequest::new_sync(VmmAction::SetScrubRanges(
+                serde_json::from_slice::<ScrubRangesConfig>(body.raw())
+                    .map_err(Error::SerdeJson)?,
+            ))),
             _ => Err(Error::InvalidPathMethod(
                 format!("/snapshot/{}", request_type),
                 Method::Put,
@@ -37,6 +45,10 @@ pub fn parse_put_snapshot(
     }
 }
 
+pub fn parse_put_cancel_ws_load() -> Result<ParsedRequest, Error> {
+    Ok(ParsedRequest::CancelWsLoad)
+}
+
 pub fn parse_patch_vm_state(body: &Body) -> Result<ParsedRequest, Error> {
     let vm = serde_json::from_slice::<Vm>(body.raw()).map_err(Error::SerdeJson)?;
 
@@ -70,6 +82,9 @@ mod tests {
             snapshot_path: PathBuf::from("foo"),
             mem_file_path: PathBuf::from("bar"),
             version: Some(String::from("0.23.0")),
+            guest_agent: None,
+            background: false,
+            preallocate: false,
         };
 
         match vmm_action_from_request(
@@ -89,6 +104,9 @@ mod tests {
             snapshot_path: PathBuf::from("foo"),
             mem_file_path: PathBuf::from("bar"),
             version: None,
+            guest_agent: None,
+            background: false,
+            preallocate: false,
         };
 
         match vmm_action_from_request(
@@ -98,6 +116,22 @@ mod tests {
             _ => panic!("Test failed."),
         }
 
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "background": true,
+                "preallocate": true
+              }"#;
+        expected_cfg.background = true;
+        expected_cfg.preallocate = true;
+
+        match vmm_action_from_request(
+            parse_put_snapshot(&Body::new(body), Some(&"create")).unwrap(),
+        ) {
+            VmmAction::CreateSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
         let invalid_body = r#"{
                 "invalid_field": "foo",
                 "mem_file_path": "bar"
@@ -143,6 +177,121 @@ mod tests {
         assert!(parse_put_snapshot(&Body::new(body), None).is_err());
     }
 
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_parse_put_snapshot_mem_backend() {
+        use std::path::PathBuf;
+        use vmm::vmm_config::snapshot::{MemBackendError, MemBackendType};
+
+        // The body firecracker-containerd sends for a uffd backed load.
+        let body = r#"{
+                "snapshot_path": "foo",
+                "mem_backend": {
+                    "backend_type": "Uffd",
+                    "backend_path": "uffd.sock"
+                },
+                "enable_diff_snapshots": false,
+                "resume_vm": true
+              }"#;
+        let cfg = match vmm_action_from_request(
+            parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap(),
+        ) {
+            VmmAction::LoadSnapshot(cfg) => cfg,
+            _ => panic!("Test failed."),
+        };
+        assert!(cfg.resume_vm);
+        assert_eq!(
+            cfg.mem_backend.as_ref().unwrap().backend_type,
+            MemBackendType::Uffd
+        );
+        let resolved = cfg.resolve_mem_backend().unwrap();
+        assert!(resolved.enable_user_page_faults);
+        assert_eq!(resolved.sock_file_path, PathBuf::from("uffd.sock"));
+        assert!(resolved.mem_file_path.as_os_str().is_empty());
+
+        let body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "mem_backend": {
+                    "backend_type": "File",
+                    "backend_path": "baz"
+                }
+              }"#;
+        match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap())
+        {
+            VmmAction::LoadSnapshot(cfg) => assert_eq!(
+                cfg.resolve_mem_backend(),
+                Err(MemBackendError::Conflict("mem_file_path"))
+            ),
+            _ => panic!("Test failed."),
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_parse_put_snapshot_prewarm() {
+        let body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "ws_regions": [[0, 2]]
+              }"#;
+        match vmm_action_from_request(
+            parse_put_snapshot(&Body::new(body), Some(&"prewarm")).unwrap(),
+        ) {
+            VmmAction::PrewarmSnapshot(cfg) => assert_eq!(cfg.ws_regions, vec![vec![0, 2]]),
+            _ => panic!("Test failed."),
+        }
+
+        let invalid_body = r#"{
+                "snapshot_path": "foo",
+                "ws_file": "bar"
+              }"#;
+        assert!(parse_put_snapshot(&Body::new(invalid_body), Some(&"prewarm")).is_err());
+
+        // The ws_regions entries are [page, number of pages] pairs.
+        for ws_regions in ["[[2]]", "[[0, 1, 2]]", "[[-1, 2]]"].iter() {
+            let invalid_body = format!(
+                r#"{{ "snapshot_path": "foo", "ws_regions": {} }}"#,
+                ws_regions
+            );
+            assert!(parse_put_snapshot(&Body::new(invalid_body), Some(&"load")).is_err());
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_parse_put_snapshot_scrub() {
+        use vmm::vmm_config::snapshot::ScrubRange;
+
+        let body = r#"{
+                "ranges": [{ "guest_addr": 4096, "len": 64 }]
+              }"#;
+        let expected_cfg = ScrubRangesConfig {
+            ranges: vec![ScrubRange {
+                guest_addr: 4096,
+                len: 64,
+            }],
+        };
+        match vmm_action_from_request(parse_put_snapshot(&Body::new(body), Some(&"scrub")).unwrap())
+        {
+            VmmAction::SetScrubRanges(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        let invalid_body = r#"{
+                "ranges": [{ "guest_addr": 4096, "size": 64 }]
+              }"#;
+        assert!(parse_put_snapshot(&Body::new(invalid_body), Some(&"scrub")).is_err());
+    }
+
+    #[test]
+    fn test_parse_put_cancel_ws_load() {
+        match parse_put_cancel_ws_load() {
+            Ok(ParsedRequest::CancelWsLoad) => {}
+            _ => panic!("Test failed."),
+        }
+    }
+
     #[test]
     fn test_parse_patch_vm_state() {
         let mut body = r#"{
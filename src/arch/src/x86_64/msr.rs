@@ -49,6 +49,22 @@ const MSR_KVM_ASYNC_PF_EN: u32 = 0x4b56_4d02;
 const MSR_KVM_STEAL_TIME: u32 = 0x4b56_4d03;
 const MSR_KVM_PV_EOI_EN: u32 = 0x4b56_4d04;
 
+/// Hyper-V synthetic MSRs, taken from arch/x86/include/asm/hyperv-tlfs.h
+const HV_X64_MSR_GUEST_OS_ID: u32 = 0x4000_0000;
+const HV_X64_MSR_HYPERCALL: u32 = 0x4000_0001;
+const HV_X64_MSR_VP_INDEX: u32 = 0x4000_0002;
+const HV_X64_MSR_TIME_REF_COUNT: u32 = 0x4000_0020;
+const HV_X64_MSR_REFERENCE_TSC: u32 = 0x4000_0021;
+/// SynIC control MSRs: SCONTROL, SVERSION, SIEFP, SIMP and EOM.
+const HV_X64_MSR_SCONTROL: u32 = 0x4000_0080;
+const HV_X64_MSR_SYNIC_CTL_COUNT: u32 = 5;
+/// SynIC interrupt sources SINT0 to SINT15.
+const HV_X64_MSR_SINT0: u32 = 0x4000_0090;
+const HV_X64_MSR_SINT_COUNT: u32 = 16;
+/// Config and count MSRs of the four synthetic timers.
+const HV_X64_MSR_STIMER0_CONFIG: u32 = 0x4000_00b0;
+const HV_X64_MSR_STIMER_COUNT: u32 = 8;
+
 /// Taken from arch/x86/include/asm/msr-index.h
 const MSR_IA32_SPEC_CTRL: u32 = 0x0000_0048;
 const MSR_IA32_PRED_CMD: u32 = 0x0000_0049;
@@ -151,6 +167,14 @@ static WHITELISTED_MSR_RANGES: &[MsrRange] = &[
     SINGLE_MSR!(MSR_IA32_TSCDEADLINE),
     MSR_RANGE!(APIC_BASE_MSR, APIC_MSR_INDEXES),
     SINGLE_MSR!(MSR_IA32_BNDCFGS),
+    SINGLE_MSR!(HV_X64_MSR_GUEST_OS_ID),
+    SINGLE_MSR!(HV_X64_MSR_HYPERCALL),
+    SINGLE_MSR!(HV_X64_MSR_VP_INDEX),
+    SINGLE_MSR!(HV_X64_MSR_TIME_REF_COUNT),
+    SINGLE_MSR!(HV_X64_MSR_REFERENCE_TSC),
+    MSR_RANGE!(HV_X64_MSR_SCONTROL, HV_X64_MSR_SYNIC_CTL_COUNT),
+    MSR_RANGE!(HV_X64_MSR_SINT0, HV_X64_MSR_SINT_COUNT),
+    MSR_RANGE!(HV_X64_MSR_STIMER0_CONFIG, HV_X64_MSR_STIMER_COUNT),
     SINGLE_MSR!(MSR_KVM_WALL_CLOCK_NEW),
     SINGLE_MSR!(MSR_KVM_SYSTEM_TIME_NEW),
     SINGLE_MSR!(MSR_KVM_ASYNC_PF_EN),
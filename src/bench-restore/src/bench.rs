@@ -0,0 +1,460 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::json;
+use vmm::vmm_config::snapshot::LoadSnapshotParams;
+
+use crate::http::{self, ApiClient};
+
+// How long to wait for a spawned Firecracker to create its API socket.
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+const SOCKET_POLL: Duration = Duration::from_millis(1);
+
+#[derive(Debug)]
+pub enum Error {
+    Api(&'static str, http::Error),
+    DropCaches(io::Error),
+    InvalidMode(String),
+    MemFile(io::Error),
+    MissingWs,
+    SocketTimeout(PathBuf),
+    Spawn(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Api(step, err) => write!(f, "Failed to {}: {}", step, err),
+            DropCaches(err) => write!(f, "Failed to drop the page cache: {}", err),
+            InvalidMode(mode) => write!(f, "Invalid restore mode {}", mode),
+            MemFile(err) => write!(f, "Failed to get the memory file size: {}", err),
+            MissingWs => write!(f, "The ws mode requires --ws-file and --ws-regions"),
+            SocketTimeout(path) => write!(f, "No API socket at {}", path.display()),
+            Spawn(err) => write!(f, "Failed to spawn Firecracker: {}", err),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// How the guest memory is brought in on restore.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// The memory file is mapped and the pages fault in on access.
+    Lazy,
+    /// The whole memory file is loaded before the guest resumes.
+    Eager,
+    /// The working set is loaded before the guest resumes, the rest faults in on access.
+    Ws,
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        match mode {
+            "lazy" => Ok(Mode::Lazy),
+            "eager" => Ok(Mode::Eager),
+            "ws" => Ok(Mode::Ws),
+            _ => Err(Error::InvalidMode(mode.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mode::Lazy => write!(f, "lazy"),
+            Mode::Eager => write!(f, "eager"),
+            Mode::Ws => write!(f, "ws"),
+        }
+    }
+}
+
+/// Reference guest booted and snapshotted for the benchmark.
+pub struct GuestConfig {
+    pub kernel: PathBuf,
+    pub rootfs: PathBuf,
+    pub boot_args: String,
+    pub vcpus: u8,
+    pub mem_size_mib: usize,
+    /// Time the guest runs after boot before it is snapshotted.
+    pub settle: Duration,
+}
+
+/// Files of the reference snapshot.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotFiles {
+    pub snapshot_path: PathBuf,
+    pub mem_file_path: PathBuf,
+}
+
+/// Working set layer of the ws mode, as built by `ws-builder`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WsLayer {
+    pub file: PathBuf,
+    pub regions: Vec<Vec<i64>>,
+}
+
+/// Firecracker process, killed when dropped.
+pub struct Firecracker {
+    child: Child,
+    client: ApiClient,
+    socket_path: PathBuf,
+}
+
+impl Firecracker {
+    /// Spawns `binary` serving its API on `socket_path`, and waits for the socket.
+    pub fn spawn(binary: &Path, socket_path: &Path) -> Result<Self> {
+        let _ = fs::remove_file(socket_path);
+        let child = Command::new(binary)
+            .arg("--api-sock")
+            .arg(socket_path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(Error::Spawn)?;
+        let firecracker = Firecracker {
+            child,
+            client: ApiClient::new(socket_path),
+            socket_path: socket_path.to_path_buf(),
+        };
+
+        let start = Instant::now();
+        while !socket_path.exists() {
+            if start.elapsed() > SOCKET_TIMEOUT {
+                return Err(Error::SocketTimeout(socket_path.to_path_buf()));
+            }
+            thread::sleep(SOCKET_POLL);
+        }
+        Ok(firecracker)
+    }
+
+    fn request(&self, step: &'static str, method: &str, path: &str, body: &str) -> Result<()> {
+        self.client
+            .request(method, path, body)
+            .map(|_| ())
+            .map_err(|e| Error::Api(step, e))
+    }
+}
+
+impl Drop for Firecracker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_file(&self.socket_path);
+    }
+}
+
+/// Boots the reference guest, and snapshots it to `dir` once it settled.
+pub fn boot_and_snapshot(binary: &Path, dir: &Path, guest: &GuestConfig) -> Result<SnapshotFiles> {
+    let firecracker = Firecracker::spawn(binary, &dir.join("boot.sock"))?;
+    let files = SnapshotFiles {
+        snapshot_path: dir.join("snapshot_file"),
+        mem_file_path: dir.join("mem_file"),
+    };
+
+    firecracker.request(
+        "configure the kernel",
+        "PUT",
+        "/boot-source",
+        &json!({
+            "kernel_image_path": guest.kernel,
+            "boot_args": guest.boot_args,
+        })
+        .to_string(),
+    )?;
+    firecracker.request(
+        "configure the rootfs",
+        "PUT",
+        "/drives/rootfs",
+        &json!({
+            "drive_id": "rootfs",
+            "path_on_host": guest.rootfs,
+            "is_root_device": true,
+            "is_read_only": false,
+        })
+        .to_string(),
+    )?;
+    firecracker.request(
+        "configure the machine",
+        "PUT",
+        "/machine-config",
+        &json!({
+            "vcpu_count": guest.vcpus,
+            "mem_size_mib": guest.mem_size_mib,
+            "ht_enabled": false,
+        })
+        .to_string(),
+    )?;
+    firecracker.request(
+        "start the guest",
+        "PUT",
+        "/actions",
+        &json!({ "action_type": "InstanceStart" }).to_string(),
+    )?;
+    thread::sleep(guest.settle);
+    firecracker.request(
+        "pause the guest",
+        "PATCH",
+        "/vm",
+        &json!({ "state": "Paused" }).to_string(),
+    )?;
+    firecracker.request(
+        "create the snapshot",
+        "PUT",
+        "/snapshot/create",
+        &json!({
+            "snapshot_type": "Full",
+            "snapshot_path": files.snapshot_path,
+            "mem_file_path": files.mem_file_path,
+        })
+        .to_string(),
+    )?;
+    Ok(files)
+}
+
+/// Returns the snapshot load request restoring `snapshot` in `mode`.
+pub fn load_params(
+    snapshot: &SnapshotFiles,
+    mode: Mode,
+    fadvise: &str,
+    ws: Option<&WsLayer>,
+    page_size: u64,
+) -> Result<LoadSnapshotParams> {
+    let (load_ws, ws_file_path, ws_regions) = match mode {
+        Mode::Lazy => (false, PathBuf::new(), Vec::new()),
+        // Without a ws file, the working set is loaded from the memory file.
+        Mode::Eager => {
+            let size = fs::metadata(&snapshot.mem_file_path)
+                .map_err(Error::MemFile)?
+                .len();
+            (
+                true,
+                PathBuf::new(),
+                vec![vec![0, (size / page_size) as i64]],
+            )
+        }
+        Mode::Ws => {
+            let ws = ws.ok_or(Error::MissingWs)?;
+            (true, ws.file.clone(), ws.regions.clone())
+        }
+    };
+
+    Ok(LoadSnapshotParams {
+        snapshot_path: snapshot.snapshot_path.clone(),
+        mem_file_path: snapshot.mem_file_path.clone(),
+        mem_file_fd: None,
+        enable_diff_snapshots: false,
+        enable_user_page_faults: false,
+        sock_file_path: PathBuf::new(),
+        overlay_file_path: PathBuf::new(),
+        overlay_file_fd: None,
+        overlay_regions: Default::default(),
+        ws_file_path,
+        ws_file_fd: None,
+        ws_regions,
+        load_ws,
+        fadvise: fadvise.to_string(),
+        layer_fadvise: None,
+        fault_trace_path: None,
+        ws_accounting: false,
+        watchdog: None,
+        mem_size_mib: None,
+        mem_backend: None,
+        resume_vm: false,
+        netns_path: None,
+        netns_fd: None,
+        network_overrides: Vec::new(),
+        warm_notify: None,
+        prefetch_throttle: None,
+        prefetch: None,
+        prefetch_coordinator: None,
+        guest_agent: None,
+        ws_lock: None,
+        ws_populate: false,
+        ksm: false,
+        ws_staging: None,
+        ws_load_deadline: None,
+        strict_memory: false,
+        protect_base: false,
+        reject_layer_conflicts: false,
+        huge_pages: false,
+        numa_node: None,
+        profile_path: None,
+        profile: None,
+        fault_attribution: false,
+        idle_reclaim: None,
+        snapshot_cache: None,
+    })
+}
+
+/// Latencies of a restore.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    /// Latency of the snapshot load request.
+    pub load: Duration,
+    /// Latency of the resume request.
+    pub resume: Duration,
+}
+
+/// Restores a snapshot in a new Firecracker process serving its API on `socket_path`, with
+/// the `load_body` snapshot load request.
+pub fn restore(binary: &Path, socket_path: &Path, load_body: &str) -> Result<Sample> {
+    let firecracker = Firecracker::spawn(binary, socket_path)?;
+
+    let start = Instant::now();
+    firecracker.request("load the snapshot", "PUT", "/snapshot/load", load_body)?;
+    let load = start.elapsed();
+
+    let start = Instant::now();
+    firecracker.request(
+        "resume the guest",
+        "PATCH",
+        "/vm",
+        &json!({ "state": "Resumed" }).to_string(),
+    )?;
+    let resume = start.elapsed();
+
+    Ok(Sample { load, resume })
+}
+
+/// Drops the host page cache, so that the restores read the snapshot files from the storage.
+pub fn drop_caches() -> Result<()> {
+    // Safe because sync has no arguments and cannot fail.
+    unsafe { libc::sync() };
+    fs::write("/proc/sys/vm/drop_caches", "3").map_err(Error::DropCaches)
+}
+
+/// Distribution of latencies, in microseconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Distribution {
+    pub count: usize,
+    pub min: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+    pub mean: u64,
+}
+
+impl Distribution {
+    pub fn new(latencies: &[Duration]) -> Self {
+        let mut us: Vec<u64> = latencies.iter().map(|d| d.as_micros() as u64).collect();
+        if us.is_empty() {
+            return Distribution::default();
+        }
+        us.sort();
+        // Nearest rank percentile.
+        let percentile = |p: usize| us[(us.len() * p + 99) / 100 - 1];
+        Distribution {
+            count: us.len(),
+            min: us[0],
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: us[us.len() - 1],
+            mean: us.iter().sum::<u64>() / us.len() as u64,
+        }
+    }
+}
+
+impl fmt::Display for Distribution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "min {} p50 {} p90 {} p99 {} max {} mean {} us",
+            self.min, self.p50, self.p90, self.p99, self.max, self.mean
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_mode() {
+        for mode in &[Mode::Lazy, Mode::Eager, Mode::Ws] {
+            assert_eq!(mode.to_string().parse::<Mode>().unwrap(), *mode);
+        }
+        match "uffd".parse::<Mode>() {
+            Err(Error::InvalidMode(mode)) => assert_eq!(mode, "uffd"),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_load_params() {
+        let mem_file = TempFile::new().unwrap();
+        mem_file.as_file().set_len(0x5000).unwrap();
+        let snapshot = SnapshotFiles {
+            snapshot_path: PathBuf::from("snapshot_file"),
+            mem_file_path: mem_file.as_path().to_path_buf(),
+        };
+        let ws = WsLayer {
+            file: PathBuf::from("ws_file"),
+            regions: vec![vec![2, 1]],
+        };
+
+        let params = load_params(&snapshot, Mode::Lazy, "random", Some(&ws), 0x1000).unwrap();
+        assert!(!params.load_ws);
+        assert!(params.ws_regions.is_empty());
+        assert_eq!(params.fadvise, "random");
+
+        let params = load_params(&snapshot, Mode::Eager, "", None, 0x1000).unwrap();
+        assert!(params.load_ws);
+        assert!(params.ws_file_path.as_os_str().is_empty());
+        assert_eq!(params.ws_regions, vec![vec![0, 5]]);
+
+        let params = load_params(&snapshot, Mode::Ws, "", Some(&ws), 0x1000).unwrap();
+        assert!(params.load_ws);
+        assert_eq!(params.ws_file_path, ws.file);
+        assert_eq!(params.ws_regions, ws.regions);
+        match load_params(&snapshot, Mode::Ws, "", None, 0x1000) {
+            Err(Error::MissingWs) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_distribution() {
+        assert_eq!(Distribution::new(&[]), Distribution::default());
+
+        let latencies: Vec<Duration> = (1..=100).rev().map(Duration::from_micros).collect();
+        assert_eq!(
+            Distribution::new(&latencies),
+            Distribution {
+                count: 100,
+                min: 1,
+                p50: 50,
+                p90: 90,
+                p99: 99,
+                max: 100,
+                mean: 50,
+            }
+        );
+
+        let distribution = Distribution::new(&[Duration::from_millis(2)]);
+        assert_eq!(distribution.p99, 2000);
+        assert_eq!(
+            distribution.to_string(),
+            "min 2000 p50 2000 p90 2000 p99 2000 max 2000 mean 2000 us"
+        );
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    InvalidResponse(String),
+    Status(u16, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Io(err) => write!(f, "API socket error: {}", err),
+            InvalidResponse(line) => write!(f, "Invalid API response: {}", line),
+            Status(status, body) => write!(f, "API request failed with {}: {}", status, body),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Client of the Firecracker API, one connection per request.
+pub struct ApiClient {
+    socket_path: PathBuf,
+}
+
+impl ApiClient {
+    pub fn new(socket_path: &Path) -> Self {
+        ApiClient {
+            socket_path: socket_path.to_path_buf(),
+        }
+    }
+
+    /// Sends the `method` request of `path` with the JSON `body`, and returns the response body
+    /// if the request succeeded.
+    pub fn request(&self, method: &str, path: &str, body: &str) -> Result<String> {
+        let mut stream = UnixStream::connect(&self.socket_path).map_err(Error::Io)?;
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\n\
+             Host: localhost\r\n\
+             Accept: application/json\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )
+        .map_err(Error::Io)?;
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).map_err(Error::Io)?;
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| Error::InvalidResponse(line.trim_end().to_string()))?;
+
+        let mut content_length = 0;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).map_err(Error::Io)? == 0 {
+                return Err(Error::InvalidResponse("truncated headers".to_string()));
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let mut parts = header.splitn(2, ':');
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value
+                        .trim()
+                        .parse()
+                        .map_err(|_| Error::InvalidResponse(header.to_string()))?;
+                }
+            }
+        }
+
+        let mut body = vec![0u8; content_length];
+        reader.read_exact(&mut body).map_err(Error::Io)?;
+        let body = String::from_utf8_lossy(&body).into_owned();
+        if status / 100 != 2 {
+            return Err(Error::Status(status, body));
+        }
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use utils::tempdir::TempDir;
+
+    // Serves one request with `response`, and returns the request received.
+    fn serve(listener: UnixListener, response: &'static str) -> thread::JoinHandle<String> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![0u8; 1024];
+            let len = stream.read(&mut request).unwrap();
+            stream.write_all(response.as_bytes()).unwrap();
+            String::from_utf8_lossy(&request[..len]).into_owned()
+        })
+    }
+
+    #[test]
+    fn test_request() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.as_path().join("api.sock");
+        let client = ApiClient::new(&socket_path);
+
+        let server = serve(
+            UnixListener::bind(&socket_path).unwrap(),
+            "HTTP/1.1 204 \r\nServer: Firecracker API\r\n\r\n",
+        );
+        assert_eq!(client.request("PATCH", "/vm", "{}").unwrap(), "");
+        let request = server.join().unwrap();
+        assert!(request.starts_with("PATCH /vm HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Length: 2\r\n"));
+        assert!(request.ends_with("\r\n\r\n{}"));
+        std::fs::remove_file(&socket_path).unwrap();
+
+        let server = serve(
+            UnixListener::bind(&socket_path).unwrap(),
+            "HTTP/1.1 400 \r\nContent-Length: 17\r\n\r\n{\"fault_message\"}",
+        );
+        match client.request("PUT", "/snapshot/load", "{}") {
+            Err(Error::Status(400, body)) => assert_eq!(body, "{\"fault_message\"}"),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        server.join().unwrap();
+        std::fs::remove_file(&socket_path).unwrap();
+
+        let server = serve(UnixListener::bind(&socket_path).unwrap(), "garbage\r\n");
+        match client.request("GET", "/", "") {
+            Err(Error::InvalidResponse(line)) => assert_eq!(line, "garbage"),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        server.join().unwrap();
+    }
+}
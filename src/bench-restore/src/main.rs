@@ -0,0 +1,377 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Measures the snapshot restore latency of Firecracker: boots and snapshots a reference guest,
+//! then restores it repeatedly under each combination of the configured restore modes and
+//! fadvise policies, and reports the latency distributions.
+
+mod bench;
+mod http;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::json;
+use utils::arg_parser::{ArgParser, Argument, Arguments, Error as ParsingError};
+
+use crate::bench::{
+    boot_and_snapshot, drop_caches, load_params, restore, Distribution, GuestConfig, Mode, Sample,
+    SnapshotFiles, WsLayer,
+};
+
+const BENCH_RESTORE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug)]
+enum Error {
+    ArgumentParsing(ParsingError),
+    Bench(bench::Error),
+    InvalidValue(&'static str, String),
+    MissingArgument(&'static str),
+    WorkDir(PathBuf, io::Error),
+    WsRegions(PathBuf, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            ArgumentParsing(err) => write!(f, "Failed to parse arguments: {}", err),
+            Bench(err) => write!(f, "{}", err),
+            InvalidValue(arg, value) => write!(f, "Invalid value for --{}: {}", arg, value),
+            MissingArgument(arg) => write!(
+                f,
+                "Argument --{} is required to boot the reference guest",
+                arg
+            ),
+            WorkDir(path, err) => write!(f, "Failed to create {}: {}", path.display(), err),
+            WsRegions(path, err) => write!(
+                f,
+                "Failed to read the ws regions from {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new()
+        .arg(
+            Argument::new("firecracker")
+                .takes_value(true)
+                .default_value("firecracker")
+                .help("Path to the Firecracker binary."),
+        )
+        .arg(
+            Argument::new("work-dir")
+                .required(true)
+                .takes_value(true)
+                .help("Directory of the reference snapshot and of the API sockets."),
+        )
+        .arg(
+            Argument::new("kernel")
+                .takes_value(true)
+                .help("Kernel image of the reference guest."),
+        )
+        .arg(
+            Argument::new("rootfs")
+                .takes_value(true)
+                .help("Root filesystem of the reference guest."),
+        )
+        .arg(
+            Argument::new("boot-args")
+                .takes_value(true)
+                .default_value("console=ttyS0 reboot=k panic=1 pci=off")
+                .help("Kernel command line of the reference guest."),
+        )
+        .arg(
+            Argument::new("vcpus")
+                .takes_value(true)
+                .default_value("1")
+                .help("Number of vCPUs of the reference guest."),
+        )
+        .arg(
+            Argument::new("mem-size-mib")
+                .takes_value(true)
+                .default_value("128")
+                .help("Memory size of the reference guest."),
+        )
+        .arg(
+            Argument::new("settle-ms")
+                .takes_value(true)
+                .default_value("1000")
+                .help("Time the reference guest runs after boot before it is snapshotted."),
+        )
+        .arg(
+            Argument::new("snapshot")
+                .takes_value(true)
+                .requires("mem-file")
+                .help("Existing microVM state file to restore, instead of booting the guest."),
+        )
+        .arg(
+            Argument::new("mem-file")
+                .takes_value(true)
+                .requires("snapshot")
+                .help("Existing memory file to restore, instead of booting the guest."),
+        )
+        .arg(
+            Argument::new("modes")
+                .takes_value(true)
+                .default_value("lazy,eager")
+                .help("Comma separated restore modes: lazy, eager and ws."),
+        )
+        .arg(Argument::new("fadvise").takes_value(true).help(
+            "Comma separated fadvise policies of the snapshot load request, \
+             none for an empty one.",
+        ))
+        .arg(
+            Argument::new("ws-file")
+                .takes_value(true)
+                .requires("ws-regions")
+                .help("Working set file of the ws mode."),
+        )
+        .arg(
+            Argument::new("ws-regions")
+                .takes_value(true)
+                .requires("ws-file")
+                .help("JSON file of the ws_regions of the ws mode, as written by ws-builder."),
+        )
+        .arg(
+            Argument::new("iterations")
+                .takes_value(true)
+                .default_value("10")
+                .help("Number of restores of each configuration."),
+        )
+        .arg(
+            Argument::new("concurrency")
+                .takes_value(true)
+                .default_value("1")
+                .help("Number of restores running at once."),
+        )
+        .arg(
+            Argument::new("drop-caches")
+                .takes_value(false)
+                .help("Drop the host page cache before each batch of restores. Requires root."),
+        )
+        .arg(
+            Argument::new("json")
+                .takes_value(false)
+                .help("Print the report as JSON."),
+        )
+}
+
+fn parse<T: std::str::FromStr>(arguments: &Arguments, arg: &'static str) -> Result<T> {
+    let value = arguments.value_as_string(arg).unwrap_or_default();
+    value.parse().map_err(|_| Error::InvalidValue(arg, value))
+}
+
+fn path_arg(arguments: &Arguments, arg: &'static str) -> Option<PathBuf> {
+    arguments.value_as_string(arg).map(PathBuf::from)
+}
+
+fn read_ws(arguments: &Arguments) -> Result<Option<WsLayer>> {
+    let (file, regions_path) = match (
+        path_arg(arguments, "ws-file"),
+        path_arg(arguments, "ws-regions"),
+    ) {
+        (Some(file), Some(regions_path)) => (file, regions_path),
+        _ => return Ok(None),
+    };
+    let regions = fs::read_to_string(&regions_path)
+        .map_err(|e| e.to_string())
+        .and_then(|regions| serde_json::from_str(&regions).map_err(|e| e.to_string()))
+        .map_err(|e| Error::WsRegions(regions_path, e))?;
+    Ok(Some(WsLayer { file, regions }))
+}
+
+fn reference_snapshot(
+    arguments: &Arguments,
+    binary: &Path,
+    work_dir: &Path,
+) -> Result<SnapshotFiles> {
+    if let (Some(snapshot_path), Some(mem_file_path)) = (
+        path_arg(arguments, "snapshot"),
+        path_arg(arguments, "mem-file"),
+    ) {
+        return Ok(SnapshotFiles {
+            snapshot_path,
+            mem_file_path,
+        });
+    }
+
+    let guest = GuestConfig {
+        kernel: path_arg(arguments, "kernel").ok_or(Error::MissingArgument("kernel"))?,
+        rootfs: path_arg(arguments, "rootfs").ok_or(Error::MissingArgument("rootfs"))?,
+        boot_args: arguments.value_as_string("boot-args").unwrap_or_default(),
+        vcpus: parse(arguments, "vcpus")?,
+        mem_size_mib: parse(arguments, "mem-size-mib")?,
+        settle: Duration::from_millis(parse(arguments, "settle-ms")?),
+    };
+    boot_and_snapshot(binary, work_dir, &guest).map_err(Error::Bench)
+}
+
+// Runs `iterations` restores with the `load_body` request, `concurrency` at once. Returns the
+// latencies of the successful restores and the number of failed ones.
+fn run_restores(
+    binary: &Path,
+    work_dir: &Path,
+    load_body: &str,
+    iterations: usize,
+    concurrency: usize,
+    cold: bool,
+) -> Result<(Vec<Sample>, usize)> {
+    let mut samples = Vec::new();
+    let mut failures = 0;
+    let mut remaining = iterations;
+    while remaining > 0 {
+        if cold {
+            drop_caches().map_err(Error::Bench)?;
+        }
+        let batch = std::cmp::min(remaining, concurrency);
+        let threads: Vec<_> = (0..batch)
+            .map(|index| {
+                let binary = binary.to_path_buf();
+                let socket_path = work_dir.join(format!("restore-{}.sock", index));
+                let load_body = load_body.to_string();
+                thread::spawn(move || restore(&binary, &socket_path, &load_body))
+            })
+            .collect();
+        for thread in threads {
+            match thread.join() {
+                Ok(Ok(sample)) => samples.push(sample),
+                Ok(Err(err)) => {
+                    eprintln!("Restore failed: {}", err);
+                    failures += 1;
+                }
+                Err(_) => failures += 1,
+            }
+        }
+        remaining -= batch;
+    }
+    Ok((samples, failures))
+}
+
+fn run(arguments: &Arguments) -> Result<()> {
+    // The required arguments are checked by the parser.
+    let work_dir = path_arg(arguments, "work-dir").unwrap_or_default();
+    let binary = path_arg(arguments, "firecracker").unwrap_or_default();
+    let modes = arguments
+        .value_as_string("modes")
+        .unwrap_or_default()
+        .split(',')
+        .map(|mode| mode.trim().parse())
+        .collect::<bench::Result<Vec<Mode>>>()
+        .map_err(Error::Bench)?;
+    let fadvise_policies: Vec<String> = arguments
+        .value_as_string("fadvise")
+        .unwrap_or_else(|| "none".to_string())
+        .split(',')
+        .map(|policy| match policy.trim() {
+            "none" => String::new(),
+            policy => policy.to_string(),
+        })
+        .collect();
+    let iterations: usize = parse(arguments, "iterations")?;
+    let concurrency = std::cmp::max(parse::<usize>(arguments, "concurrency")?, 1);
+    let cold = arguments.value_as_bool("drop-caches").unwrap_or(false);
+    let ws = read_ws(arguments)?;
+    let page_size = sysconf::page::pagesize() as u64;
+
+    fs::create_dir_all(&work_dir).map_err(|e| Error::WorkDir(work_dir.clone(), e))?;
+    let snapshot = reference_snapshot(arguments, &binary, &work_dir)?;
+
+    let mut reports = Vec::new();
+    for mode in &modes {
+        for fadvise in &fadvise_policies {
+            let params = load_params(&snapshot, *mode, fadvise, ws.as_ref(), page_size)
+                .map_err(Error::Bench)?;
+            let load_body = serde_json::to_string(&params).unwrap_or_default();
+            let (samples, failures) = run_restores(
+                &binary,
+                &work_dir,
+                &load_body,
+                iterations,
+                concurrency,
+                cold,
+            )?;
+
+            let latencies = |latency: fn(&Sample) -> Duration| {
+                Distribution::new(&samples.iter().map(latency).collect::<Vec<_>>())
+            };
+            let load = latencies(|s| s.load);
+            let resume = latencies(|s| s.resume);
+            let total = latencies(|s| s.load + s.resume);
+            reports.push(json!({
+                "mode": mode.to_string(),
+                "fadvise": fadvise,
+                "concurrency": concurrency,
+                "cold": cold,
+                "failures": failures,
+                "load_us": load,
+                "resume_us": resume,
+                "total_us": total,
+            }));
+            if !arguments.value_as_bool("json").unwrap_or(false) {
+                println!(
+                    "mode {} fadvise '{}' concurrency {}: {} restores, {} failed\n  \
+                     load   {}\n  resume {}\n  total  {}",
+                    mode,
+                    fadvise,
+                    concurrency,
+                    samples.len(),
+                    failures,
+                    load,
+                    resume,
+                    total
+                );
+            }
+        }
+    }
+    if arguments.value_as_bool("json").unwrap_or(false) {
+        println!("{}", serde_json::Value::from(reports));
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+
+    if let Err(err) = arg_parser.parse_from_cmdline() {
+        eprintln!(
+            "{} \n\n\
+             For more information try --help.",
+            Error::ArgumentParsing(err)
+        );
+        process::exit(1);
+    }
+    if arg_parser
+        .arguments()
+        .value_as_bool("help")
+        .unwrap_or(false)
+    {
+        println!("bench-restore v{}\n", BENCH_RESTORE_VERSION);
+        println!("{}", arg_parser.formatted_help());
+        process::exit(0);
+    }
+    if arg_parser
+        .arguments()
+        .value_as_bool("version")
+        .unwrap_or(false)
+    {
+        println!("bench-restore v{}\n", BENCH_RESTORE_VERSION);
+        process::exit(0);
+    }
+
+    if let Err(err) = run(arg_parser.arguments()) {
+        eprintln!("bench-restore error: {}", err);
+        process::exit(1);
+    }
+}
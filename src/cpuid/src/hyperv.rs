@@ -0,0 +1,216 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use kvm_bindings::{kvm_cpuid_entry2, CpuId};
+
+use crate::bit_helper::BitHelper;
+use crate::transformer::Error;
+
+// Hyper-V CPUID leaves, see the Hypervisor Top Level Functional Specification.
+const HYPERV_CPUID_VENDOR_AND_MAX_FUNCTIONS: u32 = 0x4000_0000;
+const HYPERV_CPUID_INTERFACE: u32 = 0x4000_0001;
+const HYPERV_CPUID_VERSION: u32 = 0x4000_0002;
+const HYPERV_CPUID_FEATURES: u32 = 0x4000_0003;
+const HYPERV_CPUID_ENLIGHTMENT_INFO: u32 = 0x4000_0004;
+const HYPERV_CPUID_IMPLEMENT_LIMITS: u32 = 0x4000_0005;
+
+// The hypervisor CPUID range is probed by guests in steps of 0x100. When the Hyper-V leaves
+// take over 0x40000000, the KVM paravirtual leaves move to the next slot.
+const KVM_CPUID_SIGNATURE: u32 = 0x4000_0000;
+const KVM_CPUID_RELOCATION_OFFSET: u32 = 0x100;
+
+// "Microsoft Hv"
+const HYPERV_VENDOR_EBX: u32 = 0x7263_694d;
+const HYPERV_VENDOR_ECX: u32 = 0x666f_736f;
+const HYPERV_VENDOR_EDX: u32 = 0x7648_2074;
+// "Hv#1"
+const HYPERV_INTERFACE_SIGNATURE: u32 = 0x3123_7648;
+// Never notify the hypervisor about spinlock retries.
+const HYPERV_SPINLOCK_NEVER_RETRY: u32 = 0xffff_ffff;
+
+// HYPERV_CPUID_FEATURES.EAX bits
+const HV_MSR_TIME_REF_COUNT_AVAILABLE_BITINDEX: u32 = 1;
+const HV_MSR_SYNIC_AVAILABLE_BITINDEX: u32 = 2;
+const HV_MSR_SYNTIMER_AVAILABLE_BITINDEX: u32 = 3;
+const HV_MSR_HYPERCALL_AVAILABLE_BITINDEX: u32 = 5;
+const HV_MSR_VP_INDEX_AVAILABLE_BITINDEX: u32 = 6;
+const HV_MSR_REFERENCE_TSC_AVAILABLE_BITINDEX: u32 = 9;
+// HYPERV_CPUID_FEATURES.EDX bits
+const HV_STIMER_DIRECT_MODE_AVAILABLE_BITINDEX: u32 = 19;
+
+/// Hyper-V synthetic features that can be exposed to the guest.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HypervFeatures {
+    /// Exposes the partition reference counter and the reference TSC page.
+    pub reference_tsc: bool,
+    /// Exposes the synthetic timers, along with the synthetic interrupt controller they need.
+    pub synthetic_timers: bool,
+}
+
+impl HypervFeatures {
+    /// Returns true if at least one Hyper-V feature is requested.
+    pub fn any(&self) -> bool {
+        self.reference_tsc || self.synthetic_timers
+    }
+}
+
+fn hyperv_entry(function: u32, eax: u32, ebx: u32, ecx: u32, edx: u32) -> kvm_cpuid_entry2 {
+    kvm_cpuid_entry2 {
+        function,
+        index: 0,
+        flags: 0,
+        eax,
+        ebx,
+        ecx,
+        edx,
+        padding: [0, 0, 0],
+    }
+}
+
+/// Adds the Hyper-V CPUID leaves describing `features`.
+///
+/// The KVM paravirtual leaves are moved from 0x40000000 to 0x40000100 so that guests can still
+/// discover them. Nothing is changed if no feature is requested.
+///
+/// # Arguments
+///
+/// * `cpuid` - KVM related structure holding the relevant CPUID info.
+/// * `features` - The Hyper-V features to expose.
+pub fn set_hyperv_entries(cpuid: &mut CpuId, features: &HypervFeatures) -> Result<(), Error> {
+    if !features.any() {
+        return Ok(());
+    }
+
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        if entry.function >= KVM_CPUID_SIGNATURE
+            && entry.function < KVM_CPUID_SIGNATURE + KVM_CPUID_RELOCATION_OFFSET
+        {
+            if entry.function == KVM_CPUID_SIGNATURE {
+                // EAX holds the largest KVM leaf, which moves along with the others.
+                entry.eax += KVM_CPUID_RELOCATION_OFFSET;
+            }
+            entry.function += KVM_CPUID_RELOCATION_OFFSET;
+        }
+    }
+
+    let mut features_eax = 0u32;
+    let mut features_edx = 0u32;
+    features_eax
+        .write_bit(HV_MSR_HYPERCALL_AVAILABLE_BITINDEX, true)
+        .write_bit(HV_MSR_VP_INDEX_AVAILABLE_BITINDEX, true)
+        .write_bit(HV_MSR_TIME_REF_COUNT_AVAILABLE_BITINDEX, true);
+    if features.reference_tsc {
+        features_eax.write_bit(HV_MSR_REFERENCE_TSC_AVAILABLE_BITINDEX, true);
+    }
+    if features.synthetic_timers {
+        features_eax
+            .write_bit(HV_MSR_SYNIC_AVAILABLE_BITINDEX, true)
+            .write_bit(HV_MSR_SYNTIMER_AVAILABLE_BITINDEX, true);
+        features_edx.write_bit(HV_STIMER_DIRECT_MODE_AVAILABLE_BITINDEX, true);
+    }
+
+    let entries = [
+        hyperv_entry(
+            HYPERV_CPUID_VENDOR_AND_MAX_FUNCTIONS,
+            HYPERV_CPUID_IMPLEMENT_LIMITS,
+            HYPERV_VENDOR_EBX,
+            HYPERV_VENDOR_ECX,
+            HYPERV_VENDOR_EDX,
+        ),
+        hyperv_entry(HYPERV_CPUID_INTERFACE, HYPERV_INTERFACE_SIGNATURE, 0, 0, 0),
+        hyperv_entry(HYPERV_CPUID_VERSION, 0, 0, 0, 0),
+        hyperv_entry(HYPERV_CPUID_FEATURES, features_eax, 0, 0, features_edx),
+        hyperv_entry(
+            HYPERV_CPUID_ENLIGHTMENT_INFO,
+            0,
+            HYPERV_SPINLOCK_NEVER_RETRY,
+            0,
+            0,
+        ),
+        hyperv_entry(HYPERV_CPUID_IMPLEMENT_LIMITS, 0, 0, 0, 0),
+    ];
+    for entry in entries.iter() {
+        cpuid.push(*entry).map_err(Error::FamError)?;
+    }
+
+    Ok(())
+}
+
+/// Returns true if `cpuid` advertises the Hyper-V synthetic interrupt controller.
+///
+/// Used when restoring a vCPU, since KVM only accepts the SynIC state once the
+/// corresponding capability has been enabled on the vCPU.
+pub fn uses_synic(cpuid: &CpuId) -> bool {
+    let entries = cpuid.as_slice();
+    let is_hyperv = entries.iter().any(|entry| {
+        entry.function == HYPERV_CPUID_VENDOR_AND_MAX_FUNCTIONS
+            && entry.ebx == HYPERV_VENDOR_EBX
+            && entry.ecx == HYPERV_VENDOR_ECX
+            && entry.edx == HYPERV_VENDOR_EDX
+    });
+
+    is_hyperv
+        && entries.iter().any(|entry| {
+            entry.function == HYPERV_CPUID_FEATURES
+                && entry.eax.read_bit(HV_MSR_SYNIC_AVAILABLE_BITINDEX)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kvm_signature_cpuid() -> CpuId {
+        let mut cpuid = CpuId::new(2);
+        let entries = cpuid.as_mut_slice();
+        entries[0].function = KVM_CPUID_SIGNATURE;
+        entries[0].eax = KVM_CPUID_SIGNATURE + 1;
+        entries[1].function = KVM_CPUID_SIGNATURE + 1;
+        cpuid
+    }
+
+    #[test]
+    fn test_set_hyperv_entries_disabled() {
+        let mut cpuid = kvm_signature_cpuid();
+        set_hyperv_entries(&mut cpuid, &HypervFeatures::default()).unwrap();
+
+        assert_eq!(cpuid.as_slice().len(), 2);
+        assert_eq!(cpuid.as_slice()[0].function, KVM_CPUID_SIGNATURE);
+        assert!(!uses_synic(&cpuid));
+    }
+
+    #[test]
+    fn test_set_hyperv_entries() {
+        let mut cpuid = kvm_signature_cpuid();
+        let features = HypervFeatures {
+            reference_tsc: true,
+            synthetic_timers: false,
+        };
+        set_hyperv_entries(&mut cpuid, &features).unwrap();
+
+        let entries = cpuid.as_slice();
+        assert_eq!(entries.len(), 8);
+        // The KVM leaves have been relocated.
+        assert_eq!(entries[0].function, 0x4000_0100);
+        assert_eq!(entries[0].eax, 0x4000_0101);
+        assert_eq!(entries[1].function, 0x4000_0101);
+
+        let features_entry = entries
+            .iter()
+            .find(|entry| entry.function == HYPERV_CPUID_FEATURES)
+            .unwrap();
+        assert!(features_entry
+            .eax
+            .read_bit(HV_MSR_REFERENCE_TSC_AVAILABLE_BITINDEX));
+        assert!(!features_entry.eax.read_bit(HV_MSR_SYNIC_AVAILABLE_BITINDEX));
+        assert!(!uses_synic(&cpuid));
+
+        let mut cpuid = kvm_signature_cpuid();
+        let features = HypervFeatures {
+            reference_tsc: false,
+            synthetic_timers: true,
+        };
+        set_hyperv_entries(&mut cpuid, &features).unwrap();
+        assert!(uses_synic(&cpuid));
+    }
+}
@@ -17,11 +17,15 @@ use crate::common::*;
 /// Contains helper methods for bit operations.
 pub mod bit_helper;
 
+/// Hyper-V enlightenments exposed through the hypervisor CPUID leaves.
+pub mod hyperv;
+
 mod template;
 pub use crate::template::c3;
 pub use crate::template::t2;
 
 mod cpu_leaf;
+use crate::cpu_leaf::{leaf_0x1, leaf_0x80000001};
 
 mod transformer;
 use crate::transformer::*;
@@ -29,6 +33,8 @@ pub use crate::transformer::{Error, VmSpec};
 
 mod brand_string;
 
+use crate::bit_helper::BitHelper;
+
 /// Sets up the CPUID entries for the given vcpu.
 ///
 /// # Arguments
@@ -66,3 +72,84 @@ pub fn filter_cpuid(kvm_cpuid: &mut CpuId, vm_spec: &VmSpec) -> Result<(), Error
 
     Ok(())
 }
+
+/// Exposes or hides the hardware virtualization extensions (VMX on Intel, SVM on AMD).
+///
+/// When `enabled` is false both feature bits are cleared. When `enabled` is true the bits
+/// are left as reported by KVM, and an error is returned if KVM reports neither of them
+/// (i.e. nested virtualization is disabled in the host's `kvm_intel`/`kvm_amd` module).
+///
+/// # Arguments
+///
+/// * `kvm_cpuid` - KVM related structure holding the relevant CPUID info.
+/// * `enabled` - Whether the guest should be able to run its own hypervisor.
+pub fn set_nested_virt(kvm_cpuid: &mut CpuId, enabled: bool) -> Result<(), Error> {
+    let mut supported = false;
+
+    for entry in kvm_cpuid.as_mut_slice().iter_mut() {
+        match entry.function {
+            leaf_0x1::LEAF_NUM => {
+                supported |= entry.ecx.read_bit(leaf_0x1::ecx::VMX_BITINDEX);
+                if !enabled {
+                    entry.ecx.write_bit(leaf_0x1::ecx::VMX_BITINDEX, false);
+                }
+            }
+            leaf_0x80000001::LEAF_NUM => {
+                supported |= entry.ecx.read_bit(leaf_0x80000001::ecx::SVM_BITINDEX);
+                if !enabled {
+                    entry
+                        .ecx
+                        .write_bit(leaf_0x80000001::ecx::SVM_BITINDEX, false);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if enabled && !supported {
+        return Err(Error::NestedVirtNotSupported);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested_virt_cpuid(vmx: bool, svm: bool) -> CpuId {
+        let mut cpuid = CpuId::new(2);
+        let entries = cpuid.as_mut_slice();
+        entries[0].function = leaf_0x1::LEAF_NUM;
+        entries[0].ecx.write_bit(leaf_0x1::ecx::VMX_BITINDEX, vmx);
+        entries[1].function = leaf_0x80000001::LEAF_NUM;
+        entries[1]
+            .ecx
+            .write_bit(leaf_0x80000001::ecx::SVM_BITINDEX, svm);
+        cpuid
+    }
+
+    #[test]
+    fn test_set_nested_virt() {
+        // Disabling clears both bits.
+        let mut cpuid = nested_virt_cpuid(true, true);
+        set_nested_virt(&mut cpuid, false).unwrap();
+        let entries = cpuid.as_mut_slice();
+        assert!(!entries[0].ecx.read_bit(leaf_0x1::ecx::VMX_BITINDEX));
+        assert!(!entries[1].ecx.read_bit(leaf_0x80000001::ecx::SVM_BITINDEX));
+
+        // Enabling keeps whatever KVM reports.
+        let mut cpuid = nested_virt_cpuid(true, false);
+        set_nested_virt(&mut cpuid, true).unwrap();
+        let entries = cpuid.as_mut_slice();
+        assert!(entries[0].ecx.read_bit(leaf_0x1::ecx::VMX_BITINDEX));
+        assert!(!entries[1].ecx.read_bit(leaf_0x80000001::ecx::SVM_BITINDEX));
+
+        // Enabling fails when KVM doesn't expose either extension.
+        let mut cpuid = nested_virt_cpuid(false, false);
+        match set_nested_virt(&mut cpuid, true) {
+            Err(Error::NestedVirtNotSupported) => (),
+            _ => panic!("Expected NestedVirtNotSupported"),
+        }
+    }
+}
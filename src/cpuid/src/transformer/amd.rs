@@ -68,11 +68,19 @@ pub fn update_amd_features_entry(
     use crate::cpu_leaf::leaf_0x80000008::*;
 
     // We don't support more then 128 threads right now.
-    // It's safe to put them all on the same processor.
+    // Unless several sockets are requested, it's safe to put them all on the same processor.
     entry
         .ecx
-        .write_bits_in_range(&ecx::THREAD_ID_SIZE_BITRANGE, THREAD_ID_MAX_SIZE)
-        .write_bits_in_range(&ecx::NUM_THREADS_BITRANGE, u32::from(vm_spec.cpu_count - 1));
+        .write_bits_in_range(
+            &ecx::THREAD_ID_SIZE_BITRANGE,
+            vm_spec
+                .package_apic_id_shift()
+                .unwrap_or(THREAD_ID_MAX_SIZE),
+        )
+        .write_bits_in_range(
+            &ecx::NUM_THREADS_BITRANGE,
+            u32::from(vm_spec.cpus_per_package() - 1),
+        );
 
     Ok(())
 }
@@ -99,7 +107,9 @@ pub fn update_extended_apic_id_entry(
     // logical CPU 1 -> core id: 0
     // logical CPU 2 -> core id: 1
     // logical CPU 3 -> core id: 1
-    let core_id = u32::from(vm_spec.cpu_index / vm_spec.cpus_per_core());
+    // Core ids are numbered within each package.
+    let core_id =
+        u32::from(vm_spec.cpu_index % vm_spec.cpus_per_package() / vm_spec.cpus_per_core());
 
     entry
         .eax
@@ -120,8 +130,8 @@ pub fn update_extended_apic_id_entry(
     entry
         .ecx
         .write_bits_in_range(&ecx::NODES_PER_PROCESSOR_BITRANGE, NODES_PER_PROCESSOR)
-        // Put all the cpus in the same node.
-        .write_bits_in_range(&ecx::NODE_ID_BITRANGE, 0);
+        // There is one node per package.
+        .write_bits_in_range(&ecx::NODE_ID_BITRANGE, u32::from(vm_spec.package_index()));
 
     Ok(())
 }
@@ -352,4 +362,57 @@ mod tests {
         check_update_extended_apic_id_entry(0, 2, true, 0, 1);
         check_update_extended_apic_id_entry(1, 2, true, 0, 1);
     }
+
+    #[test]
+    fn test_8vcpu_2sockets_ht_on() {
+        let vm_spec = VmSpec::with_sockets(6, 8, true, 2).expect("Error creating vm_spec");
+        let mut entry = &mut kvm_cpuid_entry2 {
+            function: leaf_0x80000008::LEAF_NUM,
+            index: 0,
+            flags: 0,
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+            padding: [0, 0, 0],
+        };
+        assert!(update_amd_features_entry(&mut entry, &vm_spec).is_ok());
+        assert_eq!(
+            entry
+                .ecx
+                .read_bits_in_range(&leaf_0x80000008::ecx::NUM_THREADS_BITRANGE),
+            3
+        );
+        assert_eq!(
+            entry
+                .ecx
+                .read_bits_in_range(&leaf_0x80000008::ecx::THREAD_ID_SIZE_BITRANGE),
+            2
+        );
+
+        // Logical cpu 6 is the first thread of the second core of the second package.
+        let mut entry = &mut kvm_cpuid_entry2 {
+            function: leaf_0x8000001e::LEAF_NUM,
+            index: 0,
+            flags: 0,
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+            padding: [0, 0, 0],
+        };
+        assert!(update_extended_apic_id_entry(&mut entry, &vm_spec).is_ok());
+        assert_eq!(
+            entry
+                .ebx
+                .read_bits_in_range(&leaf_0x8000001e::ebx::CORE_ID_BITRANGE),
+            1
+        );
+        assert_eq!(
+            entry
+                .ecx
+                .read_bits_in_range(&leaf_0x8000001e::ecx::NODE_ID_BITRANGE),
+            1
+        );
+    }
 }
@@ -17,10 +17,9 @@ fn update_deterministic_cache_entry(
 
     common::update_cache_parameters_entry(entry, vm_spec)?;
 
-    // Put all the cores in the same socket
     entry.eax.write_bits_in_range(
         &eax::MAX_CORES_PER_PACKAGE_BITRANGE,
-        u32::from(vm_spec.cpu_count / vm_spec.cpus_per_core()) - 1,
+        u32::from(vm_spec.cpus_per_package() / vm_spec.cpus_per_core()) - 1,
     );
 
     Ok(())
@@ -89,12 +88,17 @@ fn update_extended_topology_entry(
         }
         // Core Level Processor Topology; index = 1
         1 => {
-            entry
-                .eax
-                .write_bits_in_range(&eax::APICID_BITRANGE, LEAFBH_INDEX1_APICID);
+            // With several packages, the bits above the ones enumerating the cpus of a
+            // package hold the package id.
+            entry.eax.write_bits_in_range(
+                &eax::APICID_BITRANGE,
+                vm_spec
+                    .package_apic_id_shift()
+                    .unwrap_or(LEAFBH_INDEX1_APICID),
+            );
             entry.ebx.write_bits_in_range(
                 &ebx::NUM_LOGICAL_PROCESSORS_BITRANGE,
-                u32::from(vm_spec.cpu_count),
+                u32::from(vm_spec.cpus_per_package()),
             );
             entry
                 .ecx
@@ -292,4 +296,50 @@ mod tests {
         // index 1
         check_update_extended_topology_entry(2, true, 1, LEAFBH_INDEX1_APICID, 2, LEVEL_TYPE_CORE);
     }
+
+    #[test]
+    fn test_4vcpu_2sockets_ht_on() {
+        use crate::cpu_leaf::leaf_0xb::*;
+
+        let vm_spec = VmSpec::with_sockets(0, 4, true, 2).expect("Error creating vm_spec");
+
+        // One core with two threads in each package.
+        let mut entry = &mut kvm_cpuid_entry2 {
+            function: leaf_0x4::LEAF_NUM,
+            index: 0,
+            flags: 0,
+            eax: *(0 as u32).write_bits_in_range(&leaf_0x4::eax::CACHE_LEVEL_BITRANGE, 3),
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+            padding: [0, 0, 0],
+        };
+        assert!(update_deterministic_cache_entry(&mut entry, &vm_spec).is_ok());
+        assert_eq!(
+            entry
+                .eax
+                .read_bits_in_range(&leaf_0x4::eax::MAX_CORES_PER_PACKAGE_BITRANGE),
+            0
+        );
+
+        // The package id starts right above the bit enumerating the threads of a core.
+        let mut entry = &mut kvm_cpuid_entry2 {
+            function: LEAF_NUM,
+            index: 1,
+            flags: 0,
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+            padding: [0, 0, 0],
+        };
+        assert!(update_extended_topology_entry(&mut entry, &vm_spec).is_ok());
+        assert_eq!(entry.eax.read_bits_in_range(&eax::APICID_BITRANGE), 1);
+        assert_eq!(
+            entry
+                .ebx
+                .read_bits_in_range(&ebx::NUM_LOGICAL_PROCESSORS_BITRANGE),
+            2
+        );
+    }
 }
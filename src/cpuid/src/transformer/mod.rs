@@ -25,19 +25,45 @@ pub struct VmSpec {
 
     /// The number of bits needed to enumerate logical CPUs per core.
     cpu_bits: u8,
+    /// The number of logical cpus in each package (socket).
+    cpus_per_package: u8,
 }
 
 impl VmSpec {
     /// Creates a new instance of VmSpec with the specified parameters
     /// The brand string is deduced from the vendor_id
     pub fn new(cpu_index: u8, cpu_count: u8, ht_enabled: bool) -> Result<VmSpec, Error> {
+        VmSpec::with_sockets(cpu_index, cpu_count, ht_enabled, 1)
+    }
+
+    /// Creates a new instance of VmSpec with the logical cpus spread evenly over `sockets`
+    /// packages. When there is more than one package, the number of logical cpus per package
+    /// has to be a power of 2, so that the package id can be read from the APIC id bits.
+    pub fn with_sockets(
+        cpu_index: u8,
+        cpu_count: u8,
+        ht_enabled: bool,
+        sockets: u8,
+    ) -> Result<VmSpec, Error> {
+        let cpu_bits = (cpu_count > 1 && ht_enabled) as u8;
+        if sockets == 0 || cpu_count % sockets != 0 {
+            return Err(Error::InvalidCpuTopology);
+        }
+        let cpus_per_package = cpu_count / sockets;
+        if cpus_per_package % (1 << cpu_bits) != 0
+            || (sockets > 1 && !cpus_per_package.is_power_of_two())
+        {
+            return Err(Error::InvalidCpuTopology);
+        }
+
         let cpu_vendor_id = get_vendor_id().map_err(Error::InternalError)?;
 
         Ok(VmSpec {
             cpu_vendor_id,
             cpu_index,
             cpu_count,
-            cpu_bits: (cpu_count > 1 && ht_enabled) as u8,
+            cpu_bits,
+            cpus_per_package,
             brand_string: BrandString::from_vendor_id(&cpu_vendor_id),
         })
     }
@@ -51,6 +77,26 @@ impl VmSpec {
     pub fn cpus_per_core(&self) -> u8 {
         1 << self.cpu_bits
     }
+
+    /// Returns the number of cpus per package
+    pub fn cpus_per_package(&self) -> u8 {
+        self.cpus_per_package
+    }
+
+    /// Returns the index of the package the current logical cpu belongs to
+    pub fn package_index(&self) -> u8 {
+        self.cpu_index / self.cpus_per_package
+    }
+
+    /// Returns the number of APIC id bits that enumerate the logical cpus within a package,
+    /// or `None` if all the cpus are in the same package.
+    pub fn package_apic_id_shift(&self) -> Option<u32> {
+        if self.cpus_per_package == self.cpu_count {
+            None
+        } else {
+            Some(self.cpus_per_package.trailing_zeros())
+        }
+    }
 }
 
 /// Errors associated with processing the CPUID leaves.
@@ -60,6 +106,10 @@ pub enum Error {
     FamError(utils::fam::Error),
     /// A call to an internal helper method failed
     InternalError(super::common::Error),
+    /// The logical cpus cannot be split evenly over the requested number of packages.
+    InvalidCpuTopology,
+    /// Nested virtualization was requested but the host does not expose VMX/SVM to KVM guests.
+    NestedVirtNotSupported,
     /// The maximum number of addressable logical CPUs cannot be stored in an `u8`.
     VcpuCountOverflow,
 }
@@ -115,6 +165,31 @@ mod tests {
         let vm_spec = VmSpec::new(0, 2, true).unwrap();
         assert_eq!(vm_spec.cpu_bits, 1);
         assert_eq!(vm_spec.cpus_per_core(), 2);
+        assert_eq!(vm_spec.cpus_per_package(), 2);
+        assert_eq!(vm_spec.package_apic_id_shift(), None);
+    }
+
+    #[test]
+    fn test_vmspec_with_sockets() {
+        let vm_spec = VmSpec::with_sockets(5, 8, true, 2).unwrap();
+        assert_eq!(vm_spec.cpus_per_core(), 2);
+        assert_eq!(vm_spec.cpus_per_package(), 4);
+        assert_eq!(vm_spec.package_index(), 1);
+        assert_eq!(vm_spec.package_apic_id_shift(), Some(2));
+
+        let vm_spec = VmSpec::with_sockets(2, 6, false, 1).unwrap();
+        assert_eq!(vm_spec.cpus_per_package(), 6);
+        assert_eq!(vm_spec.package_index(), 0);
+        assert_eq!(vm_spec.package_apic_id_shift(), None);
+
+        // No sockets.
+        assert!(VmSpec::with_sockets(0, 2, false, 0).is_err());
+        // The cpus cannot be split evenly between the sockets.
+        assert!(VmSpec::with_sockets(0, 3, false, 2).is_err());
+        // A socket would hold half of a core.
+        assert!(VmSpec::with_sockets(0, 2, true, 2).is_err());
+        // The cpus per socket are not a power of 2.
+        assert!(VmSpec::with_sockets(0, 6, false, 2).is_err());
     }
 
     const PROCESSED_FN: u32 = 1;
@@ -13,7 +13,8 @@ use crate::virtio::{
     ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_NET, VIRTIO_MMIO_INT_VRING,
 };
 use crate::{report_net_event_fail, Error as DeviceError};
-use dumbo::pdu::ethernet::EthernetFrame;
+use dumbo::pdu::arp::{EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN};
+use dumbo::pdu::ethernet::{EthernetFrame, ETHERTYPE_ARP, PAYLOAD_OFFSET};
 use dumbo::{MacAddr, MAC_ADDR_LEN};
 use libc::EAGAIN;
 use logger::{error, warn, Metric, METRICS};
@@ -22,6 +23,7 @@ use rate_limiter::{BucketUpdate, RateLimiter, TokenType};
 #[cfg(not(test))]
 use std::io;
 use std::io::{Read, Write};
+use std::net::Ipv4Addr;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
@@ -34,6 +36,10 @@ use virtio_gen::virtio_net::{
 };
 use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 
+// Length of a gratuitous ARP request, along with its VNET header.
+const ANNOUNCE_FRAME_LEN: usize =
+    mem::size_of::<virtio_net_hdr_v1>() + PAYLOAD_OFFSET + ETH_IPV4_FRAME_LEN;
+
 fn vnet_hdr_len() -> usize {
     mem::size_of::<virtio_net_hdr_v1>()
 }
@@ -211,6 +217,41 @@ impl Net {
         self.mmds_ns.as_mut()
     }
 
+    /// Announces the guest at `guest_ip` on the TAP device with a gratuitous ARP request, so that
+    /// the host learns the MAC of the guest without waiting for its traffic. Does nothing when
+    /// the MAC of the guest is not configured, as it is then unknown to the device.
+    pub fn announce_guest(&mut self, guest_ip: Ipv4Addr) -> Result<()> {
+        let guest_mac = match self.guest_mac {
+            Some(mac) => mac,
+            None => return Ok(()),
+        };
+        let mut frame_buf = [0u8; ANNOUNCE_FRAME_LEN];
+        init_vnet_hdr(&mut frame_buf);
+        // Safe to unwrap because the buffer holds the whole frame.
+        let mut frame = EthernetFrame::write_incomplete(
+            frame_bytes_from_buf_mut(&mut frame_buf)?,
+            MacAddr::from_bytes_unchecked(&[0xff; MAC_ADDR_LEN]),
+            guest_mac,
+            ETHERTYPE_ARP,
+        )
+        .unwrap()
+        .with_payload_len_unchecked(ETH_IPV4_FRAME_LEN);
+        // Safe to unwrap because the payload was sized for an ARP request.
+        EthIPv4ArpFrame::write_request(
+            frame.payload_mut(),
+            guest_mac,
+            guest_ip,
+            MacAddr::from_bytes_unchecked(&[0; MAC_ADDR_LEN]),
+            guest_ip,
+        )
+        .unwrap();
+
+        self.tap.write_all(&frame_buf).map_err(|e| {
+            METRICS.net.tap_write_fails.inc();
+            Error::IO(e)
+        })
+    }
+
     fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
         self.interrupt_status
             .fetch_or(VIRTIO_MMIO_INT_VRING as usize, Ordering::SeqCst);
@@ -381,6 +422,7 @@ impl Net {
                 METRICS.net.tx_bytes_count.add(frame_buf.len());
                 METRICS.net.tx_packets_count.inc();
                 METRICS.net.tx_count.inc();
+                METRICS.readiness.resume_to_net_tx_us.record();
             }
             Err(e) => {
                 error!("Failed to write to tap: {:?}", e);
@@ -1595,6 +1637,17 @@ pub mod tests {
         (frame_buf, frame_len)
     }
 
+    #[test]
+    fn test_announce_guest() {
+        let mut net = Net::default_net();
+        let guest_ip = Ipv4Addr::new(10, 1, 2, 3);
+        assert!(net.announce_guest(guest_ip).is_ok());
+
+        // Nothing is announced for a guest whose MAC is unknown.
+        net.guest_mac = None;
+        assert!(net.announce_guest(guest_ip).is_ok());
+    }
+
     #[test]
     fn test_mmds_detour_and_injection() {
         let mut net = Net::default_net();
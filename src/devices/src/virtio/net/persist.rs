@@ -36,6 +36,13 @@ pub struct NetState {
     virtio_state: VirtioDeviceState,
 }
 
+impl NetState {
+    /// Binds the restored device to the `tap_if_name` TAP device instead of the saved one.
+    pub fn set_tap_if_name(&mut self, tap_if_name: String) {
+        self.tap_if_name = tap_if_name;
+    }
+}
+
 pub struct NetConstructorArgs {
     pub mem: GuestMemoryMmap,
 }
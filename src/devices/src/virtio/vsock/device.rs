@@ -199,6 +199,7 @@ where
                 break;
             }
 
+            METRICS.readiness.resume_to_vsock_tx_us.record();
             have_used = true;
             self.queues[TXQ_INDEX].add_used(mem, head.index, 0);
         }
@@ -2,26 +2,29 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::{
-    os::unix::io::AsRawFd,
+    os::unix::io::{AsRawFd, IntoRawFd},
+    os::unix::net::UnixListener,
     path::PathBuf,
     sync::mpsc::{channel, Receiver, Sender, TryRecvError},
     sync::{Arc, Mutex, RwLock},
     thread,
 };
 
-use api_server::{ApiRequest, ApiResponse, ApiServer};
+use api_server::{ApiRequest, ApiResponse, ApiServer, HttpServer};
 use logger::{error, warn};
 use mmds::MMDS;
 use polly::event_manager::{EventManager, Subscriber};
-use seccomp::BpfProgram;
 use utils::{
     epoll::{EpollEvent, EventSet},
     eventfd::EventFd,
 };
 use vmm::{
+    default_syscalls::ThreadFilters,
     rpc_interface::{PrebootApiController, RuntimeApiController},
+    snapshot_signing::SnapshotKeys,
     vmm_config::instance_info::InstanceInfo,
     vmm_config::machine_config::VmConfig,
+    vmm_config::snapshot::LoadSnapshotParams,
     Vmm,
 };
 
@@ -41,13 +44,14 @@ impl ApiServerAdapter {
         to_api: Sender<ApiResponse>,
         vm_config: VmConfig,
         vmm: Arc<Mutex<Vmm>>,
+        snapshot_keys: Arc<SnapshotKeys>,
         event_manager: &mut EventManager,
     ) {
         let api_adapter = Arc::new(Mutex::new(Self {
             api_event_fd,
             from_api,
             to_api,
-            controller: RuntimeApiController::new(vm_config, vmm),
+            controller: RuntimeApiController::new(vm_config, vmm, snapshot_keys),
         }));
         event_manager
             .add_subscriber(api_adapter)
@@ -97,9 +101,12 @@ impl Subscriber for ApiServerAdapter {
 }
 
 pub fn run_with_api(
-    seccomp_filter: BpfProgram,
+    seccomp_filters: ThreadFilters,
+    snapshot_keys: SnapshotKeys,
     config_json: Option<String>,
+    load_params: Option<LoadSnapshotParams>,
     bind_path: PathBuf,
+    api_listener: Option<UnixListener>,
     instance_info: InstanceInfo,
     start_time_us: Option<u64>,
     start_time_cpu_us: Option<u64>,
@@ -112,7 +119,7 @@ pub fn run_with_api(
     let (to_vmm, from_api) = channel();
     let (to_api, from_vmm) = channel();
 
-    // MMDS only supported with API.
+    // The API thread shares the MMDS data store with the guest.
     let mmds_info = MMDS.clone();
     let api_shared_info = Arc::new(RwLock::new(instance_info.clone()));
     let vmm_shared_info = api_shared_info.clone();
@@ -120,25 +127,46 @@ pub fn run_with_api(
         .try_clone()
         .expect("Failed to clone API event FD");
 
-    let api_seccomp_filter = seccomp_filter.clone();
+    let api_seccomp_filter = seccomp_filters.vmm.clone();
+    // The guest agent of a snapshot loaded at start is told on the first resume request.
+    let resumed_agent = match load_params.as_ref() {
+        Some(params) if params.resume_vm && params.guest_agent.is_some() => {
+            warn!("The guest agent is not told of resumes without a resume request.");
+            None
+        }
+        Some(params) => params.guest_agent.clone(),
+        None => None,
+    };
     // Start the separate API thread.
     thread::Builder::new()
         .name("fc_api".to_owned())
         .spawn(move || {
-            match ApiServer::new(
+            let mut api_server = ApiServer::new(
                 mmds_info,
                 vmm_shared_info,
                 to_vmm,
                 from_vmm,
                 to_vmm_event_fd,
             )
-            .expect("Cannot create API server")
-            .bind_and_run(
-                bind_path,
-                start_time_us,
-                start_time_cpu_us,
-                api_seccomp_filter,
-            ) {
+            .expect("Cannot create API server");
+            if let Some(config) = resumed_agent {
+                api_server.set_resumed_agent(config);
+            }
+            let res = match api_listener {
+                // Safe because the activated socket is listening, and handed to the server.
+                Some(listener) => {
+                    let server = unsafe { HttpServer::new_from_fd(listener.into_raw_fd()) }
+                        .expect("Failed to serve the activated API socket");
+                    api_server.run(server, start_time_us, start_time_cpu_us, api_seccomp_filter)
+                }
+                None => api_server.bind_and_run(
+                    bind_path,
+                    start_time_us,
+                    start_time_cpu_us,
+                    api_seccomp_filter,
+                ),
+            };
+            match res {
                 Ok(_) => (),
                 Err(api_server::Error::Io(inner)) => match inner.kind() {
                     std::io::ErrorKind::AddrInUse => panic!(
@@ -158,6 +186,7 @@ pub fn run_with_api(
         .expect("API thread spawn failed.");
 
     let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+    let snapshot_keys = Arc::new(snapshot_keys);
 
     // Create the firecracker metrics object responsible for periodically printing metrics.
     let firecracker_metrics = Arc::new(Mutex::new(super::metrics::PeriodicMetrics::new()));
@@ -166,12 +195,25 @@ pub fn run_with_api(
         .expect("Cannot register the metrics event to the event manager.");
 
     // Configure, build and start the microVM.
-    let (vm_resources, vmm) = match config_json {
-        Some(json) => {
-            super::build_microvm_from_json(seccomp_filter, &mut event_manager, json, &instance_info)
-        }
-        None => PrebootApiController::build_microvm_from_requests(
-            seccomp_filter,
+    let (vm_resources, vmm) = match (config_json, load_params) {
+        (Some(json), _) => super::build_microvm_from_json(
+            seccomp_filters,
+            snapshot_keys.clone(),
+            &mut event_manager,
+            json,
+            &instance_info,
+        ),
+        #[cfg(target_arch = "x86_64")]
+        (None, Some(load_params)) => super::build_microvm_from_snapshot(
+            seccomp_filters,
+            snapshot_keys.clone(),
+            &mut event_manager,
+            &load_params,
+            &instance_info,
+        ),
+        _ => PrebootApiController::build_microvm_from_requests(
+            seccomp_filters,
+            snapshot_keys.clone(),
             &mut event_manager,
             instance_info,
             || {
@@ -208,6 +250,7 @@ pub fn run_with_api(
         to_api,
         vm_resources.vm_config().clone(),
         vmm,
+        snapshot_keys,
         &mut event_manager,
     );
 }
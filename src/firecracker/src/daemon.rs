@@ -0,0 +1,165 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::process;
+
+use libc::{O_NONBLOCK, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
+use utils::syscall::SyscallReturnCode;
+
+const DEV_NULL: &str = "/dev/null";
+
+#[derive(Debug)]
+pub enum Error {
+    Daemon(String),
+    Dup2(io::Error),
+    Fork(io::Error),
+    OpenStdio(io::Error),
+    Pipe(io::Error),
+    PidFile(io::Error),
+    SetSid(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Daemon(err) => write!(f, "The daemon failed to start: {}", err),
+            Dup2(err) => write!(f, "Failed to redirect stdio: {}", err),
+            Fork(err) => write!(f, "Failed to fork: {}", err),
+            OpenStdio(err) => write!(f, "Failed to open the stdio destination: {}", err),
+            Pipe(err) => write!(f, "Failed to create the daemon status pipe: {}", err),
+            PidFile(err) => write!(f, "Failed to write the pid file: {}", err),
+            SetSid(err) => write!(f, "Failed to create a session: {}", err),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Writes the pid of the current process to `path`.
+pub fn write_pid_file(path: &Path) -> Result<()> {
+    File::create(path)
+        .and_then(|mut file| writeln!(file, "{}", process::id()))
+        .map_err(Error::PidFile)
+}
+
+/// Detaches Firecracker from its parent process, session and terminal. The daemon writes its
+/// pid to `pid_file`, reads stdin from /dev/null and writes stdout and stderr to `log_path`, or
+/// /dev/null.
+///
+/// The launched process exits once the daemon is started, or returns the error that kept it
+/// from starting. Only the daemon returns successfully. The open file descriptors, such as the
+/// inherited snapshot files, are kept. This must be called before any thread is spawned.
+pub fn daemonize(log_path: Option<&Path>, pid_file: Option<&Path>) -> Result<()> {
+    let stdin = File::open(DEV_NULL).map_err(Error::OpenStdio)?;
+    // The log file is opened as the logger does, so that a FIFO without a reader does not block.
+    let stdout = OpenOptions::new()
+        .custom_flags(O_NONBLOCK)
+        .read(true)
+        .write(true)
+        .open(log_path.unwrap_or_else(|| Path::new(DEV_NULL)))
+        .map_err(Error::OpenStdio)?;
+    let (mut status_reader, mut status_writer) = pipe()?;
+
+    let child = fork()?;
+    if child != 0 {
+        drop(status_writer);
+        // Reap the intermediate process, which exits once it has forked the daemon.
+        // Safe because the status is a valid pointer, and the result is not used.
+        unsafe { libc::waitpid(child, std::ptr::null_mut(), 0) };
+        // Each process closes the write end of the pipe when done, leaving errors behind.
+        let mut status = String::new();
+        status_reader
+            .read_to_string(&mut status)
+            .map_err(|e| Error::Daemon(e.to_string()))?;
+        if !status.is_empty() {
+            return Err(Error::Daemon(status));
+        }
+        process::exit(i32::from(vmm::FC_EXIT_CODE_OK));
+    }
+
+    drop(status_reader);
+    if let Err(err) = detach(&stdin, &stdout, pid_file) {
+        let _ = write!(status_writer, "{}", err);
+        process::exit(i32::from(vmm::FC_EXIT_CODE_GENERIC_ERROR));
+    }
+    Ok(())
+}
+
+// Runs in the child of the launched process. Starts a new session and forks the daemon, which
+// cannot get a controlling terminal back as it is not the session leader.
+fn detach(stdin: &File, stdout: &File, pid_file: Option<&Path>) -> Result<()> {
+    // Safe because it's a library function, and we check the result.
+    SyscallReturnCode(unsafe { libc::setsid() })
+        .into_empty_result()
+        .map_err(Error::SetSid)?;
+    if fork()? != 0 {
+        // Safe because the intermediate process has nothing to clean up.
+        unsafe { libc::_exit(i32::from(vmm::FC_EXIT_CODE_OK)) };
+    }
+
+    if let Some(path) = pid_file {
+        write_pid_file(path)?;
+    }
+    dup2(stdin.as_raw_fd(), STDIN_FILENO)?;
+    dup2(stdout.as_raw_fd(), STDOUT_FILENO)?;
+    dup2(stdout.as_raw_fd(), STDERR_FILENO)
+}
+
+fn fork() -> Result<libc::pid_t> {
+    // Safe because no other thread is running, and we check the result.
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Err(Error::Fork(io::Error::last_os_error()));
+    }
+    Ok(pid)
+}
+
+fn pipe() -> Result<(File, File)> {
+    let mut fds = [0; 2];
+    // Safe because `fds` holds the two fds written by the kernel, and we check the result.
+    SyscallReturnCode(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) })
+        .into_empty_result()
+        .map_err(Error::Pipe)?;
+    // Safe because the fds were just created and are owned by nothing else.
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+fn dup2(old_fd: libc::c_int, new_fd: libc::c_int) -> Result<()> {
+    // Safe because we are using fds we own, and we check the result.
+    SyscallReturnCode(unsafe { libc::dup2(old_fd, new_fd) })
+        .into_empty_result()
+        .map_err(Error::Dup2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_write_pid_file() {
+        let pid_file = TempFile::new().unwrap();
+        fs::write(pid_file.as_path(), "stale pid file contents").unwrap();
+        write_pid_file(pid_file.as_path()).unwrap();
+        assert_eq!(
+            fs::read_to_string(pid_file.as_path()).unwrap(),
+            format!("{}\n", process::id())
+        );
+
+        match write_pid_file(Path::new("/invalid/pid_file")) {
+            Err(Error::PidFile(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
@@ -1,26 +1,42 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 mod api_server_adapter;
+mod daemon;
 mod metrics;
 
 use std::fs;
 use std::io;
 use std::panic;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use logger::{error, info, Metric, LOGGER, METRICS};
+use mmds::MMDS;
 use polly::event_manager::EventManager;
-use seccomp::{BpfProgram, SeccompLevel};
+use seccomp::SeccompLevel;
 use utils::arg_parser::{ArgParser, Argument};
 use utils::terminal::Terminal;
 use utils::validators::validate_instance_id;
-use vmm::default_syscalls::get_seccomp_filter;
+use vmm::audit::AUDIT;
+use vmm::default_syscalls::policy::load_seccomp_filters;
+use vmm::default_syscalls::{get_seccomp_filters, SeccompProfile, ThreadFilters};
+use vmm::landlock::LandlockRules;
+use vmm::lifecycle::LIFECYCLE;
+use vmm::memory_debug::MEMORY_DEBUG;
+use vmm::otel::OTEL;
 use vmm::resources::VmResources;
+use vmm::restore_profile::RESTORE_PROFILES;
+use vmm::restore_trace::RESTORE_TRACE;
+#[cfg(target_arch = "x86_64")]
+use vmm::rpc_interface::PrebootApiController;
 use vmm::signal_handler::register_signal_handlers;
+use vmm::snapshot_signing::SnapshotKeys;
+use vmm::socket_activation::{API_SOCKET_NAME, SOCKET_ACTIVATION};
 use vmm::vmm_config::instance_info::InstanceInfo;
 use vmm::vmm_config::logger::{init_logger, LoggerConfig, LoggerLevel};
+use vmm::vmm_config::snapshot::LoadSnapshotParams;
 
 // The reason we place default API socket under /run is that API socket is a
 // runtime file.
@@ -87,6 +103,23 @@ fn main() {
                      number and argument values) that will be passed to executed path as argument."
                 ),
         )
+        .arg(
+            Argument::new("seccomp-profile")
+                .takes_value(true)
+                .default_value("default")
+                .help(
+                    "Set of seccomp rules to install (default | faasnap: also allows the syscalls used by \
+                     the faasnap snapshot restore paths)."
+                ),
+        )
+        .arg(
+            Argument::new("seccomp-filter")
+                .takes_value(true)
+                .help(
+                    "Path to a compiled BPF program or a JSON seccomp policy replacing the built-in \
+                     filters, regardless of seccomp-level and seccomp-profile."
+                ),
+        )
         .arg(
             Argument::new("start-time-us")
                 .takes_value(true)
@@ -102,12 +135,41 @@ fn main() {
                 .takes_value(true)
                 .help("Path to a file that contains the microVM configuration in JSON format."),
         )
+        .arg(
+            Argument::new("load-snapshot")
+                .takes_value(true)
+                .help("Path to a snapshot load manifest, the JSON body of a PUT /snapshot/load request. \
+                       The microVM is restored from it and resumed at start.")
+        )
+        .arg(
+            Argument::new("snapshot-fds")
+                .takes_value(true)
+                .help("Comma separated list of the inherited file descriptors of the snapshot files, which \
+                       the snapshot loads can refer to. Set by the jailer.")
+        )
+        .arg(
+            Argument::new("metadata")
+                .takes_value(true)
+                .help("Path to a file that contains the MMDS data in JSON format, stored before the microVM \
+                       is started or restored.")
+        )
         .arg(
             Argument::new("no-api")
                 .takes_value(false)
                 .requires("config-file")
                 .help("Optional parameter which allows starting and using a microVM without an active API socket.")
         )
+        .arg(
+            Argument::new("daemonize")
+                .takes_value(false)
+                .help("Detach from the terminal and the parent process, which exits once the daemon is started. \
+                       Stdout and stderr are redirected to the log-path file, or /dev/null.")
+        )
+        .arg(
+            Argument::new("pid-file")
+                .takes_value(true)
+                .help("Path to the file the Firecracker pid is written to, by the daemon when daemonizing.")
+        )
         .arg(
             Argument::new("log-path")
                 .takes_value(true)
@@ -131,6 +193,68 @@ fn main() {
                 .takes_value(false)
                 .requires("log-path")
                 .help("Whether or not to include the file path and line number of the log's origin.")
+        )
+        .arg(
+            Argument::new("audit-log")
+                .takes_value(true)
+                .help("Path to a fifo or a file the snapshot lifecycle audit records are appended to.")
+        )
+        .arg(
+            Argument::new("restore-trace")
+                .takes_value(true)
+                .help("Path to a fifo or a file the snapshot restore timeline events are appended to.")
+        )
+        .arg(
+            Argument::new("restore-profiles")
+                .takes_value(true)
+                .help("Path to a JSON file defining the restore profiles that the snapshot loads can \
+                       select by name.")
+        )
+        .arg(
+            Argument::new("debug-token")
+                .takes_value(true)
+                .help("Path to a file holding the bearer token that enables the guest memory debug \
+                       reads of the API.")
+        )
+        .arg(
+            Argument::new("otel-trace-file")
+                .takes_value(true)
+                .help("Path to a fifo or a file the snapshot operation spans are appended to, in the OTLP JSON format.")
+        )
+        .arg(
+            Argument::new("lifecycle-socket")
+                .takes_value(true)
+                .help("Path to the Unix socket of the scheduler the lifecycle events are sent to, such as \
+                       the guest resume.")
+        )
+        .arg(
+            Argument::new("lifecycle-ack-timeout-ms")
+                .takes_value(true)
+                .requires("lifecycle-socket")
+                .help("Milliseconds to wait for the scheduler to acknowledge the lifecycle events emitted \
+                       while the guest is paused.")
+        )
+        .arg(
+            Argument::new("snapshot-signing-key")
+                .takes_value(true)
+                .help("Path to the raw ed25519 secret key used to sign the created snapshots.")
+        )
+        .arg(
+            Argument::new("snapshot-verification-key")
+                .takes_value(true)
+                .help("Path to the raw ed25519 public key used to verify the snapshots before loading them.")
+        )
+        .arg(
+            Argument::new("landlock-read-only")
+                .takes_value(true)
+                .help("Comma separated list of files or directories that can only be read once the \
+                       landlock sandbox is enforced, such as the snapshot files.")
+        )
+        .arg(
+            Argument::new("landlock-read-write")
+                .takes_value(true)
+                .help("Comma separated list of files or directories that can be read and written once \
+                       the landlock sandbox is enforced, such as the snapshot dump directories.")
         );
 
     let arguments = match arg_parser.parse_from_cmdline() {
@@ -166,6 +290,21 @@ fn main() {
     let instance_id = arguments.value_as_string("id").unwrap();
     validate_instance_id(instance_id.as_str()).expect("Invalid instance ID");
 
+    if let Some(fds) = arguments.value_as_string("snapshot-fds") {
+        let fds = fds
+            .split(',')
+            .map(|fd| fd.parse::<i32>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .unwrap_or_else(|err| {
+                error!("Invalid snapshot file descriptor: {}", err);
+                process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+            });
+        #[cfg(target_arch = "x86_64")]
+        vmm::persist::declare_snapshot_fds(&fds);
+        #[cfg(not(target_arch = "x86_64"))]
+        let _ = fds;
+    }
+
     let instance_info = InstanceInfo {
         id: instance_id.clone(),
         started: false,
@@ -173,6 +312,20 @@ fn main() {
         app_name: "Firecracker".to_string(),
     };
 
+    let pid_file = arguments.value_as_string("pid-file").map(PathBuf::from);
+    if arguments.value_as_bool("daemonize").unwrap_or(false) {
+        let log_path = arguments.value_as_string("log-path").map(PathBuf::from);
+        daemon::daemonize(log_path.as_deref(), pid_file.as_deref()).unwrap_or_else(|err| {
+            error!("Could not daemonize: {}", err);
+            process::exit(i32::from(vmm::FC_EXIT_CODE_GENERIC_ERROR));
+        });
+    } else if let Some(pid_file) = pid_file {
+        daemon::write_pid_file(&pid_file).unwrap_or_else(|err| {
+            error!("Could not write the pid file: {}", err);
+            process::exit(i32::from(vmm::FC_EXIT_CODE_GENERIC_ERROR));
+        });
+    }
+
     LOGGER.set_instance_id(instance_id);
 
     if let Some(log) = arguments.value_as_string("log-path") {
@@ -197,23 +350,155 @@ fn main() {
         });
     }
 
-    // It's safe to unwrap here because the field's been provided with a default value.
-    let seccomp_level = arguments.value_as_string("seccomp-level").unwrap();
-    let seccomp_filter = get_seccomp_filter(
-        SeccompLevel::from_string(seccomp_level).unwrap_or_else(|err| {
-            panic!("Invalid value for seccomp-level: {}", err);
-        }),
-    )
-    .unwrap_or_else(|err| {
-        panic!("Could not create seccomp filter: {}", err);
+    if let Some(audit_log) = arguments.value_as_string("audit-log") {
+        AUDIT
+            .init(Path::new(&audit_log), &instance_info.id)
+            .unwrap_or_else(|err| {
+                error!("Could not initialize the audit log: {}", err);
+                process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+            });
+    }
+
+    if let Some(restore_trace) = arguments.value_as_string("restore-trace") {
+        RESTORE_TRACE
+            .init(Path::new(&restore_trace), &instance_info.id)
+            .unwrap_or_else(|err| {
+                error!("Could not initialize the restore trace: {}", err);
+                process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+            });
+    }
+
+    if let Some(restore_profiles) = arguments.value_as_string("restore-profiles") {
+        RESTORE_PROFILES
+            .init(Path::new(&restore_profiles))
+            .unwrap_or_else(|err| {
+                error!("Could not load the restore profiles: {}", err);
+                process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+            });
+    }
+
+    if let Some(debug_token) = arguments.value_as_string("debug-token") {
+        MEMORY_DEBUG
+            .init(Path::new(&debug_token))
+            .unwrap_or_else(|err| {
+                error!("Could not set the debug token: {}", err);
+                process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+            });
+    }
+
+    if let Some(otel_trace_file) = arguments.value_as_string("otel-trace-file") {
+        OTEL.init(Path::new(&otel_trace_file), &instance_info.id)
+            .unwrap_or_else(|err| {
+                error!("Could not initialize the span exporter: {}", err);
+                process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+            });
+    }
+
+    if let Some(lifecycle_socket) = arguments.value_as_string("lifecycle-socket") {
+        let ack_timeout = arguments
+            .value_as_string("lifecycle-ack-timeout-ms")
+            .map(|s| {
+                s.parse::<u64>()
+                    .expect("'lifecycle-ack-timeout-ms' parameter expected to be of 'u64' type.")
+            })
+            .map(Duration::from_millis);
+        LIFECYCLE
+            .init(Path::new(&lifecycle_socket), &instance_info.id, ack_timeout)
+            .unwrap_or_else(|err| {
+                error!("Could not initialize the lifecycle notifications: {}", err);
+                process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+            });
+    }
+
+    SOCKET_ACTIVATION.init().unwrap_or_else(|err| {
+        error!("Could not take the activated sockets: {}", err);
+        process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
     });
 
+    let seccomp_filters = match arguments.value_as_string("seccomp-filter") {
+        Some(seccomp_filter) => {
+            load_seccomp_filters(Path::new(&seccomp_filter)).unwrap_or_else(|err| {
+                error!("Could not load the seccomp filter: {}", err);
+                process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+            })
+        }
+        None => {
+            // It's safe to unwrap here because the field's been provided with a default value.
+            let seccomp_level = arguments.value_as_string("seccomp-level").unwrap();
+            let seccomp_profile = arguments.value_as_string("seccomp-profile").unwrap();
+            get_seccomp_filters(
+                SeccompLevel::from_string(seccomp_level).unwrap_or_else(|err| {
+                    panic!("Invalid value for seccomp-level: {}", err);
+                }),
+                SeccompProfile::from_string(&seccomp_profile).unwrap_or_else(|err| {
+                    panic!("Invalid value for seccomp-profile: {}", err);
+                }),
+            )
+            .unwrap_or_else(|err| {
+                panic!("Could not create seccomp filter: {}", err);
+            })
+        }
+    };
+
     let vmm_config_json = arguments
         .value_as_string("config-file")
         .map(fs::read_to_string)
         .map(|x| x.expect("Unable to open or read from the configuration file"));
 
+    let load_params = arguments
+        .value_as_string("load-snapshot")
+        .map(|path| read_load_manifest(Path::new(&path)));
+    if load_params.is_some() && vmm_config_json.is_some() {
+        error!("The load-snapshot and config-file parameters are mutually exclusive.");
+        process::exit(i32::from(vmm::FC_EXIT_CODE_ARG_PARSING));
+    }
+
+    if let Some(metadata) = arguments.value_as_string("metadata") {
+        init_mmds_data(Path::new(&metadata));
+    }
+
     let api_enabled = !arguments.value_as_bool("no-api").unwrap_or(false);
+    // The activated API socket is served instead of binding `api-sock`.
+    let api_listener = SOCKET_ACTIVATION.take(API_SOCKET_NAME);
+
+    let signing_key = arguments
+        .value_as_string("snapshot-signing-key")
+        .map(PathBuf::from);
+    let verification_key = arguments
+        .value_as_string("snapshot-verification-key")
+        .map(PathBuf::from);
+    let snapshot_keys =
+        SnapshotKeys::from_files(signing_key.as_deref(), verification_key.as_deref())
+            .unwrap_or_else(|err| {
+                error!("Could not load the snapshot keys: {}", err);
+                process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+            });
+
+    let landlock_read_only = arguments.value_as_string("landlock-read-only");
+    let landlock_read_write = arguments.value_as_string("landlock-read-write");
+    if landlock_read_only.is_some() || landlock_read_write.is_some() {
+        let mut rules = LandlockRules {
+            read_only: split_paths(landlock_read_only),
+            read_write: split_paths(landlock_read_write),
+        };
+        // The VMM always needs KVM and, when enabled, to create the API socket.
+        rules.read_write.push(PathBuf::from("/dev/kvm"));
+        if api_enabled && api_listener.is_none() {
+            let api_sock = arguments
+                .value_as_string("api-sock")
+                .map(PathBuf::from)
+                .expect("Missing argument: api-sock");
+            let api_sock_dir = api_sock
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new("."));
+            rules.read_write.push(api_sock_dir.to_path_buf());
+        }
+        rules.restrict_self().unwrap_or_else(|err| {
+            error!("Could not enforce the landlock sandbox: {}", err);
+            process::exit(i32::from(vmm::FC_EXIT_CODE_GENERIC_ERROR));
+        });
+    }
 
     if api_enabled {
         let bind_path = arguments
@@ -231,33 +516,134 @@ fn main() {
                 .expect("'start-time-cpu-us' parameter expected to be of 'u64' type.")
         });
         api_server_adapter::run_with_api(
-            seccomp_filter,
+            seccomp_filters,
+            snapshot_keys,
             vmm_config_json,
+            load_params,
             bind_path,
+            api_listener,
             instance_info,
             start_time_us,
             start_time_cpu_us,
         );
     } else {
-        run_without_api(seccomp_filter, vmm_config_json, &instance_info);
+        run_without_api(
+            seccomp_filters,
+            snapshot_keys,
+            vmm_config_json,
+            &instance_info,
+        );
     }
 }
 
-// Configure and start a microVM as described by the command-line JSON.
-fn build_microvm_from_json(
-    seccomp_filter: BpfProgram,
+// Splits a comma separated list of paths.
+fn split_paths(paths: Option<String>) -> Vec<PathBuf> {
+    paths
+        .map(|paths| {
+            paths
+                .split(',')
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Stores the JSON of the file at `path` as the MMDS data, as a PUT /mmds request would.
+fn init_mmds_data(path: &Path) {
+    let metadata = fs::read_to_string(path).unwrap_or_else(|err| {
+        error!("Unable to read the MMDS data file: {}", err);
+        process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+    });
+    let data = serde_json::from_str(&metadata).unwrap_or_else(|err| {
+        error!("Invalid MMDS data file: {}", err);
+        process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+    });
+    MMDS.lock()
+        .expect("Failed to acquire lock on MMDS info")
+        .put_data(data)
+        .unwrap_or_else(|err| {
+            error!("Could not store the MMDS data: {}", err);
+            process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+        });
+}
+
+// Reads the snapshot load manifest at `path`.
+fn read_load_manifest(path: &Path) -> LoadSnapshotParams {
+    if cfg!(not(target_arch = "x86_64")) {
+        error!("Loading snapshots is only supported on x86_64.");
+        process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+    }
+    let manifest = fs::read_to_string(path).unwrap_or_else(|err| {
+        error!("Unable to read the snapshot load manifest: {}", err);
+        process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+    });
+    serde_json::from_str(&manifest).unwrap_or_else(|err| {
+        error!("Invalid snapshot load manifest: {}", err);
+        process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+    })
+}
+
+// Restore and resume a microVM as described by the command-line snapshot load parameters.
+#[cfg(target_arch = "x86_64")]
+fn build_microvm_from_snapshot(
+    seccomp_filters: ThreadFilters,
+    snapshot_keys: Arc<SnapshotKeys>,
     event_manager: &mut EventManager,
-    config_json: String,
+    load_params: &LoadSnapshotParams,
     instance_info: &InstanceInfo,
 ) -> (VmResources, Arc<Mutex<vmm::Vmm>>) {
-    let vm_resources = VmResources::from_json(&config_json, instance_info).unwrap_or_else(|err| {
+    let (vm_resources, vmm) = PrebootApiController::build_microvm_from_snapshot(
+        seccomp_filters,
+        snapshot_keys,
+        event_manager,
+        instance_info.clone(),
+        load_params,
+    )
+    .unwrap_or_else(|err| {
         error!(
-            "Configuration for VMM from one single json failed: {:?}",
+            "Restoring the microVM from the cmdline snapshot failed: {}",
             err
         );
         process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
     });
-    let vmm = vmm::builder::build_microvm_for_boot(&vm_resources, event_manager, &seccomp_filter)
+    info!("Successfully restored and resumed microvm from the cmdline snapshot");
+
+    (vm_resources, vmm)
+}
+
+// Configure and start a microVM as described by the command-line JSON.
+fn build_microvm_from_json(
+    seccomp_filters: ThreadFilters,
+    snapshot_keys: Arc<SnapshotKeys>,
+    event_manager: &mut EventManager,
+    config_json: String,
+    instance_info: &InstanceInfo,
+) -> (VmResources, Arc<Mutex<vmm::Vmm>>) {
+    let (vm_resources, load_params) = VmResources::from_json(&config_json, instance_info)
+        .unwrap_or_else(|err| {
+            error!(
+                "Configuration for VMM from one single json failed: {:?}",
+                err
+            );
+            process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+        });
+    if let Some(load_params) = load_params {
+        #[cfg(target_arch = "x86_64")]
+        return build_microvm_from_snapshot(
+            seccomp_filters,
+            snapshot_keys,
+            event_manager,
+            &load_params,
+            instance_info,
+        );
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            error!("Loading snapshots is only supported on x86_64.");
+            process::exit(i32::from(vmm::FC_EXIT_CODE_BAD_CONFIGURATION));
+        }
+    }
+    let vmm = vmm::builder::build_microvm_for_boot(&vm_resources, event_manager, &seccomp_filters)
         .unwrap_or_else(|err| {
             error!(
                 "Building VMM configured from cmdline json failed: {:?}",
@@ -271,7 +657,8 @@ fn build_microvm_from_json(
 }
 
 fn run_without_api(
-    seccomp_filter: BpfProgram,
+    seccomp_filters: ThreadFilters,
+    snapshot_keys: SnapshotKeys,
     config_json: Option<String>,
     instance_info: &InstanceInfo,
 ) {
@@ -287,7 +674,8 @@ fn run_without_api(
     // - VmResources is not used without api,
     // - An `Arc` reference of the built `Vmm` is plugged in the `EventManager` by the builder.
     build_microvm_from_json(
-        seccomp_filter,
+        seccomp_filters,
+        Arc::new(snapshot_keys),
         &mut event_manager,
         // Safe to unwrap since '--no-api' requires this to be set.
         config_json.unwrap(),
@@ -3,7 +3,7 @@
 
 use std::env;
 use std::ffi::CStr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ptr::null;
 
 use super::{to_cstring, Error, Result};
@@ -13,9 +13,19 @@ const OLD_ROOT_DIR_NAME_NUL_TERMINATED: &[u8] = b"old_root\0";
 const ROOT_DIR_NUL_TERMINATED: &[u8] = b"/\0";
 const CURRENT_DIR_NUL_TERMINATED: &[u8] = b".\0";
 
+// A file or directory from outside the jail, bind mounted inside it.
+#[derive(Debug, PartialEq)]
+pub struct BindMount {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub read_only: bool,
+}
+
 // This uses switching to a new mount namespace + pivot_root(), together with the regular chroot,
 // to provide a hardened jail (at least compared to only relying on chroot).
-pub fn chroot(path: &Path) -> Result<()> {
+// Each of the `bind_mounts` is bind mounted in the new mount namespace before pivoting, so the
+// sources stay reachable from inside the jail.
+pub fn chroot(path: &Path, bind_mounts: &[BindMount]) -> Result<()> {
     // We unshare into a new mount namespace. The call is safe because we're invoking a C library
     // function with valid parameters.
     SyscallReturnCode(unsafe { libc::unshare(libc::CLONE_NEWNS) })
@@ -39,6 +49,40 @@ pub fn chroot(path: &Path) -> Result<()> {
     .into_empty_result()
     .map_err(Error::MountPropagationSlave)?;
 
+    for bind_mount in bind_mounts {
+        let source_cstr = to_cstring(&bind_mount.source)?;
+        let target_cstr = to_cstring(&bind_mount.target)?;
+        // Safe because we provide valid parameters.
+        SyscallReturnCode(unsafe {
+            libc::mount(
+                source_cstr.as_ptr(),
+                target_cstr.as_ptr(),
+                null(),
+                libc::MS_BIND,
+                null(),
+            )
+        })
+        .into_empty_result()
+        .map_err(|e| Error::BindMountArtifact(bind_mount.source.clone(), e))?;
+
+        if bind_mount.read_only {
+            // A bind mount only becomes read-only when remounted. The mount is shared with the
+            // files outside the jail, so this keeps the jailed process from writing to them.
+            // Safe because we provide valid parameters.
+            SyscallReturnCode(unsafe {
+                libc::mount(
+                    null(),
+                    target_cstr.as_ptr(),
+                    null(),
+                    libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY,
+                    null(),
+                )
+            })
+            .into_empty_result()
+            .map_err(|e| Error::BindMountArtifact(bind_mount.source.clone(), e))?;
+        }
+    }
+
     // We need a CString for the following mount call.
     let chroot_dir = to_cstring(path)?;
 
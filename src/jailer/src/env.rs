@@ -3,15 +3,15 @@
 
 use std::ffi::{CStr, OsString};
 use std::fs::{self, canonicalize, File, Permissions};
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::os::unix::io::IntoRawFd;
 use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
 use crate::cgroup::Cgroup;
-use crate::chroot::chroot;
-use crate::{Error, Result};
+use crate::chroot::{chroot, BindMount};
+use crate::{to_cstring, Error, Result};
 use utils::arg_parser::Error::MissingValue;
 use utils::syscall::SyscallReturnCode;
 use utils::{arg_parser, validators};
@@ -43,6 +43,18 @@ const DEV_NULL_WITH_NUL: &[u8] = b"/dev/null\0";
 const FOLDER_HIERARCHY: [&[u8]; 4] = [b"/\0", b"/dev\0", b"/dev/net\0", b"/run\0"];
 const FOLDER_PERMISSIONS: u32 = 0o700;
 
+// Snapshot artifacts that can be staged inside the jail, and the uffd socket directory.
+// When the snapshot files are passed as fds instead, each one is inherited by the exec-ed
+// binary on the listed fd number.
+const SNAPSHOT_FILE_ARGS: [(&str, libc::c_int); 3] = [
+    ("snapshot-mem-file", 3),
+    ("snapshot-overlay-file", 4),
+    ("snapshot-ws-file", 5),
+];
+const UFFD_SOCK_DIR_ARG: &str = "uffd-sock-dir";
+// Network namespace the exec-ed binary restores its snapshot into, inherited on this fd number.
+const RESTORE_NETNS_FD: libc::c_int = 6;
+
 // Helper function, since we'll use libc::dup2 a bunch of times for daemonization.
 fn dup2(old_fd: libc::c_int, new_fd: libc::c_int) -> Result<()> {
     // This is safe because we are using a library function with valid parameters.
@@ -59,10 +71,14 @@ pub struct Env {
     uid: u32,
     gid: u32,
     netns: Option<String>,
+    restore_netns: Option<PathBuf>,
     daemonize: bool,
     start_time_us: u64,
     start_time_cpu_us: u64,
     extra_args: Vec<String>,
+    snapshot_files: Vec<(PathBuf, libc::c_int)>,
+    snapshot_pass_fds: bool,
+    uffd_sock_dir: Option<PathBuf>,
 }
 
 impl Env {
@@ -125,9 +141,40 @@ impl Env {
         let gid = gid_str.parse::<u32>().map_err(|_| Error::Gid(gid_str))?;
 
         let netns = arguments.value_as_string("netns");
+        let restore_netns = arguments
+            .value_as_string("restore-netns")
+            .map(PathBuf::from);
 
         let daemonize = arguments.value_as_bool("daemonize").unwrap_or(false);
 
+        let mut snapshot_files = Vec::new();
+        for (arg, fd) in SNAPSHOT_FILE_ARGS.iter() {
+            if let Some(file) = arguments.value_as_string(*arg) {
+                let file_path = canonicalize(&file)
+                    .map_err(|e| Error::Canonicalize(PathBuf::from(&file), e))?;
+                if !file_path.is_file() {
+                    return Err(Error::NotAFile(file_path));
+                }
+                snapshot_files.push((file_path, *fd));
+            }
+        }
+
+        let snapshot_pass_fds = arguments
+            .value_as_bool("snapshot-pass-fds")
+            .unwrap_or(false);
+
+        let uffd_sock_dir = match arguments.value_as_string(UFFD_SOCK_DIR_ARG) {
+            Some(dir) => {
+                let dir_path =
+                    canonicalize(&dir).map_err(|e| Error::Canonicalize(PathBuf::from(&dir), e))?;
+                if !dir_path.is_dir() {
+                    return Err(Error::NotADirectory(dir_path));
+                }
+                Some(dir_path)
+            }
+            None => None,
+        };
+
         Ok(Env {
             id,
             numa_node,
@@ -136,10 +183,14 @@ impl Env {
             uid,
             gid,
             netns,
+            restore_netns,
             daemonize,
             start_time_us,
             start_time_cpu_us,
             extra_args: arguments.extra_args(),
+            snapshot_files,
+            snapshot_pass_fds,
+            uffd_sock_dir,
         })
     }
 
@@ -230,6 +281,150 @@ impl Env {
         Ok(exec_file_name.to_os_string())
     }
 
+    // Snapshot files that are staged inside the jail, as opposed to passed as fds.
+    fn staged_snapshot_files(&self) -> impl Iterator<Item = &(PathBuf, libc::c_int)> {
+        let staged = if self.snapshot_pass_fds {
+            &self.snapshot_files[..0]
+        } else {
+            &self.snapshot_files[..]
+        };
+        staged.iter()
+    }
+
+    // Opens the snapshot files outside the jail and moves them to their fixed fd numbers,
+    // without the close-on-exec flag, so they are inherited by the exec-ed binary. Must be
+    // called while no fd above stderr is open, so that the fixed fd numbers are free.
+    fn open_snapshot_fds(&self) -> Result<()> {
+        if !self.snapshot_pass_fds {
+            return Ok(());
+        }
+
+        for (file_path, target_fd) in self.snapshot_files.iter() {
+            let file_path_cstr = to_cstring(file_path)?;
+            // Safe because we provide a valid, null terminated path and check the result.
+            let fd =
+                SyscallReturnCode(unsafe { libc::open(file_path_cstr.as_ptr(), libc::O_RDONLY) })
+                    .into_result()
+                    .map_err(|e| Error::FileOpen(file_path.clone(), e))?;
+
+            if fd != *target_fd {
+                dup2(fd, *target_fd)?;
+                // Safe because we own the fd and check the result.
+                SyscallReturnCode(unsafe { libc::close(fd) })
+                    .into_empty_result()
+                    .map_err(|e| Error::CloseSnapshotFd(file_path.clone(), e))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Opens the network namespace the snapshot is restored into, outside the jail, and moves it
+    // to RESTORE_NETNS_FD without the close-on-exec flag. The jailer and the exec-ed binary stay
+    // in the namespace joined with `netns`, and only the TAP devices of the restored microVM are
+    // opened in this one. Must be called while RESTORE_NETNS_FD is free.
+    fn open_restore_netns_fd(&self) -> Result<()> {
+        let path = match self.restore_netns.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let path_cstr = to_cstring(path)?;
+        // Safe because we provide a valid, null terminated path and check the result.
+        let fd = SyscallReturnCode(unsafe { libc::open(path_cstr.as_ptr(), libc::O_RDONLY) })
+            .into_result()
+            .map_err(|e| Error::FileOpen(path.clone(), e))?;
+
+        if fd != RESTORE_NETNS_FD {
+            dup2(fd, RESTORE_NETNS_FD)?;
+            // Safe because we own the fd and check the result.
+            SyscallReturnCode(unsafe { libc::close(fd) })
+                .into_empty_result()
+                .map_err(Error::CloseNetNsFd)?;
+        }
+
+        Ok(())
+    }
+
+    // Checks that the jailed user has the `mode` permissions (a combination of the read, write
+    // and execute bits, 0o4, 0o2 and 0o1) on `path`, going by its owner, group and other bits.
+    // The artifacts are shared with the host and the other jails, so they are not handed over
+    // to the jailed user: the host has to make them accessible to it.
+    fn check_artifact_access(&self, path: &Path, mode: u32) -> Result<()> {
+        if self.uid() == 0 {
+            return Ok(());
+        }
+        let metadata = fs::metadata(path).map_err(|e| Error::FileOpen(path.to_path_buf(), e))?;
+        let bits = if metadata.uid() == self.uid() {
+            metadata.mode() >> 6
+        } else if metadata.gid() == self.gid() {
+            metadata.mode() >> 3
+        } else {
+            metadata.mode()
+        };
+        if bits & mode != mode {
+            return Err(Error::ArtifactAccess(
+                path.to_path_buf(),
+                self.uid(),
+                self.gid(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Makes the snapshot artifacts visible at the root of the jail, under their own names.
+    // Files are hard linked when they live on the same filesystem as the jail. Otherwise, and for
+    // the uffd socket directory, an empty placeholder is created and the mounts that need to be
+    // made once in the jail mount namespace are returned. The snapshot files are mounted
+    // read-only, while the uffd socket directory stays writable so that the socket can be created.
+    fn stage_snapshot_artifacts(&self) -> Result<Vec<BindMount>> {
+        // Check all the artifacts before staging any of them.
+        for (file_path, _) in self.staged_snapshot_files() {
+            self.check_artifact_access(file_path, 0o4)?;
+        }
+        if let Some(ref dir_path) = self.uffd_sock_dir {
+            self.check_artifact_access(dir_path, 0o3)?;
+        }
+
+        let mut bind_mounts = Vec::new();
+        for (file_path, _) in self.staged_snapshot_files() {
+            let file_name = file_path
+                .file_name()
+                .ok_or_else(|| Error::FileName(file_path.clone()))?;
+            let jailed_path = self.chroot_dir.join(file_name);
+
+            match fs::hard_link(file_path, &jailed_path) {
+                Ok(()) => (),
+                Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                    File::create(&jailed_path)
+                        .map_err(|e| Error::FileOpen(jailed_path.clone(), e))?;
+                    bind_mounts.push(BindMount {
+                        source: file_path.clone(),
+                        target: jailed_path,
+                        read_only: true,
+                    });
+                }
+                Err(e) => return Err(Error::HardLink(file_path.clone(), jailed_path, e)),
+            }
+        }
+
+        if let Some(ref dir_path) = self.uffd_sock_dir {
+            let dir_name = dir_path
+                .file_name()
+                .ok_or_else(|| Error::FileName(dir_path.clone()))?;
+            let jailed_path = self.chroot_dir.join(dir_name);
+
+            fs::create_dir_all(&jailed_path)
+                .map_err(|e| Error::CreateDir(jailed_path.clone(), e))?;
+            bind_mounts.push(BindMount {
+                source: dir_path.clone(),
+                target: jailed_path,
+                read_only: false,
+            });
+        }
+
+        Ok(bind_mounts)
+    }
+
     fn join_netns(path: &str) -> Result<()> {
         // This will take ownership of the raw fd.
         // TODO: for some reason, if we use as_raw_fd here instead, the resulting fd cannot
@@ -251,6 +446,10 @@ impl Env {
     }
 
     pub fn run(mut self) -> Result<()> {
+        // Do this first, while the fds the snapshot files are passed on are still free.
+        self.open_snapshot_fds()?;
+        self.open_restore_netns_fd()?;
+
         let exec_file_name = self.copy_exec_to_chroot()?;
         let chroot_exec_file = PathBuf::from("/").join(&exec_file_name);
 
@@ -280,8 +479,10 @@ impl Env {
             None
         };
 
+        let bind_mounts = self.stage_snapshot_artifacts()?;
+
         // Jail self.
-        chroot(self.chroot_dir())?;
+        chroot(self.chroot_dir(), &bind_mounts)?;
 
         // This will not only create necessary directories, but will also change ownership
         // for all of them.
@@ -319,8 +520,18 @@ impl Env {
                 .map_err(Error::CloseDevNullFd)?;
         }
 
+        let mut command = Command::new(chroot_exec_file);
+        if self.snapshot_pass_fds && !self.snapshot_files.is_empty() {
+            let fds: Vec<String> = self
+                .snapshot_files
+                .iter()
+                .map(|(_, fd)| fd.to_string())
+                .collect();
+            command.args(&["--snapshot-fds", &fds.join(",")]);
+        }
+
         Err(Error::Exec(
-            Command::new(chroot_exec_file)
+            command
                 .args(&["--id", &self.id])
                 .args(&["--start-time-us", &self.start_time_us.to_string()])
                 .args(&["--start-time-cpu-us", &self.start_time_cpu_us.to_string()])
@@ -676,6 +887,112 @@ mod tests {
         fs::remove_dir_all(env.chroot_dir()).expect("Could not remove dir hierarchy.");
     }
 
+    #[test]
+    fn test_stage_snapshot_artifacts() {
+        let arg_parser = build_arg_parser();
+        let mut args = arg_parser.arguments().clone();
+
+        let exec_file = TempFile::new_with_prefix("/tmp/").unwrap();
+        let mem_file = TempFile::new_with_prefix("/tmp/").unwrap();
+        let mem_file_name = mem_file.as_path().file_name().unwrap();
+        let uffd_sock_dir = TempDir::new().unwrap();
+        let uffd_sock_dir_name = uffd_sock_dir.as_path().file_name().unwrap();
+        let chroot_base = TempDir::new().unwrap();
+
+        let arg_vals = ArgVals {
+            exec_file: exec_file.as_path().to_str().unwrap(),
+            chroot_base: chroot_base.as_path().to_str().unwrap(),
+            netns: None,
+            daemonize: false,
+            ..ArgVals::new()
+        };
+        let mut arg_vec = make_args(&arg_vals);
+
+        // A directory is not a valid snapshot file.
+        let mut bad_arg_vec = arg_vec.clone();
+        bad_arg_vec.push("--snapshot-mem-file".to_string());
+        bad_arg_vec.push(uffd_sock_dir.as_path().to_str().unwrap().to_string());
+        args.parse(&bad_arg_vec).unwrap();
+        assert_eq!(
+            format!("{}", Env::new(&args, 0, 0).err().unwrap()),
+            format!(
+                "{} is not a file",
+                uffd_sock_dir.as_path().to_str().unwrap()
+            )
+        );
+
+        arg_vec.push("--snapshot-mem-file".to_string());
+        arg_vec.push(mem_file.as_path().to_str().unwrap().to_string());
+        arg_vec.push("--uffd-sock-dir".to_string());
+        arg_vec.push(uffd_sock_dir.as_path().to_str().unwrap().to_string());
+        let mut args = arg_parser.arguments().clone();
+        args.parse(&arg_vec).unwrap();
+        let env = Env::new(&args, 0, 0).unwrap();
+        fs::create_dir_all(env.chroot_dir()).expect("Could not create dir hierarchy.");
+
+        // The artifacts are not accessible to the jailed user, and are not re-owned for it.
+        assert_eq!(
+            format!("{}", env.stage_snapshot_artifacts().err().unwrap()),
+            format!(
+                "The jailed user 1001:1002 lacks the permissions on {} to stage it",
+                mem_file.as_path().to_str().unwrap()
+            )
+        );
+        fs::set_permissions(mem_file.as_path(), Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(
+            format!("{}", env.stage_snapshot_artifacts().err().unwrap()),
+            format!(
+                "The jailed user 1001:1002 lacks the permissions on {} to stage it",
+                uffd_sock_dir.as_path().to_str().unwrap()
+            )
+        );
+        assert!(!env.chroot_dir().join(mem_file_name).exists());
+        fs::set_permissions(uffd_sock_dir.as_path(), Permissions::from_mode(0o733)).unwrap();
+
+        let bind_mounts = env.stage_snapshot_artifacts().unwrap();
+        assert_eq!(fs::metadata(mem_file.as_path()).unwrap().st_uid(), 0);
+
+        // The memory file lives on the same filesystem, so it is hard linked.
+        let jailed_mem_file = env.chroot_dir().join(mem_file_name);
+        assert_eq!(
+            fs::metadata(&jailed_mem_file).unwrap().st_ino(),
+            fs::metadata(mem_file.as_path()).unwrap().st_ino()
+        );
+
+        // The uffd socket directory is bind mounted over a placeholder.
+        let jailed_uffd_sock_dir = env.chroot_dir().join(uffd_sock_dir_name);
+        assert!(jailed_uffd_sock_dir.is_dir());
+        assert_eq!(
+            bind_mounts,
+            vec![BindMount {
+                source: uffd_sock_dir.as_path().to_path_buf(),
+                target: jailed_uffd_sock_dir,
+                read_only: false,
+            }]
+        );
+
+        // Staging twice fails, since the memory file is already in the jail.
+        assert!(env.stage_snapshot_artifacts().is_err());
+
+        fs::remove_dir_all(env.chroot_dir()).expect("Could not remove dir hierarchy.");
+
+        // When passed as fds, the snapshot files are not staged inside the jail.
+        arg_vec.push("--snapshot-pass-fds".to_string());
+        let mut args = arg_parser.arguments().clone();
+        args.parse(&arg_vec).unwrap();
+        let env = Env::new(&args, 0, 0).unwrap();
+        assert_eq!(
+            env.snapshot_files,
+            vec![(mem_file.as_path().to_path_buf(), 3)]
+        );
+        fs::create_dir_all(env.chroot_dir()).expect("Could not create dir hierarchy.");
+
+        assert_eq!(env.stage_snapshot_artifacts().unwrap().len(), 1);
+        assert!(!jailed_mem_file.exists());
+
+        fs::remove_dir_all(env.chroot_dir()).expect("Could not remove dir hierarchy.");
+    }
+
     #[test]
     fn test_join_netns() {
         let mut path = "invalid_path";
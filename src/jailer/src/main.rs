@@ -20,6 +20,8 @@ const JAILER_VERSION: &str = env!("CARGO_PKG_VERSION");
 #[derive(Debug)]
 pub enum Error {
     ArgumentParsing(ParsingError),
+    ArtifactAccess(PathBuf, u32, u32),
+    BindMountArtifact(PathBuf, io::Error),
     Canonicalize(PathBuf, io::Error),
     CgroupInheritFromParent(PathBuf, String),
     CgroupLineNotFound(String, String),
@@ -29,6 +31,7 @@ pub enum Error {
     Chmod(PathBuf, io::Error),
     CloseNetNsFd(io::Error),
     CloseDevNullFd(io::Error),
+    CloseSnapshotFd(PathBuf, io::Error),
     Copy(PathBuf, PathBuf, io::Error),
     CreateDir(PathBuf, io::Error),
     CStringParsing(NulError),
@@ -39,6 +42,7 @@ pub enum Error {
     FromBytesWithNul(std::ffi::FromBytesWithNulError),
     GetOldFdFlags(io::Error),
     Gid(String),
+    HardLink(PathBuf, PathBuf, io::Error),
     InvalidInstanceId(validators::Error),
     MissingParent(PathBuf),
     MkdirOldRoot(io::Error),
@@ -72,6 +76,21 @@ impl fmt::Display for Error {
 
         match *self {
             ArgumentParsing(ref err) => write!(f, "Failed to parse arguments: {}", err),
+            ArtifactAccess(ref path, uid, gid) => write!(
+                f,
+                "{}",
+                format!(
+                    "The jailed user {}:{} lacks the permissions on {:?} to stage it",
+                    uid, gid, path
+                )
+                .replace("\"", "")
+            ),
+            BindMountArtifact(ref path, ref err) => write!(
+                f,
+                "{}",
+                format!("Failed to bind mount {:?} inside the jail: {}", path, err)
+                    .replace("\"", "")
+            ),
             Canonicalize(ref path, ref io_err) => write!(
                 f,
                 "{}",
@@ -105,6 +124,11 @@ impl fmt::Display for Error {
             ChdirNewRoot(ref err) => write!(f, "Failed to chdir into chroot directory: {}", err),
             CloseNetNsFd(ref err) => write!(f, "Failed to close netns fd: {}", err),
             CloseDevNullFd(ref err) => write!(f, "Failed to close /dev/null fd: {}", err),
+            CloseSnapshotFd(ref path, ref err) => write!(
+                f,
+                "{}",
+                format!("Failed to close the original fd of {:?}: {}", path, err).replace("\"", "")
+            ),
             Copy(ref file, ref path, ref err) => write!(
                 f,
                 "{}",
@@ -133,6 +157,11 @@ impl fmt::Display for Error {
             }
             GetOldFdFlags(ref err) => write!(f, "Failed to get flags from fd: {}", err),
             Gid(ref gid) => write!(f, "Invalid gid: {}", gid),
+            HardLink(ref file, ref path, ref err) => write!(
+                f,
+                "{}",
+                format!("Failed to hard link {:?} to {:?}: {}", file, path, err).replace("\"", "")
+            ),
             InvalidInstanceId(ref err) => write!(f, "Invalid instance ID: {}", err),
             MissingParent(ref path) => write!(
                 f,
@@ -257,10 +286,38 @@ pub fn build_arg_parser() -> ArgParser<'static> {
                 .takes_value(true)
                 .help("Path to the network namespace this microVM should join."),
         )
+        .arg(Argument::new("restore-netns").takes_value(true).help(
+            "Path to the network namespace the snapshot is restored into, passed to the exec \
+             file as fd 6 for the netns_fd field of the snapshot load request.",
+        ))
         .arg(Argument::new("daemonize").takes_value(false).help(
             "Daemonize the jailer before exec, by invoking setsid(), and redirecting \
              the standard I/O file descriptors to /dev/null.",
         ))
+        .arg(
+            Argument::new("snapshot-mem-file")
+                .takes_value(true)
+                .help("Snapshot memory file to stage inside the jail."),
+        )
+        .arg(
+            Argument::new("snapshot-overlay-file")
+                .takes_value(true)
+                .help("Snapshot overlay file to stage inside the jail."),
+        )
+        .arg(
+            Argument::new("snapshot-ws-file")
+                .takes_value(true)
+                .help("Snapshot working set file to stage inside the jail."),
+        )
+        .arg(Argument::new("snapshot-pass-fds").takes_value(false).help(
+            "Open the snapshot files outside the jail and pass them to the exec file as fds 3 \
+             (memory), 4 (overlay) and 5 (working set), instead of staging them inside the jail.",
+        ))
+        .arg(
+            Argument::new("uffd-sock-dir")
+                .takes_value(true)
+                .help("Directory holding the userfaultfd socket, to bind mount inside the jail."),
+        )
         .arg(
             Argument::new("extra-args")
                 .takes_value(true)
@@ -398,6 +455,20 @@ mod tests {
             format!("{}", Error::ArgumentParsing(err_args_parse)),
             "Failed to parse arguments: Found argument 'foo' which wasn't expected, or isn't valid in this context."
         );
+        assert_eq!(
+            format!("{}", Error::ArtifactAccess(file_path.clone(), 1001, 1002)),
+            "The jailed user 1001:1002 lacks the permissions on /foo/bar to stage it",
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::BindMountArtifact(file_path.clone(), io::Error::from_raw_os_error(2))
+            ),
+            format!(
+                "Failed to bind mount /foo/bar inside the jail: {}",
+                err2_str
+            )
+        );
         assert_eq!(
             format!(
                 "{}",
@@ -459,6 +530,13 @@ mod tests {
             ),
             "Failed to close /dev/null fd: No message of desired type (os error 42)",
         );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::CloseSnapshotFd(file_path.clone(), io::Error::from_raw_os_error(9))
+            ),
+            "Failed to close the original fd of /foo/bar: Bad file descriptor (os error 9)",
+        );
         assert_eq!(
             format!(
                 "{}",
@@ -473,7 +551,7 @@ mod tests {
         assert_eq!(
             format!(
                 "{}",
-                Error::CreateDir(path, io::Error::from_raw_os_error(2))
+                Error::CreateDir(path.clone(), io::Error::from_raw_os_error(2))
             ),
             format!("Failed to create directory /foo: {}", err2_str)
         );
@@ -517,6 +595,13 @@ mod tests {
             format!("{}", Error::Gid(id.to_string())),
             "Invalid gid: foobar",
         );
+        assert_eq!(
+            format!(
+                "{}",
+                Error::HardLink(file_path.clone(), path, io::Error::from_raw_os_error(2))
+            ),
+            format!("Failed to hard link /foo/bar to /foo: {}", err2_str)
+        );
         assert_eq!(
             format!(
                 "{}",
@@ -6,8 +6,10 @@ mod metrics;
 
 use std::sync::LockResult;
 
-pub use crate::logger::{LoggerError, LOGGER};
-pub use crate::metrics::{Metric, MetricsError, SharedMetric, METRICS};
+pub use crate::logger::{DebugCategory, LoggerError, LOGGER};
+pub use crate::metrics::{
+    Gauge, LatencyHistogram, Metric, MetricsError, ReadinessTimer, SharedMetric, METRICS,
+};
 pub use log::Level::*;
 pub use log::*;
 
@@ -74,6 +74,17 @@
 //! 2018-11-07T05:34:25.180751152 [anonymous-instance:ERROR:vmm/src/lib.rs:1173] Failed to write
 //! metrics: Failed to write logs. Error: operation would block
 //! ```
+//! # Debug categories
+//! The messages of the snapshot restore path are grouped in debug categories, see
+//! `DebugCategory`. They are logged through the `debug_category!(<category>, <string>)` macro,
+//! at the `DEBUG` level, and only once their category is enabled. An enabled category is logged
+//! whatever the max level of the logger, so that a single part of the restore can be debugged
+//! without flooding the logs.
+//! ## Example of a debug category log line:
+//! ```bash
+//! 2018-11-07T05:34:25.180751152 [anonymous-instance:DEBUG:vmm/src/memory_snapshot.rs:476]
+//! [uffd] Sent the fd!
+//! ```
 //! # Limitations
 //! Logs can be flushed either to stdout/stderr or to a byte-oriented sink (File, FIFO, Ring Buffer
 //! etc).
@@ -81,12 +92,13 @@
 use std::fmt;
 use std::io::{sink, stderr, stdout, Write};
 use std::result;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Mutex, RwLock};
 
 use crate::metrics::{Metric, METRICS};
 use lazy_static::lazy_static;
 use log::{max_level, set_logger, set_max_level, Level, LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
 use utils::time::LocalTime;
 
 use super::extract_guard;
@@ -109,6 +121,58 @@ lazy_static! {
     };
 }
 
+/// Categories of the snapshot restore debug messages, enabled independently of each other.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DebugCategory {
+    /// Working set mapping and prefetching.
+    WsLoader,
+    /// Userfaultfd registration and handoff.
+    Uffd,
+    /// Base and overlay memory layers mapping.
+    Overlay,
+}
+
+impl DebugCategory {
+    fn mask(self) -> usize {
+        match self {
+            DebugCategory::WsLoader => 1 << 0,
+            DebugCategory::Uffd => 1 << 1,
+            DebugCategory::Overlay => 1 << 2,
+        }
+    }
+}
+
+impl fmt::Display for DebugCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DebugCategory::WsLoader => "ws-loader",
+            DebugCategory::Uffd => "uffd",
+            DebugCategory::Overlay => "overlay",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Logs a message of a debug category, if the category is enabled.
+///
+/// # Example
+///
+/// ```
+/// use logger::{debug_category, DebugCategory, LOGGER};
+///
+/// LOGGER.set_debug_categories(&[DebugCategory::Uffd]);
+/// debug_category!(DebugCategory::Uffd, "Sent the fd to {}", "the page fault handler");
+/// ```
+#[macro_export]
+macro_rules! debug_category {
+    ($category:expr, $($arg:tt)+) => {
+        if $crate::LOGGER.is_debug_category_enabled($category) {
+            $crate::LOGGER.log_category($category, file!(), line!(), format_args!($($arg)+));
+        }
+    };
+}
+
 /// Logger representing the logging subsystem.
 // All member fields have types which are Sync, and exhibit interior mutability, so
 // we can call logging operations using a non-mut static global variable.
@@ -120,6 +184,7 @@ pub struct Logger {
     show_file_path: AtomicBool,
     show_line_numbers: AtomicBool,
     instance_id: RwLock<String>,
+    debug_categories: AtomicUsize,
 }
 
 impl Logger {
@@ -132,6 +197,7 @@ impl Logger {
             show_line_numbers: AtomicBool::new(true),
             show_file_path: AtomicBool::new(true),
             instance_id: RwLock::new(String::new()),
+            debug_categories: AtomicUsize::new(0),
         }
     }
 
@@ -244,6 +310,53 @@ impl Logger {
         self
     }
 
+    /// Enables the `categories` debug categories and disables all the others.
+    pub fn set_debug_categories(&self, categories: &[DebugCategory]) -> &Self {
+        let mask = categories
+            .iter()
+            .fold(0, |mask, category| mask | category.mask());
+        self.debug_categories.store(mask, Ordering::Relaxed);
+        self
+    }
+
+    /// Returns true if the `category` debug category is enabled.
+    pub fn is_debug_category_enabled(&self, category: DebugCategory) -> bool {
+        self.debug_categories.load(Ordering::Relaxed) & category.mask() != 0
+    }
+
+    /// Logs a message of the `category` debug category at the `DEBUG` level, whatever the max
+    /// level. Use the `debug_category!` macro instead, which checks that the category is enabled.
+    pub fn log_category(
+        &self,
+        category: DebugCategory,
+        file: &'static str,
+        line: u32,
+        args: fmt::Arguments,
+    ) {
+        self.log(
+            &Record::builder()
+                .level(Level::Debug)
+                .args(format_args!("[{}] {}", category, args))
+                .file(Some(file))
+                .line(Some(line))
+                .build(),
+        );
+    }
+
+    /// Replaces the destination of the logs, which must have been set by `init()` first.
+    ///
+    /// # Arguments
+    ///
+    /// * `log_dest` - Buffer for plain text logs. Needs to implements `Write` and `Send`.
+    pub fn redirect(&self, log_dest: Box<dyn Write + Send>) -> Result<()> {
+        if !self.init.is_initialized() {
+            return Err(LoggerError::NotInitialized);
+        }
+        let mut g = extract_guard(self.log_buf.lock());
+        *g = log_dest;
+        Ok(())
+    }
+
     /// Creates the first portion (to the left of the separator)
     /// of the log statement based on the logger settings.
     fn create_prefix(&self, record: &Record) -> String {
@@ -386,12 +499,17 @@ impl Logger {
 pub enum LoggerError {
     /// Initialization Error.
     Init(init::Error),
+    /// The logger must be initialized before redirecting it.
+    NotInitialized,
 }
 
 impl fmt::Display for LoggerError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let printable = match *self {
             LoggerError::Init(ref e) => format!("Logger initialization failure: {}", e),
+            LoggerError::NotInitialized => {
+                "Cannot redirect the logger before initializing it.".to_string()
+            }
         };
         write!(f, "{}", printable)
     }
@@ -649,6 +767,55 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_debug_categories() {
+        let logger = Logger::mock_new();
+        assert!(!logger.is_debug_category_enabled(DebugCategory::Uffd));
+
+        logger.set_debug_categories(&[DebugCategory::Uffd, DebugCategory::Overlay]);
+        assert!(logger.is_debug_category_enabled(DebugCategory::Uffd));
+        assert!(logger.is_debug_category_enabled(DebugCategory::Overlay));
+        assert!(!logger.is_debug_category_enabled(DebugCategory::WsLoader));
+
+        logger.set_debug_categories(&[]);
+        assert!(!logger.is_debug_category_enabled(DebugCategory::Uffd));
+
+        let mut reader = logger.mock_init();
+        logger.log_category(
+            DebugCategory::WsLoader,
+            LOG_SOURCE,
+            LOG_LINE,
+            format_args!("{}", "msg"),
+        );
+        validate_log(
+            &mut Box::new(&mut reader),
+            "[TEST-INSTANCE-ID:DEBUG:logger.rs:0] [ws-loader] msg\n",
+        );
+    }
+
+    #[test]
+    fn test_redirect() {
+        let logger = Logger::mock_new();
+
+        // The logger cannot be redirected before it is initialized.
+        let (writer, _) = log_channel();
+        assert!(logger.redirect(Box::new(writer)).is_err());
+
+        let mut first_reader = logger.mock_init();
+        let (writer, mut reader) = log_channel();
+        assert!(logger.redirect(Box::new(writer)).is_ok());
+        logger.mock_log(Level::Info, "info");
+        validate_log(
+            &mut Box::new(&mut reader),
+            "[TEST-INSTANCE-ID:INFO:logger.rs:0] info\n",
+        );
+
+        // Nothing is written to the previous destination anymore.
+        let mut log = Vec::new();
+        first_reader.read_to_end(&mut log).unwrap();
+        assert!(log.is_empty());
+    }
+
     #[test]
     fn test_static_logger() {
         log::set_max_level(log::LevelFilter::Info);
@@ -666,5 +833,9 @@ mod tests {
             format!("{}", LoggerError::Init(init::Error::AlreadyInitialized)),
             "Logger initialization failure: The component is already initialized."
         );
+        assert_eq!(
+            format!("{}", LoggerError::NotInitialized),
+            "Cannot redirect the logger before initializing it."
+        );
     }
 }
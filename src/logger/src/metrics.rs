@@ -55,6 +55,7 @@
 //! If if turns out this approach is not really what we want, it's pretty easy to resort to
 //! something else, while working behind the same interface.
 
+use std::cell::Cell;
 use std::fmt;
 use std::io::Write;
 use std::ops::Deref;
@@ -62,7 +63,9 @@ use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Mutex;
 
 use lazy_static::lazy_static;
+use serde::ser::SerializeStruct;
 use serde::{Serialize, Serializer};
+use serde_json::Value;
 
 use super::extract_guard;
 
@@ -71,6 +74,19 @@ lazy_static! {
     pub static ref METRICS: Metrics<FirecrackerMetrics> = Metrics::new(FirecrackerMetrics::default());
 }
 
+// Prefix of the metric names in the Prometheus exposition format.
+const PROMETHEUS_PREFIX: &str = "firecracker";
+
+thread_local! {
+    // Set while serializing the metrics for `Metrics::prometheus`, which reports cumulative
+    // values. Such a serialization must neither compute deltas nor reset the metrics.
+    static SERIALIZE_CUMULATIVE: Cell<bool> = Cell::new(false);
+}
+
+fn serialize_cumulative() -> bool {
+    SERIALIZE_CUMULATIVE.with(Cell::get)
+}
+
 /// Metrics system.
 // All member fields have types which are Sync, and exhibit interior mutability, so
 // we can call operations on metrics using a non-mut static global variable.
@@ -112,6 +128,22 @@ impl<T: Serialize> Metrics<T> {
         Ok(())
     }
 
+    /// Replaces the destination of the metrics, which must have been set by `init()` first.
+    ///
+    /// # Arguments
+    ///
+    /// * `metrics_dest` - Buffer for JSON formatted metrics. Needs to implement `Write` and `Send`.
+    pub fn redirect(&self, metrics_dest: Box<dyn Write + Send>) -> Result<(), MetricsError> {
+        if !self.is_initialized.load(Ordering::Relaxed) {
+            return Err(MetricsError::NeverInitialized(
+                "Cannot redirect the metrics before initializing them.".to_string(),
+            ));
+        }
+        let mut g = extract_guard(self.metrics_buf.lock());
+        *g = Some(metrics_dest);
+        Ok(())
+    }
+
     /// Writes metrics to the destination provided as argument upon initialization of the metrics.
     /// Upon failure, an error is returned if metrics system is initialized and metrics could not be
     /// written.
@@ -144,6 +176,41 @@ impl<T: Serialize> Metrics<T> {
         // metrics were not written.
         Ok(false)
     }
+
+    /// Renders the metrics in the Prometheus text exposition format, one sample per line.
+    ///
+    /// Nested fields are joined with `_` under the `firecracker` prefix, e.g. `block.read_count`
+    /// becomes `firecracker_block_read_count`. The samples are cumulative since startup, and
+    /// rendering them does not affect the deltas flushed by `write`.
+    pub fn prometheus(&self) -> Result<String, MetricsError> {
+        SERIALIZE_CUMULATIVE.with(|cumulative| cumulative.set(true));
+        let value = serde_json::to_value(&self.app_metrics);
+        SERIALIZE_CUMULATIVE.with(|cumulative| cumulative.set(false));
+
+        let mut text = String::new();
+        append_prometheus_samples(
+            &mut text,
+            PROMETHEUS_PREFIX,
+            &value.map_err(|e| MetricsError::Serde(e.to_string()))?,
+        );
+        Ok(text)
+    }
+}
+
+fn append_prometheus_samples(text: &mut String, name: &str, value: &Value) {
+    match value {
+        Value::Object(fields) => {
+            for (field, value) in fields.iter() {
+                // Not a metric, Prometheus timestamps the samples itself.
+                if field == "utc_timestamp_ms" {
+                    continue;
+                }
+                append_prometheus_samples(text, &format!("{}_{}", name, field), value);
+            }
+        }
+        Value::Number(number) => text.push_str(&format!("{} {}\n", name, number)),
+        _ => (),
+    }
 }
 
 impl<T: Serialize> Deref for Metrics<T> {
@@ -239,6 +306,9 @@ impl Serialize for SharedMetric {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         // There's no serializer.serialize_usize() for some reason :(
         let snapshot = self.0.load(Ordering::Relaxed);
+        if serialize_cumulative() {
+            return serializer.serialize_u64(snapshot as u64);
+        }
         let res = serializer.serialize_u64(snapshot as u64 - self.1.load(Ordering::Relaxed) as u64);
 
         if res.is_ok() {
@@ -248,6 +318,132 @@ impl Serialize for SharedMetric {
     }
 }
 
+/// Metric holding the last value it was set to, such as a count of pages sampled at a point in
+/// time. Unlike `SharedMetric`, the value is not reset on flush.
+#[derive(Default)]
+pub struct Gauge(AtomicUsize);
+
+impl Gauge {
+    /// Sets the value of the gauge.
+    pub fn set(&self, value: usize) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    /// Returns the value of the gauge.
+    pub fn value(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Serialize for Gauge {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.value() as u64)
+    }
+}
+
+// Power of two buckets, the last one also holds every latency above 2^30 us.
+const LATENCY_HISTOGRAM_BUCKETS: usize = 32;
+
+/// Distribution of latencies, in microseconds, flushed as a sample count and percentiles.
+///
+/// Samples are counted in power of two buckets, so the reported percentiles are upper bounds
+/// precise to a factor of two. Unlike `SharedMetric`, the samples are reset on each flush.
+#[derive(Default)]
+pub struct LatencyHistogram {
+    buckets: [AtomicUsize; LATENCY_HISTOGRAM_BUCKETS],
+}
+
+impl LatencyHistogram {
+    /// Adds a sample of `latency_us` microseconds.
+    pub fn record(&self, latency_us: u64) {
+        let bucket = (64 - latency_us.leading_zeros()) as usize;
+        self.buckets[std::cmp::min(bucket, LATENCY_HISTOGRAM_BUCKETS - 1)]
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Upper bound of the bucket holding the `pct` percentile of the samples.
+    fn percentile(counts: &[usize], total: usize, pct: usize) -> u64 {
+        // Rank of the sample, rounded up.
+        let rank = (total * pct + 99) / 100;
+        let mut seen = 0;
+        for (bucket, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank && *count > 0 {
+                return (1u64 << bucket) - 1;
+            }
+        }
+        0
+    }
+}
+
+impl Serialize for LatencyHistogram {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let counts: Vec<usize> = self
+            .buckets
+            .iter()
+            .map(|bucket| {
+                if serialize_cumulative() {
+                    bucket.load(Ordering::Relaxed)
+                } else {
+                    bucket.swap(0, Ordering::Relaxed)
+                }
+            })
+            .collect();
+        let total = counts.iter().sum();
+
+        let mut state = serializer.serialize_struct("LatencyHistogram", 3)?;
+        state.serialize_field("count", &(total as u64))?;
+        state.serialize_field("p50", &LatencyHistogram::percentile(&counts, total, 50))?;
+        state.serialize_field("p99", &LatencyHistogram::percentile(&counts, total, 99))?;
+        state.end()
+    }
+}
+
+/// Time elapsed between an event, such as resuming the vCPUs, and the first occurrence of
+/// another one, such as the guest transmitting a packet.
+///
+/// Only the first `record` after `arm` measures the elapsed time, later ones are no-ops until
+/// the timer is armed again. The last measured time is flushed, then reset to 0.
+#[derive(Default)]
+pub struct ReadinessTimer {
+    // Monotonic time in microseconds the timer was armed at, 0 when it is not armed.
+    armed_at_us: AtomicUsize,
+    elapsed_us: AtomicUsize,
+}
+
+impl ReadinessTimer {
+    /// Starts measuring from `start_us`, a monotonic time in microseconds.
+    pub fn arm(&self, start_us: u64) {
+        self.armed_at_us.store(start_us as usize, Ordering::Relaxed);
+    }
+
+    /// Stops measuring at the current time, if the timer is armed.
+    pub fn record(&self) {
+        // Cheap check first, this is called on the data path of the devices.
+        if self.armed_at_us.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let start_us = self.armed_at_us.swap(0, Ordering::Relaxed);
+        if start_us == 0 {
+            return;
+        }
+        let now_us = utils::time::get_time_us(utils::time::ClockType::Monotonic) as usize;
+        self.elapsed_us
+            .store(now_us.saturating_sub(start_us), Ordering::Relaxed);
+    }
+}
+
+impl Serialize for ReadinessTimer {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let elapsed_us = if serialize_cumulative() {
+            self.elapsed_us.load(Ordering::Relaxed)
+        } else {
+            self.elapsed_us.swap(0, Ordering::Relaxed)
+        };
+        serializer.serialize_u64(elapsed_us as u64)
+    }
+}
+
 // The following structs are used to define a certain organization for the set of metrics we
 // are interested in. Whenever the name of a field differs from its ideal textual representation
 // in the serialized form, we can use the #[serde(rename = "name")] attribute to, well, rename it.
@@ -276,6 +472,10 @@ pub struct GetRequestsMetrics {
     pub machine_cfg_count: SharedMetric,
     /// Number of failures during GETs for getting information on the instance.
     pub machine_cfg_fails: SharedMetric,
+    /// Number of GETs for scraping the metrics in the Prometheus format.
+    pub metrics_count: SharedMetric,
+    /// Number of failures when rendering the metrics in the Prometheus format.
+    pub metrics_fails: SharedMetric,
 }
 
 /// Metrics specific to PUT API Requests for counting user triggered actions and/or failures.
@@ -326,6 +526,14 @@ pub struct PatchRequestsMetrics {
     pub machine_cfg_count: SharedMetric,
     /// Number of failures in configuring the machine.
     pub machine_cfg_fails: SharedMetric,
+    /// Number of PATCHs for updating the logger.
+    pub logger_count: SharedMetric,
+    /// Number of failures in updating the logger.
+    pub logger_fails: SharedMetric,
+    /// Number of PATCHs for redirecting the metrics.
+    pub metrics_count: SharedMetric,
+    /// Number of failures in redirecting the metrics.
+    pub metrics_fails: SharedMetric,
 }
 
 /// Block Device associated metrics.
@@ -365,6 +573,29 @@ pub struct BlockDeviceMetrics {
     pub rate_limiter_throttled_events: SharedMetric,
 }
 
+/// Guest page faults following a snapshot restore, by the memory layer backing the faulting
+/// page. The faults served by the VMM are counted as they are served, the other ones are sampled
+/// periodically when `fault_attribution` is enabled at load.
+#[derive(Default, Serialize)]
+pub struct FaultSourceMetrics {
+    /// Number of faults on pages of the memory file.
+    pub base_faults: SharedMetric,
+    /// Number of bytes faulted in from the memory file.
+    pub base_bytes: SharedMetric,
+    /// Number of faults on pages of the overlay extents.
+    pub overlay_faults: SharedMetric,
+    /// Number of bytes faulted in from the overlay extents.
+    pub overlay_bytes: SharedMetric,
+    /// Number of faults on pages of the working set extents.
+    pub ws_faults: SharedMetric,
+    /// Number of bytes faulted in from the working set extents.
+    pub ws_bytes: SharedMetric,
+    /// Number of faults on guest pages backed by no snapshot file.
+    pub anon_faults: SharedMetric,
+    /// Number of bytes faulted in backed by no snapshot file.
+    pub anon_bytes: SharedMetric,
+}
+
 /// Metrics specific to the i8042 device.
 #[derive(Default, Serialize)]
 pub struct I8042DeviceMetrics {
@@ -382,6 +613,17 @@ pub struct I8042DeviceMetrics {
     pub write_count: SharedMetric,
 }
 
+/// Metrics related to the reclamation of the guest memory of idle restored microVMs.
+#[derive(Default, Serialize)]
+pub struct IdleReclaimMetrics {
+    /// Number of guest memory bytes paged out while the microVM was idle.
+    pub paged_out_bytes: SharedMetric,
+    /// Number of reclamations cancelled because the microVM became active again.
+    pub cancels: SharedMetric,
+    /// Number of failures to page out the guest memory.
+    pub fails: SharedMetric,
+}
+
 /// Metrics for the logging subsystem.
 #[derive(Default, Serialize)]
 pub struct LoggerSystemMetrics {
@@ -395,6 +637,17 @@ pub struct LoggerSystemMetrics {
     pub log_fails: SharedMetric,
 }
 
+/// Guest memory residency, sampled periodically once the microVM is built.
+#[derive(Default, Serialize)]
+pub struct MemoryResidencyMetrics {
+    /// Number of guest memory bytes resident in host memory.
+    pub resident_bytes: Gauge,
+    /// Size of the guest memory in bytes.
+    pub total_bytes: Gauge,
+    /// Number of process memory bytes shared with other processes by kernel samepage merging.
+    pub ksm_merged_bytes: Gauge,
+}
+
 /// Metrics for the MMDS functionality.
 #[derive(Default, Serialize)]
 pub struct MmdsMetrics {
@@ -537,6 +790,136 @@ pub struct SeccompMetrics {
     pub num_faults: SharedMetric,
 }
 
+/// Time it takes the guest to become active once its vCPUs are resumed, as a proxy for the
+/// readiness of the workload it runs.
+#[derive(Default, Serialize)]
+pub struct ReadinessMetrics {
+    /// Microseconds from resuming the vCPUs to the first packet sent on the TAP by a net device.
+    pub resume_to_net_tx_us: ReadinessTimer,
+    /// Microseconds from resuming the vCPUs to the first packet sent by the vsock device.
+    pub resume_to_vsock_tx_us: ReadinessTimer,
+}
+
+impl ReadinessMetrics {
+    /// Arms every readiness timer, to be called right before resuming the vCPUs.
+    pub fn arm(&self) {
+        let now_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+        self.resume_to_net_tx_us.arm(now_us);
+        self.resume_to_vsock_tx_us.arm(now_us);
+    }
+}
+
+/// Metrics related to creating and loading snapshots.
+#[derive(Default, Serialize)]
+pub struct SnapshotMetrics {
+    /// Number of snapshots created, full or diff.
+    pub create_count: SharedMetric,
+    /// Number of diff snapshots created.
+    pub diff_create_count: SharedMetric,
+    /// Number of snapshots created in the background, with the microVM running.
+    pub background_create_count: SharedMetric,
+    /// Time spent copying the guest memory of the running microVM for background snapshots,
+    /// in microseconds.
+    pub background_copy_us: SharedMetric,
+    /// Time the microVM spent paused for background snapshots, in microseconds.
+    pub background_pause_us: SharedMetric,
+    /// Time spent preallocating the blocks of the snapshot memory files, in microseconds.
+    pub preallocate_us: SharedMetric,
+    /// Number of guest memory bytes written to memory files.
+    pub bytes_dumped: SharedMetric,
+    /// Number of unmodified guest memory bytes copied to memory files from the file backing them.
+    pub bytes_copied: SharedMetric,
+    /// Number of snapshots loaded.
+    pub load_count: SharedMetric,
+    /// Number of working set bytes prefetched into guest memory.
+    pub ws_bytes_prefetched: SharedMetric,
+    /// Number of working set bytes the prefetch found in the host page cache, which took no I/O.
+    pub ws_bytes_cached: SharedMetric,
+    /// Number of working set chunks found in the host page cache, and mapped in before the others.
+    pub ws_cached_chunks: SharedMetric,
+    /// Number of bytes of ws files copied to the staging directory.
+    pub ws_staged_bytes: SharedMetric,
+    /// Number of ws files that could not be staged, and were mapped from their path.
+    pub ws_staging_fails: SharedMetric,
+    /// Number of snapshot files mapped from the copy already in the snapshot cache.
+    pub cache_hits: SharedMetric,
+    /// Number of bytes of snapshot files copied to the snapshot cache.
+    pub cache_copied_bytes: SharedMetric,
+    /// Number of bytes of snapshot files evicted from the snapshot cache to make room.
+    pub cache_evicted_bytes: SharedMetric,
+    /// Number of snapshot files that could not be cached, and were mapped from their path.
+    pub cache_fails: SharedMetric,
+    /// Time to map and populate the working set extents with `ws_populate`, in microseconds.
+    pub ws_populate_us: SharedMetric,
+    /// Number of working set bytes locked in memory.
+    pub ws_bytes_locked: SharedMetric,
+    /// Number of times locking the working set stopped short, on `RLIMIT_MEMLOCK` or a failure.
+    pub ws_lock_fails: SharedMetric,
+    /// Number of times the working set prefetch paused for host memory or IO pressure.
+    pub ws_prefetch_throttles: SharedMetric,
+    /// Time the working set prefetch spent paused for host pressure, in microseconds.
+    pub ws_prefetch_throttled_us: SharedMetric,
+    /// Time the working set prefetch waited for the bandwidth shared by the restores of the
+    /// host, in microseconds.
+    pub ws_prefetch_coordinated_us: SharedMetric,
+    /// Number of working set bytes left to fault in lazily, once the working set load passed its
+    /// deadline or was cancelled.
+    pub ws_bytes_abandoned: SharedMetric,
+    /// Number of working set loads which failed, failing the snapshot load.
+    pub ws_load_fails: SharedMetric,
+    /// Number of overlay extents mapped over guest memory, after merging the adjacent ones.
+    pub overlay_extents_mapped: SharedMetric,
+    /// Number of restored guest memory regions whose host address is not aligned to 2 MiB.
+    pub unaligned_regions: SharedMetric,
+    /// Number of base layer pages copied from the write-protected memory file into the guest
+    /// memory.
+    pub base_pages_copied: SharedMetric,
+    /// Number of pages mapped by both the overlay and the working set layers.
+    pub layer_conflict_pages: SharedMetric,
+    /// Time to service the page faults taken while prefetching the working set, in
+    /// microseconds. Depending on the restore, they are served by the page cache, the disk or
+    /// the userfaultfd handler.
+    pub page_fault_service_us: LatencyHistogram,
+    /// Number of snapshot loads that failed to build the microVM.
+    pub load_build_fails: SharedMetric,
+    /// Number of snapshot loads that failed to open one of the snapshot files.
+    pub load_file_fails: SharedMetric,
+    /// Number of snapshot loads that failed to restore guest memory.
+    pub load_memory_fails: SharedMetric,
+    /// Number of snapshot loads that failed to deserialize the microVM state.
+    pub load_state_fails: SharedMetric,
+    /// Number of snapshot loads that failed to hand guest memory over to a page fault handler.
+    pub load_uffd_fails: SharedMetric,
+    /// Number of snapshot loads that failed to verify the snapshot signatures.
+    pub load_verify_fails: SharedMetric,
+    /// Number of working set loads or uffd handshakes reported stalled by the restore watchdog.
+    pub restore_stalls: SharedMetric,
+    /// Number of guest agent messages that failed or were not answered.
+    pub guest_agent_fails: SharedMetric,
+    /// Number of guest agent messages not answered in time.
+    pub guest_agent_timeouts: SharedMetric,
+    /// Time the guest agent took to handle a message, in microseconds.
+    pub guest_agent_us: LatencyHistogram,
+}
+
+/// Effectiveness of the working set prefetch, sampled periodically after a snapshot load with
+/// `ws_accounting` enabled.
+#[derive(Default, Serialize)]
+pub struct WsPrefetchMetrics {
+    /// Number of pages prefetched from the working set file.
+    pub prefetched_pages: Gauge,
+    /// Number of prefetched pages accessed since the restore.
+    pub accessed_pages: Gauge,
+    /// Number of prefetched pages not accessed since the restore.
+    pub wasted_pages: Gauge,
+    /// Number of guest pages outside of the working set faulted in since the restore.
+    pub non_ws_faults: Gauge,
+    /// Per mille of the prefetched pages accessed since the restore.
+    pub hit_permille: Gauge,
+    /// Per mille of the prefetched pages not accessed since the restore.
+    pub waste_permille: Gauge,
+}
+
 /// Metrics specific to the UART device.
 #[derive(Default, Serialize)]
 pub struct SerialDeviceMetrics {
@@ -561,6 +944,8 @@ pub struct SignalMetrics {
     pub sigbus: SharedMetric,
     /// Number of times that SIGSEGV was handled.
     pub sigsegv: SharedMetric,
+    /// Number of times that SIGBUS was handled within guest memory mapped from a snapshot file.
+    pub sigbus_snapshot_file: SharedMetric,
 }
 
 /// Metrics specific to VCPUs' mode of functioning.
@@ -654,14 +1039,20 @@ pub struct FirecrackerMetrics {
     pub api_server: ApiServerMetrics,
     /// A block device's related metrics.
     pub block: BlockDeviceMetrics,
+    /// Metrics related to the layers the guest page faults are served from.
+    pub fault_sources: FaultSourceMetrics,
     /// Metrics related to API GET requests.
     pub get_api_requests: GetRequestsMetrics,
     /// Metrics related to the i8042 device.
     pub i8042: I8042DeviceMetrics,
+    /// Metrics related to the reclamation of the guest memory of idle microVMs.
+    pub idle_reclaim: IdleReclaimMetrics,
     /// Metrics related to performance measurements.
     pub latencies_us: PerformanceMetrics,
     /// Logging related metrics.
     pub logger: LoggerSystemMetrics,
+    /// Metrics related to the guest memory residency.
+    pub memory_residency: MemoryResidencyMetrics,
     /// Metrics specific to MMDS functionality.
     pub mmds: MmdsMetrics,
     /// A network device's related metrics.
@@ -670,10 +1061,14 @@ pub struct FirecrackerMetrics {
     pub patch_api_requests: PatchRequestsMetrics,
     /// Metrics related to API PUT requests.
     pub put_api_requests: PutRequestsMetrics,
+    /// Metrics related to the readiness of the guest after a resume.
+    pub readiness: ReadinessMetrics,
     /// Metrics related to the RTC device.
     pub rtc: RTCDeviceMetrics,
     /// Metrics related to seccomp filtering.
     pub seccomp: SeccompMetrics,
+    /// Metrics related to snapshots.
+    pub snapshot: SnapshotMetrics,
     /// Metrics related to a vcpu's functioning.
     pub vcpu: VcpuMetrics,
     /// Metrics related to the virtual machine manager.
@@ -684,6 +1079,8 @@ pub struct FirecrackerMetrics {
     pub signals: SignalMetrics,
     /// Metrics related to virtio-vsockets.
     pub vsock: VsockDeviceMetrics,
+    /// Metrics related to the effectiveness of the working set prefetch.
+    pub ws_prefetch: WsPrefetchMetrics,
 }
 
 #[cfg(test)]
@@ -714,6 +1111,28 @@ mod tests {
         assert!(m.init(Box::new(f.into_file()),).is_err());
     }
 
+    #[test]
+    fn test_redirect() {
+        let m = Metrics::new(SharedMetric::default());
+
+        let f = TempFile::new().expect("Failed to create temporary metrics file");
+        assert!(m.redirect(Box::new(f.into_file())).is_err());
+
+        let first = TempFile::new().expect("Failed to create temporary metrics file");
+        assert!(m
+            .init(Box::new(first.as_file().try_clone().unwrap()))
+            .is_ok());
+        let second = TempFile::new().expect("Failed to create temporary metrics file");
+        assert!(m
+            .redirect(Box::new(second.as_file().try_clone().unwrap()))
+            .is_ok());
+
+        m.add(5);
+        assert!(m.write().unwrap());
+        assert_eq!(std::fs::read_to_string(first.as_path()).unwrap(), "");
+        assert_eq!(std::fs::read_to_string(second.as_path()).unwrap(), "5\n");
+    }
+
     #[test]
     fn test_metric() {
         // Test SharedMetric.
@@ -757,6 +1176,99 @@ mod tests {
         assert_eq!(m2.1.load(Ordering::Relaxed), 0);
     }
 
+    #[test]
+    fn test_latency_histogram() {
+        let histogram = LatencyHistogram::default();
+        assert_eq!(
+            serde_json::to_string(&histogram).unwrap(),
+            r#"{"count":0,"p50":0,"p99":0}"#
+        );
+
+        // 98 fast samples, and two slow ones.
+        for _ in 0..98 {
+            histogram.record(5);
+        }
+        histogram.record(1000);
+        histogram.record(u64::max_value());
+        assert_eq!(
+            serde_json::to_string(&histogram).unwrap(),
+            r#"{"count":100,"p50":7,"p99":1023}"#
+        );
+
+        // The samples are reset on flush.
+        assert_eq!(
+            serde_json::to_string(&histogram).unwrap(),
+            r#"{"count":0,"p50":0,"p99":0}"#
+        );
+    }
+
+    #[test]
+    fn test_readiness_timer() {
+        let timer = ReadinessTimer::default();
+
+        // Nothing is measured until the timer is armed.
+        timer.record();
+        assert_eq!(serde_json::to_string(&timer).unwrap(), "0");
+
+        let now_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+        timer.arm(now_us - 1000);
+        timer.record();
+        let elapsed_us = timer.elapsed_us.load(Ordering::Relaxed);
+        assert!(elapsed_us >= 1000);
+
+        // Only the first event after arming the timer is measured.
+        timer.record();
+        assert_eq!(timer.elapsed_us.load(Ordering::Relaxed), elapsed_us);
+
+        // The measure is reset on flush.
+        assert_eq!(
+            serde_json::to_string(&timer).unwrap(),
+            elapsed_us.to_string()
+        );
+        assert_eq!(serde_json::to_string(&timer).unwrap(), "0");
+    }
+
+    #[test]
+    fn test_gauge() {
+        let gauge = Gauge::default();
+        assert_eq!(serde_json::to_string(&gauge).unwrap(), "0");
+
+        gauge.set(42);
+        assert_eq!(gauge.value(), 42);
+        // The value is kept across flushes.
+        assert_eq!(serde_json::to_string(&gauge).unwrap(), "42");
+        assert_eq!(serde_json::to_string(&gauge).unwrap(), "42");
+
+        gauge.set(7);
+        assert_eq!(serde_json::to_string(&gauge).unwrap(), "7");
+    }
+
+    #[test]
+    fn test_prometheus() {
+        let metrics = Metrics::new(FirecrackerMetrics::default());
+        metrics.app_metrics.block.read_count.add(3);
+        metrics.app_metrics.snapshot.page_fault_service_us.record(5);
+
+        let text = metrics.prometheus().unwrap();
+        assert!(text.contains("firecracker_block_read_count 3\n"));
+        assert!(text.contains("firecracker_snapshot_page_fault_service_us_count 1\n"));
+        assert!(text.contains("firecracker_snapshot_page_fault_service_us_p50 7\n"));
+        assert!(!text.contains("utc_timestamp_ms"));
+        for line in text.lines() {
+            let sample: Vec<&str> = line.split(' ').collect();
+            assert_eq!(sample.len(), 2);
+            assert!(sample[0].starts_with("firecracker_"));
+        }
+
+        // The samples are cumulative, and the deltas of the JSON metrics are left untouched.
+        metrics.app_metrics.block.read_count.add(2);
+        let text = metrics.prometheus().unwrap();
+        assert!(text.contains("firecracker_block_read_count 5\n"));
+        let json: Value = serde_json::to_value(&metrics.app_metrics).unwrap();
+        assert_eq!(json["block"]["read_count"], 5);
+        assert_eq!(json["snapshot"]["page_fault_service_us"]["count"], 1);
+    }
+
     #[test]
     fn test_serialize() {
         let s = serde_json::to_string(&FirecrackerMetrics::default());
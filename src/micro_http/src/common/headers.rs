@@ -20,6 +20,10 @@ pub enum Header {
     Server,
     /// Header `Accept`
     Accept,
+    /// Header `traceparent`, from the W3C Trace Context specification.
+    TraceParent,
+    /// Header `Authorization`.
+    Authorization,
 }
 
 impl Header {
@@ -32,6 +36,8 @@ impl Header {
             Self::TransferEncoding => b"Transfer-Encoding",
             Self::Server => b"Server",
             Self::Accept => b"Accept",
+            Self::TraceParent => b"traceparent",
+            Self::Authorization => b"Authorization",
         }
     }
 
@@ -51,6 +57,8 @@ impl Header {
                 "transfer-encoding" => Ok(Self::TransferEncoding),
                 "server" => Ok(Self::Server),
                 "accept" => Ok(Self::Accept),
+                "traceparent" => Ok(Self::TraceParent),
+                "authorization" => Ok(Self::Authorization),
                 _ => Err(RequestError::InvalidHeader),
             }
         } else {
@@ -87,6 +95,12 @@ pub struct Headers {
     /// `Accept` header might be used by HTTP clients to enforce server responses with content
     /// formatted in a specific way.
     accept: MediaType,
+    /// The `traceparent` header field identifies the distributed trace, and the span within it,
+    /// the request is part of. It is stored as is, validating it is left to the consumer.
+    trace_parent: Option<String>,
+    /// The `Authorization` header field holds the credentials of the client. It is stored as is,
+    /// checking them is left to the consumer.
+    authorization: Option<String>,
 }
 
 impl Default for Headers {
@@ -99,6 +113,8 @@ impl Default for Headers {
             // The default `Accept` media type is plain text. This is inclusive enough
             // for structured and unstructured text.
             accept: MediaType::PlainText,
+            trace_parent: None,
+            authorization: None,
         }
     }
 }
@@ -169,6 +185,14 @@ impl Headers {
                             _ => Err(RequestError::InvalidHeader),
                         },
                         Header::Server => Ok(()),
+                        Header::TraceParent => {
+                            self.trace_parent = Some(entry[1].trim().to_string());
+                            Ok(())
+                        }
+                        Header::Authorization => {
+                            self.authorization = Some(entry[1].trim().to_string());
+                            Ok(())
+                        }
                     }
                 } else {
                     Err(RequestError::UnsupportedHeader)
@@ -200,6 +224,16 @@ impl Headers {
         self.accept
     }
 
+    /// Returns the value of the `traceparent` header, if any.
+    pub fn trace_parent(&self) -> Option<&str> {
+        self.trace_parent.as_deref()
+    }
+
+    /// Returns the value of the `Authorization` header, if any.
+    pub fn authorization(&self) -> Option<&str> {
+        self.authorization.as_deref()
+    }
+
     /// Parses a byte slice into a Headers structure for a HTTP request.
     ///
     /// The byte slice is expected to have the following format: </br>
@@ -320,6 +354,8 @@ mod tests {
                 expect,
                 chunked,
                 accept: MediaType::PlainText,
+                trace_parent: None,
+                authorization: None,
             }
         }
     }
@@ -473,6 +509,25 @@ mod tests {
             header.parse_header_line(b"Content-Length: -1"),
             Err(RequestError::InvalidHeader)
         );
+
+        // Test trace parent.
+        assert!(header.trace_parent().is_none());
+        assert!(header
+            .parse_header_line(
+                b"traceparent: 00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+            )
+            .is_ok());
+        assert_eq!(
+            header.trace_parent(),
+            Some("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")
+        );
+
+        // Test authorization.
+        assert!(header.authorization().is_none());
+        assert!(header
+            .parse_header_line(b"Authorization: Bearer secret")
+            .is_ok());
+        assert_eq!(header.authorization(), Some("Bearer secret"));
     }
 
     #[test]
@@ -543,5 +598,11 @@ mod tests {
 
         let header = Header::try_from(b"Accept").unwrap();
         assert_eq!(header.raw(), b"Accept");
+
+        let header = Header::try_from(b"Traceparent").unwrap();
+        assert_eq!(header.raw(), b"traceparent");
+
+        let header = Header::try_from(b"authorization").unwrap();
+        assert_eq!(header.raw(), b"Authorization");
     }
 }
@@ -3,6 +3,7 @@
 
 use std::io::{Read, Write};
 use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
 use std::os::unix::io::RawFd;
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::Path;
@@ -273,6 +274,26 @@ impl HttpServer {
         })
     }
 
+    /// Constructor for `HttpServer` listening on an already bound socket, such as one passed
+    /// by systemd socket activation.
+    ///
+    /// Returns the newly formed `HttpServer`.
+    ///
+    /// # Safety
+    /// `socket_fd` must be a listening Unix stream socket, which the server then owns.
+    ///
+    /// # Errors
+    /// Returns an `IOError` when `epoll::create` fails.
+    pub unsafe fn new_from_fd(socket_fd: RawFd) -> Result<Self> {
+        let socket = UnixListener::from_raw_fd(socket_fd);
+        let epoll = epoll::Epoll::new().map_err(ServerError::IOError)?;
+        Ok(Self {
+            socket,
+            epoll,
+            connections: HashMap::new(),
+        })
+    }
+
     /// Starts the HTTP Server.
     pub fn start_server(&mut self) -> Result<()> {
         // Add the socket on which we listen for new connections to the
@@ -298,13 +319,27 @@ impl HttpServer {
     /// `InvalidWrite` is returned when the server attempted to perform a write operation
     /// on a connection on which it is not possible.
     pub fn requests(&mut self) -> Result<Vec<ServerRequest>> {
+        self.requests_within(-1)
+    }
+
+    /// Same as `requests()`, but never blocks: exchanges data with the clients which are
+    /// already ready for it, and returns the complete requests received so far, if any.
+    ///
+    /// # Errors
+    /// Same as `requests()`.
+    pub fn try_requests(&mut self) -> Result<Vec<ServerRequest>> {
+        self.requests_within(0)
+    }
+
+    fn requests_within(&mut self, timeout: i32) -> Result<Vec<ServerRequest>> {
         let mut parsed_requests: Vec<ServerRequest> = vec![];
         let mut events = vec![epoll::EpollEvent::default(); MAX_CONNECTIONS];
         // This is a wrapper over the syscall `epoll_wait` and it will block the
-        // current thread until at least one event is received.
+        // current thread until at least one event is received, or `timeout`
+        // milliseconds pass unless it is negative.
         // The received notifications will then populate the `events` array with
-        // `event_count` elements, where 1 <= event_count <= MAX_CONNECTIONS.
-        let event_count = match self.epoll.wait(MAX_CONNECTIONS, -1, &mut events[..]) {
+        // `event_count` elements, where 0 <= event_count <= MAX_CONNECTIONS.
+        let event_count = match self.epoll.wait(MAX_CONNECTIONS, timeout, &mut events[..]) {
             Ok(event_count) => event_count,
             Err(e) if e.raw_os_error() == Some(libc::EINTR) => 0,
             Err(e) => return Err(ServerError::IOError(e)),
@@ -581,6 +616,57 @@ mod tests {
         assert!(socket.read(&mut buf[..]).unwrap() > 0);
     }
 
+    #[test]
+    fn test_try_requests() {
+        let path_to_socket = get_temp_socket_file();
+
+        let mut server = HttpServer::new(path_to_socket.as_path()).unwrap();
+        server.start_server().unwrap();
+
+        // Nothing to exchange, the server does not block.
+        assert!(server.try_requests().unwrap().is_empty());
+
+        let mut socket = UnixStream::connect(path_to_socket.as_path()).unwrap();
+        assert!(server.try_requests().unwrap().is_empty());
+        assert_eq!(server.connections.len(), 1);
+
+        socket
+            .write_all(
+                b"PATCH /machine-config HTTP/1.1\r\n\
+                         Content-Length: 13\r\n\
+                         Content-Type: application/json\r\n\r\nwhatever body",
+            )
+            .unwrap();
+        assert_eq!(server.try_requests().unwrap().len(), 1);
+        assert!(server.try_requests().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_new_from_fd() {
+        use std::os::unix::io::IntoRawFd;
+
+        let path_to_socket = get_temp_socket_file();
+        let listener = UnixListener::bind(path_to_socket.as_path()).unwrap();
+
+        let mut server = unsafe { HttpServer::new_from_fd(listener.into_raw_fd()) }.unwrap();
+        server.start_server().unwrap();
+
+        let mut socket = UnixStream::connect(path_to_socket.as_path()).unwrap();
+        assert!(server.requests().unwrap().is_empty());
+        socket.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut req_vec = server.requests().unwrap();
+        let server_request = req_vec.remove(0);
+        server
+            .respond(
+                server_request
+                    .process(|_request| Response::new(Version::Http11, StatusCode::NoContent)),
+            )
+            .unwrap();
+
+        let mut buf: [u8; 1024] = [0; 1024];
+        assert!(socket.read(&mut buf[..]).unwrap() > 0);
+    }
+
     #[test]
     fn test_wait_concurrent_connections() {
         let path_to_socket = get_temp_socket_file();
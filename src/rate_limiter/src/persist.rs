@@ -24,12 +24,22 @@ impl Persist<'_> for TokenBucket {
     type Error = io::Error;
 
     fn save(&self) -> Self::State {
+        let elapsed_ns = self.last_update.elapsed().as_nanos() as u64;
+        let refill =
+            elapsed_ns.saturating_mul(self.processed_capacity) / self.processed_refill_time;
+        // A bucket full by now is saved without the time since its last update, so that the
+        // idle buckets always save to the same state.
+        let (budget, elapsed_ns) = if self.budget.saturating_add(refill) >= self.size {
+            (self.size, 0)
+        } else {
+            (self.budget, elapsed_ns)
+        };
         TokenBucketState {
             size: self.size,
             one_time_burst: self.one_time_burst,
             refill_time: self.refill_time,
-            budget: self.budget,
-            elapsed_ns: self.last_update.elapsed().as_nanos() as u64,
+            budget,
+            elapsed_ns,
         }
     }
 
@@ -124,6 +134,16 @@ mod tests {
         )
         .unwrap();
         assert!(tb.partial_eq(&restored_tb));
+
+        // A full bucket saves the same state whenever it is saved, a partly empty one keeps the
+        // time since its last update.
+        let mut tb = TokenBucket::new(1000, 0, 3_600_000).unwrap();
+        let state = tb.save();
+        assert_eq!((state.budget, state.elapsed_ns), (1000, 0));
+        tb.reduce(500);
+        let state = tb.save();
+        assert_eq!(state.budget, 500);
+        assert!(TokenBucket::restore((), &state).unwrap().partial_eq(&tb));
     }
 
     #[test]
@@ -903,6 +903,16 @@ impl SeccompFilter {
         Ok(())
     }
 
+    /// Removes the rules of the specified syscall from the filter, so that the syscall falls
+    /// back to the default action. Returns the removed rules, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `syscall_number` - Syscall identifier.
+    pub fn remove_rules(&mut self, syscall_number: i64) -> Option<Vec<SeccompRule>> {
+        self.rules.remove(&syscall_number)
+    }
+
     /// Builds the array of filter instructions and sends them to the kernel.
     ///
     /// # Arguments
@@ -2016,6 +2026,20 @@ mod tests {
         assert_eq!(rc2, 0);
     }
 
+    #[test]
+    fn test_remove_rules() {
+        let mut filter = SeccompFilter::new(
+            vec![allow_syscall(1), allow_syscall(2)]
+                .into_iter()
+                .collect(),
+            SeccompAction::Trap,
+        )
+        .unwrap();
+        assert_eq!(filter.remove_rules(1).unwrap().len(), 1);
+        assert!(filter.remove_rules(1).is_none());
+        assert_eq!(filter.rules.keys().cloned().collect::<Vec<i64>>(), vec![2]);
+    }
+
     #[test]
     fn test_parse_seccomp() {
         // Check `from_string()` behaviour for different scenarios.
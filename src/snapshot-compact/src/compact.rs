@@ -0,0 +1,438 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidRegion(i64, i64),
+    Open(PathBuf, io::Error),
+    Read(io::Error),
+    UnalignedMemFile(u64),
+    Write(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidRegion(page, pages) => write!(
+                f,
+                "Region of {} pages at page {} is outside of the memory file",
+                pages, page
+            ),
+            Open(path, err) => write!(f, "Failed to open {}: {}", path.display(), err),
+            Read(err) => write!(f, "Failed to read a snapshot layer: {}", err),
+            UnalignedMemFile(size) => write!(
+                f,
+                "Memory file size {:#x} is not a multiple of the page size",
+                size
+            ),
+            Write(err) => write!(f, "Failed to write a snapshot layer: {}", err),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Number of pages and pages saved by the compaction of a snapshot.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CompactStats {
+    /// Pages of the memory file.
+    pub pages: u64,
+    /// Zero pages left as holes instead of being written.
+    pub zero_pages: u64,
+    /// Pages read from the overlay file, flattened into the output.
+    pub flattened_pages: u64,
+    /// Pages identical to the base snapshot, shared instead of being written.
+    pub dedup_pages: u64,
+    /// Zero pages dropped from the working set file.
+    pub ws_zero_pages: u64,
+}
+
+impl fmt::Display for CompactStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} pages, {} zero pages elided, {} flattened from the overlay, {} deduplicated, \
+             {} zero pages dropped from the WS",
+            self.pages, self.zero_pages, self.flattened_pages, self.dedup_pages, self.ws_zero_pages
+        )
+    }
+}
+
+/// Guest memory of a snapshot, as the memory file with the overlay file mapped over it.
+pub struct Layers {
+    mem_file: File,
+    overlay_file: Option<File>,
+    // Whether each page of the memory file is read from the overlay file.
+    overlay_pages: Vec<bool>,
+    page_size: u64,
+}
+
+impl Layers {
+    /// Opens the memory file at `mem_file_path` and, if `overlay_file_path` is not empty, the
+    /// overlay file providing the `overlay_regions` pages.
+    pub fn open(
+        mem_file_path: &Path,
+        overlay_file_path: &Path,
+        overlay_regions: &HashMap<i64, i64>,
+        page_size: u64,
+    ) -> Result<Self> {
+        let mem_file = open(mem_file_path)?;
+        let size = mem_file
+            .metadata()
+            .map_err(|e| Error::Open(mem_file_path.to_path_buf(), e))?
+            .len();
+        if size % page_size != 0 {
+            return Err(Error::UnalignedMemFile(size));
+        }
+
+        let mut overlay_pages = vec![false; (size / page_size) as usize];
+        // An empty path means the layer is not used, as in the load manifest.
+        let overlay_file = if overlay_file_path.as_os_str().is_empty() {
+            None
+        } else {
+            for (page, pages) in overlay_regions {
+                let range = overlay_pages
+                    .get_mut(*page as usize..(*page + *pages) as usize)
+                    .filter(|_| *page >= 0 && *pages >= 0)
+                    .ok_or(Error::InvalidRegion(*page, *pages))?;
+                range.iter_mut().for_each(|overlay| *overlay = true);
+            }
+            Some(open(overlay_file_path)?)
+        };
+
+        Ok(Layers {
+            mem_file,
+            overlay_file,
+            overlay_pages,
+            page_size,
+        })
+    }
+
+    /// Number of pages of the guest memory.
+    pub fn pages(&self) -> u64 {
+        self.overlay_pages.len() as u64
+    }
+
+    // Reads `page` to `buf`, returning whether it comes from the overlay file.
+    fn read_page(&self, page: u64, buf: &mut [u8]) -> Result<bool> {
+        let offset = page * self.page_size;
+        match &self.overlay_file {
+            Some(overlay_file) if self.overlay_pages[page as usize] => {
+                overlay_file
+                    .read_exact_at(buf, offset)
+                    .map_err(Error::Read)?;
+                Ok(true)
+            }
+            _ => {
+                self.mem_file
+                    .read_exact_at(buf, offset)
+                    .map_err(Error::Read)?;
+                Ok(false)
+            }
+        }
+    }
+}
+
+fn open(path: &Path) -> Result<File> {
+    File::open(path).map_err(|e| Error::Open(path.to_path_buf(), e))
+}
+
+/// Creates the sparse file `path` of the size of `layers`, the holes reading as zero pages.
+pub fn create_sparse(path: &Path, layers: &Layers) -> Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map_err(|e| Error::Open(path.to_path_buf(), e))?;
+    file.set_len(layers.pages() * layers.page_size)
+        .map_err(Error::Write)?;
+    Ok(file)
+}
+
+fn is_zero(page: &[u8]) -> bool {
+    page.iter().all(|byte| *byte == 0)
+}
+
+/// Writes the guest memory of `input` to the sparse file `mem_file`, leaving the zero pages as
+/// holes.
+pub fn flatten(input: &Layers, mem_file: &File, stats: &mut CompactStats) -> Result<()> {
+    let mut buf = vec![0u8; input.page_size as usize];
+    for page in 0..input.pages() {
+        if input.read_page(page, &mut buf)? {
+            stats.flattened_pages += 1;
+        }
+        if is_zero(&buf) {
+            stats.zero_pages += 1;
+            continue;
+        }
+        mem_file
+            .write_all_at(&buf, page * input.page_size)
+            .map_err(Error::Write)?;
+    }
+    Ok(())
+}
+
+/// Writes the pages of `input` which differ from `base` to the sparse file `overlay_file`,
+/// leaving the zero pages as holes. Returns the overlay regions mapping them over `base`.
+pub fn dedup(
+    input: &Layers,
+    base: &Layers,
+    overlay_file: &File,
+    stats: &mut CompactStats,
+) -> Result<HashMap<i64, i64>> {
+    let mut buf = vec![0u8; input.page_size as usize];
+    let mut base_buf = vec![0u8; input.page_size as usize];
+    let mut regions = HashMap::new();
+    let mut region: Option<(u64, u64)> = None;
+    for page in 0..input.pages() {
+        if input.read_page(page, &mut buf)? {
+            stats.flattened_pages += 1;
+        }
+        base.read_page(page, &mut base_buf)?;
+        if buf == base_buf {
+            stats.dedup_pages += 1;
+            continue;
+        }
+
+        region = match region {
+            Some((start, pages)) if start + pages == page => Some((start, pages + 1)),
+            previous => {
+                if let Some((start, pages)) = previous {
+                    regions.insert(start as i64, pages as i64);
+                }
+                Some((page, 1))
+            }
+        };
+        if is_zero(&buf) {
+            stats.zero_pages += 1;
+            continue;
+        }
+        overlay_file
+            .write_all_at(&buf, page * input.page_size)
+            .map_err(Error::Write)?;
+    }
+    if let Some((start, pages)) = region {
+        regions.insert(start as i64, pages as i64);
+    }
+    Ok(regions)
+}
+
+/// Copies the working set pages of `ws_file`, laid out back to back as described by
+/// `ws_regions`, to `output`, dropping the zero pages which are zero in `memory` as well.
+/// Returns the regions of the pages written.
+pub fn compact_ws<W: Write>(
+    ws_file: &File,
+    ws_regions: &[Vec<i64>],
+    memory: &Layers,
+    output: &mut W,
+    stats: &mut CompactStats,
+) -> Result<Vec<Vec<i64>>> {
+    let mut buf = vec![0u8; memory.page_size as usize];
+    let mut mem_buf = vec![0u8; memory.page_size as usize];
+    let mut regions: Vec<Vec<i64>> = Vec::new();
+    let mut offset = 0;
+    for region in ws_regions {
+        let (start, pages) = match region.as_slice() {
+            [page, pages] if *page >= 0 && *pages >= 0 && page + pages <= memory.pages() as i64 => {
+                (*page as u64, *pages as u64)
+            }
+            _ => {
+                return Err(Error::InvalidRegion(
+                    region.get(0).copied().unwrap_or(-1),
+                    region.get(1).copied().unwrap_or(-1),
+                ))
+            }
+        };
+        for page in start..start + pages {
+            ws_file
+                .read_exact_at(&mut buf, offset)
+                .map_err(Error::Read)?;
+            offset += memory.page_size;
+            if is_zero(&buf) {
+                memory.read_page(page, &mut mem_buf)?;
+                if is_zero(&mem_buf) {
+                    stats.ws_zero_pages += 1;
+                    continue;
+                }
+            }
+            output.write_all(&buf).map_err(Error::Write)?;
+            match regions.last_mut() {
+                Some(last) if (last[0] + last[1]) as u64 == page => last[1] += 1,
+                _ => regions.push(vec![page as i64, 1]),
+            }
+        }
+    }
+    output.flush().map_err(Error::Write)?;
+    Ok(regions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempfile::TempFile;
+
+    const PAGE_SIZE: u64 = 0x1000;
+
+    // Creates a file of `pages`, each filled with its byte.
+    fn file_of(pages: &[u8]) -> TempFile {
+        let file = TempFile::new().unwrap();
+        for byte in pages {
+            file.as_file()
+                .write_all(&[*byte; PAGE_SIZE as usize])
+                .unwrap();
+        }
+        file
+    }
+
+    fn layers(mem_file: &TempFile, overlay: Option<(&TempFile, &[(i64, i64)])>) -> Layers {
+        let (overlay_path, regions) = match overlay {
+            Some((file, regions)) => (
+                file.as_path().to_path_buf(),
+                regions.iter().copied().collect(),
+            ),
+            None => (PathBuf::new(), HashMap::new()),
+        };
+        Layers::open(mem_file.as_path(), &overlay_path, &regions, PAGE_SIZE).unwrap()
+    }
+
+    fn read_pages(file: &File, pages: u64) -> Vec<u8> {
+        (0..pages)
+            .map(|page| {
+                let mut byte = [0u8];
+                file.read_exact_at(&mut byte, page * PAGE_SIZE).unwrap();
+                byte[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_layers() {
+        let mem_file = file_of(&[1, 2, 3, 4]);
+        let overlay_file = file_of(&[0, 5, 6, 0]);
+        let input = layers(&mem_file, Some((&overlay_file, &[(1, 2)])));
+        assert_eq!(input.pages(), 4);
+
+        let mut buf = vec![0u8; PAGE_SIZE as usize];
+        assert!(!input.read_page(0, &mut buf).unwrap());
+        assert_eq!(buf[0], 1);
+        assert!(input.read_page(2, &mut buf).unwrap());
+        assert_eq!(buf[0], 6);
+
+        let regions = [(3, 2)].iter().copied().collect();
+        match Layers::open(
+            mem_file.as_path(),
+            overlay_file.as_path(),
+            &regions,
+            PAGE_SIZE,
+        ) {
+            Err(Error::InvalidRegion(3, 2)) => (),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Unexpected success"),
+        }
+        match Layers::open(mem_file.as_path(), Path::new(""), &HashMap::new(), 0x3000) {
+            Err(Error::UnalignedMemFile(0x4000)) => (),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Unexpected success"),
+        }
+    }
+
+    #[test]
+    fn test_flatten() {
+        let mem_file = file_of(&[1, 0, 3, 4]);
+        let overlay_file = file_of(&[0, 0, 6, 0]);
+        let input = layers(&mem_file, Some((&overlay_file, &[(2, 2)])));
+
+        let output = TempFile::new().unwrap();
+        let file = create_sparse(output.as_path(), &input).unwrap();
+        let mut stats = CompactStats::default();
+        flatten(&input, &file, &mut stats).unwrap();
+        assert_eq!(read_pages(&file, 4), vec![1, 0, 6, 0]);
+        assert_eq!(
+            stats,
+            CompactStats {
+                pages: 0,
+                zero_pages: 2,
+                flattened_pages: 2,
+                dedup_pages: 0,
+                ws_zero_pages: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_dedup() {
+        let base_file = file_of(&[1, 2, 3, 4, 5]);
+        let base = layers(&base_file, None);
+        let mem_file = file_of(&[1, 7, 0, 4, 8]);
+        let input = layers(&mem_file, None);
+
+        let output = TempFile::new().unwrap();
+        let file = create_sparse(output.as_path(), &input).unwrap();
+        let mut stats = CompactStats::default();
+        let regions = dedup(&input, &base, &file, &mut stats).unwrap();
+        assert_eq!(
+            regions,
+            [(1, 2), (4, 1)].iter().copied().collect::<HashMap<_, _>>()
+        );
+        assert_eq!(read_pages(&file, 5), vec![0, 7, 0, 0, 8]);
+        assert_eq!(stats.dedup_pages, 2);
+        assert_eq!(stats.zero_pages, 1);
+
+        // The deduplicated snapshot reads as the input.
+        let output =
+            Layers::open(base_file.as_path(), output.as_path(), &regions, PAGE_SIZE).unwrap();
+        let mut buf = vec![0u8; PAGE_SIZE as usize];
+        for (page, byte) in [1, 7, 0, 4, 8].iter().enumerate() {
+            output.read_page(page as u64, &mut buf).unwrap();
+            assert!(buf.iter().all(|b| b == byte));
+        }
+    }
+
+    #[test]
+    fn test_compact_ws() {
+        let mem_file = file_of(&[1, 0, 3, 4, 5, 6]);
+        let memory = layers(&mem_file, None);
+        // Page 2 is zero in the WS but not in the memory file, so it is kept.
+        let ws_file = file_of(&[4, 5, 0, 0, 1]);
+        let ws_regions = vec![vec![3, 2], vec![1, 2], vec![0, 1]];
+
+        let mut output = Vec::new();
+        let mut stats = CompactStats::default();
+        let regions = compact_ws(
+            ws_file.as_file(),
+            &ws_regions,
+            &memory,
+            &mut output,
+            &mut stats,
+        )
+        .unwrap();
+        assert_eq!(regions, vec![vec![3, 2], vec![2, 1], vec![0, 1]]);
+        assert_eq!(output.len() as u64, 4 * PAGE_SIZE);
+        assert_eq!(stats.ws_zero_pages, 1);
+
+        let mut stats = CompactStats::default();
+        let ws_regions = vec![vec![5, 2]];
+        match compact_ws(
+            ws_file.as_file(),
+            &ws_regions,
+            &memory,
+            &mut output,
+            &mut stats,
+        ) {
+            Err(Error::InvalidRegion(5, 2)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rewrites the snapshots described by snapshot load manifests, the bodies of
+//! `PUT /snapshot/load` requests, to reclaim storage: the overlay file is flattened into the
+//! memory file, zero pages are left as holes, and the pages identical to those of the first
+//! snapshot are shared with it instead of being copied.
+
+mod compact;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use utils::arg_parser::{ArgParser, Argument, Arguments, Error as ParsingError};
+use vmm::vmm_config::snapshot::LoadSnapshotParams;
+
+use crate::compact::{compact_ws, create_sparse, dedup, flatten, CompactStats, Layers};
+
+const SNAPSHOT_COMPACT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const SNAPSHOT_FILE: &str = "snapshot_file";
+const MEM_FILE: &str = "mem_file";
+const OVERLAY_FILE: &str = "overlay_file";
+const WS_FILE: &str = "ws_file";
+const MANIFEST_FILE: &str = "load.json";
+
+#[derive(Debug)]
+enum Error {
+    ArgumentParsing(ParsingError),
+    Compact(PathBuf, compact::Error),
+    DuplicateName(String),
+    InheritedFd(PathBuf),
+    Io(PathBuf, io::Error),
+    Manifest(PathBuf, serde_json::Error),
+    MissingManifest,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            ArgumentParsing(err) => write!(f, "Failed to parse arguments: {}", err),
+            Compact(path, err) => write!(f, "Failed to compact {}: {}", path.display(), err),
+            DuplicateName(name) => write!(f, "Several manifests are named {}", name),
+            InheritedFd(path) => write!(
+                f,
+                "Manifest {} uses inherited file descriptors instead of paths",
+                path.display()
+            ),
+            Io(path, err) => write!(f, "Failed to write {}: {}", path.display(), err),
+            Manifest(path, err) => write!(f, "Invalid manifest {}: {}", path.display(), err),
+            MissingManifest => write!(f, "No manifest, list them after --"),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new()
+        .arg(
+            Argument::new("output-dir")
+                .required(true)
+                .takes_value(true)
+                .help("Directory the compacted snapshots are written to, one per manifest."),
+        )
+        .arg(Argument::new("dedup").takes_value(false).help(
+            "Share the pages identical to those of the first snapshot, which the others \
+             then use as their memory file.",
+        ))
+}
+
+fn read_manifest(path: &Path) -> Result<LoadSnapshotParams> {
+    let file = File::open(path).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+    let manifest: LoadSnapshotParams = serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| Error::Manifest(path.to_path_buf(), e))?;
+    if manifest.mem_file_fd.is_some()
+        || manifest.overlay_file_fd.is_some()
+        || manifest.ws_file_fd.is_some()
+    {
+        return Err(Error::InheritedFd(path.to_path_buf()));
+    }
+    Ok(manifest)
+}
+
+// Compacts the snapshot of `manifest` to `dir`, sharing the pages of `base` if set. Updates the
+// manifest to the compacted files.
+fn compact_snapshot(
+    manifest: &mut LoadSnapshotParams,
+    dir: &Path,
+    base: Option<&Path>,
+    page_size: u64,
+) -> compact::Result<CompactStats> {
+    let input = Layers::open(
+        &manifest.mem_file_path,
+        &manifest.overlay_file_path,
+        &manifest.overlay_regions,
+        page_size,
+    )?;
+    let mut stats = CompactStats {
+        pages: input.pages(),
+        ..Default::default()
+    };
+
+    let base = match base {
+        Some(path) => Some((
+            path,
+            Layers::open(path, Path::new(""), &Default::default(), page_size)?,
+        )),
+        None => None,
+    };
+    match base {
+        // Snapshots of a different memory size cannot share pages.
+        Some((base_path, base)) if base.pages() == input.pages() => {
+            let overlay_path = dir.join(OVERLAY_FILE);
+            let overlay_file = create_sparse(&overlay_path, &input)?;
+            manifest.overlay_regions = dedup(&input, &base, &overlay_file, &mut stats)?;
+            manifest.mem_file_path = base_path.to_path_buf();
+            manifest.overlay_file_path = if manifest.overlay_regions.is_empty() {
+                PathBuf::new()
+            } else {
+                overlay_path
+            };
+        }
+        _ => {
+            let mem_path = dir.join(MEM_FILE);
+            flatten(&input, &create_sparse(&mem_path, &input)?, &mut stats)?;
+            manifest.mem_file_path = mem_path;
+            manifest.overlay_file_path = PathBuf::new();
+            manifest.overlay_regions.clear();
+        }
+    }
+
+    if !manifest.ws_file_path.as_os_str().is_empty() {
+        let output = Layers::open(
+            &manifest.mem_file_path,
+            &manifest.overlay_file_path,
+            &manifest.overlay_regions,
+            page_size,
+        )?;
+        let ws_file = File::open(&manifest.ws_file_path)
+            .map_err(|e| compact::Error::Open(manifest.ws_file_path.clone(), e))?;
+        let ws_path = dir.join(WS_FILE);
+        let mut ws_output = BufWriter::new(
+            File::create(&ws_path).map_err(|e| compact::Error::Open(ws_path.clone(), e))?,
+        );
+        manifest.ws_regions = compact_ws(
+            &ws_file,
+            &manifest.ws_regions,
+            &output,
+            &mut ws_output,
+            &mut stats,
+        )?;
+        manifest.ws_file_path = ws_path;
+    }
+    Ok(stats)
+}
+
+fn run(arguments: &Arguments) -> Result<()> {
+    // The required arguments are checked by the parser.
+    let output_dir = PathBuf::from(arguments.value_as_string("output-dir").unwrap_or_default());
+    let share_pages = arguments.value_as_bool("dedup").unwrap_or(false);
+    let manifests = arguments.extra_args();
+    if manifests.is_empty() {
+        return Err(Error::MissingManifest);
+    }
+    let page_size = sysconf::page::pagesize() as u64;
+
+    let mut names = HashSet::new();
+    let mut base: Option<PathBuf> = None;
+    for manifest_path in manifests.iter().map(PathBuf::from) {
+        let mut manifest = read_manifest(&manifest_path)?;
+        let name = manifest_path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if !names.insert(name.clone()) {
+            return Err(Error::DuplicateName(name));
+        }
+        let dir = output_dir.join(&name);
+        fs::create_dir_all(&dir).map_err(|e| Error::Io(dir.clone(), e))?;
+
+        let stats = compact_snapshot(&mut manifest, &dir, base.as_deref(), page_size)
+            .map_err(|e| Error::Compact(manifest_path.clone(), e))?;
+        if share_pages && base.is_none() {
+            base = Some(manifest.mem_file_path.clone());
+        }
+
+        let snapshot_path = dir.join(SNAPSHOT_FILE);
+        fs::copy(&manifest.snapshot_path, &snapshot_path)
+            .map_err(|e| Error::Io(snapshot_path.clone(), e))?;
+        manifest.snapshot_path = snapshot_path;
+        let compacted_path = dir.join(MANIFEST_FILE);
+        let compacted =
+            File::create(&compacted_path).map_err(|e| Error::Io(compacted_path.clone(), e))?;
+        serde_json::to_writer_pretty(compacted, &manifest)
+            .map_err(|e| Error::Manifest(compacted_path.clone(), e))?;
+
+        println!("{}: {}", compacted_path.display(), stats);
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+
+    if let Err(err) = arg_parser.parse_from_cmdline() {
+        eprintln!(
+            "{} \n\n\
+             For more information try --help.",
+            Error::ArgumentParsing(err)
+        );
+        process::exit(1);
+    }
+    if arg_parser
+        .arguments()
+        .value_as_bool("help")
+        .unwrap_or(false)
+    {
+        println!("snapshot-compact v{}\n", SNAPSHOT_COMPACT_VERSION);
+        println!("{}", arg_parser.formatted_help());
+        process::exit(0);
+    }
+    if arg_parser
+        .arguments()
+        .value_as_bool("version")
+        .unwrap_or(false)
+    {
+        println!("snapshot-compact v{}\n", SNAPSHOT_COMPACT_VERSION);
+        process::exit(0);
+    }
+
+    if let Err(err) = run(arg_parser.arguments()) {
+        eprintln!("snapshot-compact error: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// Currently only supports x86_64.
+#![cfg(target_arch = "x86_64")]
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use utils::arg_parser::{ArgParser, Argument, Arguments, Error as ParsingError};
+use vmm::vmm_config::snapshot::LoadSnapshotParams;
+
+use crate::convert::{
+    self, flatten_memory, fork_manifest, read_state, target_data_version, write_state,
+};
+
+const SNAPSHOT_FILE: &str = "snapshot_file";
+const MEM_FILE: &str = "mem_file";
+const MANIFEST_FILE: &str = "load.json";
+
+#[derive(Debug)]
+pub enum Error {
+    ArgumentParsing(ParsingError),
+    Convert(convert::Error),
+    InheritedFd(PathBuf),
+    InvalidTarget(String),
+    Io(PathBuf, io::Error),
+    Manifest(PathBuf, serde_json::Error),
+    MissingArgument(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            ArgumentParsing(err) => write!(f, "Failed to parse arguments: {}", err),
+            Convert(err) => write!(f, "{}", err),
+            InheritedFd(path) => write!(
+                f,
+                "Manifest {} uses inherited file descriptors instead of paths",
+                path.display()
+            ),
+            InvalidTarget(target) => {
+                write!(f, "Invalid --to {}, expected fork or upstream", target)
+            }
+            Io(path, err) => write!(f, "Failed to write {}: {}", path.display(), err),
+            Manifest(path, err) => {
+                write!(f, "Invalid manifest {}: {}", path.display(), err)
+            }
+            MissingArgument(arg) => write!(f, "Argument --{} is required", arg),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new()
+        .arg(
+            Argument::new("to")
+                .required(true)
+                .takes_value(true)
+                .help("Format to convert to: fork or upstream."),
+        )
+        .arg(
+            Argument::new("output-dir")
+                .required(true)
+                .takes_value(true)
+                .help("Directory the converted snapshot is written to."),
+        )
+        .arg(
+            Argument::new("snapshot")
+                .takes_value(true)
+                .help("Upstream microVM state file, when converting to fork."),
+        )
+        .arg(
+            Argument::new("mem-file")
+                .takes_value(true)
+                .help("Upstream memory file, when converting to fork."),
+        )
+        .arg(Argument::new("manifest").takes_value(true).help(
+            "Snapshot load manifest, the JSON body of a PUT /snapshot/load request, \
+             when converting to upstream.",
+        ))
+        .arg(Argument::new("target-version").takes_value(true).help(
+            "Firecracker version the microVM state is written for. Defaults to the \
+             latest one.",
+        ))
+}
+
+fn path_arg(arguments: &Arguments, arg: &'static str) -> Result<PathBuf> {
+    arguments
+        .value_as_string(arg)
+        .map(PathBuf::from)
+        .ok_or(Error::MissingArgument(arg))
+}
+
+fn read_manifest(path: &Path) -> Result<LoadSnapshotParams> {
+    let file = File::open(path).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+    let manifest: LoadSnapshotParams = serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| Error::Manifest(path.to_path_buf(), e))?;
+    if manifest.mem_file_fd.is_some() || manifest.overlay_file_fd.is_some() {
+        return Err(Error::InheritedFd(path.to_path_buf()));
+    }
+    Ok(manifest)
+}
+
+// Converts the upstream snapshot pair of `arguments` to `dir`.
+fn to_fork(arguments: &Arguments, dir: &Path, data_version: u16) -> Result<()> {
+    let (_, state) = read_state(&path_arg(arguments, "snapshot")?).map_err(Error::Convert)?;
+    let mem_file_path = path_arg(arguments, "mem-file")?;
+
+    let snapshot_path = dir.join(SNAPSHOT_FILE);
+    write_state(&snapshot_path, &state, data_version).map_err(Error::Convert)?;
+    let mem_path = dir.join(MEM_FILE);
+    fs::copy(&mem_file_path, &mem_path).map_err(|e| Error::Io(mem_path.clone(), e))?;
+
+    let manifest_path = dir.join(MANIFEST_FILE);
+    let manifest = File::create(&manifest_path).map_err(|e| Error::Io(manifest_path.clone(), e))?;
+    serde_json::to_writer_pretty(manifest, &fork_manifest(&snapshot_path, &mem_path))
+        .map_err(|e| Error::Manifest(manifest_path.clone(), e))?;
+    println!("{}", manifest_path.display());
+    Ok(())
+}
+
+// Converts the snapshot of the manifest of `arguments` to an upstream pair in `dir`.
+fn to_upstream(arguments: &Arguments, dir: &Path, data_version: u16) -> Result<()> {
+    let manifest = read_manifest(&path_arg(arguments, "manifest")?)?;
+    let (_, state) = read_state(&manifest.snapshot_path).map_err(Error::Convert)?;
+
+    let snapshot_path = dir.join(SNAPSHOT_FILE);
+    write_state(&snapshot_path, &state, data_version).map_err(Error::Convert)?;
+    let mem_path = dir.join(MEM_FILE);
+    let page_size = state.memory_state.page_size as u64;
+    let overlay_pages = flatten_memory(&manifest, &mem_path, page_size).map_err(Error::Convert)?;
+    println!(
+        "{} {}: {} overlay pages flattened, {} working set extents dropped",
+        snapshot_path.display(),
+        mem_path.display(),
+        overlay_pages,
+        manifest.ws_regions.len()
+    );
+    Ok(())
+}
+
+pub fn run(arguments: &Arguments) -> Result<()> {
+    // The required arguments are checked by the parser.
+    let dir = PathBuf::from(arguments.value_as_string("output-dir").unwrap_or_default());
+    let data_version = target_data_version(arguments.value_as_string("target-version").as_deref())
+        .map_err(Error::Convert)?;
+    fs::create_dir_all(&dir).map_err(|e| Error::Io(dir.clone(), e))?;
+
+    match arguments.value_as_string("to").unwrap_or_default().as_str() {
+        "fork" => to_fork(arguments, &dir, data_version),
+        "upstream" => to_upstream(arguments, &dir, data_version),
+        target => Err(Error::InvalidTarget(target.to_string())),
+    }
+}
@@ -0,0 +1,308 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// Currently only supports x86_64.
+#![cfg(target_arch = "x86_64")]
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use snapshot::Snapshot;
+use versionize::Versionize;
+use vmm::persist::MicrovmState;
+use vmm::version_map::{FC_VERSION_TO_SNAP_VERSION, VERSION_MAP};
+use vmm::vmm_config::snapshot::LoadSnapshotParams;
+
+#[derive(Debug)]
+pub enum Error {
+    DeserializeState(PathBuf, snapshot::Error),
+    FlattenOverlay(io::Error),
+    InvalidRegion(i64, i64),
+    InvalidVersion(String),
+    Open(PathBuf, io::Error),
+    SerializeState(PathBuf, snapshot::Error),
+    TrailingData(PathBuf, usize),
+    UnsupportedDataVersion(u16),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            DeserializeState(path, err) => write!(
+                f,
+                "Failed to deserialize the microVM state from {}: {:?}",
+                path.display(),
+                err
+            ),
+            FlattenOverlay(err) => write!(f, "Failed to flatten the overlay file: {}", err),
+            InvalidRegion(page, pages) => write!(
+                f,
+                "Overlay region of {} pages at page {} is invalid",
+                pages, page
+            ),
+            InvalidVersion(version) => write!(
+                f,
+                "Cannot convert to Firecracker version {}, supported versions: {}",
+                version,
+                supported_versions().join(", ")
+            ),
+            Open(path, err) => write!(f, "Failed to open {}: {}", path.display(), err),
+            SerializeState(path, err) => write!(
+                f,
+                "Failed to serialize the microVM state to {}: {:?}",
+                path.display(),
+                err
+            ),
+            TrailingData(path, len) => write!(
+                f,
+                "{} has {} bytes after the microVM state",
+                path.display(),
+                len
+            ),
+            UnsupportedDataVersion(version) => write!(
+                f,
+                "Unsupported snapshot data version {}, the supported Firecracker versions are {}",
+                version,
+                supported_versions().join(", ")
+            ),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Size of the CRC64 which later upstream releases append to the microVM state.
+const CRC64_SIZE: usize = 8;
+
+fn supported_versions() -> Vec<String> {
+    let mut versions: Vec<String> = FC_VERSION_TO_SNAP_VERSION.keys().cloned().collect();
+    versions.sort();
+    versions
+}
+
+/// Returns the snapshot data version of the Firecracker `version`, or the latest one.
+pub fn target_data_version(version: Option<&str>) -> Result<u16> {
+    match version {
+        Some(version) => FC_VERSION_TO_SNAP_VERSION
+            .get(version)
+            .copied()
+            .ok_or_else(|| Error::InvalidVersion(version.to_string())),
+        None => Ok(VERSION_MAP.latest_version()),
+    }
+}
+
+/// Reads the microVM state file at `path`, with or without the trailing CRC64.
+pub fn read_state(path: &Path) -> Result<(u16, MicrovmState)> {
+    let bytes = fs::read(path).map_err(|e| Error::Open(path.to_path_buf(), e))?;
+    let mut reader = bytes.as_slice();
+    let data_version = Snapshot::get_data_version(&mut reader)
+        .map_err(|e| Error::DeserializeState(path.to_path_buf(), e))?;
+    // The state types of the data versions this version map does not know are unknown too.
+    if data_version == 0 || data_version > VERSION_MAP.latest_version() {
+        return Err(Error::UnsupportedDataVersion(data_version));
+    }
+    let state = MicrovmState::deserialize(&mut reader, &VERSION_MAP, data_version)
+        .map_err(|e| Error::DeserializeState(path.to_path_buf(), snapshot::Error::Versionize(e)))?;
+
+    match reader.len() {
+        0 => (),
+        CRC64_SIZE => {
+            Snapshot::load_with_crc64::<_, MicrovmState>(
+                &mut bytes.as_slice(),
+                VERSION_MAP.clone(),
+            )
+            .map_err(|e| Error::DeserializeState(path.to_path_buf(), e))?;
+        }
+        len => return Err(Error::TrailingData(path.to_path_buf(), len)),
+    }
+    Ok((data_version, state))
+}
+
+/// Writes `state` to the microVM state file at `path`, in the `data_version` format.
+pub fn write_state(path: &Path, state: &MicrovmState, data_version: u16) -> Result<()> {
+    let mut file = File::create(path).map_err(|e| Error::Open(path.to_path_buf(), e))?;
+    Snapshot::new(VERSION_MAP.clone(), data_version)
+        .save(&mut file, state)
+        .map_err(|e| Error::SerializeState(path.to_path_buf(), e))
+}
+
+/// Writes the memory file of `manifest` with its overlay file mapped over it to `path`.
+/// Returns the number of pages read from the overlay file.
+pub fn flatten_memory(manifest: &LoadSnapshotParams, path: &Path, page_size: u64) -> Result<u64> {
+    fs::copy(&manifest.mem_file_path, path)
+        .map_err(|e| Error::Open(manifest.mem_file_path.clone(), e))?;
+    // An empty path means the layer is not used.
+    if manifest.overlay_file_path.as_os_str().is_empty() {
+        return Ok(0);
+    }
+
+    let overlay_file = File::open(&manifest.overlay_file_path)
+        .map_err(|e| Error::Open(manifest.overlay_file_path.clone(), e))?;
+    let mem_file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(|e| Error::Open(path.to_path_buf(), e))?;
+    let mut buf = Vec::new();
+    let mut overlay_pages = 0;
+    for (page, pages) in &manifest.overlay_regions {
+        if *page < 0 || *pages < 0 {
+            return Err(Error::InvalidRegion(*page, *pages));
+        }
+        let offset = *page as u64 * page_size;
+        buf.resize((*pages as u64 * page_size) as usize, 0);
+        overlay_file
+            .read_exact_at(&mut buf, offset)
+            .map_err(Error::FlattenOverlay)?;
+        mem_file
+            .write_all_at(&buf, offset)
+            .map_err(Error::FlattenOverlay)?;
+        overlay_pages += *pages as u64;
+    }
+    Ok(overlay_pages)
+}
+
+/// Returns the load manifest of the snapshot at `snapshot_path` and `mem_file_path`, without
+/// overlay or working set layers.
+pub fn fork_manifest(snapshot_path: &Path, mem_file_path: &Path) -> LoadSnapshotParams {
+    LoadSnapshotParams {
+        snapshot_path: snapshot_path.to_path_buf(),
+        mem_file_path: mem_file_path.to_path_buf(),
+        mem_file_fd: None,
+        enable_diff_snapshots: false,
+        enable_user_page_faults: false,
+        sock_file_path: PathBuf::new(),
+        overlay_file_path: PathBuf::new(),
+        overlay_file_fd: None,
+        overlay_regions: HashMap::new(),
+        ws_file_path: PathBuf::new(),
+        ws_file_fd: None,
+        ws_regions: Vec::new(),
+        load_ws: false,
+        fadvise: String::new(),
+        layer_fadvise: None,
+        fault_trace_path: None,
+        ws_accounting: false,
+        watchdog: None,
+        mem_size_mib: None,
+        mem_backend: None,
+        resume_vm: false,
+        netns_path: None,
+        netns_fd: None,
+        network_overrides: Vec::new(),
+        warm_notify: None,
+        prefetch_throttle: None,
+        prefetch: None,
+        prefetch_coordinator: None,
+        guest_agent: None,
+        ws_lock: None,
+        ws_populate: false,
+        ksm: false,
+        ws_staging: None,
+        ws_load_deadline: None,
+        strict_memory: false,
+        protect_base: false,
+        reject_layer_conflicts: false,
+        huge_pages: false,
+        numa_node: None,
+        profile_path: None,
+        profile: None,
+        fault_attribution: false,
+        idle_reclaim: None,
+        snapshot_cache: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use utils::tempfile::TempFile;
+    use versionize::VersionMap;
+
+    const PAGE_SIZE: u64 = 0x1000;
+
+    #[test]
+    fn test_target_data_version() {
+        assert_eq!(
+            target_data_version(None).unwrap(),
+            VERSION_MAP.latest_version()
+        );
+        assert_eq!(target_data_version(Some("0.23.0")).unwrap(), 1);
+        match target_data_version(Some("0.22.0")) {
+            Err(Error::InvalidVersion(version)) => assert_eq!(version, "0.22.0"),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_read_state_errors() {
+        let state = TempFile::new().unwrap();
+        match read_state(state.as_path()) {
+            Err(Error::DeserializeState(..)) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        // A state file of a later data version.
+        let mut bytes = Vec::new();
+        Snapshot::new(VersionMap::new(), 7)
+            .save(&mut bytes, &0u64)
+            .unwrap();
+        state.as_file().write_all(&bytes).unwrap();
+        match read_state(state.as_path()) {
+            Err(Error::UnsupportedDataVersion(7)) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_flatten_memory() {
+        let mem_file = TempFile::new().unwrap();
+        mem_file
+            .as_file()
+            .write_all(&[1u8; 4 * PAGE_SIZE as usize])
+            .unwrap();
+        let overlay_file = TempFile::new().unwrap();
+        overlay_file
+            .as_file()
+            .write_all(&[2u8; 4 * PAGE_SIZE as usize])
+            .unwrap();
+        let output = TempFile::new().unwrap();
+
+        let mut manifest = fork_manifest(Path::new("snapshot_file"), mem_file.as_path());
+        assert_eq!(
+            flatten_memory(&manifest, output.as_path(), PAGE_SIZE).unwrap(),
+            0
+        );
+        assert_eq!(
+            fs::read(output.as_path()).unwrap(),
+            fs::read(mem_file.as_path()).unwrap()
+        );
+
+        manifest.overlay_file_path = overlay_file.as_path().to_path_buf();
+        manifest.overlay_regions.insert(1, 2);
+        assert_eq!(
+            flatten_memory(&manifest, output.as_path(), PAGE_SIZE).unwrap(),
+            2
+        );
+        let flattened = fs::read(output.as_path()).unwrap();
+        let pages: Vec<u8> = flattened
+            .chunks(PAGE_SIZE as usize)
+            .map(|page| page[0])
+            .collect();
+        assert_eq!(pages, vec![1, 2, 2, 1]);
+
+        manifest.overlay_regions.insert(3, 2);
+        match flatten_memory(&manifest, output.as_path(), PAGE_SIZE) {
+            Err(Error::FlattenOverlay(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
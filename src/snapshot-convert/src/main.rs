@@ -0,0 +1,59 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Converts snapshots between upstream Firecracker and this fork: an upstream state and memory
+//! file pair becomes a snapshot with its load manifest, and a snapshot described by a load
+//! manifest becomes an upstream pair, with the overlay flattened into the memory file and the
+//! working set dropped.
+
+mod cli;
+mod convert;
+
+use std::process;
+
+const SNAPSHOT_CONVERT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[cfg(target_arch = "x86_64")]
+fn main() {
+    let mut arg_parser = cli::build_arg_parser();
+
+    if let Err(err) = arg_parser.parse_from_cmdline() {
+        eprintln!(
+            "{} \n\n\
+             For more information try --help.",
+            cli::Error::ArgumentParsing(err)
+        );
+        process::exit(1);
+    }
+    if arg_parser
+        .arguments()
+        .value_as_bool("help")
+        .unwrap_or(false)
+    {
+        println!("snapshot-convert v{}\n", SNAPSHOT_CONVERT_VERSION);
+        println!("{}", arg_parser.formatted_help());
+        process::exit(0);
+    }
+    if arg_parser
+        .arguments()
+        .value_as_bool("version")
+        .unwrap_or(false)
+    {
+        println!("snapshot-convert v{}\n", SNAPSHOT_CONVERT_VERSION);
+        process::exit(0);
+    }
+
+    if let Err(err) = cli::run(arg_parser.arguments()) {
+        eprintln!("snapshot-convert error: {}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn main() {
+    eprintln!(
+        "snapshot-convert v{}: snapshots are only supported on x86_64",
+        SNAPSHOT_CONVERT_VERSION
+    );
+    process::exit(1);
+}
@@ -0,0 +1,357 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// Currently only supports x86_64.
+#![cfg(target_arch = "x86_64")]
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use snapshot::Snapshot;
+use utils::arg_parser::{ArgParser, Argument, Arguments, Error as ParsingError};
+use versionize::Versionize;
+use vmm::persist::MicrovmState;
+use vmm::snapshot_check::check_snapshot;
+use vmm::snapshot_signing::{self, SnapshotKeys};
+use vmm::version_map::VERSION_MAP;
+use vmm::vmm_config::snapshot::LoadSnapshotParams;
+
+use crate::report::{FileReport, LayerLayout, LayerReport, Report, StateReport};
+
+#[derive(Debug)]
+pub enum Error {
+    ArgumentParsing(ParsingError),
+    CheckFailed(usize),
+    DeserializeState(PathBuf, snapshot::Error),
+    Manifest(PathBuf, serde_json::Error),
+    MissingSnapshot,
+    Open(PathBuf, io::Error),
+    SerializeReport(serde_json::Error),
+    VerificationKey(snapshot_signing::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            ArgumentParsing(err) => write!(f, "Failed to parse arguments: {}", err),
+            CheckFailed(count) => write!(f, "The snapshot check found {} problems", count),
+            DeserializeState(path, err) => write!(
+                f,
+                "Failed to deserialize the microVM state from {}: {:?}",
+                path.display(),
+                err
+            ),
+            Manifest(path, err) => {
+                write!(f, "Failed to parse manifest {}: {}", path.display(), err)
+            }
+            MissingSnapshot => write!(
+                f,
+                "No microVM state file, set either --snapshot or --manifest"
+            ),
+            Open(path, err) => write!(f, "Failed to open {}: {}", path.display(), err),
+            SerializeReport(err) => write!(f, "Failed to serialize the report: {}", err),
+            VerificationKey(err) => write!(f, "Failed to read the verification key: {}", err),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new()
+        .arg(
+            Argument::new("snapshot")
+                .takes_value(true)
+                .help("Path to the microVM state file. Overrides the manifest."),
+        )
+        .arg(
+            Argument::new("mem-file")
+                .takes_value(true)
+                .help("Path to the guest memory file. Overrides the manifest."),
+        )
+        .arg(Argument::new("manifest").takes_value(true).help(
+            "Path to the snapshot load manifest, the JSON body of a PUT /snapshot/load \
+             request. Describes the overlay and working set layers.",
+        ))
+        .arg(
+            Argument::new("json")
+                .takes_value(false)
+                .help("Print the report as JSON."),
+        )
+        .arg(
+            Argument::new("check").takes_value(false).help(
+                "Check the snapshot instead of describing it, and fail if a problem is found.",
+            ),
+        )
+        .arg(
+            Argument::new("verification-key")
+                .takes_value(true)
+                .requires("check")
+                .help("Public key the signed manifest of the snapshot files is checked with."),
+        )
+}
+
+fn file_size(path: &Path) -> Result<u64> {
+    fs::metadata(path)
+        .map(|metadata| metadata.len())
+        .map_err(|e| Error::Open(path.to_path_buf(), e))
+}
+
+// An empty path means the file is not used, as in the load manifest.
+fn layer_file(path: &Path) -> Result<Option<(String, u64)>> {
+    if path.as_os_str().is_empty() {
+        return Ok(None);
+    }
+    Ok(Some((path.display().to_string(), file_size(path)?)))
+}
+
+fn read_manifest(path: &Path) -> Result<LoadSnapshotParams> {
+    let file = File::open(path).map_err(|e| Error::Open(path.to_path_buf(), e))?;
+    serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| Error::Manifest(path.to_path_buf(), e))
+}
+
+fn read_state(path: &Path) -> Result<(u16, MicrovmState)> {
+    let file = File::open(path).map_err(|e| Error::Open(path.to_path_buf(), e))?;
+    let mut reader = BufReader::new(file);
+    let data_version = Snapshot::get_data_version(&mut reader)
+        .map_err(|e| Error::DeserializeState(path.to_path_buf(), e))?;
+    let state = MicrovmState::deserialize(&mut reader, &VERSION_MAP, data_version)
+        .map_err(|e| Error::DeserializeState(path.to_path_buf(), snapshot::Error::Versionize(e)))?;
+    Ok((data_version, state))
+}
+
+/// Builds the report of the snapshot file set described by `arguments`.
+pub fn build_report(arguments: &Arguments) -> Result<Report> {
+    let manifest = match arguments.value_as_string("manifest") {
+        Some(path) => Some(read_manifest(Path::new(&path))?),
+        None => None,
+    };
+    let snapshot_path = arguments
+        .value_as_string("snapshot")
+        .map(PathBuf::from)
+        .or_else(|| manifest.as_ref().map(|m| m.snapshot_path.clone()))
+        .ok_or(Error::MissingSnapshot)?;
+    let mem_file_path = arguments
+        .value_as_string("mem-file")
+        .map(PathBuf::from)
+        .or_else(|| manifest.as_ref().map(|m| m.mem_file_path.clone()));
+
+    let (data_version, state) = read_state(&snapshot_path)?;
+    let snapshot = StateReport::new(
+        snapshot_path.display().to_string(),
+        file_size(&snapshot_path)?,
+        data_version,
+        &state,
+    );
+    let mem_file = match mem_file_path {
+        Some(path) => layer_file(&path)?.map(|(path, size)| FileReport {
+            path,
+            size,
+            expected_size: state.memory_state.file_size(),
+        }),
+        None => None,
+    };
+
+    // The extents are counted in the pages of the snapshot.
+    let page_size = state.memory_state.page_size as u64;
+    let (overlay, ws) = match manifest {
+        Some(manifest) => {
+            let mut overlay_extents: Vec<(u64, u64)> = manifest
+                .overlay_regions
+                .iter()
+                .map(|(page, pages)| (*page as u64, *pages as u64))
+                .collect();
+            overlay_extents.sort();
+            let ws_extents: Vec<(u64, u64)> = manifest
+                .ws_regions
+                .iter()
+                .map(|item| (item[0] as u64, item[1] as u64))
+                .collect();
+            (
+                Some(LayerReport::new(
+                    &state.memory_state,
+                    &overlay_extents,
+                    LayerLayout::MemoryFile,
+                    layer_file(&manifest.overlay_file_path)?,
+                    page_size,
+                )),
+                Some(LayerReport::new(
+                    &state.memory_state,
+                    &ws_extents,
+                    LayerLayout::BackToBack,
+                    layer_file(&manifest.ws_file_path)?,
+                    page_size,
+                )),
+            )
+        }
+        None => (None, None),
+    };
+
+    Ok(Report {
+        snapshot,
+        mem_file,
+        overlay,
+        ws,
+    })
+}
+
+// Returns the load manifest of the snapshot file set described by `arguments`.
+fn check_params(arguments: &Arguments) -> Result<LoadSnapshotParams> {
+    let mut params = match arguments.value_as_string("manifest") {
+        Some(path) => read_manifest(Path::new(&path))?,
+        None => LoadSnapshotParams::default(),
+    };
+    if let Some(path) = arguments.value_as_string("snapshot") {
+        params.snapshot_path = PathBuf::from(path);
+    }
+    if let Some(path) = arguments.value_as_string("mem-file") {
+        params.mem_file_path = PathBuf::from(path);
+    }
+    if params.snapshot_path.as_os_str().is_empty() {
+        return Err(Error::MissingSnapshot);
+    }
+    Ok(params)
+}
+
+/// Checks the snapshot file set described by `arguments`, and prints the problems found.
+pub fn check(arguments: &Arguments) -> Result<()> {
+    let params = check_params(arguments)?;
+    let verification_key = arguments.value_as_string("verification-key");
+    let keys = SnapshotKeys::from_files(None, verification_key.as_ref().map(Path::new))
+        .map_err(Error::VerificationKey)?;
+
+    let report = check_snapshot(&params, &keys);
+    let findings: Vec<String> = report.findings.iter().map(|f| f.to_string()).collect();
+    if arguments.value_as_bool("json").unwrap_or(false) {
+        let report = serde_json::json!({
+            "data_version": report.data_version,
+            "findings": findings,
+        });
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).map_err(Error::SerializeReport)?
+        );
+    } else if findings.is_empty() {
+        println!("{}: ok", params.snapshot_path.display());
+    } else {
+        for finding in findings.iter() {
+            println!("{}: {}", params.snapshot_path.display(), finding);
+        }
+    }
+
+    if !report.is_ok() {
+        return Err(Error::CheckFailed(findings.len()));
+    }
+    Ok(())
+}
+
+/// Prints the report of the snapshot file set described by `arguments`.
+pub fn run(arguments: &Arguments) -> Result<()> {
+    if arguments.value_as_bool("check").unwrap_or(false) {
+        return check(arguments);
+    }
+    let report = build_report(arguments)?;
+    if arguments.value_as_bool("json").unwrap_or(false) {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).map_err(Error::SerializeReport)?
+        );
+    } else {
+        print!("{}", report);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use utils::tempfile::TempFile;
+
+    fn parse_args(args: &[&str]) -> Arguments<'static> {
+        let mut arguments = build_arg_parser().arguments().clone();
+        let args: Vec<String> = std::iter::once("snapshot-inspect")
+            .chain(args.iter().cloned())
+            .map(String::from)
+            .collect();
+        arguments.parse(&args).unwrap();
+        arguments
+    }
+
+    #[test]
+    fn test_build_report_errors() {
+        match build_report(&parse_args(&[])) {
+            Err(Error::MissingSnapshot) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+        match build_report(&parse_args(&["--snapshot", "/invalid/vmstate"])) {
+            Err(Error::Open(path, _)) => assert_eq!(path, PathBuf::from("/invalid/vmstate")),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        let manifest = TempFile::new().unwrap();
+        manifest
+            .as_file()
+            .write_all(b"{\"snapshot_path\": 1}")
+            .unwrap();
+        let manifest_path = manifest.as_path().to_str().unwrap();
+        match build_report(&parse_args(&["--manifest", manifest_path])) {
+            Err(Error::Manifest(path, _)) => assert_eq!(path, manifest.as_path()),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        let state = TempFile::new().unwrap();
+        state.as_file().write_all(&[0u8; 16]).unwrap();
+        let state_path = state.as_path().to_str().unwrap();
+        match build_report(&parse_args(&["--snapshot", state_path])) {
+            Err(Error::DeserializeState(_, snapshot::Error::InvalidMagic(0))) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        match check(&parse_args(&["--check"])) {
+            Err(Error::MissingSnapshot) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let state = TempFile::new().unwrap();
+        state.as_file().write_all(&[0u8; 16]).unwrap();
+        let state_path = state.as_path().to_str().unwrap();
+        let params = check_params(&parse_args(&["--check", "--snapshot", state_path])).unwrap();
+        assert_eq!(params.snapshot_path, state.as_path());
+        assert!(params.mem_file_path.as_os_str().is_empty());
+        match check(&parse_args(&["--check", "--snapshot", state_path])) {
+            Err(Error::CheckFailed(1)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match check(&parse_args(&[
+            "--check",
+            "--snapshot",
+            state_path,
+            "--verification-key",
+            "/invalid/key",
+        ])) {
+            Err(Error::VerificationKey(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_layer_file() {
+        assert_eq!(layer_file(Path::new("")).unwrap(), None);
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&[0u8; 42]).unwrap();
+        let (path, size) = layer_file(file.as_path()).unwrap().unwrap();
+        assert_eq!(path, file.as_path().display().to_string());
+        assert_eq!(size, 42);
+    }
+}
@@ -0,0 +1,58 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Prints the contents of a snapshot file set: the microVM state file, the memory file and the
+//! overlay and working set layers described by a snapshot load manifest, which is the body of a
+//! `PUT /snapshot/load` request.
+
+mod inspect;
+mod report;
+
+use std::process;
+
+const SNAPSHOT_INSPECT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[cfg(target_arch = "x86_64")]
+fn main() {
+    let mut arg_parser = inspect::build_arg_parser();
+
+    if let Err(err) = arg_parser.parse_from_cmdline() {
+        eprintln!(
+            "{} \n\n\
+             For more information try --help.",
+            inspect::Error::ArgumentParsing(err)
+        );
+        process::exit(1);
+    }
+    if arg_parser
+        .arguments()
+        .value_as_bool("help")
+        .unwrap_or(false)
+    {
+        println!("snapshot-inspect v{}\n", SNAPSHOT_INSPECT_VERSION);
+        println!("{}", arg_parser.formatted_help());
+        process::exit(0);
+    }
+    if arg_parser
+        .arguments()
+        .value_as_bool("version")
+        .unwrap_or(false)
+    {
+        println!("snapshot-inspect v{}\n", SNAPSHOT_INSPECT_VERSION);
+        process::exit(0);
+    }
+
+    if let Err(err) = inspect::run(arg_parser.arguments()) {
+        eprintln!("snapshot-inspect error: {}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn main() {
+    eprintln!(
+        "snapshot-inspect v{}: snapshots are only supported on x86_64",
+        SNAPSHOT_INSPECT_VERSION
+    );
+    process::exit(1);
+}
@@ -0,0 +1,452 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// Currently only supports x86_64.
+#![cfg(target_arch = "x86_64")]
+
+use std::fmt::{self, Display, Formatter};
+
+use serde::Serialize;
+use vmm::memory_layers::GuestMemoryState;
+use vmm::persist::MicrovmState;
+use vmm::version_map::FC_VERSION_TO_SNAP_VERSION;
+
+/// Description of a snapshot file set.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Report {
+    pub snapshot: StateReport,
+    pub mem_file: Option<FileReport>,
+    pub overlay: Option<LayerReport>,
+    pub ws: Option<LayerReport>,
+}
+
+/// Description of the microVM state file.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StateReport {
+    pub path: String,
+    pub size: u64,
+    pub data_version: u16,
+    pub firecracker_version: Option<String>,
+    pub mem_size_mib: u64,
+    pub page_size: u64,
+    pub memory_regions: Vec<RegionReport>,
+    pub vcpus: usize,
+    pub block_devices: Vec<DeviceReport>,
+    pub net_devices: Vec<DeviceReport>,
+    pub vsock_device: Option<DeviceReport>,
+}
+
+/// Guest memory region and its place in the memory file.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RegionReport {
+    pub guest_addr: u64,
+    pub size: u64,
+    pub file_offset: u64,
+}
+
+/// Device connected to the MMIO space.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DeviceReport {
+    pub id: String,
+    pub mmio_addr: u64,
+    pub mmio_len: u64,
+    pub irqs: Vec<u32>,
+}
+
+/// Size of a snapshot file, along with the size the microVM state expects.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct FileReport {
+    pub path: String,
+    pub size: u64,
+    pub expected_size: u64,
+}
+
+/// Overlay or working set layer, made of extents of the memory file layout.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LayerReport {
+    pub file: Option<FileReport>,
+    pub pages: u64,
+    pub extents: Vec<ExtentReport>,
+}
+
+/// Extent of a layer, with the guest memory it maps.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ExtentReport {
+    pub mem_offset: u64,
+    pub len: u64,
+    pub file_offset: u64,
+    pub guest_ranges: Vec<GuestRange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Range of guest physical memory.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct GuestRange {
+    pub guest_addr: u64,
+    pub len: u64,
+}
+
+/// Layout of a layer file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LayerLayout {
+    /// The extents are at their memory file offset, as in the overlay file.
+    MemoryFile,
+    /// The extents are stored back to back, as in the working set file.
+    BackToBack,
+}
+
+impl StateReport {
+    /// Describes `state`, read from the `path` file of `size` bytes with the `data_version`.
+    pub fn new(path: String, size: u64, data_version: u16, state: &MicrovmState) -> Self {
+        let firecracker_version = FC_VERSION_TO_SNAP_VERSION
+            .iter()
+            .find(|(_, version)| **version == data_version)
+            .map(|(fc_version, _)| fc_version.clone());
+        // The connected device states have no common type.
+        macro_rules! device {
+            ($state:expr) => {
+                DeviceReport {
+                    id: $state.device_id.clone(),
+                    mmio_addr: $state.mmio_slot.addr,
+                    mmio_len: $state.mmio_slot.len,
+                    irqs: $state.mmio_slot.irqs.clone(),
+                }
+            };
+        }
+        let devices = &state.device_states;
+
+        StateReport {
+            path,
+            size,
+            data_version,
+            firecracker_version,
+            mem_size_mib: state.vm_info.mem_size_mib,
+            page_size: state.memory_state.page_size as u64,
+            memory_regions: state
+                .memory_state
+                .regions
+                .iter()
+                .map(|region| RegionReport {
+                    guest_addr: region.base_address,
+                    size: region.size as u64,
+                    file_offset: region.offset,
+                })
+                .collect(),
+            vcpus: state.vcpu_states.len(),
+            block_devices: devices
+                .block_devices
+                .iter()
+                .map(|block| device!(block))
+                .collect(),
+            net_devices: devices.net_devices.iter().map(|net| device!(net)).collect(),
+            vsock_device: devices.vsock_device.as_ref().map(|vsock| device!(vsock)),
+        }
+    }
+}
+
+impl LayerReport {
+    /// Describes the layer made of `extents`, as (memory file page offset, number of pages),
+    /// stored with `layout` in `file`, as (path, size), if any.
+    pub fn new(
+        state: &GuestMemoryState,
+        extents: &[(u64, u64)],
+        layout: LayerLayout,
+        file: Option<(String, u64)>,
+        page_size: u64,
+    ) -> Self {
+        let mut back_to_back_offset = 0;
+        let extents: Vec<ExtentReport> = extents
+            .iter()
+            .map(|(page, pages)| {
+                let mem_offset = page * page_size;
+                let len = pages * page_size;
+                let file_offset = match layout {
+                    LayerLayout::MemoryFile => mem_offset,
+                    LayerLayout::BackToBack => back_to_back_offset,
+                };
+                back_to_back_offset += len;
+                let (guest_ranges, error) = match state.translate_extent(mem_offset, len) {
+                    Ok(chunks) => (
+                        chunks
+                            .iter()
+                            .map(|chunk| GuestRange {
+                                guest_addr: state.regions[chunk.region_index].base_address
+                                    + chunk.region_offset,
+                                len: chunk.len,
+                            })
+                            .collect(),
+                        None,
+                    ),
+                    Err(e) => (Vec::new(), Some(e.to_string())),
+                };
+                ExtentReport {
+                    mem_offset,
+                    len,
+                    file_offset,
+                    guest_ranges,
+                    error,
+                }
+            })
+            .collect();
+        let expected_size = match layout {
+            LayerLayout::MemoryFile => state.file_size(),
+            LayerLayout::BackToBack => back_to_back_offset,
+        };
+
+        LayerReport {
+            file: file.map(|(path, size)| FileReport {
+                path,
+                size,
+                expected_size,
+            }),
+            pages: extents.iter().map(|extent| extent.len / page_size).sum(),
+            extents,
+        }
+    }
+}
+
+impl Display for FileReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}, {} bytes, {} bytes expected",
+            self.path, self.size, self.expected_size
+        )?;
+        if self.size != self.expected_size {
+            write!(f, " (size mismatch)")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for DeviceReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: mmio {:#x}+{:#x}, irqs {:?}",
+            self.id, self.mmio_addr, self.mmio_len, self.irqs
+        )
+    }
+}
+
+impl Display for ExtentReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "mem offset {:#x}+{:#x}, file offset {:#x}",
+            self.mem_offset, self.len, self.file_offset
+        )?;
+        if let Some(error) = self.error.as_ref() {
+            return write!(f, ": {}", error);
+        }
+        let ranges: Vec<String> = self
+            .guest_ranges
+            .iter()
+            .map(|range| format!("{:#x}+{:#x}", range.guest_addr, range.len))
+            .collect();
+        write!(f, " -> guest {}", ranges.join(", "))
+    }
+}
+
+fn fmt_devices(f: &mut Formatter, name: &str, devices: &[DeviceReport]) -> fmt::Result {
+    if devices.is_empty() {
+        return writeln!(f, "  {}: none", name);
+    }
+    writeln!(f, "  {}:", name)?;
+    for device in devices {
+        writeln!(f, "    {}", device)?;
+    }
+    Ok(())
+}
+
+fn fmt_layer(f: &mut Formatter, name: &str, layer: &LayerReport) -> fmt::Result {
+    match layer.file.as_ref() {
+        Some(file) => writeln!(f, "{} file: {}", name, file)?,
+        None => writeln!(f, "{} file: none", name)?,
+    }
+    writeln!(
+        f,
+        "  extents: {}, {} pages",
+        layer.extents.len(),
+        layer.pages
+    )?;
+    for extent in layer.extents.iter() {
+        writeln!(f, "    {}", extent)?;
+    }
+    Ok(())
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let state = &self.snapshot;
+        writeln!(f, "Snapshot file: {}, {} bytes", state.path, state.size)?;
+        write!(f, "  data version: {}", state.data_version)?;
+        match state.firecracker_version.as_ref() {
+            Some(version) => writeln!(f, " (Firecracker {})", version)?,
+            None => writeln!(f, " (unknown Firecracker version)")?,
+        }
+        writeln!(
+            f,
+            "  guest memory: {} MiB, {} byte pages",
+            state.mem_size_mib, state.page_size
+        )?;
+        writeln!(f, "  memory regions:")?;
+        for region in state.memory_regions.iter() {
+            writeln!(
+                f,
+                "    guest {:#x}+{:#x}, file offset {:#x}",
+                region.guest_addr, region.size, region.file_offset
+            )?;
+        }
+        writeln!(f, "  vcpus: {}", state.vcpus)?;
+        fmt_devices(f, "block devices", &state.block_devices)?;
+        fmt_devices(f, "net devices", &state.net_devices)?;
+        match state.vsock_device.as_ref() {
+            Some(vsock) => writeln!(f, "  vsock device: {}", vsock)?,
+            None => writeln!(f, "  vsock device: none")?,
+        }
+
+        if let Some(mem_file) = self.mem_file.as_ref() {
+            writeln!(f, "Memory file: {}", mem_file)?;
+        }
+        if let Some(overlay) = self.overlay.as_ref() {
+            fmt_layer(f, "Overlay", overlay)?;
+        }
+        if let Some(ws) = self.ws.as_ref() {
+            fmt_layer(f, "WS", ws)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vmm::memory_layers::GuestMemoryRegionState;
+
+    const PAGE_SIZE: u64 = 0x1000;
+
+    // Two regions, split by a gap in the guest physical memory.
+    fn memory_state() -> GuestMemoryState {
+        GuestMemoryState {
+            regions: vec![
+                GuestMemoryRegionState {
+                    base_address: 0,
+                    size: 0x4000,
+                    offset: 0,
+                },
+                GuestMemoryRegionState {
+                    base_address: 0x10_0000,
+                    size: 0x4000,
+                    offset: 0x4000,
+                },
+            ],
+            page_size: PAGE_SIZE as usize,
+        }
+    }
+
+    #[test]
+    fn test_layer_report() {
+        let state = memory_state();
+        let extents = [(1, 1), (3, 2), (8, 1)];
+
+        let ws = LayerReport::new(
+            &state,
+            &extents,
+            LayerLayout::BackToBack,
+            Some(("ws".to_string(), 0x4000)),
+            PAGE_SIZE,
+        );
+        assert_eq!(ws.pages, 4);
+        assert_eq!(
+            ws.file,
+            Some(FileReport {
+                path: "ws".to_string(),
+                size: 0x4000,
+                expected_size: 0x4000,
+            })
+        );
+        assert_eq!(ws.extents[0].file_offset, 0);
+        assert_eq!(ws.extents[1].file_offset, 0x1000);
+        // The second extent spans both regions.
+        assert_eq!(
+            ws.extents[1].guest_ranges,
+            vec![
+                GuestRange {
+                    guest_addr: 0x3000,
+                    len: 0x1000,
+                },
+                GuestRange {
+                    guest_addr: 0x10_0000,
+                    len: 0x1000,
+                },
+            ]
+        );
+        // The third extent is past the end of the memory file.
+        assert!(ws.extents[2].guest_ranges.is_empty());
+        assert!(ws.extents[2].error.is_some());
+
+        let overlay = LayerReport::new(&state, &extents, LayerLayout::MemoryFile, None, PAGE_SIZE);
+        assert_eq!(overlay.file, None);
+        assert_eq!(overlay.extents[1].file_offset, 0x3000);
+    }
+
+    #[test]
+    fn test_display() {
+        let state = memory_state();
+        let report = Report {
+            snapshot: StateReport {
+                path: "vmstate".to_string(),
+                size: 1234,
+                data_version: 1,
+                firecracker_version: Some("0.23.0".to_string()),
+                mem_size_mib: 128,
+                page_size: PAGE_SIZE,
+                memory_regions: vec![RegionReport {
+                    guest_addr: 0,
+                    size: 0x4000,
+                    file_offset: 0,
+                }],
+                vcpus: 2,
+                block_devices: vec![DeviceReport {
+                    id: "rootfs".to_string(),
+                    mmio_addr: 0xd000_0000,
+                    mmio_len: 0x1000,
+                    irqs: vec![5],
+                }],
+                net_devices: Vec::new(),
+                vsock_device: None,
+            },
+            mem_file: Some(FileReport {
+                path: "mem".to_string(),
+                size: 0x4000,
+                expected_size: 0x8000,
+            }),
+            overlay: None,
+            ws: Some(LayerReport::new(
+                &state,
+                &[(1, 1)],
+                LayerLayout::BackToBack,
+                None,
+                PAGE_SIZE,
+            )),
+        };
+
+        let text = report.to_string();
+        assert!(text.contains("  data version: 1 (Firecracker 0.23.0)\n"));
+        assert!(text.contains("  guest memory: 128 MiB, 4096 byte pages\n"));
+        assert!(text.contains("    guest 0x0+0x4000, file offset 0x0\n"));
+        assert!(text.contains("    rootfs: mmio 0xd0000000+0x1000, irqs [5]\n"));
+        assert!(text.contains("  net devices: none\n"));
+        assert!(
+            text.contains("Memory file: mem, 16384 bytes, 32768 bytes expected (size mismatch)\n")
+        );
+        assert!(text.contains("WS file: none\n  extents: 1, 1 pages\n"));
+        assert!(
+            text.contains("    mem offset 0x1000+0x1000, file offset 0x0 -> guest 0x1000+0x1000\n")
+        );
+        assert!(!text.contains("Overlay"));
+    }
+}
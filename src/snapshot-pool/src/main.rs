@@ -0,0 +1,125 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Keeps template snapshots in memory and hands their files to Firecracker processes: the
+//! memory, overlay and working set files of each template are copied to sealed memfds, and each
+//! lease on a template gets read-only file descriptors of them, which Firecracker loads as
+//! inherited snapshot files.
+
+mod memfd;
+mod pool;
+mod server;
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use utils::arg_parser::{ArgParser, Argument, Arguments, Error as ParsingError};
+use vmm::vmm_config::snapshot::LoadSnapshotParams;
+
+use crate::pool::{Pool, Template};
+
+const SNAPSHOT_POOL_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug)]
+enum Error {
+    ArgumentParsing(ParsingError),
+    Bind(PathBuf, io::Error),
+    Manifest(PathBuf, String),
+    Pool(pool::Error),
+    Serve(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            ArgumentParsing(err) => write!(f, "Failed to parse arguments: {}", err),
+            Bind(path, err) => write!(f, "Failed to bind to {}: {}", path.display(), err),
+            Manifest(path, err) => write!(f, "Invalid manifest {}: {}", path.display(), err),
+            Pool(err) => write!(f, "{}", err),
+            Serve(err) => write!(f, "Failed to accept a connection: {}", err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new().arg(
+        Argument::new("socket")
+            .required(true)
+            .takes_value(true)
+            .help("Path of the unix socket the pool is served on."),
+    )
+}
+
+fn read_manifest(path: &Path) -> Result<LoadSnapshotParams> {
+    let file = File::open(path).map_err(|e| Error::Manifest(path.to_path_buf(), e.to_string()))?;
+    serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| Error::Manifest(path.to_path_buf(), e.to_string()))
+}
+
+fn run(arguments: &Arguments) -> Result<()> {
+    // The required arguments are checked by the parser.
+    let socket_path = PathBuf::from(arguments.value_as_string("socket").unwrap_or_default());
+
+    // The manifests listed after -- are loaded upfront, named after their file stem.
+    let mut pool = Pool::default();
+    for manifest_path in arguments.extra_args().iter().map(PathBuf::from) {
+        let name = manifest_path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let manifest = read_manifest(&manifest_path)?;
+        let template = Template::new(&name, manifest).map_err(Error::Pool)?;
+        let info = pool.insert(&name, template).map_err(Error::Pool)?;
+        println!(
+            "Loaded template {}, {} bytes resident",
+            info.name, info.resident_bytes
+        );
+    }
+
+    let listener = UnixListener::bind(&socket_path).map_err(|e| Error::Bind(socket_path, e))?;
+    server::serve(listener, Arc::new(Mutex::new(pool))).map_err(Error::Serve)
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+
+    if let Err(err) = arg_parser.parse_from_cmdline() {
+        eprintln!(
+            "{} \n\n\
+             For more information try --help.",
+            Error::ArgumentParsing(err)
+        );
+        process::exit(1);
+    }
+    if arg_parser
+        .arguments()
+        .value_as_bool("help")
+        .unwrap_or(false)
+    {
+        println!("snapshot-pool v{}\n", SNAPSHOT_POOL_VERSION);
+        println!("{}", arg_parser.formatted_help());
+        process::exit(0);
+    }
+    if arg_parser
+        .arguments()
+        .value_as_bool("version")
+        .unwrap_or(false)
+    {
+        println!("snapshot-pool v{}\n", SNAPSHOT_POOL_VERSION);
+        process::exit(0);
+    }
+
+    if let Err(err) = run(arg_parser.arguments()) {
+        eprintln!("snapshot-pool error: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+
+// Length of the copies from the snapshot files to the memfds.
+const COPY_CHUNK: u64 = 1 << 20;
+
+// Returns the offset of the first data byte of `file` at or after `offset`, if any.
+fn next_data(file: &File, offset: u64) -> io::Result<Option<u64>> {
+    // Safe because lseek does not access memory.
+    let ret = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, libc::SEEK_DATA) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        // ENXIO means there is no data past `offset`.
+        return match err.raw_os_error() {
+            Some(libc::ENXIO) => Ok(None),
+            _ => Err(err),
+        };
+    }
+    Ok(Some(ret as u64))
+}
+
+// Returns the offset of the first hole of `file` at or after `offset`, the end of the file
+// counting as one.
+fn next_hole(file: &File, offset: u64) -> io::Result<u64> {
+    // Safe because lseek does not access memory.
+    let ret = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, libc::SEEK_HOLE) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as u64)
+}
+
+/// Copies the file at `path` to a new memfd named `name`, leaving its holes unallocated, and
+/// seals it so that its content can no longer change.
+pub fn sealed_copy(name: &str, path: &Path) -> io::Result<File> {
+    let source = File::open(path)?;
+    let size = source.metadata()?.len();
+
+    let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // Safe because `name` is a valid C string, and the returned fd is checked.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_memfd_create,
+            name.as_ptr(),
+            libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because the fd was just created and nothing else owns it.
+    let memfd = unsafe { File::from_raw_fd(fd as i32) };
+    memfd.set_len(size)?;
+
+    let mut buf = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = next_data(&source, offset)? {
+        let end = next_hole(&source, start)?;
+        let mut pos = start;
+        while pos < end {
+            buf.resize(std::cmp::min(COPY_CHUNK, end - pos) as usize, 0);
+            source.read_exact_at(&mut buf, pos)?;
+            memfd.write_all_at(&buf, pos)?;
+            pos += buf.len() as u64;
+        }
+        offset = end;
+        if offset >= size {
+            break;
+        }
+    }
+
+    let seals = libc::F_SEAL_SEAL | libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE;
+    // Safe because fcntl does not access memory.
+    if unsafe { libc::fcntl(memfd.as_raw_fd(), libc::F_ADD_SEALS, seals) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(memfd)
+}
+
+/// Opens a new read-only file description of `memfd`, as Firecracker only accepts inherited
+/// snapshot files opened read-only.
+pub fn reopen_read_only(memfd: &File) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .open(format!("/proc/self/fd/{}", memfd.as_raw_fd()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::MetadataExt;
+
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_sealed_copy() {
+        let source = TempFile::new().unwrap();
+        source.as_file().set_len(8 * COPY_CHUNK).unwrap();
+        source
+            .as_file()
+            .write_all_at(&[1u8; 16], COPY_CHUNK)
+            .unwrap();
+        source
+            .as_file()
+            .write_all_at(&[2u8; 16], 8 * COPY_CHUNK - 16)
+            .unwrap();
+
+        let memfd = sealed_copy("test", source.as_path()).unwrap();
+        assert_eq!(memfd.metadata().unwrap().len(), 8 * COPY_CHUNK);
+        // The holes of the source are not allocated.
+        assert!(memfd.metadata().unwrap().blocks() * 512 < 8 * COPY_CHUNK);
+        let mut buf = [0u8; 16];
+        memfd.read_exact_at(&mut buf, COPY_CHUNK).unwrap();
+        assert_eq!(buf, [1u8; 16]);
+        memfd.read_exact_at(&mut buf, 8 * COPY_CHUNK - 16).unwrap();
+        assert_eq!(buf, [2u8; 16]);
+        memfd.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, [0u8; 16]);
+
+        // The content is sealed.
+        assert!(memfd.write_all_at(&[3u8; 16], 0).is_err());
+        assert!(memfd.set_len(COPY_CHUNK).is_err());
+
+        let read_only = reopen_read_only(&memfd).unwrap();
+        // Safe because fcntl does not access memory.
+        let flags = unsafe { libc::fcntl(read_only.as_raw_fd(), libc::F_GETFL) };
+        assert_eq!(flags & libc::O_ACCMODE, libc::O_RDONLY);
+        read_only.read_exact_at(&mut buf, COPY_CHUNK).unwrap();
+        assert_eq!(buf, [1u8; 16]);
+
+        assert!(sealed_copy("test", Path::new("/invalid/file")).is_err());
+    }
+}
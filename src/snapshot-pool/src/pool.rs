@@ -0,0 +1,331 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+use serde::Serialize;
+use vmm::vmm_config::snapshot::LoadSnapshotParams;
+
+use crate::memfd::{reopen_read_only, sealed_copy};
+
+#[derive(Debug)]
+pub enum Error {
+    Copy(PathBuf, io::Error),
+    Evicting(String),
+    Exists(String),
+    InheritedFd(String),
+    MissingMemoryFile(String),
+    Reopen(io::Error),
+    UnknownLease(u64),
+    UnknownTemplate(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Copy(path, err) => write!(f, "Failed to copy {} to memory: {}", path.display(), err),
+            Evicting(name) => write!(f, "Template {} is being evicted", name),
+            Exists(name) => write!(f, "Template {} is already loaded", name),
+            InheritedFd(name) => write!(
+                f,
+                "The manifest of template {} uses inherited file descriptors instead of paths",
+                name
+            ),
+            MissingMemoryFile(name) => {
+                write!(f, "The manifest of template {} has no memory file", name)
+            }
+            Reopen(err) => write!(f, "Failed to open a template file read-only: {}", err),
+            UnknownLease(id) => write!(f, "Unknown lease {}", id),
+            UnknownTemplate(name) => write!(f, "Unknown template {}", name),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Snapshot files kept in memory, named after the manifest fields they replace.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum Layer {
+    #[serde(rename = "mem_file")]
+    Memory,
+    #[serde(rename = "overlay_file")]
+    Overlay,
+    #[serde(rename = "ws_file")]
+    WorkingSet,
+}
+
+/// Snapshot whose memory layers are held in sealed memfds.
+pub struct Template {
+    manifest: LoadSnapshotParams,
+    layers: Vec<(Layer, File)>,
+    leases: u64,
+    evicting: bool,
+}
+
+impl Template {
+    /// Copies the memory layers of the snapshot described by `manifest` to memory.
+    pub fn new(name: &str, manifest: LoadSnapshotParams) -> Result<Self> {
+        if manifest.mem_file_fd.is_some()
+            || manifest.overlay_file_fd.is_some()
+            || manifest.ws_file_fd.is_some()
+        {
+            return Err(Error::InheritedFd(name.to_string()));
+        }
+        if manifest.mem_file_path.as_os_str().is_empty() {
+            return Err(Error::MissingMemoryFile(name.to_string()));
+        }
+
+        let mut layers = Vec::new();
+        for (layer, path) in &[
+            (Layer::Memory, &manifest.mem_file_path),
+            (Layer::Overlay, &manifest.overlay_file_path),
+            (Layer::WorkingSet, &manifest.ws_file_path),
+        ] {
+            // An empty path means the layer is not used.
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+            let memfd = sealed_copy(name, path).map_err(|e| Error::Copy(path.to_path_buf(), e))?;
+            layers.push((*layer, memfd));
+        }
+        Ok(Template {
+            manifest,
+            layers,
+            leases: 0,
+            evicting: false,
+        })
+    }
+
+    fn info(&self, name: &str) -> TemplateInfo {
+        TemplateInfo {
+            name: name.to_string(),
+            // The memfds hold no blocks for the holes of the snapshot files.
+            resident_bytes: self
+                .layers
+                .iter()
+                .map(|(_, memfd)| memfd.metadata().map(|m| m.blocks() * 512).unwrap_or(0))
+                .sum(),
+            leases: self.leases,
+            evicting: self.evicting,
+        }
+    }
+}
+
+/// State of a template.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TemplateInfo {
+    pub name: String,
+    pub resident_bytes: u64,
+    pub leases: u64,
+    pub evicting: bool,
+}
+
+/// Use of a template by a Firecracker process.
+pub struct Lease {
+    pub id: u64,
+    /// Manifest of the template, whose `*_fd` fields the holder sets to the `files` it passes
+    /// to Firecracker.
+    pub manifest: LoadSnapshotParams,
+    /// Layers of the `files`, in the same order.
+    pub layers: Vec<Layer>,
+    /// Read-only file descriptions of the memfds of the template.
+    pub files: Vec<File>,
+}
+
+/// Templates, along with the leases handed out on them.
+#[derive(Default)]
+pub struct Pool {
+    templates: HashMap<String, Template>,
+    leases: HashMap<u64, String>,
+    next_lease: u64,
+}
+
+impl Pool {
+    /// Returns whether a template is named `name`.
+    pub fn contains(&self, name: &str) -> bool {
+        self.templates.contains_key(name)
+    }
+
+    /// Adds `template` under `name`.
+    pub fn insert(&mut self, name: &str, template: Template) -> Result<TemplateInfo> {
+        if self.contains(name) {
+            return Err(Error::Exists(name.to_string()));
+        }
+        let info = template.info(name);
+        self.templates.insert(name.to_string(), template);
+        Ok(info)
+    }
+
+    /// Hands out the files of the template `name`.
+    pub fn acquire(&mut self, name: &str) -> Result<Lease> {
+        let template = self
+            .templates
+            .get_mut(name)
+            .ok_or_else(|| Error::UnknownTemplate(name.to_string()))?;
+        if template.evicting {
+            return Err(Error::Evicting(name.to_string()));
+        }
+        let files = template
+            .layers
+            .iter()
+            .map(|(_, memfd)| reopen_read_only(memfd))
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(Error::Reopen)?;
+
+        template.leases += 1;
+        self.next_lease += 1;
+        self.leases.insert(self.next_lease, name.to_string());
+        Ok(Lease {
+            id: self.next_lease,
+            manifest: template.manifest.clone(),
+            layers: template.layers.iter().map(|(layer, _)| *layer).collect(),
+            files,
+        })
+    }
+
+    /// Ends the lease `id`, dropping its template if it is being evicted and no longer used.
+    pub fn release(&mut self, id: u64) -> Result<()> {
+        let name = self.leases.remove(&id).ok_or(Error::UnknownLease(id))?;
+        let unused = match self.templates.get_mut(&name) {
+            Some(template) => {
+                template.leases -= 1;
+                template.evicting && template.leases == 0
+            }
+            None => false,
+        };
+        if unused {
+            self.templates.remove(&name);
+        }
+        Ok(())
+    }
+
+    /// Drops the template `name` once its leases are released, and returns whether it already
+    /// was. No more leases are handed out on it meanwhile.
+    pub fn evict(&mut self, name: &str) -> Result<bool> {
+        let template = self
+            .templates
+            .get_mut(name)
+            .ok_or_else(|| Error::UnknownTemplate(name.to_string()))?;
+        if template.leases > 0 {
+            template.evicting = true;
+            return Ok(false);
+        }
+        self.templates.remove(name);
+        Ok(true)
+    }
+
+    /// Returns the state of the templates, ordered by name.
+    pub fn list(&self) -> Vec<TemplateInfo> {
+        let mut infos: Vec<TemplateInfo> = self
+            .templates
+            .iter()
+            .map(|(name, template)| template.info(name))
+            .collect();
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        infos
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::os::unix::fs::FileExt;
+
+    use utils::tempfile::TempFile;
+
+    pub(crate) fn template_files() -> (TempFile, TempFile, LoadSnapshotParams) {
+        let mem_file = TempFile::new().unwrap();
+        mem_file.as_file().write_all_at(&[1u8; 0x2000], 0).unwrap();
+        let ws_file = TempFile::new().unwrap();
+        ws_file.as_file().write_all_at(&[2u8; 0x1000], 0).unwrap();
+        let manifest = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("snapshot_file"),
+            mem_file_path: mem_file.as_path().to_path_buf(),
+            ws_file_path: ws_file.as_path().to_path_buf(),
+            ws_regions: vec![vec![1, 1]],
+            load_ws: true,
+            ..Default::default()
+        };
+        (mem_file, ws_file, manifest)
+    }
+
+    #[test]
+    fn test_template() {
+        let (_mem_file, _ws_file, manifest) = template_files();
+        let template = Template::new("fn", manifest.clone()).unwrap();
+        let layers: Vec<Layer> = template.layers.iter().map(|(layer, _)| *layer).collect();
+        assert_eq!(layers, vec![Layer::Memory, Layer::WorkingSet]);
+        assert_eq!(template.info("fn").resident_bytes, 0x3000);
+
+        let mut inherited = manifest.clone();
+        inherited.mem_file_fd = Some(3);
+        match Template::new("fn", inherited) {
+            Err(Error::InheritedFd(name)) => assert_eq!(name, "fn"),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+        let mut missing = manifest;
+        missing.mem_file_path = PathBuf::from("/invalid/mem_file");
+        match Template::new("fn", missing) {
+            Err(Error::Copy(path, _)) => assert_eq!(path, PathBuf::from("/invalid/mem_file")),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_pool() {
+        let (_mem_file, _ws_file, manifest) = template_files();
+        let mut pool = Pool::default();
+        pool.insert("fn", Template::new("fn", manifest.clone()).unwrap())
+            .unwrap();
+        match pool.insert("fn", Template::new("fn", manifest.clone()).unwrap()) {
+            Err(Error::Exists(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let first = pool.acquire("fn").unwrap();
+        let second = pool.acquire("fn").unwrap();
+        assert_ne!(first.id, second.id);
+        assert_eq!(first.manifest, manifest);
+        assert_eq!(first.layers, vec![Layer::Memory, Layer::WorkingSet]);
+        let mut buf = [0u8; 4];
+        first.files[1].read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, [2u8; 4]);
+        assert_eq!(pool.list()[0].leases, 2);
+        match pool.acquire("other") {
+            Err(Error::UnknownTemplate(_)) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|lease| lease.id)),
+        }
+
+        // The template stays until its last lease is released.
+        assert!(!pool.evict("fn").unwrap());
+        assert!(pool.list()[0].evicting);
+        match pool.acquire("fn") {
+            Err(Error::Evicting(_)) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|lease| lease.id)),
+        }
+        pool.release(first.id).unwrap();
+        match pool.release(first.id) {
+            Err(Error::UnknownLease(id)) => assert_eq!(id, first.id),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert!(pool.contains("fn"));
+        pool.release(second.id).unwrap();
+        assert!(!pool.contains("fn"));
+        // The files handed out outlive the template.
+        second.files[0].read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, [1u8; 4]);
+
+        pool.insert("fn", Template::new("fn", manifest).unwrap())
+            .unwrap();
+        assert!(pool.evict("fn").unwrap());
+        assert!(pool.list().is_empty());
+    }
+}
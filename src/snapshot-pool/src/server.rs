@@ -0,0 +1,212 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Line based JSON protocol of the pool. Each request is a JSON object on its own line, answered
+//! by a JSON object on its own line. The response to `acquire` is followed by one message per
+//! file descriptor, sent with `SCM_RIGHTS` in the order of its `layers`.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use passfd::FdPassingExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use vmm::vmm_config::snapshot::LoadSnapshotParams;
+
+use crate::pool::{self, Pool, Template};
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Request {
+    Load {
+        name: String,
+        manifest: LoadSnapshotParams,
+    },
+    Acquire {
+        name: String,
+    },
+    Release {
+        lease: u64,
+    },
+    Evict {
+        name: String,
+    },
+    List,
+}
+
+fn error_response(err: &dyn std::fmt::Display) -> Value {
+    json!({ "ok": false, "error": err.to_string() })
+}
+
+/// Handles `request`, and returns the response along with the files to send after it.
+pub fn handle_request(pool: &Mutex<Pool>, request: Request) -> (Value, Vec<File>) {
+    let outcome = match request {
+        Request::Load { name, manifest } => {
+            // The copy to memory runs without holding the pool.
+            if pool.lock().expect("Poisoned lock").contains(&name) {
+                Err(pool::Error::Exists(name))
+            } else {
+                Template::new(&name, manifest).and_then(|template| {
+                    let info = pool
+                        .lock()
+                        .expect("Poisoned lock")
+                        .insert(&name, template)?;
+                    Ok((json!({ "ok": true, "template": info }), Vec::new()))
+                })
+            }
+        }
+        Request::Acquire { name } => {
+            pool.lock()
+                .expect("Poisoned lock")
+                .acquire(&name)
+                .map(|lease| {
+                    let response = json!({
+                        "ok": true,
+                        "lease": lease.id,
+                        "manifest": lease.manifest,
+                        "layers": lease.layers,
+                    });
+                    (response, lease.files)
+                })
+        }
+        Request::Release { lease } => pool
+            .lock()
+            .expect("Poisoned lock")
+            .release(lease)
+            .map(|_| (json!({ "ok": true }), Vec::new())),
+        Request::Evict { name } => pool
+            .lock()
+            .expect("Poisoned lock")
+            .evict(&name)
+            .map(|evicted| (json!({ "ok": true, "evicted": evicted }), Vec::new())),
+        Request::List => {
+            let templates = pool.lock().expect("Poisoned lock").list();
+            Ok((json!({ "ok": true, "templates": templates }), Vec::new()))
+        }
+    };
+    outcome.unwrap_or_else(|err| (error_response(&err), Vec::new()))
+}
+
+// Serves the requests of `stream` until it is closed.
+fn serve_connection(pool: &Mutex<Pool>, stream: UnixStream) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (response, files) = match serde_json::from_str(&line) {
+            Ok(request) => handle_request(pool, request),
+            Err(err) => (error_response(&err), Vec::new()),
+        };
+        writer.write_all(format!("{}\n", response).as_bytes())?;
+        for file in &files {
+            writer.send_fd(file.as_raw_fd())?;
+        }
+    }
+    Ok(())
+}
+
+/// Serves the connections of `listener`, each on its own thread.
+pub fn serve(listener: UnixListener, pool: Arc<Mutex<Pool>>) -> std::io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let pool = pool.clone();
+        thread::spawn(move || {
+            if let Err(err) = serve_connection(&pool, stream) {
+                eprintln!("Connection closed: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::FromRawFd;
+
+    use crate::pool::tests::template_files;
+
+    // Reads a response line without reading past it, as the file descriptors follow it.
+    fn read_response(stream: &mut UnixStream) -> Value {
+        let mut line = Vec::new();
+        let mut byte = [0u8];
+        while stream.read(&mut byte).unwrap() == 1 && byte[0] != b'\n' {
+            line.push(byte[0]);
+        }
+        serde_json::from_slice(&line).unwrap()
+    }
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(
+            serde_json::from_str::<Request>(r#"{"action": "acquire", "name": "fn"}"#).unwrap(),
+            Request::Acquire {
+                name: "fn".to_string()
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<Request>(r#"{"action": "list"}"#).unwrap(),
+            Request::List
+        );
+        assert!(serde_json::from_str::<Request>(r#"{"action": "release"}"#).is_err());
+        assert!(serde_json::from_str::<Request>(r#"{"action": "unload", "name": "fn"}"#).is_err());
+    }
+
+    #[test]
+    fn test_serve_connection() {
+        let (_mem_file, _ws_file, manifest) = template_files();
+        let pool = Arc::new(Mutex::new(Pool::default()));
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let server_pool = pool.clone();
+        let server = thread::spawn(move || serve_connection(&server_pool, server));
+
+        let load = json!({ "action": "load", "name": "fn", "manifest": manifest });
+        client.write_all(format!("{}\n", load).as_bytes()).unwrap();
+        let response = read_response(&mut client);
+        assert_eq!(response["ok"], true);
+        assert_eq!(response["template"]["name"], "fn");
+        client.write_all(format!("{}\n", load).as_bytes()).unwrap();
+        assert_eq!(read_response(&mut client)["ok"], false);
+
+        client
+            .write_all(b"{\"action\": \"acquire\", \"name\": \"fn\"}\n")
+            .unwrap();
+        let response = read_response(&mut client);
+        assert_eq!(response["layers"], json!(["mem_file", "ws_file"]));
+        // Safe because the received fds are owned by nothing else.
+        let (_mem_fd, ws_fd) = unsafe {
+            (
+                File::from_raw_fd(client.recv_fd().unwrap()),
+                File::from_raw_fd(client.recv_fd().unwrap()),
+            )
+        };
+        let mut buf = [0u8; 4];
+        ws_fd.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, [2u8; 4]);
+
+        let release = json!({ "action": "release", "lease": response["lease"] });
+        client
+            .write_all(format!("{}\n", release).as_bytes())
+            .unwrap();
+        assert_eq!(read_response(&mut client)["ok"], true);
+        client.write_all(b"not json\n").unwrap();
+        assert_eq!(read_response(&mut client)["ok"], false);
+        client
+            .write_all(b"{\"action\": \"evict\", \"name\": \"fn\"}\n")
+            .unwrap();
+        assert_eq!(read_response(&mut client)["evicted"], true);
+
+        drop(client);
+        server.join().unwrap().unwrap();
+        assert!(pool.lock().unwrap().list().is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reads the working sets of the snapshots described by snapshot load manifests, the bodies of
+//! `PUT /snapshot/load` requests, into the host page cache, so that their upcoming restores do
+//! not wait on storage.
+
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use serde_json::json;
+use utils::arg_parser::{ArgParser, Argument, Arguments, Error as ParsingError};
+use vmm::page_cache::{self, PrewarmStats};
+use vmm::vmm_config::snapshot::LoadSnapshotParams;
+
+const SNAPSHOT_PREWARM_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug)]
+enum Error {
+    ArgumentParsing(ParsingError),
+    Failed(usize),
+    Manifest(PathBuf, String),
+    MissingManifest,
+    Prewarm(PathBuf, page_cache::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            ArgumentParsing(err) => write!(f, "Failed to parse arguments: {}", err),
+            Failed(count) => write!(f, "Failed to prewarm {} snapshots", count),
+            Manifest(path, err) => write!(f, "Invalid manifest {}: {}", path.display(), err),
+            MissingManifest => write!(f, "No manifest, list them after --"),
+            Prewarm(path, err) => write!(f, "Failed to prewarm {}: {}", path.display(), err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new().arg(
+        Argument::new("json")
+            .takes_value(false)
+            .help("Print the outcome of each manifest as JSON."),
+    )
+}
+
+fn read_manifest(path: &Path) -> Result<LoadSnapshotParams> {
+    let file = File::open(path).map_err(|e| Error::Manifest(path.to_path_buf(), e.to_string()))?;
+    serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| Error::Manifest(path.to_path_buf(), e.to_string()))
+}
+
+fn prewarm(path: &Path, page_size: u64) -> Result<PrewarmStats> {
+    let manifest = read_manifest(path)?;
+    page_cache::working_set_ranges(&manifest, page_size)
+        .and_then(|files| page_cache::prewarm(&files))
+        .map_err(|e| Error::Prewarm(path.to_path_buf(), e))
+}
+
+fn run(arguments: &Arguments) -> Result<()> {
+    let manifests = arguments.extra_args();
+    if manifests.is_empty() {
+        return Err(Error::MissingManifest);
+    }
+    let json = arguments.value_as_bool("json").unwrap_or(false);
+    let page_size = sysconf::page::pagesize() as u64;
+
+    // A snapshot failing to prewarm only delays its own restore, the others are still warmed.
+    let mut failures = 0;
+    for manifest_path in manifests.iter().map(PathBuf::from) {
+        match prewarm(&manifest_path, page_size) {
+            Ok(stats) if json => {
+                println!("{}", json!({ "manifest": manifest_path, "stats": stats }))
+            }
+            Ok(stats) => println!(
+                "{}: read {} bytes from {} files, {} bytes were already cached",
+                manifest_path.display(),
+                stats.bytes,
+                stats.files,
+                stats.resident_bytes
+            ),
+            Err(err) => {
+                eprintln!("{}", err);
+                failures += 1;
+            }
+        }
+    }
+    if failures > 0 {
+        return Err(Error::Failed(failures));
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+
+    if let Err(err) = arg_parser.parse_from_cmdline() {
+        eprintln!(
+            "{} \n\n\
+             For more information try --help.",
+            Error::ArgumentParsing(err)
+        );
+        process::exit(1);
+    }
+    if arg_parser
+        .arguments()
+        .value_as_bool("help")
+        .unwrap_or(false)
+    {
+        println!("snapshot-prewarm v{}\n", SNAPSHOT_PREWARM_VERSION);
+        println!("{}", arg_parser.formatted_help());
+        process::exit(0);
+    }
+    if arg_parser
+        .arguments()
+        .value_as_bool("version")
+        .unwrap_or(false)
+    {
+        println!("snapshot-prewarm v{}\n", SNAPSHOT_PREWARM_VERSION);
+        process::exit(0);
+    }
+
+    if let Err(err) = run(arg_parser.arguments()) {
+        eprintln!("snapshot-prewarm error: {}", err);
+        process::exit(1);
+    }
+}
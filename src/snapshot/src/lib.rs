@@ -101,6 +101,16 @@ impl Snapshot {
     where
         T: Read,
         O: Versionize,
+    {
+        let data_version = Self::get_data_version(&mut reader)?;
+        Ok(O::deserialize(&mut reader, &version_map, data_version).map_err(Error::Versionize)?)
+    }
+
+    /// Reads the header of an existing snapshot and returns its data version, leaving `reader`
+    /// at the start of the snapshot objects.
+    pub fn get_data_version<T>(mut reader: &mut T) -> Result<u16, Error>
+    where
+        T: Read,
     {
         let format_version_map = Self::format_version_map();
         let magic_id =
@@ -111,9 +121,7 @@ impl Snapshot {
         let hdr: SnapshotHdr =
             SnapshotHdr::deserialize(&mut reader, &format_version_map, format_version)
                 .map_err(Error::Versionize)?;
-
-        Ok(O::deserialize(&mut reader, &version_map, hdr.data_version)
-            .map_err(Error::Versionize)?)
+        Ok(hdr.data_version)
     }
 
     /// Attempts to load an existing snapshot and validate CRC.
@@ -481,6 +489,35 @@ mod tests {
         let _: Test1 = Snapshot::load_with_crc64(&mut snapshot_mem.as_slice(), vm).unwrap();
     }
 
+    #[test]
+    fn test_get_data_version() {
+        let vm = VersionMap::new();
+        let state_1 = Test1 {
+            field_x: 0,
+            field0: 0,
+            field1: 1,
+        };
+
+        let mut snapshot_mem = vec![0u8; 1024];
+
+        let mut snapshot = Snapshot::new(vm.clone(), 1);
+        snapshot
+            .save(&mut snapshot_mem.as_mut_slice(), &state_1)
+            .unwrap();
+
+        let mut reader = snapshot_mem.as_slice();
+        assert_eq!(Snapshot::get_data_version(&mut reader).unwrap(), 1);
+        // The reader is left at the start of the snapshot objects.
+        let restored_state = Test1::deserialize(&mut reader, &vm, 1).unwrap();
+        assert_eq!(restored_state.field1, 1);
+
+        let invalid_mem = vec![0u8; 16];
+        assert_eq!(
+            Snapshot::get_data_version(&mut invalid_mem.as_slice()).unwrap_err(),
+            Error::InvalidMagic(0)
+        );
+    }
+
     #[test]
     fn test_corrupted_snapshot() {
         let vm = VersionMap::new();
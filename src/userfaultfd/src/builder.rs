@@ -14,6 +14,8 @@ bitflags! {
         const MISSING_HUGETLBFS = raw::UFFD_FEATURE_MISSING_HUGETLBFS;
         const MISSING_SHMEM = raw::UFFD_FEATURE_MISSING_SHMEM;
         const EVENT_UNMAP = raw::UFFD_FEATURE_EVENT_UNMAP;
+        #[cfg(feature = "linux4_14")]
+        const THREAD_ID = raw::UFFD_FEATURE_THREAD_ID;
     }
 }
 
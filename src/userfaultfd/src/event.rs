@@ -19,6 +19,9 @@ pub enum Event {
         rw: ReadWrite,
         /// The address that triggered the fault.
         addr: *mut c_void,
+        /// The id of the faulting thread, if the `THREAD_ID` feature was requested. Requires
+        /// the `linux4_14` feature.
+        thread_id: Option<u32>,
     },
     /// Generated when the faulting process invokes `fork(2)` (or `clone(2)` without the `CLONE_VM`
     /// flag).
@@ -63,9 +66,17 @@ impl Event {
                 } else {
                     ReadWrite::Write
                 };
+                #[cfg(feature = "linux4_14")]
+                let thread_id = match unsafe { pagefault.feat.ptid } {
+                    0 => None,
+                    ptid => Some(ptid),
+                };
+                #[cfg(not(feature = "linux4_14"))]
+                let thread_id = None;
                 Ok(Event::Pagefault {
                     rw,
                     addr: pagefault.address as *mut c_void,
+                    thread_id,
                 })
             }
             raw::UFFD_EVENT_FORK => {
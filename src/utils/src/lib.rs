@@ -6,7 +6,7 @@
 // More specifically, we are re-exporting modules from `vmm_sys_util` as part
 // of the `utils` crate.
 pub use vmm_sys_util::{
-    epoll, errno, eventfd, fam, ioctl, rand, syscall, tempdir, tempfile, terminal,
+    epoll, errno, eventfd, fam, ioctl, rand, sock_ctrl_msg, syscall, tempdir, tempfile, terminal,
 };
 pub use vmm_sys_util::{ioctl_expr, ioctl_ioc_nr, ioctl_iow_nr};
 
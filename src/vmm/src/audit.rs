@@ -0,0 +1,388 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Append-only audit log of the snapshot lifecycle operations.
+//!
+//! Each record is a JSON object on its own line, written with a single `write` to a file opened
+//! in append mode, so records are never overwritten or interleaved.
+
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use logger::error;
+use serde::Serialize;
+
+use crate::snapshot_signing;
+use crate::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams};
+
+lazy_static! {
+    /// Audit log of the process. Records are dropped until it is initialized.
+    pub static ref AUDIT: AuditLog = AuditLog::default();
+}
+
+/// Errors associated with the audit log.
+#[derive(Debug)]
+pub enum Error {
+    /// The audit log is already initialized.
+    AlreadyInitialized,
+    /// Failed to open the audit log file.
+    Open(PathBuf, io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            AlreadyInitialized => write!(f, "The audit log is already initialized"),
+            Open(path, err) => write!(f, "Cannot open audit log {}: {}", path.display(), err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Snapshot lifecycle operations recorded in the audit log.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A snapshot was created.
+    SnapshotCreate,
+    /// A snapshot was loaded.
+    SnapshotLoad,
+    /// The userfaultfd of the guest memory was sent to a page fault handler.
+    UffdHandoff,
+}
+
+/// File involved in an audited operation.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct AuditFile {
+    /// Role of the file in the operation, such as `state` or `memory`.
+    pub role: &'static str,
+    /// Path of the file, unless it was passed as a file descriptor.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// Inherited file descriptor of the file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fd: Option<RawFd>,
+    /// Size of the file in bytes, if it can be read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Hex encoded SHA-512 digest of the file contents.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha512: Option<String>,
+}
+
+impl AuditFile {
+    /// Describes the file at `path`, along with the digest of its contents if `checksum` is set.
+    pub fn from_path(role: &'static str, path: &Path, checksum: bool) -> Self {
+        let sha512 = if checksum {
+            File::open(path).ok().and_then(|file| sha512(path, &file))
+        } else {
+            None
+        };
+        AuditFile {
+            role,
+            path: Some(path.to_path_buf()),
+            size: fs::metadata(path).ok().map(|metadata| metadata.len()),
+            sha512,
+            ..Default::default()
+        }
+    }
+
+    /// Describes one of the guest memory layers of a snapshot. An inherited file descriptor
+    /// takes precedence over the path, and an empty path means the layer is not used.
+    fn from_layer(role: &'static str, path: &Path, fd: Option<RawFd>) -> Option<Self> {
+        if let Some(fd) = fd {
+            // Safe because `fstat` only writes to the provided buffer and we check the result.
+            let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+            let size = if unsafe { libc::fstat(fd, &mut stat) } == 0 {
+                Some(stat.st_size as u64)
+            } else {
+                None
+            };
+            return Some(AuditFile {
+                role,
+                fd: Some(fd),
+                size,
+                ..Default::default()
+            });
+        }
+
+        if path.as_os_str().is_empty() {
+            return None;
+        }
+        Some(AuditFile::from_path(role, path, false))
+    }
+}
+
+fn sha512(path: &Path, file: &File) -> Option<String> {
+    use ed25519_dalek::Digest;
+
+    let digest = snapshot_signing::digest(path, file).ok()?.finalize();
+    Some(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Credentials of the process at the other end of a unix socket.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct PeerCredentials {
+    /// Process id.
+    pub pid: i32,
+    /// User id.
+    pub uid: u32,
+    /// Group id.
+    pub gid: u32,
+}
+
+impl PeerCredentials {
+    /// Reads the credentials of the peer of the connected unix socket `fd`.
+    pub fn from_socket(fd: RawFd) -> Option<Self> {
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // Safe because the kernel writes at most `len` bytes to `cred` and we check the result.
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return None;
+        }
+        Some(PeerCredentials {
+            pid: cred.pid,
+            uid: cred.uid,
+            gid: cred.gid,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp_us: u64,
+    instance_id: &'a str,
+    pid: u32,
+    uid: u32,
+    gid: u32,
+    event: AuditEvent,
+    files: &'a [AuditFile],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer: Option<PeerCredentials>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+struct AuditSink {
+    file: File,
+    instance_id: String,
+    // The credentials are read once, the seccomp filters do not allow reading them later.
+    uid: u32,
+    gid: u32,
+}
+
+/// Append-only sink of the audit records.
+#[derive(Default)]
+pub struct AuditLog {
+    sink: Mutex<Option<AuditSink>>,
+}
+
+impl AuditLog {
+    /// Opens the audit log file at `path`, a regular file or a named pipe, in append mode.
+    /// Records are attributed to the `instance_id` microVM.
+    pub fn init(&self, path: &Path, instance_id: &str) -> Result<()> {
+        let mut sink = self.sink.lock().expect("Poisoned lock");
+        if sink.is_some() {
+            return Err(Error::AlreadyInitialized);
+        }
+
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| Error::Open(path.to_path_buf(), e))?;
+        // Safe because these calls cannot fail and do not touch memory.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        *sink = Some(AuditSink {
+            file,
+            instance_id: instance_id.to_string(),
+            uid,
+            gid,
+        });
+        Ok(())
+    }
+
+    /// Returns true if the audit log is initialized.
+    pub fn is_enabled(&self) -> bool {
+        self.sink.lock().expect("Poisoned lock").is_some()
+    }
+
+    /// Describes the files of a snapshot creation. Only the microVM state file is hashed,
+    /// hashing the memory file would double the I/O of the snapshot.
+    pub fn create_snapshot_files(&self, params: &CreateSnapshotParams) -> Vec<AuditFile> {
+        if !self.is_enabled() {
+            return Vec::new();
+        }
+        vec![
+            AuditFile::from_path("state", &params.snapshot_path, true),
+            AuditFile::from_path("memory", &params.mem_file_path, false),
+        ]
+    }
+
+    /// Describes the files of a snapshot load, before they are opened. Only the microVM state
+    /// file is hashed, reading the memory layers in full would defeat the lazy restore.
+    pub fn load_snapshot_files(&self, params: &LoadSnapshotParams) -> Vec<AuditFile> {
+        if !self.is_enabled() {
+            return Vec::new();
+        }
+        let layers = [
+            ("memory", &params.mem_file_path, params.mem_file_fd),
+            ("overlay", &params.overlay_file_path, params.overlay_file_fd),
+            ("working_set", &params.ws_file_path, params.ws_file_fd),
+        ];
+        let mut files = vec![AuditFile::from_path("state", &params.snapshot_path, true)];
+        files.extend(
+            layers
+                .iter()
+                .filter_map(|(role, path, fd)| AuditFile::from_layer(role, path, *fd)),
+        );
+        files
+    }
+
+    /// Appends a record of `event` to the audit log, if it is initialized. `peer` identifies
+    /// the process receiving data, and `error` is set if the operation failed.
+    pub fn record(
+        &self,
+        event: AuditEvent,
+        files: &[AuditFile],
+        peer: Option<PeerCredentials>,
+        error: Option<String>,
+    ) {
+        let mut guard = self.sink.lock().expect("Poisoned lock");
+        let sink = match guard.as_mut() {
+            Some(sink) => sink,
+            None => return,
+        };
+
+        let record = AuditRecord {
+            timestamp_us: utils::time::get_time_us(utils::time::ClockType::Real),
+            instance_id: &sink.instance_id,
+            pid: std::process::id(),
+            uid: sink.uid,
+            gid: sink.gid,
+            event,
+            files,
+            peer,
+            error,
+        };
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(err) => {
+                error!("Cannot serialize audit record: {}", err);
+                return;
+            }
+        };
+        line.push(b'\n');
+        if let Err(err) = sink.file.write_all(&line) {
+            error!("Cannot write audit record: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_record() {
+        let audit_file = TempFile::new().unwrap();
+        let audit_log = AuditLog::default();
+
+        // Records are dropped until the audit log is initialized.
+        assert!(!audit_log.is_enabled());
+        audit_log.record(AuditEvent::SnapshotLoad, &[], None, None);
+
+        audit_log.init(audit_file.as_path(), "vm0").unwrap();
+        assert!(audit_log.is_enabled());
+        match audit_log.init(audit_file.as_path(), "vm0") {
+            Err(Error::AlreadyInitialized) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let state_file = TempFile::new().unwrap();
+        state_file.as_file().write_all(b"state").unwrap();
+        let files = [AuditFile::from_path("state", state_file.as_path(), true)];
+        audit_log.record(AuditEvent::SnapshotCreate, &files, None, None);
+        audit_log.record(
+            AuditEvent::SnapshotLoad,
+            &[],
+            None,
+            Some("error".to_string()),
+        );
+
+        let contents = fs::read_to_string(audit_file.as_path()).unwrap();
+        let records: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+
+        assert_eq!(records[0]["event"], "snapshot_create");
+        assert_eq!(records[0]["instance_id"], "vm0");
+        assert_eq!(records[0]["pid"], std::process::id());
+        assert_eq!(records[0]["files"][0]["role"], "state");
+        assert_eq!(records[0]["files"][0]["size"], 5);
+        assert_eq!(
+            records[0]["files"][0]["sha512"].as_str().unwrap().len(),
+            128
+        );
+        assert!(records[0].get("error").is_none());
+
+        assert_eq!(records[1]["event"], "snapshot_load");
+        assert_eq!(records[1]["error"], "error");
+    }
+
+    #[test]
+    fn test_from_layer() {
+        assert!(AuditFile::from_layer("overlay", Path::new(""), None).is_none());
+
+        let layer_file = TempFile::new().unwrap();
+        layer_file.as_file().write_all(&[0u8; 16]).unwrap();
+        let file = AuditFile::from_layer("memory", layer_file.as_path(), None).unwrap();
+        assert_eq!(file.path, Some(layer_file.as_path().to_path_buf()));
+        assert_eq!(file.size, Some(16));
+        assert!(file.sha512.is_none());
+
+        let fd = layer_file.as_file().as_raw_fd();
+        let file = AuditFile::from_layer("memory", layer_file.as_path(), Some(fd)).unwrap();
+        assert!(file.path.is_none());
+        assert_eq!(file.fd, Some(fd));
+        assert_eq!(file.size, Some(16));
+    }
+
+    #[test]
+    fn test_peer_credentials() {
+        let (sock, _peer) = UnixStream::pair().unwrap();
+        let cred = PeerCredentials::from_socket(sock.as_raw_fd()).unwrap();
+        assert_eq!(cred.pid as u32, std::process::id());
+
+        assert!(PeerCredentials::from_socket(-1).is_none());
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Snapshot files backing the guest memory, for the diagnostics of a `SIGBUS`.
+//!
+//! A private file mapping faults with `SIGBUS` past the end of its file, so a snapshot file
+//! truncated or replaced after the restore kills the process as soon as the guest, or a helper
+//! thread, touches a page past its new end. The restore records which file backs which host
+//! range, and the `SIGBUS` handler looks the faulting address up to name the file and the extent.
+//!
+//! The record also holds a descriptor of each file for as long as the mappings, rather than
+//! leaving them to the mappings alone once the restore closes its files, so that the later
+//! operations on a layer, such as advising the kernel of its access pattern, can reach its file.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::probes::MmapLayer;
+
+lazy_static! {
+    /// Snapshot files backing the guest memory of the last successful restore.
+    pub static ref BACKING_FILES: Mutex<BackingFiles> = Mutex::new(BackingFiles::default());
+}
+
+/// Returns the path of `file`, or its descriptor when the path is unknown.
+pub fn file_name(file: &File) -> String {
+    std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|_| format!("fd {}", file.as_raw_fd()))
+}
+
+// A snapshot file, open for as long as it is mapped.
+struct BackingFile {
+    name: String,
+    layer: MmapLayer,
+    file: File,
+}
+
+// A host range mapped from a snapshot file.
+struct Mapping {
+    host_addr: usize,
+    len: usize,
+    layer: MmapLayer,
+    file: usize,
+    extent: usize,
+    file_offset: u64,
+}
+
+/// Snapshot file backing a host address.
+#[derive(Debug, PartialEq)]
+pub struct Backing<'a> {
+    /// Path of the file.
+    pub file: &'a str,
+    /// Layer of the mapping.
+    pub layer: MmapLayer,
+    /// Index of the extent within its layer, or of the region for the base layer.
+    pub extent: usize,
+    /// Offset of the address within the file.
+    pub file_offset: u64,
+}
+
+/// Snapshot files of the memory layers, and the host ranges mapped from them in mapping order.
+#[derive(Default)]
+pub struct BackingFiles {
+    files: Vec<BackingFile>,
+    mappings: Vec<Mapping>,
+}
+
+impl BackingFiles {
+    /// Adds `file` of `layer` to the backing files, keeping a duplicate of its descriptor, and
+    /// returns its index.
+    pub fn add_file(&mut self, file: &File, layer: MmapLayer) -> io::Result<usize> {
+        self.files.push(BackingFile {
+            name: file_name(file),
+            layer,
+            file: file.try_clone()?,
+        });
+        Ok(self.files.len() - 1)
+    }
+
+    /// Returns the file of `layer`, if the guest memory maps one.
+    pub fn layer_file(&self, layer: MmapLayer) -> Option<&File> {
+        self.files
+            .iter()
+            .find(|file| file.layer == layer)
+            .map(|file| &file.file)
+    }
+
+    /// Records that `len` bytes at `host_addr` map `file`, added with `add_file`, from
+    /// `file_offset` on.
+    pub fn add(
+        &mut self,
+        host_addr: usize,
+        len: usize,
+        layer: MmapLayer,
+        file: usize,
+        extent: usize,
+        file_offset: u64,
+    ) {
+        self.mappings.push(Mapping {
+            host_addr,
+            len,
+            layer,
+            file,
+            extent,
+            file_offset,
+        });
+    }
+
+    /// Returns the host address and length of the ranges mapped from the files of `layer`.
+    pub fn layer_ranges(&self, layer: MmapLayer) -> Vec<(usize, usize)> {
+        self.mappings
+            .iter()
+            .filter(|m| m.layer == layer)
+            .map(|m| (m.host_addr, m.len))
+            .collect()
+    }
+
+    /// Splits the `len` bytes at `host_addr` into the ranges backed by a single layer, each given
+    /// as its host address, length, and layer, or `None` where no file backs it.
+    pub fn layer_segments(
+        &self,
+        host_addr: usize,
+        len: usize,
+    ) -> Vec<(usize, usize, Option<MmapLayer>)> {
+        let end = host_addr + len;
+        let mut bounds = vec![host_addr, end];
+        for m in self.mappings.iter() {
+            if m.host_addr < end && m.host_addr + m.len > host_addr {
+                bounds.push(m.host_addr.max(host_addr));
+                bounds.push((m.host_addr + m.len).min(end));
+            }
+        }
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        // No mapping starts or ends within a range between two bounds, so a single layer backs it.
+        let mut segments: Vec<(usize, usize, Option<MmapLayer>)> = Vec::new();
+        for range in bounds.windows(2) {
+            let layer = self.find(range[0]).map(|backing| backing.layer);
+            match segments.last_mut() {
+                Some(last) if last.2 == layer => last.1 += range[1] - range[0],
+                _ => segments.push((range[0], range[1] - range[0], layer)),
+            }
+        }
+        segments
+    }
+
+    /// Returns the file backing `addr`, if any.
+    pub fn find(&self, addr: usize) -> Option<Backing> {
+        // The layers are mapped over the base layer, so the last mapping of an address wins.
+        self.mappings
+            .iter()
+            .rev()
+            .find(|m| addr >= m.host_addr && addr - m.host_addr < m.len)
+            .map(|m| Backing {
+                file: &self.files[m.file].name,
+                layer: m.layer,
+                extent: m.extent,
+                file_offset: m.file_offset + (addr - m.host_addr) as u64,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::MetadataExt;
+
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_find() {
+        let mem_file = TempFile::new().unwrap();
+        let ws_file = TempFile::new().unwrap();
+        let mut backing = BackingFiles::default();
+        let mem = backing
+            .add_file(mem_file.as_file(), MmapLayer::Base)
+            .unwrap();
+        let ws = backing
+            .add_file(ws_file.as_file(), MmapLayer::WorkingSet)
+            .unwrap();
+        let path = mem_file.as_path().canonicalize().unwrap();
+        assert_eq!(backing.files[mem].name, path.display().to_string());
+
+        backing.add(0x10000, 0x8000, MmapLayer::Base, mem, 0, 0);
+        backing.add(0x12000, 0x2000, MmapLayer::WorkingSet, ws, 3, 0x5000);
+
+        assert_eq!(backing.find(0xffff), None);
+        assert_eq!(backing.find(0x18000), None);
+        assert_eq!(
+            backing.find(0x11000).unwrap(),
+            Backing {
+                file: &backing.files[mem].name,
+                layer: MmapLayer::Base,
+                extent: 0,
+                file_offset: 0x1000,
+            }
+        );
+        assert_eq!(
+            backing.find(0x13fff).unwrap(),
+            Backing {
+                file: &backing.files[ws].name,
+                layer: MmapLayer::WorkingSet,
+                extent: 3,
+                file_offset: 0x6fff,
+            }
+        );
+        assert_eq!(backing.find(0x14000).unwrap().file_offset, 0x4000);
+    }
+
+    #[test]
+    fn test_layer_segments() {
+        let mem_file = TempFile::new().unwrap();
+        let mut backing = BackingFiles::default();
+        let mem = backing
+            .add_file(mem_file.as_file(), MmapLayer::Base)
+            .unwrap();
+        backing.add(0x10000, 0x8000, MmapLayer::Base, mem, 0, 0);
+        backing.add(0x12000, 0x2000, MmapLayer::Overlay, mem, 0, 0);
+        backing.add(0x13000, 0x2000, MmapLayer::WorkingSet, mem, 0, 0);
+
+        assert_eq!(
+            backing.layer_segments(0xe000, 0x10000),
+            vec![
+                (0xe000, 0x2000, None),
+                (0x10000, 0x2000, Some(MmapLayer::Base)),
+                (0x12000, 0x1000, Some(MmapLayer::Overlay)),
+                (0x13000, 0x2000, Some(MmapLayer::WorkingSet)),
+                (0x15000, 0x3000, Some(MmapLayer::Base)),
+                (0x18000, 0x6000, None),
+            ]
+        );
+        assert_eq!(
+            backing.layer_segments(0x11000, 0x2000),
+            vec![
+                (0x11000, 0x1000, Some(MmapLayer::Base)),
+                (0x12000, 0x1000, Some(MmapLayer::Overlay)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_layer_file() {
+        let ws_file = TempFile::new().unwrap();
+        let mut backing = BackingFiles::default();
+        backing
+            .add_file(ws_file.as_file(), MmapLayer::WorkingSet)
+            .unwrap();
+        assert!(backing.layer_file(MmapLayer::Overlay).is_none());
+
+        // The duplicate stays open once the original file is closed and removed.
+        drop(ws_file);
+        let file = backing.layer_file(MmapLayer::WorkingSet).unwrap();
+        assert_eq!(file.metadata().unwrap().nlink(), 0);
+    }
+}
@@ -10,13 +10,18 @@ use std::io::{self, Read, Seek, SeekFrom};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::{Arc, Mutex};
 
+use crate::default_syscalls::ThreadFilters;
 use crate::device_manager::mmio::MMIODeviceManager;
 #[cfg(target_arch = "x86_64")]
 use crate::device_manager::{legacy::PortIODeviceManager, persist::MMIODevManagerConstructorArgs};
+use crate::dump_writer::DUMP_WRITER;
+use crate::memory_residency::MemoryResidency;
 #[cfg(target_arch = "x86_64")]
 use crate::persist::{MicrovmState, MicrovmStateError};
+use crate::restore_trace::{RestorePhase, RESTORE_TRACE};
 use crate::vmm_config::boot_source::BootConfig;
 use crate::vstate::{KvmContext, Vcpu, VcpuConfig, Vm};
+use crate::worker_pool::WORKER_POOL;
 use crate::{device_manager, Error, Vmm, VmmEventsObserver};
 
 use arch::InitrdConfig;
@@ -25,7 +30,7 @@ use devices::virtio::{Block, MmioTransport, Net, VirtioDevice, Vsock, VsockUnixB
 use kernel::cmdline::Cmdline as KernelCmdline;
 use logger::warn;
 use polly::event_manager::{Error as EventManagerError, EventManager, Subscriber};
-use seccomp::{BpfProgramRef, SeccompFilter};
+use seccomp::SeccompFilter;
 #[cfg(target_arch = "x86_64")]
 use snapshot::Persist;
 use utils::eventfd::EventFd;
@@ -258,11 +263,17 @@ fn create_vmm_and_vcpus(
         events_observer: Some(Box::new(SerialStdin::get())),
         guest_memory,
         vcpus_handles: Vec::new(),
+        vcpus_running: false,
         exit_evt,
         vm,
         mmio_device_manager,
         #[cfg(target_arch = "x86_64")]
         pio_device_manager,
+        #[cfg(feature = "faasnap")]
+        fault_trace: None,
+        #[cfg(feature = "faasnap")]
+        protected_base: None,
+        memory_reclaimer: None,
     };
 
     Ok((vmm, vcpus))
@@ -278,7 +289,7 @@ fn create_vmm_and_vcpus(
 pub fn build_microvm_for_boot(
     vm_resources: &super::resources::VmResources,
     event_manager: &mut EventManager,
-    seccomp_filter: BpfProgramRef,
+    seccomp_filters: &ThreadFilters,
 ) -> std::result::Result<Arc<Mutex<Vmm>>, StartMicrovmError> {
     use self::StartMicrovmError::*;
     let boot_config = vm_resources.boot_source().ok_or(MissingKernelConfig)?;
@@ -338,13 +349,17 @@ pub fn build_microvm_for_boot(
     )?;
 
     // Move vcpus to their own threads and start their state machine in the 'Paused' state.
-    vmm.start_vcpus(vcpus, seccomp_filter).map_err(Internal)?;
+    vmm.start_vcpus(vcpus, &seccomp_filters.vcpu)
+        .map_err(Internal)?;
+
+    attach_memory_residency_sampler(event_manager, vmm.guest_memory());
+    start_helper_threads(seccomp_filters);
 
     // Load seccomp filters for the VMM thread.
     // Execution panics if filters cannot be loaded, use --seccomp-level=0 if skipping filters
     // altogether is the desired behaviour.
     // Keep this as the last step before resuming vcpus.
-    SeccompFilter::apply(seccomp_filter.to_vec())
+    SeccompFilter::apply(seccomp_filters.vmm.clone())
         .map_err(Error::SeccompFilters)
         .map_err(Internal)?;
 
@@ -369,7 +384,7 @@ pub fn build_microvm_from_snapshot(
     microvm_state: MicrovmState,
     guest_memory: GuestMemoryMmap,
     track_dirty_pages: bool,
-    seccomp_filter: BpfProgramRef,
+    seccomp_filters: &ThreadFilters,
 ) -> std::result::Result<Arc<Mutex<Vmm>>, StartMicrovmError> {
     use self::StartMicrovmError::*;
     let vcpu_count = u8::try_from(microvm_state.vcpu_states.len())
@@ -384,6 +399,7 @@ pub fn build_microvm_from_snapshot(
         vcpu_count,
     )?;
 
+    let device_span = RESTORE_TRACE.span(RestorePhase::DeviceRestore);
     // Restore kvm vm state.
     vmm.vm
         .restore_state(&microvm_state.vm_state)
@@ -400,15 +416,19 @@ pub fn build_microvm_from_snapshot(
         MMIODeviceManager::restore(mmio_ctor_args, &microvm_state.device_states)
             .map_err(MicrovmStateError::RestoreDevices)
             .map_err(RestoreMicrovmState)?;
+    drop(device_span);
 
     // Move vcpus to their own threads and start their state machine in the 'Paused' state.
-    vmm.start_vcpus(vcpus, seccomp_filter)
+    vmm.start_vcpus(vcpus, &seccomp_filters.vcpu)
         .map_err(StartMicrovmError::Internal)?;
 
     // Restore vcpus kvm state.
     vmm.restore_vcpu_states(microvm_state.vcpu_states)
         .map_err(RestoreMicrovmState)?;
 
+    attach_memory_residency_sampler(event_manager, vmm.guest_memory());
+    start_helper_threads(seccomp_filters);
+
     let vmm = Arc::new(Mutex::new(vmm));
     event_manager
         .add_subscriber(vmm.clone())
@@ -416,7 +436,7 @@ pub fn build_microvm_from_snapshot(
 
     // Load seccomp filters for the VMM thread.
     // Keep this as the last step of the building process.
-    SeccompFilter::apply(seccomp_filter.to_vec())
+    SeccompFilter::apply(seccomp_filters.vmm.clone())
         .map_err(Error::SeccompFilters)
         .map_err(StartMicrovmError::Internal)?;
 
@@ -543,6 +563,43 @@ pub fn setup_interrupt_controller(
         .map_err(StartMicrovmError::Internal)
 }
 
+/// Starts the threads helping with the snapshots, while the VMM thread may still spawn them,
+/// each under its own filter of `seccomp_filters`. Does nothing if they are already started.
+pub(crate) fn start_helper_threads(seccomp_filters: &ThreadFilters) {
+    if let Err(e) = DUMP_WRITER.start(seccomp_filters.dump_writer.clone()) {
+        warn!(
+            "Could not start the dump writer, dumps are not pipelined: {}",
+            e
+        );
+    }
+    if let Err(e) = WORKER_POOL.start(seccomp_filters.worker.clone()) {
+        warn!(
+            "Could not start the helper threads, their tasks run serially: {}",
+            e
+        );
+    }
+}
+
+/// Samples the residency of `guest_memory` to the metrics. The sampler is optional, failing to
+/// set it up does not fail the build.
+fn attach_memory_residency_sampler(
+    event_manager: &mut EventManager,
+    guest_memory: &GuestMemoryMmap,
+) {
+    match MemoryResidency::new(guest_memory.clone()) {
+        Ok(mut residency) => {
+            residency.start();
+            if let Err(e) = event_manager.add_subscriber(Arc::new(Mutex::new(residency))) {
+                warn!(
+                    "Could not add the memory residency sampler to epoll: {:?}",
+                    e
+                );
+            }
+        }
+        Err(e) => warn!("Could not create the memory residency sampler: {}", e),
+    }
+}
+
 /// Sets up the serial device.
 pub fn setup_serial_device(
     event_manager: &mut EventManager,
@@ -849,11 +906,17 @@ pub mod tests {
             events_observer: Some(Box::new(SerialStdin::get())),
             guest_memory,
             vcpus_handles: Vec::new(),
+            vcpus_running: false,
             exit_evt,
             vm,
             mmio_device_manager,
             #[cfg(target_arch = "x86_64")]
             pio_device_manager,
+            #[cfg(feature = "faasnap")]
+            fault_trace: None,
+            #[cfg(feature = "faasnap")]
+            protected_base: None,
+            memory_reclaimer: None,
         };
 
         #[cfg(target_arch = "x86_64")]
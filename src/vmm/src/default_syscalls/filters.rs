@@ -4,14 +4,306 @@ use std::convert::TryInto;
 
 use seccomp::{
     allow_syscall, allow_syscall_if, BpfProgram, Error, SeccompAction, SeccompCmpArgLen as ArgLen,
-    SeccompCmpOp::Eq, SeccompCondition as Cond, SeccompError, SeccompFilter, SeccompLevel,
-    SeccompRule,
+    SeccompCmpOp::{Eq, Le, MaskedEq},
+    SeccompCondition as Cond, SeccompError, SeccompFilter, SeccompLevel, SeccompRule,
+    SyscallRuleSet,
 };
 use utils::signal::sigrtmin;
 
+use super::{SeccompProfile, ThreadFilters};
+#[cfg(target_env = "musl")]
+use crate::memory_reclaim::{MADV_COLD, MADV_PAGEOUT};
+
 /// The default filter containing the white listed syscall rules required by `Firecracker` to
 /// function.
 pub fn default_filter() -> Result<SeccompFilter, Error> {
+    profile_filter(SeccompProfile::Default)
+}
+
+/// The filter containing the default rules, extended with the rules required by the faasnap
+/// restore paths.
+pub fn faasnap_filter() -> Result<SeccompFilter, Error> {
+    let mut filter = profile_filter(SeccompProfile::Faasnap)?;
+    // Registers guest memory with a userfaultfd and hands it to the page fault handler.
+    filter.add_rules(
+        libc::SYS_userfaultfd,
+        or![and![Cond::new(
+            0,
+            ArgLen::DWORD,
+            Eq,
+            (libc::O_CLOEXEC | libc::O_NONBLOCK) as u64
+        )?]],
+    )?;
+    filter.add_rules(libc::SYS_ioctl, super::create_uffd_ioctl_seccomp_rule()?)?;
+    // The base pager waits for the guest page faults of the write-protected base layer.
+    filter.add_rules(
+        libc::SYS_ppoll,
+        vec![SeccompRule::new(vec![], SeccompAction::Allow)],
+    )?;
+    #[cfg(target_arch = "x86_64")]
+    filter.add_rules(
+        libc::SYS_poll,
+        vec![SeccompRule::new(vec![], SeccompAction::Allow)],
+    )?;
+    // The uffd socket is a listening unix socket, the fd is sent with SCM_RIGHTS. The address
+    // cannot be checked, but `socket` only creates AF_UNIX sockets, so the address length is
+    // bounded by a `sockaddr_un`. The backlog is the one `UnixListener::bind` listens with.
+    filter.add_rules(
+        libc::SYS_bind,
+        or![and![Cond::new(
+            2,
+            ArgLen::DWORD,
+            Le,
+            std::mem::size_of::<libc::sockaddr_un>() as u64
+        )?]],
+    )?;
+    filter.add_rules(
+        libc::SYS_listen,
+        or![and![Cond::new(
+            1,
+            ArgLen::DWORD,
+            Eq,
+            super::UNIX_LISTENER_BACKLOG
+        )?]],
+    )?;
+    filter.add_rules(
+        libc::SYS_sendmsg,
+        or![and![Cond::new(2, ArgLen::DWORD, Eq, 0)?]],
+    )?;
+    // Inherited snapshot files are checked for being opened read-only.
+    filter.add_rules(
+        libc::SYS_fcntl,
+        or![and![Cond::new(1, ArgLen::DWORD, Eq, super::FCNTL_F_GETFL)?]],
+    )?;
+    // The page cache pre-warmer reads ahead the snapshot files by chunks.
+    filter.add_rules(
+        libc::SYS_readahead,
+        or![and![Cond::new(
+            2,
+            ArgLen::QWORD,
+            Le,
+            crate::page_cache::READAHEAD_CHUNK
+        )?]],
+    )?;
+    Ok(filter)
+}
+
+/// The filter installed on vCPU threads.
+///
+/// Besides running guest code, vCPU threads only emulate MMIO/PIO accesses and save or restore
+/// their KVM state, so file and socket syscalls are left out.
+pub fn vcpu_filter() -> Result<SeccompFilter, Error> {
+    let mut rules = thread_rules()?;
+    rules.push(allow_syscall_if(
+        libc::SYS_ioctl,
+        super::create_vcpu_ioctl_seccomp_rule()?,
+    ));
+    Ok(SeccompFilter::new(
+        rules.into_iter().collect(),
+        SeccompAction::Trap,
+    )?)
+}
+
+/// The filter installed on the worker pool threads.
+///
+/// The workers run the state (de)serialization, the dirty log fetches and the working set loads
+/// of the VMM thread, so they keep its file and memory rules. They neither serve the API nor
+/// the devices, nor hand out a userfaultfd, so the socket, event loop, timer and userfaultfd
+/// syscalls are left out, and the only ioctl allowed fetches the dirty log.
+pub fn worker_filter(profile: SeccompProfile) -> Result<SeccompFilter, Error> {
+    let mut filter = match profile {
+        SeccompProfile::Default => default_filter()?,
+        SeccompProfile::Faasnap => faasnap_filter()?,
+    };
+    for syscall in [
+        libc::SYS_accept4,
+        libc::SYS_bind,
+        libc::SYS_connect,
+        libc::SYS_copy_file_range,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_epoll_wait,
+        libc::SYS_ioctl,
+        libc::SYS_listen,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_pipe,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_poll,
+        libc::SYS_ppoll,
+        libc::SYS_recvfrom,
+        libc::SYS_sendfile,
+        libc::SYS_sendmsg,
+        libc::SYS_setsockopt,
+        libc::SYS_socket,
+        libc::SYS_timerfd_create,
+        libc::SYS_timerfd_settime,
+        libc::SYS_tkill,
+        libc::SYS_userfaultfd,
+    ]
+    .iter()
+    {
+        filter.remove_rules(*syscall);
+    }
+    filter.add_rules(
+        libc::SYS_ioctl,
+        or![and![Cond::new(
+            1,
+            ArgLen::DWORD,
+            Eq,
+            super::KVM_GET_DIRTY_LOG
+        )?]],
+    )?;
+    Ok(filter)
+}
+
+/// The filter installed on the dump writer thread, which only writes the dumped guest memory
+/// to the memory file.
+pub fn dump_writer_filter() -> Result<SeccompFilter, Error> {
+    let mut rules = thread_rules()?;
+    rules.push(allow_syscall(libc::SYS_pwrite64));
+    Ok(SeccompFilter::new(
+        rules.into_iter().collect(),
+        SeccompAction::Trap,
+    )?)
+}
+
+/// The filter installed on the threads servicing the guest page faults of a userfaultfd: the
+/// base pager and the fault trace recorder.
+///
+/// They wait for and read the fault events, read the pages from the memory file, populate
+/// them, and name the faulting vCPU from its `/proc` entry.
+pub fn uffd_handler_filter() -> Result<SeccompFilter, Error> {
+    let mut rules = thread_rules()?;
+    rules.extend(vec![
+        allow_syscall_if(
+            libc::SYS_ioctl,
+            super::create_uffd_handler_ioctl_seccomp_rule()?,
+        ),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_open),
+        allow_syscall(libc::SYS_openat),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_poll),
+        allow_syscall(libc::SYS_ppoll),
+        allow_syscall(libc::SYS_pread64),
+        allow_syscall(libc::SYS_read),
+    ]);
+    Ok(SeccompFilter::new(
+        rules.into_iter().collect(),
+        SeccompAction::Trap,
+    )?)
+}
+
+// Rules of the threads which only run guest code or a fixed task: memory management, locking,
+// logging and exiting.
+fn thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
+    Ok(vec![
+        allow_syscall(libc::SYS_brk),
+        allow_syscall(libc::SYS_clock_gettime),
+        allow_syscall(libc::SYS_close),
+        allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_exit_group),
+        allow_syscall(libc::SYS_fstat),
+        allow_syscall_if(
+            libc::SYS_futex,
+            or![
+                and![Cond::new(1, ArgLen::DWORD, Eq, super::FUTEX_WAIT_PRIVATE)?],
+                and![Cond::new(1, ArgLen::DWORD, Eq, super::FUTEX_WAKE_PRIVATE)?],
+                and![Cond::new(
+                    1,
+                    ArgLen::DWORD,
+                    Eq,
+                    super::FUTEX_REQUEUE_PRIVATE
+                )?],
+                #[cfg(target_env = "gnu")]
+                and![Cond::new(
+                    1,
+                    ArgLen::DWORD,
+                    Eq,
+                    super::FUTEX_CMP_REQUEUE_PRIVATE
+                )?],
+            ],
+        ),
+        #[cfg(target_env = "musl")]
+        allow_syscall_if(
+            libc::SYS_madvise,
+            or![and![Cond::new(
+                2,
+                ArgLen::DWORD,
+                Eq,
+                libc::MADV_DONTNEED as u64
+            )?],],
+        ),
+        // Memory allocations never need to replace an existing mapping.
+        allow_syscall_if(
+            libc::SYS_mmap,
+            or![and![Cond::new(
+                3,
+                ArgLen::DWORD,
+                MaskedEq(libc::MAP_FIXED as u64),
+                0
+            )?]],
+        ),
+        allow_syscall(libc::SYS_mremap),
+        allow_syscall(libc::SYS_munmap),
+        // SYS_rt_sigreturn is needed to return from the signal handlers, like the vcpu kick one.
+        allow_syscall(libc::SYS_rt_sigreturn),
+        allow_syscall(libc::SYS_sigaltstack),
+        #[cfg(target_env = "gnu")]
+        allow_syscall(libc::SYS_tgkill),
+        allow_syscall(libc::SYS_write),
+        allow_syscall(libc::SYS_writev),
+    ])
+}
+
+/// Builds the `mmap` rules for `profile`.
+///
+/// The default profile allows any mapping. The faasnap profile only allows `MAP_FIXED` for the
+/// private, read-write file mappings laid over guest memory by the overlay and WS layers, and for
+/// the read-only ones the populated WS extents are mapped with before being made writable. Both
+/// may leave out `MAP_NORESERVE`, for the strict memory accounting.
+fn mmap_rules(profile: SeccompProfile) -> Result<SyscallRuleSet, Error> {
+    Ok(match profile {
+        SeccompProfile::Default => allow_syscall(libc::SYS_mmap),
+        SeccompProfile::Faasnap => allow_syscall_if(
+            libc::SYS_mmap,
+            or![
+                and![Cond::new(
+                    3,
+                    ArgLen::DWORD,
+                    MaskedEq(libc::MAP_FIXED as u64),
+                    0
+                )?],
+                and![
+                    Cond::new(
+                        2,
+                        ArgLen::DWORD,
+                        Eq,
+                        (libc::PROT_READ | libc::PROT_WRITE) as u64
+                    )?,
+                    Cond::new(
+                        3,
+                        ArgLen::DWORD,
+                        MaskedEq(!(libc::MAP_NORESERVE as u64)),
+                        (libc::MAP_FIXED | libc::MAP_PRIVATE) as u64
+                    )?,
+                ],
+                and![
+                    Cond::new(2, ArgLen::DWORD, Eq, libc::PROT_READ as u64)?,
+                    Cond::new(
+                        3,
+                        ArgLen::DWORD,
+                        MaskedEq(!(libc::MAP_NORESERVE as u64)),
+                        (libc::MAP_FIXED | libc::MAP_PRIVATE | libc::MAP_POPULATE) as u64
+                    )?,
+                ],
+            ],
+        ),
+    })
+}
+
+fn profile_filter(profile: SeccompProfile) -> Result<SeccompFilter, Error> {
     Ok(SeccompFilter::new(
         vec![
             allow_syscall(libc::SYS_accept4),
@@ -19,6 +311,8 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_clock_gettime),
             allow_syscall(libc::SYS_close),
             allow_syscall(libc::SYS_connect),
+            // Used to copy the unmodified guest memory on dumps.
+            allow_syscall(libc::SYS_copy_file_range),
             allow_syscall(libc::SYS_dup),
             allow_syscall(libc::SYS_epoll_ctl),
             allow_syscall(libc::SYS_epoll_pwait),
@@ -26,6 +320,8 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_epoll_wait),
             allow_syscall(libc::SYS_exit),
             allow_syscall(libc::SYS_exit_group),
+            // Used to preallocate the snapshot memory files.
+            allow_syscall(libc::SYS_fallocate),
             allow_syscall_if(
                 libc::SYS_fcntl,
                 or![and![
@@ -33,7 +329,11 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
                     Cond::new(2, ArgLen::QWORD, Eq, super::FCNTL_FD_CLOEXEC)?,
                 ]],
             ),
+            // Used to share the prefetch bandwidth with the other restores of the host.
+            allow_syscall(libc::SYS_flock),
             allow_syscall(libc::SYS_fstat),
+            // Used to check that the snapshot cache directory is memory-backed.
+            allow_syscall(libc::SYS_fstatfs),
             #[cfg(target_arch = "x86_64")]
             allow_syscall(libc::SYS_ftruncate),
             allow_syscall_if(
@@ -56,6 +356,8 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
                     )?],
                 ],
             ),
+            // Used to list the staged ws files.
+            allow_syscall(libc::SYS_getdents64),
             #[cfg(target_env = "gnu")]
             allow_syscall(libc::SYS_getpid),
             allow_syscall(libc::SYS_getrandom),
@@ -64,16 +366,46 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
             #[cfg(target_env = "musl")]
             allow_syscall_if(
                 libc::SYS_madvise,
+                or![
+                    and![Cond::new(2, ArgLen::DWORD, Eq, libc::MADV_DONTNEED as u64)?],
+                    // Used to mark the restored guest memory as mergeable by KSM.
+                    and![Cond::new(
+                        2,
+                        ArgLen::DWORD,
+                        Eq,
+                        libc::MADV_MERGEABLE as u64
+                    )?],
+                    // Used to read ahead the abandoned working set extents.
+                    and![Cond::new(2, ArgLen::DWORD, Eq, libc::MADV_WILLNEED as u64)?],
+                    // Used to back the restored guest memory with transparent huge pages.
+                    and![Cond::new(2, ArgLen::DWORD, Eq, libc::MADV_HUGEPAGE as u64)?],
+                    // Used to reclaim the restored guest memory.
+                    and![Cond::new(2, ArgLen::DWORD, Eq, MADV_COLD as u64)?],
+                    and![Cond::new(2, ArgLen::DWORD, Eq, MADV_PAGEOUT as u64)?],
+                ],
+            ),
+            // Used to place the restored guest memory on a NUMA node.
+            allow_syscall(libc::SYS_mbind),
+            // Used to sample the guest memory residency.
+            allow_syscall(libc::SYS_mincore),
+            // Used to lock the working set in memory.
+            allow_syscall(libc::SYS_mlock),
+            allow_syscall(libc::SYS_mlock2),
+            mmap_rules(profile)?,
+            // Used to make the populated WS extents writable.
+            allow_syscall_if(
+                libc::SYS_mprotect,
                 or![and![Cond::new(
                     2,
                     ArgLen::DWORD,
                     Eq,
-                    libc::MADV_DONTNEED as u64
+                    (libc::PROT_READ | libc::PROT_WRITE) as u64
                 )?],],
             ),
-            allow_syscall(libc::SYS_mmap),
             allow_syscall(libc::SYS_mremap),
             allow_syscall(libc::SYS_munmap),
+            // Used by the working set prefetch waiting for host pressure or bandwidth.
+            allow_syscall(libc::SYS_nanosleep),
             #[cfg(target_arch = "aarch64")]
             allow_syscall(libc::SYS_newfstatat),
             #[cfg(target_arch = "x86_64")]
@@ -81,12 +413,43 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_openat),
             #[cfg(target_arch = "x86_64")]
             allow_syscall(libc::SYS_pipe),
+            // Used to hash the snapshot files when signing them.
+            allow_syscall(libc::SYS_pread64),
+            // Used to find the cached working set chunks.
+            allow_syscall(libc::SYS_preadv2),
+            // Used to raise RLIMIT_MEMLOCK for the working set lock.
+            allow_syscall(libc::SYS_prlimit64),
+            // Used by the dump writer thread.
+            allow_syscall(libc::SYS_pwrite64),
             allow_syscall(libc::SYS_read),
+            // Used to name the snapshot files backing the guest memory.
+            #[cfg(target_arch = "x86_64")]
+            allow_syscall(libc::SYS_readlink),
+            allow_syscall(libc::SYS_readlinkat),
             allow_syscall(libc::SYS_readv),
             allow_syscall(libc::SYS_recvfrom),
+            // Used to stage the ws files.
+            #[cfg(target_arch = "x86_64")]
+            allow_syscall(libc::SYS_rename),
             // SYS_rt_sigreturn is needed in case a fault does occur, so that the signal handler
             // can return. Otherwise we get stuck in a fault loop.
             allow_syscall(libc::SYS_rt_sigreturn),
+            // Used to stream the unmodified guest memory to a pipe or socket memory file.
+            allow_syscall(libc::SYS_sendfile),
+            // Timeouts of the exchanges with the guest agent.
+            allow_syscall_if(
+                libc::SYS_setsockopt,
+                or![
+                    and![
+                        Cond::new(1, ArgLen::DWORD, Eq, libc::SOL_SOCKET as u64)?,
+                        Cond::new(2, ArgLen::DWORD, Eq, libc::SO_RCVTIMEO as u64)?,
+                    ],
+                    and![
+                        Cond::new(1, ArgLen::DWORD, Eq, libc::SOL_SOCKET as u64)?,
+                        Cond::new(2, ArgLen::DWORD, Eq, libc::SO_SNDTIMEO as u64)?,
+                    ],
+                ],
+            ),
             allow_syscall(libc::SYS_sigaltstack),
             allow_syscall_if(
                 libc::SYS_socket,
@@ -107,6 +470,11 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
             allow_syscall(libc::SYS_tgkill),
             allow_syscall(libc::SYS_timerfd_create),
             allow_syscall(libc::SYS_timerfd_settime),
+            // Used to remove the staged ws files on stop.
+            #[cfg(target_arch = "x86_64")]
+            allow_syscall(libc::SYS_unlink),
+            // Used to record the last use of the snapshot cache files.
+            allow_syscall(libc::SYS_utimensat),
             allow_syscall(libc::SYS_write),
             allow_syscall(libc::SYS_writev),
         ]
@@ -116,15 +484,43 @@ pub fn default_filter() -> Result<SeccompFilter, Error> {
     )?)
 }
 
-/// Generate a BPF program based on a seccomp level value.
-pub fn get_seccomp_filter(seccomp_level: SeccompLevel) -> Result<BpfProgram, SeccompError> {
+/// Generate a BPF program based on a seccomp level value and a seccomp profile.
+pub fn get_seccomp_filter(
+    seccomp_level: SeccompLevel,
+    seccomp_profile: SeccompProfile,
+) -> Result<BpfProgram, SeccompError> {
+    match seccomp_profile {
+        SeccompProfile::Default => build_program(seccomp_level, default_filter),
+        SeccompProfile::Faasnap => build_program(seccomp_level, faasnap_filter),
+    }
+}
+
+/// Generate the BPF programs of each kind of thread based on a seccomp level value and a seccomp
+/// profile.
+pub fn get_seccomp_filters(
+    seccomp_level: SeccompLevel,
+    seccomp_profile: SeccompProfile,
+) -> Result<ThreadFilters, SeccompError> {
+    Ok(ThreadFilters {
+        vmm: get_seccomp_filter(seccomp_level, seccomp_profile)?,
+        vcpu: build_program(seccomp_level, vcpu_filter)?,
+        worker: build_program(seccomp_level, || worker_filter(seccomp_profile))?,
+        dump_writer: build_program(seccomp_level, dump_writer_filter)?,
+        uffd_handler: build_program(seccomp_level, uffd_handler_filter)?,
+    })
+}
+
+fn build_program<F: FnOnce() -> Result<SeccompFilter, Error>>(
+    seccomp_level: SeccompLevel,
+    filter: F,
+) -> Result<BpfProgram, SeccompError> {
     match seccomp_level {
         SeccompLevel::None => Ok(vec![]),
-        SeccompLevel::Basic => default_filter()
+        SeccompLevel::Basic => filter()
             .and_then(|filter| Ok(filter.allow_all()))
             .and_then(|filter| filter.try_into())
             .map_err(SeccompError::SeccompFilter),
-        SeccompLevel::Advanced => default_filter()
+        SeccompLevel::Advanced => filter()
             .and_then(|filter| filter.try_into())
             .map_err(SeccompError::SeccompFilter),
     }
@@ -132,13 +528,64 @@ pub fn get_seccomp_filter(seccomp_level: SeccompLevel) -> Result<BpfProgram, Sec
 
 #[cfg(test)]
 mod tests {
-    use super::get_seccomp_filter;
+    use super::{get_seccomp_filter, get_seccomp_filters};
+    use crate::default_syscalls::SeccompProfile;
     use seccomp::SeccompLevel;
 
     #[test]
     fn test_get_seccomp_filter() {
-        assert!(get_seccomp_filter(SeccompLevel::None).is_ok());
-        assert!(get_seccomp_filter(SeccompLevel::Basic).is_ok());
-        assert!(get_seccomp_filter(SeccompLevel::Advanced).is_ok());
+        for profile in [SeccompProfile::Default, SeccompProfile::Faasnap].iter() {
+            assert!(get_seccomp_filter(SeccompLevel::None, *profile).is_ok());
+            assert!(get_seccomp_filter(SeccompLevel::Basic, *profile).is_ok());
+            assert!(get_seccomp_filter(SeccompLevel::Advanced, *profile).is_ok());
+        }
+
+        // The faasnap profile only adds rules on top of the default ones.
+        let default = get_seccomp_filter(SeccompLevel::Advanced, SeccompProfile::Default).unwrap();
+        let faasnap = get_seccomp_filter(SeccompLevel::Advanced, SeccompProfile::Faasnap).unwrap();
+        assert!(faasnap.len() > default.len());
+    }
+
+    #[test]
+    fn test_get_seccomp_filters() {
+        let filters = get_seccomp_filters(SeccompLevel::None, SeccompProfile::Default).unwrap();
+        assert!(filters.vmm.is_empty());
+        assert!(filters.vcpu.is_empty());
+        assert!(filters.worker.is_empty());
+        assert!(filters.dump_writer.is_empty());
+        assert!(filters.uffd_handler.is_empty());
+
+        for profile in [SeccompProfile::Default, SeccompProfile::Faasnap].iter() {
+            let filters = get_seccomp_filters(SeccompLevel::Advanced, *profile).unwrap();
+            assert_eq!(
+                filters.vmm,
+                get_seccomp_filter(SeccompLevel::Advanced, *profile).unwrap()
+            );
+            // The vCPU and helper thread filters only keep a subset of the VMM rules.
+            assert!(filters.vcpu.len() < filters.vmm.len());
+            assert!(filters.worker.len() < filters.vmm.len());
+            assert!(filters.dump_writer.len() < filters.vmm.len());
+            assert!(filters.uffd_handler.len() < filters.vmm.len());
+        }
+        assert!(get_seccomp_filters(SeccompLevel::Basic, SeccompProfile::Faasnap).is_ok());
+    }
+
+    #[test]
+    fn test_seccomp_profile_from_string() {
+        assert_eq!(
+            SeccompProfile::from_string("default").unwrap(),
+            SeccompProfile::Default
+        );
+        #[cfg(feature = "faasnap")]
+        assert_eq!(
+            SeccompProfile::from_string("faasnap").unwrap(),
+            SeccompProfile::Faasnap
+        );
+        #[cfg(not(feature = "faasnap"))]
+        assert!(SeccompProfile::from_string("faasnap").is_err());
+        assert_eq!(
+            SeccompProfile::from_string("foo").unwrap_err(),
+            "unknown seccomp profile 'foo'"
+        );
     }
 }
@@ -2,20 +2,77 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use seccomp::{
-    Error, SeccompAction, SeccompCmpArgLen as ArgLen, SeccompCmpOp::Eq, SeccompCondition as Cond,
-    SeccompRule,
+    BpfProgram, Error, SeccompAction, SeccompCmpArgLen as ArgLen, SeccompCmpOp::Eq,
+    SeccompCondition as Cond, SeccompRule,
 };
 
 #[macro_use]
 mod macros;
 mod filters;
+pub mod policy;
 
 pub use self::filters::default_filter;
+pub use self::filters::dump_writer_filter;
+pub use self::filters::faasnap_filter;
 pub use self::filters::get_seccomp_filter;
+pub use self::filters::get_seccomp_filters;
+pub use self::filters::uffd_handler_filter;
+pub use self::filters::vcpu_filter;
+pub use self::filters::worker_filter;
+
+/// BPF programs installed on the different kinds of `Firecracker` threads.
+///
+/// An empty program means that no filter is installed on the corresponding threads.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ThreadFilters {
+    /// Filter for the VMM and API threads.
+    pub vmm: BpfProgram,
+    /// Filter for the vCPU threads. These only run guest code and emulate MMIO/PIO accesses, so
+    /// they are not allowed to open files or sockets.
+    pub vcpu: BpfProgram,
+    /// Filter for the worker pool threads, which run the snapshot tasks of the VMM thread but
+    /// neither serve the API nor the devices.
+    pub worker: BpfProgram,
+    /// Filter for the dump writer thread, which only writes the guest memory to the memory file.
+    pub dump_writer: BpfProgram,
+    /// Filter for the threads servicing the guest page faults from a userfaultfd.
+    pub uffd_handler: BpfProgram,
+}
+
+/// Set of syscall rules installed by the seccomp filters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeccompProfile {
+    /// Rules required by the stock `Firecracker` boot and snapshot paths.
+    Default,
+    /// Adds the rules required by the faasnap restore paths: userfaultfd registration,
+    /// `MAP_FIXED` file mappings over guest memory, readahead and unix socket fd passing.
+    Faasnap,
+}
+
+impl SeccompProfile {
+    /// Converts from a seccomp profile name to the corresponding SeccompProfile variant
+    /// or returns an error if the name is unknown.
+    pub fn from_string(profile: &str) -> std::result::Result<Self, String> {
+        match profile {
+            "default" => Ok(SeccompProfile::Default),
+            #[cfg(feature = "faasnap")]
+            "faasnap" => Ok(SeccompProfile::Faasnap),
+            #[cfg(not(feature = "faasnap"))]
+            "faasnap" => {
+                Err("the faasnap seccomp profile requires the faasnap feature".to_string())
+            }
+            _ => Err(format!("unknown seccomp profile '{}'", profile)),
+        }
+    }
+}
 
 // See include/uapi/asm-generic/fcntl.h in the kernel code.
 const FCNTL_FD_CLOEXEC: u64 = 1;
 const FCNTL_F_SETFD: u64 = 2;
+const FCNTL_F_GETFL: u64 = 3;
+
+// The backlog `UnixListener::bind` listens with, in the standard library.
+const UNIX_LISTENER_BACKLOG: u64 = 128;
 
 // See include/uapi/linux/futex.h in the kernel code.
 const FUTEX_WAIT: u64 = 0;
@@ -84,12 +141,20 @@ const KVM_GET_XSAVE: u64 = 0x9000_aea4;
 const KVM_SET_XSAVE: u64 = 0x5000_aea5;
 const KVM_GET_XCRS: u64 = 0x8188_aea6;
 const KVM_SET_XCRS: u64 = 0x4188_aea7;
+const KVM_ENABLE_CAP: u64 = 0x4068_aea3;
 
 // See include/uapi/linux/if_tun.h in the kernel code.
 const TUNSETIFF: u64 = 0x4004_54ca;
 const TUNSETOFFLOAD: u64 = 0x4004_54d0;
 const TUNSETVNETHDRSZ: u64 = 0x4004_54d8;
 
+// See include/uapi/linux/userfaultfd.h in the kernel code.
+const UFFDIO_API: u64 = 0xc018_aa3f;
+const UFFDIO_REGISTER: u64 = 0xc020_aa00;
+const UFFDIO_WAKE: u64 = 0x8010_aa02;
+const UFFDIO_COPY: u64 = 0xc028_aa03;
+const UFFDIO_ZEROPAGE: u64 = 0xc020_aa04;
+
 fn create_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
     Ok(or![
         and![Cond::new(1, ArgLen::DWORD, Eq, TCSETS)?],
@@ -151,6 +216,53 @@ fn create_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
     ])
 }
 
+fn create_vcpu_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
+    Ok(or![
+        and![Cond::new(1, ArgLen::DWORD, Eq, TCSETS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, TCGETS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_RUN)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_ENABLE_CAP)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_CPUID2)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_CPUID2)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_DEBUGREGS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_DEBUGREGS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_FPU)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_LAPIC)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_LAPIC)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_MP_STATE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_MP_STATE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_MSRS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_MSRS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_REGS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_REGS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_SREGS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_SREGS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_VCPU_EVENTS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_VCPU_EVENTS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_XCRS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_XCRS)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_GET_XSAVE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_XSAVE)?],
+    ])
+}
+
+fn create_uffd_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
+    Ok(or![
+        and![Cond::new(1, ArgLen::DWORD, Eq, UFFDIO_API)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, UFFDIO_REGISTER)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, UFFDIO_WAKE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, UFFDIO_COPY)?],
+    ])
+}
+
+fn create_uffd_handler_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
+    Ok(or![
+        and![Cond::new(1, ArgLen::DWORD, Eq, UFFDIO_WAKE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, UFFDIO_COPY)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, UFFDIO_ZEROPAGE)?],
+    ])
+}
+
 #[cfg(test)]
 #[cfg(target_env = "musl")]
 mod tests {
@@ -207,4 +319,58 @@ mod tests {
         .join()
         .unwrap();
     }
+
+    #[test]
+    fn test_vcpu_seccomp() {
+        // Spawn a new thread before running the tests because all tests run
+        // in the same thread. Otherwise other tests will fail because of the
+        // installed seccomp filters.
+        thread::spawn(move || {
+            let filter = vcpu_filter().unwrap();
+            add_syscalls_install_filter(filter);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_helper_thread_seccomp() {
+        // Spawn a new thread before running the tests because all tests run
+        // in the same thread. Otherwise other tests will fail because of the
+        // installed seccomp filters.
+        for profile in [SeccompProfile::Default, SeccompProfile::Faasnap].iter() {
+            let profile = *profile;
+            thread::spawn(move || {
+                let filter = worker_filter(profile).unwrap();
+                add_syscalls_install_filter(filter);
+            })
+            .join()
+            .unwrap();
+        }
+        thread::spawn(move || {
+            let filter = dump_writer_filter().unwrap();
+            add_syscalls_install_filter(filter);
+        })
+        .join()
+        .unwrap();
+        thread::spawn(move || {
+            let filter = uffd_handler_filter().unwrap();
+            add_syscalls_install_filter(filter);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_faasnap_seccomp() {
+        // Spawn a new thread before running the tests because all tests run
+        // in the same thread. Otherwise other tests will fail because of the
+        // installed seccomp filters.
+        thread::spawn(move || {
+            let filter = faasnap_filter().unwrap();
+            add_syscalls_install_filter(filter);
+        })
+        .join()
+        .unwrap();
+    }
 }
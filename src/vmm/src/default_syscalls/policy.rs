@@ -0,0 +1,402 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Seccomp filters read from a file instead of the built-in ones.
+//!
+//! The file holds either a compiled BPF program, installed on every thread, or a JSON policy
+//! compiled at start into a filter for each kind of thread. A policy can extend the rules of a
+//! built-in profile, so that site specific syscalls are allowed without restating the others.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use seccomp::{
+    sock_filter, BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition,
+    SeccompFilter, SeccompRule,
+};
+use serde::Deserialize;
+
+use super::{
+    default_filter, dump_writer_filter, faasnap_filter, uffd_handler_filter, vcpu_filter,
+    worker_filter, SeccompProfile, ThreadFilters,
+};
+
+// Size of a BPF instruction.
+const BPF_INSTRUCTION_SIZE: usize = 8;
+// Largest number of instructions of a BPF program.
+const BPF_MAX_LEN: usize = 4096;
+
+/// Errors associated with loading seccomp filters from a file.
+#[derive(Debug)]
+pub enum Error {
+    /// The compiled BPF program is empty, too large, or not made of whole instructions. Holds
+    /// the size of the file.
+    InvalidBpf(usize),
+    /// The JSON policy is invalid.
+    InvalidPolicy(String),
+    /// Failed to read the file.
+    Read(io::Error),
+    /// Failed to compile the rules of the policy.
+    Seccomp(seccomp::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            InvalidBpf(size) => write!(f, "Invalid compiled BPF program of {} bytes", size),
+            InvalidPolicy(err) => write!(f, "Invalid seccomp policy: {}", err),
+            Read(err) => write!(f, "Failed to read the seccomp filter: {}", err),
+            Seccomp(err) => write!(f, "Failed to compile the seccomp policy: {}", err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Policy {
+    // Built-in profile whose rules the policy extends.
+    #[serde(default)]
+    base: Option<String>,
+    // Action on the syscalls matching no rule, when there is no base profile.
+    #[serde(default)]
+    default_action: Option<Action>,
+    #[serde(default)]
+    vmm: Vec<Rule>,
+    #[serde(default)]
+    vcpu: Vec<Rule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    // Syscall number on the host architecture.
+    syscall: i64,
+    // Conditions on the arguments, all of which must match.
+    #[serde(default)]
+    args: Vec<Condition>,
+    #[serde(default = "Action::allow")]
+    action: Action,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Condition {
+    index: u8,
+    #[serde(default)]
+    len: ArgLen,
+    op: Op,
+    value: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ArgLen {
+    Dword,
+    Qword,
+}
+
+impl Default for ArgLen {
+    fn default() -> Self {
+        ArgLen::Qword
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Op {
+    Eq,
+    Ge,
+    Gt,
+    Le,
+    Lt,
+    MaskedEq(u64),
+    Ne,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    Allow,
+    Errno(u32),
+    Kill,
+    Log,
+    Trace(u32),
+    Trap,
+}
+
+impl Action {
+    fn allow() -> Self {
+        Action::Allow
+    }
+}
+
+impl From<Action> for SeccompAction {
+    fn from(action: Action) -> Self {
+        match action {
+            Action::Allow => SeccompAction::Allow,
+            Action::Errno(errno) => SeccompAction::Errno(errno),
+            Action::Kill => SeccompAction::Kill,
+            Action::Log => SeccompAction::Log,
+            Action::Trace(value) => SeccompAction::Trace(value),
+            Action::Trap => SeccompAction::Trap,
+        }
+    }
+}
+
+impl Condition {
+    fn into_seccomp(self) -> Result<SeccompCondition> {
+        let len = match self.len {
+            ArgLen::Dword => SeccompCmpArgLen::DWORD,
+            ArgLen::Qword => SeccompCmpArgLen::QWORD,
+        };
+        let op = match self.op {
+            Op::Eq => SeccompCmpOp::Eq,
+            Op::Ge => SeccompCmpOp::Ge,
+            Op::Gt => SeccompCmpOp::Gt,
+            Op::Le => SeccompCmpOp::Le,
+            Op::Lt => SeccompCmpOp::Lt,
+            Op::MaskedEq(mask) => SeccompCmpOp::MaskedEq(mask),
+            Op::Ne => SeccompCmpOp::Ne,
+        };
+        SeccompCondition::new(self.index, len, op, self.value).map_err(Error::Seccomp)
+    }
+}
+
+// Adds the `rules` to `filter`, and compiles it.
+fn compile(mut filter: SeccompFilter, rules: Vec<Rule>) -> Result<BpfProgram> {
+    for rule in rules {
+        let conditions = rule
+            .args
+            .into_iter()
+            .map(Condition::into_seccomp)
+            .collect::<Result<Vec<_>>>()?;
+        filter
+            .add_rules(
+                rule.syscall,
+                vec![SeccompRule::new(conditions, rule.action.into())],
+            )
+            .map_err(Error::Seccomp)?;
+    }
+    filter.try_into().map_err(Error::Seccomp)
+}
+
+/// Compiles the JSON seccomp `policy` into the filters of each kind of thread.
+pub fn compile_policy(policy: &str) -> Result<ThreadFilters> {
+    let policy: Policy =
+        serde_json::from_str(policy).map_err(|e| Error::InvalidPolicy(e.to_string()))?;
+
+    let (vmm, vcpu, helpers) = match (policy.base, policy.default_action) {
+        (Some(_), Some(_)) => {
+            return Err(Error::InvalidPolicy(
+                "default_action only applies to policies without base".to_string(),
+            ))
+        }
+        (Some(base), None) => {
+            let profile = SeccompProfile::from_string(&base).map_err(Error::InvalidPolicy)?;
+            let vmm_filter = match profile {
+                SeccompProfile::Default => default_filter(),
+                SeccompProfile::Faasnap => faasnap_filter(),
+            };
+            // The rules only extend the VMM and vCPU filters, the helper threads keep the
+            // built-in filters of the base profile.
+            let helpers = (
+                compile(worker_filter(profile).map_err(Error::Seccomp)?, vec![])?,
+                compile(dump_writer_filter().map_err(Error::Seccomp)?, vec![])?,
+                compile(uffd_handler_filter().map_err(Error::Seccomp)?, vec![])?,
+            );
+            (
+                vmm_filter.map_err(Error::Seccomp)?,
+                vcpu_filter().map_err(Error::Seccomp)?,
+                Some(helpers),
+            )
+        }
+        (None, default_action) => {
+            // A filter without rules compiles to an empty program, which installs no filter.
+            if policy.vmm.is_empty() || policy.vcpu.is_empty() {
+                return Err(Error::InvalidPolicy(
+                    "policies without base must have vmm and vcpu rules".to_string(),
+                ));
+            }
+            let default_action = default_action.map_or(SeccompAction::Trap, Into::into);
+            let empty = || SeccompFilter::new(BTreeMap::new(), default_action.clone());
+            (
+                empty().map_err(Error::Seccomp)?,
+                empty().map_err(Error::Seccomp)?,
+                None,
+            )
+        }
+    };
+
+    let vmm = compile(vmm, policy.vmm)?;
+    // Without base, the helper threads run under the filter of the VMM thread they help.
+    let (worker, dump_writer, uffd_handler) =
+        helpers.unwrap_or_else(|| (vmm.clone(), vmm.clone(), vmm.clone()));
+    Ok(ThreadFilters {
+        vmm,
+        vcpu: compile(vcpu, policy.vcpu)?,
+        worker,
+        dump_writer,
+        uffd_handler,
+    })
+}
+
+/// Reads the compiled BPF program `bytes`, an array of `struct sock_filter` in the host byte
+/// order.
+pub fn parse_bpf(bytes: &[u8]) -> Result<BpfProgram> {
+    let len = bytes.len() / BPF_INSTRUCTION_SIZE;
+    if bytes.is_empty() || bytes.len() % BPF_INSTRUCTION_SIZE != 0 || len > BPF_MAX_LEN {
+        return Err(Error::InvalidBpf(bytes.len()));
+    }
+    Ok(bytes
+        .chunks_exact(BPF_INSTRUCTION_SIZE)
+        .map(|instruction| sock_filter {
+            code: u16::from_ne_bytes([instruction[0], instruction[1]]),
+            jt: instruction[2],
+            jf: instruction[3],
+            k: u32::from_ne_bytes([
+                instruction[4],
+                instruction[5],
+                instruction[6],
+                instruction[7],
+            ]),
+        })
+        .collect())
+}
+
+/// Reads the seccomp filters of the file at `path`: a JSON policy, starting with `{`, or a
+/// compiled BPF program installed on all threads.
+pub fn load_seccomp_filters(path: &Path) -> Result<ThreadFilters> {
+    let bytes = fs::read(path).map_err(Error::Read)?;
+    let is_json = bytes
+        .iter()
+        .find(|byte| !byte.is_ascii_whitespace())
+        .map_or(false, |byte| *byte == b'{');
+    if is_json {
+        let policy = String::from_utf8(bytes).map_err(|e| Error::InvalidPolicy(e.to_string()))?;
+        return compile_policy(&policy);
+    }
+    let program = parse_bpf(&bytes)?;
+    Ok(ThreadFilters {
+        vmm: program.clone(),
+        vcpu: program.clone(),
+        worker: program.clone(),
+        dump_writer: program.clone(),
+        uffd_handler: program,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use seccomp::SeccompLevel;
+    use utils::tempfile::TempFile;
+
+    use crate::default_syscalls::get_seccomp_filters;
+
+    #[test]
+    fn test_compile_policy() {
+        // io_uring_setup, allowed on top of the default rules.
+        let policy = r#"{
+            "base": "default",
+            "vmm": [{ "syscall": 425 }],
+            "vcpu": [
+                { "syscall": 1, "args": [{ "index": 0, "len": "dword", "op": "eq", "value": 2 }] }
+            ]
+        }"#;
+        let filters = compile_policy(policy).unwrap();
+        let default = get_seccomp_filters(SeccompLevel::Advanced, SeccompProfile::Default).unwrap();
+        assert!(filters.vmm.len() > default.vmm.len());
+        assert!(filters.vcpu.len() > default.vcpu.len());
+        assert_eq!(filters.worker, default.worker);
+        assert_eq!(filters.dump_writer, default.dump_writer);
+        assert_eq!(filters.uffd_handler, default.uffd_handler);
+
+        let policy = r#"{
+            "default_action": { "errno": 1 },
+            "vmm": [
+                { "syscall": 0 },
+                { "syscall": 1, "args": [{ "index": 2, "op": { "masked_eq": 7 }, "value": 1 }] }
+            ],
+            "vcpu": [{ "syscall": 0, "action": "log" }]
+        }"#;
+        let filters = compile_policy(policy).unwrap();
+        assert!(!filters.vmm.is_empty());
+        assert!(!filters.vcpu.is_empty());
+        assert_eq!(filters.worker, filters.vmm);
+        assert_eq!(filters.uffd_handler, filters.vmm);
+
+        for policy in &[
+            r#"{ "base": "faasnap", "default_action": "kill" }"#,
+            r#"{ "base": "strict" }"#,
+            r#"{ "vmm": [{ "syscall": 0 }] }"#,
+            r#"{ "base": "default", "vmm": [{ "syscall": 0, "allow": true }] }"#,
+            "not json",
+        ] {
+            match compile_policy(policy) {
+                Err(Error::InvalidPolicy(_)) => (),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+        let policy = r#"{
+            "base": "default",
+            "vmm": [{ "syscall": 0, "args": [{ "index": 6, "op": "eq", "value": 0 }] }]
+        }"#;
+        match compile_policy(policy) {
+            Err(Error::Seccomp(seccomp::Error::InvalidArgumentNumber)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_parse_bpf() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&0x20u16.to_ne_bytes());
+        bytes.extend_from_slice(&[1, 2]);
+        bytes.extend_from_slice(&4u32.to_ne_bytes());
+        assert_eq!(
+            parse_bpf(&bytes).unwrap(),
+            vec![sock_filter {
+                code: 0x20,
+                jt: 1,
+                jf: 2,
+                k: 4,
+            }]
+        );
+
+        for size in &[0, 7, (BPF_MAX_LEN + 1) * BPF_INSTRUCTION_SIZE] {
+            match parse_bpf(&vec![0u8; *size]) {
+                Err(Error::InvalidBpf(len)) => assert_eq!(len, *size),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_seccomp_filters() {
+        let file = TempFile::new().unwrap();
+        fs::write(file.as_path(), &[0u8; 16]).unwrap();
+        let filters = load_seccomp_filters(file.as_path()).unwrap();
+        assert_eq!(filters.vmm.len(), 2);
+        assert_eq!(filters.vmm, filters.vcpu);
+        assert_eq!(filters.vmm, filters.worker);
+
+        fs::write(file.as_path(), "\n { \"base\": \"default\" }").unwrap();
+        assert_eq!(
+            load_seccomp_filters(file.as_path()).unwrap(),
+            get_seccomp_filters(SeccompLevel::Advanced, SeccompProfile::Default).unwrap()
+        );
+
+        match load_seccomp_filters(Path::new("/invalid/filter")) {
+            Err(Error::Read(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
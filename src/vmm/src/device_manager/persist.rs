@@ -109,7 +109,12 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             net_devices: Vec::new(),
             vsock_device: None,
         };
-        for ((device_type, device_id), device_info) in self.get_device_info().iter() {
+        // The devices are saved in the order of their MMIO addresses rather than in the
+        // iteration order of the map, which differs from one process to another, so that the
+        // same devices always save to the same state.
+        let mut devices: Vec<_> = self.get_device_info().iter().collect();
+        devices.sort_by_key(|(_, device_info)| device_info.addr);
+        for ((device_type, device_id), device_info) in devices {
             let bus_device = self
                 .get_device(*device_type, device_id)
                 // Safe to unwrap() because we know the device exists.
@@ -0,0 +1,481 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Copy of the unmodified guest memory straight from the file backing it, on full dumps.
+//!
+//! Guest memory restored from a memory file is a private mapping of it, so every page the guest
+//! or a device never wrote still holds the content of the file. Such pages are copied from the
+//! file to the new memory file with `copy_file_range`, which file systems like XFS and Btrfs
+//! turn into a reflink, instead of being read through the mapping and written out again.
+//!
+//! The unmodified pages are the ones `/proc/self/maps` reports as mapped from the backing file
+//! at their offset in the region, which leaves out the overlay and WS layers, and that
+//! `/proc/self/pagemap` does not report as anonymous copies.
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+
+use logger::{warn, Metric, METRICS};
+use vm_memory::{FileOffset, GuestMemoryRegion, GuestRegionMmap};
+
+use crate::ws_accounting::{
+    PAGEMAP_BATCH, PAGEMAP_ENTRY_SIZE, PAGEMAP_PATH, PAGEMAP_PRESENT, PAGEMAP_SWAPPED,
+};
+
+const MAPS_PATH: &str = "/proc/self/maps";
+// Set in the pagemap entries of the pages mapped from a file.
+const PAGEMAP_FILE: u64 = 1 << 61;
+
+/// Errors associated with copying the unmodified guest memory.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to copy from the backing file.
+    Copy(io::Error),
+    /// Failed to inspect the backing file.
+    Metadata(io::Error),
+    /// Failed to read the mappings of the process.
+    ReadMaps(io::Error),
+    /// Failed to read the pagemap of the process.
+    ReadPagemap(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Copy(err) => write!(f, "Cannot copy from the backing file: {}", err),
+            Metadata(err) => write!(f, "Cannot inspect the backing file: {}", err),
+            ReadMaps(err) => write!(f, "Cannot read {}: {}", MAPS_PATH, err),
+            ReadPagemap(err) => write!(f, "Cannot read {}: {}", PAGEMAP_PATH, err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+// Mapping of a file, as read from `/proc/self/maps`.
+#[derive(Debug, PartialEq)]
+struct FileMapping {
+    start: u64,
+    end: u64,
+    offset: u64,
+    dev: (u64, u64),
+    ino: u64,
+}
+
+// Parses a `/proc/self/maps` line, leaving out the anonymous mappings.
+fn parse_maps_line(line: &str) -> Option<FileMapping> {
+    let mut fields = line.split_whitespace();
+    let mut range = fields.next()?.split('-');
+    let start = u64::from_str_radix(range.next()?, 16).ok()?;
+    let end = u64::from_str_radix(range.next()?, 16).ok()?;
+    let _perms = fields.next()?;
+    let offset = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let mut dev = fields.next()?.split(':');
+    let major = u64::from_str_radix(dev.next()?, 16).ok()?;
+    let minor = u64::from_str_radix(dev.next()?, 16).ok()?;
+    let ino = fields.next()?.parse().ok()?;
+    if ino == 0 {
+        return None;
+    }
+    Some(FileMapping {
+        start,
+        end,
+        offset,
+        dev: (major, minor),
+        ino,
+    })
+}
+
+/// Splits `dev` into its major and minor numbers, as glibc does.
+pub(crate) fn split_dev(dev: u64) -> (u64, u64) {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & 0xffff_f000);
+    let minor = (dev & 0xff) | ((dev >> 12) & 0xffff_ff00);
+    (major, minor)
+}
+
+// Returns the `[start, end)` offsets, relative to `addr`, of the `len` bytes at `addr` that
+// `maps` reports as mapped from the file `(dev, ino)` at `file_offset` plus their offset.
+fn backed_ranges(
+    maps: &str,
+    addr: u64,
+    len: u64,
+    file_offset: u64,
+    dev: (u64, u64),
+    ino: u64,
+) -> Vec<(u64, u64)> {
+    let end = addr + len;
+    let mut ranges: Vec<(u64, u64)> = maps
+        .lines()
+        .filter_map(parse_maps_line)
+        .filter(|mapping| mapping.dev == dev && mapping.ino == ino)
+        .filter_map(|mapping| {
+            let start = std::cmp::max(mapping.start, addr);
+            let stop = std::cmp::min(mapping.end, end);
+            if start < stop
+                && mapping.offset + (start - mapping.start) == file_offset + start - addr
+            {
+                Some((start - addr, stop - addr))
+            } else {
+                None
+            }
+        })
+        .collect();
+    ranges.sort();
+    ranges
+}
+
+// Removes from the `[start, end)` ranges, relative to `addr`, the pages the pagemap reports as
+// written, which are anonymous copies of the file pages.
+fn unmodified_ranges(
+    pagemap: &File,
+    addr: u64,
+    ranges: &[(u64, u64)],
+    page_size: u64,
+) -> io::Result<Vec<(u64, u64)>> {
+    let mut unmodified: Vec<(u64, u64)> = Vec::new();
+    let mut entries = vec![0u8; (PAGEMAP_BATCH * PAGEMAP_ENTRY_SIZE) as usize];
+    for &(start, end) in ranges {
+        let mut page = start / page_size;
+        let last_page = end / page_size;
+        while page < last_page {
+            let batch = std::cmp::min(PAGEMAP_BATCH, last_page - page);
+            let buf = &mut entries[..(batch * PAGEMAP_ENTRY_SIZE) as usize];
+            pagemap.read_exact_at(buf, (addr / page_size + page) * PAGEMAP_ENTRY_SIZE)?;
+            for (index, entry) in buf.chunks(PAGEMAP_ENTRY_SIZE as usize).enumerate() {
+                let mut bytes = [0u8; PAGEMAP_ENTRY_SIZE as usize];
+                bytes.copy_from_slice(entry);
+                let entry = u64::from_ne_bytes(bytes);
+                let written = entry & PAGEMAP_SWAPPED != 0
+                    || (entry & PAGEMAP_PRESENT != 0 && entry & PAGEMAP_FILE == 0);
+                if written {
+                    continue;
+                }
+                let offset = (page + index as u64) * page_size;
+                match unmodified.last_mut() {
+                    Some(last) if last.1 == offset => last.1 += page_size,
+                    _ => unmodified.push((offset, offset + page_size)),
+                }
+            }
+            page += batch;
+        }
+    }
+    Ok(unmodified)
+}
+
+// Removes the sorted `[start, end)` ranges of `holes` from the sorted `ranges`.
+fn subtract_ranges(ranges: &[(u64, u64)], holes: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut result = Vec::new();
+    for &(start, end) in ranges {
+        let mut cur = start;
+        for &(hole_start, hole_end) in holes {
+            if hole_end <= cur || hole_start >= end {
+                continue;
+            }
+            if hole_start > cur {
+                result.push((cur, hole_start));
+            }
+            cur = std::cmp::max(cur, hole_end);
+        }
+        if cur < end {
+            result.push((cur, end));
+        }
+    }
+    result
+}
+
+// Copies `len` bytes at `offset_in` of `from` to `offset_out` of `to`.
+fn copy_range(from: &File, offset_in: u64, to: &File, offset_out: u64, len: u64) -> Result<()> {
+    let mut off_in = offset_in as libc::loff_t;
+    let mut off_out = offset_out as libc::loff_t;
+    let mut remaining = len as usize;
+    while remaining > 0 {
+        // Safe because the offsets are valid for the duration of the call.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_copy_file_range,
+                from.as_raw_fd(),
+                &mut off_in as *mut libc::loff_t,
+                to.as_raw_fd(),
+                &mut off_out as *mut libc::loff_t,
+                remaining,
+                0,
+            )
+        };
+        match ret {
+            -1 => return Err(Error::Copy(io::Error::last_os_error())),
+            0 => return Err(Error::Copy(io::Error::from(io::ErrorKind::UnexpectedEof))),
+            copied => remaining -= copied as usize,
+        }
+    }
+    Ok(())
+}
+
+/// Sends `len` bytes at `offset` of `from` to `to`, which may be a pipe or a socket. On failure,
+/// returns the number of bytes sent along with the error.
+pub fn send_range(
+    from: &File,
+    offset: u64,
+    to: &File,
+    len: u64,
+) -> std::result::Result<(), (u64, Error)> {
+    let mut off_in = offset as libc::off_t;
+    let mut sent = 0;
+    while sent < len {
+        // Safe because the offset is valid for the duration of the call.
+        let ret = unsafe {
+            libc::sendfile(
+                to.as_raw_fd(),
+                from.as_raw_fd(),
+                &mut off_in,
+                (len - sent) as usize,
+            )
+        };
+        match ret {
+            -1 => return Err((sent, Error::Copy(io::Error::last_os_error()))),
+            0 => {
+                let err = io::Error::from(io::ErrorKind::UnexpectedEof);
+                return Err((sent, Error::Copy(err)));
+            }
+            count => sent += count as u64,
+        }
+    }
+    METRICS.snapshot.bytes_copied.add(len as usize);
+    Ok(())
+}
+
+// Returns the `[start, end)` offsets in `region`, backed by `backing`, of the unmodified pages.
+fn find_unmodified_pages(
+    region: &GuestRegionMmap,
+    backing: &FileOffset,
+) -> Result<Vec<(u64, u64)>> {
+    let page_size = sysconf::page::pagesize() as u64;
+    let metadata = backing.file().metadata().map_err(Error::Metadata)?;
+    let maps = std::fs::read_to_string(MAPS_PATH).map_err(Error::ReadMaps)?;
+    let addr = region.as_ptr() as u64;
+    let ranges = backed_ranges(
+        &maps,
+        addr,
+        region.len(),
+        backing.start(),
+        split_dev(metadata.dev()),
+        metadata.ino(),
+    );
+    let pagemap = File::open(PAGEMAP_PATH).map_err(Error::ReadPagemap)?;
+    unmodified_ranges(&pagemap, addr, &ranges, page_size).map_err(Error::ReadPagemap)
+}
+
+/// Returns the file backing `region` and the `[start, end)` offsets, sorted by start, of the
+/// unmodified pages of the region, but for the pages overlapping the `[start, end)` offsets
+/// in `skip`. Returns `None` if the region has no backing file or cannot be inspected.
+pub fn unmodified_pages<'a>(
+    region: &'a GuestRegionMmap,
+    skip: &[(u64, u64)],
+) -> Option<(&'a FileOffset, Vec<(u64, u64)>)> {
+    let backing = region.file_offset()?;
+    // The skipped pages are dumped whole through the mapping.
+    let page_size = sysconf::page::pagesize() as u64;
+    let skip: Vec<(u64, u64)> = skip
+        .iter()
+        .map(|&(start, end)| {
+            (
+                start / page_size * page_size,
+                (end + page_size - 1) / page_size * page_size,
+            )
+        })
+        .collect();
+    match find_unmodified_pages(region, backing) {
+        Ok(ranges) => Some((backing, subtract_ranges(&ranges, &skip))),
+        Err(e) => {
+            warn!("Cannot find the unmodified guest memory: {}", e);
+            None
+        }
+    }
+}
+
+/// Copies the unmodified pages of `region`, but for the `[start, end)` offsets in `skip`, from
+/// the file backing the region to `out`, at `out_offset` plus their offset in the region.
+/// Returns the `[start, end)` offsets of the copied pages, sorted by start.
+///
+/// Copying stops at the first failure, such as `out` not supporting copies from the backing
+/// file, and the pages left are to be dumped through the mapping.
+pub fn copy_unmodified_pages(
+    region: &GuestRegionMmap,
+    out: &File,
+    out_offset: u64,
+    skip: &[(u64, u64)],
+) -> Vec<(u64, u64)> {
+    let (backing, ranges) = match unmodified_pages(region, skip) {
+        Some(pages) => pages,
+        None => return Vec::new(),
+    };
+
+    let mut copied = Vec::new();
+    for (start, end) in ranges {
+        let res = copy_range(
+            backing.file(),
+            backing.start() + start,
+            out,
+            out_offset + start,
+            end - start,
+        );
+        if let Err(e) = res {
+            warn!(
+                "Dumping the unmodified guest memory through the mapping: {}",
+                e
+            );
+            break;
+        }
+        METRICS.snapshot.bytes_copied.add((end - start) as usize);
+        copied.push((start, end));
+    }
+    copied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    use utils::tempfile::TempFile;
+    use vm_memory::{Bytes, GuestAddress, MemoryRegionAddress, MmapRegion};
+
+    #[test]
+    fn test_parse_maps_line() {
+        assert_eq!(
+            parse_maps_line(
+                "7f2c3a000000-7f2c3a400000 rw-p 00200000 fd:01 1234    /srv/snapshot/mem"
+            ),
+            Some(FileMapping {
+                start: 0x7f2c_3a00_0000,
+                end: 0x7f2c_3a40_0000,
+                offset: 0x20_0000,
+                dev: (0xfd, 0x01),
+                ino: 1234,
+            })
+        );
+        assert_eq!(
+            parse_maps_line("7f2c3a000000-7f2c3a400000 rw-p 00000000 00:00 0 "),
+            None
+        );
+        assert_eq!(parse_maps_line("garbage"), None);
+    }
+
+    #[test]
+    fn test_backed_ranges() {
+        let maps = "\
+            1000-5000 rw-p 00010000 fd:01 7 /mem\n\
+            5000-6000 rw-p 00000000 fd:01 8 /overlay\n\
+            6000-8000 rw-p 00015000 fd:01 7 /mem\n\
+            8000-9000 rw-p 00000000 fd:01 7 /mem\n\
+            9000-a000 rw-p 00000000 00:00 0\n";
+        // The mapping at 0x8000 maps the file at the wrong offset, the one at 0x9000 is anonymous.
+        assert_eq!(
+            backed_ranges(maps, 0x2000, 0x8000, 0x11000, (0xfd, 0x01), 7),
+            vec![(0, 0x3000), (0x4000, 0x6000)]
+        );
+        assert!(backed_ranges(maps, 0x2000, 0x8000, 0x11000, (0xfd, 0x02), 7).is_empty());
+    }
+
+    #[test]
+    fn test_subtract_ranges() {
+        assert_eq!(
+            subtract_ranges(&[(0, 10), (20, 30)], &[(2, 4), (8, 22), (28, 40)]),
+            vec![(0, 2), (4, 8), (22, 28)]
+        );
+        assert_eq!(subtract_ranges(&[(0, 10)], &[]), vec![(0, 10)]);
+    }
+
+    #[test]
+    fn test_split_dev() {
+        assert_eq!(split_dev(0xfd01), (0xfd, 0x01));
+        assert_eq!(split_dev(0x1231_0345), (0x103, 0x12345));
+    }
+
+    #[test]
+    fn test_copy_unmodified_pages() {
+        let page_size = sysconf::page::pagesize();
+        let mut backing = TempFile::new().unwrap().into_file();
+        let content: Vec<u8> = (0..4 * page_size).map(|i| (i % 253) as u8).collect();
+        backing.write_all(&content).unwrap();
+        let region = GuestRegionMmap::new(
+            MmapRegion::build(
+                Some(FileOffset::new(backing, 0)),
+                4 * page_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_NORESERVE | libc::MAP_PRIVATE,
+            )
+            .unwrap(),
+            GuestAddress(0),
+        )
+        .unwrap();
+        // Fault one page in from the file and write another one.
+        let mut byte = [0u8; 1];
+        region
+            .read_slice(&mut byte, MemoryRegionAddress(0))
+            .unwrap();
+        region
+            .write_slice(&[0xff; 16], MemoryRegionAddress(page_size as u64))
+            .unwrap();
+
+        let mut out = TempFile::new().unwrap().into_file();
+        out.set_len(4 * page_size as u64).unwrap();
+        let page_size = page_size as u64;
+        let copied = copy_unmodified_pages(&region, &out, 0, &[(3 * page_size, 3 * page_size + 1)]);
+        // The written page and the skipped one are left out.
+        assert_eq!(copied, vec![(0, page_size), (2 * page_size, 3 * page_size)]);
+
+        let mut dumped = Vec::new();
+        out.seek(SeekFrom::Start(0)).unwrap();
+        out.read_to_end(&mut dumped).unwrap();
+        let page_size = page_size as usize;
+        assert_eq!(&dumped[..page_size], &content[..page_size]);
+        assert_eq!(
+            &dumped[2 * page_size..3 * page_size],
+            &content[2 * page_size..3 * page_size]
+        );
+        assert!(dumped[page_size..2 * page_size].iter().all(|&b| b == 0));
+
+        // Anonymous memory has no backing file.
+        let anon =
+            GuestRegionMmap::new(MmapRegion::new(page_size).unwrap(), GuestAddress(0)).unwrap();
+        assert!(copy_unmodified_pages(&anon, &out, 0, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_send_range() {
+        let mut from = TempFile::new().unwrap().into_file();
+        from.write_all(b"0123456789").unwrap();
+        let (mut reader, writer) = std::os::unix::net::UnixStream::pair().unwrap();
+        let to: File = unsafe { File::from_raw_fd(writer.into_raw_fd()) };
+
+        send_range(&from, 2, &to, 5).unwrap();
+        let mut sent = [0u8; 5];
+        reader.read_exact(&mut sent).unwrap();
+        assert_eq!(&sent, b"23456");
+
+        // Past the end of the file.
+        match send_range(&from, 8, &to, 5) {
+            Err((2, Error::Copy(_))) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_error_display() {
+        let errors = vec![
+            Error::Copy(io::Error::from_raw_os_error(libc::EXDEV)),
+            Error::Metadata(io::Error::from_raw_os_error(libc::EBADF)),
+            Error::ReadMaps(io::Error::from_raw_os_error(libc::ENOENT)),
+            Error::ReadPagemap(io::Error::from_raw_os_error(libc::EPERM)),
+        ];
+        for err in errors {
+            let _ = format!("{}{:?}", err, err);
+        }
+    }
+}
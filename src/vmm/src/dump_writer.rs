@@ -0,0 +1,325 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Writer thread the guest memory dumps are pipelined through.
+//!
+//! Dumping the guest memory copies every page to the memory file while the microVM is paused,
+//! so the pause lasts as long as the copies and the writes together. With the dump writer
+//! started, the VMM thread copies the pages to a staging buffer while the writer thread writes
+//! the previous one to the file, and the pause lasts about as long as the slowest of the two.
+//!
+//! The VMM thread cannot spawn threads once its seccomp filter is installed, so the writer
+//! thread is started while building the microVM, and installs the same filter.
+
+use std::cmp::min;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use lazy_static::lazy_static;
+use seccomp::{BpfProgram, SeccompFilter};
+
+/// Size of each staging buffer.
+pub const BUFFER_SIZE: usize = 8 << 20;
+// One buffer is filled by the VMM thread while the other is written by the writer thread.
+const BUFFER_COUNT: usize = 2;
+
+lazy_static! {
+    /// Dump writer of the process. Dumps are not pipelined until it is started.
+    pub static ref DUMP_WRITER: DumpWriter = DumpWriter::default();
+}
+
+/// Errors associated with the dump writer.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to install the seccomp filter of the writer thread.
+    Seccomp(seccomp::Error),
+    /// Failed to spawn the writer thread.
+    Spawn(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Seccomp(err) => write!(f, "Cannot filter the writer thread syscalls: {}", err),
+            Spawn(err) => write!(f, "Cannot spawn the writer thread: {}", err),
+        }
+    }
+}
+
+// A staging buffer to write at `offset` of `file`.
+struct Job {
+    file: Arc<File>,
+    offset: u64,
+    buf: Vec<u8>,
+}
+
+// The buffer of a job, handed back with the outcome of its write.
+type Done = (Vec<u8>, io::Result<()>);
+
+struct Worker {
+    jobs: Sender<Job>,
+    done: Receiver<Done>,
+}
+
+/// Thread writing the staging buffers of the guest memory dumps.
+#[derive(Default)]
+pub struct DumpWriter {
+    worker: Mutex<Option<Worker>>,
+}
+
+impl DumpWriter {
+    /// Starts the writer thread, which runs under `seccomp_filter`. Does nothing if the thread
+    /// is already started.
+    pub fn start(&self, seccomp_filter: BpfProgram) -> Result<(), Error> {
+        let mut worker = self.worker.lock().expect("Poisoned lock");
+        if worker.is_some() {
+            return Ok(());
+        }
+        let (jobs, job_receiver) = channel::<Job>();
+        let (done_sender, done) = channel();
+        let (ready_sender, ready) = channel();
+        thread::Builder::new()
+            .name("fc_dump_writer".to_string())
+            .spawn(move || {
+                let filtered = SeccompFilter::apply(seccomp_filter);
+                let failed = filtered.is_err();
+                let _ = ready_sender.send(filtered);
+                if failed {
+                    return;
+                }
+                for job in job_receiver.iter() {
+                    let res = job.file.write_all_at(&job.buf, job.offset);
+                    if done_sender.send((job.buf, res)).is_err() {
+                        break;
+                    }
+                }
+            })
+            .map_err(Error::Spawn)?;
+        ready
+            .recv()
+            .expect("The dump writer thread exited")
+            .map_err(Error::Seccomp)?;
+        *worker = Some(Worker { jobs, done });
+        Ok(())
+    }
+
+    /// Returns a writer to `file` pipelined through the writer thread, or `None` if the thread
+    /// is not started. Dumps are pipelined one at a time.
+    pub fn pipeline(&self, file: &File) -> io::Result<Option<Pipeline>> {
+        let worker = self.worker.lock().expect("Poisoned lock");
+        if worker.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Pipeline {
+            worker,
+            file: Arc::new(file.try_clone()?),
+            buf: Vec::with_capacity(BUFFER_SIZE),
+            buf_offset: 0,
+            free: (1..BUFFER_COUNT)
+                .map(|_| Vec::with_capacity(BUFFER_SIZE))
+                .collect(),
+            in_flight: 0,
+        }))
+    }
+}
+
+/// Writer to a file, which hands its data over to the writer thread in staging buffers.
+///
+/// The data is only known to be written once `flush` succeeds.
+pub struct Pipeline<'a> {
+    worker: MutexGuard<'a, Option<Worker>>,
+    file: Arc<File>,
+    // Staging buffer being filled, and its offset in the file.
+    buf: Vec<u8>,
+    buf_offset: u64,
+    free: Vec<Vec<u8>>,
+    in_flight: usize,
+}
+
+impl<'a> Pipeline<'a> {
+    fn worker(&self) -> &Worker {
+        self.worker
+            .as_ref()
+            .expect("Pipeline without a writer thread")
+    }
+
+    // Waits for the writer thread to hand a buffer back.
+    fn reclaim(&mut self) -> io::Result<Vec<u8>> {
+        let (mut buf, res) = match self.worker().done.recv() {
+            Ok(done) => done,
+            Err(_) => {
+                self.in_flight = 0;
+                return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+            }
+        };
+        self.in_flight -= 1;
+        res?;
+        buf.clear();
+        Ok(buf)
+    }
+
+    // Hands the staging buffer over to the writer thread, and takes a free one in its place.
+    fn submit(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let next = match self.free.pop() {
+            Some(buf) => buf,
+            None => self.reclaim()?,
+        };
+        let buf = mem::replace(&mut self.buf, next);
+        let offset = self.buf_offset;
+        self.buf_offset += buf.len() as u64;
+        let job = Job {
+            file: self.file.clone(),
+            offset,
+            buf,
+        };
+        self.worker()
+            .jobs
+            .send(job)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        self.in_flight += 1;
+        Ok(())
+    }
+}
+
+impl<'a> Write for Pipeline<'a> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() == BUFFER_SIZE {
+            self.submit()?;
+        }
+        let len = min(data.len(), BUFFER_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    /// Waits for the writer thread to write all the data.
+    fn flush(&mut self) -> io::Result<()> {
+        self.submit()?;
+        while self.in_flight > 0 {
+            let buf = self.reclaim()?;
+            self.free.push(buf);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Seek for Pipeline<'a> {
+    /// Only seeks from the start of the file are supported.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = self.buf_offset + self.buf.len() as u64;
+        let offset = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(0) => return Ok(position),
+            _ => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        };
+        if offset != position {
+            self.submit()?;
+            self.buf_offset = offset;
+        }
+        Ok(offset)
+    }
+}
+
+impl<'a> Drop for Pipeline<'a> {
+    fn drop(&mut self) {
+        // The buffers in flight must be handed back before the next dump uses the thread.
+        while self.in_flight > 0 {
+            let _ = self.reclaim();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    use utils::tempfile::TempFile;
+
+    fn read_file(file: &File) -> Vec<u8> {
+        let mut content = Vec::new();
+        let mut file = file.try_clone().unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let writer = DumpWriter::default();
+        let file = TempFile::new().unwrap();
+        assert!(writer.pipeline(file.as_file()).unwrap().is_none());
+
+        writer.start(Vec::new()).unwrap();
+        // Starting again keeps the same thread.
+        writer.start(Vec::new()).unwrap();
+
+        // Enough data to go through every buffer more than once, around a hole.
+        let data: Vec<u8> = (0..3 * BUFFER_SIZE).map(|i| (i % 251) as u8).collect();
+        let hole = 4096;
+        {
+            let mut pipeline = writer.pipeline(file.as_file()).unwrap().unwrap();
+            pipeline.write_all(&data[..BUFFER_SIZE + 10]).unwrap();
+            assert_eq!(
+                pipeline.seek(SeekFrom::Current(0)).unwrap(),
+                BUFFER_SIZE as u64 + 10
+            );
+            pipeline
+                .seek(SeekFrom::Start((BUFFER_SIZE + 10 + hole) as u64))
+                .unwrap();
+            pipeline.write_all(&data[BUFFER_SIZE + 10..]).unwrap();
+            assert!(pipeline.seek(SeekFrom::End(0)).is_err());
+            pipeline.flush().unwrap();
+        }
+
+        let content = read_file(file.as_file());
+        assert_eq!(content.len(), data.len() + hole);
+        assert_eq!(&content[..BUFFER_SIZE + 10], &data[..BUFFER_SIZE + 10]);
+        assert!(content[BUFFER_SIZE + 10..BUFFER_SIZE + 10 + hole]
+            .iter()
+            .all(|&byte| byte == 0));
+        assert_eq!(
+            &content[BUFFER_SIZE + 10 + hole..],
+            &data[BUFFER_SIZE + 10..]
+        );
+
+        // The thread serves the next dump.
+        let file = TempFile::new().unwrap();
+        {
+            let mut pipeline = writer.pipeline(file.as_file()).unwrap().unwrap();
+            pipeline.write_all(b"next").unwrap();
+            pipeline.flush().unwrap();
+        }
+        assert_eq!(read_file(file.as_file()), b"next");
+    }
+
+    #[test]
+    fn test_pipeline_write_error() {
+        let writer = DumpWriter::default();
+        writer.start(Vec::new()).unwrap();
+        let file = TempFile::new().unwrap();
+        // A read-only handle fails the writes.
+        let read_only = File::open(file.as_path()).unwrap();
+        let mut pipeline = writer.pipeline(&read_only).unwrap().unwrap();
+        pipeline.write_all(&[1u8; 4096]).unwrap();
+        assert!(pipeline.flush().is_err());
+    }
+
+    #[test]
+    fn test_error_display() {
+        let err = Error::Spawn(io::Error::from_raw_os_error(libc::EAGAIN));
+        let _ = format!("{}{:?}", err, err);
+        let err = Error::Seccomp(seccomp::Error::EmptyRulesVector);
+        let _ = format!("{}{:?}", err, err);
+    }
+}
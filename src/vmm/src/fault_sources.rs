@@ -0,0 +1,255 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Attribution of the guest page faults following a snapshot restore to the memory layers.
+//!
+//! The overlay extents are meant to hold the pages dirtied at snapshot time and the working set
+//! extents the pages touched after a restore, so the faults that fall through to the memory file
+//! are the ones neither layer captured. The faults the VMM serves itself, from the memory file
+//! with a fault trace or a protected base, are counted as they are served. The other faults are
+//! served by the kernel from the mapped files, so they are sampled instead: the pages mapped in
+//! the process are read periodically from `/proc/self/pagemap`, and the pages mapped since the
+//! previous sample are attributed to the layer backing them. Pages mapped and evicted between two
+//! samples are missed, so the sampled counts are a lower bound.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use logger::{error, warn, Metric, SharedMetric, METRICS};
+use polly::event_manager::{EventManager, Subscriber};
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
+use utils::epoll::{EpollEvent, EventSet};
+use vm_memory::GuestMemoryMmap;
+
+use crate::backing_files::BACKING_FILES;
+use crate::memory_snapshot::GuestMemoryState;
+use crate::probes::MmapLayer;
+use crate::ws_accounting::{self, PAGEMAP_PATH};
+
+/// Period of the fault attribution samples.
+pub const FAULT_SAMPLE_PERIOD_MS: u64 = 10000;
+
+/// Layer a guest page fault is attributed to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultSource {
+    /// The memory file.
+    Base,
+    /// An overlay extent.
+    Overlay,
+    /// A working set extent.
+    WorkingSet,
+    /// Guest memory backed by no snapshot file.
+    Anonymous,
+}
+
+impl From<Option<MmapLayer>> for FaultSource {
+    fn from(layer: Option<MmapLayer>) -> Self {
+        match layer {
+            Some(MmapLayer::Base) => FaultSource::Base,
+            Some(MmapLayer::Overlay) => FaultSource::Overlay,
+            Some(MmapLayer::WorkingSet) => FaultSource::WorkingSet,
+            None => FaultSource::Anonymous,
+        }
+    }
+}
+
+impl FaultSource {
+    const ALL: [FaultSource; 4] = [
+        FaultSource::Base,
+        FaultSource::Overlay,
+        FaultSource::WorkingSet,
+        FaultSource::Anonymous,
+    ];
+
+    fn metrics(self) -> (&'static SharedMetric, &'static SharedMetric) {
+        let metrics = &METRICS.fault_sources;
+        match self {
+            FaultSource::Base => (&metrics.base_faults, &metrics.base_bytes),
+            FaultSource::Overlay => (&metrics.overlay_faults, &metrics.overlay_bytes),
+            FaultSource::WorkingSet => (&metrics.ws_faults, &metrics.ws_bytes),
+            FaultSource::Anonymous => (&metrics.anon_faults, &metrics.anon_bytes),
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Counts `pages` faults of `page_size` bytes attributed to `source`.
+pub fn record(source: FaultSource, pages: u64, page_size: u64) {
+    let (faults, bytes) = source.metrics();
+    faults.add(pages as usize);
+    bytes.add((pages * page_size) as usize);
+}
+
+/// Samples the guest pages faulted in, attributing them to the layers backing them.
+pub struct FaultSampler {
+    pagemap: File,
+    page_size: u64,
+    // Host ranges of the guest memory, as (address, length, source), each backed by one layer.
+    ranges: Vec<(u64, u64, FaultSource)>,
+    // Pages mapped at the previous sample, indexed by source.
+    mapped: Option<[u64; 4]>,
+    timer: TimerFd,
+}
+
+impl FaultSampler {
+    /// Samples the faults on `guest_memory`, restored from `state`, except the ones attributed to
+    /// the `served` sources, which are counted as they are served.
+    pub fn new(
+        guest_memory: &GuestMemoryMmap,
+        state: &GuestMemoryState,
+        served: &[FaultSource],
+    ) -> io::Result<Self> {
+        let guest_ranges = ws_accounting::guest_host_ranges(guest_memory, state)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let backing = BACKING_FILES.lock().expect("Poisoned lock");
+        let ranges = guest_ranges
+            .iter()
+            .flat_map(|&(addr, len)| backing.layer_segments(addr as usize, len as usize))
+            .map(|(addr, len, layer)| (addr as u64, len as u64, FaultSource::from(layer)))
+            .filter(|(_, _, source)| !served.contains(source))
+            .collect();
+        drop(backing);
+
+        Ok(FaultSampler {
+            // Opened now, the VMM may not be allowed to open it once sandboxed.
+            pagemap: File::open(PAGEMAP_PATH)?,
+            page_size: sysconf::page::pagesize() as u64,
+            ranges,
+            mapped: None,
+            timer: TimerFd::new_custom(ClockId::Monotonic, true, true)?,
+        })
+    }
+
+    /// Starts sampling periodically, beginning with a sample straight away, which the following
+    /// ones are compared to.
+    pub fn start(&mut self) {
+        let period = Duration::from_millis(FAULT_SAMPLE_PERIOD_MS);
+        self.timer.set_state(
+            TimerState::Periodic {
+                current: period,
+                interval: period,
+            },
+            SetTimeFlags::Default,
+        );
+        if let Err(err) = self.sample() {
+            error!("Cannot sample the guest page faults: {}", err);
+        }
+    }
+
+    /// Counts the pages mapped since the previous sample, and returns them by source.
+    pub fn sample(&mut self) -> io::Result<[u64; 4]> {
+        let mut mapped = [0u64; 4];
+        for (addr, len, source) in self.ranges.iter() {
+            mapped[source.index()] +=
+                ws_accounting::count_mapped_pages(&self.pagemap, self.page_size, *addr, *len)?;
+        }
+        // The first sample is the baseline, and pages the kernel reclaimed are not faults.
+        let previous = self.mapped.replace(mapped).unwrap_or(mapped);
+        let mut faults = [0u64; 4];
+        for source in FaultSource::ALL.iter() {
+            let index = source.index();
+            faults[index] = mapped[index].saturating_sub(previous[index]);
+        }
+        Ok(faults)
+    }
+}
+
+impl Subscriber for FaultSampler {
+    /// Handle a read event (EPOLLIN).
+    fn process(&mut self, event: &EpollEvent, _: &mut EventManager) {
+        let source = event.fd();
+        let event_set = event.event_set();
+
+        if !EventSet::IN.contains(event_set) {
+            warn!(
+                "Received unknown event: {:?} from source: {:?}",
+                event_set, source
+            );
+            return;
+        }
+
+        if source == self.timer.as_raw_fd() {
+            // Consume the timer expirations.
+            self.timer.read();
+            match self.sample() {
+                Ok(faults) => {
+                    for source in FaultSource::ALL.iter() {
+                        record(*source, faults[source.index()], self.page_size);
+                    }
+                }
+                Err(err) => error!("Cannot sample the guest page faults: {}", err),
+            }
+        } else {
+            error!("Spurious EventManager event for handler: FaultSampler");
+        }
+    }
+
+    fn interest_list(&self) -> Vec<EpollEvent> {
+        vec![EpollEvent::new(EventSet::IN, self.timer.as_raw_fd() as u64)]
+    }
+}
+
+/// Attributes the faults sampled by `sampler` in the metrics from now on.
+pub fn start(event_manager: &mut EventManager, mut sampler: FaultSampler) {
+    sampler.start();
+    if let Err(err) = event_manager.add_subscriber(Arc::new(Mutex::new(sampler))) {
+        error!("Cannot register the fault sampler: {:?}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::{GuestAddress, GuestMemory};
+
+    use crate::memory_snapshot::GuestMemoryRegionState;
+
+    #[test]
+    fn test_record() {
+        let faults = METRICS.fault_sources.overlay_faults.count();
+        let bytes = METRICS.fault_sources.overlay_bytes.count();
+        record(FaultSource::Overlay, 3, 4096);
+        // Other tests may record faults concurrently.
+        assert!(METRICS.fault_sources.overlay_faults.count() >= faults + 3);
+        assert!(METRICS.fault_sources.overlay_bytes.count() >= bytes + 3 * 4096);
+    }
+
+    #[test]
+    fn test_sample() {
+        let page_size = sysconf::page::pagesize();
+        let guest_memory =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 4 * page_size)]).unwrap();
+        let state = GuestMemoryState {
+            regions: vec![GuestMemoryRegionState {
+                base_address: 0,
+                size: 4 * page_size,
+                offset: 0,
+            }],
+            page_size,
+        };
+        let mut sampler = FaultSampler::new(&guest_memory, &state, &[]).unwrap();
+        let host_addr = guest_memory.get_host_address(GuestAddress(0)).unwrap();
+        unsafe { *host_addr = 1 };
+        assert_eq!(sampler.sample().unwrap(), [0; 4]);
+
+        // The layers depend on the snapshot files other tests restored, only count the faults.
+        unsafe {
+            *host_addr.add(page_size) = 1;
+            *host_addr.add(3 * page_size) = 1;
+        }
+        assert_eq!(sampler.sample().unwrap().iter().sum::<u64>(), 2);
+        assert_eq!(sampler.sample().unwrap(), [0; 4]);
+
+        // Served faults are not sampled.
+        let mut sampler = FaultSampler::new(&guest_memory, &state, &FaultSource::ALL).unwrap();
+        assert!(sampler.ranges.is_empty());
+        assert_eq!(sampler.sample().unwrap(), [0; 4]);
+    }
+}
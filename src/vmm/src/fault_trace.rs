@@ -0,0 +1,512 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Trace of the guest page faults following a snapshot restore.
+//!
+//! When recording, the guest memory is backed by anonymous memory registered with a userfaultfd
+//! owned by the `fc_fault_trace` thread. The thread services every fault by copying the page from
+//! the memory file, and appends a record of the fault to the trace file. Offline tooling builds
+//! working set files from these traces. The thread holds on to the guest memory, and is stopped
+//! and joined when its `FaultTrace` handle is dropped, along with the microVM or on a failed
+//! restore.
+//!
+//! The trace starts with a header, followed by one record per fault, all fields little endian:
+//!
+//! | Header field  | Size | Description                    |
+//! |---------------|------|--------------------------------|
+//! | magic         | 4    | `FCFT`                         |
+//! | version       | 4    | Trace format version, 1        |
+//! | page size     | 4    | Host page size, in bytes       |
+//! | reserved      | 4    | Zero                           |
+//!
+//! | Record field  | Size | Description                                                   |
+//! |---------------|------|---------------------------------------------------------------|
+//! | timestamp     | 4    | Microseconds since the trace started                          |
+//! | vCPU id       | 2    | Index of the faulting vCPU, `0xffff` for the other threads    |
+//! | flags         | 2    | Bit 0 is set for write faults                                 |
+//! | guest address | 8    | Guest physical address of the faulting page                   |
+//! | file offset   | 8    | Offset of the faulting page in the memory file                |
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::thread::{self, JoinHandle};
+
+use logger::error;
+use seccomp::{BpfProgram, SeccompFilter};
+use userfaultfd::{Event, FeatureFlags, ReadWrite, Uffd, UffdBuilder};
+use utils::eventfd::EventFd;
+use utils::time::{get_time_ns, get_time_us, ClockType};
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
+
+use crate::fault_sources::{self, FaultSource};
+use crate::memory_snapshot::GuestMemoryState;
+use crate::probes;
+
+const TRACE_MAGIC: &[u8; 4] = b"FCFT";
+const TRACE_VERSION: u32 = 1;
+/// Size of the trace header, in bytes.
+pub const HEADER_SIZE: usize = 16;
+/// Size of a fault record, in bytes.
+pub const RECORD_SIZE: usize = 24;
+/// vCPU id of the faults raised by other threads, such as device emulation in the VMM thread.
+pub const NO_VCPU: u16 = 0xffff;
+const FLAG_WRITE: u16 = 1 << 0;
+const VCPU_THREAD_PREFIX: &str = "fc_vcpu ";
+
+/// Errors associated with the fault trace.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create the userfaultfd.
+    CreateUffd(userfaultfd::Error),
+    /// Failed to create the event stopping the fault trace thread.
+    EventFd(io::Error),
+    /// The memory file is required to service the faults.
+    MissingMemoryFile,
+    /// Failed to open the fault trace file.
+    Open(PathBuf, io::Error),
+    /// Failed to register the guest memory with the userfaultfd.
+    Register(userfaultfd::Error),
+    /// Failed to install the seccomp filter of the fault trace thread.
+    Seccomp(seccomp::Error),
+    /// Failed to spawn the fault trace thread.
+    Spawn(io::Error),
+    /// Faults are serviced from the memory file only, they cannot be recorded along with a page
+    /// fault handler, overlay or working set layers.
+    UnsupportedLayers,
+    /// Failed to write the trace header.
+    Write(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            CreateUffd(err) => write!(f, "Cannot create the fault trace userfaultfd: {}", err),
+            EventFd(err) => write!(f, "Cannot create the fault trace stop event: {}", err),
+            MissingMemoryFile => write!(f, "Recording faults requires the memory file"),
+            Open(path, err) => write!(f, "Cannot open fault trace {}: {}", path.display(), err),
+            Register(err) => write!(f, "Cannot register guest memory for tracing: {}", err),
+            Seccomp(err) => write!(f, "Cannot filter the fault trace thread syscalls: {}", err),
+            Spawn(err) => write!(f, "Cannot spawn the fault trace thread: {}", err),
+            UnsupportedLayers => write!(
+                f,
+                "Recording faults is incompatible with user page faults, overlay and working set \
+                 layers"
+            ),
+            Write(err) => write!(f, "Cannot write the fault trace header: {}", err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A guest page fault.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FaultRecord {
+    /// Microseconds since the trace started.
+    pub timestamp_us: u32,
+    /// Index of the faulting vCPU, `NO_VCPU` for the other threads.
+    pub vcpu_id: u16,
+    /// Whether the fault is on a write.
+    pub write: bool,
+    /// Guest physical address of the faulting page.
+    pub guest_addr: u64,
+    /// Offset of the faulting page in the memory file.
+    pub file_offset: u64,
+}
+
+impl FaultRecord {
+    /// Encodes the record in the trace format.
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let flags = if self.write { FLAG_WRITE } else { 0 };
+        let mut bytes = [0u8; RECORD_SIZE];
+        bytes[0..4].copy_from_slice(&self.timestamp_us.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.vcpu_id.to_le_bytes());
+        bytes[6..8].copy_from_slice(&flags.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.guest_addr.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.file_offset.to_le_bytes());
+        bytes
+    }
+
+    /// Decodes a record from the trace format.
+    pub fn from_bytes(bytes: &[u8; RECORD_SIZE]) -> Self {
+        let mut u16_bytes = [0u8; 2];
+        let mut u32_bytes = [0u8; 4];
+        let mut u64_bytes = [0u8; 8];
+
+        u32_bytes.copy_from_slice(&bytes[0..4]);
+        let timestamp_us = u32::from_le_bytes(u32_bytes);
+        u16_bytes.copy_from_slice(&bytes[4..6]);
+        let vcpu_id = u16::from_le_bytes(u16_bytes);
+        u16_bytes.copy_from_slice(&bytes[6..8]);
+        let flags = u16::from_le_bytes(u16_bytes);
+        u64_bytes.copy_from_slice(&bytes[8..16]);
+        let guest_addr = u64::from_le_bytes(u64_bytes);
+        u64_bytes.copy_from_slice(&bytes[16..24]);
+        let file_offset = u64::from_le_bytes(u64_bytes);
+
+        FaultRecord {
+            timestamp_us,
+            vcpu_id,
+            write: flags & FLAG_WRITE != 0,
+            guest_addr,
+            file_offset,
+        }
+    }
+}
+
+/// Encodes the header of a trace of pages of `page_size` bytes.
+pub fn header(page_size: u32) -> [u8; HEADER_SIZE] {
+    let mut bytes = [0u8; HEADER_SIZE];
+    bytes[0..4].copy_from_slice(TRACE_MAGIC);
+    bytes[4..8].copy_from_slice(&TRACE_VERSION.to_le_bytes());
+    bytes[8..12].copy_from_slice(&page_size.to_le_bytes());
+    bytes
+}
+
+/// Decodes a trace header, returning the page size of the trace. Returns `None` if `bytes` is
+/// not the header of a trace in a supported format version.
+pub fn parse_header(bytes: &[u8; HEADER_SIZE]) -> Option<u32> {
+    let mut u32_bytes = [0u8; 4];
+    u32_bytes.copy_from_slice(&bytes[4..8]);
+    if &bytes[0..4] != TRACE_MAGIC || u32::from_le_bytes(u32_bytes) != TRACE_VERSION {
+        return None;
+    }
+    u32_bytes.copy_from_slice(&bytes[8..12]);
+    Some(u32::from_le_bytes(u32_bytes))
+}
+
+// Host mapping of a guest memory region.
+struct RegionMapping {
+    host_addr: u64,
+    len: u64,
+    guest_addr: u64,
+    file_offset: u64,
+}
+
+// Parses the index out of a vCPU thread name.
+fn parse_vcpu_id(thread_name: &str) -> Option<u16> {
+    let thread_name = thread_name.trim_end();
+    if !thread_name.starts_with(VCPU_THREAD_PREFIX) {
+        return None;
+    }
+    thread_name[VCPU_THREAD_PREFIX.len()..].parse().ok()
+}
+
+// Waits for `fd` to be readable, or for `stop` to be signaled. Returns whether `fd` is readable.
+pub(crate) fn wait_readable(fd: RawFd, stop: &EventFd) -> io::Result<bool> {
+    let mut fds = [
+        libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: stop.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    loop {
+        // Safe because the kernel only writes to the `revents` of `fds`.
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+        if ret >= 0 {
+            break;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    Ok(fds[1].revents == 0 && fds[0].revents != 0)
+}
+
+/// Handle to the `fc_fault_trace` thread, which stops and joins it when dropped.
+pub struct FaultTrace {
+    stop: EventFd,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for FaultTrace {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop.write(1) {
+            error!("Cannot stop the fault trace thread: {}", e);
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("The fault trace thread panicked");
+            }
+        }
+    }
+}
+
+struct FaultRecorder {
+    // Keeps the guest memory mapped for as long as the faults are serviced.
+    _guest_memory: GuestMemoryMmap,
+    uffd: Uffd,
+    stop: EventFd,
+    mem_file: File,
+    trace: File,
+    regions: Vec<RegionMapping>,
+    page_size: u64,
+    start_us: u64,
+    vcpu_ids: HashMap<u32, u16>,
+}
+
+impl FaultRecorder {
+    fn run(&mut self) {
+        let mut page = vec![0u8; self.page_size as usize];
+        loop {
+            match wait_readable(self.uffd.as_raw_fd(), &self.stop) {
+                Ok(true) => (),
+                Ok(false) => return,
+                Err(err) => {
+                    error!("Cannot wait for the guest page faults: {}", err);
+                    return;
+                }
+            }
+            match self.uffd.read_event() {
+                Ok(Some(Event::Pagefault {
+                    rw,
+                    addr,
+                    thread_id,
+                })) => self.service(&mut page, addr as u64, rw, thread_id),
+                // Only page faults are requested.
+                Ok(_) => (),
+                Err(err) => {
+                    error!("Cannot read the fault trace userfaultfd: {}", err);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn service(&mut self, page: &mut [u8], addr: u64, rw: ReadWrite, thread_id: Option<u32>) {
+        let page_addr = addr & !(self.page_size - 1);
+        let region = match self.regions.iter().find(|region| {
+            page_addr >= region.host_addr && page_addr < region.host_addr + region.len
+        }) {
+            Some(region) => region,
+            None => {
+                error!("Fault at {:#x} outside of the guest memory", addr);
+                return;
+            }
+        };
+        let region_offset = page_addr - region.host_addr;
+        let guest_addr = region.guest_addr + region_offset;
+        let file_offset = region.file_offset + region_offset;
+        let start_ns = get_time_ns(ClockType::Monotonic);
+
+        // The guest stays blocked until the page is populated, zero it rather than leaving the
+        // vCPU stuck if the memory file cannot be read.
+        let populated = match self.mem_file.read_exact_at(page, file_offset) {
+            // Safe because `page` is one page long and `page_addr` is a registered page.
+            Ok(()) => unsafe {
+                self.uffd
+                    .copy(page.as_ptr() as _, page_addr as _, page.len(), true)
+                    .map(|_| ())
+            },
+            // Safe because `page_addr` is a registered page.
+            Err(err) => unsafe {
+                error!("Cannot read memory file at {:#x}: {}", file_offset, err);
+                self.uffd
+                    .zeropage(page_addr as _, page.len(), true)
+                    .map(|_| ())
+            },
+        };
+        if let Err(err) = populated {
+            // The page may have been populated by a concurrent fault on the same address.
+            error!("Cannot populate page at {:#x}: {}", guest_addr, err);
+            if let Err(err) = self.uffd.wake(page_addr as _, page.len()) {
+                error!("Cannot wake the faulting thread: {}", err);
+            }
+        }
+        probes::fc_probe_fault_service(guest_addr, get_time_ns(ClockType::Monotonic) - start_ns);
+        fault_sources::record(FaultSource::Base, 1, self.page_size);
+
+        let record = FaultRecord {
+            timestamp_us: (get_time_us(ClockType::Monotonic) - self.start_us) as u32,
+            vcpu_id: thread_id.map_or(NO_VCPU, |tid| self.vcpu_id(tid)),
+            write: rw == ReadWrite::Write,
+            guest_addr,
+            file_offset,
+        };
+        if let Err(err) = self.trace.write_all(&record.to_bytes()) {
+            error!("Cannot write fault trace record: {}", err);
+        }
+    }
+
+    fn vcpu_id(&mut self, tid: u32) -> u16 {
+        *self.vcpu_ids.entry(tid).or_insert_with(|| {
+            // Read without querying the file size first, which the seccomp filter of the thread
+            // does not allow.
+            let mut name = String::new();
+            File::open(format!("/proc/self/task/{}/comm", tid))
+                .and_then(|mut file| file.read_to_string(&mut name))
+                .ok()
+                .and_then(|_| parse_vcpu_id(&name))
+                .unwrap_or(NO_VCPU)
+        })
+    }
+}
+
+/// Registers `guest_memory`, anonymous memory restored from `state`, with a userfaultfd and
+/// records its faults to the trace file at `path`, while servicing them from `mem_file`, until
+/// the returned handle is dropped. The thread recording the faults runs under `seccomp_filter`.
+pub fn start(
+    path: &Path,
+    guest_memory: &GuestMemoryMmap,
+    state: &GuestMemoryState,
+    mem_file: File,
+    seccomp_filter: BpfProgram,
+) -> Result<FaultTrace> {
+    let page_size = sysconf::page::pagesize() as u64;
+    let uffd = UffdBuilder::new()
+        .close_on_exec(true)
+        .require_features(FeatureFlags::THREAD_ID)
+        .create()
+        .map_err(Error::CreateUffd)?;
+
+    let mut regions = Vec::with_capacity(state.regions.len());
+    for region in state.regions.iter() {
+        let host_addr = guest_memory
+            .get_host_address(GuestAddress(region.base_address))
+            .expect("Guest memory restored from this state") as u64;
+        uffd.register(host_addr as _, region.size)
+            .map_err(Error::Register)?;
+        regions.push(RegionMapping {
+            host_addr,
+            len: region.size as u64,
+            guest_addr: region.base_address,
+            file_offset: region.offset,
+        });
+    }
+
+    let mut trace = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .map_err(|e| Error::Open(path.to_path_buf(), e))?;
+    trace
+        .write_all(&header(page_size as u32))
+        .map_err(Error::Write)?;
+
+    let stop = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
+    let mut recorder = FaultRecorder {
+        _guest_memory: guest_memory.clone(),
+        uffd,
+        stop: stop.try_clone().map_err(Error::EventFd)?,
+        mem_file,
+        trace,
+        regions,
+        page_size,
+        start_us: get_time_us(ClockType::Monotonic),
+        vcpu_ids: HashMap::new(),
+    };
+    let (ready_sender, ready) = channel();
+    let thread = thread::Builder::new()
+        .name("fc_fault_trace".to_owned())
+        .spawn(move || {
+            let filtered = SeccompFilter::apply(seccomp_filter);
+            let failed = filtered.is_err();
+            let _ = ready_sender.send(filtered);
+            if !failed {
+                recorder.run();
+            }
+        })
+        .map_err(Error::Spawn)?;
+    ready
+        .recv()
+        .expect("The fault trace thread exited")
+        .map_err(Error::Seccomp)?;
+    Ok(FaultTrace {
+        stop,
+        thread: Some(thread),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_encoding() {
+        let record = FaultRecord {
+            timestamp_us: 1234,
+            vcpu_id: 1,
+            write: true,
+            guest_addr: 0x1_0000_1000,
+            file_offset: 0xc000_1000,
+        };
+        let bytes = record.to_bytes();
+        assert_eq!(&bytes[0..4], &1234u32.to_le_bytes());
+        assert_eq!(&bytes[4..6], &[1, 0]);
+        assert_eq!(&bytes[6..8], &[1, 0]);
+        assert_eq!(FaultRecord::from_bytes(&bytes), record);
+
+        let record = FaultRecord {
+            vcpu_id: NO_VCPU,
+            write: false,
+            ..record
+        };
+        assert_eq!(FaultRecord::from_bytes(&record.to_bytes()), record);
+
+        let header = header(4096);
+        assert_eq!(&header[0..4], b"FCFT");
+        assert_eq!(&header[4..8], &[1, 0, 0, 0]);
+        assert_eq!(&header[8..12], &4096u32.to_le_bytes());
+        assert_eq!(parse_header(&header), Some(4096));
+
+        let mut bad_header = header;
+        bad_header[4] = 2;
+        assert_eq!(parse_header(&bad_header), None);
+        bad_header = header;
+        bad_header[0] = b'X';
+        assert_eq!(parse_header(&bad_header), None);
+    }
+
+    #[test]
+    fn test_stop() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let ready = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let stop = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        ready.write(1).unwrap();
+        assert!(wait_readable(ready.as_raw_fd(), &stop).unwrap());
+        // Stopping takes precedence over the pending faults.
+        stop.write(1).unwrap();
+        assert!(!wait_readable(ready.as_raw_fd(), &stop).unwrap());
+
+        // Dropping the handle stops the thread waiting for faults, and joins it.
+        let idle = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let stop = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let thread_stop = stop.try_clone().unwrap();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_stopped = stopped.clone();
+        let thread = thread::spawn(move || {
+            let readable = wait_readable(idle.as_raw_fd(), &thread_stop).unwrap();
+            thread_stopped.store(!readable, Ordering::SeqCst);
+        });
+        drop(FaultTrace {
+            stop,
+            thread: Some(thread),
+        });
+        assert!(stopped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_parse_vcpu_id() {
+        assert_eq!(parse_vcpu_id("fc_vcpu 0\n"), Some(0));
+        assert_eq!(parse_vcpu_id("fc_vcpu 13"), Some(13));
+        assert_eq!(parse_vcpu_id("fc_vmm\n"), None);
+        assert_eq!(parse_vcpu_id("fc_vcpu x"), None);
+    }
+}
@@ -0,0 +1,329 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Coordination with an agent in the guest around the snapshots, over the vsock device.
+//!
+//! Each message opens a connection to the host side socket of the vsock device, which forwards
+//! it to the port the agent listens on after a `CONNECT <port>` line. The message is then a JSON
+//! line, which the agent answers with a JSON line of its own:
+//!
+//! - `{"type":"prepare-for-snapshot"}`, before the microVM pauses for a snapshot. The agent
+//!   flushes its buffers and quiesces the workload.
+//! - `{"type":"resumed","entropy":"<hex>"}`, once the microVM runs again, either restored or
+//!   after its snapshot. The agent reseeds the guest entropy pool with the 32 random bytes and
+//!   reconnects the workload.
+//!
+//! The agent answers `{"status":"ok"}`, or `{"status":"error","detail":"<reason>"}`.
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use logger::{info, Metric, METRICS};
+use serde::{Deserialize, Serialize};
+use utils::time::{get_time_us, ClockType};
+
+use crate::vmm_config::snapshot::GuestAgentConfig;
+
+// Bytes of entropy sent along with the `resumed` message.
+const ENTROPY_LEN: usize = 32;
+
+/// Errors associated with the guest agent.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to connect to the vsock device socket.
+    Connect(io::Error),
+    /// Failed to get random bytes for the guest.
+    Entropy(io::Error),
+    /// The agent reported a failure.
+    Failed(String),
+    /// The vsock device did not forward the connection to the agent port.
+    Forward(u32),
+    /// The agent answer is not a status.
+    InvalidReply(String),
+    /// Failed to talk to the agent.
+    Io(io::Error),
+    /// The agent did not answer in time.
+    Timeout(u64),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Connect(err) => write!(f, "Cannot connect to the vsock socket: {}", err),
+            Entropy(err) => write!(f, "Cannot get random bytes: {}", err),
+            Failed(detail) => write!(f, "The guest agent failed: {}", detail),
+            Forward(port) => write!(f, "No guest agent listens on vsock port {}", port),
+            InvalidReply(reply) => write!(f, "Invalid guest agent reply: {}", reply),
+            Io(err) => write!(f, "Cannot talk to the guest agent: {}", err),
+            Timeout(timeout_ms) => write!(f, "The guest agent did not answer in {} ms", timeout_ms),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Messages sent to the guest agent.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Message {
+    /// The microVM is about to pause for a snapshot.
+    PrepareForSnapshot,
+    /// The microVM runs again.
+    Resumed {
+        /// Random bytes to reseed the guest entropy pool with, hex encoded.
+        entropy: String,
+    },
+}
+
+impl Message {
+    /// Returns a `resumed` message with fresh random bytes.
+    pub fn resumed() -> Result<Self> {
+        let mut entropy = [0u8; ENTROPY_LEN];
+        // Safe because the kernel writes at most `entropy.len()` bytes to `entropy`.
+        let ret =
+            unsafe { libc::syscall(libc::SYS_getrandom, entropy.as_mut_ptr(), entropy.len(), 0) };
+        if ret != entropy.len() as i64 {
+            return Err(Error::Entropy(io::Error::last_os_error()));
+        }
+        Ok(Message::Resumed {
+            entropy: entropy.iter().map(|byte| format!("{:02x}", byte)).collect(),
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Message::PrepareForSnapshot => "prepare-for-snapshot",
+            Message::Resumed { .. } => "resumed",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Status {
+    Ok,
+    Error,
+}
+
+#[derive(Debug, Deserialize)]
+struct Reply {
+    status: Status,
+    #[serde(default)]
+    detail: Option<String>,
+}
+
+// Reads a line, failing once `deadline` is reached.
+fn read_line(
+    reader: &mut BufReader<&UnixStream>,
+    deadline: Instant,
+    timeout_ms: u64,
+) -> Result<String> {
+    let remaining = deadline
+        .checked_duration_since(Instant::now())
+        .filter(|remaining| *remaining > Duration::from_millis(0))
+        .ok_or(Error::Timeout(timeout_ms))?;
+    reader
+        .get_ref()
+        .set_read_timeout(Some(remaining))
+        .map_err(Error::Io)?;
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(_) => Ok(line),
+        Err(ref err)
+            if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut =>
+        {
+            Err(Error::Timeout(timeout_ms))
+        }
+        Err(err) => Err(Error::Io(err)),
+    }
+}
+
+// Sends `message` to the agent and waits for its answer.
+fn exchange(config: &GuestAgentConfig, message: &Message) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_millis(config.timeout_ms);
+    let stream = UnixStream::connect(&config.uds_path).map_err(Error::Connect)?;
+    stream
+        .set_write_timeout(Some(Duration::from_millis(config.timeout_ms)))
+        .map_err(Error::Io)?;
+    let mut writer = &stream;
+    writer
+        .write_all(format!("CONNECT {}\n", config.port).as_bytes())
+        .map_err(Error::Io)?;
+    let mut reader = BufReader::new(&stream);
+    // The vsock device closes the connection when nothing listens on the port.
+    if !read_line(&mut reader, deadline, config.timeout_ms)?.starts_with("OK ") {
+        return Err(Error::Forward(config.port));
+    }
+
+    let mut line = serde_json::to_string(message).map_err(|e| Error::Io(e.into()))?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).map_err(Error::Io)?;
+    let reply = read_line(&mut reader, deadline, config.timeout_ms)?;
+    let reply: Reply = serde_json::from_str(reply.trim_end())
+        .map_err(|_| Error::InvalidReply(reply.trim_end().to_string()))?;
+    match reply.status {
+        Status::Ok => Ok(()),
+        Status::Error => Err(Error::Failed(reply.detail.unwrap_or_default())),
+    }
+}
+
+/// Sends `message` to the agent configured by `config`, and records the outcome in the metrics.
+pub fn send(config: &GuestAgentConfig, message: &Message) -> Result<()> {
+    let start_us = get_time_us(ClockType::Monotonic);
+    let res = exchange(config, message);
+    METRICS
+        .snapshot
+        .guest_agent_us
+        .record(get_time_us(ClockType::Monotonic) - start_us);
+    match res {
+        Ok(()) => info!("The guest agent handled {}.", message.name()),
+        Err(Error::Timeout(_)) => {
+            METRICS.snapshot.guest_agent_timeouts.inc();
+            METRICS.snapshot.guest_agent_fails.inc();
+        }
+        Err(_) => METRICS.snapshot.guest_agent_fails.inc(),
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+    use std::thread;
+
+    use utils::tempdir::TempDir;
+
+    // Serves one connection like the vsock device and an agent answering `reply`, and returns
+    // the message the agent got.
+    fn fake_agent(path: &Path, port: u32, reply: &'static str) -> thread::JoinHandle<String> {
+        let listener = UnixListener::bind(path).unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line != format!("CONNECT {}\n", port) {
+                return line;
+            }
+            let mut stream = stream;
+            stream.write_all(b"OK 1073741824\n").unwrap();
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            if !reply.is_empty() {
+                stream.write_all(reply.as_bytes()).unwrap();
+            } else {
+                // Never answer, until the host gives up.
+                let mut rest = String::new();
+                let _ = reader.read_line(&mut rest);
+            }
+            line
+        })
+    }
+
+    fn config(dir: &TempDir, name: &str) -> GuestAgentConfig {
+        GuestAgentConfig {
+            uds_path: dir.as_path().join(name),
+            port: 52,
+            timeout_ms: 200,
+            required: false,
+        }
+    }
+
+    #[test]
+    fn test_send() {
+        let dir = TempDir::new().unwrap();
+
+        let config_ok = config(&dir, "ok.sock");
+        let agent = fake_agent(&config_ok.uds_path, 52, "{\"status\":\"ok\"}\n");
+        send(&config_ok, &Message::PrepareForSnapshot).unwrap();
+        assert_eq!(
+            agent.join().unwrap(),
+            "{\"type\":\"prepare-for-snapshot\"}\n"
+        );
+
+        let config_failed = config(&dir, "failed.sock");
+        let agent = fake_agent(
+            &config_failed.uds_path,
+            52,
+            "{\"status\":\"error\",\"detail\":\"disk full\"}\n",
+        );
+        let fails = METRICS.snapshot.guest_agent_fails.count();
+        match send(&config_failed, &Message::resumed().unwrap()) {
+            Err(Error::Failed(detail)) => assert_eq!(detail, "disk full"),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert!(agent
+            .join()
+            .unwrap()
+            .starts_with("{\"type\":\"resumed\",\"entropy\":\""));
+        assert!(METRICS.snapshot.guest_agent_fails.count() > fails);
+
+        let config_invalid = config(&dir, "invalid.sock");
+        let agent = fake_agent(&config_invalid.uds_path, 52, "done\n");
+        match send(&config_invalid, &Message::PrepareForSnapshot) {
+            Err(Error::InvalidReply(reply)) => assert_eq!(reply, "done"),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        agent.join().unwrap();
+
+        let config_silent = config(&dir, "silent.sock");
+        let agent = fake_agent(&config_silent.uds_path, 52, "");
+        match send(&config_silent, &Message::PrepareForSnapshot) {
+            Err(Error::Timeout(200)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        agent.join().unwrap();
+
+        let mut config_port = config(&dir, "port.sock");
+        let agent = fake_agent(&config_port.uds_path, 52, "");
+        config_port.port = 1024;
+        match send(&config_port, &Message::PrepareForSnapshot) {
+            Err(Error::Forward(1024)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        agent.join().unwrap();
+
+        match send(&config(&dir, "missing.sock"), &Message::PrepareForSnapshot) {
+            Err(Error::Connect(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_resumed_entropy() {
+        let first = Message::resumed().unwrap();
+        match &first {
+            Message::Resumed { entropy } => assert_eq!(entropy.len(), 2 * ENTROPY_LEN),
+            msg => panic!("Unexpected message: {:?}", msg),
+        }
+        assert_ne!(first, Message::resumed().unwrap());
+    }
+
+    #[test]
+    fn test_error_display() {
+        assert_eq!(
+            Error::Forward(52).to_string(),
+            "No guest agent listens on vsock port 52"
+        );
+        assert_eq!(
+            Error::Timeout(100).to_string(),
+            "The guest agent did not answer in 100 ms"
+        );
+        let errors = vec![
+            Error::Connect(io::Error::from_raw_os_error(libc::ENOENT)),
+            Error::Failed("disk full".to_string()),
+            Error::Entropy(io::Error::from_raw_os_error(libc::ENOSYS)),
+            Error::InvalidReply("done".to_string()),
+            Error::Io(io::Error::from_raw_os_error(libc::EPIPE)),
+        ];
+        for err in errors {
+            let _ = format!("{}{:?}", err, err);
+        }
+    }
+}
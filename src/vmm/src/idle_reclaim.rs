@@ -0,0 +1,369 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Automatic reclamation of the guest memory of idle restored microVMs.
+//!
+//! A restored microVM mostly touches its working set again once it serves a new request, so the
+//! rest of its guest memory is the first to give back while it waits. The CPU usage of the
+//! process is checked periodically, and once it stays under the activity threshold for the
+//! configured idle period, the guest memory outside of the working set extents is paged out a
+//! step at each period, keeping the working set resident for the next request. The reclamation
+//! is cancelled as soon as the microVM is active again, and starts over from the beginning of
+//! the cold memory once it is idle for another idle period. The CPU time spent paging out is not
+//! counted as activity.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use logger::{error, info, warn, Metric, METRICS};
+use polly::event_manager::{EventManager, Subscriber};
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
+use utils::epoll::{EpollEvent, EventSet};
+use utils::time::{get_time_us, ClockType};
+
+use crate::memory_reclaim::MemoryReclaimer;
+use crate::vmm_config::snapshot::IdleReclaimConfig;
+
+const MIB: u64 = 1 << 20;
+
+/// Errors associated with the idle memory reclamation.
+#[derive(Debug)]
+pub enum Error {
+    /// The activity threshold is not a percentage.
+    InvalidPercent(u8),
+    /// The check period is zero.
+    InvalidPeriod,
+    /// The page out step is zero.
+    InvalidStep,
+    /// The idle period, in milliseconds, does not fit in microseconds.
+    IdleTooLong(u64),
+    /// The page out step, in MiB, does not fit in bytes.
+    StepTooLarge(u64),
+    /// Failed to set up the reclamation of the guest memory.
+    Reclaimer(io::Error),
+    /// Failed to create the check timer.
+    Timer(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            InvalidPercent(percent) => write!(
+                f,
+                "The active CPU threshold must be between 1 and 100, not {}",
+                percent
+            ),
+            InvalidPeriod => write!(f, "The idle reclaim period must not be zero"),
+            InvalidStep => write!(f, "The idle reclaim step must not be zero"),
+            IdleTooLong(idle_ms) => write!(f, "The idle period of {} ms is too long", idle_ms),
+            StepTooLarge(step_mib) => {
+                write!(f, "The idle reclaim step of {} MiB is too large", step_mib)
+            }
+            Reclaimer(err) => write!(f, "Cannot reclaim the guest memory: {}", err),
+            Timer(err) => write!(f, "Cannot create the check timer: {}", err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Pages out the guest memory outside of the working set of a restored microVM once idle.
+pub struct IdleReclaimer {
+    reclaimer: Arc<MemoryReclaimer>,
+    period: Duration,
+    idle_us: u64,
+    step_bytes: u64,
+    active_cpu_percent: u8,
+    // Time and process CPU time of the previous check, in microseconds.
+    last_check_us: u64,
+    last_cpu_us: u64,
+    // CPU time spent paging out since the previous check.
+    reclaim_cpu_us: u64,
+    // Time the microVM was last active.
+    active_us: u64,
+    // Bytes of the cold memory paged out since the microVM is idle.
+    cursor: u64,
+    timer: TimerFd,
+}
+
+impl IdleReclaimer {
+    /// Pages out the guest memory `reclaimer` reclaims outside of the working set extents, as
+    /// configured by `config`. The reclaimer is shared with the reclaim requests of the API.
+    pub fn new(reclaimer: Arc<MemoryReclaimer>, config: &IdleReclaimConfig) -> Result<Self> {
+        if config.active_cpu_percent == 0 || config.active_cpu_percent > 100 {
+            return Err(Error::InvalidPercent(config.active_cpu_percent));
+        }
+        if config.period_ms == 0 {
+            return Err(Error::InvalidPeriod);
+        }
+        if config.step_mib == 0 {
+            return Err(Error::InvalidStep);
+        }
+        let idle_us = config
+            .idle_ms
+            .checked_mul(1000)
+            .ok_or(Error::IdleTooLong(config.idle_ms))?;
+        let step_bytes = config
+            .step_mib
+            .checked_mul(MIB)
+            .ok_or(Error::StepTooLarge(config.step_mib))?;
+        let now_us = get_time_us(ClockType::Monotonic);
+        Ok(IdleReclaimer {
+            reclaimer,
+            period: Duration::from_millis(config.period_ms),
+            idle_us,
+            step_bytes,
+            active_cpu_percent: config.active_cpu_percent,
+            last_check_us: now_us,
+            last_cpu_us: get_time_us(ClockType::ProcessCpu),
+            reclaim_cpu_us: 0,
+            active_us: now_us,
+            cursor: 0,
+            timer: TimerFd::new_custom(ClockId::Monotonic, true, true).map_err(Error::Timer)?,
+        })
+    }
+
+    /// Starts checking the activity periodically. The idle period starts now.
+    pub fn start(&mut self) {
+        self.last_check_us = get_time_us(ClockType::Monotonic);
+        self.last_cpu_us = get_time_us(ClockType::ProcessCpu);
+        self.active_us = self.last_check_us;
+        self.timer.set_state(
+            TimerState::Periodic {
+                current: self.period,
+                interval: self.period,
+            },
+            SetTimeFlags::Default,
+        );
+    }
+
+    // Accounts for the process CPU time `cpu_us` used up to `now_us`, and returns whether the
+    // next step of the cold memory is to be paged out. Cancels the reclamation if the microVM is
+    // active.
+    fn update(&mut self, now_us: u64, cpu_us: u64) -> bool {
+        let elapsed_us = now_us.saturating_sub(self.last_check_us);
+        let busy_us = cpu_us
+            .saturating_sub(self.last_cpu_us)
+            .saturating_sub(self.reclaim_cpu_us);
+        self.last_check_us = now_us;
+        self.last_cpu_us = cpu_us;
+        self.reclaim_cpu_us = 0;
+
+        if busy_us * 100 > elapsed_us * u64::from(self.active_cpu_percent) {
+            if self.cursor > 0 {
+                info!(
+                    "The microVM is active again, {} bytes of guest memory were paged out.",
+                    self.cursor
+                );
+                METRICS.idle_reclaim.cancels.inc();
+                self.cursor = 0;
+            }
+            self.active_us = now_us;
+            return false;
+        }
+        now_us.saturating_sub(self.active_us) >= self.idle_us
+            && self.cursor < self.reclaimer.cold_bytes()
+    }
+
+    // Checks the activity, and pages out the next step of the cold memory once idle.
+    fn check(&mut self) {
+        if !self.update(
+            get_time_us(ClockType::Monotonic),
+            get_time_us(ClockType::ProcessCpu),
+        ) {
+            return;
+        }
+        if self.cursor == 0 {
+            info!("The microVM is idle, paging out its guest memory.");
+        }
+
+        let start_cpu_us = get_time_us(ClockType::ThreadCpu);
+        let paged_out = self.reclaimer.page_out_cold(self.cursor, self.step_bytes);
+        self.reclaim_cpu_us += get_time_us(ClockType::ThreadCpu).saturating_sub(start_cpu_us);
+        match paged_out {
+            Ok(bytes) => {
+                METRICS.idle_reclaim.paged_out_bytes.add(bytes as usize);
+                self.cursor += self.step_bytes;
+            }
+            Err(err) => {
+                // The advice is unknown to kernels before 5.4, retrying would fail all the same.
+                error!("Cannot page out the idle guest memory: {}", err);
+                METRICS.idle_reclaim.fails.inc();
+                self.timer
+                    .set_state(TimerState::Disarmed, SetTimeFlags::Default);
+            }
+        }
+    }
+}
+
+impl Subscriber for IdleReclaimer {
+    /// Handle a read event (EPOLLIN).
+    fn process(&mut self, event: &EpollEvent, _: &mut EventManager) {
+        let source = event.fd();
+        let event_set = event.event_set();
+
+        if !EventSet::IN.contains(event_set) {
+            warn!(
+                "Received unknown event: {:?} from source: {:?}",
+                event_set, source
+            );
+            return;
+        }
+
+        if source == self.timer.as_raw_fd() {
+            // Consume the timer expirations.
+            self.timer.read();
+            self.check();
+        } else {
+            error!("Spurious EventManager event for handler: IdleReclaimer");
+        }
+    }
+
+    fn interest_list(&self) -> Vec<EpollEvent> {
+        vec![EpollEvent::new(EventSet::IN, self.timer.as_raw_fd() as u64)]
+    }
+}
+
+/// Starts watching the activity of the restored microVM with `reclaimer`. Failing to register
+/// it does not fail the restore.
+pub fn start(event_manager: &mut EventManager, mut reclaimer: IdleReclaimer) {
+    reclaimer.start();
+    if let Err(err) = event_manager.add_subscriber(Arc::new(Mutex::new(reclaimer))) {
+        error!("Cannot register the idle memory reclamation: {:?}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+    use crate::memory_snapshot::SnapshotMemory;
+
+    fn config() -> IdleReclaimConfig {
+        IdleReclaimConfig {
+            idle_ms: 100,
+            period_ms: 10,
+            step_mib: 1,
+            active_cpu_percent: 10,
+        }
+    }
+
+    // Reclaims 4 MiB of guest memory, whose working set is `ws_regions`. The reclaimer keeps
+    // the host ranges of the guest memory, which is returned along with it.
+    fn reclaimer(ws_regions: &[Vec<i64>]) -> (GuestMemoryMmap, Arc<MemoryReclaimer>) {
+        let guest_memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 4 << 20)]).unwrap();
+        let state = guest_memory.describe();
+        let reclaimer = MemoryReclaimer::new(&guest_memory, &state, ws_regions).unwrap();
+        (guest_memory, Arc::new(reclaimer))
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let (_guest_memory, reclaimer) = reclaimer(&[]);
+
+        let mut bad = config();
+        bad.active_cpu_percent = 0;
+        match IdleReclaimer::new(reclaimer.clone(), &bad) {
+            Err(Error::InvalidPercent(0)) => (),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+
+        let mut bad = config();
+        bad.period_ms = 0;
+        match IdleReclaimer::new(reclaimer.clone(), &bad) {
+            Err(Error::InvalidPeriod) => (),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+
+        let mut bad = config();
+        bad.step_mib = 0;
+        match IdleReclaimer::new(reclaimer.clone(), &bad) {
+            Err(Error::InvalidStep) => (),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+
+        let mut bad = config();
+        bad.idle_ms = std::u64::MAX;
+        match IdleReclaimer::new(reclaimer.clone(), &bad) {
+            Err(Error::IdleTooLong(std::u64::MAX)) => (),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+
+        let mut bad = config();
+        bad.step_mib = 1 << 44;
+        match IdleReclaimer::new(reclaimer, &bad) {
+            Err(Error::StepTooLarge(_)) => (),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+    }
+
+    #[test]
+    fn test_update() {
+        // The first MiB is the working set, 3 MiB are left to page out.
+        let pages = (1 << 20) / sysconf::page::pagesize() as i64;
+        let (_guest_memory, reclaimer) = reclaimer(&[vec![0, pages]]);
+        let mut reclaimer = IdleReclaimer::new(reclaimer, &config()).unwrap();
+        reclaimer.last_check_us = 0;
+        reclaimer.last_cpu_us = 0;
+        reclaimer.active_us = 0;
+
+        // Not idle for long enough.
+        assert!(!reclaimer.update(50_000, 1_000));
+        // Idle, 2% of the CPU is under the threshold.
+        assert!(reclaimer.update(100_000, 2_000));
+        reclaimer.cursor = 3 * MIB;
+        // Everything is paged out already.
+        assert!(!reclaimer.update(110_000, 2_000));
+
+        // Activity cancels the reclamation, and the idle period starts over.
+        let cancels = METRICS.idle_reclaim.cancels.count();
+        assert!(!reclaimer.update(120_000, 4_000));
+        assert_eq!(reclaimer.cursor, 0);
+        assert_eq!(reclaimer.active_us, 120_000);
+        // Other tests may cancel reclamations concurrently.
+        assert!(METRICS.idle_reclaim.cancels.count() > cancels);
+        assert!(!reclaimer.update(200_000, 4_000));
+
+        // The CPU time spent paging out is not activity.
+        reclaimer.reclaim_cpu_us = 5_000;
+        assert!(reclaimer.update(220_000, 9_000));
+    }
+
+    #[test]
+    fn test_check() {
+        let (_guest_memory, reclaimer) = reclaimer(&[]);
+        let mut config = config();
+        config.idle_ms = 0;
+        let mut reclaimer = IdleReclaimer::new(reclaimer, &config).unwrap();
+        reclaimer.check();
+        // Kernels before 5.4 do not know the advice.
+        if reclaimer.cursor > 0 {
+            assert_eq!(reclaimer.cursor, MIB);
+        }
+
+        let mut event_manager = EventManager::new().unwrap();
+        start(&mut event_manager, reclaimer);
+    }
+
+    #[test]
+    fn test_error_display() {
+        let errors = vec![
+            Error::InvalidPercent(101),
+            Error::InvalidPeriod,
+            Error::InvalidStep,
+            Error::IdleTooLong(std::u64::MAX),
+            Error::StepTooLarge(std::u64::MAX),
+            Error::Reclaimer(io::Error::from_raw_os_error(libc::EINVAL)),
+            Error::Timer(io::Error::from_raw_os_error(libc::EMFILE)),
+        ];
+        for err in errors {
+            let _ = format!("{}{:?}", err, err);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Kernel samepage merging (KSM) of restored guest memory.
+//!
+//! Many clones restored from the same snapshot hold the same pages. Once marked mergeable, the
+//! anonymous guest memory pages are scanned by `ksmd`, and the identical ones across microVMs are
+//! shared copy-on-write, which trades `ksmd` CPU time for host memory. Pages still mapped from the
+//! snapshot files are shared through the page cache already, and are left to it.
+
+use std::io;
+
+use vm_memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+/// Number of pages of the process merged by KSM, reported by Linux 6.1 and later.
+pub const KSM_MERGING_PAGES_PATH: &str = "/proc/self/ksm_merging_pages";
+
+/// Marks the whole of `guest_memory`, with the overlay and WS layers mapped over it, as
+/// mergeable by KSM.
+pub fn mark_mergeable(guest_memory: &GuestMemoryMmap) -> io::Result<()> {
+    guest_memory.with_regions(|_, region| {
+        // Safe because advising KSM does not modify the content of the memory.
+        let ret = unsafe {
+            libc::madvise(
+                region.as_ptr() as *mut libc::c_void,
+                region.len() as usize,
+                libc::MADV_MERGEABLE,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    })
+}
+
+/// Returns the number of pages of the process merged by KSM.
+pub fn merging_pages() -> io::Result<usize> {
+    parse_merging_pages(&std::fs::read_to_string(KSM_MERGING_PAGES_PATH)?)
+}
+
+fn parse_merging_pages(content: &str) -> io::Result<usize> {
+    content
+        .trim()
+        .parse()
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::GuestAddress;
+
+    #[test]
+    fn test_mark_mergeable() {
+        let page_size = sysconf::page::pagesize();
+        let guest_memory = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 4 * page_size),
+            (GuestAddress(8 * page_size as u64), 4 * page_size),
+        ])
+        .unwrap();
+        // Kernels built without KSM reject the advice.
+        match mark_mergeable(&guest_memory) {
+            Ok(()) => (),
+            Err(err) => assert_eq!(err.raw_os_error(), Some(libc::EINVAL)),
+        }
+    }
+
+    #[test]
+    fn test_parse_merging_pages() {
+        assert_eq!(parse_merging_pages("0\n").unwrap(), 0);
+        assert_eq!(parse_merging_pages("1234\n").unwrap(), 1234);
+        assert!(parse_merging_pages("").is_err());
+        assert!(parse_merging_pages("pages").is_err());
+    }
+}
@@ -0,0 +1,279 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::ffi::CString;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+
+// The landlock syscalls use the same numbers on x86_64 and aarch64.
+// See include/uapi/asm-generic/unistd.h in the kernel code.
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+
+// See include/uapi/linux/landlock.h in the kernel code.
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const LANDLOCK_ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const LANDLOCK_ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const LANDLOCK_ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const LANDLOCK_ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const LANDLOCK_ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const LANDLOCK_ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const LANDLOCK_ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+const LANDLOCK_ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const LANDLOCK_ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const LANDLOCK_ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const LANDLOCK_ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+const LANDLOCK_ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+const LANDLOCK_ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+
+// Every access right of the first landlock ABI. Handling all of them denies any filesystem
+// access that is not granted by a rule.
+const ACCESS_FS_ALL: u64 = LANDLOCK_ACCESS_FS_EXECUTE
+    | LANDLOCK_ACCESS_FS_WRITE_FILE
+    | LANDLOCK_ACCESS_FS_READ_FILE
+    | LANDLOCK_ACCESS_FS_READ_DIR
+    | LANDLOCK_ACCESS_FS_REMOVE_DIR
+    | LANDLOCK_ACCESS_FS_REMOVE_FILE
+    | LANDLOCK_ACCESS_FS_MAKE_CHAR
+    | LANDLOCK_ACCESS_FS_MAKE_DIR
+    | LANDLOCK_ACCESS_FS_MAKE_REG
+    | LANDLOCK_ACCESS_FS_MAKE_SOCK
+    | LANDLOCK_ACCESS_FS_MAKE_FIFO
+    | LANDLOCK_ACCESS_FS_MAKE_BLOCK
+    | LANDLOCK_ACCESS_FS_MAKE_SYM;
+const ACCESS_FS_READ: u64 = LANDLOCK_ACCESS_FS_READ_FILE | LANDLOCK_ACCESS_FS_READ_DIR;
+const ACCESS_FS_READ_WRITE: u64 = ACCESS_FS_ALL & !LANDLOCK_ACCESS_FS_EXECUTE;
+// Rights which can be granted on a path that is not a directory.
+const ACCESS_FS_FILE: u64 =
+    LANDLOCK_ACCESS_FS_EXECUTE | LANDLOCK_ACCESS_FS_WRITE_FILE | LANDLOCK_ACCESS_FS_READ_FILE;
+
+#[repr(C)]
+struct LandlockRulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Errors associated with the landlock filesystem sandbox.
+#[derive(Debug)]
+pub enum Error {
+    /// Adding a rule for a path to the ruleset failed.
+    AddRule(PathBuf, io::Error),
+    /// Creating the ruleset failed.
+    CreateRuleset(io::Error),
+    /// A path covered by a rule cannot be opened.
+    OpenPath(PathBuf, io::Error),
+    /// Setting the no_new_privs bit failed.
+    NoNewPrivs(io::Error),
+    /// Enforcing the ruleset on the calling thread failed.
+    RestrictSelf(io::Error),
+    /// The host kernel does not support landlock or it is disabled.
+    Unsupported,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            AddRule(path, err) => write!(
+                f,
+                "Cannot add a landlock rule for {}: {}",
+                path.display(),
+                err
+            ),
+            CreateRuleset(err) => write!(f, "Cannot create the landlock ruleset: {}", err),
+            OpenPath(path, err) => write!(f, "Cannot open {}: {}", path.display(), err),
+            NoNewPrivs(err) => write!(f, "Cannot set the no_new_privs bit: {}", err),
+            RestrictSelf(err) => write!(f, "Cannot enforce the landlock ruleset: {}", err),
+            Unsupported => write!(f, "Landlock is not supported by the host kernel"),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Filesystem accesses allowed once the landlock sandbox is enforced.
+///
+/// Any access outside of these paths is denied, so the rules must also cover the files opened by
+/// the VMM after startup, such as drives, tap devices, or the log and metrics files.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LandlockRules {
+    /// Files or directories that can only be read, such as the snapshot, memory, overlay and
+    /// working set files.
+    pub read_only: Vec<PathBuf>,
+    /// Files or directories that can be read, written, created and removed, such as the
+    /// directories snapshots are dumped to.
+    pub read_write: Vec<PathBuf>,
+}
+
+impl LandlockRules {
+    /// Enforces the rules on the calling thread and on the threads it spawns afterwards.
+    pub fn restrict_self(&self) -> Result<()> {
+        let attr = LandlockRulesetAttr {
+            handled_access_fs: ACCESS_FS_ALL,
+        };
+        // Safe because the kernel only reads `attr`, which lives until the syscall returns.
+        let ret = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_CREATE_RULESET,
+                &attr as *const LandlockRulesetAttr,
+                std::mem::size_of::<LandlockRulesetAttr>(),
+                0,
+            )
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => Err(Error::Unsupported),
+                _ => Err(Error::CreateRuleset(err)),
+            };
+        }
+        // Safe because the ruleset fd was just created and is owned by nothing else.
+        let ruleset = unsafe { File::from_raw_fd(ret as i32) };
+
+        for path in self.read_only.iter() {
+            add_path_rule(&ruleset, path, ACCESS_FS_READ)?;
+        }
+        for path in self.read_write.iter() {
+            add_path_rule(&ruleset, path, ACCESS_FS_READ_WRITE)?;
+        }
+
+        // Unprivileged processes can only enforce a ruleset with no_new_privs set.
+        // Safe because the arguments are valid for PR_SET_NO_NEW_PRIVS.
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+            return Err(Error::NoNewPrivs(io::Error::last_os_error()));
+        }
+        // Safe because the ruleset fd is valid and no flags are passed.
+        let ret = unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset.as_raw_fd(), 0) };
+        if ret < 0 {
+            return Err(Error::RestrictSelf(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+}
+
+// Only the file related rights can be granted on paths which are not directories.
+fn allowed_access(access: u64, is_dir: bool) -> u64 {
+    if is_dir {
+        access
+    } else {
+        access & ACCESS_FS_FILE
+    }
+}
+
+fn add_path_rule(ruleset: &File, path: &Path, access: u64) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| Error::OpenPath(path.to_path_buf(), io::Error::from(e)))?;
+    // Safe because `c_path` is a valid null terminated string.
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(Error::OpenPath(
+            path.to_path_buf(),
+            io::Error::last_os_error(),
+        ));
+    }
+    // Safe because the fd was just opened and is owned by nothing else.
+    let parent = unsafe { File::from_raw_fd(fd) };
+    let is_dir = parent
+        .metadata()
+        .map_err(|e| Error::OpenPath(path.to_path_buf(), e))?
+        .is_dir();
+
+    let attr = LandlockPathBeneathAttr {
+        allowed_access: allowed_access(access, is_dir),
+        parent_fd: parent.as_raw_fd(),
+    };
+    // Safe because the kernel only reads `attr`, which lives until the syscall returns.
+    let ret = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_ADD_RULE,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr as *const LandlockPathBeneathAttr,
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::AddRule(
+            path.to_path_buf(),
+            io::Error::last_os_error(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::OpenOptions;
+    use std::thread;
+
+    use utils::tempdir::TempDir;
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_allowed_access() {
+        assert_eq!(allowed_access(ACCESS_FS_READ, true), ACCESS_FS_READ);
+        assert_eq!(
+            allowed_access(ACCESS_FS_READ, false),
+            LANDLOCK_ACCESS_FS_READ_FILE
+        );
+        assert_eq!(
+            allowed_access(ACCESS_FS_READ_WRITE, false),
+            LANDLOCK_ACCESS_FS_READ_FILE | LANDLOCK_ACCESS_FS_WRITE_FILE
+        );
+    }
+
+    #[test]
+    fn test_restrict_self() {
+        let read_only_file = TempFile::new().unwrap();
+        let read_write_dir = TempDir::new().unwrap();
+        let denied_file = TempFile::new().unwrap();
+        let read_only = read_only_file.as_path().to_path_buf();
+        let read_write = read_write_dir.as_path().to_path_buf();
+        let denied = denied_file.as_path().to_path_buf();
+        let rules = LandlockRules {
+            read_only: vec![read_only.clone()],
+            read_write: vec![read_write.clone()],
+        };
+
+        // Landlock restricts the calling thread, keep the test runner out of the sandbox.
+        thread::spawn(move || {
+            match rules.restrict_self() {
+                Err(Error::Unsupported) => return,
+                res => res.unwrap(),
+            }
+
+            assert!(File::open(&read_only).is_ok());
+            assert!(OpenOptions::new().write(true).open(&read_only).is_err());
+            assert!(File::create(read_write.join("dump")).is_ok());
+            assert!(File::open(&denied).is_err());
+        })
+        .join()
+        .unwrap();
+
+        let rules = LandlockRules {
+            read_only: vec![PathBuf::from("/does/not/exist")],
+            read_write: vec![],
+        };
+        thread::spawn(move || match rules.restrict_self() {
+            Err(Error::Unsupported) | Err(Error::OpenPath(_, _)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Page cache advice on the snapshot files of each memory layer.
+//!
+//! The layers are read in different patterns: the guest faults in the pages of the memory file
+//! all over it, while the working set file is read from start to end by the prefetch, and the
+//! overlay file holds the pages the guest dirtied, read as it touches them again. Each file is
+//! given its own `posix_fadvise` advice, possibly several, rather than the single `fadvise`
+//! advice of the memory file. Failing to advise the kernel does not fail the restore.
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use logger::warn;
+
+use crate::restore_profile::FADVISE_POLICIES;
+use crate::vmm_config::snapshot::LayerFadviseConfig;
+
+/// Errors associated with the advice on the layer files.
+#[derive(Debug)]
+pub enum Error {
+    /// The advice given to a layer, as (layer, advice), is not known.
+    UnknownAdvice(&'static str, String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            UnknownAdvice(layer, advice) => write!(
+                f,
+                "Unknown fadvise advice '{}' for the {}, expected one of {}",
+                advice,
+                layer,
+                FADVISE_POLICIES[1..].join(", ")
+            ),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+// Returns the `posix_fadvise` advice named `name`, none for the empty name, which leaves the
+// default.
+fn parse_advice(layer: &'static str, name: &str) -> Result<Option<libc::c_int>> {
+    let advice = match name {
+        "" => return Ok(None),
+        "normal" => libc::POSIX_FADV_NORMAL,
+        "random" => libc::POSIX_FADV_RANDOM,
+        "sequential" => libc::POSIX_FADV_SEQUENTIAL,
+        "willneed" => libc::POSIX_FADV_WILLNEED,
+        "dontneed" => libc::POSIX_FADV_DONTNEED,
+        "noreuse" => libc::POSIX_FADV_NOREUSE,
+        _ => return Err(Error::UnknownAdvice(layer, name.to_string())),
+    };
+    Ok(Some(advice))
+}
+
+fn parse_advices(layer: &'static str, names: &[String]) -> Result<Vec<libc::c_int>> {
+    let mut advices = Vec::new();
+    for name in names.iter() {
+        advices.extend(parse_advice(layer, name)?);
+    }
+    Ok(advices)
+}
+
+/// Advice on the layer files of a restore.
+#[derive(Debug, Default, PartialEq)]
+pub struct LayerAdvice {
+    mem_file: Vec<libc::c_int>,
+    overlay_file: Vec<libc::c_int>,
+    ws_file: Vec<libc::c_int>,
+}
+
+impl LayerAdvice {
+    /// Returns the advice of `layers` on each file, or `fadvise` on the memory file when
+    /// `layers` gives it none.
+    pub fn new(fadvise: &str, layers: Option<&LayerFadviseConfig>) -> Result<Self> {
+        let default = LayerFadviseConfig::default();
+        let layers = layers.unwrap_or(&default);
+        let mem_file = if layers.mem_file.is_empty() {
+            parse_advices("memory file", &[fadvise.to_string()])?
+        } else {
+            parse_advices("memory file", &layers.mem_file)?
+        };
+        Ok(LayerAdvice {
+            mem_file,
+            overlay_file: parse_advices("overlay file", &layers.overlay_file)?,
+            ws_file: parse_advices("ws file", &layers.ws_file)?,
+        })
+    }
+
+    /// Advises the kernel on the layer files, in the order the advices are given.
+    pub fn apply(
+        &self,
+        mem_file: Option<&File>,
+        overlay_file: Option<&File>,
+        ws_file: Option<&File>,
+    ) {
+        for (layer, file, advices) in [
+            ("memory file", mem_file, &self.mem_file),
+            ("overlay file", overlay_file, &self.overlay_file),
+            ("ws file", ws_file, &self.ws_file),
+        ]
+        .iter()
+        {
+            if let Some(file) = file {
+                for advice in advices.iter() {
+                    if let Err(err) = advise(file, *advice) {
+                        warn!("Cannot advise the kernel on the {}: {}", layer, err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn advise(file: &File, advice: libc::c_int) -> io::Result<()> {
+    // Safe because `posix_fadvise` does not modify memory. It returns the error number.
+    let ret = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_layer_advice() {
+        assert_eq!(LayerAdvice::new("", None).unwrap(), LayerAdvice::default());
+        let advice = LayerAdvice::new("random", None).unwrap();
+        assert_eq!(advice.mem_file, vec![libc::POSIX_FADV_RANDOM]);
+        assert!(advice.ws_file.is_empty());
+
+        // The advice on the memory file replaces `fadvise`.
+        let layers = LayerFadviseConfig {
+            mem_file: vec!["normal".to_string()],
+            overlay_file: vec![],
+            ws_file: vec!["sequential".to_string(), "willneed".to_string()],
+        };
+        let advice = LayerAdvice::new("random", Some(&layers)).unwrap();
+        assert_eq!(advice.mem_file, vec![libc::POSIX_FADV_NORMAL]);
+        assert!(advice.overlay_file.is_empty());
+        assert_eq!(
+            advice.ws_file,
+            vec![libc::POSIX_FADV_SEQUENTIAL, libc::POSIX_FADV_WILLNEED]
+        );
+
+        // Every advice name of the restore profiles is known.
+        for name in FADVISE_POLICIES.iter() {
+            parse_advice("memory file", name).unwrap();
+        }
+        match LayerAdvice::new("hugepage", None) {
+            Err(Error::UnknownAdvice(layer, advice)) => {
+                assert_eq!((layer, advice.as_str()), ("memory file", "hugepage"))
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        let layers = LayerFadviseConfig {
+            ws_file: vec!["later".to_string()],
+            ..Default::default()
+        };
+        match LayerAdvice::new("", Some(&layers)) {
+            Err(Error::UnknownAdvice(layer, _)) => assert_eq!(layer, "ws file"),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(0x1000).unwrap();
+        let layers = LayerFadviseConfig {
+            mem_file: vec!["random".to_string()],
+            overlay_file: vec!["noreuse".to_string()],
+            ws_file: vec!["sequential".to_string(), "willneed".to_string()],
+        };
+        let advice = LayerAdvice::new("", Some(&layers)).unwrap();
+        advice.apply(Some(file.as_file()), None, Some(file.as_file()));
+        advise(file.as_file(), libc::POSIX_FADV_DONTNEED).unwrap();
+        assert!(advise(file.as_file(), -1).is_err());
+    }
+
+    #[test]
+    fn test_error_display() {
+        let err = Error::UnknownAdvice("ws file", "later".to_string());
+        let _ = format!("{}{:?}", err, err);
+    }
+}
@@ -11,28 +11,75 @@
 #![deny(missing_docs)]
 
 // crates for userfaultfd
+#[cfg(feature = "faasnap")]
 extern crate userfaultfd;
+#[cfg(feature = "faasnap")]
 extern crate passfd;
 
+pub mod audit;
+pub mod backing_files;
 /// Handles setup and initialization a `Vmm` object.
 pub mod builder;
 /// Syscalls allowed through the seccomp filter.
 pub mod default_syscalls;
 pub(crate) mod device_manager;
+pub mod dump_copy;
+pub mod dump_writer;
+pub mod fault_sources;
+#[cfg(feature = "faasnap")]
+pub mod fault_trace;
+pub mod guest_agent;
+pub mod idle_reclaim;
+/// Landlock based filesystem sandboxing.
+pub mod ksm;
+pub mod landlock;
+pub mod layer_fadvise;
+pub mod lifecycle;
+pub mod memory_debug;
+pub mod memory_layers;
+pub mod memory_policy;
+pub mod memory_reclaim;
+pub mod memory_residency;
 pub mod memory_snapshot;
+pub mod otel;
+pub mod page_cache;
 /// Save/restore utilities.
 pub mod persist;
+pub mod prefetch_coordinator;
+pub mod prefetch_tuning;
+pub mod probes;
+#[cfg(feature = "faasnap")]
+pub mod protected_base;
+pub mod psi;
 /// Resource store for configured microVM resources.
 pub mod resources;
+pub mod restore_profile;
+pub mod restore_trace;
+pub mod restore_watchdog;
 /// microVM RPC API adapters.
 pub mod rpc_interface;
 /// Signal handling utilities.
 pub mod signal_handler;
+pub mod snapshot;
+pub mod snapshot_cache;
+pub mod snapshot_check;
+pub mod snapshot_io;
+pub mod snapshot_ops;
+pub mod snapshot_signing;
+pub mod socket_activation;
 /// microVM state versions.
 pub mod version_map;
 /// Wrappers over structures used to configure the VMM.
 pub mod vmm_config;
 mod vstate;
+pub mod warm_notify;
+pub mod worker_pool;
+pub mod ws_accounting;
+#[cfg(feature = "faasnap")]
+pub mod ws_layout;
+pub mod ws_load_control;
+pub mod ws_lock;
+pub mod ws_staging;
 
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
@@ -40,26 +87,35 @@ use std::io;
 use std::os::unix::io::AsRawFd;
 #[cfg(target_arch = "x86_64")]
 use std::sync::mpsc::RecvTimeoutError;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 #[cfg(target_arch = "x86_64")]
 use crate::device_manager::legacy::PortIODeviceManager;
 use crate::device_manager::mmio::MMIODeviceManager;
+#[cfg(feature = "faasnap")]
+use crate::fault_trace::FaultTrace;
+use crate::lifecycle::LIFECYCLE;
+use crate::memory_reclaim::{MemoryReclaimParams, MemoryReclaimer, ReclaimStats};
 #[cfg(target_arch = "x86_64")]
 use crate::memory_snapshot::SnapshotMemory;
 #[cfg(target_arch = "x86_64")]
 use crate::persist::{MicrovmState, MicrovmStateError, VmInfo};
+#[cfg(feature = "faasnap")]
+use crate::protected_base::ProtectedBase;
+#[cfg(target_arch = "x86_64")]
+use crate::version_map::StateExtensions;
 #[cfg(target_arch = "x86_64")]
 use crate::vstate::VcpuState;
 use crate::vstate::{Vcpu, VcpuEvent, VcpuHandle, VcpuResponse, Vm};
+use crate::worker_pool::WORKER_POOL;
+#[cfg(target_arch = "x86_64")]
+use ::snapshot::Persist;
 use arch::DeviceType;
 use devices::BusDevice;
 use logger::{error, info, warn, LoggerError, MetricsError, METRICS};
 use polly::event_manager::{self, EventManager, Subscriber};
 use seccomp::BpfProgramRef;
-#[cfg(target_arch = "x86_64")]
-use snapshot::Persist;
 use utils::epoll::{EpollEvent, EventSet};
 use utils::eventfd::EventFd;
 use vm_memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap};
@@ -80,6 +136,11 @@ pub const FC_EXIT_CODE_SIGSEGV: u8 = 150;
 pub const FC_EXIT_CODE_BAD_CONFIGURATION: u8 = 152;
 /// Command line arguments parsing error.
 pub const FC_EXIT_CODE_ARG_PARSING: u8 = 153;
+/// Firecracker was shut down by the watchdog of a stalled snapshot restore.
+pub const FC_EXIT_CODE_RESTORE_STALLED: u8 = 154;
+/// Firecracker was shut down after intercepting `SIGBUS` within guest memory mapped from a
+/// snapshot file.
+pub const FC_EXIT_CODE_SNAPSHOT_FILE_SIGBUS: u8 = 155;
 
 /// Errors associated with the VMM internal logic. These errors cannot be generated by direct user
 /// input, but can result from bad configuration of the host (for example if Firecracker doesn't
@@ -196,6 +257,9 @@ pub type Result<T> = std::result::Result<T, Error>;
 /// Shorthand type for KVM dirty page bitmap.
 pub type DirtyBitmap = HashMap<usize, Vec<u64>>;
 
+/// Task fetching the KVM dirty bitmap of a memory slot.
+pub type DirtyLogTask<'a> = worker_pool::Task<'a, std::result::Result<Vec<u64>, kvm_ioctls::Error>>;
+
 /// Contains the state and associated methods required for the Firecracker VMM.
 pub struct Vmm {
     events_observer: Option<Box<dyn VmmEventsObserver>>,
@@ -204,6 +268,7 @@ pub struct Vmm {
     guest_memory: GuestMemoryMmap,
 
     vcpus_handles: Vec<VcpuHandle>,
+    vcpus_running: bool,
     exit_evt: EventFd,
     vm: Vm,
 
@@ -211,6 +276,15 @@ pub struct Vmm {
     mmio_device_manager: MMIODeviceManager,
     #[cfg(target_arch = "x86_64")]
     pio_device_manager: PortIODeviceManager,
+
+    // Faasnap helper threads touching the guest memory.
+    #[cfg(feature = "faasnap")]
+    fault_trace: Option<FaultTrace>,
+    #[cfg(feature = "faasnap")]
+    protected_base: Option<ProtectedBase>,
+
+    // Reclaims the restored guest memory on demand.
+    memory_reclaimer: Option<Arc<MemoryReclaimer>>,
 }
 
 impl Vmm {
@@ -223,6 +297,35 @@ impl Vmm {
         self.mmio_device_manager.get_device(device_type, device_id)
     }
 
+    /// Hands over the thread recording the guest page faults, stopped along with the microVM.
+    #[cfg(feature = "faasnap")]
+    pub fn set_fault_trace(&mut self, fault_trace: FaultTrace) {
+        self.fault_trace = Some(fault_trace);
+    }
+
+    /// Hands over the thread paging in the write-protected base layer, stopped along with the
+    /// microVM.
+    #[cfg(feature = "faasnap")]
+    pub fn set_protected_base(&mut self, protected_base: ProtectedBase) {
+        self.protected_base = Some(protected_base);
+    }
+
+    /// Hands over the reclaimer of the restored guest memory.
+    pub fn set_memory_reclaimer(&mut self, memory_reclaimer: Arc<MemoryReclaimer>) {
+        self.memory_reclaimer = Some(memory_reclaimer);
+    }
+
+    /// Reclaims the restored guest memory as described by `params`.
+    pub fn reclaim_memory(
+        &self,
+        params: &MemoryReclaimParams,
+    ) -> std::result::Result<ReclaimStats, memory_reclaim::Error> {
+        self.memory_reclaimer
+            .as_ref()
+            .ok_or(memory_reclaim::Error::NotRestored)?
+            .reclaim(params, self.vcpus_running)
+    }
+
     /// Starts the microVM vcpus.
     pub fn start_vcpus(
         &mut self,
@@ -272,13 +375,17 @@ impl Vmm {
 
     /// Sends a resume command to the vCPUs.
     pub fn resume_vcpus(&mut self) -> Result<()> {
+        // The guest may send packets as soon as the first vCPU runs.
+        METRICS.readiness.arm();
         for handle in self.vcpus_handles.iter() {
             handle
                 .send_event(VcpuEvent::Resume)
                 .map_err(Error::VcpuEvent)?;
         }
         self.check_vcpus_response(VcpuResponse::Resumed)
-            .map_err(|_| Error::VcpuResume)
+            .map_err(|_| Error::VcpuResume)?;
+        self.vcpus_running = true;
+        Ok(())
     }
 
     /// Sends a pause command to the vCPUs.
@@ -289,7 +396,9 @@ impl Vmm {
                 .map_err(Error::VcpuEvent)?;
         }
         self.check_vcpus_response(VcpuResponse::Paused)
-            .map_err(|_| Error::VcpuPause)
+            .map_err(|_| Error::VcpuPause)?;
+        self.vcpus_running = false;
+        Ok(())
     }
 
     /// Returns a reference to the inner `GuestMemoryMmap` object if present, or `None` otherwise.
@@ -311,6 +420,7 @@ impl Vmm {
     /// Waits for all vCPUs to exit and terminates the Firecracker process.
     pub fn stop(&mut self, exit_code: i32) {
         info!("Vmm is stopping.");
+        LIFECYCLE.notify_shutdown(exit_code);
 
         if let Some(observer) = self.events_observer.as_mut() {
             if let Err(e) = observer.on_vmm_stop() {
@@ -318,6 +428,17 @@ impl Vmm {
             }
         }
 
+        // No helper thread may touch the guest memory once it is unmapped.
+        #[cfg(feature = "faasnap")]
+        {
+            self.fault_trace.take();
+            self.protected_base.take();
+        }
+        WORKER_POOL.stop();
+
+        // The restores mapping the staged ws files keep their content.
+        ws_staging::cleanup();
+
         // Write the metrics before exiting.
         if let Err(e) = METRICS.write() {
             error!("Failed to write metrics while stopping: {}", e);
@@ -349,6 +470,7 @@ impl Vmm {
         let memory_state = self.guest_memory().describe();
 
         Ok(MicrovmState {
+            extensions: StateExtensions::current(),
             vm_info: VmInfo { mem_size_mib },
             memory_state,
             vm_state,
@@ -431,22 +553,37 @@ impl Vmm {
     }
 
     /// Retrieves the KVM dirty bitmap for each of the guest's memory regions.
+    ///
+    /// The bitmaps of the memory slots are fetched concurrently on the helper threads.
     pub fn get_dirty_bitmap(&self) -> Result<DirtyBitmap> {
+        let (slots, tasks): (Vec<usize>, Vec<_>) = self.dirty_log_tasks().into_iter().unzip();
         let mut bitmap: DirtyBitmap = HashMap::new();
-        self.guest_memory.with_regions_mut(
-            |slot: usize, region: &GuestRegionMmap| -> Result<()> {
-                let bitmap_region = self
-                    .vm
-                    .fd()
-                    .get_dirty_log(slot as u32, region.len() as usize)
-                    .map_err(Error::DirtyBitmap)?;
-                bitmap.insert(slot, bitmap_region);
-                Ok(())
-            },
-        )?;
+        for (slot, bitmap_region) in slots.iter().zip(WORKER_POOL.run(tasks)) {
+            bitmap.insert(*slot, bitmap_region.map_err(Error::DirtyBitmap)?);
+        }
         Ok(bitmap)
     }
 
+    /// Returns the tasks fetching the KVM dirty bitmap of each of the guest's memory regions,
+    /// along with their memory slot, to be run on the helper threads.
+    pub fn dirty_log_tasks(&self) -> Vec<(usize, DirtyLogTask)> {
+        let mut slots = Vec::new();
+        let _: std::result::Result<(), ()> =
+            self.guest_memory
+                .with_regions_mut(|slot: usize, region: &GuestRegionMmap| {
+                    slots.push((slot, region.len() as usize));
+                    Ok(())
+                });
+        let vm_fd = self.vm.fd();
+        slots
+            .into_iter()
+            .map(|(slot, len)| {
+                let task = Box::new(move || vm_fd.get_dirty_log(slot as u32, len)) as DirtyLogTask;
+                (slot, task)
+            })
+            .collect()
+    }
+
     /// Enables or disables KVM dirty page tracking.
     pub fn set_dirty_page_tracking(&mut self, enable: bool) -> Result<()> {
         // This function _always_ results in an ioctl update. The VMM is stateless in the sense
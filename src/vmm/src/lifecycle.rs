@@ -0,0 +1,272 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Notifications of the microVM lifecycle transitions, sent to a scheduler.
+//!
+//! Each event is a JSON object on its own line, written to the Unix stream socket the scheduler
+//! listens on. The events emitted while the guest is paused can wait for the scheduler to
+//! acknowledge them with a line of its own, so that it acts before the guest runs again.
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use logger::{error, warn};
+use serde::Serialize;
+use utils::time::{get_time_us, ClockType};
+
+/// Time after which a write to a scheduler that does not read its socket is given up.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    /// Lifecycle notifications of the process. Events are dropped until it is initialized.
+    pub static ref LIFECYCLE: Lifecycle = Lifecycle::default();
+}
+
+/// Errors associated with the lifecycle notifications.
+#[derive(Debug)]
+pub enum Error {
+    /// The lifecycle notifications are already initialized.
+    AlreadyInitialized,
+    /// Failed to connect to the scheduler socket.
+    Connect(PathBuf, io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            AlreadyInitialized => write!(f, "The lifecycle notifications are already initialized"),
+            Connect(path, err) => write!(f, "Cannot connect to {}: {}", path.display(), err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Lifecycle transitions of the microVM.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LifecycleEvent {
+    /// A snapshot of the paused microVM was created.
+    SnapshotCreated,
+    /// A snapshot started loading.
+    RestoreStarted,
+    /// The working set of the snapshot is loaded into guest memory.
+    WsLoadComplete,
+    /// The working set load passed its deadline or was cancelled, leaving the rest of the
+    /// working set to fault in lazily.
+    WsLoadStopped,
+    /// The working set load failed, failing the snapshot load.
+    WsLoadFailed,
+    /// The vCPUs were resumed.
+    GuestResumed,
+    /// Enough of the working set of the restored microVM is resident for it to take its full
+    /// share of the load.
+    FullyWarmed,
+    /// The VMM is exiting.
+    GuestShutdown,
+}
+
+impl LifecycleEvent {
+    // Returns true if the guest is paused when the event is emitted.
+    fn before_resume(self) -> bool {
+        match self {
+            LifecycleEvent::SnapshotCreated
+            | LifecycleEvent::RestoreStarted
+            | LifecycleEvent::WsLoadComplete
+            | LifecycleEvent::WsLoadStopped
+            | LifecycleEvent::WsLoadFailed => true,
+            LifecycleEvent::GuestResumed
+            | LifecycleEvent::FullyWarmed
+            | LifecycleEvent::GuestShutdown => false,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EventRecord<'a> {
+    timestamp_us: u64,
+    instance_id: &'a str,
+    event: LifecycleEvent,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+    ack: bool,
+}
+
+struct EventSink {
+    stream: UnixStream,
+    acks: Option<BufReader<UnixStream>>,
+    instance_id: String,
+}
+
+/// Sink of the lifecycle events.
+#[derive(Default)]
+pub struct Lifecycle {
+    sink: Mutex<Option<EventSink>>,
+}
+
+impl Lifecycle {
+    /// Connects to the scheduler listening on the Unix socket at `path`. Events are attributed
+    /// to the `instance_id` microVM. With an `ack_timeout`, the events emitted while the guest
+    /// is paused wait up to that long for the scheduler to acknowledge them.
+    pub fn init(
+        &self,
+        path: &Path,
+        instance_id: &str,
+        ack_timeout: Option<Duration>,
+    ) -> Result<()> {
+        let mut sink = self.sink.lock().expect("Poisoned lock");
+        if sink.is_some() {
+            return Err(Error::AlreadyInitialized);
+        }
+
+        let connect_error = |e| Error::Connect(path.to_path_buf(), e);
+        let stream = UnixStream::connect(path).map_err(connect_error)?;
+        stream
+            .set_write_timeout(Some(WRITE_TIMEOUT))
+            .map_err(connect_error)?;
+        let acks = match ack_timeout {
+            Some(timeout) => {
+                stream
+                    .set_read_timeout(Some(timeout))
+                    .map_err(connect_error)?;
+                Some(BufReader::new(stream.try_clone().map_err(connect_error)?))
+            }
+            None => None,
+        };
+        *sink = Some(EventSink {
+            stream,
+            acks,
+            instance_id: instance_id.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Returns true if the lifecycle notifications are initialized.
+    pub fn is_enabled(&self) -> bool {
+        self.sink.lock().expect("Poisoned lock").is_some()
+    }
+
+    /// Notifies the scheduler of `event`, waiting for its acknowledgment if the guest is
+    /// paused and acknowledgments are enabled.
+    pub fn notify(&self, event: LifecycleEvent) {
+        self.emit(event, None);
+    }
+
+    /// Notifies the scheduler that the VMM exits with `exit_code`.
+    pub fn notify_shutdown(&self, exit_code: i32) {
+        self.emit(LifecycleEvent::GuestShutdown, Some(exit_code));
+    }
+
+    fn emit(&self, event: LifecycleEvent, exit_code: Option<i32>) {
+        let mut guard = self.sink.lock().expect("Poisoned lock");
+        let sink = match guard.as_mut() {
+            Some(sink) => sink,
+            None => return,
+        };
+
+        let ack = sink.acks.is_some() && event.before_resume();
+        let record = EventRecord {
+            timestamp_us: get_time_us(ClockType::Monotonic),
+            instance_id: &sink.instance_id,
+            event,
+            exit_code,
+            ack,
+        };
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(err) => {
+                error!("Cannot serialize lifecycle event: {}", err);
+                return;
+            }
+        };
+        line.push(b'\n');
+        if let Err(err) = sink.stream.write_all(&line) {
+            error!("Cannot write lifecycle event: {}", err);
+            return;
+        }
+
+        if let (true, Some(acks)) = (ack, sink.acks.as_mut()) {
+            let mut reply = String::new();
+            match acks.read_line(&mut reply) {
+                Ok(0) => error!("The scheduler closed the lifecycle socket"),
+                Ok(_) => (),
+                Err(err) => warn!("Lifecycle event {:?} not acknowledged: {}", event, err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use utils::tempdir::TempDir;
+
+    #[test]
+    fn test_notify() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("lifecycle.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let lifecycle = Lifecycle::default();
+
+        // Events are dropped until the notifications are initialized.
+        assert!(!lifecycle.is_enabled());
+        lifecycle.notify(LifecycleEvent::RestoreStarted);
+
+        // The scheduler acknowledges the events that ask for it.
+        let scheduler = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut events = Vec::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+                if event["ack"] == true {
+                    (&stream).write_all(b"ok\n").unwrap();
+                }
+                events.push(event);
+                line.clear();
+            }
+            events
+        });
+
+        lifecycle
+            .init(&path, "vm0", Some(Duration::from_secs(5)))
+            .unwrap();
+        assert!(lifecycle.is_enabled());
+        match lifecycle.init(&path, "vm0", None) {
+            Err(Error::AlreadyInitialized) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        lifecycle.notify(LifecycleEvent::RestoreStarted);
+        lifecycle.notify(LifecycleEvent::GuestResumed);
+        lifecycle.notify_shutdown(0);
+        // Closing the socket ends the scheduler.
+        *lifecycle.sink.lock().unwrap() = None;
+
+        let events = scheduler.join().unwrap();
+        let expected = [
+            ("restore-started", true),
+            ("guest-resumed", false),
+            ("guest-shutdown", false),
+        ];
+        assert_eq!(events.len(), expected.len());
+        for (event, (name, ack)) in events.iter().zip(expected.iter()) {
+            assert_eq!(event["instance_id"], "vm0");
+            assert_eq!(event["event"], *name);
+            assert_eq!(event["ack"], *ack);
+        }
+        assert_eq!(events[2]["exit_code"], 0);
+        assert!(events[0].get("exit_code").is_none());
+    }
+}
@@ -0,0 +1,382 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reads of the guest memory of a running or paused microVM, for debugging.
+//!
+//! Diagnosing a corrupted restore takes looking at a few guest pages, which would otherwise take
+//! dumping a whole snapshot. The reads are disabled unless a token is set at launch, and each
+//! request presents the token as a bearer credential. A read is at most `MAX_READ_LEN` bytes, and
+//! the reads share a budget of `READ_BUDGET_BYTES` per `READ_BUDGET_REFILL_MS`, so that they
+//! cannot copy the guest memory out wholesale.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use rate_limiter::{BucketReduction, TokenBucket};
+use serde::{Deserialize, Serialize};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+lazy_static! {
+    /// Guest memory debug reads of the process, disabled until a token is set.
+    pub static ref MEMORY_DEBUG: MemoryDebug = MemoryDebug::default();
+}
+
+/// Maximum length of a read, in bytes.
+pub const MAX_READ_LEN: u64 = 1 << 20;
+/// Bytes the reads can return within `READ_BUDGET_REFILL_MS`.
+pub const READ_BUDGET_BYTES: u64 = 4 << 20;
+/// Time the read budget takes to refill entirely, in milliseconds.
+pub const READ_BUDGET_REFILL_MS: u64 = 1000;
+
+const BEARER_PREFIX: &str = "Bearer ";
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Errors associated with the guest memory debug reads.
+#[derive(Debug)]
+pub enum Error {
+    /// The token is already set.
+    AlreadyInitialized,
+    /// The reads are disabled, no token was set at launch.
+    Disabled,
+    /// The token file is empty.
+    EmptyToken(PathBuf),
+    /// The read is longer than `MAX_READ_LEN`.
+    TooLong(u64),
+    /// The range is not entirely in the guest memory.
+    OutOfRange(u64, u64, GuestMemoryError),
+    /// The read exceeds the remaining read budget.
+    RateLimited(u64),
+    /// Failed to read the token file.
+    Token(PathBuf, io::Error),
+    /// The request does not present the token.
+    Unauthorized,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            AlreadyInitialized => write!(f, "The guest memory debug token is already set"),
+            Disabled => write!(f, "The guest memory debug reads are disabled"),
+            EmptyToken(path) => write!(f, "The debug token file {} is empty", path.display()),
+            TooLong(len) => write!(
+                f,
+                "Cannot read {:#x} bytes of guest memory, the reads are limited to {:#x} bytes",
+                len, MAX_READ_LEN
+            ),
+            OutOfRange(addr, len, err) => write!(
+                f,
+                "Cannot read {:#x} bytes of guest memory at {:#x}: {}",
+                len, addr, err
+            ),
+            RateLimited(len) => write!(
+                f,
+                "Cannot read {:#x} more bytes of guest memory before the read budget refills",
+                len
+            ),
+            Token(path, err) => write!(
+                f,
+                "Cannot read the debug token file {}: {}",
+                path.display(),
+                err
+            ),
+            Unauthorized => write!(f, "The request does not present the debug token"),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Guest physical range to read.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GuestMemoryReadParams {
+    /// Guest physical address of the range.
+    pub guest_addr: u64,
+    /// Length of the range, in bytes.
+    pub len: u64,
+}
+
+/// Content of a guest physical range.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct GuestMemoryContent {
+    /// Guest physical address of the range.
+    pub guest_addr: u64,
+    /// Length of the range, in bytes.
+    pub len: u64,
+    /// Base64 encoded content of the range.
+    pub data: String,
+}
+
+struct DebugAccess {
+    token: Vec<u8>,
+    budget: Option<TokenBucket>,
+}
+
+/// Access control of the guest memory debug reads.
+#[derive(Default)]
+pub struct MemoryDebug {
+    access: Mutex<Option<DebugAccess>>,
+}
+
+impl MemoryDebug {
+    /// Enables the reads, for the requests presenting the token in the file at `path`. Leading
+    /// and trailing whitespace is not part of the token.
+    pub fn init(&self, path: &Path) -> Result<()> {
+        let mut access = self.access.lock().expect("Poisoned lock");
+        if access.is_some() {
+            return Err(Error::AlreadyInitialized);
+        }
+
+        let content =
+            std::fs::read_to_string(path).map_err(|e| Error::Token(path.to_path_buf(), e))?;
+        let token = content.trim();
+        if token.is_empty() {
+            return Err(Error::EmptyToken(path.to_path_buf()));
+        }
+        *access = Some(DebugAccess {
+            token: token.as_bytes().to_vec(),
+            budget: TokenBucket::new(READ_BUDGET_BYTES, 0, READ_BUDGET_REFILL_MS),
+        });
+        Ok(())
+    }
+
+    /// Checks that `authorization`, the value of the `Authorization` header of a request, is
+    /// the bearer token.
+    pub fn authorize(&self, authorization: Option<&str>) -> Result<()> {
+        let access = self.access.lock().expect("Poisoned lock");
+        let access = access.as_ref().ok_or(Error::Disabled)?;
+        let presented = authorization
+            .filter(|value| value.starts_with(BEARER_PREFIX))
+            .map(|value| value[BEARER_PREFIX.len()..].trim().as_bytes())
+            .ok_or(Error::Unauthorized)?;
+        if !constant_time_eq(presented, &access.token) {
+            return Err(Error::Unauthorized);
+        }
+        Ok(())
+    }
+
+    /// Reads the range of `guest_memory` described by `params`, charging it to the read budget.
+    pub fn read(
+        &self,
+        guest_memory: &GuestMemoryMmap,
+        params: &GuestMemoryReadParams,
+    ) -> Result<GuestMemoryContent> {
+        if params.len > MAX_READ_LEN {
+            return Err(Error::TooLong(params.len));
+        }
+
+        // The read is charged before the guest memory is copied, and refunded if it fails, so
+        // that only the reads served are charged.
+        self.with_budget(|budget| match budget.reduce(params.len) {
+            BucketReduction::Failure => Err(Error::RateLimited(params.len)),
+            _ => Ok(()),
+        })?;
+        let mut buf = vec![0u8; params.len as usize];
+        if let Err(err) = guest_memory.read_slice(&mut buf, GuestAddress(params.guest_addr)) {
+            self.with_budget(|budget| {
+                budget.replenish(params.len);
+                Ok(())
+            })?;
+            return Err(Error::OutOfRange(params.guest_addr, params.len, err));
+        }
+        Ok(GuestMemoryContent {
+            guest_addr: params.guest_addr,
+            len: params.len,
+            data: base64_encode(&buf),
+        })
+    }
+
+    // Runs `f` on the read budget, if any.
+    fn with_budget<F: FnOnce(&mut TokenBucket) -> Result<()>>(&self, f: F) -> Result<()> {
+        let mut access = self.access.lock().expect("Poisoned lock");
+        let access = access.as_mut().ok_or(Error::Disabled)?;
+        match access.budget.as_mut() {
+            Some(budget) => f(budget),
+            None => Ok(()),
+        }
+    }
+}
+
+// Compares the two byte strings in a time independent of their content.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+// Encodes `data` in the standard, padded, base64 alphabet.
+fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let group = (u32::from(bytes[0]) << 16) | (u32::from(bytes[1]) << 8) | u32::from(bytes[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - 6 * i)) & 0x3f;
+                encoded.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use utils::tempfile::TempFile;
+
+    fn token_file(content: &str) -> TempFile {
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_error_display() {
+        let path = PathBuf::from("token");
+        let errors = vec![
+            Error::AlreadyInitialized,
+            Error::Disabled,
+            Error::EmptyToken(path.clone()),
+            Error::TooLong(MAX_READ_LEN + 1),
+            Error::OutOfRange(0, 1, GuestMemoryError::InvalidGuestAddress(GuestAddress(0))),
+            Error::RateLimited(MAX_READ_LEN),
+            Error::Token(path, io::Error::from_raw_os_error(libc::ENOENT)),
+            Error::Unauthorized,
+        ];
+        for err in errors {
+            let _ = format!("{}{:?}", err, err);
+        }
+    }
+
+    #[test]
+    fn test_base64_encode() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_encode(&[0xff, 0xfe, 0x00]), "//4A");
+    }
+
+    #[test]
+    fn test_authorize() {
+        let debug = MemoryDebug::default();
+        match debug.authorize(Some("Bearer secret")) {
+            Err(Error::Disabled) => (),
+            _ => panic!("The reads should be disabled without a token."),
+        }
+
+        let empty = token_file(" \n");
+        match debug.init(empty.as_path()) {
+            Err(Error::EmptyToken(_)) => (),
+            _ => panic!("Empty tokens should be rejected."),
+        }
+
+        let token = token_file("secret\n");
+        debug.init(token.as_path()).unwrap();
+        match debug.init(token.as_path()) {
+            Err(Error::AlreadyInitialized) => (),
+            _ => panic!("The token should only be set once."),
+        }
+
+        debug.authorize(Some("Bearer secret")).unwrap();
+        for authorization in &[
+            None,
+            Some("secret"),
+            Some("Bearer secre"),
+            Some("Basic secret"),
+        ] {
+            match debug.authorize(*authorization) {
+                Err(Error::Unauthorized) => (),
+                _ => panic!("{:?} should not be authorized.", authorization),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read() {
+        let mem_len = MAX_READ_LEN as usize;
+        let guest_memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), mem_len)]).unwrap();
+        guest_memory
+            .write_slice(b"foobar", GuestAddress(0x1000))
+            .unwrap();
+        let debug = MemoryDebug::default();
+        let params = GuestMemoryReadParams {
+            guest_addr: 0x1000,
+            len: 6,
+        };
+        match debug.read(&guest_memory, &params) {
+            Err(Error::Disabled) => (),
+            _ => panic!("The reads should be disabled without a token."),
+        }
+
+        let token = token_file("secret");
+        debug.init(token.as_path()).unwrap();
+        assert_eq!(
+            debug.read(&guest_memory, &params).unwrap(),
+            GuestMemoryContent {
+                guest_addr: 0x1000,
+                len: 6,
+                data: "Zm9vYmFy".to_string(),
+            }
+        );
+
+        let params = GuestMemoryReadParams {
+            guest_addr: MAX_READ_LEN - 0x1000,
+            len: 0x2000,
+        };
+        match debug.read(&guest_memory, &params) {
+            Err(Error::OutOfRange(_, 0x2000, _)) => (),
+            _ => panic!("Ranges past the guest memory should be rejected."),
+        }
+
+        let params = GuestMemoryReadParams {
+            guest_addr: 0,
+            len: MAX_READ_LEN + 1,
+        };
+        match debug.read(&guest_memory, &params) {
+            Err(Error::TooLong(_)) => (),
+            _ => panic!("Reads longer than the limit should be rejected."),
+        }
+
+        // The budget runs out before the whole guest memory is read again and again.
+        let params = GuestMemoryReadParams {
+            guest_addr: 0,
+            len: MAX_READ_LEN,
+        };
+        let reads = 2 * (READ_BUDGET_BYTES / MAX_READ_LEN + 1);
+        let limited = (0..reads).any(|_| match debug.read(&guest_memory, &params) {
+            Err(Error::RateLimited(_)) => true,
+            _ => false,
+        });
+        assert!(limited);
+        // The budget is charged before the guest memory is read.
+        let params = GuestMemoryReadParams {
+            guest_addr: MAX_READ_LEN,
+            len: MAX_READ_LEN,
+        };
+        match debug.read(&guest_memory, &params) {
+            Err(Error::RateLimited(_)) => (),
+            _ => panic!("Reads past the budget should be rejected before reading."),
+        }
+    }
+}
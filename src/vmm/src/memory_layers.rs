@@ -0,0 +1,33 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Library interface of the snapshot guest memory and of its layers.
+//!
+//! Crates working on snapshots outside of the VMM, such as the snapshot tools and page fault
+//! handlers, depend on the items re-exported here rather than on the modules implementing them,
+//! which change along with the restore paths.
+//!
+//! The items follow [`API_VERSION`]: new items, trait methods with a default, and variants of
+//! [`Error`], which is `#[non_exhaustive]`, may be added within a version, while renaming or
+//! removing an item, changing a signature or the meaning of a field bumps it. Items due to go are
+//! marked `#[deprecated]` for a version beforehand. `GuestMemoryState` and
+//! `GuestMemoryRegionState` are also part of the snapshot format, and follow its versioning.
+
+// Currently only supports x86_64.
+#![cfg(target_arch = "x86_64")]
+
+pub use crate::backing_files::{file_name, Backing, BackingFiles, BACKING_FILES};
+#[cfg(feature = "faasnap")]
+pub use crate::memory_snapshot::UpfRegistration;
+pub use crate::memory_snapshot::{
+    layer_conflicts, restore_from_buffers, BufferLayers, Error, ExtentChunk,
+    GuestMemoryRegionState, GuestMemoryState, GuestRegionUffdMapping, SnapshotMemory,
+    MEMORY_BLOCK_SIZE,
+};
+pub use crate::probes::MmapLayer;
+pub use crate::snapshot_io::{stage, Checksummed, LocalFile, SnapshotIo, SnapshotWriter};
+#[cfg(feature = "faasnap")]
+pub use crate::ws_layout::Extent;
+
+/// Version of the items of this module.
+pub const API_VERSION: u32 = 1;
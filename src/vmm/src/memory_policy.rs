@@ -0,0 +1,121 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Transparent huge page and NUMA policies of restored guest memory.
+//!
+//! Restores on hosts with several NUMA nodes place the guest memory wherever its pages happen to
+//! be faulted in. The guest memory can instead prefer a node, the one the vCPUs are pinned to,
+//! with the pages faulted in at restore moved to it. It can also be marked for transparent huge
+//! pages, so that khugepaged collapses its anonymous 2 MiB ranges, regardless of the system-wide
+//! `madvise` mode.
+
+use std::io;
+
+use vm_memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+/// List of the online NUMA nodes of the host.
+pub const NODES_ONLINE_PATH: &str = "/sys/devices/system/node/online";
+
+// See include/uapi/linux/mempolicy.h in the kernel code.
+const MPOL_PREFERRED: libc::c_long = 1;
+const MPOL_MF_MOVE: libc::c_long = 1 << 1;
+
+/// Marks the whole of `guest_memory` for transparent huge pages.
+pub fn enable_huge_pages(guest_memory: &GuestMemoryMmap) -> io::Result<()> {
+    guest_memory.with_regions(|_, region| {
+        // Safe because advising huge pages does not modify the content of the memory.
+        let ret = unsafe {
+            libc::madvise(
+                region.as_ptr() as *mut libc::c_void,
+                region.len() as usize,
+                libc::MADV_HUGEPAGE,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    })
+}
+
+/// Makes the whole of `guest_memory` prefer NUMA node `node`, and moves the pages faulted in
+/// already to it, as far as it has free memory.
+pub fn prefer_node(guest_memory: &GuestMemoryMmap, node: u32) -> io::Result<()> {
+    let mut nodemask = vec![0u64; node as usize / 64 + 1];
+    nodemask[node as usize / 64] |= 1 << (node % 64);
+    guest_memory.with_regions(|_, region| {
+        // Safe because the kernel only reads `nodemask`, whose length in bits is passed, and
+        // setting the memory policy does not modify the content of the memory.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                region.as_ptr() as *mut libc::c_void,
+                region.len() as usize,
+                MPOL_PREFERRED,
+                nodemask.as_ptr(),
+                nodemask.len() * 64 + 1,
+                MPOL_MF_MOVE,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    })
+}
+
+/// Returns the online NUMA nodes of the host.
+pub fn online_nodes() -> io::Result<Vec<u32>> {
+    parse_node_list(&std::fs::read_to_string(NODES_ONLINE_PATH)?)
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))
+}
+
+// Parses a list of nodes such as `0-2,4`.
+fn parse_node_list(content: &str) -> Option<Vec<u32>> {
+    let mut nodes = Vec::new();
+    for range in content.trim().split(',') {
+        let mut bounds = range.splitn(2, '-');
+        let first: u32 = bounds.next()?.parse().ok()?;
+        let last: u32 = match bounds.next() {
+            Some(last) => last.parse().ok()?,
+            None => first,
+        };
+        nodes.extend(first..=last);
+    }
+    Some(nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::GuestAddress;
+
+    #[test]
+    fn test_memory_policies() {
+        let page_size = sysconf::page::pagesize();
+        let guest_memory = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 4 * page_size),
+            (GuestAddress(8 * page_size as u64), 4 * page_size),
+        ])
+        .unwrap();
+        // Kernels built without transparent huge pages reject the advice.
+        match enable_huge_pages(&guest_memory) {
+            Ok(()) => (),
+            Err(err) => assert_eq!(err.raw_os_error(), Some(libc::EINVAL)),
+        }
+        // Node 0 is the only node of the hosts without NUMA, when built with NUMA support.
+        match prefer_node(&guest_memory, 0) {
+            Ok(()) => (),
+            Err(err) => assert_eq!(err.raw_os_error(), Some(libc::ENOSYS)),
+        }
+    }
+
+    #[test]
+    fn test_parse_node_list() {
+        assert_eq!(parse_node_list("0\n"), Some(vec![0]));
+        assert_eq!(parse_node_list("0-2,4\n"), Some(vec![0, 1, 2, 4]));
+        assert_eq!(parse_node_list(""), None);
+        assert_eq!(parse_node_list("0-x"), None);
+    }
+}
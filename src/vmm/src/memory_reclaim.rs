@@ -0,0 +1,443 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reclamation of the guest memory of idle restored microVMs.
+//!
+//! A restored guest memory is mapped from the snapshot files, so the pages the guest only read
+//! can be dropped and mapped back from the page cache, or read back from the files, on the next
+//! access. Keeping hundreds of idle clones fully resident otherwise takes most of the host
+//! memory. The reclamation advises the kernel on the guest memory outside of the working set, or
+//! on all of it:
+//!
+//! - `DontNeed` unmaps the pages mapped from the snapshot files right away. The pages the guest
+//!   wrote to, and the anonymous guest memory, are left alone, since their content would be lost.
+//!   It takes the vCPUs to be paused, or a guest write could land between the check of a page and
+//!   its unmapping.
+//! - `Cold` makes the pages the first the kernel reclaims under memory pressure.
+//! - `PageOut` reclaims the pages right away, writing the anonymous ones to swap, if any.
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+use serde::{Deserialize, Serialize};
+use vm_memory::GuestMemoryMmap;
+
+use crate::memory_snapshot::GuestMemoryState;
+use crate::ws_accounting::{
+    self, PAGEMAP_BATCH, PAGEMAP_ENTRY_SIZE, PAGEMAP_PATH, PAGEMAP_PRESENT,
+};
+
+// See include/uapi/asm-generic/mman-common.h in the kernel code, these are not in libc yet.
+pub(crate) const MADV_COLD: libc::c_int = 20;
+pub(crate) const MADV_PAGEOUT: libc::c_int = 21;
+// The page is mapped from a file, or is shared anonymous memory, see pagemap in proc(5).
+const PAGEMAP_FILE: u64 = 1 << 61;
+
+/// Advice the guest memory is reclaimed with.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ReclaimAdvice {
+    /// Unmap the pages mapped from the snapshot files, with `MADV_DONTNEED`.
+    DontNeed,
+    /// Deactivate the pages, with `MADV_COLD`.
+    Cold,
+    /// Reclaim the pages, with `MADV_PAGEOUT`.
+    PageOut,
+}
+
+/// Parameters of a guest memory reclamation.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryReclaimParams {
+    /// Advice the guest memory is reclaimed with.
+    pub advice: ReclaimAdvice,
+    /// Reclaim the working set extents too.
+    #[serde(default)]
+    pub include_ws: bool,
+}
+
+/// Outcome of a guest memory reclamation.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ReclaimStats {
+    /// Bytes of guest memory advised.
+    pub advised_bytes: u64,
+    /// Bytes of guest memory mapped in the process before the reclamation.
+    pub mapped_bytes_before: u64,
+    /// Bytes of guest memory mapped in the process after the reclamation.
+    pub mapped_bytes_after: u64,
+}
+
+/// Errors associated with the guest memory reclamation.
+#[derive(Debug)]
+pub enum Error {
+    /// The kernel rejected the advice.
+    Advise(io::Error),
+    /// Only the guest memory of restored microVMs is reclaimed.
+    NotRestored,
+    /// Failed to read the pagemap of the process.
+    Pagemap(io::Error),
+    /// Unmapping the pages takes the vCPUs to be paused.
+    VcpusRunning,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Advise(err) => write!(f, "Cannot advise the guest memory: {}", err),
+            NotRestored => write!(
+                f,
+                "Only the guest memory of restored microVMs can be reclaimed"
+            ),
+            Pagemap(err) => write!(f, "Cannot read {}: {}", PAGEMAP_PATH, err),
+            VcpusRunning => write!(
+                f,
+                "The microVM must be paused to unmap its guest memory with DontNeed"
+            ),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Reclaims the guest memory of a restored microVM.
+pub struct MemoryReclaimer {
+    pagemap: File,
+    page_size: u64,
+    // Host ranges of the guest memory, as (address, length).
+    guest_ranges: Vec<(u64, u64)>,
+    // Host ranges of the guest memory outside of the working set extents.
+    cold_ranges: Vec<(u64, u64)>,
+}
+
+impl MemoryReclaimer {
+    /// Reclaims `guest_memory`, restored from `state`, whose working set is `ws_regions`.
+    pub fn new(
+        guest_memory: &GuestMemoryMmap,
+        state: &GuestMemoryState,
+        ws_regions: &[Vec<i64>],
+    ) -> io::Result<Self> {
+        let to_io =
+            |e: ws_accounting::Error| io::Error::new(io::ErrorKind::InvalidInput, e.to_string());
+        let guest_ranges = ws_accounting::guest_host_ranges(guest_memory, state).map_err(to_io)?;
+        let ws_ranges =
+            ws_accounting::ws_host_ranges(guest_memory, state, ws_regions).map_err(to_io)?;
+        Ok(MemoryReclaimer {
+            // Opened now, the VMM may not be allowed to open it once sandboxed.
+            pagemap: File::open(PAGEMAP_PATH)?,
+            page_size: sysconf::page::pagesize() as u64,
+            cold_ranges: subtract_ranges(&guest_ranges, &ws_ranges),
+            guest_ranges,
+        })
+    }
+
+    /// Reclaims the guest memory as described by `params`. `vcpus_running` tells whether the
+    /// guest may write to its memory meanwhile.
+    pub fn reclaim(
+        &self,
+        params: &MemoryReclaimParams,
+        vcpus_running: bool,
+    ) -> Result<ReclaimStats> {
+        if params.advice == ReclaimAdvice::DontNeed && vcpus_running {
+            return Err(Error::VcpusRunning);
+        }
+        let ranges = if params.include_ws {
+            &self.guest_ranges
+        } else {
+            &self.cold_ranges
+        };
+
+        let mapped_bytes_before = self.mapped_bytes()?;
+        let mut advised_bytes = 0;
+        for &(addr, len) in ranges.iter() {
+            advised_bytes += match params.advice {
+                ReclaimAdvice::DontNeed => self.unmap_file_pages(addr, len)?,
+                ReclaimAdvice::Cold => advise(addr, len, MADV_COLD).map(|_| len)?,
+                ReclaimAdvice::PageOut => advise(addr, len, MADV_PAGEOUT).map(|_| len)?,
+            };
+        }
+        Ok(ReclaimStats {
+            advised_bytes,
+            mapped_bytes_before,
+            mapped_bytes_after: self.mapped_bytes()?,
+        })
+    }
+
+    /// Returns the size of the guest memory outside of the working set extents.
+    pub fn cold_bytes(&self) -> u64 {
+        self.cold_ranges.iter().map(|(_, len)| len).sum()
+    }
+
+    /// Pages out at most `max_len` bytes of the guest memory outside of the working set extents,
+    /// starting `offset` bytes into it, and returns the size advised.
+    pub fn page_out_cold(&self, offset: u64, max_len: u64) -> Result<u64> {
+        let end = offset + max_len;
+        let mut position = 0;
+        let mut advised_bytes = 0;
+        for &(addr, len) in self.cold_ranges.iter() {
+            let start = std::cmp::max(offset, position);
+            let stop = std::cmp::min(end, position + len);
+            if start < stop {
+                advise(addr + start - position, stop - start, MADV_PAGEOUT)?;
+                advised_bytes += stop - start;
+            }
+            position += len;
+            if position >= end {
+                break;
+            }
+        }
+        Ok(advised_bytes)
+    }
+
+    // Unmaps the pages of the `len` bytes at `addr` mapped from a file, and returns their size.
+    fn unmap_file_pages(&self, addr: u64, len: u64) -> Result<u64> {
+        let mut runs = Vec::new();
+        let mut run_start = None;
+        self.for_each_entry(addr, len, |page_addr, entry| {
+            let file_page = entry & PAGEMAP_PRESENT != 0 && entry & PAGEMAP_FILE != 0;
+            match (file_page, run_start) {
+                (true, None) => run_start = Some(page_addr),
+                (false, Some(start)) => {
+                    runs.push((start, page_addr - start));
+                    run_start = None;
+                }
+                _ => (),
+            }
+        })?;
+        if let Some(start) = run_start {
+            runs.push((start, addr + len - start));
+        }
+
+        let mut unmapped = 0;
+        for (run_addr, run_len) in runs {
+            advise(run_addr, run_len, libc::MADV_DONTNEED)?;
+            unmapped += run_len;
+        }
+        Ok(unmapped)
+    }
+
+    // Returns the size of the guest memory mapped in the process.
+    fn mapped_bytes(&self) -> Result<u64> {
+        let mut mapped = 0;
+        for &(addr, len) in self.guest_ranges.iter() {
+            self.for_each_entry(addr, len, |_, entry| {
+                if entry & PAGEMAP_PRESENT != 0 {
+                    mapped += self.page_size;
+                }
+            })?;
+        }
+        Ok(mapped)
+    }
+
+    // Calls `f` with the address and the pagemap entry of each page of the `len` bytes at `addr`.
+    fn for_each_entry<F: FnMut(u64, u64)>(&self, addr: u64, len: u64, mut f: F) -> Result<()> {
+        let first_page = addr / self.page_size;
+        let page_count = len / self.page_size;
+        let mut entries = vec![0u8; (PAGEMAP_BATCH * PAGEMAP_ENTRY_SIZE) as usize];
+        let mut page = 0;
+        while page < page_count {
+            let batch = std::cmp::min(PAGEMAP_BATCH, page_count - page);
+            let buf = &mut entries[..(batch * PAGEMAP_ENTRY_SIZE) as usize];
+            self.pagemap
+                .read_exact_at(buf, (first_page + page) * PAGEMAP_ENTRY_SIZE)
+                .map_err(Error::Pagemap)?;
+            for (index, entry) in buf.chunks(PAGEMAP_ENTRY_SIZE as usize).enumerate() {
+                let mut bytes = [0u8; PAGEMAP_ENTRY_SIZE as usize];
+                bytes.copy_from_slice(entry);
+                f(
+                    addr + (page + index as u64) * self.page_size,
+                    u64::from_ne_bytes(bytes),
+                );
+            }
+            page += batch;
+        }
+        Ok(())
+    }
+}
+
+fn advise(addr: u64, len: u64, advice: libc::c_int) -> Result<()> {
+    // Safe because the range is part of the guest memory, and the advice only drops the pages
+    // whose content can be read back: from the page cache or the files they are mapped from, or
+    // from swap.
+    let ret = unsafe { libc::madvise(addr as _, len as usize, advice) };
+    if ret < 0 {
+        return Err(Error::Advise(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+// Returns the parts of the sorted, disjoint `ranges` outside of the `holes`, all given as
+// (address, length).
+fn subtract_ranges(ranges: &[(u64, u64)], holes: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut holes = holes.to_vec();
+    holes.sort();
+    let mut remaining = Vec::new();
+    for &(addr, len) in ranges.iter() {
+        let end = addr + len;
+        let mut start = addr;
+        for &(hole_addr, hole_len) in holes.iter() {
+            let hole_end = hole_addr + hole_len;
+            if hole_end <= start || hole_addr >= end {
+                continue;
+            }
+            if hole_addr > start {
+                remaining.push((start, hole_addr - start));
+            }
+            start = std::cmp::max(start, hole_end);
+        }
+        if start < end {
+            remaining.push((start, end - start));
+        }
+    }
+    remaining
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use utils::tempfile::TempFile;
+    use vm_memory::{Bytes, GuestAddress};
+
+    use crate::memory_snapshot::SnapshotMemory;
+
+    #[test]
+    fn test_subtract_ranges() {
+        assert_eq!(subtract_ranges(&[(0, 10)], &[]), vec![(0, 10)]);
+        assert_eq!(
+            subtract_ranges(&[(0, 10), (20, 10)], &[(22, 2), (2, 3), (8, 4)]),
+            vec![(0, 2), (5, 3), (20, 2), (24, 6)]
+        );
+        assert!(subtract_ranges(&[(0, 10)], &[(0, 10)]).is_empty());
+        assert_eq!(
+            subtract_ranges(&[(10, 10)], &[(0, 5), (25, 5)]),
+            vec![(10, 10)]
+        );
+    }
+
+    #[test]
+    fn test_reclaim() {
+        let page_size = sysconf::page::pagesize();
+        let guest_memory =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 8 * page_size)]).unwrap();
+        let state = guest_memory.describe();
+        let reclaimer = MemoryReclaimer::new(&guest_memory, &state, &[vec![2, 2]]).unwrap();
+        assert_eq!(reclaimer.guest_ranges.len(), 1);
+        assert_eq!(reclaimer.cold_ranges.len(), 2);
+
+        // The guest memory is anonymous, its pages are never unmapped.
+        guest_memory.write_obj(1u8, GuestAddress(0)).unwrap();
+        let params = MemoryReclaimParams {
+            advice: ReclaimAdvice::DontNeed,
+            include_ws: true,
+        };
+        match reclaimer.reclaim(&params, true) {
+            Err(Error::VcpusRunning) => (),
+            _ => panic!("Unmapping should take the vCPUs to be paused."),
+        }
+        let stats = reclaimer.reclaim(&params, false).unwrap();
+        assert_eq!(stats.advised_bytes, 0);
+        assert_eq!(stats.mapped_bytes_before, page_size as u64);
+        assert_eq!(stats.mapped_bytes_after, page_size as u64);
+        assert_eq!(guest_memory.read_obj::<u8>(GuestAddress(0)).unwrap(), 1);
+
+        // Kernels before 5.4 do not know the advice.
+        let params = MemoryReclaimParams {
+            advice: ReclaimAdvice::Cold,
+            include_ws: false,
+        };
+        if let Ok(stats) = reclaimer.reclaim(&params, true) {
+            assert_eq!(stats.advised_bytes, 6 * page_size as u64);
+        }
+    }
+
+    #[test]
+    fn test_page_out_cold() {
+        let page_size = sysconf::page::pagesize();
+        let guest_memory =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 8 * page_size)]).unwrap();
+        let state = guest_memory.describe();
+        // The cold memory is made of pages 0 and 1, then 4 to 7.
+        let reclaimer = MemoryReclaimer::new(&guest_memory, &state, &[vec![2, 2]]).unwrap();
+        let page_size = page_size as u64;
+        assert_eq!(reclaimer.cold_bytes(), 6 * page_size);
+
+        // Nothing is left past the end of the cold memory.
+        assert_eq!(
+            reclaimer.page_out_cold(6 * page_size, page_size).unwrap(),
+            0
+        );
+        // Kernels before 5.4 do not know the advice.
+        if let Ok(advised_bytes) = reclaimer.page_out_cold(page_size, 3 * page_size) {
+            assert_eq!(advised_bytes, 3 * page_size);
+            assert_eq!(
+                reclaimer
+                    .page_out_cold(4 * page_size, 4 * page_size)
+                    .unwrap(),
+                2 * page_size
+            );
+        }
+    }
+
+    #[test]
+    fn test_unmap_file_pages() {
+        let page_size = sysconf::page::pagesize();
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&vec![1u8; 4 * page_size]).unwrap();
+        // Safe because the mapping is checked, and unmapped at the end of the test.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                4 * page_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE,
+                std::os::unix::io::AsRawFd::as_raw_fd(file.as_file()),
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        let pages = addr as *mut u8;
+        unsafe {
+            // Read from the file.
+            assert_eq!(*pages, 1);
+            assert_eq!(*pages.add(page_size), 1);
+            // Copied on write.
+            *pages.add(2 * page_size) = 2;
+        }
+
+        let reclaimer = MemoryReclaimer {
+            pagemap: File::open(PAGEMAP_PATH).unwrap(),
+            page_size: page_size as u64,
+            guest_ranges: vec![(addr as u64, 4 * page_size as u64)],
+            cold_ranges: vec![],
+        };
+        assert_eq!(reclaimer.mapped_bytes().unwrap(), 3 * page_size as u64);
+        let unmapped = reclaimer
+            .unmap_file_pages(addr as u64, 4 * page_size as u64)
+            .unwrap();
+        assert_eq!(unmapped, 2 * page_size as u64);
+        assert_eq!(reclaimer.mapped_bytes().unwrap(), page_size as u64);
+        unsafe {
+            // Mapped back from the file, and the write is kept.
+            assert_eq!(*pages, 1);
+            assert_eq!(*pages.add(2 * page_size), 2);
+            libc::munmap(addr, 4 * page_size);
+        }
+    }
+
+    #[test]
+    fn test_error_display() {
+        let errors = vec![
+            Error::Advise(io::Error::from_raw_os_error(libc::EINVAL)),
+            Error::NotRestored,
+            Error::Pagemap(io::Error::from_raw_os_error(libc::EPERM)),
+            Error::VcpusRunning,
+        ];
+        for err in errors {
+            let _ = format!("{}{:?}", err, err);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Periodic sampling of the resident guest memory.
+//!
+//! A restored guest memory is backed lazily by the snapshot files, so its resident size grows as
+//! the guest touches it. Sampling it shows how a restored microVM fills in, and a residency that
+//! keeps growing on an idle guest points at a prefetcher that never stops.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use logger::{error, warn, METRICS};
+use polly::event_manager::{EventManager, Subscriber};
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
+use utils::epoll::{EpollEvent, EventSet};
+use vm_memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap};
+
+use crate::ksm;
+
+/// Period of the guest memory residency samples.
+pub const MEMORY_RESIDENCY_PERIOD_MS: u64 = 10000;
+
+/// Samples the resident guest memory to the `memory_residency` metrics.
+pub struct MemoryResidency {
+    guest_memory: GuestMemoryMmap,
+    page_size: usize,
+    timer: TimerFd,
+}
+
+impl MemoryResidency {
+    /// Creates a sampler of the `guest_memory` residency.
+    pub fn new(guest_memory: GuestMemoryMmap) -> io::Result<Self> {
+        Ok(MemoryResidency {
+            guest_memory,
+            page_size: sysconf::page::pagesize(),
+            timer: TimerFd::new_custom(ClockId::Monotonic, true, true)?,
+        })
+    }
+
+    /// Starts sampling periodically, beginning with a sample straight away.
+    pub fn start(&mut self) {
+        let period = Duration::from_millis(MEMORY_RESIDENCY_PERIOD_MS);
+        self.timer.set_state(
+            TimerState::Periodic {
+                current: period,
+                interval: period,
+            },
+            SetTimeFlags::Default,
+        );
+        self.update_metrics();
+    }
+
+    /// Returns the number of resident guest memory bytes.
+    pub fn sample(&self) -> io::Result<usize> {
+        let pages = self.guest_memory.map_and_fold(
+            Ok(0),
+            |(_, region)| self.resident_pages(region),
+            |a: io::Result<usize>, b| a.and_then(|a| b.map(|b| a + b)),
+        )?;
+        Ok(pages * self.page_size)
+    }
+
+    fn resident_pages(&self, region: &GuestRegionMmap) -> io::Result<usize> {
+        let len = region.len() as usize;
+        let mut residency = vec![0u8; (len + self.page_size - 1) / self.page_size];
+        // Safe because the region is mapped for as long as `guest_memory` holds it, and
+        // `residency` has a byte for each of its pages.
+        let ret = unsafe {
+            libc::mincore(
+                region.as_ptr() as *mut libc::c_void,
+                len,
+                residency.as_mut_ptr(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(residency.iter().filter(|page| *page & 1 != 0).count())
+    }
+
+    fn update_metrics(&self) {
+        match self.sample() {
+            Ok(resident_bytes) => {
+                let total_bytes = self.guest_memory.map_and_fold(
+                    0,
+                    |(_, region)| region.len() as usize,
+                    |a, b| a + b,
+                );
+                METRICS.memory_residency.resident_bytes.set(resident_bytes);
+                METRICS.memory_residency.total_bytes.set(total_bytes);
+            }
+            Err(err) => error!("Cannot sample the guest memory residency: {}", err),
+        }
+        // Kernels before 6.1 do not report the merged pages.
+        if let Ok(pages) = ksm::merging_pages() {
+            METRICS
+                .memory_residency
+                .ksm_merged_bytes
+                .set(pages * self.page_size);
+        }
+    }
+}
+
+impl Subscriber for MemoryResidency {
+    /// Handle a read event (EPOLLIN).
+    fn process(&mut self, event: &EpollEvent, _: &mut EventManager) {
+        let source = event.fd();
+        let event_set = event.event_set();
+
+        if !EventSet::IN.contains(event_set) {
+            warn!(
+                "Received unknown event: {:?} from source: {:?}",
+                event_set, source
+            );
+            return;
+        }
+
+        if source == self.timer.as_raw_fd() {
+            // Consume the timer expirations.
+            self.timer.read();
+            self.update_metrics();
+        } else {
+            error!("Spurious EventManager event for handler: MemoryResidency");
+        }
+    }
+
+    fn interest_list(&self) -> Vec<EpollEvent> {
+        vec![EpollEvent::new(EventSet::IN, self.timer.as_raw_fd() as u64)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::GuestAddress;
+
+    #[test]
+    fn test_sample() {
+        let page_size = sysconf::page::pagesize();
+        let guest_memory =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 4 * page_size)]).unwrap();
+        let residency = MemoryResidency::new(guest_memory.clone()).unwrap();
+        assert_eq!(residency.sample().unwrap(), 0);
+
+        let host_addr = guest_memory.get_host_address(GuestAddress(0)).unwrap();
+        unsafe {
+            *host_addr = 1;
+            *host_addr.add(2 * page_size) = 1;
+        }
+        assert_eq!(residency.sample().unwrap(), 2 * page_size);
+    }
+}
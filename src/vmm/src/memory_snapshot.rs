@@ -8,27 +8,84 @@
 
 use std::fmt::{Display, Formatter};
 use std::fs::File;
-use std::io::SeekFrom;
+use std::io::{Read, SeekFrom};
 use std::io;
 use std::collections::HashMap;
 use std::ptr::null_mut;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::thread;
 
-use libc::printf;
-use logger::info;
+#[cfg(feature = "faasnap")]
+use lazy_static::lazy_static;
+use logger::{debug_category, warn, DebugCategory, Metric, METRICS};
+use utils::time::{get_time_ns, get_time_us, ClockType};
 // for userfaultfd
-use std::path::PathBuf;
+#[cfg(feature = "faasnap")]
+use passfd::FdPassingExt;
+use serde::Serialize;
+use std::os::unix::fs::FileExt;
+#[cfg(feature = "faasnap")]
+use std::os::unix::fs::FileTypeExt;
 use std::os::unix::io::AsRawFd;
-use std::os::unix::net::UnixListener;
+#[cfg(feature = "faasnap")]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(feature = "faasnap")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "faasnap")]
 use userfaultfd::UffdBuilder;
-use passfd::FdPassingExt;
+#[cfg(feature = "faasnap")]
+use utils::sock_ctrl_msg::ScmSocket;
 
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::{Bytes, FileOffset, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap, MemoryRegionAddress, MmapRegion, mmap};
 
+#[cfg(feature = "faasnap")]
+use crate::audit::{AuditEvent, AuditFile, PeerCredentials, AUDIT};
+use crate::backing_files::{self, BackingFiles, BACKING_FILES};
+use crate::dump_copy;
+use crate::otel::OTEL;
+use crate::probes::{self, MmapLayer};
+use crate::prefetch_coordinator::PrefetchCoordinator;
+use crate::prefetch_tuning::PrefetchTuning;
+use crate::psi::PrefetchThrottle;
+use crate::restore_trace::{RestorePhase, RESTORE_TRACE};
+#[cfg(feature = "faasnap")]
+use crate::restore_watchdog::WatchedOperation;
+use crate::restore_watchdog::RESTORE_WATCHDOG;
+use crate::snapshot_io;
+#[cfg(feature = "faasnap")]
+use crate::socket_activation::{SOCKET_ACTIVATION, UFFD_SOCKET_NAME};
+use crate::vmm_config::snapshot::ScrubRange;
+use crate::worker_pool::{Task, WORKER_POOL};
+use crate::ws_load_control::{StopReason, WS_LOAD};
 use crate::DirtyBitmap;
 
+#[cfg(feature = "faasnap")]
+lazy_static! {
+    // Host ranges of the guest memory registered for user page faults, as (address, length).
+    static ref UPF_REGISTERED: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+}
+
+/// Granularity of the guest memory added at restore. This is the size of the x86 Linux memory
+/// blocks, which the guest onlines one at a time.
+pub const MEMORY_BLOCK_SIZE: usize = 128 << 20;
+
+// Memory mappings of the process, one per line.
+const MAPS_PATH: &str = "/proc/self/maps";
+// Maximum number of memory mappings of a process.
+const MAX_MAP_COUNT_PATH: &str = "/proc/sys/vm/max_map_count";
+// Memory usage of the host.
+const MEMINFO_PATH: &str = "/proc/meminfo";
+// Overcommit policy of the host, 2 to never overcommit.
+const OVERCOMMIT_MEMORY_PATH: &str = "/proc/sys/vm/overcommit_memory";
+// `preadv2` flag failing with `EAGAIN` rather than waiting for I/O, not in this libc.
+const RWF_NOWAIT: libc::c_int = 0x8;
+/// Alignment of the host address of the restored guest memory regions, the size of the x86
+/// transparent huge pages.
+pub const HUGE_PAGE_SIZE: usize = 2 << 20;
+
 /// State of a guest memory region saved to file/buffer.
 #[derive(Debug, PartialEq, Versionize)]
 pub struct GuestMemoryRegionState {
@@ -45,6 +102,134 @@ pub struct GuestMemoryRegionState {
 pub struct GuestMemoryState {
     /// List of regions.
     pub regions: Vec<GuestMemoryRegionState>,
+    /// Size in bytes of the host pages the snapshot was taken with, the unit of the dirty
+    /// bitmaps and of the overlay and working set extents. Snapshots predating the field were
+    /// taken on 4K hosts.
+    #[version(start = 2, default_fn = "default_page_size")]
+    pub page_size: usize,
+}
+
+/// Guest memory region described to the page fault handler, in the upstream Firecracker
+/// handshake.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct GuestRegionUffdMapping {
+    /// Host virtual address of the start of the region.
+    pub base_host_virt_addr: u64,
+    /// Region size.
+    pub size: usize,
+    /// Offset of the region in the memory file.
+    pub offset: u64,
+}
+
+/// Part of a memory file extent that falls inside a single guest memory region.
+#[derive(Debug, PartialEq)]
+pub struct ExtentChunk {
+    /// Index of the region in `GuestMemoryState::regions`.
+    pub region_index: usize,
+    /// Offset of the chunk from the start of the region.
+    pub region_offset: u64,
+    /// Chunk length in bytes.
+    pub len: u64,
+}
+
+impl GuestMemoryState {
+    fn default_page_size(_: u16) -> usize {
+        4096
+    }
+
+    /// Checks that the snapshot was taken with the page size of the host, which the dirty
+    /// bitmaps and the overlay and working set extents are counted in.
+    pub fn check_page_size(&self) -> std::result::Result<(), Error> {
+        let host_page_size = sysconf::page::pagesize();
+        if self.page_size != host_page_size {
+            return Err(Error::PageSize(self.page_size, host_page_size));
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the memory file the regions are saved to.
+    pub fn file_size(&self) -> u64 {
+        self.regions
+            .iter()
+            .map(|region| region.offset + region.size as u64)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Splits the `[file_offset, file_offset + len)` extent of the memory file into the
+    /// chunks covered by each region.
+    ///
+    /// Overlay and working set extents are expressed as memory file offsets. These only
+    /// match guest physical addresses below the x86 MMIO gap, so every extent goes through
+    /// this translation before being mapped.
+    pub fn translate_extent(
+        &self,
+        file_offset: u64,
+        len: u64,
+    ) -> std::result::Result<Vec<ExtentChunk>, Error> {
+        let end = file_offset
+            .checked_add(len)
+            .ok_or(Error::InvalidExtent(file_offset, len))?;
+        let mut chunks = Vec::new();
+        let mut cur = file_offset;
+
+        while cur < end {
+            let (region_index, region) = self
+                .regions
+                .iter()
+                .enumerate()
+                .find(|(_, region)| {
+                    region.offset <= cur && cur < region.offset + region.size as u64
+                })
+                .ok_or(Error::InvalidExtent(file_offset, len))?;
+            let region_end = region.offset + region.size as u64;
+            let chunk_len = std::cmp::min(end, region_end) - cur;
+
+            chunks.push(ExtentChunk {
+                region_index,
+                region_offset: cur - region.offset,
+                len: chunk_len,
+            });
+            cur += chunk_len;
+        }
+
+        Ok(chunks)
+    }
+
+    /// Returns the guest memory ranges to add to the regions of the snapshot for the guest
+    /// to have `mem_size_mib` MiB of memory. The ranges follow the layout of a microVM booted
+    /// with that size, and must be made of whole memory blocks.
+    pub fn extra_regions(
+        &self,
+        mem_size_mib: usize,
+    ) -> std::result::Result<Vec<(GuestAddress, usize)>, Error> {
+        let mut extra_regions = Vec::new();
+        for (base, size) in arch::arch_memory_regions(mem_size_mib << 20) {
+            let covered = self
+                .regions
+                .iter()
+                .find(|region| region.base_address == base.0)
+                .map_or(0, |region| region.size);
+            if covered > size {
+                return Err(Error::InvalidMemorySize(mem_size_mib));
+            }
+            if covered < size {
+                extra_regions.push((GuestAddress(base.0 + covered as u64), size - covered));
+            }
+        }
+
+        let block_size = MEMORY_BLOCK_SIZE as u64;
+        let size: usize = extra_regions.iter().map(|(_, size)| size).sum();
+        let snapshot_size: usize = self.regions.iter().map(|region| region.size).sum();
+        if snapshot_size + size != mem_size_mib << 20
+            || extra_regions
+                .iter()
+                .any(|(addr, size)| addr.0 % block_size != 0 || *size as u64 % block_size != 0)
+        {
+            return Err(Error::InvalidMemorySize(mem_size_mib));
+        }
+        Ok(extra_regions)
+    }
 }
 
 /// Defines the interface for snapshotting memory.
@@ -54,34 +239,123 @@ where
 {
     /// Describes GuestMemoryMmap through a GuestMemoryState struct.
     fn describe(&self) -> GuestMemoryState;
-    /// Dumps all contents of GuestMemoryMmap to a writer.
-    fn dump<T: std::io::Write>(&self, writer: &mut T) -> std::result::Result<(), Error>;
-    /// Dumps all pages of GuestMemoryMmap present in `dirty_bitmap` to a writer.
+    /// Checks that every byte of `scrub_ranges` is backed by guest memory.
+    fn check_scrub_ranges(&self, scrub_ranges: &[ScrubRange]) -> std::result::Result<(), Error>;
+    /// Dumps all contents of GuestMemoryMmap to a writer, writing zeros for `scrub_ranges`.
+    fn dump<T: std::io::Write>(
+        &self,
+        writer: &mut T,
+        scrub_ranges: &[ScrubRange],
+    ) -> std::result::Result<(), Error>;
+    /// Dumps all pages of GuestMemoryMmap present in `dirty_bitmap` to a writer, writing zeros
+    /// for `scrub_ranges`.
     fn dump_dirty<T: std::io::Write + std::io::Seek>(
         &self,
         writer: &mut T,
         dirty_bitmap: &DirtyBitmap,
+        scrub_ranges: &[ScrubRange],
+    ) -> std::result::Result<(), Error>;
+    /// Dumps the pages of the region in memory slot `slot` present in its `bitmap` to a writer,
+    /// at their offset in the memory file, writing zeros for `scrub_ranges`.
+    fn dump_dirty_slot<T: std::io::Write + std::io::Seek>(
+        &self,
+        writer: &mut T,
+        slot: usize,
+        bitmap: &[u64],
+        scrub_ranges: &[ScrubRange],
+    ) -> std::result::Result<(), Error>;
+    /// Returns the `(offset, length)` in the memory file of the runs of pages of the region in
+    /// memory slot `slot` present in its `bitmap`.
+    fn dirty_extents(&self, slot: usize, bitmap: &[u64]) -> Vec<(u64, u64)>;
+    /// Dumps all contents of GuestMemoryMmap to `file` like `dump`, copying the pages unmodified
+    /// since the restore straight from the memory file and writing the others through `writer`.
+    fn dump_copying<T: std::io::Write + std::io::Seek>(
+        &self,
+        writer: &mut T,
+        file: &File,
+        scrub_ranges: &[ScrubRange],
+    ) -> std::result::Result<(), Error>;
+    /// Dumps all contents of GuestMemoryMmap to the pipe or socket `stream` like `dump`, sending
+    /// the pages unmodified since the restore straight from the memory file.
+    fn dump_streaming(
+        &self,
+        stream: &File,
+        scrub_ranges: &[ScrubRange],
     ) -> std::result::Result<(), Error>;
     /// Creates a GuestMemoryMmap given a `file` containing the data
     /// and a `state` containing mapping information.
-    fn restore(mem_file_path: &PathBuf,
+    /// Without a memory file, the base layer is anonymous memory.
+    /// The `extra_regions` are added to the snapshot regions as anonymous memory.
+    /// With `populate_ws`, the working set extents are populated as they are mapped from
+    /// `ws_file`. With `strict_memory`, the guest memory is charged against the commit limit of
+    /// the host as it is mapped, once checked to fit in the memory available.
+    fn restore(
+        mem_file: Option<&File>,
         mem_state: &GuestMemoryState,
-        enable_user_page_faults: bool,
-        overlay_file_path: &PathBuf,
+        extra_regions: &[(GuestAddress, usize)],
+        overlay_file: Option<&File>,
         overlay_regions: &HashMap<i64, i64>,
-        ws_file_path: &PathBuf,
+        ws_file: Option<&File>,
         ws_regions: &Vec<Vec<i64>>,
-        load_ws: bool,
-        fadvise: &String,
+        populate_ws: bool,
+        strict_memory: bool,
     ) -> std::result::Result<Self, Error>;
-    /// Registers guest memory for hanlding page faults with an external user-level process
-    fn register_for_upf(&self, sock_file_path: &PathBuf) -> std::result::Result<(), Error>;
-    /// load working set
-    fn load_working_set(&self, ws_regions: &Vec<Vec<i64>>) -> std::result::Result<(), Error>;
+    /// Creates a GuestMemoryMmap from the memory `file` alone, given the `state` containing
+    /// mapping information, as upstream Firecracker does.
+    fn restore_from_file(file: &File, state: &GuestMemoryState) -> std::result::Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        Self::restore(
+            Some(file),
+            state,
+            &[],
+            None,
+            &HashMap::new(),
+            None,
+            &Vec::new(),
+            false,
+            false,
+        )
+    }
+    /// Registers guest memory for hanlding page faults with an external user-level process.
+    /// Fails if the guest memory is registered already, until the returned registration is
+    /// dropped.
+    #[cfg(feature = "faasnap")]
+    fn register_for_upf(
+        &self,
+        sock_file_path: &PathBuf,
+    ) -> std::result::Result<UpfRegistration, Error>;
+    /// Registers guest memory for handling page faults with the handler listening on
+    /// `sock_file_path`, following the upstream Firecracker handshake. Fails as
+    /// `register_for_upf` if the guest memory is registered already.
+    #[cfg(feature = "faasnap")]
+    fn connect_uffd_handler(
+        &self,
+        sock_file_path: &PathBuf,
+    ) -> std::result::Result<UpfRegistration, Error>;
+    /// load working set in the order of the backing file, pausing under host pressure when
+    /// `throttle` is set. `in_ws_file` tells whether the extents are packed in a WS file. The
+    /// extents are split in chunks loaded by helper threads as set by `tuning`, each waiting for
+    /// its share of the host bandwidth when `coordinator` is set. The chunks `backing_file`
+    /// already holds in the page cache are mapped in first. The chunks left once `WS_LOAD`
+    /// stops the load are faulted in lazily, and read ahead when `readahead_abandoned` is set.
+    /// Returns the reason the load stopped short, if it left chunks to the guest.
+    fn load_working_set(
+        &self,
+        ws_regions: &Vec<Vec<i64>>,
+        in_ws_file: bool,
+        backing_file: Option<&File>,
+        tuning: &PrefetchTuning,
+        throttle: Option<&mut PrefetchThrottle>,
+        coordinator: Option<&PrefetchCoordinator>,
+        readahead_abandoned: bool,
+    ) -> std::result::Result<Option<StopReason>, Error>;
 }
 
 /// Errors associated with dumping guest memory to file.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// Cannot access file.
     FileHandle(std::io::Error),
@@ -92,9 +366,35 @@ pub enum Error {
     /// Cannot dump memory.
     WriteMemory(GuestMemoryError),
     /// Cannot register region for user page fault handling.
+    #[cfg(feature = "faasnap")]
     UserPageFault(userfaultfd::Error),
     /// Overlay regions error.
     OverlayRegions(std::io::Error),
+    /// Extent (file offset, length) is not covered by the guest memory regions.
+    InvalidExtent(u64, u64),
+    /// Working set extent that is not a [first page, pages] pair of non-negative page counts
+    /// within the file offsets range.
+    InvalidWsRegion(Vec<i64>),
+    /// Scrub range (guest address, length) is empty or not covered by the guest memory regions.
+    InvalidScrubRange(u64, u64),
+    /// The guest memory of the snapshot cannot grow to this size, in MiB.
+    InvalidMemorySize(usize),
+    /// Cannot hand the guest memory over to the page fault handler.
+    UffdHandler(std::io::Error),
+    /// The layered mappings need more memory mappings (needed, `vm.max_map_count`) than the
+    /// process may have.
+    TooManyMappings(usize, usize),
+    /// The dirty bitmap has no entry for this memory slot, or one short of its pages.
+    MissingDirtyBitmap(usize),
+    /// The snapshot was taken with another page size, as (snapshot, host) page sizes.
+    PageSize(usize, usize),
+    /// The guest memory does not fit in the memory available, as (needed, available) bytes.
+    InsufficientMemory(u64, u64),
+    /// A layer extent ends past the end of its file, as (file, extent index, end of the extent,
+    /// file length).
+    ExtentPastEof(String, usize, u64, u64),
+    /// The guest memory region at this host address is registered for user page faults already.
+    AlreadyRegistered(usize),
 }
 
 impl Display for Error {
@@ -105,8 +405,57 @@ impl Display for Error {
             CreateMemory(err) => write!(f, "Cannot create memory: {:?}", err),
             CreateRegion(err) => write!(f, "Cannot create memory region: {:?}", err),
             WriteMemory(err) => write!(f, "Cannot dump memory: {:?}", err),
+            #[cfg(feature = "faasnap")]
             UserPageFault(err) => write!(f, "Cannot register memory for uPF: {:?}", err),
-            OverlayRegions(err) => write!(f, "Cannot mmap overlay regions: {:?}", err),            
+            OverlayRegions(err) => write!(f, "Cannot mmap overlay regions: {:?}", err),
+            InvalidExtent(offset, len) => write!(
+                f,
+                "Extent at file offset {:#x} of length {:#x} is outside guest memory",
+                offset, len
+            ),
+            InvalidWsRegion(region) => write!(f, "Invalid working set extent {:?}", region),
+            InvalidScrubRange(addr, len) => write!(
+                f,
+                "Scrub range at guest address {:#x} of length {:#x} is outside guest memory",
+                addr, len
+            ),
+            InvalidMemorySize(mem_size_mib) => write!(
+                f,
+                "Cannot grow the guest memory to {} MiB in {} MiB blocks",
+                mem_size_mib,
+                MEMORY_BLOCK_SIZE >> 20
+            ),
+            UffdHandler(err) => write!(f, "Cannot connect to the page fault handler: {}", err),
+            TooManyMappings(needed, max) => write!(
+                f,
+                "The layered mappings need up to {} memory mappings, above the vm.max_map_count \
+                 of {}",
+                needed, max
+            ),
+            MissingDirtyBitmap(slot) => {
+                write!(f, "The dirty bitmap is missing memory slot {}", slot)
+            }
+            PageSize(snapshot, host) => write!(
+                f,
+                "The snapshot was taken with {} byte pages, the host has {} byte pages",
+                snapshot, host
+            ),
+            InsufficientMemory(needed, available) => write!(
+                f,
+                "The guest memory needs {} bytes, only {} bytes are available",
+                needed, available
+            ),
+            ExtentPastEof(file, index, end, file_len) => write!(
+                f,
+                "Extent {} of {} ends at file offset {:#x}, past its end at {:#x}",
+                index, file, end, file_len
+            ),
+            AlreadyRegistered(addr) => write!(
+                f,
+                "The guest memory at host address {:#x} is registered for user page faults \
+                 already",
+                addr
+            ),
         }
     }
 }
@@ -114,7 +463,10 @@ impl Display for Error {
 impl SnapshotMemory for GuestMemoryMmap {
     /// Describes GuestMemoryMmap through a GuestMemoryState struct.
     fn describe(&self) -> GuestMemoryState {
-        let mut guest_memory_state = GuestMemoryState::default();
+        let mut guest_memory_state = GuestMemoryState {
+            regions: Vec::new(),
+            page_size: sysconf::page::pagesize(),
+        };
         let mut offset = 0;
         let _: std::result::Result<(), ()> = self.with_regions_mut(|_, region| {
             guest_memory_state.regions.push(GuestMemoryRegionState {
@@ -129,93 +481,239 @@ impl SnapshotMemory for GuestMemoryMmap {
         guest_memory_state
     }
 
-    /// Dumps all contents of GuestMemoryMmap to a writer.
-    fn dump<T: std::io::Write>(&self, writer: &mut T) -> std::result::Result<(), Error> {
+    /// Checks that every byte of `scrub_ranges` is backed by guest memory.
+    fn check_scrub_ranges(&self, scrub_ranges: &[ScrubRange]) -> std::result::Result<(), Error> {
+        for range in scrub_ranges.iter() {
+            let end = range
+                .guest_addr
+                .checked_add(range.len)
+                .ok_or(Error::InvalidScrubRange(range.guest_addr, range.len))?;
+            let covered = self.map_and_fold(
+                0,
+                |(_, region)| {
+                    let start = std::cmp::max(range.guest_addr, region.start_addr().0);
+                    let region_end = region.start_addr().0 + region.len();
+                    std::cmp::min(end, region_end).saturating_sub(start)
+                },
+                |a, b| a + b,
+            );
+            if range.len == 0 || covered != range.len {
+                return Err(Error::InvalidScrubRange(range.guest_addr, range.len));
+            }
+        }
+        Ok(())
+    }
+
+    /// Dumps all contents of GuestMemoryMmap to a writer, writing zeros for `scrub_ranges`.
+    fn dump<T: std::io::Write>(
+        &self,
+        writer: &mut T,
+        scrub_ranges: &[ScrubRange],
+    ) -> std::result::Result<(), Error> {
         self.with_regions_mut(|_, region| {
-            region.write_all_to(MemoryRegionAddress(0), writer, region.len() as usize)
+            let scrub = region_scrub_ranges(region, scrub_ranges);
+            write_scrubbed(region, writer, 0, region.len(), &scrub)
         })
         .map_err(Error::WriteMemory)
     }
 
-    /// Dumps all pages of GuestMemoryMmap present in `dirty_bitmap` to a writer.
+    /// Dumps all pages of GuestMemoryMmap present in `dirty_bitmap` to a writer, writing zeros
+    /// for `scrub_ranges`.
+    ///
+    /// Pages overlapping a scrub range are always written, dirty or not, so that the zeros of
+    /// the diff layer hide whatever the underlying snapshot holds for them.
     fn dump_dirty<T: std::io::Write + std::io::Seek>(
         &self,
         writer: &mut T,
         dirty_bitmap: &DirtyBitmap,
+        scrub_ranges: &[ScrubRange],
     ) -> std::result::Result<(), Error> {
-        let page_size = sysconf::page::pagesize();
         let mut writer_offset = 0;
-
         self.with_regions_mut(|slot, region| {
-            let bitmap = dirty_bitmap.get(&slot).unwrap();
-            let mut write_size = 0;
-            let mut dirty_batch_start: u64 = 0;
-
-            for (i, v) in bitmap.iter().enumerate() {
-                for j in 0..64 {
-                    let is_dirty_page = ((v >> j) & 1u64) != 0u64;
-                    if is_dirty_page {
-                        let page_offset = ((i * 64) + j) * page_size;
-                        // We are at the start of a new batch of dirty pages.
-                        if write_size == 0 {
-                            // Seek forward over the unmodified pages.
-                            writer
-                                .seek(SeekFrom::Start(writer_offset + page_offset as u64))
-                                .unwrap();
-                            dirty_batch_start = page_offset as u64;
-                        }
-                        write_size += page_size;
-                    } else if write_size > 0 {
-                        // We are at the end of a batch of dirty pages.
-                        region.write_all_to(
-                            MemoryRegionAddress(dirty_batch_start),
-                            writer,
-                            write_size,
-                        )?;
-                        write_size = 0;
-                    }
+            let bitmap = dirty_bitmap
+                .get(&slot)
+                .filter(|bitmap| bitmap_covers(bitmap, region))
+                .ok_or(Error::MissingDirtyBitmap(slot))?;
+            let scrub = region_scrub_ranges(region, scrub_ranges);
+            dump_dirty_region(region, writer, writer_offset, bitmap, &scrub)
+                .map_err(Error::WriteMemory)?;
+            writer_offset += region.len();
+            Ok(())
+        })
+    }
+
+    /// Dumps the pages of the region in memory slot `slot` present in its `bitmap` to a writer,
+    /// at their offset in the memory file, writing zeros for `scrub_ranges`, like `dump_dirty`.
+    fn dump_dirty_slot<T: std::io::Write + std::io::Seek>(
+        &self,
+        writer: &mut T,
+        slot: usize,
+        bitmap: &[u64],
+        scrub_ranges: &[ScrubRange],
+    ) -> std::result::Result<(), Error> {
+        let mut writer_offset = 0;
+        let mut dumped = false;
+        self.with_regions_mut(|index, region| {
+            if index == slot {
+                // A short bitmap would leave the last pages of the region out of the dump.
+                if !bitmap_covers(bitmap, region) {
+                    return Err(Error::MissingDirtyBitmap(slot));
                 }
+                let scrub = region_scrub_ranges(region, scrub_ranges);
+                dump_dirty_region(region, writer, writer_offset, bitmap, &scrub)
+                    .map_err(Error::WriteMemory)?;
+                dumped = true;
             }
+            writer_offset += region.len();
+            Ok(())
+        })?;
+        if !dumped {
+            return Err(Error::MissingDirtyBitmap(slot));
+        }
+        Ok(())
+    }
 
-            if write_size > 0 {
-                region.write_all_to(MemoryRegionAddress(dirty_batch_start), writer, write_size)?;
+    /// Returns the `(offset, length)` in the memory file of the runs of pages of the region in
+    /// memory slot `slot` present in its `bitmap`.
+    fn dirty_extents(&self, slot: usize, bitmap: &[u64]) -> Vec<(u64, u64)> {
+        let page_size = sysconf::page::pagesize() as u64;
+        let mut extents = Vec::new();
+        let mut file_offset = 0;
+        let _ = self.with_regions_mut(|index, region| {
+            if index == slot {
+                extents = dirty_runs(bitmap, page_size, file_offset);
             }
+            file_offset += region.len();
+            Ok::<(), ()>(())
+        });
+        extents
+    }
 
+    /// Dumps all contents of GuestMemoryMmap to `file`, whose writes go through `writer`,
+    /// writing zeros for `scrub_ranges`.
+    ///
+    /// The pages of a region mapped from a memory file that are unmodified since the restore are
+    /// copied from that file with `copy_file_range`, the other ones are written to `writer`.
+    fn dump_copying<T: std::io::Write + std::io::Seek>(
+        &self,
+        writer: &mut T,
+        file: &File,
+        scrub_ranges: &[ScrubRange],
+    ) -> std::result::Result<(), Error> {
+        let mut writer_offset = 0;
+        self.with_regions_mut(|_, region| {
+            let scrub = region_scrub_ranges(region, scrub_ranges);
+            let copied = dump_copy::copy_unmodified_pages(region, file, writer_offset, &scrub);
+            let mut cur = 0;
+            for &(start, end) in copied.iter().chain(Some(&(region.len(), region.len()))) {
+                if start > cur {
+                    writer
+                        .seek(SeekFrom::Start(writer_offset + cur))
+                        .map_err(GuestMemoryError::IOError)?;
+                    write_scrubbed(region, writer, cur, start - cur, &scrub)?;
+                }
+                cur = end;
+            }
             writer_offset += region.len();
             Ok(())
         })
         .map_err(Error::WriteMemory)
     }
 
+    /// Dumps all contents of GuestMemoryMmap to the pipe or socket `stream`, writing zeros for
+    /// `scrub_ranges`.
+    ///
+    /// The pages of a region mapped from a memory file that are unmodified since the restore are
+    /// sent from that file with `sendfile`, the other ones are written from guest memory.
+    fn dump_streaming(
+        &self,
+        stream: &File,
+        scrub_ranges: &[ScrubRange],
+    ) -> std::result::Result<(), Error> {
+        let mut writer = stream;
+        let mut sendfile = true;
+        self.with_regions_mut(|_, region| {
+            let scrub = region_scrub_ranges(region, scrub_ranges);
+            let mut cur = 0;
+            if let Some((backing, pages)) = dump_copy::unmodified_pages(region, &scrub) {
+                for (start, end) in pages {
+                    if !sendfile {
+                        break;
+                    }
+                    if start > cur {
+                        write_scrubbed(region, &mut writer, cur, start - cur, &scrub)?;
+                    }
+                    cur = end;
+                    if let Err((sent, e)) = dump_copy::send_range(
+                        backing.file(),
+                        backing.start() + start,
+                        stream,
+                        end - start,
+                    ) {
+                        warn!(
+                            "Streaming the unmodified guest memory from the mapping: {}",
+                            e
+                        );
+                        sendfile = false;
+                        cur = start + sent;
+                    }
+                }
+            }
+            write_scrubbed(region, &mut writer, cur, region.len() - cur, &scrub)
+        })
+        .map_err(Error::WriteMemory)
+    }
+
     /// Creates a GuestMemoryMmap given a `file` containing the data
     /// and a `state` containing mapping information.
-    fn restore(mem_file_path: &PathBuf,
+    fn restore(
+        mem_file: Option<&File>,
         state: &GuestMemoryState,
-        enable_user_page_faults: bool,
-        overlay_file_path: &PathBuf,
+        extra_regions: &[(GuestAddress, usize)],
+        overlay_file: Option<&File>,
         overlay_regions: &HashMap<i64, i64>,
-        ws_file_path: &PathBuf,
+        ws_file: Option<&File>,
         ws_regions: &Vec<Vec<i64>>,
-        load_ws: bool,
-        fadvise: &String,
+        populate_ws: bool,
+        strict_memory: bool,
     ) -> std::result::Result<Self, Error> {
-        let page_size = sysconf::page::pagesize() as i64;
+        let page_size = sysconf::page::pagesize() as u64;
+        // Without `MAP_NORESERVE`, the kernel refuses the mappings past its commit limit, rather
+        // than the OOM killer stepping in once the guest dirties its memory.
+        let noreserve = match strict_memory {
+            true => {
+                check_available_memory(state, extra_regions)?;
+                0
+            }
+            false => libc::MAP_NORESERVE,
+        };
         let mut mmap_regions = Vec::new();
-        assert!(state.regions.len() == 1); // for now only support one region
-        for region in state.regions.iter() {
-            assert!(region.offset == 0);
-
-            let (flags, file_offset) = if mem_file_path.clone().into_os_string().eq("") { // no memfile, anony mapping
-                (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, None)
-            } else { // backing file
-                let file = File::open(mem_file_path).map_err(Error::FileHandle)?;
-                (libc::MAP_NORESERVE | libc::MAP_PRIVATE, Some(FileOffset::new(
-                    file.try_clone().map_err(Error::FileHandle)?,
-                    region.offset,
-                )))
+        let mut backing = BackingFiles::default();
+        let mem_file_index = match mem_file {
+            Some(file) => Some(
+                backing
+                    .add_file(file, MmapLayer::Base)
+                    .map_err(Error::FileHandle)?,
+            ),
+            None => None,
+        };
+        let base_span = RESTORE_TRACE.span(RestorePhase::BaseMmap);
+        for (index, region) in state.regions.iter().enumerate() {
+            let (flags, file_offset) = match mem_file {
+                // no memfile, anony mapping
+                None => (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, None),
+                // backing file
+                Some(file) => (
+                    noreserve | libc::MAP_PRIVATE,
+                    Some(FileOffset::new(
+                        file.try_clone().map_err(Error::FileHandle)?,
+                        region.offset,
+                    )),
+                ),
             };
 
-            let mmap_region = MmapRegion::build( // build base layer
+            // build base layer
+            let mmap_region = build_aligned(
                 file_offset,
                 region.size,
                 libc::PROT_READ | libc::PROT_WRITE,
@@ -224,237 +722,1862 @@ impl SnapshotMemory for GuestMemoryMmap {
             .map(|r| GuestRegionMmap::new(r, GuestAddress(region.base_address)))
             .map_err(Error::CreateRegion)?
             .map_err(Error::CreateMemory)?;
-            info!("base layer mmap'd. offset = {:?}, len={:?}", region.offset, region.size);
-            let addr = mmap_region.as_ptr();
-            // overlay layer
-            if !overlay_file_path.clone().into_os_string().eq("") {
-                let file = File::open(overlay_file_path).map_err(Error::FileHandle)?;
-                let fd = file.as_raw_fd();
-                for (off, len) in overlay_regions {
-                    let offset = *off * page_size;
-                    let length = *len * page_size;
-                    let ret = unsafe { libc::mmap((addr.offset(offset as isize)) as *mut u8 as _, length as usize, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_FIXED | libc::MAP_NORESERVE | libc::MAP_PRIVATE, fd, offset as libc::off_t)};
-                    if ret == libc::MAP_FAILED {
-                        return Err(Error::OverlayRegions(std::io::Error::last_os_error()));
-                    }
+            debug_category!(
+                DebugCategory::Overlay,
+                "base layer mmap'd. offset = {:?}, len={:?}",
+                region.offset,
+                region.size
+            );
+            probes::fc_probe_mmap(MmapLayer::Base, region.offset, region.size as u64);
+            if let Some(file) = mem_file_index {
+                backing.add(
+                    mmap_region.as_ptr() as usize,
+                    region.size,
+                    MmapLayer::Base,
+                    file,
+                    index,
+                    region.offset,
+                );
+            }
+            mmap_regions.push(mmap_region);
+        }
+        for (base_address, size) in extra_regions {
+            let mmap_region = build_aligned(
+                None,
+                *size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            )
+            .map(|r| GuestRegionMmap::new(r, *base_address))
+            .map_err(Error::CreateRegion)?
+            .map_err(Error::CreateMemory)?;
+            mmap_regions.push(mmap_region);
+        }
+        drop(base_span);
+
+        let overlay_extents = match overlay_file {
+            Some(_) => {
+                coalesce_extents(overlay_regions.iter().map(|(&page, &pages)| (page, pages)))
+            }
+            None => Vec::new(),
+        };
+        let ws_extents = match ws_file {
+            Some(_) => parse_ws_regions(ws_regions, page_size)?,
+            None => Vec::new(),
+        };
+        check_map_count(state, &overlay_extents, &ws_extents)?;
+        // An extent mapped past the end of its file would only fail once the guest touches it,
+        // with a SIGBUS.
+        if let Some(file) = overlay_file {
+            let mut pages: Vec<_> = overlay_regions.iter().collect();
+            pages.sort();
+            // The overlay file mirrors the memory file layout.
+            let extents = pages
+                .into_iter()
+                .map(|(&page, &pages)| (page as u64, pages as u64));
+            check_extents_in_file(file, extents, page_size)?;
+        }
+        if let Some(file) = ws_file {
+            // The working set file packs the extents back to back.
+            let mut file_page: u64 = 0;
+            let extents = ws_extents.iter().map(|&(_, pages)| {
+                let start = file_page;
+                file_page = file_page.saturating_add(pages);
+                (start, pages)
+            });
+            check_extents_in_file(file, extents, page_size)?;
+        }
+
+        // The layers are mapped over the base layer mappings, so a failure part way drops
+        // `mmap_regions`, which unmaps the layers mapped so far along with the base layer, and
+        // leaves nothing of the restore mapped.
+
+        // overlay layer
+        if let Some(file) = overlay_file {
+            let _span = RESTORE_TRACE.span(RestorePhase::OverlayMap);
+            let extents = overlay_extents;
+            let overlay = backing
+                .add_file(file, MmapLayer::Overlay)
+                .map_err(Error::FileHandle)?;
+            for (index, &(page, pages)) in extents.iter().enumerate() {
+                let offset = page * page_size;
+                let length = pages * page_size;
+                // The overlay file mirrors the memory file layout.
+                let mapped = map_file_extent(
+                    &mmap_regions,
+                    state,
+                    offset,
+                    length,
+                    file,
+                    offset,
+                    false,
+                    noreserve,
+                )?;
+                for (addr, len, file_offset) in mapped {
+                    backing.add(addr, len, MmapLayer::Overlay, overlay, index, file_offset);
                 }
+                probes::fc_probe_mmap(MmapLayer::Overlay, offset, length);
+                METRICS.snapshot.overlay_extents_mapped.inc();
             }
+            debug_category!(
+                DebugCategory::Overlay,
+                "overlay layer mmap'd. extents={:?}, coalesced={:?}",
+                overlay_regions.len(),
+                extents.len()
+            );
+        }
 
-            // working set layer
-            if !ws_file_path.clone().into_os_string().eq("") {
-                let file = File::open(ws_file_path).map_err(Error::FileHandle)?;
-                let fd = file.as_raw_fd();
-                let mut file_off: u64 = 0;
-                for region in ws_regions {
-                    let off = region[0] * page_size;
-                    let len = region[1] * page_size;
-                    let fd = file.as_raw_fd();
-                    let ret = unsafe { libc::mmap((addr.offset(off as isize)) as *mut u8 as _, len as usize, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_FIXED | libc::MAP_NORESERVE | libc::MAP_PRIVATE, fd, (file_off) as libc::off_t) };
-                    if ret == libc::MAP_FAILED {
-                        return Err(Error::OverlayRegions(std::io::Error::last_os_error()));
-                    }
-                    file_off += len as u64;
+        // working set layer
+        if let Some(file) = ws_file {
+            let _span = RESTORE_TRACE.span(RestorePhase::WsMap);
+            let start_us = get_time_us(ClockType::Monotonic);
+            let mut file_off: u64 = 0;
+            let ws = backing
+                .add_file(file, MmapLayer::WorkingSet)
+                .map_err(Error::FileHandle)?;
+            for (index, &(page, pages)) in ws_extents.iter().enumerate() {
+                let off = page * page_size;
+                let len = pages * page_size;
+                // The working set file packs the extents back to back.
+                let mapped = map_file_extent(
+                    &mmap_regions,
+                    state,
+                    off,
+                    len,
+                    file,
+                    file_off,
+                    populate_ws,
+                    noreserve,
+                )?;
+                for (addr, len, file_offset) in mapped {
+                    backing.add(addr, len, MmapLayer::WorkingSet, ws, index, file_offset);
                 }
+                probes::fc_probe_mmap(MmapLayer::WorkingSet, off, len);
+                file_off += len;
             }
-            mmap_regions.push(mmap_region);
+            if populate_ws {
+                METRICS.snapshot.ws_bytes_prefetched.add(file_off as usize);
+                METRICS
+                    .snapshot
+                    .ws_populate_us
+                    .add((get_time_us(ClockType::Monotonic) - start_us) as usize);
+            }
+            debug_category!(
+                DebugCategory::WsLoader,
+                "working set layer mmap'd. extents={:?}, len={:?}",
+                ws_regions.len(),
+                file_off
+            );
         }
-    
-        // if load_ws {
-        //         let start = addr.clone() as u64;
-        //         let new_ws_regions = ws_regions.clone();
-        //         let new_ol_regions = overlay_regions.clone();
-        //         thread::Builder::new()
-        //             .name("fc_ws_loader".to_owned()).spawn(move || {
-        //             info!("in the thread");
-        //             let mut a: u8 = 0;
-        //             let mut sorted: Vec<_> = new_ws_regions.into_iter().collect();
-        //             sorted.sort_by(|x,y| x.1.cmp(&y.1));
-        //             for (off, file_off) in sorted {
-        //                 let len = new_ol_regions[&off];
-        //                 for pos in (off..off+len).step_by(4096) {
-        //                     unsafe {a ^= *((start as *const u8).offset(pos as isize))};
-        //                 }
-        //             }
-        //             info!("loaded, {}", a);
-        //         }).expect("loader thread spawn failed.");
-        //     }
-
-        Ok(Self::from_regions(mmap_regions).map_err(Error::CreateMemory)?)
-    }
-
-    /// Use both memfile and wsfile
-    // fn restore2(memfile: &File, wsfile: &File, state: &GuestMemoryState, enable_user_page_faults: bool, overlay_regions: &HashMap<i64, i64>, groups: &Vec<Vec<i64>>, load_ws: bool) -> std::result::Result<Self, Error> {
-    //     let page_size = sysconf::page::pagesize() as i64;
-    //     let mut mmap_regions = Vec::new();
-    //     assert!(state.regions.len() == 1); // for now only support one region
-    //     for region in state.regions.iter() {
-    //         assert!(region.offset == 0);
-    //         // anonymous memory as base layer
-    //         let mut flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
-    //         let mmap_region = MmapRegion::build(
-    //             None,
-    //             region.size,
-    //             libc::PROT_READ | libc::PROT_WRITE,
-    //             flags,
-    //         )
-    //         .map(|r| GuestRegionMmap::new(r, GuestAddress(region.base_address)))
-    //         .map_err(Error::CreateRegion)?
-    //         .map_err(Error::CreateMemory)?;
-    //         info!("original region's offset = {:?}, len={:?}", region.offset, region.size);
-    //         let addr = mmap_region.as_ptr();
-    //         // memfile as non-active upper layer
-    //         for (off, len) in overlay_regions {
-    //             let file_off = *off as u64;
-    //             let fd = memfile.as_raw_fd();
-    //             let ret = unsafe { libc::mmap((addr.offset(*off as isize)) as *mut u8 as _, *len as usize, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_FIXED | libc::MAP_NORESERVE | libc::MAP_PRIVATE, fd, (file_off) as libc::off_t) };
-    //             if ret == libc::MAP_FAILED {
-    //                 return Err(Error::OverlayRegions(std::io::Error::last_os_error()));
-    //             }
-    //         }
-    //         // wsfile as active upper layer
-    //         let mut file_off: u64 = 0;
-    //         for group in groups {
-    //             let off = group[0] * page_size;
-    //             let len = group[1] * page_size;
-    //             let fd = wsfile.as_raw_fd();
-    //             let ret = unsafe { libc::mmap((addr.offset(off as isize)) as *mut u8 as _, len as usize, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_FIXED | libc::MAP_NORESERVE | libc::MAP_PRIVATE, fd, (file_off) as libc::off_t) };
-    //             if ret == libc::MAP_FAILED {
-    //                 return Err(Error::OverlayRegions(std::io::Error::last_os_error()));
-    //             }
-    //             file_off += len as u64;
-    //         }
-    //         mmap_regions.push(mmap_region);
-    //     }
-    //     Ok(Self::from_regions(mmap_regions).map_err(Error::CreateMemory)?)
-    // }    
+
+        let memory = Self::from_regions(mmap_regions).map_err(Error::CreateMemory)?;
+        // Only a successful restore replaces the mappings of the previous one, and closes its
+        // files.
+        *BACKING_FILES.lock().expect("Poisoned lock") = backing;
+        Ok(memory)
+    }
 
     /// Registers guest memory regions for handling page faults
     /// with an external user-level process.
-    fn register_for_upf(&self, sock_file_path: &PathBuf) -> std::result::Result<(), Error> {
-        self.with_regions(|_, region| {
-            info!("Guest memory size={:?}MB, base_address={:?}, last_addr={:?}",
-                region.len()/1024/1024,
-                region.get_host_address(region.to_region_addr(region.start_addr()).unwrap()),
-                region.get_host_address(region.to_region_addr(region.last_addr()).unwrap()));
+    #[cfg(feature = "faasnap")]
+    fn register_for_upf(
+        &self,
+        sock_file_path: &PathBuf,
+    ) -> std::result::Result<UpfRegistration, Error> {
+        // Checked before listening, so that a second registration leaves the socket alone.
+        let registration = UpfRegistration::new(self)?;
+        let _watch = RESTORE_WATCHDOG.watch(WatchedOperation::UffdHandshake);
+        // Each region is handed over on its own connection to the same socket.
+        let socket = UffdSocket::listen(sock_file_path)?;
+        let mut handed_over = Vec::new();
+        let res = self.with_regions_mut(|_, region| {
+            let addr = region.as_ptr();
+            let len = region.len();
+            debug_category!(
+                DebugCategory::Uffd,
+                "Guest memory size={:?}MB, host address of the region's start = {:p}",
+                len / 1024 / 1024,
+                addr
+            );
 
             let uffd = UffdBuilder::new()
-            .close_on_exec(true)
-            .non_blocking(true)
-            .create()
-            .expect("uffd creation");
+                .close_on_exec(true)
+                .non_blocking(true)
+                .create()
+                .map_err(Error::UserPageFault)?;
+            uffd.register(addr as _, len as usize)
+                .map_err(Error::UserPageFault)?;
 
-            let addr = region.get_host_address(region.to_region_addr(region.start_addr()).unwrap()).unwrap();
-            let len = region.len();
-            info!("Host address of the region's start = {:p}, len={:?}", addr, len);
-            uffd.register(addr as *mut u8 as _, len as u64 as _).expect("uffd.register()");
+            RESTORE_WATCHDOG.set_fd(socket.listener.as_raw_fd());
+            let stream = socket.accept()?;
+            RESTORE_WATCHDOG.set_fd(stream.as_raw_fd());
+            stream
+                .send_fd(uffd.as_raw_fd())
+                .map_err(Error::UffdHandler)?;
+            RESTORE_WATCHDOG.progress();
+            AUDIT.record(
+                AuditEvent::UffdHandoff,
+                &[AuditFile::from_path("uffd_socket", sock_file_path, false)],
+                PeerCredentials::from_socket(stream.as_raw_fd()),
+                None,
+            );
 
-            let listener = UnixListener::bind(sock_file_path).unwrap();
-            let (stream, _) = listener.accept().unwrap();
-            stream.send_fd(uffd.as_raw_fd()).unwrap();
+            debug_category!(DebugCategory::Uffd, "Sent the fd!");
 
-            info!("Sent the fd!");
+            // Cause a page fault on the first page to communicate the start_addr's hVA.
+            // Safe because `addr` is the start of the mapped region, served by the handler.
+            unsafe { std::ptr::read_volatile(addr) };
+            debug_category!(
+                DebugCategory::Uffd,
+                "Faulted in the region at {:p}, len={}",
+                addr,
+                len
+            );
 
-            // Cause a page fault on the first page to communicate the start_addr's hVA
-            unsafe{
-                print!("after reg: ptr={:p}, mem value = {:?}, len={:?}", addr, *addr, len)
+            handed_over.push((uffd, addr, len));
+            Ok(())
+        });
+        // The handler keeps the uffds of the regions already handed over, whose registration
+        // is released so that a failed handoff leaves no region registered.
+        if res.is_err() {
+            for (uffd, addr, len) in handed_over {
+                if let Err(e) = uffd.unregister(addr as _, len as usize) {
+                    warn!("Cannot unregister the guest memory from the uffd: {}", e);
+                }
             }
+        }
+        res.map(|_| registration)
+    }
 
-            Ok(())
-        })
-        .map_err(Error::UserPageFault)
+    #[cfg(feature = "faasnap")]
+    fn connect_uffd_handler(
+        &self,
+        sock_file_path: &PathBuf,
+    ) -> std::result::Result<UpfRegistration, Error> {
+        let registration = UpfRegistration::new(self)?;
+        let _watch = RESTORE_WATCHDOG.watch(WatchedOperation::UffdHandshake);
+        // A single uffd covers all of the regions, which are described to the handler along
+        // with it.
+        let uffd = UffdBuilder::new()
+            .close_on_exec(true)
+            .non_blocking(true)
+            .create()
+            .map_err(Error::UserPageFault)?;
+        let mut mappings = Vec::new();
+        for region in self.describe().regions {
+            let addr = self
+                .get_host_address(GuestAddress(region.base_address))
+                .map_err(|_| Error::InvalidExtent(region.offset, region.size as u64))?;
+            uffd.register(addr as _, region.size)
+                .map_err(Error::UserPageFault)?;
+            mappings.push(GuestRegionUffdMapping {
+                base_host_virt_addr: addr as u64,
+                size: region.size,
+                offset: region.offset,
+            });
+        }
+        let body = serde_json::to_string(&mappings)
+            .map_err(|e| Error::UffdHandler(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+
+        let stream = UnixStream::connect(sock_file_path).map_err(Error::UffdHandler)?;
+        RESTORE_WATCHDOG.set_fd(stream.as_raw_fd());
+        stream
+            .send_with_fd(body.as_bytes(), uffd.as_raw_fd())
+            .map_err(|e| Error::UffdHandler(std::io::Error::from_raw_os_error(e.errno())))?;
+        RESTORE_WATCHDOG.progress();
+        AUDIT.record(
+            AuditEvent::UffdHandoff,
+            &[AuditFile::from_path("uffd_socket", sock_file_path, false)],
+            PeerCredentials::from_socket(stream.as_raw_fd()),
+            None,
+        );
+        debug_category!(
+            DebugCategory::Uffd,
+            "Sent the uffd and {} regions to the handler",
+            mappings.len()
+        );
+        Ok(registration)
     }
 
-    fn load_working_set(&self, ws_regions: &Vec<Vec<i64>>) -> std::result::Result<(), Error> {
-        self.with_regions(|_, region| {
-            info!("Start loading working set");
+    fn load_working_set(
+        &self,
+        ws_regions: &Vec<Vec<i64>>,
+        in_ws_file: bool,
+        backing_file: Option<&File>,
+        tuning: &PrefetchTuning,
+        throttle: Option<&mut PrefetchThrottle>,
+        coordinator: Option<&PrefetchCoordinator>,
+        readahead_abandoned: bool,
+    ) -> std::result::Result<Option<StopReason>, Error> {
+        debug_category!(
+            DebugCategory::WsLoader,
+            "Start loading working set, {:?}",
+            tuning
+        );
+        let _span = RESTORE_TRACE.span(RestorePhase::Prefetch);
+        let _otel_span = OTEL.span("prefetch");
 
-            let addr = region.get_host_address(region.to_region_addr(region.start_addr()).unwrap()).unwrap();
-            let len = region.len();
-            info!("Host address of the region's start = {:p}, len={:?}", addr, len);
-            // let mut sorted: Vec<_> = ws_regions.into_iter().collect();
-            // sorted.sort_by(|x,y| x.1.cmp(&y.1));
-            let mut a: u8 = 0;
-            let page_size = sysconf::page::pagesize() as i64;
-            for item in ws_regions {
-                let off = item[0] * page_size;
-                let len = item[1] * page_size;
-                for pos in (off..off+len).step_by(page_size as usize) {
-                    unsafe {a ^= *((addr as *const u8).offset(pos as isize))};
-                }
+        let state = self.describe();
+        let page_size = sysconf::page::pagesize() as u64;
+        let ws_extents = parse_ws_regions(ws_regions, page_size)?;
+        let chunks = split_extents(
+            &prefetch_order(&ws_extents, page_size, in_ws_file),
+            tuning.chunk_pages.saturating_mul(page_size),
+        );
+        // The cached chunks cost no I/O, map them in before blocking on the others.
+        let chunks = match backing_file {
+            Some(file) => map_cached_chunks(self, &state, file, chunks, page_size, tuning)?,
+            None => chunks,
+        };
+        // The helper threads take the chunks in order, so the file is still read front to back.
+        let next = AtomicUsize::new(0);
+        let throttle = Mutex::new(throttle);
+        let tasks = (0..tuning.concurrency)
+            .map(|_| {
+                let (state, chunks, next, throttle) = (&state, &chunks, &next, &throttle);
+                Box::new(move || {
+                    let mut a: u8 = 0;
+                    while WS_LOAD.stop_reason().is_none() {
+                        let (off, len, _) = match chunks.get(next.fetch_add(1, Ordering::SeqCst)) {
+                            Some(&chunk) => chunk,
+                            None => break,
+                        };
+                        a ^= load_extent(self, state, off, len, page_size, throttle, coordinator)?;
+                    }
+                    Ok(a)
+                }) as Task<std::result::Result<u8, Error>>
+            })
+            .collect();
+        let mut a: u8 = 0;
+        for loaded in WORKER_POOL.run(tasks) {
+            a ^= loaded?;
+        }
+        // The chunks before `next` were all taken by a helper thread, and loaded.
+        let abandoned = chunks.get(next.into_inner()..).unwrap_or(&[]);
+        let stopped = match (WS_LOAD.stop_reason(), abandoned.is_empty()) {
+            (Some(reason), false) => {
+                let bytes = abandon_chunks(self, &state, abandoned, readahead_abandoned)?;
+                warn!(
+                    "Working set load stopped short ({}), {} bytes left to fault in lazily",
+                    reason, bytes
+                );
+                METRICS.snapshot.ws_bytes_abandoned.add(bytes as usize);
+                Some(reason)
             }
-            info!("loaded, {}", a);
-            Ok(())
-        })
-        .map_err(Error::FileHandle)
+            _ => None,
+        };
+        debug_category!(DebugCategory::WsLoader, "loaded, {}", a);
+        Ok(stopped)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
+/// Guest memory registered for user page faults. Registering it again fails until this is
+/// dropped, along with the guest memory.
+#[cfg(feature = "faasnap")]
+#[derive(Debug)]
+pub struct UpfRegistration {
+    ranges: Vec<(usize, usize)>,
+}
 
-    use super::*;
-    use utils::tempfile::TempFile;
-    use vm_memory::GuestAddress;
+#[cfg(feature = "faasnap")]
+impl UpfRegistration {
+    // Records the host ranges of `memory`, unless one of them is registered already.
+    fn new(memory: &GuestMemoryMmap) -> std::result::Result<Self, Error> {
+        let mut ranges = Vec::new();
+        let _: std::result::Result<(), ()> = memory.with_regions_mut(|_, region| {
+            ranges.push((region.as_ptr() as usize, region.len() as usize));
+            Ok(())
+        });
+        let mut registered = UPF_REGISTERED.lock().expect("Poisoned lock");
+        for &(addr, len) in ranges.iter() {
+            let overlaps = |&(other, other_len): &(usize, usize)| {
+                addr < other + other_len && other < addr + len
+            };
+            if registered.iter().any(overlaps) {
+                return Err(Error::AlreadyRegistered(addr));
+            }
+        }
+        registered.extend(ranges.iter().copied());
+        Ok(UpfRegistration { ranges })
+    }
 
-    #[test]
-    fn test_describe_state() {
-        let page_size: usize = sysconf::page::pagesize();
+    /// Keeps the guest memory registered for the lifetime of the process, for guest memory
+    /// which is never unmapped.
+    pub fn keep(self) {
+        std::mem::forget(self);
+    }
+}
 
-        // Two regions of one page each, with a one page gap between them.
-        let mem_regions = [
-            (GuestAddress(0), page_size),
-            (GuestAddress(page_size as u64 * 2), page_size),
-        ];
-        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions[..]).unwrap();
+#[cfg(feature = "faasnap")]
+impl Drop for UpfRegistration {
+    fn drop(&mut self) {
+        let mut registered = UPF_REGISTERED.lock().expect("Poisoned lock");
+        registered.retain(|range| !self.ranges.contains(range));
+    }
+}
 
-        let expected_memory_state = GuestMemoryState {
-            regions: vec![
-                GuestMemoryRegionState {
-                    base_address: 0,
-                    size: page_size,
-                    offset: 0,
-                },
-                GuestMemoryRegionState {
-                    base_address: page_size as u64 * 2,
-                    size: page_size,
-                    offset: page_size as u64,
-                },
-            ],
+// Socket the page fault handler connects to, to receive the userfaultfds: the activated one if
+// any, which stays with the process, or one bound to `sock_file_path`, removed once the handoffs
+// are done so that the guest memory can be registered again.
+#[cfg(feature = "faasnap")]
+struct UffdSocket {
+    listener: UnixListener,
+    bound_path: Option<PathBuf>,
+}
+
+#[cfg(feature = "faasnap")]
+impl UffdSocket {
+    fn listen(path: &Path) -> std::result::Result<Self, Error> {
+        if let Some(listener) = SOCKET_ACTIVATION.listener(UFFD_SOCKET_NAME) {
+            return Ok(UffdSocket {
+                listener,
+                bound_path: None,
+            });
+        }
+        // A socket left behind by a process which stopped before removing it refuses the
+        // connections, while one still in use is left alone and fails the bind.
+        let stale = match std::fs::metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => matches!(
+                UnixStream::connect(path),
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused
+            ),
+            _ => false,
         };
+        if stale {
+            std::fs::remove_file(path).map_err(Error::UffdHandler)?;
+        }
+        let listener = UnixListener::bind(path).map_err(Error::UffdHandler)?;
+        Ok(UffdSocket {
+            listener,
+            bound_path: Some(path.to_path_buf()),
+        })
+    }
 
-        let actual_memory_state = guest_memory.describe();
-        assert_eq!(expected_memory_state, actual_memory_state);
+    fn accept(&self) -> std::result::Result<UnixStream, Error> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => return Ok(stream),
+                // A signal interrupted the wait for the handler.
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::UffdHandler(e)),
+            }
+        }
+    }
+}
 
-        // Two regions of three pages each, with a one page gap between them.
-        let mem_regions = [
-            (GuestAddress(0), page_size * 3),
-            (GuestAddress(page_size as u64 * 4), page_size * 3),
-        ];
-        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions[..]).unwrap();
+#[cfg(feature = "faasnap")]
+impl Drop for UffdSocket {
+    fn drop(&mut self) {
+        if let Some(path) = self.bound_path.as_ref() {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Cannot remove the uffd socket {}: {}", path.display(), e);
+            }
+        }
+    }
+}
 
-        let expected_memory_state = GuestMemoryState {
-            regions: vec![
-                GuestMemoryRegionState {
-                    base_address: 0,
-                    size: page_size * 3,
-                    offset: 0,
-                },
-                GuestMemoryRegionState {
-                    base_address: page_size as u64 * 4,
-                    size: page_size * 3,
-                    offset: page_size as u64 * 3,
-                },
-            ],
-        };
+/// Content of the memory layers held in memory, for restoring guest memory without snapshot
+/// files or a page fault handler, as the tests do.
+#[derive(Default)]
+pub struct BufferLayers<'a> {
+    /// Content of the memory file, or `None` for an anonymous base layer.
+    pub mem: Option<&'a [u8]>,
+    /// Content of the overlay file, mirroring the memory file layout, and its extents as
+    /// (first page, pages).
+    pub overlay: Option<(&'a [u8], HashMap<i64, i64>)>,
+    /// Content of the working set file, packing the extents back to back, and its extents as
+    /// [first page, pages].
+    pub ws: Option<(&'a [u8], Vec<Vec<i64>>)>,
+    /// Whether the working set extents are populated as they are mapped.
+    pub populate_ws: bool,
+}
 
-        let actual_memory_state = guest_memory.describe();
-        assert_eq!(expected_memory_state, actual_memory_state);
+// Copies `buf` to a new memfd named `name`.
+fn memfd_from_buffer(name: &str, buf: &[u8]) -> io::Result<File> {
+    let memfd = snapshot_io::create_memfd(name)?;
+    memfd.write_all_at(buf, 0)?;
+    Ok(memfd)
+}
+
+/// Restores the guest memory described by `state` from the layers in `layers`, each copied to a
+/// memfd mapped as its layer file would be.
+pub fn restore_from_buffers(
+    state: &GuestMemoryState,
+    layers: &BufferLayers,
+) -> std::result::Result<GuestMemoryMmap, Error> {
+    let mem_file = match layers.mem {
+        Some(buf) => Some(memfd_from_buffer("mem", buf).map_err(Error::FileHandle)?),
+        None => None,
+    };
+    let (overlay_file, overlay_regions) = match layers.overlay.as_ref() {
+        Some((buf, regions)) => (
+            Some(memfd_from_buffer("overlay", buf).map_err(Error::FileHandle)?),
+            regions.clone(),
+        ),
+        None => (None, HashMap::new()),
+    };
+    let (ws_file, ws_regions) = match layers.ws.as_ref() {
+        Some((buf, regions)) => (
+            Some(memfd_from_buffer("ws", buf).map_err(Error::FileHandle)?),
+            regions.clone(),
+        ),
+        None => (None, Vec::new()),
+    };
+    // The mappings hold on to the memfds, which are closed here.
+    GuestMemoryMmap::restore(
+        mem_file.as_ref(),
+        state,
+        &[],
+        overlay_file.as_ref(),
+        &overlay_regions,
+        ws_file.as_ref(),
+        &ws_regions,
+        layers.populate_ws,
+        false,
+    )
+}
+
+// Maps in the `chunks` whose backing `file` range is all in the page cache, as found by reads
+// with `RWF_NOWAIT`, and returns the others, in order. Only fully cached chunks are mapped in, as
+// the partly cached ones block on their reads anyway.
+fn map_cached_chunks(
+    guest_memory: &GuestMemoryMmap,
+    state: &GuestMemoryState,
+    file: &File,
+    chunks: Vec<(u64, u64, u64)>,
+    page_size: u64,
+    tuning: &PrefetchTuning,
+) -> std::result::Result<Vec<(u64, u64, u64)>, Error> {
+    let next = AtomicUsize::new(0);
+    let tasks = (0..tuning.concurrency)
+        .map(|_| {
+            let (chunks, next) = (&chunks, &next);
+            Box::new(move || {
+                let mut buf = Vec::new();
+                let mut misses = Vec::new();
+                let mut a: u8 = 0;
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let (off, len, backing_off) = match chunks.get(index) {
+                        Some(&chunk) => chunk,
+                        None => {
+                            debug_category!(DebugCategory::WsLoader, "mapped, {}", a);
+                            return Ok(misses);
+                        }
+                    };
+                    buf.resize(len as usize, 0);
+                    if !read_cached(file, &mut buf, backing_off) {
+                        misses.push(index);
+                        continue;
+                    }
+                    a ^= map_extent(guest_memory, state, off, len, page_size)?;
+                    METRICS.snapshot.ws_cached_chunks.inc();
+                    METRICS.snapshot.ws_bytes_cached.add(len as usize);
+                    RESTORE_WATCHDOG.progress();
+                }
+            }) as Task<std::result::Result<Vec<usize>, Error>>
+        })
+        .collect();
+    let mut misses = Vec::new();
+    for missed in WORKER_POOL.run(tasks) {
+        misses.extend(missed?);
     }
+    misses.sort();
+    debug_category!(
+        DebugCategory::WsLoader,
+        "{} of {} chunks in the page cache",
+        chunks.len() - misses.len(),
+        chunks.len()
+    );
+    Ok(misses.into_iter().map(|index| chunks[index]).collect())
+}
 
-    #[test]
-    fn test_restore_memory() {
-        let page_size: usize = sysconf::page::pagesize();
+// Reads `buf.len()` bytes of `file` at `offset` without waiting for I/O. Returns whether they
+// were all in the page cache. Kernels or filesystems without `RWF_NOWAIT` have none cached.
+fn read_cached(file: &File, buf: &mut [u8], offset: u64) -> bool {
+    let iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // Safe because the kernel only writes to `buf`, whose length `iov` holds. The offset is
+    // passed whole in its low half on 64-bit architectures.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_preadv2,
+            file.as_raw_fd(),
+            &iov as *const libc::iovec,
+            1,
+            offset,
+            0,
+            RWF_NOWAIT,
+        )
+    };
+    ret == buf.len() as i64
+}
+
+// Touches each page of the `len` bytes at `off` in the memory file layout. Returns the bytes
+// read, folded together.
+fn map_extent(
+    guest_memory: &GuestMemoryMmap,
+    state: &GuestMemoryState,
+    off: u64,
+    len: u64,
+    page_size: u64,
+) -> std::result::Result<u8, Error> {
+    let mut a: u8 = 0;
+    for chunk in state.translate_extent(off, len)? {
+        let region = &state.regions[chunk.region_index];
+        let addr = guest_memory
+            .get_host_address(GuestAddress(region.base_address + chunk.region_offset))
+            .map_err(|_| Error::InvalidExtent(off, len))?;
+        for pos in (0..chunk.len).step_by(page_size as usize) {
+            // Safe because the page is in mapped guest memory.
+            unsafe { a ^= *((addr as *const u8).offset(pos as isize)) };
+        }
+    }
+    Ok(a)
+}
+
+// Faults in the pages of the `len` bytes at `off` in the memory file layout, but for the ones in
+// the page cache, which alone take tokens from `coordinator`. Returns the bytes read, folded
+// together.
+fn load_extent(
+    guest_memory: &GuestMemoryMmap,
+    state: &GuestMemoryState,
+    off: u64,
+    len: u64,
+    page_size: u64,
+    throttle: &Mutex<Option<&mut PrefetchThrottle>>,
+    coordinator: Option<&PrefetchCoordinator>,
+) -> std::result::Result<u8, Error> {
+    let mut a: u8 = 0;
+    probes::fc_probe_prefetch_extent_start(off, len);
+    RESTORE_WATCHDOG.set_extent(off, len);
+    for chunk in state.translate_extent(off, len)? {
+        let region = &state.regions[chunk.region_index];
+        let guest_addr = region.base_address + chunk.region_offset;
+        let addr = guest_memory
+            .get_host_address(GuestAddress(guest_addr))
+            .map_err(|_| Error::InvalidExtent(off, len))?;
+        let resident = residency(addr, chunk.len, page_size);
+        if let Some(coordinator) = coordinator {
+            let missing = (0..chunk.len)
+                .step_by(page_size as usize)
+                .enumerate()
+                .filter(|&(page, _)| resident.get(page).map_or(true, |state| state & 1 == 0))
+                .count() as u64;
+            match coordinator.acquire(missing * page_size) {
+                Ok(waited_us) => METRICS
+                    .snapshot
+                    .ws_prefetch_coordinated_us
+                    .add(waited_us as usize),
+                Err(e) => warn!("Cannot take the prefetch bandwidth: {}", e),
+            }
+        }
+        let mut cached = 0;
+        for (page, pos) in (0..chunk.len).step_by(page_size as usize).enumerate() {
+            // Pages in the page cache fault in without I/O when the guest accesses them.
+            if resident.get(page).map_or(false, |state| state & 1 != 0) {
+                cached += page_size;
+                continue;
+            }
+            if let Some(throttle) = throttle.lock().expect("Poisoned lock").as_mut() {
+                throttle.throttle();
+            }
+            // Each first touch faults the page in, time how long it takes to service.
+            let start_ns = get_time_ns(ClockType::Monotonic);
+            unsafe { a ^= *((addr as *const u8).offset(pos as isize)) };
+            let fault_ns = get_time_ns(ClockType::Monotonic) - start_ns;
+            let fault_us = fault_ns / 1000;
+            METRICS.snapshot.page_fault_service_us.record(fault_us);
+            probes::fc_probe_fault_service(guest_addr + pos, fault_ns);
+            RESTORE_WATCHDOG.progress();
+        }
+        METRICS
+            .snapshot
+            .ws_bytes_prefetched
+            .add((chunk.len - cached) as usize);
+        METRICS.snapshot.ws_bytes_cached.add(cached as usize);
+    }
+    probes::fc_probe_prefetch_extent_end(off, len);
+    Ok(a)
+}
+
+// Leaves the `chunks` the working set load did not get to for the guest to fault in, after
+// asking the kernel to read them ahead when `readahead` is set. Returns their length in bytes.
+fn abandon_chunks(
+    guest_memory: &GuestMemoryMmap,
+    state: &GuestMemoryState,
+    chunks: &[(u64, u64, u64)],
+    readahead: bool,
+) -> std::result::Result<u64, Error> {
+    let mut abandoned = 0;
+    for &(off, len, _) in chunks {
+        abandoned += len;
+        if !readahead {
+            continue;
+        }
+        for chunk in state.translate_extent(off, len)? {
+            let region = &state.regions[chunk.region_index];
+            let addr = guest_memory
+                .get_host_address(GuestAddress(region.base_address + chunk.region_offset))
+                .map_err(|_| Error::InvalidExtent(off, len))?;
+            // Safe because the range is within a guest memory mapping, and the advice only
+            // starts reading the file backing it.
+            let ret = unsafe {
+                libc::madvise(
+                    addr as *mut libc::c_void,
+                    chunk.len as usize,
+                    libc::MADV_WILLNEED,
+                )
+            };
+            if ret < 0 {
+                warn!(
+                    "Cannot read ahead the working set extent at {:#x}: {}",
+                    off,
+                    io::Error::last_os_error()
+                );
+            }
+        }
+    }
+    Ok(abandoned)
+}
+
+/// Splits the `(offset, length, backing offset)` extents into pieces of at most `max_len` bytes,
+/// in order.
+fn split_extents(extents: &[(u64, u64, u64)], max_len: u64) -> Vec<(u64, u64, u64)> {
+    let mut pieces = Vec::new();
+    for &(off, len, backing_off) in extents.iter() {
+        let mut pos = 0;
+        while pos < len {
+            let piece = std::cmp::min(max_len, len - pos);
+            pieces.push((off + pos, piece, backing_off + pos));
+            pos += piece;
+        }
+    }
+    pieces
+}
+
+/// Returns the `mincore` state of each page of the `len` bytes at `addr`, whose lowest bit tells
+/// whether the page is in the page cache. Returns no states if `mincore` fails.
+fn residency(addr: *mut u8, len: u64, page_size: u64) -> Vec<u8> {
+    let mut states = vec![0u8; ((len + page_size - 1) / page_size) as usize];
+    // Safe because the range is mapped guest memory, and `states` has a byte for each page.
+    let ret =
+        unsafe { libc::mincore(addr as *mut libc::c_void, len as usize, states.as_mut_ptr()) };
+    if ret < 0 {
+        debug_category!(
+            DebugCategory::WsLoader,
+            "mincore failed: {}",
+            io::Error::last_os_error()
+        );
+        return Vec::new();
+    }
+    states
+}
+
+/// Returns the `(memory file offset, length, backing offset)` of the (first page, pages)
+/// `ws_extents`, sorted by their offset in the file backing them so that faulting them in reads
+/// the file sequentially.
+///
+/// A WS file packs the extents back to back, in the `ws_extents` order. Otherwise the extents
+/// are read from the memory or overlay file, at their memory file offset.
+fn prefetch_order(
+    ws_extents: &[(u64, u64)],
+    page_size: u64,
+    in_ws_file: bool,
+) -> Vec<(u64, u64, u64)> {
+    let mut file_off = 0;
+    let mut extents: Vec<(u64, u64, u64)> = ws_extents
+        .iter()
+        .map(|&(page, pages)| {
+            let off = page * page_size;
+            let len = pages * page_size;
+            let backing_off = if in_ws_file { file_off } else { off };
+            file_off += len;
+            (backing_off, off, len)
+        })
+        .collect();
+    extents.sort_by_key(|&(backing_off, _, _)| backing_off);
+    extents
+        .into_iter()
+        .map(|(backing_off, off, len)| (off, len, backing_off))
+        .collect()
+}
+
+/// Returns the `[start, end)` offsets, relative to `region` and sorted by start, of the parts of
+/// `scrub_ranges` inside `region`.
+fn region_scrub_ranges(region: &GuestRegionMmap, scrub_ranges: &[ScrubRange]) -> Vec<(u64, u64)> {
+    let region_start = region.start_addr().0;
+    let region_end = region_start + region.len();
+    let mut ranges: Vec<(u64, u64)> = scrub_ranges
+        .iter()
+        .filter_map(|range| {
+            let start = std::cmp::max(range.guest_addr, region_start);
+            let end = std::cmp::min(range.guest_addr.saturating_add(range.len), region_end);
+            if start < end {
+                Some((start - region_start, end - region_start))
+            } else {
+                None
+            }
+        })
+        .collect();
+    ranges.sort();
+    ranges
+}
+
+fn overlaps_scrub_range(offset: u64, len: u64, scrub: &[(u64, u64)]) -> bool {
+    scrub
+        .iter()
+        .any(|&(start, end)| start < offset + len && offset < end)
+}
+
+// Whether `bitmap` has a bit for each page of `region`.
+fn bitmap_covers(bitmap: &[u64], region: &GuestRegionMmap) -> bool {
+    let pages = region.len() as usize / sysconf::page::pagesize();
+    bitmap.len().saturating_mul(64) >= pages
+}
+
+/// Writes the pages of `region` present in `bitmap` to `writer`, at `writer_offset` plus their
+/// offset in the region, with zeros in place of the bytes covered by `scrub`. Pages overlapping
+/// `scrub` are written, dirty or not.
+fn dump_dirty_region<T: std::io::Write + std::io::Seek>(
+    region: &GuestRegionMmap,
+    writer: &mut T,
+    writer_offset: u64,
+    bitmap: &[u64],
+    scrub: &[(u64, u64)],
+) -> std::result::Result<(), GuestMemoryError> {
+    let page_size = sysconf::page::pagesize();
+    let mut write_size = 0;
+    let mut dirty_batch_start: u64 = 0;
+
+    for (i, v) in bitmap.iter().enumerate() {
+        // Skip the clean words at once, ending the current batch.
+        let word_offset = (i * 64 * page_size) as u64;
+        if *v == 0 && !overlaps_scrub_range(word_offset, 64 * page_size as u64, scrub) {
+            if write_size > 0 {
+                write_scrubbed(region, writer, dirty_batch_start, write_size as u64, scrub)?;
+                write_size = 0;
+            }
+            continue;
+        }
+        for j in 0..64 {
+            let page_offset = ((i * 64) + j) * page_size;
+            let is_dirty_page = ((v >> j) & 1u64) != 0u64
+                || overlaps_scrub_range(page_offset as u64, page_size as u64, scrub);
+            if is_dirty_page {
+                // We are at the start of a new batch of dirty pages.
+                if write_size == 0 {
+                    // Seek forward over the unmodified pages.
+                    writer
+                        .seek(SeekFrom::Start(writer_offset + page_offset as u64))
+                        .map_err(GuestMemoryError::IOError)?;
+                    dirty_batch_start = page_offset as u64;
+                }
+                write_size += page_size;
+            } else if write_size > 0 {
+                // We are at the end of a batch of dirty pages.
+                write_scrubbed(region, writer, dirty_batch_start, write_size as u64, scrub)?;
+                write_size = 0;
+            }
+        }
+    }
+
+    if write_size > 0 {
+        write_scrubbed(region, writer, dirty_batch_start, write_size as u64, scrub)?;
+    }
+    Ok(())
+}
+
+/// Returns the `(base + offset, length)` of the runs of pages present in `bitmap`.
+fn dirty_runs(bitmap: &[u64], page_size: u64, base: u64) -> Vec<(u64, u64)> {
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    for (i, word) in bitmap.iter().enumerate().filter(|&(_, word)| *word != 0) {
+        for j in (0..64).filter(|j| (word >> j) & 1 != 0) {
+            let offset = base + (i as u64 * 64 + j) * page_size;
+            match runs.last_mut() {
+                Some((start, len)) if *start + *len == offset => *len += page_size,
+                _ => runs.push((offset, page_size)),
+            }
+        }
+    }
+    runs
+}
+
+/// Writes the `[offset, offset + len)` part of `region` to `writer`, with zeros in place of the
+/// bytes covered by `scrub`. Guest memory itself is left untouched.
+fn write_scrubbed<T: std::io::Write>(
+    region: &GuestRegionMmap,
+    writer: &mut T,
+    offset: u64,
+    len: u64,
+    scrub: &[(u64, u64)],
+) -> std::result::Result<(), GuestMemoryError> {
+    let end = offset + len;
+    let mut cur = offset;
+    for &(scrub_start, scrub_end) in scrub.iter() {
+        if scrub_end <= cur || scrub_start >= end {
+            continue;
+        }
+        if scrub_start > cur {
+            region.write_all_to(
+                MemoryRegionAddress(cur),
+                writer,
+                (scrub_start - cur) as usize,
+            )?;
+            cur = scrub_start;
+        }
+        let zeros_end = std::cmp::min(scrub_end, end);
+        io::copy(&mut io::repeat(0).take(zeros_end - cur), writer)
+            .map_err(GuestMemoryError::IOError)?;
+        cur = zeros_end;
+    }
+    if cur < end {
+        region.write_all_to(MemoryRegionAddress(cur), writer, (end - cur) as usize)?;
+    }
+    METRICS.snapshot.bytes_dumped.add(len as usize);
+    Ok(())
+}
+
+/// Returns the `(first page, number of pages)` extents covering `extents`, sorted by first page,
+/// with the adjacent and overlapping ones merged so that each takes a single mapping.
+fn coalesce_extents<I: IntoIterator<Item = (i64, i64)>>(extents: I) -> Vec<(u64, u64)> {
+    let mut sorted: Vec<(u64, u64)> = extents
+        .into_iter()
+        .filter(|&(page, pages)| page >= 0 && pages > 0)
+        .map(|(page, pages)| (page as u64, (page as u64).saturating_add(pages as u64)))
+        .collect();
+    sorted.sort_unstable();
+
+    let mut coalesced: Vec<(u64, u64)> = Vec::with_capacity(sorted.len());
+    for (start, end) in sorted {
+        match coalesced.last_mut() {
+            Some(last) if start <= last.1 => last.1 = std::cmp::max(last.1, end),
+            _ => coalesced.push((start, end)),
+        }
+    }
+    coalesced
+        .into_iter()
+        .map(|(start, end)| (start, end - start))
+        .collect()
+}
+
+// Converts the [first page, pages] `ws_regions` to (first page, pages) extents, checking that
+// their byte ranges fit in the file offsets.
+fn parse_ws_regions(
+    ws_regions: &[Vec<i64>],
+    page_size: u64,
+) -> std::result::Result<Vec<(u64, u64)>, Error> {
+    ws_regions
+        .iter()
+        .map(|region| match region.as_slice() {
+            [page, pages] if *page >= 0 && *pages >= 0 => {
+                let (page, pages) = (*page as u64, *pages as u64);
+                page.checked_add(pages)
+                    .and_then(|end| end.checked_mul(page_size))
+                    .map(|_| (page, pages))
+                    .ok_or_else(|| Error::InvalidWsRegion(region.clone()))
+            }
+            _ => Err(Error::InvalidWsRegion(region.clone())),
+        })
+        .collect()
+}
+
+/// Returns the pages mapped by both the overlay and the working set layers, as coalesced
+/// (page, number of pages) extents of the memory file. The working set is mapped last, so it
+/// wins over the overlay on these pages.
+pub fn layer_conflicts(
+    overlay_regions: &HashMap<i64, i64>,
+    ws_regions: &[Vec<i64>],
+) -> Vec<(u64, u64)> {
+    let overlay = coalesce_extents(overlay_regions.iter().map(|(&page, &pages)| (page, pages)));
+    let ws = coalesce_extents(
+        ws_regions
+            .iter()
+            .filter_map(|region| match region.as_slice() {
+                [page, pages] => Some((*page, *pages)),
+                _ => None,
+            }),
+    );
+    // Both lists are sorted and their extents disjoint, so a merge walk finds the overlaps.
+    let (mut i, mut j) = (0, 0);
+    let mut conflicts = Vec::new();
+    while i < overlay.len() && j < ws.len() {
+        let (overlay_start, overlay_end) = (overlay[i].0, overlay[i].0 + overlay[i].1);
+        let (ws_start, ws_end) = (ws[j].0, ws[j].0 + ws[j].1);
+        let start = std::cmp::max(overlay_start, ws_start);
+        let end = std::cmp::min(overlay_end, ws_end);
+        if start < end {
+            conflicts.push((start, end - start));
+        }
+        if overlay_end < ws_end {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    conflicts
+}
+
+// Returns the number of memory mappings of the process.
+fn mapped_areas() -> io::Result<usize> {
+    let mut maps = String::new();
+    File::open(MAPS_PATH)?.read_to_string(&mut maps)?;
+    Ok(maps.lines().count())
+}
+
+// Returns the maximum number of memory mappings a process may have.
+fn max_map_count() -> io::Result<usize> {
+    std::fs::read_to_string(MAX_MAP_COUNT_PATH)?
+        .trim()
+        .parse()
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
+}
+
+// Returns an upper bound of the memory mappings added by layering `overlay_extents` and
+// `ws_extents` over the base layer of `state`.
+//
+// Each layered mapping is one more mapping, and splits the base layer mapping it lands in, so
+// each run of adjacent layered extents adds one more base layer mapping. Extents crossing a
+// region boundary take a mapping per region.
+fn layered_areas(
+    state: &GuestMemoryState,
+    overlay_extents: &[(u64, u64)],
+    ws_extents: &[(u64, u64)],
+) -> usize {
+    let runs = coalesce_extents(
+        overlay_extents
+            .iter()
+            .map(|&(page, pages)| (page as i64, pages as i64))
+            .chain(
+                ws_extents
+                    .iter()
+                    .map(|&(page, pages)| (page as i64, pages as i64)),
+            ),
+    );
+    overlay_extents.len() + ws_extents.len() + runs.len() + state.regions.len()
+}
+
+// Fails if layering the extents may take the process above `vm.max_map_count`, where the
+// kernel would start failing the mappings halfway through the restore.
+fn check_map_count(
+    state: &GuestMemoryState,
+    overlay_extents: &[(u64, u64)],
+    ws_extents: &[(u64, u64)],
+) -> std::result::Result<(), Error> {
+    if overlay_extents.is_empty() && ws_extents.is_empty() {
+        return Ok(());
+    }
+    let (mapped, max) = match (mapped_areas(), max_map_count()) {
+        (Ok(mapped), Ok(max)) => (mapped, max),
+        (Err(err), _) | (_, Err(err)) => {
+            warn!(
+                "Cannot check the memory mappings against vm.max_map_count: {}",
+                err
+            );
+            return Ok(());
+        }
+    };
+    let needed = mapped + layered_areas(state, overlay_extents, ws_extents);
+    debug_category!(
+        DebugCategory::Overlay,
+        "memory mappings. mapped={:?}, needed={:?}, max={:?}",
+        mapped,
+        needed,
+        max
+    );
+    if needed > max {
+        return Err(Error::TooManyMappings(needed, max));
+    }
+    Ok(())
+}
+
+// Fails on the first of the (file page, number of pages) `extents` ending past the end of
+// `file`. An extent ending past the largest file offset ends at `u64::MAX`.
+fn check_extents_in_file<I: Iterator<Item = (u64, u64)>>(
+    file: &File,
+    extents: I,
+    page_size: u64,
+) -> std::result::Result<(), Error> {
+    let file_len = file.metadata().map_err(Error::FileHandle)?.len();
+    for (index, (page, pages)) in extents.enumerate() {
+        let end = page
+            .checked_add(pages)
+            .and_then(|end| end.checked_mul(page_size))
+            .unwrap_or(std::u64::MAX);
+        if end > file_len {
+            let name = backing_files::file_name(file);
+            return Err(Error::ExtentPastEof(name, index, end, file_len));
+        }
+    }
+    Ok(())
+}
+
+// Returns the bytes that can still be committed according to the `meminfo` of the host: the
+// room left under the commit limit when the host never overcommits, as per `overcommit_memory`,
+// the memory and swap available otherwise.
+fn available_memory(meminfo: &str, overcommit_memory: u8) -> Option<u64> {
+    let field = |name: &str| {
+        meminfo.lines().find_map(|line| {
+            let mut words = line.split_whitespace();
+            if words.next()? != name {
+                return None;
+            }
+            let kib: u64 = words.next()?.parse().ok()?;
+            Some(kib << 10)
+        })
+    };
+    match overcommit_memory {
+        2 => Some(field("CommitLimit:")?.saturating_sub(field("Committed_AS:")?)),
+        _ => Some(field("MemAvailable:")? + field("SwapFree:")?),
+    }
+}
+
+// Fails if the guest memory of `state` and the `extra_regions` do not fit in the memory the host
+// has available. The check is skipped, with a warning, when the host does not tell.
+fn check_available_memory(
+    state: &GuestMemoryState,
+    extra_regions: &[(GuestAddress, usize)],
+) -> std::result::Result<(), Error> {
+    let sizes = state.regions.iter().map(|region| region.size);
+    let needed: u64 = sizes
+        .chain(extra_regions.iter().map(|&(_, size)| size))
+        .map(|size| size as u64)
+        .sum();
+    let meminfo = std::fs::read_to_string(MEMINFO_PATH);
+    let overcommit_memory = std::fs::read_to_string(OVERCOMMIT_MEMORY_PATH);
+    let available = match (meminfo, overcommit_memory) {
+        (Ok(meminfo), Ok(mode)) => mode
+            .trim()
+            .parse()
+            .ok()
+            .and_then(|mode| available_memory(&meminfo, mode)),
+        _ => None,
+    };
+    let available = match available {
+        Some(available) => available,
+        None => {
+            warn!("Cannot check the guest memory against the memory available");
+            return Ok(());
+        }
+    };
+    debug_category!(
+        DebugCategory::Overlay,
+        "guest memory. needed={:?}, available={:?}",
+        needed,
+        available
+    );
+    if needed > available {
+        return Err(Error::InsufficientMemory(needed, available));
+    }
+    Ok(())
+}
+
+/// Builds the mapping of a guest memory region of `size` bytes, at a host address aligned to
+/// `HUGE_PAGE_SIZE` where possible, so that its guest huge pages line up with host ones which
+/// khugepaged can collapse.
+///
+/// A range larger by the alignment is reserved first, then the aligned window inside it is
+/// released, and the region mapped in its place: the kernel lays mappings out in the highest
+/// gap they fit, which is the window unless another gap above the reservation also fits. The
+/// rest of the reservation is released afterwards. None of these mappings use `MAP_FIXED`.
+fn build_aligned(
+    file_offset: Option<FileOffset>,
+    size: usize,
+    prot: i32,
+    flags: i32,
+) -> std::result::Result<MmapRegion, vm_memory::mmap::MmapRegionError> {
+    let reserved_size = size + HUGE_PAGE_SIZE;
+    let reserved = if size >= HUGE_PAGE_SIZE {
+        // Safe because the reservation is a new mapping, which no memory is borrowed from.
+        unsafe {
+            libc::mmap(
+                null_mut(),
+                reserved_size,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        }
+    } else {
+        libc::MAP_FAILED
+    };
+    if reserved == libc::MAP_FAILED {
+        return MmapRegion::build(file_offset, size, prot, flags);
+    }
+
+    let (window, rest) = reservation_window(reserved as usize, size);
+    // Safe because these ranges lie within the reservation, which nothing else uses.
+    unsafe {
+        libc::munmap(window as _, size);
+    }
+    let mmap_region = MmapRegion::build(file_offset, size, prot, flags);
+    for (addr, len) in rest.iter() {
+        unsafe {
+            libc::munmap(*addr as _, *len);
+        }
+    }
+
+    if let Ok(region) = mmap_region.as_ref() {
+        if region.as_ptr() as usize % HUGE_PAGE_SIZE != 0 {
+            METRICS.snapshot.unaligned_regions.inc();
+        }
+    }
+    mmap_region
+}
+
+// Returns the address of the window of `size` bytes aligned to `HUGE_PAGE_SIZE` in the
+// reservation of `size + HUGE_PAGE_SIZE` bytes at `reserved`, and the (address, length) of the
+// parts of the reservation before and after it.
+fn reservation_window(reserved: usize, size: usize) -> (usize, [(usize, usize); 2]) {
+    let window = (reserved + HUGE_PAGE_SIZE - 1) / HUGE_PAGE_SIZE * HUGE_PAGE_SIZE;
+    let reserved_end = reserved + size + HUGE_PAGE_SIZE;
+    (
+        window,
+        [
+            (reserved, window - reserved),
+            (window + size, reserved_end - window - size),
+        ],
+    )
+}
+
+/// Maps `len` bytes of `file`, starting at `file_offset`, over the guest memory backing the
+/// `[mem_offset, mem_offset + len)` extent of the memory file.
+///
+/// The extent replaces pages of the base layer, which `MAP_FIXED_NOREPLACE` refuses to do, so
+/// the mapping uses `MAP_FIXED` once the target is checked to lie within the base layer mapping
+/// of its region, where it cannot clobber any other mapping of the process.
+///
+/// With `populate`, the pages are faulted in by the `mmap` call itself. They are mapped
+/// read-only first, then made writable: populating a writable private mapping would give it a
+/// private copy of every page, while pages populated read-only stay shared with the page cache
+/// until the guest writes them, as when touched.
+///
+/// `noreserve` is either `MAP_NORESERVE` or 0, for the mapping to be charged against the commit
+/// limit.
+///
+/// Returns the host address, length and file offset of each mapping made.
+#[allow(clippy::too_many_arguments)]
+fn map_file_extent(
+    mmap_regions: &[GuestRegionMmap],
+    state: &GuestMemoryState,
+    mem_offset: u64,
+    len: u64,
+    file: &File,
+    file_offset: u64,
+    populate: bool,
+    noreserve: libc::c_int,
+) -> std::result::Result<Vec<(usize, usize, u64)>, Error> {
+    let (prot, flags) = match populate {
+        true => (libc::PROT_READ, libc::MAP_POPULATE),
+        false => (libc::PROT_READ | libc::PROT_WRITE, 0),
+    };
+    let mut file_offset = file_offset;
+    let mut mapped = Vec::new();
+    for chunk in state.translate_extent(mem_offset, len)? {
+        let region = mmap_regions
+            .get(chunk.region_index)
+            .filter(|region| {
+                chunk
+                    .region_offset
+                    .checked_add(chunk.len)
+                    .map_or(false, |end| end <= region.len())
+            })
+            .ok_or(Error::InvalidExtent(mem_offset, len))?;
+        let addr = region.as_ptr();
+        // Safe because the target range lies within the base layer mapping of `region`.
+        let ret = unsafe {
+            libc::mmap(
+                addr.offset(chunk.region_offset as isize) as _,
+                chunk.len as usize,
+                prot,
+                libc::MAP_FIXED | noreserve | libc::MAP_PRIVATE | flags,
+                file.as_raw_fd(),
+                file_offset as libc::off_t,
+            )
+        };
+        if ret == libc::MAP_FAILED {
+            return Err(Error::OverlayRegions(std::io::Error::last_os_error()));
+        }
+        if populate {
+            let prot = libc::PROT_READ | libc::PROT_WRITE;
+            // Safe because the range is the mapping just made.
+            if unsafe { libc::mprotect(ret, chunk.len as usize, prot) } < 0 {
+                return Err(Error::OverlayRegions(std::io::Error::last_os_error()));
+            }
+        }
+        mapped.push((ret as usize, chunk.len as usize, file_offset));
+        file_offset += chunk.len;
+    }
+    Ok(mapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use std::os::unix::fs::FileExt;
+
+    use super::*;
+    use crate::version_map::VERSION_MAP;
+    #[cfg(feature = "faasnap")]
+    use utils::tempdir::TempDir;
+    use utils::tempfile::TempFile;
+    use vm_memory::GuestAddress;
+
+    #[test]
+    fn test_reservation_window() {
+        let size = 2 * HUGE_PAGE_SIZE + 4096;
+        for reserved in [
+            HUGE_PAGE_SIZE,
+            HUGE_PAGE_SIZE + 4096,
+            2 * HUGE_PAGE_SIZE - 4096,
+            0x7f00_0012_3000,
+        ]
+        .iter()
+        {
+            let (window, [before, after]) = reservation_window(*reserved, size);
+            assert_eq!(window % HUGE_PAGE_SIZE, 0);
+            assert_eq!(before, (*reserved, window - reserved));
+            assert!(before.1 < HUGE_PAGE_SIZE);
+            assert_eq!(after.0, window + size);
+            assert_eq!(before.1 + after.1, HUGE_PAGE_SIZE);
+        }
+        // An aligned reservation leaves nothing before the window.
+        assert_eq!(
+            reservation_window(HUGE_PAGE_SIZE, size),
+            (
+                HUGE_PAGE_SIZE,
+                [(HUGE_PAGE_SIZE, 0), (HUGE_PAGE_SIZE + size, HUGE_PAGE_SIZE)]
+            )
+        );
+    }
+
+    #[test]
+    fn test_build_aligned() {
+        // Whether the region lands in the window depends on the other mappings of the process,
+        // which the other test threads change, so only its size is checked here.
+        let region = build_aligned(
+            None,
+            2 * HUGE_PAGE_SIZE + 4096,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+        )
+        .unwrap();
+        assert_eq!(region.len(), 2 * HUGE_PAGE_SIZE + 4096);
+
+        // Regions smaller than a huge page are mapped as usual.
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(4096).unwrap();
+        let region = build_aligned(
+            Some(FileOffset::new(file.as_file().try_clone().unwrap(), 0)),
+            4096,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_NORESERVE | libc::MAP_PRIVATE,
+        )
+        .unwrap();
+        assert_eq!(region.len(), 4096);
+        assert!(region.file_offset().is_some());
+    }
+
+    #[test]
+    fn test_available_memory() {
+        let meminfo = "MemTotal:       16000000 kB\n\
+                       MemFree:         1000000 kB\n\
+                       MemAvailable:    8000000 kB\n\
+                       SwapFree:        1000000 kB\n\
+                       CommitLimit:    10000000 kB\n\
+                       Committed_AS:    4000000 kB\n";
+        assert_eq!(available_memory(meminfo, 0), Some(9000000 << 10));
+        assert_eq!(available_memory(meminfo, 1), Some(9000000 << 10));
+        assert_eq!(available_memory(meminfo, 2), Some(6000000 << 10));
+
+        // Past the commit limit, nothing is left.
+        let meminfo = "CommitLimit: 1000 kB\nCommitted_AS: 2000 kB\n";
+        assert_eq!(available_memory(meminfo, 2), Some(0));
+        assert_eq!(available_memory(meminfo, 0), None);
+
+        // An empty guest memory always fits, a huge one never does.
+        let state = GuestMemoryState::default();
+        check_available_memory(&state, &[]).unwrap();
+        match check_available_memory(&state, &[(GuestAddress(0), usize::MAX)]) {
+            Err(Error::InsufficientMemory(needed, _)) => assert_eq!(needed, usize::MAX as u64),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_coalesce_extents() {
+        let mut extents = HashMap::new();
+        assert!(coalesce_extents(extents.clone()).is_empty());
+
+        // Adjacent single pages, an overlapping extent, one inside another and a lone one.
+        for page in 10..20 {
+            extents.insert(page, 1);
+        }
+        extents.insert(18, 4);
+        extents.insert(30, 10);
+        extents.insert(32, 2);
+        extents.insert(50, 1);
+        // Empty extents are left out.
+        extents.insert(60, 0);
+        assert_eq!(coalesce_extents(extents), vec![(10, 12), (30, 10), (50, 1)]);
+    }
+
+    #[test]
+    fn test_layer_conflicts() {
+        let mut overlay_regions = HashMap::new();
+        assert!(layer_conflicts(&overlay_regions, &[vec![0, 4]]).is_empty());
+
+        overlay_regions.insert(0, 4);
+        overlay_regions.insert(10, 10);
+        overlay_regions.insert(30, 2);
+        // One working set extent straddles two overlay extents, another sits inside one, and
+        // the last ones only touch the overlay.
+        let ws_regions = vec![vec![2, 10], vec![15, 2], vec![20, 10], vec![32, 1]];
+        assert_eq!(
+            layer_conflicts(&overlay_regions, &ws_regions),
+            vec![(2, 2), (10, 2), (15, 2)]
+        );
+    }
+
+    #[test]
+    fn test_check_map_count() {
+        let state = GuestMemoryState {
+            regions: vec![GuestMemoryRegionState {
+                base_address: 0,
+                size: 0x10_0000,
+                offset: 0,
+            }],
+            page_size: sysconf::page::pagesize(),
+        };
+        // Two overlay extents and a working set extent next to the first one make two runs.
+        let overlay_extents = vec![(0, 4), (16, 1)];
+        let ws_extents = vec![(4, 2)];
+        assert_eq!(layered_areas(&state, &overlay_extents, &ws_extents), 6);
+
+        assert!(mapped_areas().unwrap() > 0);
+        assert!(max_map_count().unwrap() > 0);
+        assert!(check_map_count(&state, &[], &[]).is_ok());
+        assert!(check_map_count(&state, &overlay_extents, &ws_extents).is_ok());
+
+        // More extents than the process may map.
+        let ws_extents: Vec<(u64, u64)> = (0..max_map_count().unwrap() as u64)
+            .map(|page| (2 * page, 1))
+            .collect();
+        match check_map_count(&state, &[], &ws_extents) {
+            Err(Error::TooManyMappings(needed, max)) => assert!(needed > max),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_residency() {
+        let page_size = sysconf::page::pagesize();
+        let region = MmapRegion::new(4 * page_size).unwrap();
+        // Only the written page is resident.
+        unsafe { *region.as_ptr().add(page_size) = 1 };
+        let states = residency(region.as_ptr(), 4 * page_size as u64, page_size as u64);
+        let resident: Vec<u8> = states.iter().map(|state| state & 1).collect();
+        assert_eq!(resident, vec![0, 1, 0, 0]);
+        // Unaligned addresses fail.
+        let states = residency(
+            unsafe { region.as_ptr().add(1) },
+            page_size as u64,
+            page_size as u64,
+        );
+        assert!(states.is_empty());
+    }
+
+    #[test]
+    fn test_map_file_extent() {
+        let page_size = sysconf::page::pagesize();
+        let region = MmapRegion::new(4 * page_size).unwrap();
+        let regions = vec![GuestRegionMmap::new(region, GuestAddress(0)).unwrap()];
+        let state = GuestMemoryState {
+            regions: vec![GuestMemoryRegionState {
+                base_address: 0,
+                size: 4 * page_size,
+                offset: 0,
+            }],
+            page_size,
+        };
+        let file = TempFile::new().unwrap();
+        std::fs::write(file.as_path(), vec![0xaa; 2 * page_size]).unwrap();
+
+        for &populate in [false, true].iter() {
+            let mapped = map_file_extent(
+                &regions,
+                &state,
+                page_size as u64,
+                2 * page_size as u64,
+                file.as_file(),
+                0,
+                populate,
+                libc::MAP_NORESERVE,
+            )
+            .unwrap();
+            let addr = regions[0].as_ptr();
+            let expected = (addr as usize + page_size, 2 * page_size, 0);
+            assert_eq!(mapped, vec![expected]);
+            unsafe {
+                assert_eq!(*addr, 0);
+                assert_eq!(*addr.add(page_size), 0xaa);
+                assert_eq!(*addr.add(3 * page_size - 1), 0xaa);
+                assert_eq!(*addr.add(3 * page_size), 0);
+                // The extent is writable, and private.
+                *addr.add(page_size) = 1;
+            }
+            let mut content = Vec::new();
+            File::open(file.as_path())
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            assert!(content.iter().all(|&byte| byte == 0xaa));
+        }
+
+        // Extents past the region are rejected.
+        assert!(map_file_extent(
+            &regions,
+            &state,
+            3 * page_size as u64,
+            2 * page_size as u64,
+            file.as_file(),
+            0,
+            true,
+            0,
+        )
+        .is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "faasnap")]
+    fn test_upf_registration() {
+        let page_size = sysconf::page::pagesize();
+        let mem_regions = [
+            (GuestAddress(0), page_size),
+            (GuestAddress(0x10000), page_size),
+        ];
+        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions[..]).unwrap();
+        let other_memory = GuestMemoryMmap::from_ranges(&mem_regions[..1]).unwrap();
+
+        let registration = UpfRegistration::new(&guest_memory).unwrap();
+        let addr = guest_memory.get_host_address(GuestAddress(0)).unwrap() as usize;
+        match UpfRegistration::new(&guest_memory) {
+            Err(Error::AlreadyRegistered(registered)) => assert_eq!(registered, addr),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        // Other guest memory is registered independently.
+        let _other = UpfRegistration::new(&other_memory).unwrap();
+
+        // Once dropped, the guest memory can be registered again.
+        drop(registration);
+        let _registration = UpfRegistration::new(&guest_memory).unwrap();
+    }
+
+    #[test]
+    fn test_prefetch_order() {
+        let ws_regions = vec![(8, 2), (1, 1), (4, 3)];
+        // Packed in a WS file, the extents are read in their order.
+        assert_eq!(
+            prefetch_order(&ws_regions, 0x1000, true),
+            vec![
+                (0x8000, 0x2000, 0),
+                (0x1000, 0x1000, 0x2000),
+                (0x4000, 0x3000, 0x3000)
+            ]
+        );
+        // From the memory file, the extents are read by memory file offset.
+        assert_eq!(
+            prefetch_order(&ws_regions, 0x1000, false),
+            vec![
+                (0x1000, 0x1000, 0x1000),
+                (0x4000, 0x3000, 0x4000),
+                (0x8000, 0x2000, 0x8000)
+            ]
+        );
+        assert!(prefetch_order(&[], 0x1000, false).is_empty());
+    }
+
+    #[test]
+    fn test_split_extents() {
+        let extents = vec![
+            (0x8000, 0x2000, 0),
+            (0x1000, 0x1000, 0x2000),
+            (0x4000, 0x5000, 0x3000),
+        ];
+        assert_eq!(split_extents(&extents, u64::max_value()), extents);
+        assert_eq!(
+            split_extents(&extents, 0x2000),
+            vec![
+                (0x8000, 0x2000, 0),
+                (0x1000, 0x1000, 0x2000),
+                (0x4000, 0x2000, 0x3000),
+                (0x6000, 0x2000, 0x5000),
+                (0x8000, 0x1000, 0x7000),
+            ]
+        );
+        assert!(split_extents(&[], 0x1000).is_empty());
+    }
+
+    #[test]
+    fn test_dirty_runs() {
+        assert!(dirty_runs(&[0, 0], 0x1000, 0).is_empty());
+        // Runs carry over from one word to the next.
+        assert_eq!(
+            dirty_runs(&[0b1101 | 1 << 63, 0b1], 0x1000, 0x10000),
+            vec![
+                (0x10000, 0x1000),
+                (0x12000, 0x2000),
+                (0x10000 + 63 * 0x1000, 0x2000)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_cached() {
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all_at(&[0xab; 0x2000], 0).unwrap();
+        let mut buf = vec![0u8; 0x1000];
+        // Just written, the file is in the page cache, unless it does not support `RWF_NOWAIT`.
+        if read_cached(file.as_file(), &mut buf, 0x1000) {
+            assert!(buf.iter().all(|&b| b == 0xab));
+        }
+        // Past the end of the file, the read is short.
+        assert!(!read_cached(file.as_file(), &mut buf, 0x1800));
+        assert!(!read_cached(file.as_file(), &mut buf, 0x4000));
+    }
+
+    #[test]
+    #[cfg(feature = "faasnap")]
+    fn test_uffd_socket() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("uffd.sock");
+
+        // A socket left behind is replaced.
+        drop(UnixListener::bind(&path).unwrap());
+        let socket = UffdSocket::listen(&path).unwrap();
+        let _handler = UnixStream::connect(&path).unwrap();
+        assert!(socket.accept().is_ok());
+        // A socket in use is not.
+        match UffdSocket::listen(&path) {
+            Err(Error::UffdHandler(_)) => (),
+            _ => panic!("A socket in use should not be replaced."),
+        }
+
+        // The socket is removed after the handoffs, and bound again by the next registration.
+        drop(socket);
+        assert!(!path.exists());
+        drop(UffdSocket::listen(&path).unwrap());
+        assert!(!path.exists());
+
+        // A file which is not a socket is left alone.
+        std::fs::write(&path, b"").unwrap();
+        match UffdSocket::listen(&path) {
+            Err(Error::UffdHandler(_)) => assert!(path.exists()),
+            _ => panic!("A regular file should not be replaced."),
+        }
+    }
+
+    #[test]
+    fn test_describe_state() {
+        let page_size: usize = sysconf::page::pagesize();
+
+        // Two regions of one page each, with a one page gap between them.
+        let mem_regions = [
+            (GuestAddress(0), page_size),
+            (GuestAddress(page_size as u64 * 2), page_size),
+        ];
+        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions[..]).unwrap();
+
+        let expected_memory_state = GuestMemoryState {
+            regions: vec![
+                GuestMemoryRegionState {
+                    base_address: 0,
+                    size: page_size,
+                    offset: 0,
+                },
+                GuestMemoryRegionState {
+                    base_address: page_size as u64 * 2,
+                    size: page_size,
+                    offset: page_size as u64,
+                },
+            ],
+            page_size,
+        };
+
+        let actual_memory_state = guest_memory.describe();
+        assert_eq!(expected_memory_state, actual_memory_state);
+
+        // Two regions of three pages each, with a one page gap between them.
+        let mem_regions = [
+            (GuestAddress(0), page_size * 3),
+            (GuestAddress(page_size as u64 * 4), page_size * 3),
+        ];
+        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions[..]).unwrap();
+
+        let expected_memory_state = GuestMemoryState {
+            regions: vec![
+                GuestMemoryRegionState {
+                    base_address: 0,
+                    size: page_size * 3,
+                    offset: 0,
+                },
+                GuestMemoryRegionState {
+                    base_address: page_size as u64 * 4,
+                    size: page_size * 3,
+                    offset: page_size as u64 * 3,
+                },
+            ],
+            page_size,
+        };
+
+        let actual_memory_state = guest_memory.describe();
+        assert_eq!(expected_memory_state, actual_memory_state);
+        assert_eq!(actual_memory_state.file_size(), page_size as u64 * 6);
+        assert_eq!(GuestMemoryState::default().file_size(), 0);
+    }
+
+    #[test]
+    fn test_page_size() {
+        let mut state = GuestMemoryState {
+            regions: vec![GuestMemoryRegionState {
+                base_address: 0,
+                size: 0x10_0000,
+                offset: 0,
+            }],
+            page_size: 0x1_0000,
+        };
+        match state.check_page_size() {
+            Err(Error::PageSize(0x1_0000, host)) => assert_eq!(host, sysconf::page::pagesize()),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert_eq!(
+            Error::PageSize(0x1_0000, 0x1000).to_string(),
+            "The snapshot was taken with 65536 byte pages, the host has 4096 byte pages"
+        );
+
+        // The page size is saved from the second data version, and 4K before it.
+        let mut buf = Vec::new();
+        state.serialize(&mut buf, &VERSION_MAP, 2).unwrap();
+        let restored = GuestMemoryState::deserialize(&mut buf.as_slice(), &VERSION_MAP, 2).unwrap();
+        assert_eq!(restored, state);
+        let mut buf = Vec::new();
+        state.serialize(&mut buf, &VERSION_MAP, 1).unwrap();
+        let restored = GuestMemoryState::deserialize(&mut buf.as_slice(), &VERSION_MAP, 1).unwrap();
+        assert_eq!(restored.page_size, 4096);
+
+        state.page_size = sysconf::page::pagesize();
+        assert!(state.check_page_size().is_ok());
+    }
+
+    #[test]
+    fn test_translate_extent() {
+        let page_size = sysconf::page::pagesize() as u64;
+
+        // Two regions of two pages each, separated by a one page gap in guest physical memory.
+        let state = GuestMemoryState {
+            regions: vec![
+                GuestMemoryRegionState {
+                    base_address: 0,
+                    size: page_size as usize * 2,
+                    offset: 0,
+                },
+                GuestMemoryRegionState {
+                    base_address: page_size * 3,
+                    size: page_size as usize * 2,
+                    offset: page_size * 2,
+                },
+            ],
+            page_size: sysconf::page::pagesize(),
+        };
+
+        // Extent inside the first region.
+        assert_eq!(
+            state.translate_extent(0, page_size).unwrap(),
+            vec![ExtentChunk {
+                region_index: 0,
+                region_offset: 0,
+                len: page_size,
+            }]
+        );
+
+        // Extent inside the second region is relative to that region's file offset.
+        assert_eq!(
+            state.translate_extent(page_size * 3, page_size).unwrap(),
+            vec![ExtentChunk {
+                region_index: 1,
+                region_offset: page_size,
+                len: page_size,
+            }]
+        );
+
+        // Extent straddling both regions is split.
+        assert_eq!(
+            state.translate_extent(page_size, page_size * 2).unwrap(),
+            vec![
+                ExtentChunk {
+                    region_index: 0,
+                    region_offset: page_size,
+                    len: page_size,
+                },
+                ExtentChunk {
+                    region_index: 1,
+                    region_offset: 0,
+                    len: page_size,
+                },
+            ]
+        );
+
+        // Extent past the end of the memory file.
+        assert!(state
+            .translate_extent(page_size * 3, page_size * 2)
+            .is_err());
+        assert!(state.translate_extent(u64::max_value(), page_size).is_err());
+    }
+
+    #[test]
+    fn test_extra_regions() {
+        const MIB: usize = 1 << 20;
+        let state = GuestMemoryState {
+            regions: vec![GuestMemoryRegionState {
+                base_address: 0,
+                size: 256 * MIB,
+                offset: 0,
+            }],
+            page_size: sysconf::page::pagesize(),
+        };
+
+        assert_eq!(state.extra_regions(256).unwrap(), vec![]);
+        assert_eq!(
+            state.extra_regions(512).unwrap(),
+            vec![(GuestAddress(256 * MIB as u64), 256 * MIB)]
+        );
+        // Past the MMIO gap, the memory is added on both sides of it.
+        assert_eq!(
+            state.extra_regions(4096).unwrap(),
+            vec![
+                (GuestAddress(256 * MIB as u64), 3072 * MIB),
+                (GuestAddress(1 << 32), 768 * MIB)
+            ]
+        );
+
+        // The memory cannot shrink, nor grow by a partial memory block.
+        match state.extra_regions(128) {
+            Err(Error::InvalidMemorySize(128)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match state.extra_regions(300) {
+            Err(Error::InvalidMemorySize(300)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_restore_memory() {
+        let page_size: usize = sysconf::page::pagesize();
 
         // Two regions of two pages each, with a one page gap between them.
         let mem_regions = [
@@ -478,11 +2601,14 @@ mod tests {
 
         // Case 1: dump the full memory.
         {
-            let memory_file = TempFile::new().unwrap();
-            guest_memory.dump(&mut memory_file.as_file()).unwrap();
+            let mut memory_file = Cursor::new(Vec::new());
+            guest_memory.dump(&mut memory_file, &[]).unwrap();
 
-            let restored_guest_memory =
-                GuestMemoryMmap::restore(&memory_file.as_file(), &memory_state).unwrap();
+            let layers = BufferLayers {
+                mem: Some(memory_file.get_ref()),
+                ..Default::default()
+            };
+            let restored_guest_memory = restore_from_buffers(&memory_state, &layers).unwrap();
 
             // Check that the region contents are the same.
             let mut actual_region = vec![0u8; page_size * 2];
@@ -508,13 +2634,16 @@ mod tests {
             dirty_bitmap.insert(0, vec![0b01; 1]);
             dirty_bitmap.insert(1, vec![0b10; 1]);
 
-            let file = TempFile::new().unwrap();
+            let mut file = Cursor::new(Vec::new());
             guest_memory
-                .dump_dirty(&mut file.as_file(), &dirty_bitmap)
+                .dump_dirty(&mut file, &dirty_bitmap, &[])
                 .unwrap();
 
-            let restored_guest_memory =
-                GuestMemoryMmap::restore(&file.as_file(), &memory_state).unwrap();
+            let layers = BufferLayers {
+                mem: Some(file.get_ref()),
+                ..Default::default()
+            };
+            let restored_guest_memory = restore_from_buffers(&memory_state, &layers).unwrap();
 
             // Check that only the dirty pages have been restored.
             let zeros = vec![0u8; page_size];
@@ -536,6 +2665,316 @@ mod tests {
                 )
                 .unwrap();
             assert_eq!(expected_second_region, actual_region);
+
+            // Case 3: dump the dirty pages slot by slot, in any order.
+            let mut file = Cursor::new(Vec::new());
+            for slot in [1, 0].iter() {
+                guest_memory
+                    .dump_dirty_slot(&mut file, *slot, &dirty_bitmap[slot], &[])
+                    .unwrap();
+            }
+            let layers = BufferLayers {
+                mem: Some(file.get_ref()),
+                ..Default::default()
+            };
+            let restored_guest_memory = restore_from_buffers(&memory_state, &layers).unwrap();
+            restored_guest_memory
+                .read(&mut actual_region.as_mut_slice(), GuestAddress(0))
+                .unwrap();
+            assert_eq!(expected_first_region, actual_region);
+            restored_guest_memory
+                .read(
+                    &mut actual_region.as_mut_slice(),
+                    GuestAddress(page_size as u64 * 3),
+                )
+                .unwrap();
+            assert_eq!(expected_second_region, actual_region);
+
+            // Case 4: a slot missing from the dirty bitmap fails the dump.
+            dirty_bitmap.remove(&1);
+            let file = TempFile::new().unwrap();
+            match guest_memory.dump_dirty(&mut file.as_file(), &dirty_bitmap, &[]) {
+                Err(Error::MissingDirtyBitmap(1)) => (),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+            assert_eq!(
+                Error::MissingDirtyBitmap(1).to_string(),
+                "The dirty bitmap is missing memory slot 1"
+            );
+
+            // Case 5: dumping a slot without its bitmap fails rather than skipping it.
+            let mut file = Cursor::new(Vec::new());
+            match guest_memory.dump_dirty_slot(&mut file, 0, &[], &[]) {
+                Err(Error::MissingDirtyBitmap(0)) => (),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+            match guest_memory.dump_dirty_slot(&mut file, 2, &[0b1], &[]) {
+                Err(Error::MissingDirtyBitmap(2)) => (),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+            assert!(file.get_ref().is_empty());
+            dirty_bitmap.insert(1, vec![]);
+            match guest_memory.dump_dirty(&mut file, &dirty_bitmap, &[]) {
+                Err(Error::MissingDirtyBitmap(1)) => (),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn test_restore_layers() {
+        let page_size: usize = sysconf::page::pagesize();
+        let guest_memory =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), page_size * 4)]).unwrap();
+        let memory_state = guest_memory.describe();
+        let read_pages = |memory: &GuestMemoryMmap| {
+            let mut pages = vec![0u8; page_size * 4];
+            memory
+                .read(&mut pages.as_mut_slice(), GuestAddress(0))
+                .unwrap();
+            pages
+                .chunks(page_size)
+                .map(|page| page[0])
+                .collect::<Vec<u8>>()
+        };
+
+        // The overlay covers pages 1 and 2, and the working set page 2.
+        let mem = vec![1u8; page_size * 4];
+        let overlay = vec![2u8; page_size * 4];
+        let ws = vec![3u8; page_size];
+        let mut overlay_regions = HashMap::new();
+        overlay_regions.insert(1, 2);
+        let ws_regions = vec![vec![2, 1]];
+        for populate_ws in [false, true].iter() {
+            let layers = BufferLayers {
+                mem: Some(&mem),
+                overlay: Some((&overlay, overlay_regions.clone())),
+                ws: Some((&ws, ws_regions.clone())),
+                populate_ws: *populate_ws,
+            };
+            let restored = restore_from_buffers(&memory_state, &layers).unwrap();
+            assert_eq!(read_pages(&restored), vec![1, 2, 3, 1]);
+        }
+
+        // Without a memory file, the pages outside the layers are zero.
+        let layers = BufferLayers {
+            ws: Some((&ws, ws_regions.clone())),
+            ..Default::default()
+        };
+        let restored = restore_from_buffers(&memory_state, &layers).unwrap();
+        assert_eq!(read_pages(&restored), vec![0, 0, 3, 0]);
+
+        // The extents outside the guest memory fail the restore.
+        let layers = BufferLayers {
+            mem: Some(&mem),
+            ws: Some((&ws, vec![vec![4, 1]])),
+            ..Default::default()
+        };
+        match restore_from_buffers(&memory_state, &layers) {
+            Err(Error::InvalidExtent(..)) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        // So do the extents past the end of their file, rather than a SIGBUS on access.
+        let layers = BufferLayers {
+            mem: Some(&mem),
+            ws: Some((&ws, vec![vec![0, 1], vec![2, 1]])),
+            ..Default::default()
+        };
+        match restore_from_buffers(&memory_state, &layers) {
+            Err(Error::ExtentPastEof(file, 1, end, len)) => {
+                assert!(file.contains("ws"));
+                assert_eq!((end, len), (2 * page_size as u64, page_size as u64));
+            }
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+        let layers = BufferLayers {
+            mem: Some(&mem),
+            overlay: Some((&overlay[..page_size * 2], overlay_regions.clone())),
+            ..Default::default()
+        };
+        match restore_from_buffers(&memory_state, &layers) {
+            Err(Error::ExtentPastEof(_, 0, end, _)) => assert_eq!(end, 3 * page_size as u64),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+        // Extents ending past the largest file offset end at `u64::MAX`.
+        let mut overflowing = overlay_regions.clone();
+        overflowing.insert(std::i64::MAX, 1);
+        let layers = BufferLayers {
+            mem: Some(&mem),
+            overlay: Some((&overlay, overflowing)),
+            ..Default::default()
+        };
+        match restore_from_buffers(&memory_state, &layers) {
+            Err(Error::ExtentPastEof(_, 1, end, _)) => assert_eq!(end, std::u64::MAX),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
         }
+        // Working set extents past the largest file offset, and the malformed ones, are rejected
+        // as they are parsed.
+        for region in [vec![0, std::i64::MAX], vec![-1, 1], vec![0, 1, 2], vec![0]].iter() {
+            let layers = BufferLayers {
+                mem: Some(&mem),
+                ws: Some((&ws, vec![region.clone()])),
+                ..Default::default()
+            };
+            match restore_from_buffers(&memory_state, &layers) {
+                Err(Error::InvalidWsRegion(invalid)) => assert_eq!(&invalid, region),
+                res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_restore_rollback() {
+        let page_size: usize = sysconf::page::pagesize();
+        let guest_memory =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), page_size * 4)]).unwrap();
+        let memory_state = guest_memory.describe();
+        let overlay_file = TempFile::new().unwrap();
+        overlay_file
+            .as_file()
+            .set_len(page_size as u64 * 4)
+            .unwrap();
+        let ws_file = TempFile::new().unwrap();
+        ws_file.as_file().set_len(page_size as u64).unwrap();
+        let is_mapped = |file: &TempFile| {
+            let path = file.as_path().to_str().unwrap().to_string();
+            std::fs::read_to_string(MAPS_PATH)
+                .unwrap()
+                .lines()
+                .any(|line| line.ends_with(&path))
+        };
+
+        // The overlay is mapped, then the working set fails past the guest memory.
+        let mut overlay_regions = HashMap::new();
+        overlay_regions.insert(0, 2);
+        let res = GuestMemoryMmap::restore(
+            None,
+            &memory_state,
+            &[],
+            Some(overlay_file.as_file()),
+            &overlay_regions,
+            Some(ws_file.as_file()),
+            &vec![vec![4, 1]],
+            false,
+            false,
+        );
+        match res {
+            Err(Error::InvalidExtent(..)) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+        assert!(!is_mapped(&overlay_file));
+        assert!(!is_mapped(&ws_file));
+
+        // Another restore of the same layers can be attempted.
+        let restored = GuestMemoryMmap::restore(
+            None,
+            &memory_state,
+            &[],
+            Some(overlay_file.as_file()),
+            &overlay_regions,
+            Some(ws_file.as_file()),
+            &vec![vec![3, 1]],
+            false,
+            false,
+        )
+        .unwrap();
+        assert!(is_mapped(&overlay_file));
+        drop(restored);
+        assert!(!is_mapped(&overlay_file));
+    }
+
+    #[test]
+    fn test_check_scrub_ranges() {
+        let page_size = sysconf::page::pagesize() as u64;
+
+        // Two regions of one page each, with a one page gap between them.
+        let mem_regions = [
+            (GuestAddress(0), page_size as usize),
+            (GuestAddress(page_size * 2), page_size as usize),
+        ];
+        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions[..]).unwrap();
+
+        let range = |guest_addr, len| ScrubRange { guest_addr, len };
+        guest_memory.check_scrub_ranges(&[]).unwrap();
+        guest_memory
+            .check_scrub_ranges(&[range(16, 32), range(page_size * 2, page_size)])
+            .unwrap();
+
+        // Empty range.
+        assert!(guest_memory.check_scrub_ranges(&[range(0, 0)]).is_err());
+        // Range spanning the gap between the regions.
+        assert!(guest_memory
+            .check_scrub_ranges(&[range(page_size - 1, page_size + 2)])
+            .is_err());
+        // Range past the end of guest memory.
+        assert!(guest_memory
+            .check_scrub_ranges(&[range(page_size * 3 - 1, 2)])
+            .is_err());
+        assert!(guest_memory
+            .check_scrub_ranges(&[range(u64::max_value(), 2)])
+            .is_err());
+    }
+
+    #[test]
+    fn test_dump_scrubbed() {
+        let page_size: usize = sysconf::page::pagesize();
+
+        // Two regions of two pages each, with a one page gap between them.
+        let mem_regions = [
+            (GuestAddress(0), page_size * 2),
+            (GuestAddress(page_size as u64 * 3), page_size * 2),
+        ];
+        let guest_memory = GuestMemoryMmap::from_ranges(&mem_regions[..]).unwrap();
+        let contents = vec![1u8; page_size * 2];
+        guest_memory.write(&contents[..], GuestAddress(0)).unwrap();
+        guest_memory
+            .write(&contents[..], GuestAddress(page_size as u64 * 3))
+            .unwrap();
+
+        // Scrub 16 bytes of the first region and the first page of the second one.
+        let scrub_ranges = [
+            ScrubRange {
+                guest_addr: 16,
+                len: 16,
+            },
+            ScrubRange {
+                guest_addr: page_size as u64 * 3,
+                len: page_size as u64,
+            },
+        ];
+        let mut expected = vec![1u8; page_size * 4];
+        expected[16..32].copy_from_slice(&[0u8; 16]);
+        for byte in expected[page_size * 2..page_size * 3].iter_mut() {
+            *byte = 0;
+        }
+
+        // Full dump.
+        let bytes_dumped = METRICS.snapshot.bytes_dumped.count();
+        let memory_file = TempFile::new().unwrap();
+        guest_memory
+            .dump(&mut memory_file.as_file(), &scrub_ranges)
+            .unwrap();
+        assert_eq!(std::fs::read(memory_file.as_path()).unwrap(), expected);
+        assert!(METRICS.snapshot.bytes_dumped.count() >= bytes_dumped + page_size * 4);
+
+        // Diff dump with only the second page of each region dirty: the scrubbed pages are
+        // written anyway.
+        let mut dirty_bitmap: DirtyBitmap = HashMap::new();
+        dirty_bitmap.insert(0, vec![0b10; 1]);
+        dirty_bitmap.insert(1, vec![0b10; 1]);
+        let memory_file = TempFile::new().unwrap();
+        memory_file.as_file().set_len(page_size as u64 * 4).unwrap();
+        guest_memory
+            .dump_dirty(&mut memory_file.as_file(), &dirty_bitmap, &scrub_ranges)
+            .unwrap();
+        assert_eq!(std::fs::read(memory_file.as_path()).unwrap(), expected);
+
+        // Guest memory is left untouched.
+        let mut actual = vec![0u8; page_size * 2];
+        guest_memory
+            .read(&mut actual.as_mut_slice(), GuestAddress(0))
+            .unwrap();
+        assert_eq!(actual, contents);
     }
 }
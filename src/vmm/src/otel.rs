@@ -0,0 +1,419 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! OpenTelemetry spans of the snapshot operations.
+//!
+//! Spans are exported in the OTLP JSON encoding, one `ExportTraceServiceRequest` object per line,
+//! to a file or a named pipe opened at startup. The VMM thread cannot open sockets once its
+//! seccomp filter is installed, so the spans are shipped by a collector reading that file, such
+//! as the OpenTelemetry Collector with its `otlpjsonfile` receiver.
+//!
+//! A span started while another one is in progress on the same thread is its child. Otherwise it
+//! continues the trace of the API request being served, if the request carried a W3C
+//! `traceparent` header, or starts a new trace.
+
+use std::cell::RefCell;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use logger::error;
+use serde_json::{json, Value};
+use utils::time::{get_time_ns, ClockType};
+
+// See the `SpanKind` and `StatusCode` enums in opentelemetry/proto/trace/v1/trace.proto.
+const SPAN_KIND_INTERNAL: u32 = 1;
+const STATUS_CODE_OK: u32 = 1;
+const STATUS_CODE_ERROR: u32 = 2;
+const SERVICE_NAME: &str = "firecracker";
+const TRACE_PARENT_VERSION: &str = "00";
+
+lazy_static! {
+    /// Span exporter of the process. Spans are dropped until it is initialized.
+    pub static ref OTEL: OtelExporter = OtelExporter::default();
+}
+
+thread_local! {
+    // Innermost span in progress on the current thread.
+    static CURRENT_SPAN: RefCell<Option<SpanContext>> = RefCell::new(None);
+}
+
+/// Errors associated with the span exporter.
+#[derive(Debug)]
+pub enum Error {
+    /// The span exporter is already initialized.
+    AlreadyInitialized,
+    /// Failed to open the span export file.
+    Open(PathBuf, io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            AlreadyInitialized => write!(f, "The span exporter is already initialized"),
+            Open(path, err) => write!(f, "Cannot open span export {}: {}", path.display(), err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Identifies a span within a distributed trace.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpanContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+}
+
+impl SpanContext {
+    /// Parses the value of a W3C `traceparent` header, `00-<trace id>-<parent id>-<flags>`.
+    /// Returns `None` if the value is malformed, in which case the trace context is discarded.
+    pub fn from_trace_parent(value: &str) -> Option<Self> {
+        let fields: Vec<&str> = value.trim().split('-').collect();
+        if fields.len() != 4 || fields[0] != TRACE_PARENT_VERSION || fields[3].len() != 2 {
+            return None;
+        }
+
+        let mut trace_id = [0u8; 16];
+        let mut span_id = [0u8; 8];
+        if !decode_hex(fields[1], &mut trace_id)
+            || !decode_hex(fields[2], &mut span_id)
+            || trace_id == [0u8; 16]
+            || span_id == [0u8; 8]
+        {
+            return None;
+        }
+        Some(SpanContext { trace_id, span_id })
+    }
+
+    fn child_of(parent: Option<SpanContext>) -> Self {
+        let mut context = SpanContext {
+            trace_id: [0u8; 16],
+            span_id: [0u8; 8],
+        };
+        match parent {
+            Some(parent) => context.trace_id = parent.trace_id,
+            None => fill_random(&mut context.trace_id),
+        }
+        fill_random(&mut context.span_id);
+        context
+    }
+}
+
+fn decode_hex(hex: &str, out: &mut [u8]) -> bool {
+    if hex.len() != out.len() * 2 {
+        return false;
+    }
+    for (i, byte) in out.iter_mut().enumerate() {
+        match u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16) {
+            Ok(value) => *byte = value,
+            Err(_) => return false,
+        }
+    }
+    true
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Identifiers only need to be unique, fall back to the clock if no entropy is available.
+fn fill_random(buf: &mut [u8]) {
+    // Safe because the kernel writes at most `buf.len()` bytes to `buf`.
+    let ret = unsafe { libc::syscall(libc::SYS_getrandom, buf.as_mut_ptr(), buf.len(), 0) };
+    if ret != buf.len() as i64 {
+        let mut seed = get_time_ns(ClockType::Monotonic) | 1;
+        for byte in buf.iter_mut() {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            *byte = seed as u8;
+        }
+    }
+    // All zero identifiers are invalid.
+    buf[0] |= 1;
+}
+
+struct OtelSink {
+    file: File,
+    instance_id: String,
+}
+
+/// Exporter of the snapshot operation spans.
+#[derive(Default)]
+pub struct OtelExporter {
+    sink: Mutex<Option<OtelSink>>,
+    remote_parent: Mutex<Option<SpanContext>>,
+}
+
+impl OtelExporter {
+    /// Opens the span export file at `path`, a regular file or a named pipe, in append mode.
+    /// Spans are attributed to the `instance_id` microVM.
+    pub fn init(&self, path: &Path, instance_id: &str) -> Result<()> {
+        let mut sink = self.sink.lock().expect("Poisoned lock");
+        if sink.is_some() {
+            return Err(Error::AlreadyInitialized);
+        }
+
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| Error::Open(path.to_path_buf(), e))?;
+        *sink = Some(OtelSink {
+            file,
+            instance_id: instance_id.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Returns true if the span exporter is initialized.
+    pub fn is_enabled(&self) -> bool {
+        self.sink.lock().expect("Poisoned lock").is_some()
+    }
+
+    /// Sets the trace context of the API request being served, which parents the spans started
+    /// outside of any other span until it is cleared.
+    pub fn set_remote_parent(&self, parent: Option<SpanContext>) {
+        *self.remote_parent.lock().expect("Poisoned lock") = parent;
+    }
+
+    /// Starts the span `name`. The span ends and is exported when dropped, so it ends on every
+    /// return path.
+    pub fn span(&self, name: &'static str) -> OtelSpan<'_> {
+        let previous = CURRENT_SPAN.with(|current| *current.borrow());
+        let mut span = OtelSpan {
+            exporter: self,
+            name,
+            context: None,
+            parent: None,
+            previous,
+            start_ns: get_time_ns(ClockType::Real),
+            attributes: Vec::new(),
+            error: None,
+        };
+        if !self.is_enabled() {
+            return span;
+        }
+
+        span.parent = previous.or(*self.remote_parent.lock().expect("Poisoned lock"));
+        let context = SpanContext::child_of(span.parent);
+        span.context = Some(context);
+        CURRENT_SPAN.with(|current| *current.borrow_mut() = Some(context));
+        span
+    }
+
+    fn export(&self, span: &OtelSpan, context: SpanContext, end_ns: u64) {
+        let mut guard = self.sink.lock().expect("Poisoned lock");
+        let sink = match guard.as_mut() {
+            Some(sink) => sink,
+            None => return,
+        };
+
+        let request = span.to_otlp(context, end_ns, &sink.instance_id);
+        let mut line = match serde_json::to_vec(&request) {
+            Ok(line) => line,
+            Err(err) => {
+                error!("Cannot serialize span: {}", err);
+                return;
+            }
+        };
+        line.push(b'\n');
+        if let Err(err) = sink.file.write_all(&line) {
+            error!("Cannot write span: {}", err);
+        }
+    }
+}
+
+fn string_attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+/// A span in progress, which ends when dropped.
+pub struct OtelSpan<'a> {
+    exporter: &'a OtelExporter,
+    name: &'static str,
+    // `None` when the exporter is not initialized.
+    context: Option<SpanContext>,
+    parent: Option<SpanContext>,
+    previous: Option<SpanContext>,
+    start_ns: u64,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+impl<'a> OtelSpan<'a> {
+    /// Attaches the `key` attribute to the span.
+    pub fn set_attribute(&mut self, key: &'static str, value: String) {
+        if self.context.is_some() {
+            self.attributes.push((key, value));
+        }
+    }
+
+    /// Marks the operation covered by the span as failed.
+    pub fn set_error(&mut self, message: String) {
+        if self.context.is_some() {
+            self.error = Some(message);
+        }
+    }
+
+    fn to_otlp(&self, context: SpanContext, end_ns: u64, instance_id: &str) -> Value {
+        let status = match self.error.as_ref() {
+            Some(message) => json!({ "code": STATUS_CODE_ERROR, "message": message }),
+            None => json!({ "code": STATUS_CODE_OK }),
+        };
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| string_attribute(key, value))
+            .collect();
+        let mut span = json!({
+            "traceId": encode_hex(&context.trace_id),
+            "spanId": encode_hex(&context.span_id),
+            "name": self.name,
+            "kind": SPAN_KIND_INTERNAL,
+            "startTimeUnixNano": self.start_ns.to_string(),
+            "endTimeUnixNano": end_ns.to_string(),
+            "attributes": attributes,
+            "status": status,
+        });
+        if let Some(parent) = self.parent {
+            span["parentSpanId"] = Value::from(encode_hex(&parent.span_id));
+        }
+
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [
+                        string_attribute("service.name", SERVICE_NAME),
+                        string_attribute("service.instance.id", instance_id),
+                    ]
+                },
+                "scopeSpans": [{
+                    "scope": { "name": module_path!() },
+                    "spans": [span],
+                }],
+            }]
+        })
+    }
+}
+
+impl<'a> Drop for OtelSpan<'a> {
+    fn drop(&mut self) {
+        if let Some(context) = self.context {
+            CURRENT_SPAN.with(|current| *current.borrow_mut() = self.previous);
+            self.exporter
+                .export(self, context, get_time_ns(ClockType::Real));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use utils::tempfile::TempFile;
+
+    const TRACE_PARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn test_from_trace_parent() {
+        let context = SpanContext::from_trace_parent(TRACE_PARENT).unwrap();
+        assert_eq!(
+            encode_hex(&context.trace_id),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+        assert_eq!(encode_hex(&context.span_id), "b7ad6b7169203331");
+
+        for value in [
+            "",
+            "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b71692033zz-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+        ]
+        .iter()
+        {
+            assert!(SpanContext::from_trace_parent(value).is_none(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_span() {
+        let export_file = TempFile::new().unwrap();
+        let exporter = OtelExporter::default();
+
+        // Spans are dropped until the exporter is initialized.
+        assert!(!exporter.is_enabled());
+        drop(exporter.span("snapshot_load"));
+
+        exporter.init(export_file.as_path(), "vm0").unwrap();
+        assert!(exporter.is_enabled());
+        match exporter.init(export_file.as_path(), "vm0") {
+            Err(Error::AlreadyInitialized) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        exporter.set_remote_parent(SpanContext::from_trace_parent(TRACE_PARENT));
+        {
+            let mut load = exporter.span("snapshot_load");
+            load.set_attribute("snapshot.path", "/srv/vm.snap".to_string());
+            let mut prefetch = exporter.span("prefetch");
+            prefetch.set_error("Cannot prefetch".to_string());
+        }
+        exporter.set_remote_parent(None);
+        drop(exporter.span("snapshot_create"));
+
+        let contents = fs::read_to_string(export_file.as_path()).unwrap();
+        let spans: Vec<Value> = contents
+            .lines()
+            .map(|line| {
+                let request: Value = serde_json::from_str(line).unwrap();
+                let resource_spans = &request["resourceSpans"][0];
+                assert_eq!(
+                    resource_spans["resource"]["attributes"][1],
+                    string_attribute("service.instance.id", "vm0")
+                );
+                resource_spans["scopeSpans"][0]["spans"][0].clone()
+            })
+            .collect();
+        assert_eq!(spans.len(), 3);
+
+        // Spans are exported as they end, the child first.
+        let (prefetch, load, create) = (&spans[0], &spans[1], &spans[2]);
+        assert_eq!(prefetch["name"], "prefetch");
+        assert_eq!(load["name"], "snapshot_load");
+        assert_eq!(create["name"], "snapshot_create");
+
+        assert_eq!(load["traceId"], "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(load["parentSpanId"], "b7ad6b7169203331");
+        assert_eq!(
+            load["attributes"][0],
+            string_attribute("snapshot.path", "/srv/vm.snap")
+        );
+        assert_eq!(load["status"]["code"], STATUS_CODE_OK);
+
+        assert_eq!(prefetch["traceId"], load["traceId"]);
+        assert_eq!(prefetch["parentSpanId"], load["spanId"]);
+        assert_eq!(prefetch["status"]["code"], STATUS_CODE_ERROR);
+        assert_eq!(prefetch["status"]["message"], "Cannot prefetch");
+
+        // Without a remote parent, a new trace starts.
+        assert_ne!(create["traceId"], load["traceId"]);
+        assert!(create.get("parentSpanId").is_none());
+        assert_eq!(create["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(create["spanId"].as_str().unwrap().len(), 16);
+
+        let start_ns: u64 = load["startTimeUnixNano"].as_str().unwrap().parse().unwrap();
+        let end_ns: u64 = load["endTimeUnixNano"].as_str().unwrap().parse().unwrap();
+        assert!(start_ns <= end_ns);
+    }
+}
@@ -0,0 +1,364 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Loads the working set of a snapshot into the host page cache ahead of its restore.
+//!
+//! A restore reads the working set from the snapshot files, and waits on storage for the parts
+//! evicted from the page cache. Reading them in while the restore is only expected hides that
+//! latency. The working set is the `ws_regions` of the load manifest.
+
+use std::cmp::{max, min};
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::ptr::null_mut;
+
+use serde::{Deserialize, Serialize};
+
+use crate::vmm_config::snapshot::LoadSnapshotParams;
+
+// Length of each readahead call, as the kernel caps a call to the readahead window of the device.
+pub(crate) const READAHEAD_CHUNK: u64 = 128 << 10;
+
+/// Errors associated with warming the page cache.
+#[derive(Debug)]
+pub enum Error {
+    /// An extent, as (memory file page offset, number of pages), is invalid.
+    InvalidExtent(i64, i64),
+    /// The load manifest has no working set.
+    NoWorkingSet,
+    /// Cannot open a snapshot file.
+    Open(PathBuf, io::Error),
+    /// Cannot read a snapshot file into the page cache.
+    Readahead(PathBuf, io::Error),
+    /// Cannot query the page cache residency of a snapshot file.
+    Residency(PathBuf, io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            InvalidExtent(page, pages) => {
+                write!(f, "Invalid extent of {} pages at page {}", pages, page)
+            }
+            NoWorkingSet => write!(f, "The load manifest has no ws_regions"),
+            Open(path, err) => write!(f, "Cannot open {}: {}", path.display(), err),
+            Readahead(path, err) => write!(f, "Cannot read {} ahead: {}", path.display(), err),
+            Residency(path, err) => write!(
+                f,
+                "Cannot query the page cache residency of {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Byte ranges of a snapshot file holding a part of the working set.
+#[derive(Debug, PartialEq)]
+pub struct FileRanges {
+    /// Path of the snapshot file.
+    pub path: PathBuf,
+    /// Ranges, as (file offset, length), in the order the restore reads them.
+    pub ranges: Vec<(u64, u64)>,
+}
+
+impl FileRanges {
+    fn new(path: PathBuf) -> Self {
+        FileRanges {
+            path,
+            ranges: Vec::new(),
+        }
+    }
+
+    // Appends a range, merging it with the last one if they are contiguous.
+    fn push(&mut self, offset: u64, len: u64) {
+        match self.ranges.last_mut() {
+            Some(last) if last.0 + last.1 == offset => last.1 += len,
+            _ => self.ranges.push((offset, len)),
+        }
+    }
+
+    /// Returns the total length of the ranges.
+    pub fn len(&self) -> u64 {
+        self.ranges.iter().map(|(_, len)| len).sum()
+    }
+
+    /// Returns true if there are no ranges.
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+/// Outcome of warming the page cache.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct PrewarmStats {
+    /// Number of snapshot files read.
+    pub files: u64,
+    /// Number of working set bytes read.
+    pub bytes: u64,
+    /// Number of working set bytes already in the page cache before the reads.
+    pub resident_bytes: u64,
+}
+
+// Converts the (page, pages) `extent` to a byte range.
+fn extent_range(extent: &[i64], page_size: u64) -> Result<(u64, u64)> {
+    match extent {
+        [page, pages] if *page >= 0 && *pages > 0 => {
+            Ok((*page as u64 * page_size, *pages as u64 * page_size))
+        }
+        _ => Err(Error::InvalidExtent(
+            extent.get(0).copied().unwrap_or(-1),
+            extent.get(1).copied().unwrap_or(-1),
+        )),
+    }
+}
+
+/// Returns the ranges of the snapshot files the restore described by `params` reads its working
+/// set from. Layers passed as inherited file descriptors are left out.
+pub fn working_set_ranges(params: &LoadSnapshotParams, page_size: u64) -> Result<Vec<FileRanges>> {
+    let extents = params
+        .ws_regions
+        .iter()
+        .map(|extent| extent_range(extent, page_size))
+        .collect::<Result<Vec<_>>>()?;
+    if extents.is_empty() {
+        return Err(Error::NoWorkingSet);
+    }
+
+    // The working set file packs the extents back to back.
+    if !params.ws_file_path.as_os_str().is_empty() {
+        let mut ws_file = FileRanges::new(params.ws_file_path.clone());
+        ws_file.push(0, extents.iter().map(|(_, len)| len).sum());
+        return Ok(vec![ws_file]);
+    }
+
+    // Otherwise the extents are read from the memory file, or from the overlay file where it
+    // covers them.
+    let mut overlay_ranges = Vec::new();
+    if !params.overlay_file_path.as_os_str().is_empty() {
+        for (page, pages) in &params.overlay_regions {
+            let (offset, len) = extent_range(&[*page, *pages], page_size)?;
+            overlay_ranges.push((offset, offset + len));
+        }
+        overlay_ranges.sort();
+    }
+    let mut mem_file = FileRanges::new(params.mem_file_path.clone());
+    let mut overlay_file = FileRanges::new(params.overlay_file_path.clone());
+    for (offset, len) in extents {
+        let end = offset + len;
+        let mut pos = offset;
+        for (start, stop) in overlay_ranges.iter().copied() {
+            if stop <= pos || start >= end {
+                continue;
+            }
+            if start > pos {
+                mem_file.push(pos, start - pos);
+            }
+            let (start, stop) = (max(start, pos), min(stop, end));
+            overlay_file.push(start, stop - start);
+            pos = stop;
+        }
+        if pos < end {
+            mem_file.push(pos, end - pos);
+        }
+    }
+    Ok(vec![mem_file, overlay_file]
+        .into_iter()
+        .filter(|file| !file.path.as_os_str().is_empty() && !file.is_empty())
+        .collect())
+}
+
+/// Reads the ranges of `files` into the page cache.
+pub fn prewarm(files: &[FileRanges]) -> Result<PrewarmStats> {
+    let page_size = sysconf::page::pagesize() as u64;
+    let mut stats = PrewarmStats::default();
+    for file_ranges in files {
+        let path = &file_ranges.path;
+        let file = File::open(path).map_err(|e| Error::Open(path.clone(), e))?;
+        stats.resident_bytes += resident_bytes(&file, &file_ranges.ranges, page_size)
+            .map_err(|e| Error::Residency(path.clone(), e))?;
+        for (offset, len) in &file_ranges.ranges {
+            readahead(&file, *offset, *len).map_err(|e| Error::Readahead(path.clone(), e))?;
+        }
+        stats.files += 1;
+        stats.bytes += file_ranges.len();
+    }
+    Ok(stats)
+}
+
+fn readahead(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    let end = offset + len;
+    let mut pos = offset;
+    while pos < end {
+        let count = min(READAHEAD_CHUNK, end - pos);
+        // Safe because the call only populates the page cache of the file.
+        let ret =
+            unsafe { libc::readahead(file.as_raw_fd(), pos as libc::off64_t, count as usize) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        pos += count;
+    }
+    Ok(())
+}
+
+/// Returns the number of bytes of the page aligned `ranges` of `file` in the page cache.
+pub fn resident_bytes(file: &File, ranges: &[(u64, u64)], page_size: u64) -> io::Result<u64> {
+    // The size is read with lseek, which the seccomp filters allow unlike statx.
+    let size = (&*file).seek(SeekFrom::End(0))?;
+    if size == 0 {
+        return Ok(0);
+    }
+    // Safe because the mapping is only used to query its residency, and unmapped below.
+    let addr = unsafe {
+        libc::mmap(
+            null_mut(),
+            size as usize,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut residency = Vec::new();
+    let mut result = Ok(0);
+    for (offset, len) in ranges {
+        // The parts of the ranges past the end of the file are not resident.
+        let start = min(*offset, size);
+        let end = min(offset + len, size);
+        if start >= end {
+            continue;
+        }
+        residency.resize(((end - start + page_size - 1) / page_size) as usize, 0);
+        // Safe because [start, end) is inside the mapping, and `residency` has a byte for each
+        // of its pages.
+        let ret = unsafe {
+            libc::mincore(
+                (addr as *mut u8).add(start as usize) as *mut libc::c_void,
+                (end - start) as usize,
+                residency.as_mut_ptr(),
+            )
+        };
+        if ret < 0 {
+            result = Err(io::Error::last_os_error());
+            break;
+        }
+        let resident: u64 = residency
+            .iter()
+            .enumerate()
+            .filter(|(_, page)| **page & 1 != 0)
+            .map(|(index, _)| min(page_size, end - start - index as u64 * page_size))
+            .sum();
+        result = result.map(|bytes| bytes + resident);
+    }
+    // Safe because `addr` was mapped above with `size` bytes.
+    unsafe { libc::munmap(addr, size as usize) };
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use utils::tempfile::TempFile;
+
+    const PAGE_SIZE: u64 = 0x1000;
+
+    fn params(ws_regions: Vec<Vec<i64>>) -> LoadSnapshotParams {
+        LoadSnapshotParams {
+            mem_file_path: PathBuf::from("mem_file"),
+            ws_regions,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_working_set_ranges() {
+        match working_set_ranges(&params(vec![]), PAGE_SIZE) {
+            Err(Error::NoWorkingSet) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match working_set_ranges(&params(vec![vec![1, 0]]), PAGE_SIZE) {
+            Err(Error::InvalidExtent(1, 0)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // From the memory file, contiguous extents are merged.
+        let mut manifest = params(vec![vec![4, 2], vec![6, 1], vec![0, 1]]);
+        assert_eq!(
+            working_set_ranges(&manifest, PAGE_SIZE).unwrap(),
+            vec![FileRanges {
+                path: PathBuf::from("mem_file"),
+                ranges: vec![(0x4000, 0x3000), (0, 0x1000)],
+            }]
+        );
+
+        // With the overlay file covering a part of the extents.
+        manifest.overlay_file_path = PathBuf::from("overlay_file");
+        manifest.overlay_regions.insert(5, 3);
+        assert_eq!(
+            working_set_ranges(&manifest, PAGE_SIZE).unwrap(),
+            vec![
+                FileRanges {
+                    path: PathBuf::from("mem_file"),
+                    ranges: vec![(0x4000, 0x1000), (0, 0x1000)],
+                },
+                FileRanges {
+                    path: PathBuf::from("overlay_file"),
+                    ranges: vec![(0x5000, 0x2000)],
+                },
+            ]
+        );
+
+        // The working set file is read whole.
+        manifest.ws_file_path = PathBuf::from("ws_file");
+        assert_eq!(
+            working_set_ranges(&manifest, PAGE_SIZE).unwrap(),
+            vec![FileRanges {
+                path: PathBuf::from("ws_file"),
+                ranges: vec![(0, 0x4000)],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_prewarm() {
+        let page_size = sysconf::page::pagesize() as u64;
+        let file = TempFile::new().unwrap();
+        file.as_file()
+            .write_all(&vec![1u8; 4 * page_size as usize])
+            .unwrap();
+        let files = vec![FileRanges {
+            path: file.as_path().to_path_buf(),
+            ranges: vec![(page_size, 2 * page_size), (3 * page_size, 4 * page_size)],
+        }];
+
+        let stats = prewarm(&files).unwrap();
+        assert_eq!(stats.files, 1);
+        assert_eq!(stats.bytes, 6 * page_size);
+        // The file was just written, and the range past its end is never resident.
+        assert_eq!(stats.resident_bytes, 3 * page_size);
+        assert_eq!(
+            resident_bytes(file.as_file(), &files[0].ranges, page_size).unwrap(),
+            3 * page_size
+        );
+
+        let files = vec![FileRanges::new(PathBuf::from("/invalid/file"))];
+        match prewarm(&files) {
+            Err(Error::Open(..)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
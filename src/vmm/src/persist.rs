@@ -8,30 +8,78 @@
 
 use std::fmt::{Display, Formatter};
 use std::fs::{File, OpenOptions};
-use std::io;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
 use std::os::unix::prelude::AsRawFd;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::collections::HashMap;
-use libc::posix_fadvise;
-use libc::POSIX_FADV_RANDOM;
+#[cfg(feature = "faasnap")]
+use crate::backing_files::BACKING_FILES;
 use crate::builder::{self, StartMicrovmError};
+use crate::default_syscalls::ThreadFilters;
 use crate::device_manager::persist::Error as DevicePersistError;
-use crate::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams, SnapshotType};
+use crate::dump_writer::DUMP_WRITER;
+use crate::fault_sources::{self, FaultSampler, FaultSource};
+#[cfg(feature = "faasnap")]
+use crate::fault_trace;
+use crate::idle_reclaim::{self, IdleReclaimer};
+use crate::ksm;
+use crate::layer_fadvise::{self, LayerAdvice};
+use crate::memory_policy;
+use crate::memory_reclaim::MemoryReclaimer;
+#[cfg(feature = "faasnap")]
+use crate::vmm_config::snapshot::MemBackendType;
+use crate::vmm_config::snapshot::{
+    CreateSnapshotParams, LoadSnapshotParams, NetworkOverride, ScrubRange, SnapshotCacheConfig,
+    SnapshotType,
+};
 use crate::vstate::{self, VcpuState, VmState};
+use crate::warm_notify::{self, WarmNotifier};
+use crate::ws_accounting::{self, WsStats};
+use crate::ws_load_control::WS_LOAD;
+use crate::ws_lock;
+use crate::ws_staging;
 
 use crate::device_manager::persist::DeviceStates;
+use crate::lifecycle::{LifecycleEvent, LIFECYCLE};
 use crate::memory_snapshot;
 use crate::memory_snapshot::{GuestMemoryState, SnapshotMemory};
-use crate::version_map::FC_VERSION_TO_SNAP_VERSION;
+use crate::prefetch_coordinator::PrefetchCoordinator;
+use crate::prefetch_tuning::{PrefetchTuning, StorageProfile};
+#[cfg(feature = "faasnap")]
+use crate::probes::MmapLayer;
+#[cfg(feature = "faasnap")]
+use crate::protected_base;
+use crate::psi::PrefetchThrottle;
+use crate::restore_trace::{RestorePhase, RESTORE_TRACE};
+use crate::restore_watchdog::{self, WatchedOperation, RESTORE_WATCHDOG};
+use crate::snapshot_cache;
+use crate::snapshot_io::{LocalFile, SnapshotWriter};
+use crate::snapshot_signing::{self, Layer, Manifest, SnapshotKeys};
+use crate::version_map::{
+    check_compatibility, Incompatibility, StateExtensions, FC_VERSION_TO_SNAP_VERSION,
+};
+use crate::worker_pool::{Task, WORKER_POOL};
 use polly::event_manager::EventManager;
-use seccomp::BpfProgramRef;
 use snapshot::Snapshot;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
-use vm_memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 
 use crate::Vmm;
+use arch::DeviceType;
+use devices::virtio::{MmioTransport, Net, TYPE_NET};
+use lazy_static::lazy_static;
+use logger::{info, warn, Metric, METRICS};
+use utils::syscall::SyscallReturnCode;
+use utils::time::{get_time_us, ClockType};
+
+// Suffix of the temporary names the snapshot files are written to.
+const PENDING_SUFFIX: &str = ".tmp";
 
 /// Holds information related to the VM that is not part of VmState.
 #[derive(Debug, PartialEq, Versionize)]
@@ -43,6 +91,10 @@ pub struct VmInfo {
 /// Contains the necesary state for saving/restoring a microVM.
 #[derive(Versionize)]
 pub struct MicrovmState {
+    /// Versions and features of the writer. Stays the first field, for the loaders to check it
+    /// before the rest of the state.
+    #[version(start = 2, default_fn = "default_extensions")]
+    pub extensions: StateExtensions,
     /// Miscellaneous VM info.
     pub vm_info: VmInfo,
     /// Memory state.
@@ -55,6 +107,12 @@ pub struct MicrovmState {
     pub device_states: DeviceStates,
 }
 
+impl MicrovmState {
+    fn default_extensions(_: u16) -> StateExtensions {
+        StateExtensions::current()
+    }
+}
+
 /// Errors related to saving and restoring Microvm state.
 #[derive(Debug)]
 pub enum MicrovmStateError {
@@ -95,8 +153,14 @@ impl Display for MicrovmStateError {
 /// Errors associated with creating a snapshot.
 #[derive(Debug)]
 pub enum CreateSnapshotError {
+    /// Background snapshots are full snapshots only.
+    BackgroundDiff,
     /// Failed to get dirty bitmap.
     DirtyBitmap,
+    /// Failed to enable KVM dirty page tracking.
+    DirtyPageTracking(crate::Error),
+    /// Failed to move the snapshot file at the path to its final name, or to make it durable.
+    CommitSnapshot(PathBuf, io::Error),
     /// Failed to translate microVM version to snapshot data version.
     InvalidVersion,
     /// Failed to save VM state.
@@ -107,27 +171,60 @@ pub enum CreateSnapshotError {
     MemoryBackingFile(io::Error),
     /// Failed to save MicrovmState.
     MicrovmState(MicrovmStateError),
+    /// Nested virtualization is exposed to the guest; its hypervisor state cannot be saved.
+    NestedVirtualization,
+    /// The snapshot data version (version, page size) predates the page size field, and the
+    /// host pages are not 4K.
+    PageSize(u16, usize),
     /// Failed to serialize microVM state.
     SerializeMicrovmState(snapshot::Error),
+    /// Failed to sign the manifest of the snapshot files.
+    SignSnapshot(snapshot_signing::Error),
     /// Failed to open the snapshot backing file.
     SnapshotBackingFile(io::Error),
+    /// The memory file is a pipe or a socket, which cannot take a diff or background snapshot,
+    /// or be signed.
+    StreamedMemoryFile,
+    /// Failed to pause or resume the vCPUs for a background snapshot.
+    Vcpus(crate::Error),
 }
 
 impl Display for CreateSnapshotError {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         use self::CreateSnapshotError::*;
         match self {
+            BackgroundDiff => write!(f, "Cannot create a diff snapshot in the background"),
             DirtyBitmap => write!(f, "Cannot get dirty bitmap"),
+            DirtyPageTracking(err) => write!(f, "Cannot enable dirty page tracking: {}", err),
+            CommitSnapshot(path, err) => {
+                write!(f, "Cannot commit snapshot file {}: {}", path.display(), err)
+            }
             InvalidVersion => write!(
                 f,
                 "Cannot translate microVM version to snapshot data version"
             ),
             InvalidVmState(err) => write!(f, "Cannot save Vm state. Error: {:?}", err),
-            Memory(err) => write!(f, "Cannot write memory file: {:?}", err),
+            Memory(err) => write!(f, "Cannot write memory file: {}", err),
             MemoryBackingFile(err) => write!(f, "Cannot open memory file: {:?}", err),
             MicrovmState(err) => write!(f, "Cannot save microvm state: {}", err),
+            NestedVirtualization => write!(
+                f,
+                "Cannot snapshot a microVM with nested virtualization enabled"
+            ),
+            PageSize(version, page_size) => write!(
+                f,
+                "Snapshot data version {} cannot record the {} byte pages of the host",
+                version, page_size
+            ),
             SerializeMicrovmState(err) => write!(f, "Cannot serialize MicrovmState: {:?}", err),
+            SignSnapshot(err) => write!(f, "Cannot sign snapshot: {}", err),
             SnapshotBackingFile(err) => write!(f, "Cannot open snapshot file: {:?}", err),
+            StreamedMemoryFile => write!(
+                f,
+                "Cannot stream a diff, background or signed snapshot to a pipe or socket memory \
+                 file"
+            ),
+            Vcpus(err) => write!(f, "Cannot pause or resume the vCPUs: {}", err),
         }
     }
 }
@@ -141,12 +238,54 @@ pub enum LoadSnapshotError {
     DeserializeMemory(memory_snapshot::Error),
     /// Failed to deserialize microVM state.
     DeserializeMicrovmState(snapshot::Error),
+    /// The microVM state was written by a binary with features this one lacks.
+    IncompatibleSnapshot(Incompatibility),
     /// Failed to open memory backing file.
     MemoryBackingFile(io::Error),
+    /// A file descriptor was not inherited for the snapshot files, or is not a regular file
+    /// opened read-only.
+    InvalidInheritedFd(RawFd),
+    /// The load parameters request this faasnap extension, which the build lacks.
+    FaasnapDisabled(&'static str),
     /// Failed to open the snapshot backing file.
     SnapshotBackingFile(io::Error),
+    /// The advice on a layer file is invalid.
+    LayerFadvise(layer_fadvise::Error),
+    /// The memory file is shorter than the guest memory mapped from it, as (file, size,
+    /// required size).
+    TruncatedFile(String, u64, u64),
+    /// Failed to grow the guest memory of the snapshot.
+    GrowMemory(memory_snapshot::Error),
+    /// The guest memory cannot grow when its page faults are handled by another process.
+    GrowWithUserPageFaults,
     /// Failed to register guest memory for user page fault handling.
     UserPageFault(memory_snapshot::Error),
+    /// The snapshot files do not match their signed manifest.
+    VerifySnapshot(snapshot_signing::Error),
+    /// Failed to start recording the guest page faults.
+    #[cfg(feature = "faasnap")]
+    FaultTrace(fault_trace::Error),
+    /// The overlay and working set layers both map these (page, number of pages) extents.
+    LayerConflicts(Vec<(u64, u64)>),
+    /// Failed to start paging in the write-protected base layer.
+    #[cfg(feature = "faasnap")]
+    ProtectBase(protected_base::Error),
+    /// Failed to account for the working set prefetch.
+    WsAccounting(ws_accounting::Error),
+    /// Failed to load the working set into guest memory.
+    WsLoad(memory_snapshot::Error),
+    /// Failed to lock the working set in memory.
+    WsLock(ws_lock::Error),
+    /// The working set is populated only when loaded from a ws file.
+    WsPopulateWithoutWsFile,
+    /// Failed to join the network namespace of the microVM.
+    NetNs(io::Error),
+    /// A network override names an interface missing from the snapshot.
+    UnknownNetworkInterface(String),
+    /// Failed to set up the notification of the scheduler once the microVM is warm.
+    WarmNotify(warm_notify::Error),
+    /// Failed to set up the reclamation of the guest memory once the microVM is idle.
+    IdleReclaim(idle_reclaim::Error),
 }
 
 impl Display for LoadSnapshotError {
@@ -156,35 +295,221 @@ impl Display for LoadSnapshotError {
             BuildMicroVm(err) => write!(f, "Cannot build a microVM from snapshot: {}", err),
             DeserializeMemory(err) => write!(f, "Cannot deserialize memory: {}", err),
             DeserializeMicrovmState(err) => write!(f, "Cannot deserialize MicrovmState: {:?}", err),
+            IncompatibleSnapshot(err) => write!(f, "{}", err),
             MemoryBackingFile(err) => write!(f, "Cannot open memory file: {}", err),
+            InvalidInheritedFd(fd) => write!(
+                f,
+                "File descriptor {} is not an inherited snapshot file opened read-only",
+                fd
+            ),
+            FaasnapDisabled(option) => write!(
+                f,
+                "Cannot use {}: Firecracker was built without the faasnap feature",
+                option
+            ),
             SnapshotBackingFile(err) => write!(f, "Cannot open snapshot file: {}", err),
+            LayerFadvise(err) => write!(f, "Invalid layer file advice: {}", err),
+            TruncatedFile(file, size, required) => write!(
+                f,
+                "The {} has {} bytes, {} short of the {} bytes mapped from it",
+                file,
+                size,
+                required - size,
+                required
+            ),
+            GrowMemory(err) => write!(f, "Cannot grow guest memory: {}", err),
+            GrowWithUserPageFaults => {
+                write!(f, "Cannot grow guest memory with user page faults enabled")
+            }
             UserPageFault(err) => write!(f, "Cannot register memory for uPF: {:?}", err),
+            VerifySnapshot(err) => write!(f, "Cannot verify snapshot: {}", err),
+            #[cfg(feature = "faasnap")]
+            FaultTrace(err) => write!(f, "Cannot record page faults: {}", err),
+            LayerConflicts(conflicts) => write!(
+                f,
+                "The overlay and working set layers both map pages {}",
+                format_extents(conflicts)
+            ),
+            #[cfg(feature = "faasnap")]
+            ProtectBase(err) => write!(f, "Cannot protect the base layer: {}", err),
+            WsAccounting(err) => write!(f, "Cannot account for the working set: {}", err),
+            WsLoad(err) => write!(f, "Cannot load the working set: {}", err),
+            WsLock(err) => write!(f, "Cannot lock the working set: {}", err),
+            WsPopulateWithoutWsFile => write!(
+                f,
+                "Populating the working set requires load_ws and a ws file"
+            ),
+            NetNs(err) => write!(f, "Cannot join the network namespace: {}", err),
+            UnknownNetworkInterface(id) => {
+                write!(f, "The snapshot has no network interface with ID {}", id)
+            }
+            WarmNotify(err) => write!(f, "Cannot set up the warm notification: {}", err),
+            IdleReclaim(err) => write!(f, "Cannot set up the idle memory reclamation: {}", err),
         }
     }
 }
 
-/// Creates a Microvm snapshot.
+/// Creates a Microvm snapshot. The bytes of guest memory inside `scrub_ranges` are written as
+/// zeros to the memory file.
+///
+/// Once the state of the microVM is saved, it is serialized on a helper thread while the guest
+/// memory is written, so that the microVM is paused for the longer of the two only. Background
+/// snapshots are taken of the running microVM instead, see `create_background_snapshot`.
+///
+/// The snapshot files are written under temporary names, and only take their final names once
+/// complete, see `commit_snapshot`.
 pub fn create_snapshot(
     vmm: &mut Vmm,
     params: &CreateSnapshotParams,
     version_map: VersionMap,
+    keys: &SnapshotKeys,
+    scrub_ranges: &[ScrubRange],
 ) -> std::result::Result<(), CreateSnapshotError> {
+    if params.background {
+        return create_background_snapshot(vmm, params, version_map, keys, scrub_ranges);
+    }
     let microvm_state = vmm
         .save_state()
         .map_err(CreateSnapshotError::MicrovmState)?;
 
-    snapshot_memory_to_file(vmm, &params.mem_file_path, &params.snapshot_type)?;
+    let microvm_state = &microvm_state;
+    let snapshot_path = &pending_path(&params.snapshot_path);
+    let save_state = Box::new(move || {
+        snapshot_state_to_file(microvm_state, snapshot_path, &params.version, version_map)
+    }) as SaveStateTask;
+    let written = snapshot_memory_to_file(
+        vmm,
+        &pending_path(&params.mem_file_path),
+        &params.snapshot_type,
+        keys.signs(),
+        params.preallocate,
+        scrub_ranges,
+        save_state,
+    );
+    commit_snapshot(params, keys, written)?;
+
+    METRICS.snapshot.create_count.inc();
+    if params.snapshot_type == SnapshotType::Diff {
+        METRICS.snapshot.diff_create_count.inc();
+    }
+    Ok(())
+}
 
-    snapshot_state_to_file(
-        &microvm_state,
-        &params.snapshot_path,
-        &params.version,
-        version_map,
-    )?;
+/// Creates a full snapshot of the running microVM. The guest memory is first copied while the
+/// vCPUs keep running, with KVM dirty page tracking enabled, then the microVM pauses while the
+/// pages dirtied meanwhile and its state are written, and resumes.
+///
+/// The emulated devices run on the VMM thread, so they wait for the copy and all the writes to
+/// guest memory meanwhile come from the vCPUs, which KVM logs. Dirty page tracking is left
+/// enabled, so that diff snapshots can follow.
+pub fn create_background_snapshot(
+    vmm: &mut Vmm,
+    params: &CreateSnapshotParams,
+    version_map: VersionMap,
+    keys: &SnapshotKeys,
+    scrub_ranges: &[ScrubRange],
+) -> std::result::Result<(), CreateSnapshotError> {
+    if params.snapshot_type != SnapshotType::Full {
+        return Err(CreateSnapshotError::BackgroundDiff);
+    }
+    let written = write_background_snapshot(vmm, params, version_map, scrub_ranges);
+    commit_snapshot(params, keys, written)?;
 
+    METRICS.snapshot.create_count.inc();
+    METRICS.snapshot.background_create_count.inc();
     Ok(())
 }
 
+// Writes the background snapshot of `vmm` to the temporary names of the snapshot files.
+fn write_background_snapshot(
+    vmm: &mut Vmm,
+    params: &CreateSnapshotParams,
+    version_map: VersionMap,
+    scrub_ranges: &[ScrubRange],
+) -> std::result::Result<(), CreateSnapshotError> {
+    use self::CreateSnapshotError::*;
+    let (mut file, streamed) =
+        open_memory_file(&pending_path(&params.mem_file_path)).map_err(MemoryBackingFile)?;
+    if streamed {
+        return Err(StreamedMemoryFile);
+    }
+    let mem_size_mib = mem_size_mib(vmm.guest_memory());
+    file.set_len((mem_size_mib * 1024 * 1024) as u64)
+        .map_err(MemoryBackingFile)?;
+    if params.preallocate {
+        preallocate_file(&file, &[(0, mem_size_mib << 20)]).map_err(MemoryBackingFile)?;
+    }
+
+    vmm.set_dirty_page_tracking(true)
+        .map_err(DirtyPageTracking)?;
+    // Fetching the dirty log clears it, only the writes from now on are left for the final pass.
+    vmm.get_dirty_bitmap().map_err(|_| DirtyBitmap)?;
+    match DUMP_WRITER.pipeline(&file).map_err(MemoryBackingFile)? {
+        Some(mut pipeline) => {
+            background_passes(vmm, &mut pipeline, &file, params, version_map, scrub_ranges)?;
+            pipeline.flush().map_err(MemoryBackingFile)?;
+        }
+        None => {
+            let out = file.try_clone().map_err(MemoryBackingFile)?;
+            background_passes(vmm, &mut file, &out, params, version_map, scrub_ranges)?;
+        }
+    }
+    file.sync_all().map_err(MemoryBackingFile)
+}
+
+// Copies the guest memory of the running microVM to `file`, whose writes go through `writer`,
+// then pauses it to write the pages dirtied meanwhile and its state, and resumes it.
+fn background_passes<T: std::io::Write + std::io::Seek>(
+    vmm: &mut Vmm,
+    writer: &mut T,
+    file: &File,
+    params: &CreateSnapshotParams,
+    version_map: VersionMap,
+    scrub_ranges: &[ScrubRange],
+) -> std::result::Result<(), CreateSnapshotError> {
+    use self::CreateSnapshotError::*;
+    let copy_start_us = get_time_us(ClockType::Monotonic);
+    vmm.guest_memory()
+        .dump_copying(writer, file, scrub_ranges)
+        .map_err(Memory)?;
+    // The final pass rewrites some of the pages, after the copied ones.
+    writer.flush().map_err(MemoryBackingFile)?;
+    let pause_start_us = get_time_us(ClockType::Monotonic);
+    METRICS
+        .snapshot
+        .background_copy_us
+        .add((pause_start_us - copy_start_us) as usize);
+
+    vmm.pause_vcpus().map_err(Vcpus)?;
+    let written = vmm
+        .save_state()
+        .map_err(MicrovmState)
+        .and_then(|microvm_state| {
+            let microvm_state = &microvm_state;
+            let snapshot_path = &pending_path(&params.snapshot_path);
+            let save_state = Box::new(move || {
+                snapshot_state_to_file(microvm_state, snapshot_path, &params.version, version_map)
+            }) as SaveStateTask;
+            // The whole file is preallocated already, if at all.
+            dump_memory(
+                vmm,
+                writer,
+                file,
+                &SnapshotType::Diff,
+                false,
+                scrub_ranges,
+                save_state,
+            )
+        });
+    // The microVM runs again whether the snapshot is written or not.
+    let resumed = vmm.resume_vcpus().map_err(Vcpus);
+    METRICS
+        .snapshot
+        .background_pause_us
+        .add((get_time_us(ClockType::Monotonic) - pause_start_us) as usize);
+    written.and(resumed)
+}
+
 fn snapshot_state_to_file(
     microvm_state: &MicrovmState,
     snapshot_path: &PathBuf,
@@ -195,6 +520,7 @@ fn snapshot_state_to_file(
     let mut snapshot_file = OpenOptions::new()
         .create(true)
         .write(true)
+        .truncate(true)
         .open(snapshot_path)
         .map_err(SnapshotBackingFile)?;
 
@@ -206,111 +532,1175 @@ fn snapshot_state_to_file(
         },
         _ => Ok(version_map.latest_version()),
     }?;
+    // The older versions imply 4K pages.
+    let page_size = microvm_state.memory_state.page_size;
+    if version_map.get_type_version(snapshot_data_version, GuestMemoryState::type_id()) < 2
+        && page_size != 4096
+    {
+        return Err(PageSize(snapshot_data_version, page_size));
+    }
 
     let mut snapshot = Snapshot::new(version_map, snapshot_data_version);
     snapshot
         .save(&mut snapshot_file, microvm_state)
         .map_err(SerializeMicrovmState)?;
 
-    Ok(())
+    snapshot_file.sync_all().map_err(SnapshotBackingFile)
 }
 
+// Returns the temporary name the snapshot file at `path` is written to, or `path` itself for the
+// pipes and sockets the memory file is streamed to.
+fn pending_path(path: &Path) -> PathBuf {
+    let streamed = std::fs::metadata(path)
+        .map(|metadata| metadata.file_type().is_socket() || metadata.file_type().is_fifo())
+        .unwrap_or(false);
+    if streamed {
+        return path.to_path_buf();
+    }
+    let mut pending = path.as_os_str().to_owned();
+    pending.push(PENDING_SUFFIX);
+    PathBuf::from(pending)
+}
+
+// Makes the renames and removals in the directory of `path` durable.
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+// Moves the snapshot files from their temporary names to their paths once `written`, or removes
+// them if writing them failed, and then signs their manifest.
+//
+// The memory file takes its name before the snapshot file, which records the completion of the
+// snapshot, so that a host crash leaves either no snapshot file or a complete snapshot. The
+// previous snapshot file at the same path is removed first, so that it never goes with the new
+// memory file.
+fn commit_snapshot(
+    params: &CreateSnapshotParams,
+    keys: &SnapshotKeys,
+    written: std::result::Result<(), CreateSnapshotError>,
+) -> std::result::Result<(), CreateSnapshotError> {
+    use self::CreateSnapshotError::{CommitSnapshot, SignSnapshot};
+    let files = [&params.mem_file_path, &params.snapshot_path];
+    if let Err(err) = written {
+        for path in files.iter() {
+            let pending = pending_path(path);
+            if pending != **path {
+                let _ = std::fs::remove_file(&pending);
+            }
+        }
+        return Err(err);
+    }
+
+    if let Err(err) = std::fs::remove_file(&params.snapshot_path) {
+        if err.kind() != io::ErrorKind::NotFound {
+            return Err(CommitSnapshot(params.snapshot_path.clone(), err));
+        }
+    }
+    for path in files.iter() {
+        let pending = pending_path(path);
+        if pending != **path {
+            std::fs::rename(&pending, path)
+                .and_then(|_| sync_parent_dir(path))
+                .map_err(|e| CommitSnapshot(path.to_path_buf(), e))?;
+        }
+    }
+
+    keys.sign(
+        &params.snapshot_path,
+        &[
+            (Layer::State, params.snapshot_path.as_path()),
+            (Layer::Memory, params.mem_file_path.as_path()),
+        ],
+    )
+    .map_err(SignSnapshot)
+}
+
+// Task serializing the microVM state to the snapshot file.
+type SaveStateTask<'a> = Task<'a, std::result::Result<(), CreateSnapshotError>>;
+
+// Outcome of a task of the snapshot creation run by the helper threads.
+enum DumpStep {
+    StateSaved(std::result::Result<(), CreateSnapshotError>),
+    DirtyLogFetched(usize, std::result::Result<Vec<u64>, kvm_ioctls::Error>),
+}
+
+// Writes the guest memory to the memory file, while `save_state` runs on a helper thread.
 fn snapshot_memory_to_file(
     vmm: &Vmm,
     mem_file_path: &PathBuf,
     snapshot_type: &SnapshotType,
+    signed: bool,
+    preallocate: bool,
+    scrub_ranges: &[ScrubRange],
+    save_state: SaveStateTask,
 ) -> std::result::Result<(), CreateSnapshotError> {
     use self::CreateSnapshotError::*;
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(mem_file_path)
-        .map_err(MemoryBackingFile)?;
+    let (file, streamed) = open_memory_file(mem_file_path).map_err(MemoryBackingFile)?;
+
+    // Pipes and sockets only take the pages in order, which they are sent to without a copy
+    // through userspace where possible.
+    if streamed {
+        if *snapshot_type == SnapshotType::Diff || signed {
+            return Err(StreamedMemoryFile);
+        }
+        return WORKER_POOL.run_with(vec![save_state], |saved| {
+            vmm.guest_memory()
+                .dump_streaming(&file, scrub_ranges)
+                .map_err(Memory)?;
+            saved.map(|(_, res)| res).collect()
+        });
+    }
 
     // Set the length of the file to the full size of the memory area.
     let mem_size_mib = mem_size_mib(vmm.guest_memory());
     file.set_len((mem_size_mib * 1024 * 1024) as u64)
         .map_err(MemoryBackingFile)?;
+    // The dirty pages of a diff snapshot are preallocated slot by slot, once they are known.
+    if preallocate && *snapshot_type == SnapshotType::Full {
+        preallocate_file(&file, &[(0, mem_size_mib << 20)]).map_err(MemoryBackingFile)?;
+    }
 
-    match snapshot_type {
-        SnapshotType::Diff => {
-            let dirty_bitmap = vmm.get_dirty_bitmap().map_err(|_| DirtyBitmap)?;
-            vmm.guest_memory()
-                .dump_dirty(&mut file, &dirty_bitmap)
-                .map_err(Memory)
+    // The writer thread writes the pages while the next ones are copied, when it is started.
+    match DUMP_WRITER.pipeline(&file).map_err(MemoryBackingFile)? {
+        Some(mut pipeline) => {
+            dump_memory(
+                vmm,
+                &mut pipeline,
+                &file,
+                snapshot_type,
+                preallocate,
+                scrub_ranges,
+                save_state,
+            )?;
+            pipeline.flush().map_err(MemoryBackingFile)?;
+        }
+        None => {
+            let io = LocalFile::new(file.try_clone().map_err(MemoryBackingFile)?);
+            dump_memory(
+                vmm,
+                &mut SnapshotWriter::new(&io),
+                &file,
+                snapshot_type,
+                preallocate,
+                scrub_ranges,
+                save_state,
+            )?;
+        }
+    }
+    file.sync_all().map_err(MemoryBackingFile)
+}
+
+// Opens the memory file to write, or connects to it if it is a Unix socket. Also returns whether
+// it is a pipe or a socket rather than a regular file.
+fn open_memory_file(path: &PathBuf) -> io::Result<(File, bool)> {
+    let is_socket = std::fs::metadata(path)
+        .map(|metadata| metadata.file_type().is_socket())
+        .unwrap_or(false);
+    if is_socket {
+        let stream = UnixStream::connect(path)?;
+        // Safe because the descriptor is owned by the stream, which gives it up.
+        return Ok((unsafe { File::from_raw_fd(stream.into_raw_fd()) }, true));
+    }
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    let is_fifo = file.metadata()?.file_type().is_fifo();
+    Ok((file, is_fifo))
+}
+
+// Allocates the `(offset, length)` ranges of `file` with `fallocate`, so that the dump does not
+// allocate blocks write by write. File systems without `fallocate` allocate them on write.
+fn preallocate_file(file: &File, ranges: &[(u64, u64)]) -> io::Result<()> {
+    let start_us = get_time_us(ClockType::Monotonic);
+    for &(offset, len) in ranges {
+        // Safe because `fallocate` does not modify memory.
+        let ret = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                0,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(err);
+            }
+            warn!("Cannot preallocate the memory file: {}", err);
+            break;
         }
-        SnapshotType::Full => vmm.guest_memory().dump(&mut file).map_err(Memory),
     }
+    METRICS
+        .snapshot
+        .preallocate_us
+        .add((get_time_us(ClockType::Monotonic) - start_us) as usize);
+    Ok(())
+}
+
+// Dumps the guest memory to `file`, whose writes go through `writer`, while `save_state` runs
+// on a helper thread. The dirty pages of a diff snapshot are written slot by slot, as soon as the
+// dirty log of the slot is fetched, while the other slots are still being fetched, and are first
+// allocated in `file` if `preallocate` is set.
+fn dump_memory<T: std::io::Write + std::io::Seek>(
+    vmm: &Vmm,
+    writer: &mut T,
+    file: &File,
+    snapshot_type: &SnapshotType,
+    preallocate: bool,
+    scrub_ranges: &[ScrubRange],
+    save_state: SaveStateTask,
+) -> std::result::Result<(), CreateSnapshotError> {
+    use self::CreateSnapshotError::*;
+    let mut tasks = vec![Box::new(move || DumpStep::StateSaved(save_state())) as Task<_>];
+    if *snapshot_type == SnapshotType::Diff {
+        for (slot, fetch) in vmm.dirty_log_tasks() {
+            tasks.push(Box::new(move || DumpStep::DirtyLogFetched(slot, fetch())) as Task<_>);
+        }
+    }
+    WORKER_POOL.run_with(tasks, |steps| {
+        // The pages still holding the memory file content are copied from it.
+        if *snapshot_type == SnapshotType::Full {
+            vmm.guest_memory()
+                .dump_copying(writer, file, scrub_ranges)
+                .map_err(Memory)?;
+        }
+        let mut state_saved = Ok(());
+        for (_, step) in steps {
+            match step {
+                DumpStep::StateSaved(res) => state_saved = res,
+                DumpStep::DirtyLogFetched(slot, bitmap) => {
+                    let bitmap = bitmap.map_err(|_| DirtyBitmap)?;
+                    if preallocate {
+                        let extents = vmm.guest_memory().dirty_extents(slot, &bitmap);
+                        preallocate_file(file, &extents).map_err(MemoryBackingFile)?;
+                    }
+                    vmm.guest_memory()
+                        .dump_dirty_slot(writer, slot, &bitmap, scrub_ranges)
+                        .map_err(Memory)?;
+                }
+            }
+        }
+        state_saved
+    })
 }
 
 pub(crate) fn mem_size_mib(guest_memory: &GuestMemoryMmap) -> u64 {
     guest_memory.map_and_fold(0, |(_, region)| region.len(), |a, b| a + b) >> 20
 }
 
-/// Loads a Microvm snapshot producing a 'paused' Microvm.
+/// Loads a Microvm snapshot producing a 'paused' Microvm, along with the working set prefetch
+/// accounting if requested.
 pub fn load_snapshot(
     event_manager: &mut EventManager,
-    seccomp_filter: BpfProgramRef,
+    seccomp_filters: &ThreadFilters,
+    params: &LoadSnapshotParams,
+    version_map: VersionMap,
+    keys: &SnapshotKeys,
+) -> std::result::Result<(Arc<Mutex<Vmm>>, Option<WsStats>), LoadSnapshotError> {
+    use self::LoadSnapshotError::*;
+    let span = RESTORE_TRACE.span(RestorePhase::SnapshotLoad);
+    let result = restore_from_snapshot(event_manager, seccomp_filters, params, version_map, keys);
+    drop(span);
+    match result.as_ref() {
+        Ok(_) => METRICS.snapshot.load_count.inc(),
+        Err(BuildMicroVm(_)) => METRICS.snapshot.load_build_fails.inc(),
+        Err(DeserializeMemory(_)) | Err(GrowMemory(_)) | Err(GrowWithUserPageFaults) => {
+            METRICS.snapshot.load_memory_fails.inc()
+        }
+        Err(DeserializeMicrovmState(_))
+        | Err(IncompatibleSnapshot(_))
+        | Err(FaasnapDisabled(_)) => METRICS.snapshot.load_state_fails.inc(),
+        Err(MemoryBackingFile(_))
+        | Err(InvalidInheritedFd(_))
+        | Err(SnapshotBackingFile(_))
+        | Err(LayerFadvise(_))
+        | Err(TruncatedFile(..)) => METRICS.snapshot.load_file_fails.inc(),
+        Err(UserPageFault(_)) => METRICS.snapshot.load_uffd_fails.inc(),
+        #[cfg(feature = "faasnap")]
+        Err(FaultTrace(_)) | Err(ProtectBase(_)) => METRICS.snapshot.load_uffd_fails.inc(),
+        Err(VerifySnapshot(_)) => METRICS.snapshot.load_verify_fails.inc(),
+        Err(WsAccounting(_))
+        | Err(WsLoad(_))
+        | Err(WsLock(_))
+        | Err(WsPopulateWithoutWsFile)
+        | Err(LayerConflicts(_)) => METRICS.snapshot.load_memory_fails.inc(),
+        Err(NetNs(_))
+        | Err(UnknownNetworkInterface(_))
+        | Err(WarmNotify(_))
+        | Err(IdleReclaim(_)) => METRICS.snapshot.load_build_fails.inc(),
+    }
+    result
+}
+
+fn restore_from_snapshot(
+    event_manager: &mut EventManager,
+    seccomp_filters: &ThreadFilters,
     params: &LoadSnapshotParams,
     version_map: VersionMap,
-) -> std::result::Result<Arc<Mutex<Vmm>>, LoadSnapshotError> {
+    keys: &SnapshotKeys,
+) -> std::result::Result<(Arc<Mutex<Vmm>>, Option<WsStats>), LoadSnapshotError> {
     use self::LoadSnapshotError::*;
+    // Without the faasnap extensions, the guest memory is restored from the memory file alone.
+    #[cfg(not(feature = "faasnap"))]
+    {
+        if let Some(option) = faasnap_extension(params) {
+            return Err(FaasnapDisabled(option));
+        }
+    }
+    let _watchdog = params.watchdog.and_then(|config| {
+        restore_watchdog::arm(config)
+            .map_err(|e| warn!("Cannot arm the restore watchdog: {}", e))
+            .ok()
+    });
     let track_dirty = params.enable_diff_snapshots;
-    let microvm_state = snapshot_state_from_file(&params.snapshot_path, version_map)?;
-    let guest_memory = guest_memory_from_file(&params.mem_file_path, &microvm_state.memory_state, params.enable_user_page_faults, &params.overlay_file_path, &params.overlay_regions, &params.ws_file_path, &params.ws_regions, params.load_ws, &params.fadvise)?;
-    if params.enable_user_page_faults == true {
-        guest_memory.register_for_upf(&params.sock_file_path).map_err(UserPageFault)?;
+    let layer_advice =
+        LayerAdvice::new(&params.fadvise, params.layer_fadvise.as_ref()).map_err(LayerFadvise)?;
+    // The manifest of a signed snapshot lists its layers, along with the digests of their files.
+    let manifest = keys
+        .manifest(&params.snapshot_path)
+        .map_err(VerifySnapshot)?;
+    let manifest = manifest.as_ref();
+    // The state is deserialized on a helper thread while the memory layers are opened, and read
+    // in full to be verified when signing is enabled.
+    builder::start_helper_threads(seccomp_filters);
+    let load_state =
+        Box::new(move || snapshot_state_from_file(&params.snapshot_path, version_map, manifest))
+            as Task<std::result::Result<MicrovmState, LoadSnapshotError>>;
+    let (mut microvm_state, (mem_file, overlay_file, ws_file)) =
+        WORKER_POOL.run_with(vec![load_state], |loaded| {
+            let layers = open_memory_layers(params, manifest);
+            let (_, microvm_state) = loaded.next().expect("The state load has no result");
+            Ok((microvm_state?, layers?))
+        })?;
+    // No layer of the signed snapshot may be left out. The memory file is verified through the
+    // descriptor it is then mapped from, unless a page fault handler serves it, which has to
+    // check the file itself.
+    if let Some(manifest) = manifest {
+        let mut loaded = vec![Layer::State];
+        if mem_file.is_some() || params.enable_user_page_faults {
+            loaded.push(Layer::Memory);
+        }
+        loaded.extend(overlay_file.as_ref().map(|_| Layer::Overlay));
+        loaded.extend(ws_file.as_ref().map(|_| Layer::WorkingSet));
+        manifest.check_layout(&loaded).map_err(VerifySnapshot)?;
+    }
+    layer_advice.apply(mem_file.as_ref(), overlay_file.as_ref(), ws_file.as_ref());
+    // The dirty bitmaps and the overlay and working set extents are counted in host pages.
+    microvm_state
+        .memory_state
+        .check_page_size()
+        .map_err(DeserializeMemory)?;
+    override_network_interfaces(&mut microvm_state.device_states, &params.network_overrides)?;
+    // Recorded faults are serviced from the memory file, which then does not back the guest
+    // memory.
+    #[cfg(feature = "faasnap")]
+    let (mem_file, traced_mem_file) = match params.fault_trace_path {
+        Some(_) => {
+            if params.enable_user_page_faults
+                || params.load_ws
+                || overlay_file.is_some()
+                || ws_file.is_some()
+            {
+                return Err(FaultTrace(fault_trace::Error::UnsupportedLayers));
+            }
+            let mem_file = mem_file.ok_or(FaultTrace(fault_trace::Error::MissingMemoryFile))?;
+            (None, Some(mem_file))
+        }
+        None => (mem_file, None),
+    };
+    // A protected memory file is only read by the base pager, and does not back the guest
+    // memory either.
+    #[cfg(feature = "faasnap")]
+    let (mem_file, protected_mem_file) = match params.protect_base {
+        true => {
+            if params.enable_user_page_faults || params.fault_trace_path.is_some() {
+                return Err(ProtectBase(protected_base::Error::UnsupportedFaultHandler));
+            }
+            let mem_file = mem_file.ok_or(ProtectBase(protected_base::Error::MissingMemoryFile))?;
+            (None, Some(mem_file))
+        }
+        false => (mem_file, None),
+    };
+    #[cfg(not(feature = "faasnap"))]
+    let protected_mem_file: Option<File> = None;
+    if params.ws_populate && (!params.load_ws || ws_file.is_none()) {
+        return Err(WsPopulateWithoutWsFile);
+    }
+    if params.ws_accounting {
+        if params.ws_lock.is_some() {
+            return Err(WsLock(ws_lock::Error::WithAccounting));
+        }
+        if !params.load_ws || ws_file.is_none() || params.enable_user_page_faults {
+            return Err(WsAccounting(ws_accounting::Error::NoPrefetch));
+        }
+    }
+    // The memory added to the snapshot is anonymous, so it has no pages for a uffd handler.
+    let extra_regions = match params.mem_size_mib {
+        Some(mem_size_mib) => {
+            if params.enable_user_page_faults {
+                return Err(GrowWithUserPageFaults);
+            }
+            microvm_state
+                .memory_state
+                .extra_regions(mem_size_mib)
+                .map_err(GrowMemory)?
+        }
+        None => Vec::new(),
+    };
+    // A truncated memory file would only fault with SIGBUS once the guest touches its missing
+    // pages.
+    if let Some(file) = mem_file.as_ref().or_else(|| protected_mem_file.as_ref()) {
+        check_memory_file_size(params, &microvm_state.memory_state, file)?;
     }
-    if params.load_ws {
-        guest_memory.load_working_set(&params.ws_regions);
+    if overlay_file.is_some() && ws_file.is_some() {
+        check_layer_conflicts(params)?;
     }
-    builder::build_microvm_from_snapshot(
+    for (base_address, size) in extra_regions.iter() {
+        info!(
+            "Adding guest memory at {:#x}, length {:#x}",
+            base_address.0, size
+        );
+    }
+    #[cfg(feature = "faasnap")]
+    let guest_memory = guest_memory_from_file(
+        mem_file.as_ref(),
+        &microvm_state.memory_state,
+        &extra_regions,
+        overlay_file.as_ref(),
+        &params.overlay_regions,
+        ws_file.as_ref(),
+        &params.ws_regions,
+        params.ws_populate,
+        params.strict_memory,
+    )?;
+    #[cfg(not(feature = "faasnap"))]
+    let guest_memory = guest_memory_from_file(
+        mem_file.as_ref(),
+        &microvm_state.memory_state,
+        &extra_regions,
+        params.strict_memory,
+    )?;
+    if params.ksm {
+        if let Err(e) = ksm::mark_mergeable(&guest_memory) {
+            warn!("Cannot mark the guest memory as mergeable: {}", e);
+        }
+    }
+    if params.huge_pages {
+        if let Err(e) = memory_policy::enable_huge_pages(&guest_memory) {
+            warn!("Cannot back the guest memory with huge pages: {}", e);
+        }
+    }
+    if let Some(node) = params.numa_node {
+        if let Err(e) = memory_policy::prefer_node(&guest_memory, node) {
+            warn!("Cannot place the guest memory on NUMA node {}: {}", node, e);
+        }
+    }
+    // The fault trace thread is stopped when the restore fails, or along with the microVM.
+    #[cfg(feature = "faasnap")]
+    let fault_trace = match (params.fault_trace_path.as_ref(), traced_mem_file) {
+        (Some(path), Some(file)) => Some(
+            fault_trace::start(
+                path,
+                &guest_memory,
+                &microvm_state.memory_state,
+                file,
+                seccomp_filters.uffd_handler.clone(),
+            )
+            .map_err(FaultTrace)?,
+        ),
+        _ => None,
+    };
+    // The base pager is stopped when the restore fails, or along with the microVM. The pages
+    // mapped from the overlay and working set files are not its to service.
+    #[cfg(feature = "faasnap")]
+    let protected_base = match protected_mem_file {
+        Some(file) => {
+            let mapped = {
+                let backing = BACKING_FILES.lock().expect("Poisoned lock");
+                let mut mapped = backing.layer_ranges(MmapLayer::Overlay);
+                mapped.extend(backing.layer_ranges(MmapLayer::WorkingSet));
+                mapped
+            };
+            Some(
+                protected_base::start(
+                    &guest_memory,
+                    &microvm_state.memory_state,
+                    file,
+                    &mapped,
+                    seccomp_filters.uffd_handler.clone(),
+                )
+                .map_err(ProtectBase)?,
+            )
+        }
+        None => None,
+    };
+    // Dropped along with the guest memory when the restore fails.
+    #[cfg(feature = "faasnap")]
+    let upf_registration = if params.enable_user_page_faults == true {
+        let _span = RESTORE_TRACE.span(RestorePhase::UffdRegister);
+        let upstream_handshake = params.mem_backend.as_ref().map_or(false, |backend| {
+            backend.backend_type == MemBackendType::Uffd
+        });
+        let registered = if upstream_handshake {
+            guest_memory.connect_uffd_handler(&params.sock_file_path)
+        } else {
+            guest_memory.register_for_upf(&params.sock_file_path)
+        };
+        Some(registered.map_err(UserPageFault)?)
+    } else {
+        None
+    };
+    // Populated extents are loaded as they are mapped.
+    if params.load_ws && !params.ws_populate {
+        let _watch = RESTORE_WATCHDOG.watch(WatchedOperation::WsLoad);
+        if let Some(file) = ws_file.as_ref().or_else(|| mem_file.as_ref()) {
+            RESTORE_WATCHDOG.set_fd(file.as_raw_fd());
+        }
+        // Without PSI on the host, the working set loads unthrottled.
+        let mut throttle = params.prefetch_throttle.as_ref().and_then(|config| {
+            PrefetchThrottle::new(config)
+                .map_err(|e| warn!("Cannot throttle the working set load: {}", e))
+                .ok()
+        });
+        // Without the token file, the working set loads regardless of the other restores.
+        let coordinator = params.prefetch_coordinator.as_ref().and_then(|config| {
+            PrefetchCoordinator::new(config)
+                .map_err(|e| warn!("Cannot coordinate the working set load: {}", e))
+                .ok()
+        });
+        let profile = match ws_file.as_ref().or_else(|| mem_file.as_ref()) {
+            Some(file) => StorageProfile::detect(file, 0),
+            None => StorageProfile::default(),
+        };
+        let tuning = PrefetchTuning::new(&profile, params.prefetch.as_ref());
+        info!(
+            "Loading the working set with {:?}, tuned for {:?}",
+            tuning, profile
+        );
+        // The load can be cancelled through the API until it returns.
+        let deadline = params.ws_load_deadline.as_ref();
+        let _load = WS_LOAD.begin(deadline.map(|config| Duration::from_millis(config.deadline_ms)));
+        let loaded = guest_memory.load_working_set(
+            &params.ws_regions,
+            ws_file.is_some(),
+            ws_file.as_ref().or_else(|| mem_file.as_ref()),
+            &tuning,
+            throttle.as_mut(),
+            coordinator.as_ref(),
+            deadline.map_or(false, |config| config.readahead),
+        );
+        match loaded {
+            Ok(None) => LIFECYCLE.notify(LifecycleEvent::WsLoadComplete),
+            Ok(Some(_)) => LIFECYCLE.notify(LifecycleEvent::WsLoadStopped),
+            Err(err) => {
+                METRICS.snapshot.ws_load_fails.inc();
+                LIFECYCLE.notify(LifecycleEvent::WsLoadFailed);
+                return Err(WsLoad(err));
+            }
+        }
+    } else if params.load_ws {
+        LIFECYCLE.notify(LifecycleEvent::WsLoadComplete);
+    }
+    if let Some(mode) = params.ws_lock {
+        let locked = ws_lock::lock_working_set(
+            &guest_memory,
+            &microvm_state.memory_state,
+            &params.ws_regions,
+            mode,
+        )
+        .map_err(WsLock)?;
+        info!("Locked {} bytes of the working set in memory", locked);
+    }
+    let accounting = if params.ws_accounting {
+        Some(
+            ws_accounting::WsAccounting::new(
+                &guest_memory,
+                &microvm_state.memory_state,
+                &params.ws_regions,
+            )
+            .map_err(WsAccounting)?,
+        )
+    } else {
+        None
+    };
+    // The scheduler is reached from the host network namespace, before the VMM is sandboxed.
+    let warm_notifier = match params.warm_notify.as_ref() {
+        Some(config) => Some(
+            WarmNotifier::new(
+                &guest_memory,
+                &microvm_state.memory_state,
+                &params.ws_regions,
+                config,
+            )
+            .map_err(WarmNotify)?,
+        ),
+        None => None,
+    };
+    // Reclaiming the guest memory is optional, unless the microVM reclaims it once idle. The
+    // idle reclamation shares the reclaimer of the API requests.
+    let memory_reclaimer = match MemoryReclaimer::new(
+        &guest_memory,
+        &microvm_state.memory_state,
+        &params.ws_regions,
+    ) {
+        Ok(reclaimer) => Some(Arc::new(reclaimer)),
+        Err(e) if params.idle_reclaim.is_some() => {
+            return Err(IdleReclaim(idle_reclaim::Error::Reclaimer(e)))
+        }
+        Err(e) => {
+            warn!("Cannot reclaim the guest memory: {}", e);
+            None
+        }
+    };
+    let idle_reclaimer = match (params.idle_reclaim.as_ref(), memory_reclaimer.as_ref()) {
+        (Some(config), Some(reclaimer)) => {
+            Some(IdleReclaimer::new(reclaimer.clone(), config).map_err(IdleReclaim)?)
+        }
+        _ => None,
+    };
+    // The faults the VMM serves are counted as they are served, and the ones a page fault
+    // handler serves are its own to attribute. The sampler is optional, failing to set it up does
+    // not fail the restore.
+    #[cfg(feature = "faasnap")]
+    let (traced, base_served) = (fault_trace.is_some(), protected_base.is_some());
+    #[cfg(not(feature = "faasnap"))]
+    let (traced, base_served) = (false, false);
+    let fault_sampler = if !params.fault_attribution || traced {
+        None
+    } else if params.enable_user_page_faults {
+        warn!("The faults served by the page fault handler are not attributed");
+        None
+    } else {
+        let served: &[FaultSource] = if base_served {
+            &[FaultSource::Base, FaultSource::Anonymous]
+        } else {
+            &[]
+        };
+        FaultSampler::new(&guest_memory, &microvm_state.memory_state, served)
+            .map_err(|e| warn!("Cannot sample the guest page faults: {}", e))
+            .ok()
+    };
+    let ws_stats = match accounting.as_ref() {
+        Some(accounting) => Some(ws_accounting::prepare(accounting).map_err(WsAccounting)?),
+        None => None,
+    };
+    // The TAP devices are opened in the namespace of the thread restoring the devices.
+    join_netns(params.netns_path.as_ref(), params.netns_fd)?;
+    // Nothing below fails once the microVM is built, as its partly built state cannot be rolled
+    // back. Resuming it is left to the caller, which fails the load for good if that fails.
+    let vmm = builder::build_microvm_from_snapshot(
         event_manager,
         microvm_state,
         guest_memory,
         track_dirty,
-        seccomp_filter,
+        seccomp_filters,
     )
-    .map_err(BuildMicroVm)
+    .map_err(BuildMicroVm)?;
+    // The microVM keeps its guest memory until the process exits.
+    #[cfg(feature = "faasnap")]
+    {
+        if let Some(registration) = upf_registration {
+            registration.keep();
+        }
+        if let Some(fault_trace) = fault_trace {
+            vmm.lock()
+                .expect("Poisoned lock")
+                .set_fault_trace(fault_trace);
+        }
+        if let Some(protected_base) = protected_base {
+            vmm.lock()
+                .expect("Poisoned lock")
+                .set_protected_base(protected_base);
+        }
+    }
+    if let Some(memory_reclaimer) = memory_reclaimer {
+        vmm.lock()
+            .expect("Poisoned lock")
+            .set_memory_reclaimer(memory_reclaimer);
+    }
+    announce_guests(
+        &vmm.lock().expect("Poisoned lock"),
+        &params.network_overrides,
+    );
+    if let Some(accounting) = accounting {
+        ws_accounting::start(event_manager, accounting);
+    }
+    if let Some(sampler) = fault_sampler {
+        fault_sources::start(event_manager, sampler);
+    }
+    if let Some(notifier) = warm_notifier {
+        warm_notify::start(event_manager, notifier);
+    }
+    if let Some(reclaimer) = idle_reclaimer {
+        idle_reclaim::start(event_manager, reclaimer);
+    }
+    Ok((vmm, ws_stats))
+}
+
+// Binds the network interfaces of the snapshot to the TAP devices named by the overrides.
+fn override_network_interfaces(
+    device_states: &mut DeviceStates,
+    overrides: &[NetworkOverride],
+) -> std::result::Result<(), LoadSnapshotError> {
+    for net_override in overrides.iter() {
+        let net_state = device_states
+            .net_devices
+            .iter_mut()
+            .find(|state| state.device_id == net_override.iface_id)
+            .ok_or_else(|| {
+                LoadSnapshotError::UnknownNetworkInterface(net_override.iface_id.clone())
+            })?;
+        if let Some(host_dev_name) = net_override.host_dev_name.as_ref() {
+            net_state
+                .device_state
+                .set_tap_if_name(host_dev_name.clone());
+        }
+    }
+    Ok(())
 }
 
+// Moves the calling thread to the network namespace at `path`, or to the inherited `fd`. The
+// TAP devices missing from the namespace are created when the devices are restored.
+fn join_netns(
+    path: Option<&PathBuf>,
+    fd: Option<RawFd>,
+) -> std::result::Result<(), LoadSnapshotError> {
+    use self::LoadSnapshotError::NetNs;
+
+    let path_file;
+    let netns_fd = match (fd, path) {
+        (Some(fd), _) => fd,
+        (None, Some(path)) => {
+            path_file = File::open(path).map_err(NetNs)?;
+            path_file.as_raw_fd()
+        }
+        (None, None) => return Ok(()),
+    };
+    // Safe because `setns` does not modify memory, and we check the result. It fails for the
+    // fds that do not refer to a network namespace, which are then left open.
+    SyscallReturnCode(unsafe { libc::setns(netns_fd, libc::CLONE_NEWNET) })
+        .into_empty_result()
+        .map_err(NetNs)?;
+    if fd.is_some() {
+        // Safe because the fd refers to a network namespace, which is no longer needed.
+        drop(unsafe { File::from_raw_fd(netns_fd) });
+    }
+    Ok(())
+}
+
+// Announces the guests of the overrides with an IPv4 address, so that the host neighbours
+// point to the restored MACs before the guest resumes. Failures are not fatal, as the host
+// learns the MACs from the guest traffic anyway.
+fn announce_guests(vmm: &Vmm, overrides: &[NetworkOverride]) {
+    for net_override in overrides.iter() {
+        let guest_ip = match net_override.guest_ipv4 {
+            Some(guest_ip) => guest_ip,
+            None => continue,
+        };
+        let busdev = match vmm.get_bus_device(DeviceType::Virtio(TYPE_NET), &net_override.iface_id)
+        {
+            Some(busdev) => busdev,
+            None => continue,
+        };
+        let virtio_device = busdev
+            .lock()
+            .expect("Poisoned lock")
+            .as_any()
+            .downcast_ref::<MmioTransport>()
+            // Only MmioTransport implements BusDevice at this point.
+            .expect("Unexpected BusDevice type")
+            .device();
+        let mut locked_device = virtio_device.lock().expect("Poisoned lock");
+        let net = locked_device.as_mut_any().downcast_mut::<Net>().unwrap();
+        if let Err(err) = net.announce_guest(guest_ip) {
+            warn!(
+                "Cannot announce the guest on {}: {:?}",
+                net_override.iface_id, err
+            );
+        }
+    }
+}
+
+// Deserializes the microVM state from a mapping of the state file, rather than from reads. A
+// signed state file is read in full instead, and deserialized from the very bytes checked against
+// the `manifest`, which later writes to the file cannot change.
 fn snapshot_state_from_file(
     snapshot_path: &PathBuf,
     version_map: VersionMap,
+    manifest: Option<&Manifest>,
 ) -> std::result::Result<MicrovmState, LoadSnapshotError> {
-    use self::LoadSnapshotError::{DeserializeMicrovmState, SnapshotBackingFile};
-    let mut snapshot_reader =
-        std::io::BufReader::new(File::open(snapshot_path).map_err(SnapshotBackingFile)?);
-    Snapshot::load(&mut snapshot_reader, version_map).map_err(DeserializeMicrovmState)
+    use self::LoadSnapshotError::{SnapshotBackingFile, VerifySnapshot};
+    let _span = RESTORE_TRACE.span(RestorePhase::StateDeserialize);
+    let snapshot_file = File::open(snapshot_path).map_err(SnapshotBackingFile)?;
+    match manifest {
+        Some(manifest) => {
+            let mut bytes = Vec::new();
+            (&snapshot_file)
+                .read_to_end(&mut bytes)
+                .map_err(SnapshotBackingFile)?;
+            manifest
+                .verify_bytes(Layer::State, &bytes)
+                .map_err(VerifySnapshot)?;
+            snapshot_state_from_bytes(&bytes, version_map)
+        }
+        None => {
+            let map = StateFileMap::new(&snapshot_file).map_err(SnapshotBackingFile)?;
+            snapshot_state_from_bytes(map.as_slice(), version_map)
+        }
+    }
+}
+
+// Checks the versions first, for an error naming what this binary lacks, rather than whichever
+// field fails to parse.
+fn snapshot_state_from_bytes(
+    bytes: &[u8],
+    version_map: VersionMap,
+) -> std::result::Result<MicrovmState, LoadSnapshotError> {
+    use self::LoadSnapshotError::{DeserializeMicrovmState, IncompatibleSnapshot};
+    let mut state = bytes;
+    let data_version = Snapshot::get_data_version(&mut state).map_err(DeserializeMicrovmState)?;
+    check_compatibility(state, data_version).map_err(IncompatibleSnapshot)?;
+    let mut state = bytes;
+    Snapshot::load(&mut state, version_map).map_err(DeserializeMicrovmState)
+}
+
+// Read-only mapping of the microVM state file, unmapped when dropped.
+struct StateFileMap {
+    addr: *mut libc::c_void,
+    len: usize,
+}
+
+impl StateFileMap {
+    fn new(file: &File) -> io::Result<Self> {
+        // The size is read with lseek, which the seccomp filters allow unlike statx.
+        let len = (&*file).seek(SeekFrom::End(0))? as usize;
+        if len == 0 {
+            return Ok(StateFileMap {
+                addr: std::ptr::null_mut(),
+                len,
+            });
+        }
+        // Safe because the mapping is only read through `as_slice`, and unmapped on drop.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(StateFileMap { addr, len })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        // Safe because the `len` bytes at `addr` are mapped readable for the lifetime of `self`.
+        unsafe { std::slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+}
+
+impl Drop for StateFileMap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // Safe because the mapping is owned by `self`, and no slice of it outlives it.
+            unsafe { libc::munmap(self.addr, self.len) };
+        }
+    }
 }
 
+// Opens the memory, overlay and ws files. Every layer is verified against the `manifest` before
+// anything gets mapped.
+fn open_memory_layers(
+    params: &LoadSnapshotParams,
+    manifest: Option<&Manifest>,
+) -> std::result::Result<(Option<File>, Option<File>, Option<File>), LoadSnapshotError> {
+    let cache = params.snapshot_cache.as_ref();
+    let mem_file = open_snapshot_file(
+        Layer::Memory,
+        &params.mem_file_path,
+        params.mem_file_fd,
+        cache,
+        manifest,
+    )?;
+    let overlay_file = open_snapshot_file(
+        Layer::Overlay,
+        &params.overlay_file_path,
+        params.overlay_file_fd,
+        cache,
+        manifest,
+    )?;
+    let ws_file = match open_staged_ws_file(params, manifest)? {
+        Some(file) => Some(file),
+        None => open_snapshot_file(
+            Layer::WorkingSet,
+            &params.ws_file_path,
+            params.ws_file_fd,
+            cache,
+            manifest,
+        )?,
+    };
+    Ok((mem_file, overlay_file, ws_file))
+}
+
+// Opens the guest memory `layer` and checks it against the `manifest`. An inherited file
+// descriptor takes precedence over the path, and an empty path means the layer is not used. A
+// path is mapped from its copy in the snapshot `cache`, if any, falling back to the path itself.
+fn open_snapshot_file(
+    layer: Layer,
+    path: &PathBuf,
+    fd: Option<RawFd>,
+    cache: Option<&SnapshotCacheConfig>,
+    manifest: Option<&Manifest>,
+) -> std::result::Result<Option<File>, LoadSnapshotError> {
+    let cached = match (cache, fd) {
+        (Some(config), None) => snapshot_cache::open_cached(config, path),
+        _ => None,
+    };
+    let file = match cached {
+        Some(file) => Some(file),
+        None => open_layer_file(path, fd)?,
+    };
+    if let (Some(file), Some(manifest)) = (file.as_ref(), manifest) {
+        manifest
+            .verify_file(layer, path, file)
+            .map_err(LoadSnapshotError::VerifySnapshot)?;
+    }
+    Ok(file)
+}
+
+// Opens the staged copy of the ws file if `ws_staging` is set, checked against the ws file entry
+// of the `manifest`. Returns `None` if the ws file is not staged, and is then opened from its
+// path.
+fn open_staged_ws_file(
+    params: &LoadSnapshotParams,
+    manifest: Option<&Manifest>,
+) -> std::result::Result<Option<File>, LoadSnapshotError> {
+    match ws_staging::stage_ws_file(params) {
+        Some((path, file)) => {
+            info!("Mapping the ws file from {}", path.display());
+            if let Some(manifest) = manifest {
+                manifest
+                    .verify_file(Layer::WorkingSet, &path, &file)
+                    .map_err(LoadSnapshotError::VerifySnapshot)?;
+            }
+            Ok(Some(file))
+        }
+        None => Ok(None),
+    }
+}
+
+// Returns the first parameter of `params` requesting an overlay, working set or userfaultfd
+// restore extension, if any.
+#[cfg(not(feature = "faasnap"))]
+fn faasnap_extension(params: &LoadSnapshotParams) -> Option<&'static str> {
+    let extensions = [
+        ("enable_user_page_faults", params.enable_user_page_faults),
+        (
+            "overlay_file_path",
+            !params.overlay_file_path.as_os_str().is_empty(),
+        ),
+        ("overlay_file_fd", params.overlay_file_fd.is_some()),
+        ("overlay_regions", !params.overlay_regions.is_empty()),
+        ("ws_file_path", !params.ws_file_path.as_os_str().is_empty()),
+        ("ws_file_fd", params.ws_file_fd.is_some()),
+        ("ws_regions", !params.ws_regions.is_empty()),
+        ("load_ws", params.load_ws),
+        ("fault_trace_path", params.fault_trace_path.is_some()),
+        ("protect_base", params.protect_base),
+    ];
+    extensions
+        .iter()
+        .find(|(_, requested)| *requested)
+        .map(|(option, _)| *option)
+}
+
+/// Formats (page, number of pages) extents, up to a few of them.
+fn format_extents(extents: &[(u64, u64)]) -> String {
+    const MAX_LISTED: usize = 8;
+    let mut listed: Vec<String> = extents
+        .iter()
+        .take(MAX_LISTED)
+        .map(|(page, pages)| format!("{}..{}", page, page + pages))
+        .collect();
+    if extents.len() > MAX_LISTED {
+        listed.push(format!("and {} more extents", extents.len() - MAX_LISTED));
+    }
+    listed.join(", ")
+}
+
+// Checks the pages mapped by both the overlay and the working set layers. The working set is
+// mapped last and wins, unless the conflicts are rejected.
+fn check_layer_conflicts(
+    params: &LoadSnapshotParams,
+) -> std::result::Result<(), LoadSnapshotError> {
+    let conflicts = memory_snapshot::layer_conflicts(&params.overlay_regions, &params.ws_regions);
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    let pages: u64 = conflicts.iter().map(|&(_, pages)| pages).sum();
+    METRICS.snapshot.layer_conflict_pages.add(pages as usize);
+    if params.reject_layer_conflicts {
+        return Err(LoadSnapshotError::LayerConflicts(conflicts));
+    }
+    warn!(
+        "The working set takes precedence over the overlay on {} pages: {}",
+        pages,
+        format_extents(&conflicts)
+    );
+    Ok(())
+}
+
+// Checks that the memory file covers the guest memory regions mapped from it. The overlay and
+// working set extents are checked against their files as they are mapped.
+fn check_memory_file_size(
+    params: &LoadSnapshotParams,
+    mem_state: &GuestMemoryState,
+    mem_file: &File,
+) -> std::result::Result<(), LoadSnapshotError> {
+    let required = mem_state.file_size();
+    let size = mem_file
+        .metadata()
+        .map_err(LoadSnapshotError::MemoryBackingFile)?
+        .len();
+    if size < required {
+        let name = match params.mem_file_fd {
+            Some(fd) => format!("memory file (fd {})", fd),
+            None => format!("memory file {}", params.mem_file_path.display()),
+        };
+        return Err(LoadSnapshotError::TruncatedFile(name, size, required));
+    }
+    Ok(())
+}
+
+lazy_static! {
+    // The fds the snapshot files were inherited on, which the fd fields of the load parameters
+    // may refer to.
+    static ref SNAPSHOT_FDS: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+}
+
+/// Declares `fds` as inherited snapshot files, which the loads can then refer to. Must be called
+/// at startup, before the process opens any file of its own that could take these fd numbers.
+pub fn declare_snapshot_fds(fds: &[RawFd]) {
+    SNAPSHOT_FDS
+        .lock()
+        .expect("Poisoned lock")
+        .extend_from_slice(fds);
+}
+
+fn open_layer_file(
+    path: &PathBuf,
+    fd: Option<RawFd>,
+) -> std::result::Result<Option<File>, LoadSnapshotError> {
+    open_layer_file_from(&SNAPSHOT_FDS.lock().expect("Poisoned lock"), path, fd)
+}
+
+// Opens the layer file at `path`, or from the inherited `fd`, which must be one of
+// `snapshot_fds`.
+fn open_layer_file_from(
+    snapshot_fds: &[RawFd],
+    path: &PathBuf,
+    fd: Option<RawFd>,
+) -> std::result::Result<Option<File>, LoadSnapshotError> {
+    use self::LoadSnapshotError::{InvalidInheritedFd, MemoryBackingFile};
+
+    if let Some(fd) = fd {
+        // Only accept the fds that were handed over for reading snapshot data, so that API
+        // clients cannot get hold of, or close, other files opened by this process.
+        if !snapshot_fds.contains(&fd) {
+            return Err(InvalidInheritedFd(fd));
+        }
+        // Safe because `fstat` only writes to the provided buffer and we check the result.
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut stat) } < 0 || stat.st_mode & libc::S_IFMT != libc::S_IFREG
+        {
+            return Err(InvalidInheritedFd(fd));
+        }
+        // Safe because `fcntl` does not modify memory and we check the result.
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || flags & libc::O_ACCMODE != libc::O_RDONLY {
+            return Err(InvalidInheritedFd(fd));
+        }
+        // The inherited fd stays open, so that a failed load can be retried with it. Safe
+        // because `dup` does not modify memory and we check the result.
+        let dup_fd = SyscallReturnCode(unsafe { libc::dup(fd) })
+            .into_result()
+            .map_err(MemoryBackingFile)?;
+        // Safe because `dup_fd` is a new fd, owned by the returned file.
+        let file = unsafe { File::from_raw_fd(dup_fd) };
+        // Safe because `fcntl` does not modify memory and we check the result.
+        SyscallReturnCode(unsafe { libc::fcntl(dup_fd, libc::F_SETFD, libc::FD_CLOEXEC) })
+            .into_empty_result()
+            .map_err(MemoryBackingFile)?;
+        return Ok(Some(file));
+    }
+
+    if path.as_os_str().is_empty() {
+        return Ok(None);
+    }
+    File::open(path).map(Some).map_err(MemoryBackingFile)
+}
+
+#[cfg(feature = "faasnap")]
 fn guest_memory_from_file(
-    mem_file_path: &PathBuf,
+    mem_file: Option<&File>,
     mem_state: &GuestMemoryState,
-    enable_user_page_faults: bool,
-    overlay_file_path: &PathBuf,
+    extra_regions: &[(GuestAddress, usize)],
+    overlay_file: Option<&File>,
     overlay_regions: &HashMap<i64, i64>,
-    ws_file_path: &PathBuf,
+    ws_file: Option<&File>,
     ws_regions: &Vec<Vec<i64>>,
-    load_ws: bool,
-    fadvise: &String,
+    populate_ws: bool,
+    strict_memory: bool,
 ) -> std::result::Result<GuestMemoryMmap, LoadSnapshotError> {
-    use self::LoadSnapshotError::{DeserializeMemory, MemoryBackingFile};
-    GuestMemoryMmap::restore(mem_file_path, mem_state, enable_user_page_faults, overlay_file_path, overlay_regions, ws_file_path, ws_regions, load_ws, fadvise).map_err(DeserializeMemory)
-    // if overlay_regions.is_empty()  { // vanilla
-    //     let memfile = File::open(mem_file_path).map_err(MemoryBackingFile)?;
-    //     GuestMemoryMmap::restore(&memfile, mem_state, enable_user_page_faults, overlay_regions, ws_regions, load_ws).map_err(DeserializeMemory)
-    // }
-    // else if !ws_regions.is_empty() && groups.is_empty() { // use wsfile only
-    //     let wsfile = File::open(ws_file).map_err(MemoryBackingFile)?;
-    //     // unsafe {posix_fadvise(f.as_raw_fd(), 0, 0, POSIX_FADV_RANDOM);}
-    //     GuestMemoryMmap::restore(&wsfile, mem_state, enable_user_page_faults, overlay_regions, ws_regions, load_ws).map_err(DeserializeMemory)
-    // } else { // use both memfile and wsfile
-    //     let memfile = File::open(mem_file_path).map_err(MemoryBackingFile)?;
-    //     let wsfile = File::open(ws_file).map_err(MemoryBackingFile)?;    
-    //     GuestMemoryMmap::restore2(&memfile, &wsfile, mem_state, enable_user_page_faults, overlay_regions, groups, load_ws).map_err(DeserializeMemory)
-    // }
+    use self::LoadSnapshotError::DeserializeMemory;
+    GuestMemoryMmap::restore(
+        mem_file,
+        mem_state,
+        extra_regions,
+        overlay_file,
+        overlay_regions,
+        ws_file,
+        ws_regions,
+        populate_ws,
+        strict_memory,
+    )
+    .map_err(DeserializeMemory)
+}
+
+// Without the faasnap extensions, the guest memory is mapped from the memory file alone.
+#[cfg(not(feature = "faasnap"))]
+fn guest_memory_from_file(
+    mem_file: Option<&File>,
+    mem_state: &GuestMemoryState,
+    extra_regions: &[(GuestAddress, usize)],
+    strict_memory: bool,
+) -> std::result::Result<GuestMemoryMmap, LoadSnapshotError> {
+    use self::LoadSnapshotError::DeserializeMemory;
+    GuestMemoryMmap::restore(
+        mem_file,
+        mem_state,
+        extra_regions,
+        None,
+        &HashMap::new(),
+        None,
+        &Vec::new(),
+        false,
+        strict_memory,
+    )
+    .map_err(DeserializeMemory)
 }
 
 #[cfg(test)]
@@ -321,6 +1711,7 @@ mod tests {
         insert_vsock_device, CustomBlockConfig,
     };
     use crate::memory_snapshot::SnapshotMemory;
+    use crate::version_map::VERSION_MAP;
     use crate::vmm_config::net::NetworkInterfaceConfig;
     use crate::vmm_config::vsock::tests::default_config;
     use crate::vstate::tests::default_vcpu_state;
@@ -328,6 +1719,7 @@ mod tests {
 
     use polly::event_manager::EventManager;
     use snapshot::Persist;
+    use utils::tempdir::TempDir;
     use utils::tempfile::TempFile;
 
     fn default_vmm_with_devices(event_manager: &mut EventManager) -> Vmm {
@@ -375,6 +1767,7 @@ mod tests {
         let memory_state = vmm.guest_memory().describe();
 
         let microvm_state = MicrovmState {
+            extensions: StateExtensions::current(),
             device_states: states,
             memory_state,
             vcpu_states: vec![default_vcpu_state()],
@@ -396,7 +1789,20 @@ mod tests {
         assert_eq!(
             restored_microvm_state.device_states,
             microvm_state.device_states
-        )
+        );
+
+        // The latest data version starts with the extensions of the writer.
+        let latest = VERSION_MAP.latest_version();
+        microvm_state
+            .serialize(&mut buf.as_mut_slice(), &VERSION_MAP, latest)
+            .unwrap();
+        assert!(check_compatibility(&buf, latest).is_ok());
+        let restored_microvm_state =
+            MicrovmState::deserialize(&mut buf.as_slice(), &VERSION_MAP, latest).unwrap();
+        assert_eq!(
+            restored_microvm_state.extensions,
+            StateExtensions::current()
+        );
     }
 
     #[test]
@@ -404,9 +1810,21 @@ mod tests {
         use crate::persist::CreateSnapshotError::*;
         use vm_memory::GuestMemoryError;
 
+        let err = BackgroundDiff;
+        let _ = format!("{}{:?}", err, err);
+
         let err = DirtyBitmap;
         let _ = format!("{}{:?}", err, err);
 
+        let err = DirtyPageTracking(crate::Error::VcpuPause);
+        let _ = format!("{}{:?}", err, err);
+
+        let err = CommitSnapshot(
+            PathBuf::from("/srv/vm.snap"),
+            io::Error::from_raw_os_error(0),
+        );
+        let _ = format!("{}{:?}", err, err);
+
         let err = InvalidVersion;
         let _ = format!("{}{:?}", err, err);
 
@@ -418,17 +1836,38 @@ mod tests {
         ));
         let _ = format!("{}{:?}", err, err);
 
+        let err = Memory(memory_snapshot::Error::MissingDirtyBitmap(2));
+        assert_eq!(
+            err.to_string(),
+            "Cannot write memory file: The dirty bitmap is missing memory slot 2"
+        );
+
         let err = MemoryBackingFile(io::Error::from_raw_os_error(0));
         let _ = format!("{}{:?}", err, err);
 
         let err = MicrovmState(MicrovmStateError::UnexpectedVcpuResponse);
         let _ = format!("{}{:?}", err, err);
 
+        let err = NestedVirtualization;
+        let _ = format!("{}{:?}", err, err);
+
+        let err = PageSize(1, 0x10000);
+        assert_eq!(
+            err.to_string(),
+            "Snapshot data version 1 cannot record the 65536 byte pages of the host"
+        );
+
         let err = SerializeMicrovmState(snapshot::Error::InvalidMagic(0));
         let _ = format!("{}{:?}", err, err);
 
+        let err = SignSnapshot(snapshot_signing::Error::MissingLayer(Layer::Overlay));
+        let _ = format!("{}{:?}", err, err);
+
         let err = SnapshotBackingFile(io::Error::from_raw_os_error(0));
         let _ = format!("{}{:?}", err, err);
+
+        let err = Vcpus(crate::Error::VcpuResume);
+        let _ = format!("{}{:?}", err, err);
     }
 
     #[test]
@@ -446,11 +1885,325 @@ mod tests {
         let err = DeserializeMicrovmState(snapshot::Error::Io(0));
         let _ = format!("{}{:?}", err, err);
 
+        let err = IncompatibleSnapshot(Incompatibility {
+            data_version: 7,
+            extensions: None,
+            unknown_features: vec!["feature".to_string()],
+        });
+        let _ = format!("{}{:?}", err, err);
+
         let err = MemoryBackingFile(io::Error::from_raw_os_error(0));
         let _ = format!("{}{:?}", err, err);
 
+        let err = InvalidInheritedFd(3);
+        let _ = format!("{}{:?}", err, err);
+
         let err = SnapshotBackingFile(io::Error::from_raw_os_error(0));
         let _ = format!("{}{:?}", err, err);
+
+        let err = LayerFadvise(layer_fadvise::Error::UnknownAdvice(
+            "ws file",
+            "later".to_string(),
+        ));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = TruncatedFile("memory file /snapshot/mem".to_string(), 0x1000, 0x3000);
+        assert_eq!(
+            err.to_string(),
+            "The memory file /snapshot/mem has 4096 bytes, 8192 short of the 12288 bytes mapped \
+             from it"
+        );
+
+        let err = VerifySnapshot(snapshot_signing::Error::LayerMismatch(Layer::Memory));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = NetNs(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = UnknownNetworkInterface("eth0".to_string());
+        let _ = format!("{}{:?}", err, err);
+
+        let err = WarmNotify(warm_notify::Error::InvalidPeriod);
+        let _ = format!("{}{:?}", err, err);
+
+        let err = IdleReclaim(idle_reclaim::Error::InvalidStep);
+        let _ = format!("{}{:?}", err, err);
+
+        let err = WsLoad(memory_snapshot::Error::InvalidExtent(0, 0x1000));
+        let _ = format!("{}{:?}", err, err);
+    }
+
+    #[test]
+    fn test_override_network_interfaces() {
+        let mut event_manager = EventManager::new().expect("Cannot create EventManager");
+        let vmm = default_vmm_with_devices(&mut event_manager);
+        let mut states = vmm.mmio_device_manager.save();
+        let iface_id = states.net_devices[0].device_id.clone();
+
+        let mut overrides = vec![NetworkOverride {
+            iface_id: iface_id.clone(),
+            host_dev_name: Some("tap-restored".to_string()),
+            guest_ipv4: None,
+        }];
+        override_network_interfaces(&mut states, &overrides).unwrap();
+
+        overrides[0].iface_id = "no-such-iface".to_string();
+        match override_network_interfaces(&mut states, &overrides) {
+            Err(LoadSnapshotError::UnknownNetworkInterface(id)) => assert_eq!(id, "no-such-iface"),
+            _ => panic!("Unknown interfaces should be rejected."),
+        }
+    }
+
+    #[test]
+    fn test_join_netns() {
+        // Nothing to join.
+        join_netns(None, None).unwrap();
+        assert!(join_netns(Some(&PathBuf::from("/no/such/netns")), None).is_err());
+
+        // Fds that are not network namespaces are rejected, and stay open.
+        let tmp_file = TempFile::new().unwrap();
+        let file = File::open(tmp_file.as_path()).unwrap();
+        match join_netns(None, Some(file.as_raw_fd())) {
+            Err(LoadSnapshotError::NetNs(_)) => (),
+            _ => panic!("Regular files should be rejected."),
+        }
+        // Safe because `fcntl` does not modify memory.
+        assert!(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFD) } >= 0);
+    }
+
+    #[test]
+    fn test_open_memory_file() {
+        use std::os::unix::net::UnixListener;
+
+        let tmp_file = TempFile::new().unwrap();
+        let path = tmp_file.as_path().to_path_buf();
+        let (_, streamed) = open_memory_file(&path).unwrap();
+        assert!(!streamed);
+
+        // A listening Unix socket is connected to.
+        let socket_path = PathBuf::from(format!("{}.sock", path.display()));
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let (mut file, streamed) = open_memory_file(&socket_path).unwrap();
+        assert!(streamed);
+        file.write_all(b"page").unwrap();
+        let mut received = [0u8; 4];
+        std::io::Read::read_exact(&mut listener.accept().unwrap().0, &mut received).unwrap();
+        assert_eq!(&received, b"page");
+        std::fs::remove_file(&socket_path).unwrap();
+    }
+
+    #[test]
+    fn test_commit_snapshot() {
+        use std::os::unix::net::UnixListener;
+
+        let dir = TempDir::new().unwrap();
+        let params = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_path: dir.as_path().join("vm.snap"),
+            mem_file_path: dir.as_path().join("vm.mem"),
+            version: None,
+            guest_agent: None,
+            background: false,
+            preallocate: false,
+        };
+        let keys = SnapshotKeys::default();
+        let mem_pending = pending_path(&params.mem_file_path);
+        let snapshot_pending = pending_path(&params.snapshot_path);
+        assert_eq!(mem_pending, dir.as_path().join("vm.mem.tmp"));
+
+        // The files of a failed snapshot are removed, and the previous snapshot is left alone.
+        std::fs::write(&params.snapshot_path, b"previous").unwrap();
+        std::fs::write(&mem_pending, b"mem").unwrap();
+        std::fs::write(&snapshot_pending, b"state").unwrap();
+        match commit_snapshot(&params, &keys, Err(CreateSnapshotError::DirtyBitmap)) {
+            Err(CreateSnapshotError::DirtyBitmap) => (),
+            _ => panic!("The snapshot should fail."),
+        }
+        assert!(!mem_pending.exists() && !snapshot_pending.exists());
+        assert_eq!(std::fs::read(&params.snapshot_path).unwrap(), b"previous");
+
+        // The files of a complete snapshot take their names.
+        std::fs::write(&mem_pending, b"mem").unwrap();
+        std::fs::write(&snapshot_pending, b"state").unwrap();
+        commit_snapshot(&params, &keys, Ok(())).unwrap();
+        assert!(!mem_pending.exists() && !snapshot_pending.exists());
+        assert_eq!(std::fs::read(&params.mem_file_path).unwrap(), b"mem");
+        assert_eq!(std::fs::read(&params.snapshot_path).unwrap(), b"state");
+
+        // A missing snapshot file fails the commit once the previous one is removed.
+        std::fs::write(&mem_pending, b"mem").unwrap();
+        match commit_snapshot(&params, &keys, Ok(())) {
+            Err(CreateSnapshotError::CommitSnapshot(path, _)) => {
+                assert_eq!(path, params.snapshot_path)
+            }
+            _ => panic!("The snapshot file should be missing."),
+        }
+        assert!(!params.snapshot_path.exists());
+
+        // Streamed memory files are written in place.
+        let socket_path = dir.as_path().join("vm.sock");
+        let _listener = UnixListener::bind(&socket_path).unwrap();
+        assert_eq!(pending_path(&socket_path), socket_path);
+    }
+
+    #[test]
+    fn test_preallocate_file() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp_file = TempFile::new().unwrap();
+        let file = tmp_file.as_file();
+        file.set_len(0x10000).unwrap();
+        preallocate_file(file, &[(0, 0x1000), (0x8000, 0x2000)]).unwrap();
+        // The length is unchanged, and the file holds at most the blocks of the whole file.
+        let metadata = file.metadata().unwrap();
+        assert_eq!(metadata.len(), 0x10000);
+        assert!(metadata.blocks() <= 0x10000 / 512);
+        assert!(preallocate_file(file, &[]).is_ok());
+    }
+
+    #[test]
+    fn test_open_layer_file() {
+        let tmp_file = TempFile::new().unwrap();
+        let path = tmp_file.as_path().to_path_buf();
+        // The declared fds are kept local rather than in `SNAPSHOT_FDS`, as the test closes them
+        // and their numbers get reused.
+        let mut snapshot_fds = Vec::new();
+
+        // Unused layer.
+        assert!(open_layer_file_from(&snapshot_fds, &PathBuf::new(), None)
+            .unwrap()
+            .is_none());
+        // Opened by path.
+        assert!(open_layer_file_from(&snapshot_fds, &path, None)
+            .unwrap()
+            .is_some());
+        assert!(
+            open_layer_file_from(&snapshot_fds, &PathBuf::from("/no/such/file"), None).is_err()
+        );
+
+        // Fds that were not declared as snapshot files are rejected.
+        let fd = File::open(&path).unwrap().into_raw_fd();
+        match open_layer_file_from(&snapshot_fds, &path, Some(fd)) {
+            Err(LoadSnapshotError::InvalidInheritedFd(_)) => (),
+            _ => panic!("Undeclared fd should be rejected."),
+        }
+
+        // Inherited read-only fd, which takes precedence over the path. It is duplicated, and
+        // stays open once the file is dropped.
+        snapshot_fds.push(fd);
+        let file = open_layer_file_from(&snapshot_fds, &PathBuf::from("/no/such/file"), Some(fd))
+            .unwrap()
+            .unwrap();
+        assert_ne!(file.as_raw_fd(), fd);
+        drop(file);
+        assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0);
+        assert!(open_layer_file_from(&snapshot_fds, &path, Some(fd))
+            .unwrap()
+            .is_some());
+        // Safe because the test owns the fd.
+        unsafe { libc::close(fd) };
+
+        // Writable fds and fds that are not regular files are rejected.
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        snapshot_fds.push(file.as_raw_fd());
+        match open_layer_file_from(&snapshot_fds, &path, Some(file.as_raw_fd())) {
+            Err(LoadSnapshotError::InvalidInheritedFd(_)) => (),
+            _ => panic!("Writable fd should be rejected."),
+        }
+        let dev_null = File::open("/dev/null").unwrap();
+        snapshot_fds.push(dev_null.as_raw_fd());
+        match open_layer_file_from(&snapshot_fds, &path, Some(dev_null.as_raw_fd())) {
+            Err(LoadSnapshotError::InvalidInheritedFd(_)) => (),
+            _ => panic!("Character device should be rejected."),
+        }
+    }
+
+    #[test]
+    fn test_check_memory_file_size() {
+        use crate::memory_snapshot::GuestMemoryRegionState;
+
+        let page_size = sysconf::page::pagesize();
+        let mem_state = GuestMemoryState {
+            regions: vec![
+                GuestMemoryRegionState {
+                    base_address: 0,
+                    size: 4 * page_size,
+                    offset: 0,
+                },
+                GuestMemoryRegionState {
+                    base_address: 0x1_0000_0000,
+                    size: 2 * page_size,
+                    offset: 4 * page_size as u64,
+                },
+            ],
+            page_size,
+        };
+        let mem_file = TempFile::new().unwrap();
+        let mut params = LoadSnapshotParams {
+            mem_file_path: mem_file.as_path().to_path_buf(),
+            ..Default::default()
+        };
+
+        // The memory file must cover the regions.
+        match check_memory_file_size(&params, &mem_state, mem_file.as_file()) {
+            Err(LoadSnapshotError::TruncatedFile(name, 0, required)) => {
+                assert_eq!(
+                    name,
+                    format!("memory file {}", mem_file.as_path().display())
+                );
+                assert_eq!(required, 6 * page_size as u64);
+            }
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+        mem_file.as_file().set_len(5 * page_size as u64).unwrap();
+        params.mem_file_fd = Some(42);
+        match check_memory_file_size(&params, &mem_state, mem_file.as_file()) {
+            Err(LoadSnapshotError::TruncatedFile(name, size, _)) => {
+                assert_eq!(name, "memory file (fd 42)");
+                assert_eq!(size, 5 * page_size as u64);
+            }
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+        mem_file.as_file().set_len(6 * page_size as u64).unwrap();
+        assert!(check_memory_file_size(&params, &mem_state, mem_file.as_file()).is_ok());
+    }
+
+    #[cfg(not(feature = "faasnap"))]
+    #[test]
+    fn test_faasnap_extension() {
+        let mut params = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("snapshot"),
+            mem_file_path: PathBuf::from("mem"),
+            ..Default::default()
+        };
+        assert_eq!(faasnap_extension(&params), None);
+        params.ws_regions = vec![vec![0, 1]];
+        assert_eq!(faasnap_extension(&params), Some("ws_regions"));
+        params.enable_user_page_faults = true;
+        assert_eq!(faasnap_extension(&params), Some("enable_user_page_faults"));
+    }
+
+    #[test]
+    fn test_check_layer_conflicts() {
+        let mut params = LoadSnapshotParams::default();
+        params.overlay_regions.insert(2, 3);
+        params.ws_regions = vec![vec![0, 1], vec![5, 2]];
+        assert!(check_layer_conflicts(&params).is_ok());
+
+        // The working set wins over the overlay, unless the conflicts are rejected.
+        params.ws_regions.push(vec![3, 1]);
+        assert!(check_layer_conflicts(&params).is_ok());
+        params.reject_layer_conflicts = true;
+        match check_layer_conflicts(&params) {
+            Err(LoadSnapshotError::LayerConflicts(conflicts)) => {
+                assert_eq!(conflicts, vec![(3, 1)]);
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let extents: Vec<_> = (0..10).map(|i| (i * 4, 2)).collect();
+        assert_eq!(format_extents(&extents[..2]), "0..2, 4..6");
+        assert!(format_extents(&extents).ends_with("28..30, and 2 more extents"));
     }
 
     #[test]
@@ -0,0 +1,215 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host-wide coordination of the working set prefetches.
+//!
+//! Each Firecracker process only paces its own prefetch, so a burst of restores on a host still
+//! reads from the disks all at once, and a rotational disk then spends its time seeking between
+//! the files. The restores sharing a token file draw the bytes they prefetch from a single token
+//! bucket kept in that file, under an exclusive `flock`, which caps their total prefetch
+//! bandwidth. Tokens refill by the `CLOCK_MONOTONIC` time, which the processes of a host share.
+
+use std::cmp::min;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use utils::time::{get_time_us, ClockType};
+
+use crate::restore_watchdog::RESTORE_WATCHDOG;
+use crate::vmm_config::snapshot::PrefetchCoordinatorConfig;
+
+// Length of the bucket state in the token file: the tokens left, then the time of the last
+// refill in microseconds, both little endian.
+const STATE_LEN: usize = 16;
+// Longest wait between two attempts to take tokens, so that the watchdog sees progress.
+const MAX_WAIT_US: u64 = 100_000;
+
+/// Errors associated with the prefetch coordination.
+#[derive(Debug)]
+pub enum Error {
+    /// The bandwidth is zero.
+    InvalidBandwidth,
+    /// Failed to open the token file.
+    Open(PathBuf, io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            InvalidBandwidth => write!(f, "The prefetch bandwidth must not be zero"),
+            Open(path, err) => write!(f, "Cannot open {}: {}", path.display(), err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Exclusive `flock` of a file, released when dropped.
+pub(crate) struct FileLock<'a>(&'a File);
+
+impl<'a> FileLock<'a> {
+    /// Waits for an exclusive `flock` of `file`.
+    pub(crate) fn exclusive(file: &'a File) -> io::Result<Self> {
+        // Safe because `flock` does not modify memory.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(FileLock(file))
+    }
+}
+
+impl<'a> Drop for FileLock<'a> {
+    fn drop(&mut self) {
+        // Safe because `flock` does not modify memory.
+        unsafe { libc::flock(self.0.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+/// Token bucket shared by the working set prefetches of the processes using the same file.
+pub struct PrefetchCoordinator {
+    file: File,
+    rate_bytes_per_s: u64,
+    burst_bytes: u64,
+}
+
+impl PrefetchCoordinator {
+    /// Opens the token file of `config`, creating it if missing.
+    pub fn new(config: &PrefetchCoordinatorConfig) -> Result<Self> {
+        if config.bandwidth_mib_s == 0 {
+            return Err(Error::InvalidBandwidth);
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&config.token_file)
+            .map_err(|e| Error::Open(config.token_file.clone(), e))?;
+        let burst_mib = config.burst_mib.unwrap_or(config.bandwidth_mib_s);
+        Ok(PrefetchCoordinator {
+            file,
+            rate_bytes_per_s: config.bandwidth_mib_s << 20,
+            burst_bytes: std::cmp::max(burst_mib, 1) << 20,
+        })
+    }
+
+    /// Takes `bytes` tokens from the bucket, waiting for them to refill if needed. Requests
+    /// larger than the bucket wait for it to be full. Returns the time waited, in microseconds.
+    pub fn acquire(&self, bytes: u64) -> io::Result<u64> {
+        let bytes = min(bytes, self.burst_bytes);
+        let start_us = get_time_us(ClockType::Monotonic);
+        loop {
+            let now_us = get_time_us(ClockType::Monotonic);
+            let wait_us = self.try_acquire(bytes, now_us)?;
+            if wait_us == 0 {
+                return Ok(now_us - start_us);
+            }
+            thread::sleep(Duration::from_micros(min(wait_us, MAX_WAIT_US)));
+            // Waiting for the other restores is not stalling.
+            RESTORE_WATCHDOG.progress();
+        }
+    }
+
+    // Refills the bucket up to `now_us` and takes `bytes` tokens from it if it holds them.
+    // Returns 0 once they are taken, or how long to wait for them otherwise.
+    fn try_acquire(&self, bytes: u64, now_us: u64) -> io::Result<u64> {
+        let _lock = FileLock::exclusive(&self.file)?;
+        let mut state = [0u8; STATE_LEN];
+        let read = self.file.read_at(&mut state, 0)?;
+        let tokens = if read < STATE_LEN {
+            // A new bucket starts full.
+            self.burst_bytes
+        } else {
+            let mut tokens = [0u8; 8];
+            let mut last_us = [0u8; 8];
+            tokens.copy_from_slice(&state[..8]);
+            last_us.copy_from_slice(&state[8..]);
+            let elapsed_us = now_us.saturating_sub(u64::from_le_bytes(last_us));
+            let refill = u128::from(elapsed_us) * u128::from(self.rate_bytes_per_s) / 1_000_000;
+            min(
+                u128::from(u64::from_le_bytes(tokens)) + refill,
+                u128::from(self.burst_bytes),
+            ) as u64
+        };
+
+        let (left, wait_us) = if tokens >= bytes {
+            (tokens - bytes, 0)
+        } else {
+            let missing = u128::from(bytes - tokens) * 1_000_000;
+            let rate = u128::from(self.rate_bytes_per_s);
+            (tokens, ((missing + rate - 1) / rate) as u64)
+        };
+        state[..8].copy_from_slice(&left.to_le_bytes());
+        state[8..].copy_from_slice(&now_us.to_le_bytes());
+        self.file.write_all_at(&state, 0)?;
+        Ok(wait_us)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempfile::TempFile;
+
+    fn config(token_file: &TempFile, bandwidth_mib_s: u64) -> PrefetchCoordinatorConfig {
+        PrefetchCoordinatorConfig {
+            token_file: token_file.as_path().to_path_buf(),
+            bandwidth_mib_s,
+            burst_mib: Some(1),
+        }
+    }
+
+    #[test]
+    fn test_try_acquire() {
+        let token_file = TempFile::new().unwrap();
+        let first = PrefetchCoordinator::new(&config(&token_file, 1)).unwrap();
+        let second = PrefetchCoordinator::new(&config(&token_file, 1)).unwrap();
+
+        // The bucket starts full, and is shared through the file.
+        assert_eq!(first.try_acquire(3 << 18, 1000).unwrap(), 0);
+        assert_eq!(second.try_acquire(1 << 18, 1000).unwrap(), 0);
+        // Empty, it refills by 1 MiB per second.
+        assert_eq!(first.try_acquire(1 << 19, 1000).unwrap(), 500_000);
+        assert_eq!(second.try_acquire(1 << 19, 251_000).unwrap(), 250_000);
+        assert_eq!(first.try_acquire(1 << 19, 501_000).unwrap(), 0);
+        // Up to the burst.
+        assert_eq!(first.try_acquire(1 << 20, 100_000_000).unwrap(), 0);
+        assert_eq!(first.try_acquire(1, 100_000_000).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_acquire() {
+        let token_file = TempFile::new().unwrap();
+        let coordinator = PrefetchCoordinator::new(&config(&token_file, 16)).unwrap();
+        // Larger than the bucket, the request takes it whole.
+        coordinator.acquire(4 << 20).unwrap();
+        let now_us = get_time_us(ClockType::Monotonic);
+        assert!(coordinator.try_acquire(1 << 20, now_us).unwrap() > 0);
+        // The next MiB refills in about 60 milliseconds.
+        assert!(coordinator.acquire(1 << 20).unwrap() > 0);
+    }
+
+    #[test]
+    fn test_new() {
+        let token_file = TempFile::new().unwrap();
+        match PrefetchCoordinator::new(&config(&token_file, 0)) {
+            Err(Error::InvalidBandwidth) => (),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+        let mut bad = config(&token_file, 1);
+        bad.token_file = PathBuf::from("/no/such/dir/tokens");
+        match PrefetchCoordinator::new(&bad) {
+            Err(Error::Open(path, _)) => assert_eq!(path, PathBuf::from("/no/such/dir/tokens")),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+        let err = Error::InvalidBandwidth;
+        let _ = format!("{}{:?}", err, err);
+    }
+}
@@ -0,0 +1,223 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tuning of the working set load to the storage of the snapshot files.
+//!
+//! Local SSDs serve many requests at once at a low latency, spinning disks serve sequential reads
+//! best, and network file systems take a round trip per request. The working set load adapts its
+//! concurrency and the size of the chunks each helper thread loads at once to the device backing
+//! the file it reads, as told by sysfs and by the latency of a probe read.
+
+use std::fs::File;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::PathBuf;
+
+use utils::time::{get_time_us, ClockType};
+
+use crate::dump_copy::split_dev;
+use crate::vmm_config::snapshot::PrefetchConfig;
+use crate::worker_pool::WORKER_COUNT;
+
+// Block devices by major and minor number.
+const SYS_DEV_BLOCK: &str = "/sys/dev/block";
+// Probe reads slower than this come from a remote or a slow device.
+const SLOW_PROBE_US: u64 = 1000;
+
+/// Chunk size, in pages, on rotational devices: the larger, the fewer seeks.
+pub const ROTATIONAL_CHUNK_PAGES: u64 = 1024;
+/// Chunk size, in pages, on slow or remote storage: large enough to amortize the round trips.
+pub const REMOTE_CHUNK_PAGES: u64 = 256;
+/// Chunk size, in pages, on local solid state devices.
+pub const SOLID_STATE_CHUNK_PAGES: u64 = 64;
+
+/// Characteristics of the storage backing a snapshot file. Each is `None` when unknown.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StorageProfile {
+    /// Whether the block device is rotational. Unknown on file systems without one, such as
+    /// network file systems.
+    pub rotational: Option<bool>,
+    /// Number of requests the block device queues.
+    pub nr_requests: Option<u64>,
+    /// Latency of a page read at the probed offset, in microseconds.
+    pub probe_latency_us: Option<u64>,
+}
+
+impl StorageProfile {
+    /// Detects the storage of `file`, timing a page read at `probe_offset`.
+    pub fn detect(file: &File, probe_offset: u64) -> Self {
+        let queue = file
+            .metadata()
+            .ok()
+            .and_then(|metadata| queue_path(metadata.dev()));
+        let attr = |name: &str| {
+            queue
+                .as_ref()
+                .and_then(|queue| std::fs::read_to_string(queue.join(name)).ok())
+                .and_then(|value| value.trim().parse::<u64>().ok())
+        };
+        StorageProfile {
+            rotational: attr("rotational").map(|rotational| rotational != 0),
+            nr_requests: attr("nr_requests"),
+            probe_latency_us: probe_latency(file, probe_offset),
+        }
+    }
+}
+
+/// Concurrency and chunk size of a working set load.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PrefetchTuning {
+    /// Number of helper threads loading the working set.
+    pub concurrency: usize,
+    /// Largest number of pages a thread loads at once.
+    pub chunk_pages: u64,
+}
+
+impl Default for PrefetchTuning {
+    /// Loads on a single thread, one extent at a time.
+    fn default() -> Self {
+        PrefetchTuning {
+            concurrency: 1,
+            chunk_pages: u64::max_value(),
+        }
+    }
+}
+
+impl PrefetchTuning {
+    /// Selects the tuning suited to `profile`, with the settings of `config` taking precedence.
+    pub fn new(profile: &StorageProfile, config: Option<&PrefetchConfig>) -> Self {
+        let slow = profile
+            .probe_latency_us
+            .map_or(false, |latency_us| latency_us >= SLOW_PROBE_US);
+        let (concurrency, chunk_pages) = match profile.rotational {
+            // Concurrent reads make a disk seek back and forth.
+            Some(true) => (1, ROTATIONAL_CHUNK_PAGES),
+            Some(false) if !slow => {
+                let queued = profile.nr_requests.unwrap_or(WORKER_COUNT as u64) as usize;
+                (queued, SOLID_STATE_CHUNK_PAGES)
+            }
+            // Without a local block device, or on a slow one, the latency is hidden behind
+            // concurrent and larger requests.
+            _ => (WORKER_COUNT, REMOTE_CHUNK_PAGES),
+        };
+        let concurrency = config
+            .and_then(|config| config.concurrency)
+            .unwrap_or(concurrency);
+        let chunk_pages = config
+            .and_then(|config| config.chunk_pages)
+            .unwrap_or(chunk_pages);
+        PrefetchTuning {
+            concurrency: std::cmp::min(std::cmp::max(concurrency, 1), WORKER_COUNT),
+            chunk_pages: std::cmp::max(chunk_pages, 1),
+        }
+    }
+}
+
+// Returns the sysfs queue directory of the block device `dev`, or of the disk holding it if it
+// is a partition.
+fn queue_path(dev: u64) -> Option<PathBuf> {
+    let (major, minor) = split_dev(dev);
+    let device = PathBuf::from(format!("{}/{}:{}", SYS_DEV_BLOCK, major, minor));
+    [device.join("queue"), device.join("../queue")]
+        .iter()
+        .find(|queue| queue.is_dir())
+        .cloned()
+}
+
+// Returns the time to read a page of `file` at `offset`, in microseconds.
+fn probe_latency(file: &File, offset: u64) -> Option<u64> {
+    let mut page = vec![0u8; sysconf::page::pagesize()];
+    let start_us = get_time_us(ClockType::Monotonic);
+    file.read_at(&mut page, offset).ok()?;
+    Some(get_time_us(ClockType::Monotonic) - start_us)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_detect() {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(4096).unwrap();
+        let profile = StorageProfile::detect(file.as_file(), 0);
+        assert!(profile.probe_latency_us.is_some());
+        // Reads past the end of the file still time the request.
+        assert!(StorageProfile::detect(file.as_file(), 1 << 20)
+            .probe_latency_us
+            .is_some());
+    }
+
+    #[test]
+    fn test_tuning() {
+        let profile = StorageProfile {
+            rotational: Some(true),
+            nr_requests: Some(64),
+            probe_latency_us: Some(5000),
+        };
+        assert_eq!(
+            PrefetchTuning::new(&profile, None),
+            PrefetchTuning {
+                concurrency: 1,
+                chunk_pages: ROTATIONAL_CHUNK_PAGES,
+            }
+        );
+
+        let profile = StorageProfile {
+            rotational: Some(false),
+            nr_requests: Some(2),
+            probe_latency_us: Some(100),
+        };
+        assert_eq!(
+            PrefetchTuning::new(&profile, None),
+            PrefetchTuning {
+                concurrency: 2,
+                chunk_pages: SOLID_STATE_CHUNK_PAGES,
+            }
+        );
+        // A slow probe read points at a remote or busy device.
+        let profile = StorageProfile {
+            probe_latency_us: Some(SLOW_PROBE_US),
+            ..profile
+        };
+        assert_eq!(
+            PrefetchTuning::new(&profile, None),
+            PrefetchTuning {
+                concurrency: WORKER_COUNT,
+                chunk_pages: REMOTE_CHUNK_PAGES,
+            }
+        );
+        assert_eq!(
+            PrefetchTuning::new(&StorageProfile::default(), None),
+            PrefetchTuning {
+                concurrency: WORKER_COUNT,
+                chunk_pages: REMOTE_CHUNK_PAGES,
+            }
+        );
+
+        // The settings of the API take precedence, within the helper threads available.
+        let config = PrefetchConfig {
+            concurrency: Some(2 * WORKER_COUNT),
+            chunk_pages: Some(0),
+        };
+        assert_eq!(
+            PrefetchTuning::new(&StorageProfile::default(), Some(&config)),
+            PrefetchTuning {
+                concurrency: WORKER_COUNT,
+                chunk_pages: 1,
+            }
+        );
+        let config = PrefetchConfig {
+            concurrency: None,
+            chunk_pages: Some(16),
+        };
+        assert_eq!(
+            PrefetchTuning::new(&StorageProfile::default(), Some(&config)),
+            PrefetchTuning {
+                concurrency: WORKER_COUNT,
+                chunk_pages: 16,
+            }
+        );
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Static probe points of the snapshot restore and fault paths.
+//!
+//! Each probe is an exported function which is never inlined nor optimized out, so its symbol
+//! stays in the release binaries and uprobe based tools such as `bpftrace` and `perf probe` can
+//! attach to it without rebuilding Firecracker. The probe arguments are the function arguments,
+//! `arg0`, `arg1` and so on in `bpftrace`. A probe costs a function call when nothing is
+//! attached.
+//!
+//! ```text
+//! bpftrace -e 'uprobe:/usr/bin/firecracker:fc_probe_fault_service { @us = hist(arg1 / 1000); }'
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Memory layer mapped by `fc_probe_mmap`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u32)]
+pub enum MmapLayer {
+    /// The memory file, or anonymous memory.
+    Base = 0,
+    /// An overlay extent.
+    Overlay = 1,
+    /// A working set extent.
+    WorkingSet = 2,
+}
+
+impl Display for MmapLayer {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            MmapLayer::Base => write!(f, "memory file"),
+            MmapLayer::Overlay => write!(f, "overlay"),
+            MmapLayer::WorkingSet => write!(f, "working set"),
+        }
+    }
+}
+
+// Keeps the probe calls from being optimized out, since the compiler cannot assume anything about
+// an atomic access.
+static PROBE_HITS: AtomicU64 = AtomicU64::new(0);
+
+#[inline(never)]
+fn hit() {
+    PROBE_HITS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Fires after `len` bytes at `mem_offset` in the memory file layout are mapped as `layer`.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn fc_probe_mmap(layer: MmapLayer, mem_offset: u64, len: u64) {
+    let _ = (layer, mem_offset, len);
+    hit();
+}
+
+/// Fires before the working set extent of `len` bytes at `mem_offset` is prefetched.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn fc_probe_prefetch_extent_start(mem_offset: u64, len: u64) {
+    let _ = (mem_offset, len);
+    hit();
+}
+
+/// Fires after the working set extent of `len` bytes at `mem_offset` is prefetched.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn fc_probe_prefetch_extent_end(mem_offset: u64, len: u64) {
+    let _ = (mem_offset, len);
+    hit();
+}
+
+/// Fires after the fault on the guest page at `guest_addr` is serviced, which took
+/// `duration_ns`.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn fc_probe_fault_service(guest_addr: u64, duration_ns: u64) {
+    let _ = (guest_addr, duration_ns);
+    hit();
+}
+
+/// Fires before the vCPUs are resumed.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn fc_probe_resume_start() {
+    hit();
+}
+
+/// Fires after the vCPUs are resumed, `ok` is false if resuming them failed.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn fc_probe_resume_end(ok: bool) {
+    let _ = ok;
+    hit();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probes() {
+        let hits = PROBE_HITS.load(Ordering::Relaxed);
+        fc_probe_mmap(MmapLayer::Base, 0, 0x1000);
+        fc_probe_prefetch_extent_start(0, 0x1000);
+        fc_probe_fault_service(0x1000, 42);
+        fc_probe_prefetch_extent_end(0, 0x1000);
+        fc_probe_resume_start();
+        fc_probe_resume_end(true);
+        // Other tests may fire probes concurrently.
+        assert!(PROBE_HITS.load(Ordering::Relaxed) >= hits + 6);
+    }
+}
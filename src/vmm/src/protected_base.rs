@@ -0,0 +1,330 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Restore keeping the memory file out of reach of the guest writes.
+//!
+//! A private mapping of the memory file is writable, so nothing but the copy-on-write of the
+//! kernel stands between a guest write and the golden snapshot file. With `protect_base`, the
+//! memory file is only ever mapped read-only and shared, from a descriptor opened read-only,
+//! which no later `mprotect` can make writable. The guest memory is anonymous instead, registered
+//! with a userfaultfd owned by the `fc_base_pager` thread wherever the overlay and working set
+//! layers do not map their own files. The thread services every fault by copying the page from
+//! the read-only mapping. It holds on to the guest memory, and is stopped and joined when its
+//! `ProtectedBase` handle is dropped, along with the microVM or on a failed restore.
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::channel;
+use std::thread::{self, JoinHandle};
+
+use logger::{error, Metric, METRICS};
+use seccomp::{BpfProgram, SeccompFilter};
+use userfaultfd::{Event, Uffd, UffdBuilder};
+use utils::eventfd::EventFd;
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
+
+use crate::backing_files::BACKING_FILES;
+use crate::fault_sources::{self, FaultSource};
+use crate::fault_trace::wait_readable;
+use crate::memory_snapshot::GuestMemoryState;
+use crate::probes::MmapLayer;
+
+/// Errors associated with the write-protected base layer.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create the userfaultfd.
+    CreateUffd(userfaultfd::Error),
+    /// Failed to create the event stopping the base pager thread.
+    EventFd(io::Error),
+    /// Failed to map the memory file read-only.
+    Map(io::Error),
+    /// The memory file is required to service the faults.
+    MissingMemoryFile,
+    /// Failed to register the guest memory with the userfaultfd.
+    Register(userfaultfd::Error),
+    /// Failed to install the seccomp filter of the base pager thread.
+    Seccomp(seccomp::Error),
+    /// Failed to spawn the base pager thread.
+    Spawn(io::Error),
+    /// The guest memory faults are already handled by a page fault handler or the fault trace.
+    UnsupportedFaultHandler,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            CreateUffd(err) => write!(f, "Cannot create the base pager userfaultfd: {}", err),
+            EventFd(err) => write!(f, "Cannot create the base pager stop event: {}", err),
+            Map(err) => write!(f, "Cannot map the memory file read-only: {}", err),
+            MissingMemoryFile => write!(f, "Protecting the base layer requires the memory file"),
+            Register(err) => write!(
+                f,
+                "Cannot register guest memory for the base pager: {}",
+                err
+            ),
+            Seccomp(err) => write!(f, "Cannot filter the base pager thread syscalls: {}", err),
+            Spawn(err) => write!(f, "Cannot spawn the base pager thread: {}", err),
+            UnsupportedFaultHandler => write!(
+                f,
+                "Protecting the base layer is incompatible with user page faults and fault traces"
+            ),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Returns the parts of `len` bytes at `host_addr` outside of the `mapped` ranges, each given as
+/// its host address and length.
+pub fn gaps(host_addr: usize, len: usize, mapped: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    let end = host_addr + len;
+    let mut mapped: Vec<_> = mapped
+        .iter()
+        .filter(|&&(addr, len)| addr < end && addr + len > host_addr)
+        .map(|&(addr, len)| (addr.max(host_addr), (addr + len).min(end)))
+        .collect();
+    mapped.sort();
+
+    let mut gaps = Vec::new();
+    let mut cur = host_addr;
+    for (start, stop) in mapped {
+        if start > cur {
+            gaps.push((cur, start - cur));
+        }
+        cur = cur.max(stop);
+    }
+    if cur < end {
+        gaps.push((cur, end - cur));
+    }
+    gaps
+}
+
+// Guest memory region, along with the read-only mapping of its pages in the memory file.
+struct RegionMapping {
+    host_addr: usize,
+    len: usize,
+    base_addr: usize,
+}
+
+/// Handle to the `fc_base_pager` thread, which stops and joins it when dropped.
+pub struct ProtectedBase {
+    stop: EventFd,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for ProtectedBase {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop.write(1) {
+            error!("Cannot stop the base pager thread: {}", e);
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("The base pager thread panicked");
+            }
+        }
+    }
+}
+
+struct BasePager {
+    // Keeps the guest memory mapped for as long as the faults are serviced.
+    _guest_memory: GuestMemoryMmap,
+    uffd: Uffd,
+    stop: EventFd,
+    regions: Vec<RegionMapping>,
+    page_size: usize,
+}
+
+impl Drop for BasePager {
+    fn drop(&mut self) {
+        for region in self.regions.iter() {
+            // Safe because the mapping is owned by the pager, and no longer read.
+            unsafe { libc::munmap(region.base_addr as *mut libc::c_void, region.len) };
+        }
+    }
+}
+
+impl BasePager {
+    fn run(&mut self) {
+        loop {
+            match wait_readable(self.uffd.as_raw_fd(), &self.stop) {
+                Ok(true) => (),
+                Ok(false) => return,
+                Err(err) => {
+                    error!("Cannot wait for the guest page faults: {}", err);
+                    return;
+                }
+            }
+            match self.uffd.read_event() {
+                Ok(Some(Event::Pagefault { addr, .. })) => self.service(addr as usize),
+                // Only page faults are requested.
+                Ok(_) => (),
+                Err(err) => {
+                    error!("Cannot read the base pager userfaultfd: {}", err);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn service(&mut self, addr: usize) {
+        let page_addr = addr & !(self.page_size - 1);
+        let region = match self.regions.iter().find(|region| {
+            page_addr >= region.host_addr && page_addr < region.host_addr + region.len
+        }) {
+            Some(region) => region,
+            None => {
+                error!("Fault at {:#x} outside of the guest memory", addr);
+                return;
+            }
+        };
+        let src = region.base_addr + (page_addr - region.host_addr);
+        // Safe because `src` is a page of the read-only mapping and `page_addr` a registered
+        // page.
+        let copied = unsafe {
+            self.uffd
+                .copy(src as _, page_addr as _, self.page_size, true)
+                .map(|_| ())
+        };
+        match copied {
+            Ok(()) => {
+                METRICS.snapshot.base_pages_copied.inc();
+                fault_sources::record(FaultSource::Base, 1, self.page_size as u64);
+            }
+            Err(err) => {
+                // The page may have been populated by a concurrent fault on the same address.
+                error!("Cannot populate page at {:#x}: {}", page_addr, err);
+                if let Err(err) = self.uffd.wake(page_addr as _, self.page_size) {
+                    error!("Cannot wake the faulting thread: {}", err);
+                }
+            }
+        }
+    }
+}
+
+// Maps `len` bytes of `file` from `offset` on, read-only.
+fn map_read_only(file: &File, offset: u64, len: usize) -> Result<usize> {
+    // Safe because the mapping is fresh, and its result checked.
+    let addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_SHARED | libc::MAP_NORESERVE,
+            file.as_raw_fd(),
+            offset as libc::off_t,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(Error::Map(io::Error::last_os_error()));
+    }
+    Ok(addr as usize)
+}
+
+/// Registers the parts of `guest_memory`, anonymous memory restored from `state`, outside of the
+/// `mapped` layer ranges with a userfaultfd, and services their faults from a read-only mapping
+/// of `mem_file` until the returned handle is dropped. The pager thread runs under
+/// `seccomp_filter`.
+pub fn start(
+    guest_memory: &GuestMemoryMmap,
+    state: &GuestMemoryState,
+    mem_file: File,
+    mapped: &[(usize, usize)],
+    seccomp_filter: BpfProgram,
+) -> Result<ProtectedBase> {
+    let page_size = sysconf::page::pagesize();
+    let uffd = UffdBuilder::new()
+        .close_on_exec(true)
+        .create()
+        .map_err(Error::CreateUffd)?;
+
+    let stop = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
+    // Unmaps the read-only mappings made so far when the start fails.
+    let mut pager = BasePager {
+        _guest_memory: guest_memory.clone(),
+        uffd,
+        stop: stop.try_clone().map_err(Error::EventFd)?,
+        regions: Vec::with_capacity(state.regions.len()),
+        page_size,
+    };
+    for region in state.regions.iter() {
+        let host_addr = guest_memory
+            .get_host_address(GuestAddress(region.base_address))
+            .expect("Guest memory restored from this state") as usize;
+        let base_addr = map_read_only(&mem_file, region.offset, region.size)?;
+        pager.regions.push(RegionMapping {
+            host_addr,
+            len: region.size,
+            base_addr,
+        });
+        for (addr, len) in gaps(host_addr, region.size, mapped) {
+            pager
+                .uffd
+                .register(addr as _, len)
+                .map_err(Error::Register)?;
+        }
+    }
+
+    // The pager thread reads the memory file through the read-only mappings.
+    let mut backing = BACKING_FILES.lock().expect("Poisoned lock");
+    let file = backing
+        .add_file(&mem_file, MmapLayer::Base)
+        .map_err(Error::Map)?;
+    for (index, (region, mapping)) in state.regions.iter().zip(pager.regions.iter()).enumerate() {
+        backing.add(
+            mapping.base_addr,
+            mapping.len,
+            MmapLayer::Base,
+            file,
+            index,
+            region.offset,
+        );
+    }
+    drop(backing);
+
+    let (ready_sender, ready) = channel();
+    let thread = thread::Builder::new()
+        .name("fc_base_pager".to_owned())
+        .spawn(move || {
+            let filtered = SeccompFilter::apply(seccomp_filter);
+            let failed = filtered.is_err();
+            let _ = ready_sender.send(filtered);
+            if !failed {
+                pager.run();
+            }
+        })
+        .map_err(Error::Spawn)?;
+    ready
+        .recv()
+        .expect("The base pager thread exited")
+        .map_err(Error::Seccomp)?;
+    Ok(ProtectedBase {
+        stop,
+        thread: Some(thread),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gaps() {
+        assert_eq!(gaps(0x10000, 0x8000, &[]), vec![(0x10000, 0x8000)]);
+        // The layers mapped outside of the region are ignored, and the overlapping ones clipped.
+        let mapped = [
+            (0x4000, 0x1000),
+            (0x11000, 0x2000),
+            (0x12000, 0x2000),
+            (0x16000, 0x4000),
+        ];
+        assert_eq!(
+            gaps(0x10000, 0x8000, &mapped),
+            vec![(0x10000, 0x1000), (0x14000, 0x2000)]
+        );
+        assert_eq!(gaps(0x10000, 0x1000, &[(0xf000, 0x3000)]), vec![]);
+    }
+}
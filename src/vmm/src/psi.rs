@@ -0,0 +1,261 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Throttling of the working set prefetch by the pressure stall information (PSI) of the host.
+//!
+//! The kernel accumulates the time some tasks stall on memory and on IO in the `total` field of
+//! the PSI files. The growth of the totals between two checks, over the time elapsed, is the share
+//! of the time spent stalled, which follows bursts much closer than the 10 second averages.
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use logger::{error, warn, Metric, METRICS};
+use utils::time::{get_time_us, ClockType};
+
+use crate::restore_watchdog::RESTORE_WATCHDOG;
+use crate::vmm_config::snapshot::PrefetchThrottleConfig;
+
+/// Errors associated with the pressure stall information.
+#[derive(Debug)]
+pub enum Error {
+    /// The stall threshold is not a percentage.
+    InvalidThreshold(u8),
+    /// Failed to open a PSI file.
+    Open(PathBuf, io::Error),
+    /// A PSI file has no `some` total.
+    Parse(PathBuf),
+    /// Failed to read a PSI file.
+    Read(PathBuf, io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            InvalidThreshold(percent) => write!(
+                f,
+                "The stall threshold must be between 1 and 100, not {}",
+                percent
+            ),
+            Open(path, err) => write!(f, "Cannot open {}: {}", path.display(), err),
+            Parse(path) => write!(f, "No stall total in {}", path.display()),
+            Read(path, err) => write!(f, "Cannot read {}: {}", path.display(), err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+// Returns the `total` field, in microseconds, of the `some` line of a PSI file.
+fn parse_some_total(contents: &str) -> Option<u64> {
+    contents
+        .lines()
+        .find(|line| line.starts_with("some "))?
+        .split_whitespace()
+        .find(|field| field.starts_with("total="))
+        .and_then(|field| field["total=".len()..].parse().ok())
+}
+
+struct PressureFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl PressureFile {
+    fn open(path: &Path) -> Result<Self> {
+        Ok(PressureFile {
+            path: path.to_path_buf(),
+            file: File::open(path).map_err(|e| Error::Open(path.to_path_buf(), e))?,
+        })
+    }
+
+    fn some_total(&mut self) -> Result<u64> {
+        let mut contents = String::new();
+        self.file
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| self.file.read_to_string(&mut contents))
+            .map_err(|e| Error::Read(self.path.clone(), e))?;
+        parse_some_total(&contents).ok_or_else(|| Error::Parse(self.path.clone()))
+    }
+}
+
+/// Pauses the working set prefetch while the host memory or IO pressure is above a threshold.
+pub struct PrefetchThrottle {
+    files: Vec<PressureFile>,
+    totals: Vec<u64>,
+    stall_percent: u64,
+    check_interval_us: u64,
+    max_pause_us: u64,
+    paused_us: u64,
+    last_check_us: u64,
+}
+
+impl PrefetchThrottle {
+    /// Opens the PSI files of `config`, and takes the first pressure sample.
+    pub fn new(config: &PrefetchThrottleConfig) -> Result<Self> {
+        if config.stall_percent == 0 || config.stall_percent > 100 {
+            return Err(Error::InvalidThreshold(config.stall_percent));
+        }
+        let mut files = vec![
+            PressureFile::open(&config.memory_pressure_path)?,
+            PressureFile::open(&config.io_pressure_path)?,
+        ];
+        let totals = files
+            .iter_mut()
+            .map(PressureFile::some_total)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(PrefetchThrottle {
+            files,
+            totals,
+            stall_percent: u64::from(config.stall_percent),
+            check_interval_us: config.check_interval_ms * 1000,
+            max_pause_us: config.max_pause_ms * 1000,
+            paused_us: 0,
+            last_check_us: get_time_us(ClockType::Monotonic),
+        })
+    }
+
+    /// Checks the pressure, once per check interval, and pauses while it stays above the
+    /// threshold. Called between the pages of the prefetch.
+    pub fn throttle(&mut self) {
+        let mut now_us = get_time_us(ClockType::Monotonic);
+        if now_us.saturating_sub(self.last_check_us) < self.check_interval_us {
+            return;
+        }
+        let mut paused = false;
+        while self.sample(now_us) {
+            if self.paused_us >= self.max_pause_us {
+                warn!(
+                    "The working set load paused for {} us under host pressure, going on.",
+                    self.paused_us
+                );
+                // No more checks for the rest of the load.
+                self.files.clear();
+                return;
+            }
+            if !paused {
+                METRICS.snapshot.ws_prefetch_throttles.inc();
+                paused = true;
+            }
+            thread::sleep(Duration::from_micros(self.check_interval_us));
+            let resumed_us = get_time_us(ClockType::Monotonic);
+            let pause_us = resumed_us - now_us;
+            self.paused_us += pause_us;
+            METRICS
+                .snapshot
+                .ws_prefetch_throttled_us
+                .add(pause_us as usize);
+            // Pausing is not stalling.
+            RESTORE_WATCHDOG.progress();
+            now_us = resumed_us;
+        }
+    }
+
+    // Samples the pressure at `now_us`. Returns true if some tasks stalled on memory or on IO
+    // for more than the threshold since the last sample.
+    fn sample(&mut self, now_us: u64) -> bool {
+        let elapsed_us = now_us.saturating_sub(self.last_check_us);
+        self.last_check_us = now_us;
+        let mut stalled = false;
+        for index in 0..self.files.len() {
+            let current = match self.files[index].some_total() {
+                Ok(current) => current,
+                Err(err) => {
+                    error!("Cannot check the host pressure: {}", err);
+                    self.files.clear();
+                    return false;
+                }
+            };
+            let stall_us = current.saturating_sub(self.totals[index]);
+            stalled |= stall_us * 100 > elapsed_us * self.stall_percent;
+            self.totals[index] = current;
+        }
+        stalled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use utils::tempfile::TempFile;
+
+    fn psi(some_total: u64) -> String {
+        format!(
+            "some avg10=1.00 avg60=0.50 avg300=0.10 total={}\n\
+             full avg10=0.00 avg60=0.00 avg300=0.00 total=10\n",
+            some_total
+        )
+    }
+
+    fn write_psi(file: &TempFile, some_total: u64) {
+        let mut file = File::create(file.as_path()).unwrap();
+        file.write_all(psi(some_total).as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_parse_some_total() {
+        assert_eq!(parse_some_total(&psi(1234)), Some(1234));
+        assert_eq!(parse_some_total("some avg10=0.00\n"), None);
+        assert_eq!(parse_some_total("full avg10=0.00 total=3\n"), None);
+    }
+
+    #[test]
+    fn test_throttle() {
+        let memory = TempFile::new().unwrap();
+        let io = TempFile::new().unwrap();
+        write_psi(&memory, 1000);
+        write_psi(&io, 1000);
+        let config = PrefetchThrottleConfig {
+            stall_percent: 20,
+            check_interval_ms: 1,
+            max_pause_ms: 5,
+            memory_pressure_path: memory.as_path().to_path_buf(),
+            io_pressure_path: io.as_path().to_path_buf(),
+        };
+        let mut throttle = PrefetchThrottle::new(&config).unwrap();
+        let start_us = throttle.last_check_us;
+
+        // Memory stalls during half of the 10 ms since the first sample.
+        write_psi(&memory, 6000);
+        assert!(throttle.sample(start_us + 10_000));
+        // Then IO stalls during 10% of the next 10 ms.
+        write_psi(&io, 2000);
+        assert!(!throttle.sample(start_us + 20_000));
+
+        // The load pauses while the pressure is high.
+        throttle.last_check_us = 0;
+        write_psi(&memory, 1 << 50);
+        throttle.throttle();
+        assert!(throttle.paused_us >= 1000);
+        assert!(!throttle.files.is_empty());
+
+        // It goes on once the pauses reach their limit, and stops checking.
+        throttle.max_pause_us = throttle.paused_us;
+        throttle.last_check_us = 0;
+        write_psi(&memory, 1 << 51);
+        throttle.throttle();
+        assert!(throttle.files.is_empty());
+        assert!(!throttle.sample(get_time_us(ClockType::Monotonic)));
+
+        let mut bad = config.clone();
+        bad.stall_percent = 0;
+        match PrefetchThrottle::new(&bad) {
+            Err(Error::InvalidThreshold(0)) => (),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+        let mut bad = config;
+        bad.io_pressure_path = PathBuf::from("/no/such/pressure");
+        match PrefetchThrottle::new(&bad) {
+            Err(Error::Open(path, _)) => assert_eq!(path, PathBuf::from("/no/such/pressure")),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+    }
+}
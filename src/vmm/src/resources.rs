@@ -15,6 +15,7 @@ use crate::vmm_config::machine_config::{VmConfig, VmConfigError};
 use crate::vmm_config::metrics::{init_metrics, MetricsConfig, MetricsConfigError};
 use crate::vmm_config::mmds::{MmdsConfig, MmdsConfigError};
 use crate::vmm_config::net::*;
+use crate::vmm_config::snapshot::LoadSnapshotParams;
 use crate::vmm_config::vsock::*;
 use crate::vstate::VcpuConfig;
 use mmds::ns::MmdsNetworkStack;
@@ -45,14 +46,18 @@ pub enum Error {
     VsockDevice(VsockConfigError),
     /// MMDS configuration error.
     MmdsConfig(MmdsConfigError),
+    /// Neither a boot source nor a snapshot to load is configured.
+    MissingBootSource,
+    /// A snapshot to load is configured along with resources the snapshot already describes.
+    SnapshotLoadWithResources,
 }
 
 /// Used for configuring a vmm from one single json passed to the Firecracker process.
 #[derive(Deserialize)]
 pub struct VmmConfig {
     #[serde(rename = "boot-source")]
-    boot_source: BootSourceConfig,
-    #[serde(rename = "drives")]
+    boot_source: Option<BootSourceConfig>,
+    #[serde(rename = "drives", default)]
     block_devices: Vec<BlockDeviceConfig>,
     #[serde(rename = "network-interfaces", default)]
     net_devices: Vec<NetworkInterfaceConfig>,
@@ -66,6 +71,8 @@ pub struct VmmConfig {
     vsock_device: Option<VsockDeviceConfig>,
     #[serde(rename = "mmds-config")]
     mmds_config: Option<MmdsConfig>,
+    #[serde(rename = "snapshot-load")]
+    snapshot_load: Option<LoadSnapshotParams>,
 }
 
 /// A data structure that encapsulates the device configurations
@@ -88,13 +95,28 @@ pub struct VmResources {
 
 impl VmResources {
     /// Configures Vmm resources as described by the `config_json` param.
+    ///
+    /// Returns the snapshot load parameters instead when the JSON has a `snapshot-load` section.
+    /// The restored microVM gets its resources from the snapshot, so only the logger and the
+    /// metrics system can be configured along with it.
     pub fn from_json(
         config_json: &str,
         instance_info: &InstanceInfo,
-    ) -> std::result::Result<Self, Error> {
+    ) -> std::result::Result<(Self, Option<LoadSnapshotParams>), Error> {
         let vmm_config: VmmConfig = serde_json::from_slice::<VmmConfig>(config_json.as_bytes())
             .map_err(|_| Error::InvalidJson)?;
 
+        if vmm_config.snapshot_load.is_some()
+            && (vmm_config.boot_source.is_some()
+                || !vmm_config.block_devices.is_empty()
+                || !vmm_config.net_devices.is_empty()
+                || vmm_config.machine_config.is_some()
+                || vmm_config.vsock_device.is_some()
+                || vmm_config.mmds_config.is_some())
+        {
+            return Err(Error::SnapshotLoadWithResources);
+        }
+
         if let Some(logger) = vmm_config.logger {
             init_logger(logger, instance_info).map_err(Error::Logger)?;
         }
@@ -104,6 +126,10 @@ impl VmResources {
         }
 
         let mut resources: Self = Self::default();
+        if vmm_config.snapshot_load.is_some() {
+            return Ok((resources, vmm_config.snapshot_load));
+        }
+
         if let Some(machine_config) = vmm_config.machine_config {
             resources
                 .set_vm_config(&machine_config)
@@ -111,7 +137,7 @@ impl VmResources {
         }
 
         resources
-            .set_boot_source(vmm_config.boot_source)
+            .set_boot_source(vmm_config.boot_source.ok_or(Error::MissingBootSource)?)
             .map_err(Error::BootSource)?;
 
         for drive_config in vmm_config.block_devices.into_iter() {
@@ -138,7 +164,7 @@ impl VmResources {
                 .map_err(Error::MmdsConfig)?;
         }
 
-        Ok(resources)
+        Ok((resources, None))
     }
 
     /// Returns a VcpuConfig based on the vm config.
@@ -149,6 +175,12 @@ impl VmResources {
             vcpu_count: self.vm_config().vcpu_count.unwrap(),
             ht_enabled: self.vm_config().ht_enabled.unwrap(),
             cpu_template: self.vm_config().cpu_template,
+            hyperv: self.vm_config().hyperv,
+            sockets: self
+                .vm_config()
+                .cpu_topology
+                .map_or(1, |topology| topology.sockets),
+            nested_virt: self.vm_config().nested_virt.unwrap_or(false),
         }
     }
 
@@ -172,14 +204,27 @@ impl VmResources {
             return Err(VmConfigError::InvalidMemorySize);
         }
 
-        let ht_enabled = machine_config
-            .ht_enabled
-            .unwrap_or_else(|| self.vm_config.ht_enabled.unwrap());
+        // A new topology defines whether hyperthreading is enabled, unless that is also set.
+        let ht_enabled = machine_config.ht_enabled.unwrap_or_else(|| {
+            machine_config
+                .cpu_topology
+                .map_or(self.vm_config.ht_enabled.unwrap(), |topology| {
+                    topology.threads_per_core > 1
+                })
+        });
 
         let vcpu_count_value = machine_config
             .vcpu_count
             .unwrap_or_else(|| self.vm_config.vcpu_count.unwrap());
 
+        let cpu_topology = machine_config.cpu_topology.or(self.vm_config.cpu_topology);
+        if let Some(topology) = cpu_topology {
+            topology.validate(vcpu_count_value)?;
+            if ht_enabled != (topology.threads_per_core > 1) {
+                return Err(VmConfigError::InvalidCpuTopology);
+            }
+        }
+
         // If hyperthreading is enabled or is to be enabled in this call
         // only allow vcpu count to be 1 or even.
         if ht_enabled && vcpu_count_value > 1 && vcpu_count_value % 2 == 1 {
@@ -189,6 +234,7 @@ impl VmResources {
         // Update all the fields that have a new value.
         self.vm_config.vcpu_count = Some(vcpu_count_value);
         self.vm_config.ht_enabled = Some(ht_enabled);
+        self.vm_config.cpu_topology = cpu_topology;
         self.vm_config.track_dirty_pages = machine_config.track_dirty_pages;
 
         if machine_config.mem_size_mib.is_some() {
@@ -199,6 +245,14 @@ impl VmResources {
             self.vm_config.cpu_template = machine_config.cpu_template;
         }
 
+        if machine_config.hyperv.is_some() {
+            self.vm_config.hyperv = machine_config.hyperv;
+        }
+
+        if machine_config.nested_virt.is_some() {
+            self.vm_config.nested_virt = machine_config.nested_virt;
+        }
+
         Ok(())
     }
 
@@ -298,12 +352,15 @@ impl VmResources {
 mod tests {
     use std::fs::File;
     use std::os::linux::fs::MetadataExt;
+    use std::path::PathBuf;
 
     use super::*;
     use crate::resources::VmResources;
     use crate::vmm_config::boot_source::{BootConfig, BootSourceConfig, DEFAULT_KERNEL_CMDLINE};
     use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig};
-    use crate::vmm_config::machine_config::{CpuFeaturesTemplate, VmConfig, VmConfigError};
+    use crate::vmm_config::machine_config::{
+        CpuFeaturesTemplate, CpuTopology, VmConfig, VmConfigError,
+    };
     use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
     use crate::vmm_config::vsock::tests::default_config;
     use crate::vmm_config::RateLimiterConfig;
@@ -697,6 +754,44 @@ mod tests {
             rootfs_file.as_path().to_str().unwrap(),
         );
         assert!(VmResources::from_json(json.as_str(), &default_instance_info).is_ok());
+
+        // Neither a boot source nor a snapshot to load.
+        json = r#"{ "drives": [] }"#.to_string();
+        match VmResources::from_json(json.as_str(), &default_instance_info) {
+            Err(Error::MissingBootSource) => (),
+            _ => unreachable!(),
+        }
+
+        // A snapshot to load, whose resources cannot be configured.
+        json = r#"{
+                    "snapshot-load": {
+                        "snapshot_path": "snapshot_file",
+                        "mem_file_path": "mem_file"
+                    }
+            }"#
+        .to_string();
+        let (_, load_params) =
+            VmResources::from_json(json.as_str(), &default_instance_info).unwrap();
+        let load_params = load_params.unwrap();
+        assert_eq!(load_params.snapshot_path, PathBuf::from("snapshot_file"));
+        assert_eq!(load_params.mem_file_path, PathBuf::from("mem_file"));
+
+        json = r#"{
+                    "machine-config": {
+                        "vcpu_count": 2,
+                        "mem_size_mib": 1024,
+                        "ht_enabled": false
+                    },
+                    "snapshot-load": {
+                        "snapshot_path": "snapshot_file",
+                        "mem_file_path": "mem_file"
+                    }
+            }"#
+        .to_string();
+        match VmResources::from_json(json.as_str(), &default_instance_info) {
+            Err(Error::SnapshotLoadWithResources) => (),
+            _ => unreachable!(),
+        }
     }
 
     #[test]
@@ -706,6 +801,9 @@ mod tests {
             vcpu_count: vm_resources.vm_config().vcpu_count.unwrap(),
             ht_enabled: vm_resources.vm_config().ht_enabled.unwrap(),
             cpu_template: vm_resources.vm_config().cpu_template,
+            hyperv: vm_resources.vm_config().hyperv,
+            sockets: 1,
+            nested_virt: vm_resources.vm_config().nested_virt.unwrap(),
         };
 
         let vcpu_config = vm_resources.vcpu_config();
@@ -728,7 +826,10 @@ mod tests {
             mem_size_mib: Some(512),
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            hyperv: None,
+            cpu_topology: None,
             track_dirty_pages: false,
+            nested_virt: Some(false),
         };
 
         assert_ne!(vm_resources.vm_config, aux_vm_config);
@@ -754,6 +855,41 @@ mod tests {
             vm_resources.set_vm_config(&aux_vm_config),
             Err(VmConfigError::InvalidMemorySize)
         );
+        aux_vm_config.mem_size_mib = Some(512);
+
+        // Nested virtualization is kept when it is not set.
+        aux_vm_config.nested_virt = Some(true);
+        vm_resources.set_vm_config(&aux_vm_config).unwrap();
+        aux_vm_config.nested_virt = None;
+        vm_resources.set_vm_config(&aux_vm_config).unwrap();
+        assert_eq!(vm_resources.vm_config.nested_virt, Some(true));
+        assert!(vm_resources.vcpu_config().nested_virt);
+
+        // The topology defines hyperthreading when it is not set explicitly.
+        aux_vm_config.ht_enabled = None;
+        aux_vm_config.cpu_topology = Some(CpuTopology {
+            sockets: 2,
+            cores_per_socket: 16,
+            threads_per_core: 1,
+        });
+        vm_resources.set_vm_config(&aux_vm_config).unwrap();
+        assert_eq!(vm_resources.vm_config.ht_enabled, Some(false));
+        assert_eq!(vm_resources.vcpu_config().sockets, 2);
+
+        // Topology not matching the vcpu count.
+        aux_vm_config.vcpu_count = Some(16);
+        assert_eq!(
+            vm_resources.set_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidCpuTopology)
+        );
+        aux_vm_config.vcpu_count = Some(32);
+
+        // Topology contradicting the hyperthreading setting.
+        aux_vm_config.ht_enabled = Some(true);
+        assert_eq!(
+            vm_resources.set_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidCpuTopology)
+        );
     }
 
     #[test]
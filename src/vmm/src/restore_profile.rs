@@ -0,0 +1,469 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Restore profiles, kept alongside the snapshot files.
+//!
+//! The restore options tuned to a snapshot, such as how its working set is prefetched or the
+//! policies of its guest memory, are read from a JSON file named in the load parameters rather
+//! than set by the controller on each load. Tuning a snapshot is then a matter of updating its
+//! profile. The options of the profile take precedence over the ones of the load parameters.
+//!
+//! Profiles can also be defined by name when Firecracker is launched, bundling the options of a
+//! class of functions, such as `latency` or `density`, and selected by name in the load
+//! parameters. The profile of the snapshot, when there is one, is applied over the named one.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::layer_fadvise::LayerAdvice;
+use crate::memory_policy;
+use crate::vmm_config::snapshot::{
+    LayerFadviseConfig, LoadSnapshotParams, PrefetchConfig, WsLockMode,
+};
+
+lazy_static! {
+    /// Named restore profiles of the process, empty until they are loaded.
+    pub static ref RESTORE_PROFILES: RestoreProfiles = RestoreProfiles::default();
+}
+
+/// Advice names accepted for the memory file, the empty one leaving the default.
+pub const FADVISE_POLICIES: [&str; 7] = [
+    "",
+    "normal",
+    "random",
+    "sequential",
+    "willneed",
+    "dontneed",
+    "noreuse",
+];
+
+/// Errors associated with the restore profiles.
+#[derive(Debug)]
+pub enum Error {
+    /// The named profiles are already loaded.
+    AlreadyInitialized,
+    /// An option of the profile is invalid.
+    Invalid(PathBuf, &'static str, String),
+    /// An option of a named profile is invalid.
+    InvalidNamed(String, &'static str, String),
+    /// Failed to parse the profile.
+    Parse(PathBuf, serde_json::Error),
+    /// Failed to read the profile.
+    Read(PathBuf, io::Error),
+    /// No profile has the requested name.
+    Unknown(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            AlreadyInitialized => write!(f, "The named restore profiles are already loaded"),
+            Invalid(path, option, reason) => write!(
+                f,
+                "Invalid option {} of restore profile {}: {}",
+                option,
+                path.display(),
+                reason
+            ),
+            InvalidNamed(name, option, reason) => write!(
+                f,
+                "Invalid option {} of restore profile '{}': {}",
+                option, name, reason
+            ),
+            Parse(path, err) => write!(
+                f,
+                "Cannot parse restore profile {}: {}",
+                path.display(),
+                err
+            ),
+            Read(path, err) => write!(f, "Cannot read restore profile {}: {}", path.display(), err),
+            Unknown(name) => write!(f, "No restore profile is named '{}'", name),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// How the working set of a restored microVM is prefetched.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum PrefetchMode {
+    /// The working set is faulted in by the guest, as any other page.
+    Lazy,
+    /// The working set pages are touched once mapped, as with `load_ws`.
+    Load,
+    /// The working set extents are populated as they are mapped, as with `ws_populate`.
+    Populate,
+}
+
+/// Restore options of a snapshot. The options left out keep the values of the load
+/// parameters.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RestoreProfile {
+    /// How the working set is prefetched.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefetch_mode: Option<PrefetchMode>,
+    /// Concurrency and chunk size of the working set load.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefetch: Option<PrefetchConfig>,
+    /// Advice given to the kernel on the accesses to the memory file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fadvise: Option<String>,
+    /// Advice given to the kernel on the accesses to each of the layer files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer_fadvise: Option<LayerFadviseConfig>,
+    /// Whether the guest memory is backed by transparent huge pages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub huge_pages: Option<bool>,
+    /// NUMA node the guest memory is placed on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub numa_node: Option<u32>,
+    /// How the working set is locked in memory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ws_lock: Option<WsLockMode>,
+}
+
+impl RestoreProfile {
+    /// Reads and validates the profile at `path`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read(path).map_err(|e| Error::Read(path.to_path_buf(), e))?;
+        let profile: RestoreProfile =
+            serde_json::from_slice(&content).map_err(|e| Error::Parse(path.to_path_buf(), e))?;
+        profile
+            .validate()
+            .map_err(|(option, reason)| Error::Invalid(path.to_path_buf(), option, reason))?;
+        Ok(profile)
+    }
+
+    fn validate(&self) -> std::result::Result<(), (&'static str, String)> {
+        if let Some(prefetch) = self.prefetch.as_ref() {
+            if prefetch.concurrency == Some(0) {
+                return Err(("prefetch.concurrency", "must be at least 1".to_string()));
+            }
+            if prefetch.chunk_pages == Some(0) {
+                return Err(("prefetch.chunk_pages", "must be at least 1".to_string()));
+            }
+        }
+        if let Some(fadvise) = self.fadvise.as_ref() {
+            if !FADVISE_POLICIES.contains(&fadvise.as_str()) {
+                return Err((
+                    "fadvise",
+                    format!(
+                        "unknown advice '{}', expected one of {}",
+                        fadvise,
+                        FADVISE_POLICIES[1..].join(", ")
+                    ),
+                ));
+            }
+        }
+        if let Some(layers) = self.layer_fadvise.as_ref() {
+            LayerAdvice::new("", Some(layers)).map_err(|e| ("layer_fadvise", e.to_string()))?;
+        }
+        if let Some(node) = self.numa_node {
+            let nodes = memory_policy::online_nodes()
+                .map_err(|e| ("numa_node", format!("cannot list the online nodes: {}", e)))?;
+            if !nodes.contains(&node) {
+                return Err(("numa_node", format!("node {} is not online", node)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `params` with the options of this profile applied.
+    pub fn apply(&self, params: &LoadSnapshotParams) -> LoadSnapshotParams {
+        let mut params = params.clone();
+        if let Some(mode) = self.prefetch_mode {
+            params.load_ws = mode != PrefetchMode::Lazy;
+            params.ws_populate = mode == PrefetchMode::Populate;
+        }
+        if let Some(prefetch) = self.prefetch.as_ref() {
+            params.prefetch = Some(prefetch.clone());
+        }
+        if let Some(fadvise) = self.fadvise.as_ref() {
+            params.fadvise = fadvise.clone();
+        }
+        if let Some(layers) = self.layer_fadvise.as_ref() {
+            params.layer_fadvise = Some(layers.clone());
+        }
+        if let Some(huge_pages) = self.huge_pages {
+            params.huge_pages = huge_pages;
+        }
+        if let Some(node) = self.numa_node {
+            params.numa_node = Some(node);
+        }
+        if let Some(mode) = self.ws_lock {
+            params.ws_lock = Some(mode);
+        }
+        params
+    }
+}
+
+/// Restore profiles defined by name at launch.
+#[derive(Default)]
+pub struct RestoreProfiles {
+    profiles: Mutex<Option<HashMap<String, RestoreProfile>>>,
+}
+
+impl RestoreProfiles {
+    /// Reads and validates the JSON object at `path`, which maps the names of the profiles to
+    /// their options.
+    pub fn init(&self, path: &Path) -> Result<()> {
+        let mut profiles = self.profiles.lock().expect("Poisoned lock");
+        if profiles.is_some() {
+            return Err(Error::AlreadyInitialized);
+        }
+
+        let content = std::fs::read(path).map_err(|e| Error::Read(path.to_path_buf(), e))?;
+        let named: HashMap<String, RestoreProfile> =
+            serde_json::from_slice(&content).map_err(|e| Error::Parse(path.to_path_buf(), e))?;
+        for (name, profile) in named.iter() {
+            profile
+                .validate()
+                .map_err(|(option, reason)| Error::InvalidNamed(name.clone(), option, reason))?;
+        }
+        *profiles = Some(named);
+        Ok(())
+    }
+
+    /// Returns the profile named `name`.
+    pub fn get(&self, name: &str) -> Result<RestoreProfile> {
+        self.profiles
+            .lock()
+            .expect("Poisoned lock")
+            .as_ref()
+            .and_then(|profiles| profiles.get(name).cloned())
+            .ok_or_else(|| Error::Unknown(name.to_string()))
+    }
+
+    /// Returns `params` with the named profile they select applied, then the profile file they
+    /// name, relative paths being relative to the directory of the snapshot file.
+    pub fn resolve(&self, params: &LoadSnapshotParams) -> Result<LoadSnapshotParams> {
+        let params = match params.profile.as_ref() {
+            Some(name) => self.get(name)?.apply(params),
+            None => params.clone(),
+        };
+        let path = match params.profile_path.as_ref() {
+            Some(path) => path,
+            None => return Ok(params),
+        };
+        let path = match params.snapshot_path.parent() {
+            Some(dir) if path.is_relative() => dir.join(path),
+            _ => path.clone(),
+        };
+        Ok(RestoreProfile::from_file(&path)?.apply(&params))
+    }
+}
+
+/// Returns `params` with the restore profiles they select applied, see
+/// [`RestoreProfiles::resolve`].
+pub fn resolve(params: &LoadSnapshotParams) -> Result<LoadSnapshotParams> {
+    RESTORE_PROFILES.resolve(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use utils::tempdir::TempDir;
+
+    fn write_profile(dir: &TempDir, content: &str) -> PathBuf {
+        write_file(dir, "profile.json", content)
+    }
+
+    fn write_file(dir: &TempDir, name: &str, content: &str) -> PathBuf {
+        let path = dir.as_path().join(name);
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn test_error_display() {
+        let path = PathBuf::from("profile.json");
+        let err = Error::AlreadyInitialized;
+        let _ = format!("{}{:?}", err, err);
+        let err = Error::Invalid(path.clone(), "fadvise", "unknown advice".to_string());
+        let _ = format!("{}{:?}", err, err);
+        let err = Error::InvalidNamed("latency".to_string(), "fadvise", "unknown".to_string());
+        let _ = format!("{}{:?}", err, err);
+        let err = Error::Parse(path.clone(), serde_json::from_str::<u32>("x").unwrap_err());
+        let _ = format!("{}{:?}", err, err);
+        let err = Error::Read(path, io::Error::from_raw_os_error(libc::ENOENT));
+        let _ = format!("{}{:?}", err, err);
+        let err = Error::Unknown("latency".to_string());
+        let _ = format!("{}{:?}", err, err);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = TempDir::new().unwrap();
+        write_profile(
+            &dir,
+            r#"{
+                "prefetch_mode": "Populate",
+                "prefetch": { "concurrency": 4 },
+                "fadvise": "random",
+                "layer_fadvise": { "ws_file": ["sequential", "willneed"] },
+                "huge_pages": true
+            }"#,
+        );
+        let params = LoadSnapshotParams {
+            snapshot_path: dir.as_path().join("snapshot"),
+            fadvise: "sequential".to_string(),
+            ksm: true,
+            ..Default::default()
+        };
+        assert_eq!(resolve(&params).unwrap(), params);
+
+        // The relative profile path is found next to the snapshot file, and its options win
+        // over the ones of the load parameters.
+        let params = LoadSnapshotParams {
+            profile_path: Some(PathBuf::from("profile.json")),
+            ..params
+        };
+        let resolved = resolve(&params).unwrap();
+        assert!(resolved.load_ws);
+        assert!(resolved.ws_populate);
+        assert_eq!(resolved.prefetch.unwrap().concurrency, Some(4));
+        assert_eq!(resolved.fadvise, "random");
+        assert_eq!(
+            resolved.layer_fadvise.unwrap().ws_file,
+            vec!["sequential", "willneed"]
+        );
+        assert!(resolved.huge_pages);
+        assert!(resolved.ksm);
+        assert_eq!(resolved.numa_node, None);
+
+        let params = LoadSnapshotParams {
+            profile_path: Some(PathBuf::from("missing.json")),
+            ..params
+        };
+        match resolve(&params) {
+            Err(Error::Read(path, _)) => assert_eq!(path, dir.as_path().join("missing.json")),
+            _ => panic!("A missing profile should be rejected."),
+        }
+    }
+
+    #[test]
+    fn test_invalid_profile() {
+        let dir = TempDir::new().unwrap();
+        let path = write_profile(&dir, r#"{ "prefetch_mode": "Eager" }"#);
+        match RestoreProfile::from_file(&path) {
+            Err(Error::Parse(_, _)) => (),
+            _ => panic!("Unknown prefetch modes should be rejected."),
+        }
+
+        let path = write_profile(&dir, r#"{ "huge_page": true }"#);
+        match RestoreProfile::from_file(&path) {
+            Err(Error::Parse(_, _)) => (),
+            _ => panic!("Unknown options should be rejected."),
+        }
+
+        let path = write_profile(&dir, r#"{ "prefetch": { "chunk_pages": 0 } }"#);
+        match RestoreProfile::from_file(&path) {
+            Err(Error::Invalid(_, "prefetch.chunk_pages", _)) => (),
+            _ => panic!("Empty chunks should be rejected."),
+        }
+
+        let path = write_profile(&dir, r#"{ "fadvise": "hugepage" }"#);
+        match RestoreProfile::from_file(&path) {
+            Err(Error::Invalid(_, "fadvise", reason)) => assert!(reason.contains("random")),
+            _ => panic!("Unknown advices should be rejected."),
+        }
+
+        let path = write_profile(&dir, r#"{ "layer_fadvise": { "ws_file": ["later"] } }"#);
+        match RestoreProfile::from_file(&path) {
+            Err(Error::Invalid(_, "layer_fadvise", reason)) => assert!(reason.contains("ws file")),
+            _ => panic!("Unknown layer advices should be rejected."),
+        }
+
+        let path = write_profile(&dir, r#"{ "numa_node": 4096 }"#);
+        match RestoreProfile::from_file(&path) {
+            Err(Error::Invalid(_, "numa_node", _)) => (),
+            _ => panic!("Offline nodes should be rejected."),
+        }
+    }
+
+    #[test]
+    fn test_named_profiles() {
+        let dir = TempDir::new().unwrap();
+        let profiles = RestoreProfiles::default();
+        let params = LoadSnapshotParams {
+            snapshot_path: dir.as_path().join("snapshot"),
+            profile: Some("latency".to_string()),
+            ..Default::default()
+        };
+        match profiles.resolve(&params) {
+            Err(Error::Unknown(name)) => assert_eq!(name, "latency"),
+            _ => panic!("Profiles should not be found before being loaded."),
+        }
+
+        let path = write_file(
+            &dir,
+            "profiles.json",
+            r#"{
+                "latency": {
+                    "prefetch_mode": "Load",
+                    "prefetch": { "concurrency": 8 },
+                    "huge_pages": true,
+                    "ws_lock": "Full"
+                },
+                "lazy": { "prefetch_mode": "Lazy" }
+            }"#,
+        );
+        profiles.init(&path).unwrap();
+        match profiles.init(&path) {
+            Err(Error::AlreadyInitialized) => (),
+            _ => panic!("Profiles should only be loaded once."),
+        }
+
+        let resolved = profiles.resolve(&params).unwrap();
+        assert!(resolved.load_ws);
+        assert!(!resolved.ws_populate);
+        assert_eq!(resolved.prefetch.unwrap().concurrency, Some(8));
+        assert!(resolved.huge_pages);
+        assert_eq!(resolved.ws_lock, Some(WsLockMode::Full));
+
+        // The profile of the snapshot is applied over the named one.
+        write_profile(&dir, r#"{ "prefetch_mode": "Lazy" }"#);
+        let resolved = profiles
+            .resolve(&LoadSnapshotParams {
+                profile_path: Some(PathBuf::from("profile.json")),
+                ..params.clone()
+            })
+            .unwrap();
+        assert!(!resolved.load_ws);
+        assert!(resolved.huge_pages);
+
+        match profiles.resolve(&LoadSnapshotParams {
+            profile: Some("density".to_string()),
+            ..params
+        }) {
+            Err(Error::Unknown(name)) => assert_eq!(name, "density"),
+            _ => panic!("Unknown profiles should be rejected."),
+        }
+
+        let path = write_file(
+            &dir,
+            "invalid.json",
+            r#"{ "density": { "prefetch": { "concurrency": 0 } } }"#,
+        );
+        match RestoreProfiles::default().init(&path) {
+            Err(Error::InvalidNamed(name, "prefetch.concurrency", _)) => {
+                assert_eq!(name, "density")
+            }
+            _ => panic!("Invalid named profiles should be rejected."),
+        }
+    }
+}
@@ -0,0 +1,250 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Timeline of the snapshot restore phases.
+//!
+//! Each event is a JSON object on its own line, timestamped with the monotonic clock so that the
+//! phases of a restore can be ordered and measured against each other.
+
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use logger::error;
+use serde::Serialize;
+use utils::time::{get_time_us, ClockType};
+
+lazy_static! {
+    /// Restore trace of the process. Events are dropped until it is initialized.
+    pub static ref RESTORE_TRACE: RestoreTrace = RestoreTrace::default();
+}
+
+/// Errors associated with the restore trace.
+#[derive(Debug)]
+pub enum Error {
+    /// The restore trace is already initialized.
+    AlreadyInitialized,
+    /// Failed to open the restore trace file.
+    Open(PathBuf, io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            AlreadyInitialized => write!(f, "The restore trace is already initialized"),
+            Open(path, err) => write!(f, "Cannot open restore trace {}: {}", path.display(), err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Phases of a snapshot restore.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestorePhase {
+    /// The whole snapshot load, enclosing every other phase but the vCPU resume.
+    SnapshotLoad,
+    /// Reading, verifying and deserializing the microVM state file.
+    StateDeserialize,
+    /// Mapping the base memory file, or anonymous memory, over the guest memory regions.
+    BaseMmap,
+    /// Mapping the overlay extents over the base layer.
+    OverlayMap,
+    /// Mapping the working set extents over the lower layers.
+    WsMap,
+    /// Registering the guest memory with userfaultfd and handing it over to the page fault
+    /// handler.
+    UffdRegister,
+    /// Touching the working set pages to fault them in.
+    Prefetch,
+    /// Restoring the VM and device states.
+    DeviceRestore,
+    /// Resuming the vCPUs.
+    VcpuResume,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum TraceEvent {
+    Start,
+    End,
+}
+
+#[derive(Serialize)]
+struct TraceRecord<'a> {
+    timestamp_us: u64,
+    instance_id: &'a str,
+    phase: RestorePhase,
+    event: TraceEvent,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_us: Option<u64>,
+}
+
+struct TraceSink {
+    file: File,
+    instance_id: String,
+}
+
+/// Sink of the restore trace events.
+#[derive(Default)]
+pub struct RestoreTrace {
+    sink: Mutex<Option<TraceSink>>,
+}
+
+impl RestoreTrace {
+    /// Opens the restore trace file at `path`, a regular file or a named pipe, in append mode.
+    /// Events are attributed to the `instance_id` microVM.
+    pub fn init(&self, path: &Path, instance_id: &str) -> Result<()> {
+        let mut sink = self.sink.lock().expect("Poisoned lock");
+        if sink.is_some() {
+            return Err(Error::AlreadyInitialized);
+        }
+
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| Error::Open(path.to_path_buf(), e))?;
+        *sink = Some(TraceSink {
+            file,
+            instance_id: instance_id.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Returns true if the restore trace is initialized.
+    pub fn is_enabled(&self) -> bool {
+        self.sink.lock().expect("Poisoned lock").is_some()
+    }
+
+    /// Emits the start event of `phase`. The end event is emitted when the returned span is
+    /// dropped, so the phase ends on every return path.
+    pub fn span(&self, phase: RestorePhase) -> PhaseSpan<'_> {
+        let start_us = get_time_us(ClockType::Monotonic);
+        self.emit(phase, TraceEvent::Start, start_us, None);
+        PhaseSpan {
+            trace: self,
+            phase,
+            start_us,
+        }
+    }
+
+    fn emit(
+        &self,
+        phase: RestorePhase,
+        event: TraceEvent,
+        timestamp_us: u64,
+        duration_us: Option<u64>,
+    ) {
+        let mut guard = self.sink.lock().expect("Poisoned lock");
+        let sink = match guard.as_mut() {
+            Some(sink) => sink,
+            None => return,
+        };
+
+        let record = TraceRecord {
+            timestamp_us,
+            instance_id: &sink.instance_id,
+            phase,
+            event,
+            duration_us,
+        };
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(err) => {
+                error!("Cannot serialize restore trace event: {}", err);
+                return;
+            }
+        };
+        line.push(b'\n');
+        if let Err(err) = sink.file.write_all(&line) {
+            error!("Cannot write restore trace event: {}", err);
+        }
+    }
+}
+
+/// A restore phase in progress, which ends when dropped.
+pub struct PhaseSpan<'a> {
+    trace: &'a RestoreTrace,
+    phase: RestorePhase,
+    start_us: u64,
+}
+
+impl<'a> Drop for PhaseSpan<'a> {
+    fn drop(&mut self) {
+        let end_us = get_time_us(ClockType::Monotonic);
+        self.trace.emit(
+            self.phase,
+            TraceEvent::End,
+            end_us,
+            Some(end_us - self.start_us),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use utils::tempfile::TempFile;
+
+    #[test]
+    fn test_span() {
+        let trace_file = TempFile::new().unwrap();
+        let trace = RestoreTrace::default();
+
+        // Events are dropped until the restore trace is initialized.
+        assert!(!trace.is_enabled());
+        drop(trace.span(RestorePhase::SnapshotLoad));
+
+        trace.init(trace_file.as_path(), "vm0").unwrap();
+        assert!(trace.is_enabled());
+        match trace.init(trace_file.as_path(), "vm0") {
+            Err(Error::AlreadyInitialized) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        {
+            let _load = trace.span(RestorePhase::SnapshotLoad);
+            let _state = trace.span(RestorePhase::StateDeserialize);
+        }
+
+        let contents = fs::read_to_string(trace_file.as_path()).unwrap();
+        let events: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 4);
+
+        let expected = [
+            ("snapshot_load", "start"),
+            ("state_deserialize", "start"),
+            ("state_deserialize", "end"),
+            ("snapshot_load", "end"),
+        ];
+        for (event, (phase, kind)) in events.iter().zip(expected.iter()) {
+            assert_eq!(event["instance_id"], "vm0");
+            assert_eq!(event["phase"], *phase);
+            assert_eq!(event["event"], *kind);
+            assert_eq!(event.get("duration_us").is_some(), *kind == "end");
+        }
+
+        // Timestamps are monotonic and the durations match them.
+        let timestamps: Vec<u64> = events
+            .iter()
+            .map(|event| event["timestamp_us"].as_u64().unwrap())
+            .collect();
+        assert!(timestamps.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(
+            events[3]["duration_us"].as_u64().unwrap(),
+            timestamps[3] - timestamps[0]
+        );
+    }
+}
@@ -0,0 +1,288 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Watchdog of the snapshot restore operations that wait on the outside world.
+//!
+//! The working set load waits on the storage or on the page fault handler, and the uffd
+//! handshake waits for the page fault handler to connect. Both report their progress to
+//! `RESTORE_WATCHDOG`, and an armed watchdog thread reports the operation that makes no progress
+//! for longer than the configured timeout, instead of the restore hanging silently.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicI32, AtomicU64, AtomicU8, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use logger::{error, Metric, METRICS};
+
+use crate::vmm_config::snapshot::RestoreWatchdogConfig;
+use crate::FC_EXIT_CODE_RESTORE_STALLED;
+
+lazy_static! {
+    /// Progress of the watched restore operations of the process.
+    pub static ref RESTORE_WATCHDOG: RestoreWatchdog = RestoreWatchdog::new();
+}
+
+// Number of progress checks per timeout.
+const CHECKS_PER_TIMEOUT: u32 = 4;
+
+/// Restore operations watched for stalls.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchedOperation {
+    /// No watched operation is in progress.
+    Idle,
+    /// Touching the working set pages to fault them in.
+    WsLoad,
+    /// Handing the guest memory over to the page fault handler.
+    UffdHandshake,
+}
+
+impl WatchedOperation {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => WatchedOperation::WsLoad,
+            2 => WatchedOperation::UffdHandshake,
+            _ => WatchedOperation::Idle,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            WatchedOperation::Idle => 0,
+            WatchedOperation::WsLoad => 1,
+            WatchedOperation::UffdHandshake => 2,
+        }
+    }
+}
+
+impl Display for WatchedOperation {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            WatchedOperation::Idle => write!(f, "idle"),
+            WatchedOperation::WsLoad => write!(f, "working set load"),
+            WatchedOperation::UffdHandshake => write!(f, "uffd handshake"),
+        }
+    }
+}
+
+/// Progress of the watched restore operation.
+pub struct RestoreWatchdog {
+    operation: AtomicU8,
+    progress: AtomicU64,
+    extent_offset: AtomicU64,
+    extent_len: AtomicU64,
+    fd: AtomicI32,
+}
+
+/// Marks the end of a watched operation when dropped.
+pub struct WatchGuard<'a> {
+    watchdog: &'a RestoreWatchdog,
+}
+
+impl Drop for WatchGuard<'_> {
+    fn drop(&mut self) {
+        self.watchdog
+            .operation
+            .store(WatchedOperation::Idle.to_u8(), Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Progress {
+    operation: WatchedOperation,
+    progress: u64,
+}
+
+impl RestoreWatchdog {
+    fn new() -> Self {
+        RestoreWatchdog {
+            operation: AtomicU8::new(WatchedOperation::Idle.to_u8()),
+            progress: AtomicU64::new(0),
+            extent_offset: AtomicU64::new(0),
+            extent_len: AtomicU64::new(0),
+            fd: AtomicI32::new(-1),
+        }
+    }
+
+    /// Starts watching `operation`, until the returned guard is dropped.
+    pub fn watch(&self, operation: WatchedOperation) -> WatchGuard {
+        self.extent_offset.store(0, Ordering::SeqCst);
+        self.extent_len.store(0, Ordering::SeqCst);
+        self.fd.store(-1, Ordering::SeqCst);
+        self.progress.fetch_add(1, Ordering::SeqCst);
+        self.operation.store(operation.to_u8(), Ordering::SeqCst);
+        WatchGuard { watchdog: self }
+    }
+
+    /// Reports that the watched operation made progress.
+    pub fn progress(&self) {
+        self.progress.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Reports that the watched operation moved on to the extent of `len` bytes at `offset` in
+    /// the memory file layout.
+    pub fn set_extent(&self, offset: u64, len: u64) {
+        self.extent_offset.store(offset, Ordering::Relaxed);
+        self.extent_len.store(len, Ordering::Relaxed);
+        self.progress();
+    }
+
+    /// Reports that the watched operation waits on `fd`.
+    pub fn set_fd(&self, fd: RawFd) {
+        self.fd.store(fd, Ordering::Relaxed);
+        self.progress();
+    }
+
+    fn current(&self) -> Progress {
+        Progress {
+            operation: WatchedOperation::from_u8(self.operation.load(Ordering::SeqCst)),
+            progress: self.progress.load(Ordering::SeqCst),
+        }
+    }
+
+    fn report_stall(&self, operation: WatchedOperation, stalled_ms: u64) {
+        let fd = self.fd.load(Ordering::Relaxed);
+        error!(
+            "Restore stalled: the {} made no progress for {} ms, extent {:#x} of length {:#x}, \
+             fd {}: {}",
+            operation,
+            stalled_ms,
+            self.extent_offset.load(Ordering::Relaxed),
+            self.extent_len.load(Ordering::Relaxed),
+            fd,
+            describe_fd(fd)
+        );
+        METRICS.snapshot.restore_stalls.inc();
+    }
+}
+
+// Describes the file `fd` refers to and its state, as reported by procfs.
+fn describe_fd(fd: RawFd) -> String {
+    if fd < 0 {
+        return "none".to_string();
+    }
+    let path = std::fs::read_link(format!("/proc/self/fd/{}", fd))
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|e| format!("unknown ({})", e));
+    let info = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", fd))
+        .map(|info| info.split_whitespace().collect::<Vec<_>>().join(" "))
+        .unwrap_or_else(|e| format!("unknown ({})", e));
+    format!("{}, {}", path, info)
+}
+
+/// Watchdog thread, stopped when dropped.
+pub struct Watchdog {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        // Disconnecting the channel stops the thread.
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("The restore watchdog thread panicked");
+            }
+        }
+    }
+}
+
+/// Starts checking the progress of the watched operations, as configured by `config`.
+pub fn arm(config: RestoreWatchdogConfig) -> io::Result<Watchdog> {
+    let (stop, stop_receiver) = channel();
+    let timeout = Duration::from_millis(std::cmp::max(config.timeout_ms, 1));
+    let period = timeout / CHECKS_PER_TIMEOUT;
+
+    let thread = thread::Builder::new()
+        .name("fc_restore_wd".to_string())
+        .spawn(move || {
+            let mut last = RESTORE_WATCHDOG.current();
+            let mut stalled = Duration::from_millis(0);
+            let mut reported = false;
+            while let Err(RecvTimeoutError::Timeout) = stop_receiver.recv_timeout(period) {
+                let current = RESTORE_WATCHDOG.current();
+                if current.operation == WatchedOperation::Idle || current != last {
+                    last = current;
+                    stalled = Duration::from_millis(0);
+                    reported = false;
+                    continue;
+                }
+                stalled += period;
+                if stalled < timeout || reported {
+                    continue;
+                }
+                RESTORE_WATCHDOG.report_stall(current.operation, stalled.as_millis() as u64);
+                reported = true;
+                if config.abort {
+                    error!("Aborting the stalled restore");
+                    if let Err(e) = METRICS.write() {
+                        error!("Failed to write metrics: {}", e);
+                    }
+                    std::process::exit(i32::from(FC_EXIT_CODE_RESTORE_STALLED));
+                }
+            }
+        })?;
+
+    Ok(Watchdog {
+        stop: Some(stop),
+        thread: Some(thread),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watch() {
+        let watchdog = RestoreWatchdog::new();
+        assert_eq!(watchdog.current().operation, WatchedOperation::Idle);
+
+        let guard = watchdog.watch(WatchedOperation::WsLoad);
+        let before = watchdog.current();
+        assert_eq!(before.operation, WatchedOperation::WsLoad);
+        watchdog.set_extent(0x1000, 0x2000);
+        assert_ne!(watchdog.current(), before);
+        let before = watchdog.current();
+        watchdog.set_fd(0);
+        assert_ne!(watchdog.current(), before);
+        drop(guard);
+        assert_eq!(watchdog.current().operation, WatchedOperation::Idle);
+
+        for operation in &[
+            WatchedOperation::Idle,
+            WatchedOperation::WsLoad,
+            WatchedOperation::UffdHandshake,
+        ] {
+            assert_eq!(WatchedOperation::from_u8(operation.to_u8()), *operation);
+        }
+    }
+
+    #[test]
+    fn test_describe_fd() {
+        assert_eq!(describe_fd(-1), "none");
+        let file = std::fs::File::open("/proc/self/status").unwrap();
+        let description = describe_fd(std::os::unix::io::AsRawFd::as_raw_fd(&file));
+        assert!(description.contains("pos:"));
+    }
+
+    #[test]
+    fn test_arm() {
+        let stalls = METRICS.snapshot.restore_stalls.count();
+        let watchdog = arm(RestoreWatchdogConfig {
+            timeout_ms: 20,
+            abort: false,
+        })
+        .unwrap();
+        let guard = RESTORE_WATCHDOG.watch(WatchedOperation::UffdHandshake);
+        thread::sleep(Duration::from_millis(100));
+        drop(guard);
+        drop(watchdog);
+        assert!(METRICS.snapshot.restore_stalls.count() > stalls);
+    }
+}
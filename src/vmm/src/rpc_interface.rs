@@ -9,30 +9,48 @@ use super::Vmm;
 
 use super::Error as VmmError;
 use crate::builder::{self, StartMicrovmError};
+use crate::default_syscalls::ThreadFilters;
+use crate::lifecycle::{LifecycleEvent, LIFECYCLE};
+use crate::memory_debug::{self, GuestMemoryContent, GuestMemoryReadParams, MEMORY_DEBUG};
+use crate::memory_reclaim::{self, MemoryReclaimParams, ReclaimStats};
 #[cfg(target_arch = "x86_64")]
-use crate::persist::{self, CreateSnapshotError, LoadSnapshotError};
+use crate::memory_snapshot::{self, SnapshotMemory};
+#[cfg(target_arch = "x86_64")]
+use crate::page_cache::{self, PrewarmStats};
+#[cfg(target_arch = "x86_64")]
+use crate::persist::{CreateSnapshotError, LoadSnapshotError};
+use crate::probes;
 use crate::resources::VmResources;
 #[cfg(target_arch = "x86_64")]
-use crate::version_map::VERSION_MAP;
+use crate::restore_profile;
+use crate::restore_trace::{RestorePhase, RESTORE_TRACE};
+#[cfg(target_arch = "x86_64")]
+use crate::snapshot::{self, RestoreConfig};
+use crate::snapshot_ops::{self, SnapshotOpState, SNAPSHOT_OPS};
+use crate::snapshot_signing::SnapshotKeys;
 use crate::vmm_config;
 use crate::vmm_config::boot_source::{BootSourceConfig, BootSourceConfigError};
 use crate::vmm_config::drive::{BlockDeviceConfig, DriveError};
 use crate::vmm_config::instance_info::InstanceInfo;
-use crate::vmm_config::logger::{LoggerConfig, LoggerConfigError};
+use crate::vmm_config::logger::{LoggerConfig, LoggerConfigError, LoggerUpdateConfig};
 use crate::vmm_config::machine_config::{VmConfig, VmConfigError};
-use crate::vmm_config::metrics::{MetricsConfig, MetricsConfigError};
+use crate::vmm_config::metrics::{MetricsConfig, MetricsConfigError, MetricsUpdateConfig};
 use crate::vmm_config::mmds::{MmdsConfig, MmdsConfigError};
 use crate::vmm_config::net::{
     NetworkInterfaceConfig, NetworkInterfaceError, NetworkInterfaceUpdateConfig,
 };
 #[cfg(target_arch = "x86_64")]
-use crate::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams, SnapshotType};
+use crate::vmm_config::snapshot::{
+    CreateSnapshotParams, LoadSnapshotParams, MemBackendError, ScrubRange, ScrubRangesConfig,
+};
 use crate::vmm_config::vsock::{VsockConfigError, VsockDeviceConfig};
+use crate::ws_accounting::WsStats;
+#[cfg(target_arch = "x86_64")]
+use crate::ws_staging;
 use arch::DeviceType;
 use devices::virtio::{Block, MmioTransport, Net, TYPE_BLOCK, TYPE_NET};
 use logger::{info, update_metric_with_elapsed_time, METRICS};
 use polly::event_manager::EventManager;
-use seccomp::BpfProgram;
 
 /// This enum represents the public interface of the VMM. Each action contains various
 /// bits of information (ids, paths, etc.).
@@ -48,7 +66,8 @@ pub enum VmmAction {
     /// before the microVM has booted.
     ConfigureMetrics(MetricsConfig),
     /// Create a snapshot using as input the `CreateSnapshotParams`. This action can only be called
-    /// after the microVM has booted and only when the microVM is in `Paused` state.
+    /// after the microVM has booted and only when the microVM is in `Paused` state, or in
+    /// `Resumed` state for a background snapshot.
     #[cfg(target_arch = "x86_64")]
     CreateSnapshot(CreateSnapshotParams),
     /// Get the configuration of the microVM.
@@ -69,10 +88,25 @@ pub enum VmmAction {
     LoadSnapshot(LoadSnapshotParams),
     /// Pause the guest, by pausing the microVM VCPUs.
     Pause,
+    /// Read the working set of the snapshot described by the `LoadSnapshotParams` into the host
+    /// page cache, ahead of its load. This action can only be called before the microVM has
+    /// booted.
+    #[cfg(target_arch = "x86_64")]
+    PrewarmSnapshot(LoadSnapshotParams),
+    /// Read the guest memory range described by the `GuestMemoryReadParams`, for debugging. This
+    /// action can only be called after the microVM has booted.
+    ReadGuestMemory(GuestMemoryReadParams),
+    /// Reclaim the restored guest memory using as input the `MemoryReclaimParams`. This action
+    /// can only be called after the microVM has booted.
+    ReclaimMemory(MemoryReclaimParams),
     /// Resume the guest, by resuming the microVM VCPUs.
     Resume,
     /// Set the MMDS configuration.
     SetMmdsConfiguration(MmdsConfig),
+    /// Replace the guest memory ranges zeroed in the memory file of the snapshots using as input
+    /// the `ScrubRangesConfig`. This action can only be called after the microVM has booted.
+    #[cfg(target_arch = "x86_64")]
+    SetScrubRanges(ScrubRangesConfig),
     /// Set the vsock device or update the one that already exists using the
     /// `VsockDeviceConfig` as input. This action can only be called before the microVM has
     /// booted.
@@ -89,6 +123,12 @@ pub enum VmmAction {
     /// Update the path of an existing block device. The data associated with this variant
     /// represents the `drive_id` and the `path_on_host`.
     UpdateBlockDevicePath(String, String),
+    /// Update the level, the debug categories or the output of the logger using as input the
+    /// `LoggerUpdateConfig`. This action can be called before or after the microVM has booted.
+    UpdateLogger(LoggerUpdateConfig),
+    /// Update the output of the metrics using as input the `MetricsUpdateConfig`. This action can
+    /// be called before or after the microVM has booted.
+    UpdateMetrics(MetricsUpdateConfig),
     /// Update a network interface, after microVM start. Currently, the only updatable properties
     /// are the RX and TX rate limiters.
     UpdateNetworkInterface(NetworkInterfaceUpdateConfig),
@@ -110,11 +150,19 @@ pub enum VmmActionError {
     /// Loading a microVM snapshot failed.
     #[cfg(target_arch = "x86_64")]
     LoadSnapshot(LoadSnapshotError),
-    /// The action `ConfigureLogger` failed because of bad user input.
+    /// One of the actions `ConfigureLogger` or `UpdateLogger` failed because of bad user input.
     Logger(LoggerConfigError),
     /// One of the actions `GetVmConfiguration` or `SetVmConfiguration` failed because of bad input.
     MachineConfig(VmConfigError),
-    /// The action `ConfigureMetrics` failed because of bad user input.
+    /// One of the actions `LoadSnapshot` or `PrewarmSnapshot` failed because of a conflicting
+    /// memory backend.
+    #[cfg(target_arch = "x86_64")]
+    MemBackend(MemBackendError),
+    /// The action `ReadGuestMemory` failed.
+    MemoryDebug(memory_debug::Error),
+    /// The action `ReclaimMemory` failed.
+    MemoryReclaim(memory_reclaim::Error),
+    /// One of the actions `ConfigureMetrics` or `UpdateMetrics` failed because of bad user input.
     Metrics(MetricsConfigError),
     /// The action `SetMmdsConfiguration` failed because of bad user input.
     MmdsConfig(MmdsConfigError),
@@ -124,6 +172,18 @@ pub enum VmmActionError {
     OperationNotSupportedPostBoot,
     /// The requested operation is not supported before starting the microVM.
     OperationNotSupportedPreBoot,
+    /// The action `PrewarmSnapshot` failed.
+    #[cfg(target_arch = "x86_64")]
+    PrewarmSnapshot(page_cache::Error),
+    /// The action `LoadSnapshot` failed because of an invalid restore profile.
+    #[cfg(target_arch = "x86_64")]
+    RestoreProfile(restore_profile::Error),
+    /// The action `SetScrubRanges` failed because of bad user input.
+    #[cfg(target_arch = "x86_64")]
+    ScrubRanges(memory_snapshot::Error),
+    /// One of the actions `StartMicroVm`, `LoadSnapshot` or `CreateSnapshot` conflicts with the
+    /// state of the microVM or with another of them in flight.
+    SnapshotState(snapshot_ops::Error),
     /// The action `StartMicroVm` failed because of an internal error.
     StartMicrovm(StartMicrovmError),
     /// The action `SetVsockDevice` failed because of bad user input.
@@ -147,6 +207,10 @@ impl Display for VmmActionError {
                 LoadSnapshot(err) => format!("Load microVM snapshot error: {}", err),
                 Logger(err) => err.to_string(),
                 MachineConfig(err) => err.to_string(),
+                #[cfg(target_arch = "x86_64")]
+                MemBackend(err) => err.to_string(),
+                MemoryDebug(err) => err.to_string(),
+                MemoryReclaim(err) => err.to_string(),
                 Metrics(err) => err.to_string(),
                 MmdsConfig(err) => err.to_string(),
                 NetworkConfig(err) => err.to_string(),
@@ -158,6 +222,13 @@ impl Display for VmmActionError {
                     "The requested operation is not supported before starting the microVM."
                         .to_string()
                 }
+                #[cfg(target_arch = "x86_64")]
+                PrewarmSnapshot(err) => format!("Prewarm snapshot error: {}", err),
+                #[cfg(target_arch = "x86_64")]
+                RestoreProfile(err) => err.to_string(),
+                #[cfg(target_arch = "x86_64")]
+                ScrubRanges(err) => err.to_string(),
+                SnapshotState(err) => err.to_string(),
                 StartMicrovm(err) => err.to_string(),
                 // The action `SetVsockDevice` failed because of bad user input.
                 VsockConfig(err) => err.to_string(),
@@ -166,19 +237,43 @@ impl Display for VmmActionError {
     }
 }
 
+#[cfg(target_arch = "x86_64")]
+impl From<snapshot::Error> for VmmActionError {
+    fn from(err: snapshot::Error) -> Self {
+        match err {
+            snapshot::Error::Create(err) => VmmActionError::CreateSnapshot(err),
+            snapshot::Error::InvalidParams(err) => VmmActionError::MemBackend(err),
+            snapshot::Error::Profile(err) => VmmActionError::RestoreProfile(err),
+            snapshot::Error::Restore(err) => VmmActionError::LoadSnapshot(err),
+            snapshot::Error::Resume(err) => VmmActionError::InternalVmm(err),
+            snapshot::Error::State(err) => VmmActionError::SnapshotState(err),
+        }
+    }
+}
+
 /// The enum represents the response sent by the VMM in case of success. The response is either
 /// empty, when no data needs to be sent, or an internal VMM structure.
 #[derive(Debug)]
 pub enum VmmData {
     /// No data is sent on the channel.
     Empty,
+    /// The content of a guest memory range.
+    GuestMemory(GuestMemoryContent),
     /// The microVM configuration represented by `VmConfig`.
     MachineConfiguration(VmConfig),
+    /// The outcome of reclaiming the guest memory.
+    MemoryReclaim(ReclaimStats),
+    /// The outcome of warming the page cache with the working set of a snapshot.
+    #[cfg(target_arch = "x86_64")]
+    Prewarm(PrewarmStats),
+    /// The working set prefetch accounting of a snapshot load.
+    WsPrefetch(WsStats),
 }
 
 /// Enables pre-boot setup and instantiation of a Firecracker VMM.
 pub struct PrebootApiController<'a> {
-    seccomp_filter: BpfProgram,
+    seccomp_filters: ThreadFilters,
+    snapshot_keys: Arc<SnapshotKeys>,
     instance_info: InstanceInfo,
     vm_resources: &'a mut VmResources,
     event_manager: &'a mut EventManager,
@@ -188,13 +283,15 @@ pub struct PrebootApiController<'a> {
 impl<'a> PrebootApiController<'a> {
     /// Constructor for the PrebootApiController.
     pub fn new(
-        seccomp_filter: BpfProgram,
+        seccomp_filters: ThreadFilters,
+        snapshot_keys: Arc<SnapshotKeys>,
         instance_info: InstanceInfo,
         vm_resources: &'a mut VmResources,
         event_manager: &'a mut EventManager,
     ) -> PrebootApiController<'a> {
         PrebootApiController {
-            seccomp_filter,
+            seccomp_filters,
+            snapshot_keys,
             instance_info,
             vm_resources,
             event_manager,
@@ -208,7 +305,8 @@ impl<'a> PrebootApiController<'a> {
     ///
     /// Returns a populated `VmResources` object and a running `Vmm` object.
     pub fn build_microvm_from_requests<F, G>(
-        seccomp_filter: BpfProgram,
+        seccomp_filters: ThreadFilters,
+        snapshot_keys: Arc<SnapshotKeys>,
         event_manager: &mut EventManager,
         instance_info: InstanceInfo,
         recv_req: F,
@@ -220,7 +318,8 @@ impl<'a> PrebootApiController<'a> {
     {
         let mut vm_resources = VmResources::default();
         let mut preboot_controller = PrebootApiController::new(
-            seccomp_filter,
+            seccomp_filters,
+            snapshot_keys,
             instance_info,
             &mut vm_resources,
             event_manager,
@@ -238,6 +337,40 @@ impl<'a> PrebootApiController<'a> {
         (vm_resources, vmm)
     }
 
+    /// Restores the microVM described by `load_params` and resumes it, without going through
+    /// the API.
+    ///
+    /// Returns a populated `VmResources` object and a running `Vmm` object.
+    #[cfg(target_arch = "x86_64")]
+    pub fn build_microvm_from_snapshot(
+        seccomp_filters: ThreadFilters,
+        snapshot_keys: Arc<SnapshotKeys>,
+        event_manager: &mut EventManager,
+        instance_info: InstanceInfo,
+        load_params: &LoadSnapshotParams,
+    ) -> result::Result<(VmResources, Arc<Mutex<Vmm>>), VmmActionError> {
+        let mut vm_resources = VmResources::default();
+        let vmm = {
+            let mut preboot_controller = PrebootApiController::new(
+                seccomp_filters,
+                snapshot_keys.clone(),
+                instance_info,
+                &mut vm_resources,
+                event_manager,
+            );
+            preboot_controller.load_snapshot(load_params)?;
+            // Safe to unwrap because a successful load builds the Vmm.
+            preboot_controller.built_vmm.take().unwrap()
+        };
+        // The load already resumed the microVM if asked to.
+        if !load_params.resume_vm {
+            // Resume through the runtime controller, which records the resume like the API does.
+            RuntimeApiController::new(vm_resources.vm_config().clone(), vmm.clone(), snapshot_keys)
+                .resume()?;
+        }
+        Ok((vm_resources, vmm))
+    }
+
     /// Handles the incoming preboot request and provides a response for it.
     /// Returns a built/running `Vmm` after handling a successful `StartMicroVm` request.
     pub fn handle_preboot_request(
@@ -275,9 +408,9 @@ impl<'a> PrebootApiController<'a> {
                 .map(|_| VmmData::Empty)
                 .map_err(VmmActionError::NetworkConfig),
             #[cfg(target_arch = "x86_64")]
-            LoadSnapshot(snapshot_load_cfg) => self
-                .load_snapshot(&snapshot_load_cfg)
-                .map(|_| VmmData::Empty),
+            LoadSnapshot(snapshot_load_cfg) => self.load_snapshot(&snapshot_load_cfg),
+            #[cfg(target_arch = "x86_64")]
+            PrewarmSnapshot(snapshot_load_cfg) => self.prewarm_snapshot(&snapshot_load_cfg),
             SetVsockDevice(vsock_cfg) => self
                 .vm_resources
                 .set_vsock_device(vsock_cfg)
@@ -293,48 +426,101 @@ impl<'a> PrebootApiController<'a> {
                 .set_mmds_config(mmds_config)
                 .map(|_| VmmData::Empty)
                 .map_err(VmmActionError::MmdsConfig),
-            StartMicroVm => builder::build_microvm_for_boot(
-                &self.vm_resources,
-                &mut self.event_manager,
-                &self.seccomp_filter,
-            )
-            .map(|vmm| {
-                self.built_vmm = Some(vmm);
-                VmmData::Empty
-            })
-            .map_err(VmmActionError::StartMicrovm),
+            StartMicroVm => self.start_microvm(),
+            UpdateLogger(logger_cfg) => vmm_config::logger::update_logger(logger_cfg)
+                .map(|_| VmmData::Empty)
+                .map_err(VmmActionError::Logger),
+            UpdateMetrics(metrics_cfg) => vmm_config::metrics::update_metrics(metrics_cfg)
+                .map(|_| VmmData::Empty)
+                .map_err(VmmActionError::Metrics),
             // Operations not allowed pre-boot.
             FlushMetrics
             | Pause
+            | ReadGuestMemory(_)
+            | ReclaimMemory(_)
             | Resume
             | UpdateBlockDevicePath(_, _)
             | UpdateNetworkInterface(_) => Err(VmmActionError::OperationNotSupportedPreBoot),
             #[cfg(target_arch = "x86_64")]
-            CreateSnapshot(_) | SendCtrlAltDel => Err(VmmActionError::OperationNotSupportedPreBoot),
+            CreateSnapshot(_) | SendCtrlAltDel | SetScrubRanges(_) => {
+                Err(VmmActionError::OperationNotSupportedPreBoot)
+            }
         }
     }
 
-    #[cfg(target_arch = "x86_64")]
-    fn load_snapshot(&mut self, load_params: &LoadSnapshotParams) -> ActionResult {
-        let load_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
-
-        let loaded_vmm = persist::load_snapshot(
+    fn start_microvm(&mut self) -> result::Result<VmmData, VmmActionError> {
+        let mut operation = SNAPSHOT_OPS
+            .begin_boot()
+            .map_err(VmmActionError::SnapshotState)?;
+        let vmm = builder::build_microvm_for_boot(
+            &self.vm_resources,
             &mut self.event_manager,
-            &self.seccomp_filter,
-            load_params,
-            VERSION_MAP.clone(),
-        );
+            &self.seccomp_filters,
+        )
+        .map_err(VmmActionError::StartMicrovm)?;
+        operation.complete(SnapshotOpState::Running);
+        self.built_vmm = Some(vmm);
+        Ok(VmmData::Empty)
+    }
 
-        let elapsed_time_us =
-            update_metric_with_elapsed_time(&METRICS.latencies_us.vmm_load_snapshot, load_start_us);
-        info!("'load snapshot' VMM action took {} us.", elapsed_time_us);
+    #[cfg(target_arch = "x86_64")]
+    fn load_snapshot(
+        &mut self,
+        load_params: &LoadSnapshotParams,
+    ) -> result::Result<VmmData, VmmActionError> {
+        let config = RestoreConfig {
+            params: load_params.clone(),
+            keys: self.snapshot_keys.clone(),
+            seccomp_filters: self.seccomp_filters.clone(),
+        };
+        let restored =
+            snapshot::restore(&mut self.event_manager, &config).map_err(VmmActionError::from)?;
+        self.built_vmm = Some(restored.vmm);
+        Ok(restored
+            .ws_stats
+            .map_or(VmmData::Empty, VmmData::WsPrefetch))
+    }
 
-        loaded_vmm
-            .map(|vmm| self.built_vmm = Some(vmm))
-            .map_err(VmmActionError::LoadSnapshot)
+    #[cfg(target_arch = "x86_64")]
+    fn prewarm_snapshot(
+        &mut self,
+        load_params: &LoadSnapshotParams,
+    ) -> result::Result<VmmData, VmmActionError> {
+        let mut load_params = load_params
+            .resolve_mem_backend()
+            .map_err(VmmActionError::MemBackend)?;
+        let prewarm_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+        // A staged ws file is read from the staging directory by the restore.
+        if let Some((path, _)) = ws_staging::stage_ws_file(&load_params) {
+            load_params.ws_file_path = path;
+        }
+        let load_params = &load_params;
+        let page_size = sysconf::page::pagesize() as u64;
+        let stats = page_cache::working_set_ranges(load_params, page_size)
+            .and_then(|files| page_cache::prewarm(&files))
+            .map_err(VmmActionError::PrewarmSnapshot)?;
+        let elapsed_time_us = utils::time::get_time_us(utils::time::ClockType::Monotonic)
+            .saturating_sub(prewarm_start_us);
+        info!(
+            "'prewarm snapshot' VMM action read {} bytes, {} already cached, in {} us.",
+            stats.bytes, stats.resident_bytes, elapsed_time_us
+        );
+        Ok(VmmData::Prewarm(stats))
     }
 }
 
+// Resumes the vCPUs of `vmm`, and notifies the scheduler that the guest runs.
+pub(crate) fn resume_vmm(vmm: &Mutex<Vmm>) -> result::Result<(), VmmError> {
+    let span = RESTORE_TRACE.span(RestorePhase::VcpuResume);
+    probes::fc_probe_resume_start();
+    let result = vmm.lock().expect("Poisoned lock").resume_vcpus();
+    probes::fc_probe_resume_end(result.is_ok());
+    result?;
+    drop(span);
+    LIFECYCLE.notify(LifecycleEvent::GuestResumed);
+    Ok(())
+}
+
 /// Shorthand result type for external VMM commands.
 pub type ActionResult = result::Result<(), VmmActionError>;
 
@@ -342,6 +528,9 @@ pub type ActionResult = result::Result<(), VmmActionError>;
 pub struct RuntimeApiController {
     vmm: Arc<Mutex<Vmm>>,
     vm_config: VmConfig,
+    snapshot_keys: Arc<SnapshotKeys>,
+    #[cfg(target_arch = "x86_64")]
+    scrub_ranges: Vec<ScrubRange>,
 }
 
 impl RuntimeApiController {
@@ -360,13 +549,33 @@ impl RuntimeApiController {
             FlushMetrics => self.flush_metrics().map(|_| VmmData::Empty),
             GetVmConfiguration => Ok(VmmData::MachineConfiguration(self.vm_config.clone())),
             Pause => self.pause().map(|_| VmmData::Empty),
+            ReadGuestMemory(read_params) => self
+                .read_guest_memory(&read_params)
+                .map(VmmData::GuestMemory),
+            ReclaimMemory(reclaim_params) => self
+                .vmm
+                .lock()
+                .expect("Poisoned lock")
+                .reclaim_memory(&reclaim_params)
+                .map(VmmData::MemoryReclaim)
+                .map_err(VmmActionError::MemoryReclaim),
             Resume => self.resume().map(|_| VmmData::Empty),
             #[cfg(target_arch = "x86_64")]
             SendCtrlAltDel => self.send_ctrl_alt_del().map(|_| VmmData::Empty),
+            #[cfg(target_arch = "x86_64")]
+            SetScrubRanges(scrub_ranges_cfg) => self
+                .set_scrub_ranges(scrub_ranges_cfg)
+                .map(|_| VmmData::Empty),
             UpdateBlockDevicePath(drive_id, path_on_host) => self
                 .update_block_device_path(&drive_id, path_on_host)
                 .map(|_| VmmData::Empty)
                 .map_err(VmmActionError::DriveConfig),
+            UpdateLogger(logger_cfg) => vmm_config::logger::update_logger(logger_cfg)
+                .map(|_| VmmData::Empty)
+                .map_err(VmmActionError::Logger),
+            UpdateMetrics(metrics_cfg) => vmm_config::metrics::update_metrics(metrics_cfg)
+                .map(|_| VmmData::Empty)
+                .map_err(VmmActionError::Metrics),
             UpdateNetworkInterface(netif_update) => self
                 .update_net_rate_limiters(netif_update)
                 .map(|_| VmmData::Empty),
@@ -381,7 +590,9 @@ impl RuntimeApiController {
             | SetMmdsConfiguration(_)
             | SetVmConfiguration(_) => Err(VmmActionError::OperationNotSupportedPostBoot),
             #[cfg(target_arch = "x86_64")]
-            LoadSnapshot(_) => Err(VmmActionError::OperationNotSupportedPostBoot),
+            LoadSnapshot(_) | PrewarmSnapshot(_) => {
+                Err(VmmActionError::OperationNotSupportedPostBoot)
+            }
             StartMicroVm => Err(VmmActionError::StartMicrovm(
                 StartMicrovmError::MicroVMAlreadyRunning,
             )),
@@ -389,8 +600,18 @@ impl RuntimeApiController {
     }
 
     /// Creates a new `RuntimeApiController`.
-    pub fn new(vm_config: VmConfig, vmm: Arc<Mutex<Vmm>>) -> Self {
-        Self { vm_config, vmm }
+    pub fn new(
+        vm_config: VmConfig,
+        vmm: Arc<Mutex<Vmm>>,
+        snapshot_keys: Arc<SnapshotKeys>,
+    ) -> Self {
+        Self {
+            vm_config,
+            vmm,
+            snapshot_keys,
+            #[cfg(target_arch = "x86_64")]
+            scrub_ranges: Vec::new(),
+        }
     }
 
     /// Pauses the microVM by pausing the vCPUs.
@@ -414,11 +635,7 @@ impl RuntimeApiController {
     pub fn resume(&mut self) -> ActionResult {
         let resume_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
 
-        self.vmm
-            .lock()
-            .expect("Poisoned lock")
-            .resume_vcpus()
-            .map_err(VmmActionError::InternalVmm)?;
+        resume_vmm(&self.vmm).map_err(VmmActionError::InternalVmm)?;
 
         let elapsed_time_us =
             update_metric_with_elapsed_time(&METRICS.latencies_us.vmm_resume_vm, resume_start_us);
@@ -427,6 +644,19 @@ impl RuntimeApiController {
         Ok(())
     }
 
+    /// Reads a range of the guest memory, for debugging.
+    fn read_guest_memory(
+        &mut self,
+        read_params: &GuestMemoryReadParams,
+    ) -> result::Result<GuestMemoryContent, VmmActionError> {
+        MEMORY_DEBUG
+            .read(
+                self.vmm.lock().expect("Poisoned lock").guest_memory(),
+                read_params,
+            )
+            .map_err(VmmActionError::MemoryDebug)
+    }
+
     /// Write the metrics on user demand (flush). We use the word `flush` here to highlight the fact
     /// that the metrics will be written immediately.
     /// Defer to inner Vmm. We'll move to a variant where the Vmm simply exposes functionality like
@@ -452,34 +682,31 @@ impl RuntimeApiController {
 
     #[cfg(target_arch = "x86_64")]
     fn create_snapshot(&mut self, create_params: &CreateSnapshotParams) -> ActionResult {
-        let mut locked_vmm = self.vmm.lock().unwrap();
-        let create_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+        if self.vm_config.nested_virt == Some(true) {
+            return Err(VmmActionError::CreateSnapshot(
+                CreateSnapshotError::NestedVirtualization,
+            ));
+        }
 
-        persist::create_snapshot(&mut locked_vmm, create_params, VERSION_MAP.clone())
-            .map_err(VmmActionError::CreateSnapshot)?;
+        snapshot::create(
+            &self.vmm,
+            create_params,
+            &self.snapshot_keys,
+            &self.scrub_ranges,
+        )
+        .map_err(VmmActionError::from)
+    }
 
-        match create_params.snapshot_type {
-            SnapshotType::Full => {
-                let elapsed_time_us = update_metric_with_elapsed_time(
-                    &METRICS.latencies_us.vmm_full_create_snapshot,
-                    create_start_us,
-                );
-                info!(
-                    "'create full snapshot' VMM action took {} us.",
-                    elapsed_time_us
-                );
-            }
-            SnapshotType::Diff => {
-                let elapsed_time_us = update_metric_with_elapsed_time(
-                    &METRICS.latencies_us.vmm_diff_create_snapshot,
-                    create_start_us,
-                );
-                info!(
-                    "'create diff snapshot' VMM action took {} us.",
-                    elapsed_time_us
-                );
-            }
-        }
+    /// Replaces the guest memory ranges zeroed in the memory file of the snapshots.
+    #[cfg(target_arch = "x86_64")]
+    fn set_scrub_ranges(&mut self, scrub_ranges_cfg: ScrubRangesConfig) -> ActionResult {
+        self.vmm
+            .lock()
+            .expect("Poisoned lock")
+            .guest_memory()
+            .check_scrub_ranges(&scrub_ranges_cfg.ranges)
+            .map_err(VmmActionError::ScrubRanges)?;
+        self.scrub_ranges = scrub_ranges_cfg.ranges;
         Ok(())
     }
 
@@ -6,6 +6,8 @@ use libc::{_exit, c_int, c_void, siginfo_t, SIGBUS, SIGSEGV, SIGSYS};
 use logger::{error, Metric, METRICS};
 use utils::signal::register_signal_handler;
 
+use crate::backing_files::BACKING_FILES;
+
 // The offset of `si_syscall` (offending syscall identifier) within the siginfo structure
 // expressed as an `(u)int*`.
 // Offset `6` for an `i32` field means that the needed information is located at `6 * sizeof(i32)`.
@@ -15,6 +17,9 @@ const SI_OFF_SYSCALL: isize = 6;
 
 const SYS_SECCOMP_CODE: i32 = 1;
 
+// `si_code` of a `SIGBUS` for an address with no backing, such as past the end of a file.
+const BUS_ADRERR: i32 = 2;
+
 /// Signal handler for `SIGSYS`.
 ///
 /// Increments the `seccomp.num_faults` metric, logs an error message and terminates the process
@@ -51,9 +56,32 @@ extern "C" fn sigsys_handler(num: c_int, info: *mut siginfo_t, _unused: *mut c_v
     };
 }
 
+// Logs the snapshot file mapped at `addr`, if any, and returns whether there is one.
+fn log_backing_file(addr: usize) -> bool {
+    // The lock is only held while a restore records its mappings, rather than wait for it, give
+    // up on the diagnostics.
+    let backing_files = match BACKING_FILES.try_lock() {
+        Ok(backing_files) => backing_files,
+        Err(_) => return false,
+    };
+    match backing_files.find(addr) {
+        Some(backing) => {
+            METRICS.signals.sigbus_snapshot_file.inc();
+            error!(
+                "Shutting down VM after intercepting SIGBUS at {:#x}, mapped from {} at offset \
+                 {:#x}, {} extent {}. The file was truncated or replaced after the restore.",
+                addr, backing.file, backing.file_offset, backing.layer, backing.extent
+            );
+            true
+        }
+        None => false,
+    }
+}
+
 /// Signal handler for `SIGBUS` and `SIGSEGV`.
 ///
-/// Logs an error message and terminates the process with a specific exit code.
+/// Logs an error message and terminates the process with a specific exit code. A `SIGBUS` within
+/// the guest memory mapped from a snapshot file names the file and the extent.
 extern "C" fn sigbus_sigsegv_handler(num: c_int, info: *mut siginfo_t, _unused: *mut c_void) {
     // Safe because we're just reading some fields from a supposedly valid argument.
     let si_signo = unsafe { (*info).si_signo };
@@ -73,10 +101,17 @@ extern "C" fn sigbus_sigsegv_handler(num: c_int, info: *mut siginfo_t, _unused:
         _ => (),
     }
 
-    error!(
-        "Shutting down VM after intercepting signal {}, code {}.",
-        si_signo, si_code
-    );
+    // A file mapping faults past the end of its file, only the kernel reports the address.
+    let snapshot_file = si_signo == SIGBUS
+        && si_code == BUS_ADRERR
+        // Safe because `si_addr` is valid for a `SIGBUS` sent by the kernel.
+        && log_backing_file(unsafe { (*info).si_addr() } as usize);
+    if !snapshot_file {
+        error!(
+            "Shutting down VM after intercepting signal {}, code {}.",
+            si_signo, si_code
+        );
+    }
     // Write the metrics before exiting.
     if let Err(e) = METRICS.write() {
         error!("Failed to write metrics while stopping: {}", e);
@@ -87,6 +122,7 @@ extern "C" fn sigbus_sigsegv_handler(num: c_int, info: *mut siginfo_t, _unused:
     #[cfg(not(test))]
     unsafe {
         _exit(i32::from(match si_signo {
+            SIGBUS if snapshot_file => super::FC_EXIT_CODE_SNAPSHOT_FILE_SIGBUS,
             SIGBUS => super::FC_EXIT_CODE_SIGBUS,
             SIGSEGV => super::FC_EXIT_CODE_SIGSEGV,
             _ => super::FC_EXIT_CODE_UNEXPECTED_ERROR,
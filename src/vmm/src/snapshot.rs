@@ -0,0 +1,281 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Library interface of the snapshot engine.
+//!
+//! Services embedding the VMM create and restore snapshots through [`create`] and [`restore`]
+//! instead of driving a Firecracker process over its API. Both take the parameters of the
+//! matching API requests, and go through the same verification, auditing, tracing and metrics.
+
+// Currently only supports x86_64.
+#![cfg(target_arch = "x86_64")]
+
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+
+use logger::{info, update_metric_with_elapsed_time, METRICS};
+use polly::event_manager::EventManager;
+use utils::time::{get_time_us, ClockType};
+
+use crate::audit::{AuditEvent, AUDIT};
+use crate::lifecycle::{LifecycleEvent, LIFECYCLE};
+use crate::otel::OTEL;
+use crate::persist;
+use crate::restore_profile;
+use crate::rpc_interface::resume_vmm;
+use crate::snapshot_ops::{self, SnapshotOpState, SNAPSHOT_OPS};
+use crate::version_map::VERSION_MAP;
+use crate::Vmm;
+
+pub use crate::default_syscalls::ThreadFilters;
+pub use crate::persist::{CreateSnapshotError, LoadSnapshotError};
+pub use crate::snapshot_signing::SnapshotKeys;
+pub use crate::vmm_config::snapshot::{
+    CreateSnapshotParams, LoadSnapshotParams, MemBackendError, ScrubRange, SnapshotType,
+};
+pub use crate::ws_accounting::WsStats;
+
+/// Errors of the snapshot operations. Variants may be added, so matches on it need a
+/// wildcard arm.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Failed to create the snapshot.
+    Create(CreateSnapshotError),
+    /// The load parameters conflict with each other.
+    InvalidParams(MemBackendError),
+    /// The restore profile named in the load parameters is invalid.
+    Profile(restore_profile::Error),
+    /// Failed to restore the microVM.
+    Restore(LoadSnapshotError),
+    /// Failed to resume the restored microVM.
+    Resume(crate::Error),
+    /// The operation conflicts with the state of the microVM or with another operation.
+    State(snapshot_ops::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Create(err) => write!(f, "Cannot create the snapshot: {}", err),
+            InvalidParams(err) => write!(f, "Invalid load parameters: {}", err),
+            Profile(err) => write!(f, "Invalid restore profile: {}", err),
+            Restore(err) => write!(f, "Cannot restore the snapshot: {}", err),
+            Resume(err) => write!(f, "Cannot resume the restored microVM: {}", err),
+            State(err) => write!(f, "Invalid snapshot operation: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Result of the snapshot operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Configuration of a snapshot restore.
+#[derive(Default)]
+pub struct RestoreConfig {
+    /// Load parameters, as in the body of the `PUT /snapshot/load` request.
+    pub params: LoadSnapshotParams,
+    /// Keys the snapshot files are verified with.
+    pub keys: Arc<SnapshotKeys>,
+    /// Seccomp filters installed on the threads of the restored microVM.
+    pub seccomp_filters: ThreadFilters,
+}
+
+impl RestoreConfig {
+    /// Returns the configuration of an unverified restore with `params`, which installs no
+    /// seccomp filters.
+    pub fn new(params: LoadSnapshotParams) -> Self {
+        RestoreConfig {
+            params,
+            ..Default::default()
+        }
+    }
+}
+
+/// A restored microVM.
+pub struct Restored {
+    /// The microVM, paused unless `resume_vm` was set in the load parameters.
+    pub vmm: Arc<Mutex<Vmm>>,
+    /// Accounting of the working set prefetch, when requested in the load parameters.
+    pub ws_stats: Option<WsStats>,
+}
+
+/// Creates a snapshot of the paused `vmm`, or of the running one for a background snapshot,
+/// which leaves it running. The bytes of guest memory inside `scrub_ranges` are
+/// written as zeros to the memory file, and the snapshot files are signed with `keys`. Fails
+/// while another snapshot is created or loaded.
+pub fn create(
+    vmm: &Mutex<Vmm>,
+    params: &CreateSnapshotParams,
+    keys: &SnapshotKeys,
+    scrub_ranges: &[ScrubRange],
+) -> Result<()> {
+    let _operation = SNAPSHOT_OPS.begin_create().map_err(Error::State)?;
+    let mut locked_vmm = vmm.lock().expect("Poisoned lock");
+    let create_start_us = get_time_us(ClockType::Monotonic);
+    let mut span = OTEL.span("snapshot_create");
+    span.set_attribute("snapshot.path", params.snapshot_path.display().to_string());
+    span.set_attribute(
+        "snapshot.type",
+        match params.snapshot_type {
+            SnapshotType::Full => "full",
+            SnapshotType::Diff => "diff",
+        }
+        .to_string(),
+    );
+
+    let result = persist::create_snapshot(
+        &mut locked_vmm,
+        params,
+        VERSION_MAP.clone(),
+        keys,
+        scrub_ranges,
+    );
+    AUDIT.record(
+        AuditEvent::SnapshotCreate,
+        &AUDIT.create_snapshot_files(params),
+        None,
+        result.as_ref().err().map(ToString::to_string),
+    );
+    if let Err(err) = result.as_ref() {
+        span.set_error(err.to_string());
+    }
+    drop(span);
+    result.map_err(Error::Create)?;
+    LIFECYCLE.notify(LifecycleEvent::SnapshotCreated);
+
+    let (latency, kind) = match params.snapshot_type {
+        SnapshotType::Full => (&METRICS.latencies_us.vmm_full_create_snapshot, "full"),
+        SnapshotType::Diff => (&METRICS.latencies_us.vmm_diff_create_snapshot, "diff"),
+    };
+    let elapsed_time_us = update_metric_with_elapsed_time(latency, create_start_us);
+    info!(
+        "'create {} snapshot' VMM action took {} us.",
+        kind, elapsed_time_us
+    );
+    Ok(())
+}
+
+/// Restores a microVM from the snapshot described by `config`. Its devices are registered
+/// with `event_manager`, which the caller then runs to drive them. Fails once a microVM is
+/// booted or restored, and while another snapshot is loaded or created.
+pub fn restore(event_manager: &mut EventManager, config: &RestoreConfig) -> Result<Restored> {
+    let params = &config
+        .params
+        .resolve_mem_backend()
+        .map_err(Error::InvalidParams)?;
+    let params = &restore_profile::resolve(params).map_err(Error::Profile)?;
+    let mut operation = SNAPSHOT_OPS.begin_load().map_err(Error::State)?;
+    LIFECYCLE.notify(LifecycleEvent::RestoreStarted);
+    let load_start_us = get_time_us(ClockType::Monotonic);
+    let mut span = OTEL.span("snapshot_load");
+    span.set_attribute("snapshot.path", params.snapshot_path.display().to_string());
+    // Inherited file descriptors are consumed by the load, describe them beforehand.
+    let audit_files = AUDIT.load_snapshot_files(params);
+
+    let loaded_vmm = persist::load_snapshot(
+        event_manager,
+        &config.seccomp_filters,
+        params,
+        VERSION_MAP.clone(),
+        &config.keys,
+    );
+    AUDIT.record(
+        AuditEvent::SnapshotLoad,
+        &audit_files,
+        None,
+        loaded_vmm.as_ref().err().map(ToString::to_string),
+    );
+    if let Err(err) = loaded_vmm.as_ref() {
+        span.set_error(err.to_string());
+    }
+    drop(span);
+
+    let elapsed_time_us =
+        update_metric_with_elapsed_time(&METRICS.latencies_us.vmm_load_snapshot, load_start_us);
+    info!("'load snapshot' VMM action took {} us.", elapsed_time_us);
+
+    // The microVM built before the load or its resume failed cannot be torn down.
+    let (vmm, ws_stats) = match loaded_vmm {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            if let LoadSnapshotError::BuildMicroVm(_) = err {
+                operation.complete(SnapshotOpState::Failed);
+            }
+            return Err(Error::Restore(err));
+        }
+    };
+    if params.resume_vm {
+        if let Err(err) = resume_vmm(&vmm) {
+            operation.complete(SnapshotOpState::Failed);
+            return Err(Error::Resume(err));
+        }
+    }
+    operation.complete(SnapshotOpState::Running);
+    Ok(Restored { vmm, ws_stats })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use crate::vmm_config::snapshot::{MemBackendConfig, MemBackendType};
+
+    #[test]
+    fn test_error_display() {
+        let err = Error::Create(CreateSnapshotError::NestedVirtualization);
+        let _ = format!("{}{:?}", err, err);
+
+        let err = Error::InvalidParams(MemBackendError::Conflict("mem_file_fd"));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = Error::Profile(restore_profile::Error::Invalid(
+            PathBuf::from("profile.json"),
+            "numa_node",
+            "node 1 is not online".to_string(),
+        ));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = Error::Restore(LoadSnapshotError::GrowWithUserPageFaults);
+        let _ = format!("{}{:?}", err, err);
+
+        let err = Error::Resume(crate::Error::VcpuResume);
+        let _ = format!("{}{:?}", err, err);
+
+        let err = Error::State(snapshot_ops::Error::AlreadyStarted);
+        let _ = format!("{}{:?}", err, err);
+    }
+
+    #[test]
+    fn test_restore_invalid_params() {
+        let mut event_manager = EventManager::new().unwrap();
+        let config = RestoreConfig::new(LoadSnapshotParams {
+            snapshot_path: PathBuf::from("snapshot"),
+            mem_file_fd: Some(3),
+            mem_backend: Some(MemBackendConfig {
+                backend_type: MemBackendType::File,
+                backend_path: PathBuf::from("mem"),
+            }),
+            ..Default::default()
+        });
+        match restore(&mut event_manager, &config) {
+            Err(Error::InvalidParams(MemBackendError::Conflict("mem_file_fd"))) => (),
+            _ => panic!("Conflicting parameters should be rejected."),
+        }
+
+        // Nothing is restored from missing snapshot files.
+        let config = RestoreConfig::new(LoadSnapshotParams {
+            snapshot_path: PathBuf::from("/no/such/snapshot"),
+            ..Default::default()
+        });
+        match restore(&mut event_manager, &config) {
+            Err(Error::Restore(LoadSnapshotError::SnapshotBackingFile(_))) => (),
+            _ => panic!("Missing snapshot files should be rejected."),
+        }
+    }
+}
@@ -0,0 +1,596 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host-wide cache of the snapshot files in a memory-backed directory, shared by the restores.
+//!
+//! The memory, overlay and ws files of a snapshot are copied once to a directory on a tmpfs or a
+//! ramfs, which keeps them in memory, and the restores of the same snapshot map the copies. A
+//! directory on another file system, a ramdisk included, is rejected rather than letting the
+//! restores map copies which may be written back and read from storage. A
+//! copy is named after the inode, length and modification time of the file, as the staged ws
+//! files are. Each process using a copy holds a shared `flock` of it for as long as it may map
+//! it, which the kernel drops when the process exits, so that the references of a crashed process
+//! do not outlive it; the references are counted from `/proc/locks`. When a file needs room
+//! within the capacity, the least recently used copies no process references are evicted, the
+//! last use of a copy being its access time, set on each use. The copies are added and evicted
+//! under an exclusive `flock` of the directory.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use logger::{info, warn, Metric, METRICS};
+use serde::Serialize;
+
+use crate::prefetch_coordinator::FileLock;
+use crate::vmm_config::snapshot::SnapshotCacheConfig;
+use crate::ws_staging;
+
+/// File listing the `flock`s of the host.
+pub const PROC_LOCKS_PATH: &str = "/proc/locks";
+
+const MIB: u64 = 1 << 20;
+// `f_type` of the file systems keeping their files in memory.
+const TMPFS_MAGIC: u64 = 0x0102_1994;
+const RAMFS_MAGIC: u64 = 0x8584_58f6;
+
+lazy_static! {
+    // References to the copies the restored microVM maps, held until the process exits.
+    static ref REFERENCES: Mutex<Vec<CachedFile>> = Mutex::new(Vec::new());
+}
+
+/// Errors associated with the snapshot cache.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to copy a snapshot file to the cache.
+    Copy(PathBuf, io::Error),
+    /// The file would not fit in the cache, as (file length, bytes left cached, capacity).
+    Full(u64, u64, u64),
+    /// The capacity is zero.
+    InvalidCapacity,
+    /// The capacity, in MiB, does not fit in bytes.
+    CapacityTooLarge(u64),
+    /// The cache directory is not on a tmpfs or a ramfs.
+    NotMemoryBacked(PathBuf),
+    /// Failed to lock a file of the cache.
+    Lock(PathBuf, io::Error),
+    /// Failed to inspect a snapshot file or the cache.
+    Metadata(PathBuf, io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Copy(path, err) => write!(f, "Cannot cache {}: {}", path.display(), err),
+            Full(len, cached, capacity) => write!(
+                f,
+                "Caching {} bytes over the {} bytes cached exceeds the capacity of {} bytes",
+                len, cached, capacity
+            ),
+            InvalidCapacity => write!(f, "The snapshot cache capacity must not be zero"),
+            CapacityTooLarge(capacity_mib) => write!(
+                f,
+                "The snapshot cache capacity of {} MiB is too large",
+                capacity_mib
+            ),
+            NotMemoryBacked(path) => write!(
+                f,
+                "The snapshot cache directory {} is not on a tmpfs or a ramfs",
+                path.display()
+            ),
+            Lock(path, err) => write!(f, "Cannot lock {}: {}", path.display(), err),
+            Metadata(path, err) => write!(f, "Cannot inspect {}: {}", path.display(), err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Copy of a snapshot file in the cache.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CacheEntry {
+    /// Name of the copy in the cache directory.
+    pub name: String,
+    /// Length of the copy, in bytes.
+    pub len: u64,
+    /// Number of references to the copy, held by the processes mapping it.
+    pub references: u64,
+    /// Time of the last use of the copy, in seconds since the epoch.
+    pub last_use_s: i64,
+}
+
+/// Reference to a copy in the cache, which is not evicted while the file, or a clone of it, is
+/// open.
+pub struct CachedFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl CachedFile {
+    /// Returns the path of the copy.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the copy, opened read-only.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+// File of the cache directory, as found when listing it.
+struct DirFile {
+    name: String,
+    path: PathBuf,
+    len: u64,
+    last_use: (i64, i64),
+    lock_id: String,
+}
+
+/// Snapshot files cached in a directory shared by the processes of the host.
+pub struct SnapshotCache {
+    dir: PathBuf,
+    capacity_bytes: u64,
+}
+
+impl SnapshotCache {
+    /// Returns the cache described by `config`. Fails unless its directory is on a tmpfs or a
+    /// ramfs.
+    pub fn new(config: &SnapshotCacheConfig) -> Result<Self> {
+        if config.capacity_mib == 0 {
+            return Err(Error::InvalidCapacity);
+        }
+        let capacity_bytes = config
+            .capacity_mib
+            .checked_mul(MIB)
+            .ok_or(Error::CapacityTooLarge(config.capacity_mib))?;
+        let cache = SnapshotCache {
+            dir: config.dir.clone(),
+            capacity_bytes,
+        };
+        cache.check_memory_backed()?;
+        Ok(cache)
+    }
+
+    /// Returns a reference to the copy of the file at `path`, copying it to the cache first
+    /// unless another process did, and evicting the least recently used copies to make room.
+    pub fn acquire(&self, path: &Path) -> Result<CachedFile> {
+        let metadata = fs::metadata(path).map_err(|e| Error::Metadata(path.to_path_buf(), e))?;
+        let name = ws_staging::staged_name(path, &metadata);
+        let cached_path = self.dir.join(&name);
+        if let Some(cached) = reference(&cached_path, metadata.len())? {
+            METRICS.snapshot.cache_hits.inc();
+            return Ok(cached);
+        }
+
+        let dir = self.open_dir()?;
+        let _lock = FileLock::exclusive(&dir).map_err(|e| Error::Lock(self.dir.clone(), e))?;
+        // Another process may have cached it meanwhile.
+        if let Some(cached) = reference(&cached_path, metadata.len())? {
+            METRICS.snapshot.cache_hits.inc();
+            return Ok(cached);
+        }
+        self.make_room(metadata.len())?;
+
+        // The copy is only renamed in place once complete.
+        let tmp_path = self
+            .dir
+            .join(format!(".{}-{}.tmp", std::process::id(), name));
+        ws_staging::copy(path, &tmp_path, metadata.len())
+            .and_then(|_| fs::rename(&tmp_path, &cached_path))
+            .map_err(|e| {
+                let _ = fs::remove_file(&tmp_path);
+                Error::Copy(path.to_path_buf(), e)
+            })?;
+        METRICS
+            .snapshot
+            .cache_copied_bytes
+            .add(metadata.len() as usize);
+        reference(&cached_path, metadata.len())?.ok_or_else(|| {
+            Error::Copy(path.to_path_buf(), io::Error::from(io::ErrorKind::NotFound))
+        })
+    }
+
+    /// Evicts the least recently used copies no process references, until `bytes` are freed or
+    /// none is left, and returns the bytes freed.
+    pub fn evict(&self, bytes: u64) -> Result<u64> {
+        let dir = self.open_dir()?;
+        let _lock = FileLock::exclusive(&dir).map_err(|e| Error::Lock(self.dir.clone(), e))?;
+        self.evict_unused(bytes)
+    }
+
+    /// Returns the copies in the cache, least recently used first.
+    pub fn entries(&self) -> Result<Vec<CacheEntry>> {
+        let locks = fs::read_to_string(PROC_LOCKS_PATH)
+            .map_err(|e| Error::Metadata(PathBuf::from(PROC_LOCKS_PATH), e))?;
+        let references = shared_locks(&locks);
+        Ok(self
+            .files()?
+            .into_iter()
+            .filter(|file| !file.name.starts_with('.'))
+            .map(|file| CacheEntry {
+                references: references.get(&file.lock_id).copied().unwrap_or(0),
+                name: file.name,
+                len: file.len,
+                last_use_s: file.last_use.0,
+            })
+            .collect())
+    }
+
+    // Fails unless the directory is on a file system keeping its files in memory.
+    fn check_memory_backed(&self) -> Result<()> {
+        let dir = self.open_dir()?;
+        // Safe because the statfs buffer is a local, and the result is checked.
+        let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstatfs(dir.as_raw_fd(), &mut stat) } < 0 {
+            return Err(Error::Metadata(
+                self.dir.clone(),
+                io::Error::last_os_error(),
+            ));
+        }
+        match stat.f_type as u64 {
+            TMPFS_MAGIC | RAMFS_MAGIC => Ok(()),
+            _ => Err(Error::NotMemoryBacked(self.dir.clone())),
+        }
+    }
+
+    fn open_dir(&self) -> Result<File> {
+        File::open(&self.dir).map_err(|e| Error::Metadata(self.dir.clone(), e))
+    }
+
+    // Evicts copies until `len` more bytes fit in the capacity. Called under the lock of the
+    // directory.
+    fn make_room(&self, len: u64) -> Result<()> {
+        let cached: u64 = self.files()?.iter().map(|file| file.len).sum();
+        if len > self.capacity_bytes {
+            return Err(Error::Full(len, cached, self.capacity_bytes));
+        }
+        if cached + len <= self.capacity_bytes {
+            return Ok(());
+        }
+        let needed = cached + len - self.capacity_bytes;
+        let freed = self.evict_unused(needed)?;
+        if freed < needed {
+            return Err(Error::Full(len, cached - freed, self.capacity_bytes));
+        }
+        Ok(())
+    }
+
+    // Evicts the least recently used copies no process references, until `bytes` are freed.
+    // Called under the lock of the directory.
+    fn evict_unused(&self, bytes: u64) -> Result<u64> {
+        let mut freed = 0;
+        // The copies in progress are not evicted.
+        for file in self
+            .files()?
+            .iter()
+            .filter(|file| !file.name.starts_with('.'))
+        {
+            if freed >= bytes {
+                break;
+            }
+            if try_evict(&file.path)? {
+                info!("Evicted {} from the snapshot cache", file.name);
+                METRICS.snapshot.cache_evicted_bytes.add(file.len as usize);
+                freed += file.len;
+            }
+        }
+        Ok(freed)
+    }
+
+    // Lists the files of the directory, including the copies in progress, least recently used
+    // first.
+    fn files(&self) -> Result<Vec<DirFile>> {
+        let dir_error = |e| Error::Metadata(self.dir.clone(), e);
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir).map_err(dir_error)? {
+            let entry = entry.map_err(dir_error)?;
+            let metadata = match entry.metadata() {
+                Ok(metadata) if metadata.is_file() => metadata,
+                // Removed meanwhile, or not a copy.
+                _ => continue,
+            };
+            files.push(DirFile {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                path: entry.path(),
+                len: metadata.len(),
+                last_use: (metadata.atime(), metadata.atime_nsec()),
+                lock_id: lock_id(&metadata),
+            });
+        }
+        files.sort_by_key(|file| file.last_use);
+        Ok(files)
+    }
+}
+
+/// Maps the snapshot file at `path` from the cache of `config`: returns its copy, opened, and
+/// holds the reference to it until the process exits. Returns `None`, counted in the metrics, if
+/// the file could not be cached.
+pub fn open_cached(config: &SnapshotCacheConfig, path: &Path) -> Option<File> {
+    if path.as_os_str().is_empty() {
+        return None;
+    }
+    SnapshotCache::new(config)
+        .and_then(|cache| cache.acquire(path))
+        .and_then(|cached| {
+            let file = cached
+                .file()
+                .try_clone()
+                .map_err(|e| Error::Copy(path.to_path_buf(), e))?;
+            info!(
+                "Mapping {} from {}",
+                path.display(),
+                cached.path().display()
+            );
+            REFERENCES.lock().expect("Poisoned lock").push(cached);
+            Ok(file)
+        })
+        .map_err(|e| {
+            METRICS.snapshot.cache_fails.inc();
+            warn!(
+                "Cannot map {} from the snapshot cache: {}",
+                path.display(),
+                e
+            );
+        })
+        .ok()
+}
+
+// Returns a reference to the copy at `path`, if it is there whole.
+fn reference(path: &Path, len: u64) -> Result<Option<CachedFile>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::Metadata(path.to_path_buf(), e)),
+    };
+    // Safe because `flock` does not modify memory.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH) } < 0 {
+        return Err(Error::Lock(path.to_path_buf(), io::Error::last_os_error()));
+    }
+    let metadata = file
+        .metadata()
+        .map_err(|e| Error::Metadata(path.to_path_buf(), e))?;
+    // The copy may have been evicted between its opening and its locking.
+    if metadata.nlink() == 0 || metadata.len() != len {
+        return Ok(None);
+    }
+    touch(&file);
+    Ok(Some(CachedFile {
+        path: path.to_path_buf(),
+        file,
+    }))
+}
+
+// Sets the access time of `file`, which orders the evictions, to now.
+fn touch(file: &File) {
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_NOW,
+        },
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+    ];
+    // Safe because `times` outlives the call. Only the owner and the writers of the file may set
+    // its times, the uses by other processes leave it where it is in the eviction order.
+    unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) };
+}
+
+// Removes the copy at `path` if no process references it, and returns whether it did.
+fn try_evict(path: &Path) -> Result<bool> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(Error::Metadata(path.to_path_buf(), e)),
+    };
+    // Safe because `flock` does not modify memory. The lock is dropped along with `file`.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::EWOULDBLOCK) => Ok(false),
+            _ => Err(Error::Lock(path.to_path_buf(), err)),
+        };
+    }
+    fs::remove_file(path).map_err(|e| Error::Metadata(path.to_path_buf(), e))?;
+    Ok(true)
+}
+
+// Identifies the file of `metadata` as `/proc/locks` does, by "<major>:<minor>:<inode>".
+fn lock_id(metadata: &Metadata) -> String {
+    let dev = metadata.dev();
+    let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0xfff);
+    let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0xff);
+    format!("{:02x}:{:02x}:{}", major, minor, metadata.ino())
+}
+
+// Counts the shared `flock`s held on each file listed in `locks`, the content of /proc/locks.
+fn shared_locks(locks: &str) -> HashMap<String, u64> {
+    let mut counts = HashMap::new();
+    for line in locks.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // The locks being waited for are listed as "<id>: -> FLOCK ...", and are not held.
+        if fields.len() >= 6 && fields[1] == "FLOCK" && fields[3] == "READ" {
+            *counts.entry(fields[5].to_string()).or_insert(0) += 1;
+        }
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    use utils::tempdir::TempDir;
+    use utils::tempfile::TempFile;
+
+    fn config(dir: &TempDir, capacity_mib: u64) -> SnapshotCacheConfig {
+        SnapshotCacheConfig {
+            dir: dir.as_path().to_path_buf(),
+            capacity_mib,
+        }
+    }
+
+    // The cache directory must be memory-backed.
+    fn shm_dir() -> TempDir {
+        TempDir::new_with_prefix("/dev/shm/snapshot-cache-").unwrap()
+    }
+
+    fn snapshot_file(len: u64) -> TempFile {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(len).unwrap();
+        std::os::unix::fs::FileExt::write_all_at(file.as_file(), b"mem", 0).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_shared_locks() {
+        let locks = "1: FLOCK  ADVISORY  READ  1234 00:2a:17 0 EOF\n\
+                     2: FLOCK  ADVISORY  READ  1235 00:2a:17 0 EOF\n\
+                     2: -> FLOCK  ADVISORY  WRITE 1236 00:2a:17 0 EOF\n\
+                     3: FLOCK  ADVISORY  WRITE 1237 00:2a:18 0 EOF\n\
+                     4: POSIX  ADVISORY  READ  1238 00:2a:19 0 EOF\n";
+        let counts = shared_locks(locks);
+        assert_eq!(counts.len(), 1);
+        assert_eq!(counts["00:2a:17"], 2);
+    }
+
+    #[test]
+    fn test_acquire() {
+        let dir = shm_dir();
+        let cache = SnapshotCache::new(&config(&dir, 1)).unwrap();
+        let mem_file = snapshot_file(0x3000);
+
+        let first = cache.acquire(mem_file.as_path()).unwrap();
+        assert_eq!(first.path().parent().unwrap(), dir.as_path());
+        let mut content = Vec::new();
+        first.file().read_to_end(&mut content).unwrap();
+        assert_eq!(content.len(), 0x3000);
+        assert_eq!(&content[..3], b"mem");
+        // The next restores share the copy.
+        let second = cache.acquire(mem_file.as_path()).unwrap();
+        assert_eq!(second.path(), first.path());
+
+        let entries = cache.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].len, 0x3000);
+        assert_eq!(entries[0].references, 2);
+        drop(first);
+        assert_eq!(cache.entries().unwrap()[0].references, 1);
+
+        // A referenced copy is not evicted.
+        assert_eq!(cache.evict(1).unwrap(), 0);
+        drop(second);
+        assert_eq!(cache.evict(1).unwrap(), 0x3000);
+        assert!(cache.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_make_room() {
+        let dir = shm_dir();
+        let cache = SnapshotCache::new(&config(&dir, 1)).unwrap();
+        let files: Vec<TempFile> = (0..3).map(|_| snapshot_file(400 << 10)).collect();
+
+        let first = cache.acquire(files[0].as_path()).unwrap();
+        let second = cache.acquire(files[1].as_path()).unwrap();
+        // Both copies are referenced, the third file does not fit.
+        match cache.acquire(files[2].as_path()) {
+            Err(Error::Full(len, cached, capacity)) => {
+                assert_eq!((len, cached, capacity), (400 << 10, 800 << 10, 1 << 20))
+            }
+            res => panic!("Unexpected result: {:?}", res.map(|cached| cached.path)),
+        }
+
+        // Only the unreferenced copy is evicted.
+        let first_path = first.path().to_path_buf();
+        drop(first);
+        let third = cache.acquire(files[2].as_path()).unwrap();
+        assert!(!first_path.exists());
+        assert!(second.path().exists());
+        assert!(third.path().exists());
+
+        // A file larger than the cache is never cached.
+        let large = snapshot_file(2 << 20);
+        match cache.acquire(large.as_path()) {
+            Err(Error::Full(len, _, _)) => assert_eq!(len, 2 << 20),
+            res => panic!("Unexpected result: {:?}", res.map(|cached| cached.path)),
+        }
+        assert_eq!(cache.entries().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_open_cached() {
+        let dir = shm_dir();
+        let mem_file = snapshot_file(0x1000);
+        assert!(open_cached(&config(&dir, 1), Path::new("")).is_none());
+        let fails = METRICS.snapshot.cache_fails.count();
+        assert!(open_cached(&config(&dir, 1), Path::new("/no/such/mem_file")).is_none());
+        assert!(METRICS.snapshot.cache_fails.count() > fails);
+
+        let mut file = open_cached(&config(&dir, 1), mem_file.as_path()).unwrap();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+        assert_eq!(&content[..3], b"mem");
+        // The reference is held by the process, even once the file is closed.
+        drop(file);
+        let cache = SnapshotCache::new(&config(&dir, 1)).unwrap();
+        assert_eq!(cache.evict(1).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_errors() {
+        let dir = shm_dir();
+        match SnapshotCache::new(&config(&dir, 0)) {
+            Err(Error::InvalidCapacity) => (),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+        match SnapshotCache::new(&config(&dir, std::u64::MAX)) {
+            Err(Error::CapacityTooLarge(std::u64::MAX)) => (),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+        let disk_dir = TempDir::new_with_prefix("/var/tmp/snapshot-cache-").unwrap();
+        match SnapshotCache::new(&config(&disk_dir, 1)) {
+            Err(Error::NotMemoryBacked(path)) => assert_eq!(path, disk_dir.as_path()),
+            // The test host may keep /var/tmp in memory.
+            Ok(_) => (),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+        let cache = SnapshotCache::new(&config(&dir, 1)).unwrap();
+        match cache.acquire(Path::new("/no/such/mem_file")) {
+            Err(Error::Metadata(path, _)) => assert_eq!(path, PathBuf::from("/no/such/mem_file")),
+            res => panic!("Unexpected result: {:?}", res.map(|cached| cached.path)),
+        }
+
+        let errors = vec![
+            Error::Copy(
+                PathBuf::from("mem"),
+                io::Error::from_raw_os_error(libc::ENOSPC),
+            ),
+            Error::Full(1, 2, 3),
+            Error::InvalidCapacity,
+            Error::CapacityTooLarge(std::u64::MAX),
+            Error::NotMemoryBacked(PathBuf::from("dir")),
+            Error::Lock(
+                PathBuf::from("dir"),
+                io::Error::from_raw_os_error(libc::EBADF),
+            ),
+            Error::Metadata(
+                PathBuf::from("dir"),
+                io::Error::from_raw_os_error(libc::ENOENT),
+            ),
+        ];
+        for err in errors {
+            let _ = format!("{}{:?}", err, err);
+        }
+    }
+}
@@ -0,0 +1,468 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Checks a snapshot bundle end to end without creating a microVM: the microVM state file
+//! deserializes, the memory file matches its regions, the overlay and working set extents are
+//! in range of the guest memory and of their files, and the files match their checksums and the
+//! signed manifest.
+
+// Currently only supports x86_64.
+#![cfg(target_arch = "x86_64")]
+
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use snapshot::Snapshot;
+use versionize::Versionize;
+
+use crate::memory_snapshot::{self, GuestMemoryState};
+use crate::persist::MicrovmState;
+use crate::snapshot_signing::{self, Manifest, SnapshotKeys};
+use crate::version_map::{check_compatibility, VERSION_MAP};
+use crate::vmm_config::snapshot::LoadSnapshotParams;
+
+// Size of the CRC64 optionally following the microVM state.
+const CRC64_SIZE: usize = 8;
+
+/// Layers of the guest memory of a snapshot.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layer {
+    /// The memory file.
+    Memory,
+    /// The overlay file, in the memory file layout.
+    Overlay,
+    /// The working set file, extents stored back to back.
+    WorkingSet,
+}
+
+impl Display for Layer {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Layer::Memory => write!(f, "memory"),
+            Layer::Overlay => write!(f, "overlay"),
+            Layer::WorkingSet => write!(f, "working set"),
+        }
+    }
+}
+
+/// Problem found in a snapshot bundle.
+#[derive(Debug, PartialEq)]
+pub enum Finding {
+    /// A file of the bundle cannot be read.
+    Unreadable(PathBuf, String),
+    /// A file does not match the signed manifest, or the manifest its signature.
+    InvalidSignature(PathBuf, String),
+    /// The microVM state file does not deserialize, or does not match its CRC64.
+    InvalidState(String),
+    /// Two guest memory regions overlap in the memory file, at the given offset.
+    OverlappingRegions(u64),
+    /// A layer file does not have the size its regions or extents require.
+    FileSize(Layer, u64, u64),
+    /// A layer extent, as (memory file page offset, number of pages), is outside of the guest
+    /// memory.
+    ExtentOutOfRange(Layer, i64, i64),
+    /// The snapshot was taken with another page size, as (snapshot, host) page sizes.
+    PageSize(usize, usize),
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Finding::*;
+        match self {
+            Unreadable(path, err) => write!(f, "Cannot read {}: {}", path.display(), err),
+            InvalidSignature(path, err) => {
+                write!(f, "Signature check of {} failed: {}", path.display(), err)
+            }
+            InvalidState(err) => write!(f, "Invalid microVM state: {}", err),
+            OverlappingRegions(offset) => write!(
+                f,
+                "Guest memory regions overlap at memory file offset {:#x}",
+                offset
+            ),
+            FileSize(layer, size, expected) => write!(
+                f,
+                "The {} file has {} bytes instead of {}",
+                layer, size, expected
+            ),
+            ExtentOutOfRange(layer, page, pages) => write!(
+                f,
+                "The {} extent of {} pages at page {} is outside of the guest memory",
+                layer, pages, page
+            ),
+            PageSize(snapshot, host) => write!(
+                f,
+                "The snapshot has {} byte pages, the host has {} byte pages",
+                snapshot, host
+            ),
+        }
+    }
+}
+
+/// Result of the check of a snapshot bundle.
+#[derive(Debug, Default, PartialEq)]
+pub struct CheckReport {
+    /// Data version of the microVM state, if it could be read.
+    pub data_version: Option<u16>,
+    /// Problems found, none for a sound bundle.
+    pub findings: Vec<Finding>,
+}
+
+impl CheckReport {
+    /// Whether no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+/// Checks the snapshot bundle described by `params`, verifying the files against the signed
+/// manifest if `keys` holds a verification key. Inherited file descriptors are not checked, and
+/// an empty path means the layer is not used.
+pub fn check_snapshot(params: &LoadSnapshotParams, keys: &SnapshotKeys) -> CheckReport {
+    let mut report = CheckReport::default();
+
+    let manifest = match keys.manifest(&params.snapshot_path) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            report.findings.push(Finding::InvalidSignature(
+                params.snapshot_path.clone(),
+                e.to_string(),
+            ));
+            None
+        }
+    };
+    let manifest = manifest.as_ref();
+    let memory_state = check_state(&params.snapshot_path, manifest, &mut report);
+    let mem_file_size = check_file(
+        snapshot_signing::Layer::Memory,
+        &params.mem_file_path,
+        manifest,
+        &mut report,
+    );
+    let overlay_file_size = check_file(
+        snapshot_signing::Layer::Overlay,
+        &params.overlay_file_path,
+        manifest,
+        &mut report,
+    );
+    let ws_file_size = check_file(
+        snapshot_signing::Layer::WorkingSet,
+        &params.ws_file_path,
+        manifest,
+        &mut report,
+    );
+    if let Some(manifest) = manifest {
+        let mut used = vec![snapshot_signing::Layer::State];
+        used.extend(mem_file_size.map(|_| snapshot_signing::Layer::Memory));
+        used.extend(overlay_file_size.map(|_| snapshot_signing::Layer::Overlay));
+        used.extend(ws_file_size.map(|_| snapshot_signing::Layer::WorkingSet));
+        if let Err(e) = manifest.check_layout(&used) {
+            report.findings.push(Finding::InvalidSignature(
+                params.snapshot_path.clone(),
+                e.to_string(),
+            ));
+        }
+    }
+    let memory_state = match memory_state {
+        Some(memory_state) => memory_state,
+        None => return report,
+    };
+    // The extents are counted in the pages of the snapshot, checked against the host's.
+    if let Err(memory_snapshot::Error::PageSize(snapshot, host)) = memory_state.check_page_size() {
+        report.findings.push(Finding::PageSize(snapshot, host));
+    }
+    let page_size = memory_state.page_size as u64;
+
+    let expected_mem_size = check_regions(&memory_state, &mut report);
+    if let Some(size) = mem_file_size {
+        if size != expected_mem_size {
+            report
+                .findings
+                .push(Finding::FileSize(Layer::Memory, size, expected_mem_size));
+        }
+    }
+
+    // The overlay file mirrors the memory file layout.
+    let overlay_end = check_extents(
+        Layer::Overlay,
+        params
+            .overlay_regions
+            .iter()
+            .map(|(page, pages)| (*page, *pages)),
+        &memory_state,
+        page_size,
+        &mut report,
+    )
+    .1;
+    if let Some(size) = overlay_file_size {
+        if size < overlay_end {
+            report
+                .findings
+                .push(Finding::FileSize(Layer::Overlay, size, overlay_end));
+        }
+    }
+
+    // The working set file packs the extents back to back.
+    let ws_len = check_extents(
+        Layer::WorkingSet,
+        params.ws_regions.iter().map(|region| {
+            (
+                region.get(0).copied().unwrap_or(-1),
+                region.get(1).copied().unwrap_or(-1),
+            )
+        }),
+        &memory_state,
+        page_size,
+        &mut report,
+    )
+    .0;
+    if let Some(size) = ws_file_size {
+        if size != ws_len {
+            report
+                .findings
+                .push(Finding::FileSize(Layer::WorkingSet, size, ws_len));
+        }
+    }
+
+    report
+}
+
+// Opens the file of `layer` at `path`, verifies it against the `manifest`, and returns its size.
+fn check_file(
+    layer: snapshot_signing::Layer,
+    path: &Path,
+    manifest: Option<&Manifest>,
+    report: &mut CheckReport,
+) -> Option<u64> {
+    if path.as_os_str().is_empty() {
+        return None;
+    }
+    let opened = File::open(path).and_then(|file| {
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    });
+    let (file, size) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            report
+                .findings
+                .push(Finding::Unreadable(path.to_path_buf(), e.to_string()));
+            return None;
+        }
+    };
+    if let Some(Err(e)) = manifest.map(|manifest| manifest.verify_file(layer, path, &file)) {
+        report
+            .findings
+            .push(Finding::InvalidSignature(path.to_path_buf(), e.to_string()));
+    }
+    Some(size)
+}
+
+// Deserializes the microVM state file, checking the CRC64 following it if any, and the
+// `manifest` entry of the very bytes deserialized.
+fn check_state(
+    path: &Path,
+    manifest: Option<&Manifest>,
+    report: &mut CheckReport,
+) -> Option<GuestMemoryState> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            report
+                .findings
+                .push(Finding::Unreadable(path.to_path_buf(), e.to_string()));
+            return None;
+        }
+    };
+    if let Some(Err(e)) =
+        manifest.map(|manifest| manifest.verify_bytes(snapshot_signing::Layer::State, &bytes))
+    {
+        report
+            .findings
+            .push(Finding::InvalidSignature(path.to_path_buf(), e.to_string()));
+    }
+
+    let mut reader = bytes.as_slice();
+    let state = Snapshot::get_data_version(&mut reader)
+        .map_err(|e| format!("{:?}", e))
+        .and_then(|data_version| {
+            report.data_version = Some(data_version);
+            check_compatibility(reader, data_version).map_err(|e| e.to_string())?;
+            MicrovmState::deserialize(&mut reader, &VERSION_MAP, data_version)
+                .map_err(|e| format!("{:?}", snapshot::Error::Versionize(e)))
+        });
+    let state = match state {
+        Ok(state) => state,
+        Err(e) => {
+            report.findings.push(Finding::InvalidState(e));
+            return None;
+        }
+    };
+
+    let trailing = match reader.len() {
+        0 => None,
+        CRC64_SIZE => {
+            Snapshot::load_with_crc64::<_, MicrovmState>(&mut bytes.as_slice(), VERSION_MAP.clone())
+                .err()
+                .map(|e| format!("{:?}", e))
+        }
+        len => Some(format!("{} bytes follow the state", len)),
+    };
+    if let Some(err) = trailing {
+        report.findings.push(Finding::InvalidState(err));
+        return None;
+    }
+    Some(state.memory_state)
+}
+
+// Checks that the regions do not overlap in the memory file, and returns the size of the memory
+// file they require.
+fn check_regions(memory_state: &GuestMemoryState, report: &mut CheckReport) -> u64 {
+    let mut ranges: Vec<(u64, u64)> = memory_state
+        .regions
+        .iter()
+        .map(|region| (region.offset, region.offset + region.size as u64))
+        .collect();
+    ranges.sort();
+    for pair in ranges.windows(2) {
+        if pair[1].0 < pair[0].1 {
+            report.findings.push(Finding::OverlappingRegions(pair[1].0));
+        }
+    }
+    ranges.iter().map(|(_, end)| *end).max().unwrap_or(0)
+}
+
+// Checks that the `extents` of `layer` are inside the guest memory. Returns the total length of
+// the extents and the end of the furthest one, in bytes.
+fn check_extents<I: Iterator<Item = (i64, i64)>>(
+    layer: Layer,
+    extents: I,
+    memory_state: &GuestMemoryState,
+    page_size: u64,
+    report: &mut CheckReport,
+) -> (u64, u64) {
+    let mut len = 0;
+    let mut end = 0;
+    for (page, pages) in extents {
+        let in_range = page >= 0
+            && pages >= 0
+            && memory_state
+                .translate_extent(page as u64 * page_size, pages as u64 * page_size)
+                .is_ok();
+        if !in_range {
+            report
+                .findings
+                .push(Finding::ExtentOutOfRange(layer, page, pages));
+            continue;
+        }
+        len += pages as u64 * page_size;
+        end = std::cmp::max(end, (page + pages) as u64 * page_size);
+    }
+    (len, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use utils::tempfile::TempFile;
+
+    use crate::memory_snapshot::GuestMemoryRegionState;
+
+    fn memory_state() -> GuestMemoryState {
+        GuestMemoryState {
+            regions: vec![
+                GuestMemoryRegionState {
+                    base_address: 0,
+                    size: 0x4000,
+                    offset: 0,
+                },
+                GuestMemoryRegionState {
+                    base_address: 0x1_0000_0000,
+                    size: 0x2000,
+                    offset: 0x4000,
+                },
+            ],
+            page_size: 0x1000,
+        }
+    }
+
+    #[test]
+    fn test_check_regions() {
+        let mut report = CheckReport::default();
+        let mut state = memory_state();
+        assert_eq!(check_regions(&state, &mut report), 0x6000);
+        assert!(report.is_ok());
+
+        state.regions[1].offset = 0x3000;
+        assert_eq!(check_regions(&state, &mut report), 0x5000);
+        assert_eq!(report.findings, vec![Finding::OverlappingRegions(0x3000)]);
+    }
+
+    #[test]
+    fn test_check_extents() {
+        let page_size = 0x1000;
+        let mut report = CheckReport::default();
+        let extents = vec![(4, 2), (1, 2)];
+        assert_eq!(
+            check_extents(
+                Layer::WorkingSet,
+                extents.into_iter(),
+                &memory_state(),
+                page_size,
+                &mut report
+            ),
+            (0x4000, 0x6000)
+        );
+        assert!(report.is_ok());
+
+        let extents = vec![(5, 2), (-1, 1), (0, 1)];
+        assert_eq!(
+            check_extents(
+                Layer::Overlay,
+                extents.into_iter(),
+                &memory_state(),
+                page_size,
+                &mut report
+            ),
+            (0x1000, 0x1000)
+        );
+        assert_eq!(
+            report.findings,
+            vec![
+                Finding::ExtentOutOfRange(Layer::Overlay, 5, 2),
+                Finding::ExtentOutOfRange(Layer::Overlay, -1, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_snapshot() {
+        let params = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("/does/not/exist"),
+            ..Default::default()
+        };
+        let report = check_snapshot(&params, &SnapshotKeys::default());
+        assert_eq!(report.data_version, None);
+        match report.findings.as_slice() {
+            [Finding::Unreadable(path, _)] => assert_eq!(path, &params.snapshot_path),
+            findings => panic!("Unexpected findings: {:?}", findings),
+        }
+
+        let state_file = TempFile::new().unwrap();
+        state_file.as_file().write_all(&[0u8; 16]).unwrap();
+        let mem_file = TempFile::new().unwrap();
+        let params = LoadSnapshotParams {
+            snapshot_path: state_file.as_path().to_path_buf(),
+            mem_file_path: mem_file.as_path().to_path_buf(),
+            ..Default::default()
+        };
+        let report = check_snapshot(&params, &SnapshotKeys::default());
+        match report.findings.as_slice() {
+            [Finding::InvalidState(_)] => (),
+            findings => panic!("Unexpected findings: {:?}", findings),
+        }
+    }
+}
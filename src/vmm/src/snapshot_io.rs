@@ -0,0 +1,370 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Storage backends of the guest memory snapshot files.
+//!
+//! The dumps walk the guest memory once, finding the pages to write, the dirty ones or the ones
+//! to scrub, and hand each run to a writer. With a [`SnapshotWriter`], the runs go to a
+//! [`SnapshotIo`] backend, which only has to read and write bytes at an offset. Backends stack:
+//! [`Checksummed`] records a digest of each block it writes to the backend it wraps, and checks
+//! the blocks it reads back. Compressing, encrypting or remote backends plug in the same way,
+//! implemented by the services embedding the VMM.
+//!
+//! The restore maps the layer files, and the working set prefetch faults their pages in through
+//! the mappings. A backend with a file of its own, such as [`LocalFile`], is mapped as is. The
+//! content of the other ones is [`stage`]d to a memfd first, which then holds the whole layer in
+//! memory.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::FromRawFd;
+use std::sync::Mutex;
+
+use ed25519_dalek::{Digest, Sha512};
+
+/// Size of the reads staging the content of a backend.
+pub const STAGE_CHUNK_SIZE: usize = 1 << 20;
+// Length of the block digests kept by `Checksummed`, a truncated SHA-512.
+const DIGEST_LEN: usize = 32;
+
+/// Storage of a snapshot file.
+pub trait SnapshotIo {
+    /// Reads exactly `buf.len()` bytes at `offset`.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+    /// Writes all of `buf` at `offset`.
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+    /// Returns the size of the content, in bytes.
+    fn size(&self) -> io::Result<u64>;
+    /// Truncates or extends the content to `size` bytes, extended with zeros.
+    fn set_size(&self, size: u64) -> io::Result<()>;
+    /// Makes the writes durable.
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+    /// Returns the file holding the content as is, which the restore maps directly. Backends
+    /// transforming the bytes have none.
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+}
+
+/// Snapshot file on a local file system.
+pub struct LocalFile {
+    file: File,
+}
+
+impl LocalFile {
+    /// Returns the backend storing the content in `file`.
+    pub fn new(file: File) -> Self {
+        LocalFile { file }
+    }
+}
+
+impl SnapshotIo for LocalFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.file.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.file.set_len(size)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        Some(&self.file)
+    }
+}
+
+/// Backend checking the blocks read from `B` against the digests of the blocks written to it.
+/// Blocks without a digest, never written through this backend, are read unchecked.
+pub struct Checksummed<B> {
+    inner: B,
+    block_size: u64,
+    digests: Mutex<HashMap<u64, Vec<u8>>>,
+}
+
+impl<B: SnapshotIo> Checksummed<B> {
+    /// Returns the backend checksumming `inner` in blocks of `block_size` bytes.
+    pub fn new(inner: B, block_size: u64) -> Self {
+        Self::with_digests(inner, block_size, HashMap::new())
+    }
+
+    /// Returns the backend checking the blocks of `inner` against `digests`, by block index, as
+    /// returned by `digests` when they were written.
+    pub fn with_digests(inner: B, block_size: u64, digests: HashMap<u64, Vec<u8>>) -> Self {
+        assert!(block_size > 0, "Checksummed blocks cannot be empty");
+        Checksummed {
+            inner,
+            block_size,
+            digests: Mutex::new(digests),
+        }
+    }
+
+    /// Returns the digests of the blocks written, by block index.
+    pub fn digests(&self) -> HashMap<u64, Vec<u8>> {
+        self.digests.lock().expect("Poisoned lock").clone()
+    }
+
+    /// Returns the wrapped backend.
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+
+    // Reads block `index`, the end of the content cutting the last one short.
+    fn read_block(&self, index: u64, size: u64) -> io::Result<Vec<u8>> {
+        let start = index * self.block_size;
+        let len = std::cmp::min(self.block_size, size.saturating_sub(start));
+        let mut block = vec![0u8; len as usize];
+        self.inner.read_at(&mut block, start)?;
+        Ok(block)
+    }
+
+    // Returns the indices of the blocks holding the `len` bytes at `offset`.
+    fn blocks(&self, offset: u64, len: usize) -> std::ops::Range<u64> {
+        let end = offset + len as u64;
+        offset / self.block_size..(end + self.block_size - 1) / self.block_size
+    }
+}
+
+fn digest(block: &[u8]) -> Vec<u8> {
+    Sha512::digest(block)[..DIGEST_LEN].to_vec()
+}
+
+impl<B: SnapshotIo> SnapshotIo for Checksummed<B> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let size = self.inner.size()?;
+        let digests = self.digests.lock().expect("Poisoned lock");
+        let end = offset + buf.len() as u64;
+        for index in self.blocks(offset, buf.len()) {
+            let block = self.read_block(index, size)?;
+            let start = index * self.block_size;
+            if start + (block.len() as u64) < std::cmp::min(end, start + self.block_size) {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            if let Some(expected) = digests.get(&index) {
+                if digest(&block) != *expected {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("checksum mismatch in the block at offset {:#x}", start),
+                    ));
+                }
+            }
+            // The part of the block within the read.
+            let from = std::cmp::max(offset, start);
+            let to = std::cmp::min(end, start + block.len() as u64);
+            buf[(from - offset) as usize..(to - offset) as usize]
+                .copy_from_slice(&block[(from - start) as usize..(to - start) as usize]);
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.inner.write_at(buf, offset)?;
+        let size = self.inner.size()?;
+        let mut digests = self.digests.lock().expect("Poisoned lock");
+        for index in self.blocks(offset, buf.len()) {
+            let start = index * self.block_size;
+            let block_end = std::cmp::min(start + self.block_size, size);
+            // Blocks written in full are digested from `buf`, the others read back.
+            let entry = if start >= offset && block_end <= offset + buf.len() as u64 {
+                digest(&buf[(start - offset) as usize..(block_end - offset) as usize])
+            } else {
+                digest(&self.read_block(index, size)?)
+            };
+            digests.insert(index, entry);
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.inner.size()
+    }
+
+    fn set_size(&self, size: u64) -> io::Result<()> {
+        self.inner.set_size(size)?;
+        // The block cut by the new end, and the ones past it, no longer match their digests.
+        let mut digests = self.digests.lock().expect("Poisoned lock");
+        let block_size = self.block_size;
+        digests.retain(|index, _| (index + 1) * block_size <= size);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.inner.sync()
+    }
+}
+
+/// Writer over a backend, for the dumps of the guest memory.
+pub struct SnapshotWriter<'a> {
+    io: &'a dyn SnapshotIo,
+    pos: u64,
+}
+
+impl<'a> SnapshotWriter<'a> {
+    /// Returns a writer at the start of the content of `io`.
+    pub fn new(io: &'a dyn SnapshotIo) -> Self {
+        SnapshotWriter { io, pos: 0 }
+    }
+}
+
+impl<'a> Write for SnapshotWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write_at(buf, self.pos)?;
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Seek for SnapshotWriter<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                self.pos = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(delta) => (self.pos, delta),
+            SeekFrom::End(delta) => (self.io.size()?, delta),
+        };
+        let pos = if delta < 0 {
+            base.checked_sub(delta.wrapping_neg() as u64)
+        } else {
+            base.checked_add(delta as u64)
+        };
+        self.pos = pos.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(self.pos)
+    }
+}
+
+// Creates an empty memfd named `name`.
+pub(crate) fn create_memfd(name: &str) -> io::Result<File> {
+    let name =
+        std::ffi::CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // Safe because `name` is a valid C string, and the returned fd is checked.
+    let fd = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because the fd was just created and nothing else owns it.
+    Ok(unsafe { File::from_raw_fd(fd as i32) })
+}
+
+/// Returns a file holding the content of `io` for the restore to map: the file of `io` if it has
+/// one, or else a memfd named `name` the content is copied to.
+pub fn stage(io: &dyn SnapshotIo, name: &str) -> io::Result<File> {
+    if let Some(file) = io.as_file() {
+        return file.try_clone();
+    }
+    let memfd = create_memfd(name)?;
+    let size = io.size()?;
+    memfd.set_len(size)?;
+    let mut buf = vec![0u8; STAGE_CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < size {
+        let len = std::cmp::min(STAGE_CHUNK_SIZE as u64, size - offset) as usize;
+        io.read_at(&mut buf[..len], offset)?;
+        memfd.write_all_at(&buf[..len], offset)?;
+        offset += len as u64;
+    }
+    Ok(memfd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempfile::TempFile;
+
+    fn local_file() -> (TempFile, LocalFile) {
+        let file = TempFile::new().unwrap();
+        let io = LocalFile::new(file.as_file().try_clone().unwrap());
+        (file, io)
+    }
+
+    #[test]
+    fn test_snapshot_writer() {
+        let (_file, io) = local_file();
+        let mut writer = SnapshotWriter::new(&io);
+        writer.write_all(&[1u8; 16]).unwrap();
+        assert_eq!(writer.seek(SeekFrom::Current(-8)).unwrap(), 8);
+        writer.write_all(&[2u8; 4]).unwrap();
+        assert_eq!(writer.seek(SeekFrom::End(4)).unwrap(), 20);
+        writer.write_all(&[3u8; 4]).unwrap();
+        assert!(writer.seek(SeekFrom::Current(-25)).is_err());
+
+        let mut content = vec![0u8; 24];
+        io.read_at(&mut content, 0).unwrap();
+        assert_eq!(&content[..8], &[1u8; 8]);
+        assert_eq!(&content[8..12], &[2u8; 4]);
+        assert_eq!(&content[12..16], &[1u8; 4]);
+        assert_eq!(&content[16..20], &[0u8; 4]);
+        assert_eq!(&content[20..], &[3u8; 4]);
+    }
+
+    #[test]
+    fn test_checksummed() {
+        let (file, io) = local_file();
+        let io = Checksummed::new(io, 8);
+        io.set_size(20).unwrap();
+        // Full blocks, and the blocks written in part.
+        io.write_at(&[1u8; 16], 0).unwrap();
+        io.write_at(&[2u8; 6], 12).unwrap();
+        assert_eq!(io.digests().len(), 3);
+
+        let mut buf = vec![0u8; 10];
+        io.read_at(&mut buf, 6).unwrap();
+        assert_eq!(&buf[..6], &[1u8; 6]);
+        assert_eq!(&buf[6..], &[2u8; 4]);
+        // Reads past the end fail.
+        assert!(io.read_at(&mut buf, 16).is_err());
+
+        // The blocks changed behind the backend fail their check, the others still read.
+        file.as_file().write_all_at(&[9u8], 9).unwrap();
+        let err = io.read_at(&mut buf[..4], 8).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        io.read_at(&mut buf[..4], 16).unwrap();
+        assert_eq!(&buf[..4], &[2u8; 4]);
+
+        // The digests check the content once reopened.
+        let digests = io.digests();
+        let io = Checksummed::with_digests(io.into_inner(), 8, digests);
+        assert!(io.read_at(&mut buf[..1], 15).is_err());
+        io.set_size(8).unwrap();
+        assert_eq!(io.digests().len(), 1);
+    }
+
+    #[test]
+    fn test_stage() {
+        let (_file, io) = local_file();
+        io.write_at(&[5u8; 8], 0).unwrap();
+        // Local files are mapped as they are.
+        let staged = stage(&io, "mem").unwrap();
+        assert_eq!(staged.metadata().unwrap().len(), 8);
+
+        // The content of the other backends is copied.
+        let io = Checksummed::new(io, 4);
+        let content = vec![7u8; STAGE_CHUNK_SIZE + 4];
+        io.write_at(&content, 8).unwrap();
+        let staged = stage(&io, "mem").unwrap();
+        let mut buf = vec![0u8; content.len() + 8];
+        staged.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf[..8], &[5u8; 8]);
+        assert!(buf[8..].iter().all(|&b| b == 7));
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! State machine of the operations building the microVM or snapshotting it.
+//!
+//! A process runs a single microVM, booted or restored once. Booting, loading a snapshot and
+//! creating one go through `SNAPSHOT_OPS`, which rejects the operations conflicting with the
+//! state of the microVM or with another operation in flight, instead of letting them run over
+//! each other. A failed boot or load leaves the process idle, so that it can be retried, unless
+//! the load failed after building the microVM.
+
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// State of the snapshot operations of the process.
+    pub static ref SNAPSHOT_OPS: SnapshotOps = SnapshotOps::new();
+}
+
+/// States of the microVM, as seen by the snapshot operations.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SnapshotOpState {
+    /// No microVM was booted or restored yet.
+    Idle,
+    /// The microVM is booting.
+    Booting,
+    /// A snapshot is loading.
+    Loading,
+    /// The microVM was booted or restored.
+    Running,
+    /// A snapshot of the microVM is being created.
+    Creating,
+    /// A load failed once it started building the microVM.
+    Failed,
+}
+
+impl Display for SnapshotOpState {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::SnapshotOpState::*;
+        match self {
+            Idle => write!(f, "idle"),
+            Booting => write!(f, "booting"),
+            Loading => write!(f, "loading a snapshot"),
+            Running => write!(f, "running"),
+            Creating => write!(f, "creating a snapshot"),
+            Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// Errors of the snapshot operations conflicting with the state of the microVM.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// Another operation is in flight, in the given state.
+    InFlight(SnapshotOpState),
+    /// The microVM is already booted or restored.
+    AlreadyStarted,
+    /// A previous load failed after building the microVM.
+    PreviousFailure,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            InFlight(state) => write!(f, "The microVM is {}, retry once it is done", state),
+            AlreadyStarted => write!(f, "The microVM is already booted or restored"),
+            PreviousFailure => write!(
+                f,
+                "A previous snapshot load failed after building the microVM"
+            ),
+        }
+    }
+}
+
+/// Result of the state checks of the snapshot operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// State of the snapshot operations.
+pub struct SnapshotOps {
+    state: Mutex<SnapshotOpState>,
+}
+
+/// Operation in flight, moving the state on when dropped: back to where it started, unless the
+/// operation completed to another state.
+pub struct Operation<'a> {
+    ops: &'a SnapshotOps,
+    next: SnapshotOpState,
+}
+
+impl Operation<'_> {
+    /// Moves the state to `state` once the operation is over.
+    pub fn complete(&mut self, state: SnapshotOpState) {
+        self.next = state;
+    }
+}
+
+impl Drop for Operation<'_> {
+    fn drop(&mut self) {
+        *self.ops.state.lock().expect("Poisoned lock") = self.next;
+    }
+}
+
+impl SnapshotOps {
+    fn new() -> Self {
+        SnapshotOps {
+            state: Mutex::new(SnapshotOpState::Idle),
+        }
+    }
+
+    /// Returns the current state.
+    pub fn state(&self) -> SnapshotOpState {
+        *self.state.lock().expect("Poisoned lock")
+    }
+
+    // Moves to `in_flight` from the states accepted by `check`, until the returned operation is
+    // dropped.
+    fn begin<F>(&self, in_flight: SnapshotOpState, check: F) -> Result<Operation>
+    where
+        F: Fn(SnapshotOpState) -> Result<()>,
+    {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        match *state {
+            SnapshotOpState::Booting | SnapshotOpState::Loading | SnapshotOpState::Creating => {
+                return Err(Error::InFlight(*state))
+            }
+            SnapshotOpState::Failed => return Err(Error::PreviousFailure),
+            current => check(current)?,
+        }
+        let previous = *state;
+        *state = in_flight;
+        Ok(Operation {
+            ops: self,
+            next: previous,
+        })
+    }
+
+    /// Starts booting the microVM, which must not be started yet.
+    pub fn begin_boot(&self) -> Result<Operation> {
+        self.begin(SnapshotOpState::Booting, check_not_started)
+    }
+
+    /// Starts loading a snapshot, which requires the microVM not to be started yet.
+    pub fn begin_load(&self) -> Result<Operation> {
+        self.begin(SnapshotOpState::Loading, check_not_started)
+    }
+
+    /// Starts creating a snapshot. The microVM may have been built without going through
+    /// `SNAPSHOT_OPS`, so only the operations in flight and the failures are rejected.
+    pub fn begin_create(&self) -> Result<Operation> {
+        self.begin(SnapshotOpState::Creating, |_| Ok(()))
+    }
+}
+
+fn check_not_started(state: SnapshotOpState) -> Result<()> {
+    match state {
+        SnapshotOpState::Running => Err(Error::AlreadyStarted),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transitions() {
+        let ops = SnapshotOps::new();
+        assert_eq!(ops.state(), SnapshotOpState::Idle);
+
+        // A failed load goes back to idle, and rejects the other operations meanwhile.
+        let load = ops.begin_load().unwrap();
+        assert_eq!(ops.state(), SnapshotOpState::Loading);
+        assert_eq!(
+            ops.begin_load().err(),
+            Some(Error::InFlight(SnapshotOpState::Loading))
+        );
+        assert_eq!(
+            ops.begin_create().err(),
+            Some(Error::InFlight(SnapshotOpState::Loading))
+        );
+        assert_eq!(
+            ops.begin_boot().err(),
+            Some(Error::InFlight(SnapshotOpState::Loading))
+        );
+        drop(load);
+        assert_eq!(ops.state(), SnapshotOpState::Idle);
+
+        // A successful load leaves the microVM running, which cannot boot or load again.
+        let mut load = ops.begin_load().unwrap();
+        load.complete(SnapshotOpState::Running);
+        drop(load);
+        assert_eq!(ops.state(), SnapshotOpState::Running);
+        assert_eq!(ops.begin_load().err(), Some(Error::AlreadyStarted));
+        assert_eq!(ops.begin_boot().err(), Some(Error::AlreadyStarted));
+
+        // Creating a snapshot goes back to running, successful or not.
+        let create = ops.begin_create().unwrap();
+        assert_eq!(
+            ops.begin_create().err(),
+            Some(Error::InFlight(SnapshotOpState::Creating))
+        );
+        drop(create);
+        assert_eq!(ops.state(), SnapshotOpState::Running);
+
+        // A partly built microVM rejects every operation.
+        let ops = SnapshotOps::new();
+        let mut load = ops.begin_load().unwrap();
+        load.complete(SnapshotOpState::Failed);
+        drop(load);
+        assert_eq!(ops.begin_load().err(), Some(Error::PreviousFailure));
+        assert_eq!(ops.begin_create().err(), Some(Error::PreviousFailure));
+    }
+
+    #[test]
+    fn test_error_display() {
+        assert_eq!(
+            Error::InFlight(SnapshotOpState::Loading).to_string(),
+            "The microVM is loading a snapshot, retry once it is done"
+        );
+        let _ = format!("{}{:?}", Error::AlreadyStarted, Error::AlreadyStarted);
+        let _ = format!("{}{:?}", Error::PreviousFailure, Error::PreviousFailure);
+    }
+}
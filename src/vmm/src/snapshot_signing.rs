@@ -0,0 +1,478 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Signs the files of a snapshot when creating it and verifies them before loading it.
+//!
+//! The files are not signed one by one, which would let the memory file of a snapshot be loaded
+//! along with the state file of another one, or a layer be left out. Instead, a manifest lists
+//! the layer, size and SHA-512 digest of each file of the snapshot, and is written next to the
+//! microVM state file, in `<state path>.manifest`. The manifest is signed with ed25519ph over the
+//! SHA-512 digest of its contents, and the 64 bytes signature is stored in
+//! `<state path>.manifest.sig`.
+
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{Digest, Keypair, PublicKey, SecretKey, Sha512, Signature, SignatureError};
+use serde::{Deserialize, Serialize};
+
+// Domain separation context of the ed25519ph signatures.
+const SIGNATURE_CONTEXT: &[u8] = b"firecracker-snapshot";
+const MANIFEST_EXTENSION: &str = ".manifest";
+const SIGNATURE_EXTENSION: &str = ".sig";
+const MANIFEST_VERSION: u32 = 1;
+const DIGEST_CHUNK_SIZE: usize = 1 << 20;
+
+/// Role of a file in a snapshot.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layer {
+    /// The microVM state file.
+    State,
+    /// The guest memory file.
+    Memory,
+    /// The overlay file.
+    Overlay,
+    /// The working set file.
+    WorkingSet,
+}
+
+impl Display for Layer {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Layer::State => write!(f, "microVM state"),
+            Layer::Memory => write!(f, "memory"),
+            Layer::Overlay => write!(f, "overlay"),
+            Layer::WorkingSet => write!(f, "working set"),
+        }
+    }
+}
+
+/// Errors associated with signing and verifying snapshot files.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to read a file while computing its digest.
+    Digest(PathBuf, io::Error),
+    /// The key file does not contain a valid ed25519 key.
+    InvalidKey(PathBuf),
+    /// The manifest cannot be parsed.
+    InvalidManifest(PathBuf, String),
+    /// The signature does not match the manifest.
+    InvalidSignature(PathBuf),
+    /// The file of a layer does not match its size or digest in the manifest.
+    LayerMismatch(Layer),
+    /// A layer of the manifest is not loaded.
+    MissingLayer(Layer),
+    /// Failed to read a key file.
+    ReadKey(PathBuf, io::Error),
+    /// Failed to read the manifest.
+    ReadManifest(PathBuf, io::Error),
+    /// Failed to read a signature file.
+    ReadSignature(PathBuf, io::Error),
+    /// Failed to sign the manifest.
+    Sign(PathBuf, SignatureError),
+    /// A loaded layer is not listed in the manifest.
+    UnsignedLayer(Layer),
+    /// Failed to write the manifest.
+    WriteManifest(PathBuf, io::Error),
+    /// Failed to write a signature file.
+    WriteSignature(PathBuf, io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Digest(path, err) => write!(f, "Cannot read {}: {}", path.display(), err),
+            InvalidKey(path) => write!(f, "{} is not a valid ed25519 key", path.display()),
+            InvalidManifest(path, err) => {
+                write!(f, "Invalid snapshot manifest {}: {}", path.display(), err)
+            }
+            InvalidSignature(path) => write!(f, "Invalid signature for {}", path.display()),
+            LayerMismatch(layer) => {
+                write!(f, "The {} file does not match the snapshot manifest", layer)
+            }
+            MissingLayer(layer) => write!(
+                f,
+                "The {} file of the snapshot manifest is not loaded",
+                layer
+            ),
+            ReadKey(path, err) => write!(f, "Cannot read key {}: {}", path.display(), err),
+            ReadManifest(path, err) => {
+                write!(f, "Cannot read manifest {}: {}", path.display(), err)
+            }
+            ReadSignature(path, err) => {
+                write!(f, "Cannot read signature {}: {}", path.display(), err)
+            }
+            Sign(path, err) => write!(f, "Cannot sign {}: {}", path.display(), err),
+            UnsignedLayer(layer) => {
+                write!(f, "The snapshot manifest does not list a {} file", layer)
+            }
+            WriteManifest(path, err) => {
+                write!(f, "Cannot write manifest {}: {}", path.display(), err)
+            }
+            WriteSignature(path, err) => {
+                write!(f, "Cannot write signature {}: {}", path.display(), err)
+            }
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Keys used to sign the created snapshots and to verify the loaded ones.
+#[derive(Debug, Default)]
+pub struct SnapshotKeys {
+    signing_key: Option<Keypair>,
+    verification_key: Option<PublicKey>,
+}
+
+impl SnapshotKeys {
+    /// Reads the raw 32 bytes ed25519 secret key used for signing and the raw 32 bytes ed25519
+    /// public key used for verification. Signing, respectively verification, is disabled when
+    /// the corresponding key is not provided.
+    pub fn from_files(
+        signing_key_path: Option<&Path>,
+        verification_key_path: Option<&Path>,
+    ) -> Result<Self> {
+        let signing_key = match signing_key_path {
+            Some(path) => {
+                let secret = SecretKey::from_bytes(&read_key(path)?)
+                    .map_err(|_| Error::InvalidKey(path.to_path_buf()))?;
+                let public = PublicKey::from(&secret);
+                Some(Keypair { secret, public })
+            }
+            None => None,
+        };
+        let verification_key = match verification_key_path {
+            Some(path) => Some(
+                PublicKey::from_bytes(&read_key(path)?)
+                    .map_err(|_| Error::InvalidKey(path.to_path_buf()))?,
+            ),
+            None => None,
+        };
+
+        Ok(SnapshotKeys {
+            signing_key,
+            verification_key,
+        })
+    }
+
+    /// Returns whether a signing key is configured.
+    pub fn signs(&self) -> bool {
+        self.signing_key.is_some()
+    }
+
+    /// Writes and signs the manifest of the snapshot whose microVM state file is at
+    /// `state_path`, listing the `files` of each layer, if a signing key is configured.
+    pub fn sign(&self, state_path: &Path, files: &[(Layer, &Path)]) -> Result<()> {
+        let keypair = match self.signing_key.as_ref() {
+            Some(keypair) => keypair,
+            None => return Ok(()),
+        };
+
+        let mut manifest = Manifest {
+            version: MANIFEST_VERSION,
+            files: Vec::with_capacity(files.len()),
+        };
+        for (layer, path) in files.iter() {
+            let file = File::open(path).map_err(|e| Error::Digest(path.to_path_buf(), e))?;
+            let (hasher, size) = digest_with_len(path, &file)?;
+            manifest.files.push(ManifestFile {
+                layer: *layer,
+                size,
+                sha512: encode_hex(&hasher.finalize()),
+            });
+        }
+        let manifest_path = manifest_path(state_path);
+        let bytes = serde_json::to_vec(&manifest).expect("The manifest is serializable");
+        let signature = keypair
+            .sign_prehashed(Sha512::new().chain(&bytes), Some(SIGNATURE_CONTEXT))
+            .map_err(|e| Error::Sign(manifest_path.clone(), e))?;
+        fs::write(&manifest_path, &bytes)
+            .map_err(|e| Error::WriteManifest(manifest_path.clone(), e))?;
+        let signature_path = signature_path(&manifest_path);
+        fs::write(&signature_path, &signature.to_bytes()[..])
+            .map_err(|e| Error::WriteSignature(signature_path, e))
+    }
+
+    /// Reads the manifest of the snapshot whose microVM state file is at `state_path`, and checks
+    /// it against its signature. Returns `None` if no verification key is configured.
+    pub fn manifest(&self, state_path: &Path) -> Result<Option<Manifest>> {
+        let public_key = match self.verification_key.as_ref() {
+            Some(public_key) => public_key,
+            None => return Ok(None),
+        };
+
+        let manifest_path = manifest_path(state_path);
+        let bytes =
+            fs::read(&manifest_path).map_err(|e| Error::ReadManifest(manifest_path.clone(), e))?;
+        let signature_path = signature_path(&manifest_path);
+        let signature_bytes = fs::read(&signature_path)
+            .map_err(|e| Error::ReadSignature(signature_path.clone(), e))?;
+        let signature = Signature::try_from(&signature_bytes[..])
+            .map_err(|_| Error::InvalidSignature(manifest_path.clone()))?;
+        public_key
+            .verify_prehashed(
+                Sha512::new().chain(&bytes),
+                Some(SIGNATURE_CONTEXT),
+                &signature,
+            )
+            .map_err(|_| Error::InvalidSignature(manifest_path.clone()))?;
+        // Only parsed once authenticated.
+        let manifest: Manifest = serde_json::from_slice(&bytes)
+            .map_err(|e| Error::InvalidManifest(manifest_path.clone(), e.to_string()))?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(Error::InvalidManifest(
+                manifest_path,
+                format!("unsupported version {}", manifest.version),
+            ));
+        }
+        Ok(Some(manifest))
+    }
+}
+
+/// Layers of a snapshot, along with the size and digest of their files, as signed when the
+/// snapshot was created.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    version: u32,
+    files: Vec<ManifestFile>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestFile {
+    layer: Layer,
+    size: u64,
+    sha512: String,
+}
+
+impl Manifest {
+    /// Checks `file`, opened from `path`, against the entry of `layer`. An empty `path` means the
+    /// file was passed as a file descriptor.
+    pub fn verify_file(&self, layer: Layer, path: &Path, file: &File) -> Result<()> {
+        let entry = self.entry(layer)?;
+        let (hasher, size) = digest_with_len(path, file)?;
+        if size != entry.size || encode_hex(&hasher.finalize()) != entry.sha512 {
+            return Err(Error::LayerMismatch(layer));
+        }
+        Ok(())
+    }
+
+    /// Checks `bytes`, the contents of the file of `layer`, against its entry. The very bytes
+    /// that are checked are the ones to use afterwards, the file may have changed since.
+    pub fn verify_bytes(&self, layer: Layer, bytes: &[u8]) -> Result<()> {
+        let entry = self.entry(layer)?;
+        if bytes.len() as u64 != entry.size || encode_hex(&Sha512::digest(bytes)) != entry.sha512 {
+            return Err(Error::LayerMismatch(layer));
+        }
+        Ok(())
+    }
+
+    /// Checks that the `loaded` layers include all of the layers of the manifest.
+    pub fn check_layout(&self, loaded: &[Layer]) -> Result<()> {
+        match self.files.iter().find(|file| !loaded.contains(&file.layer)) {
+            Some(file) => Err(Error::MissingLayer(file.layer)),
+            None => Ok(()),
+        }
+    }
+
+    fn entry(&self, layer: Layer) -> Result<&ManifestFile> {
+        self.files
+            .iter()
+            .find(|file| file.layer == layer)
+            .ok_or(Error::UnsignedLayer(layer))
+    }
+}
+
+fn read_key(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|e| Error::ReadKey(path.to_path_buf(), e))
+}
+
+fn manifest_path(state_path: &Path) -> PathBuf {
+    let mut manifest_path = OsString::from(state_path.as_os_str());
+    manifest_path.push(MANIFEST_EXTENSION);
+    PathBuf::from(manifest_path)
+}
+
+fn signature_path(path: &Path) -> PathBuf {
+    let mut signature_path = OsString::from(path.as_os_str());
+    signature_path.push(SIGNATURE_EXTENSION);
+    PathBuf::from(signature_path)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub(crate) fn digest(path: &Path, file: &File) -> Result<Sha512> {
+    digest_with_len(path, file).map(|(hasher, _)| hasher)
+}
+
+// Reads at explicit offsets, inherited file descriptors may not be at the start of the file.
+// Returns the length read along with the digest, rather than querying the size of the file.
+fn digest_with_len(path: &Path, file: &File) -> Result<(Sha512, u64)> {
+    let mut hasher = Sha512::new();
+    let mut buf = vec![0u8; DIGEST_CHUNK_SIZE];
+    let mut offset = 0;
+    loop {
+        let count = file
+            .read_at(&mut buf, offset)
+            .map_err(|e| Error::Digest(path.to_path_buf(), e))?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buf[..count]);
+        offset += count as u64;
+    }
+    Ok((hasher, offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use utils::tempfile::TempFile;
+
+    fn key_files() -> (TempFile, TempFile) {
+        let secret = SecretKey::from_bytes(&[7u8; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        let secret_file = TempFile::new().unwrap();
+        secret_file.as_file().write_all(secret.as_bytes()).unwrap();
+        let public_file = TempFile::new().unwrap();
+        public_file.as_file().write_all(public.as_bytes()).unwrap();
+        (secret_file, public_file)
+    }
+
+    #[test]
+    fn test_signature_path() {
+        assert_eq!(
+            signature_path(Path::new("/srv/snapshot.mem")),
+            PathBuf::from("/srv/snapshot.mem.sig")
+        );
+    }
+
+    #[test]
+    fn test_manifest_path() {
+        assert_eq!(
+            manifest_path(Path::new("/srv/snapshot.state")),
+            PathBuf::from("/srv/snapshot.state.manifest")
+        );
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let (secret_file, public_file) = key_files();
+        let keys =
+            SnapshotKeys::from_files(Some(secret_file.as_path()), Some(public_file.as_path()))
+                .unwrap();
+
+        let state_file = TempFile::new().unwrap();
+        let state_path = state_file.as_path().to_path_buf();
+        state_file.as_file().write_all(&[0x55; 512]).unwrap();
+        let mem_file = TempFile::new().unwrap();
+        let mem_path = mem_file.as_path().to_path_buf();
+        mem_file.as_file().write_all(&[0xaa; 4096]).unwrap();
+        keys.sign(
+            &state_path,
+            &[
+                (Layer::State, state_path.as_path()),
+                (Layer::Memory, mem_path.as_path()),
+            ],
+        )
+        .unwrap();
+        let manifest_path = manifest_path(&state_path);
+        assert!(signature_path(&manifest_path).exists());
+
+        let manifest = keys.manifest(&state_path).unwrap().unwrap();
+        manifest
+            .verify_bytes(Layer::State, &fs::read(&state_path).unwrap())
+            .unwrap();
+        // Files passed as file descriptors are checked the same way.
+        manifest
+            .verify_file(Layer::Memory, Path::new(""), mem_file.as_file())
+            .unwrap();
+        manifest
+            .check_layout(&[Layer::State, Layer::Memory])
+            .unwrap();
+
+        // The files cannot be swapped, nor unlisted layers be added or listed ones left out.
+        match manifest.verify_file(Layer::State, &mem_path, mem_file.as_file()) {
+            Err(Error::LayerMismatch(Layer::State)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match manifest.verify_file(Layer::Overlay, &mem_path, mem_file.as_file()) {
+            Err(Error::UnsignedLayer(Layer::Overlay)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match manifest.check_layout(&[]) {
+            Err(Error::MissingLayer(Layer::State)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match manifest.check_layout(&[Layer::State]) {
+            Err(Error::MissingLayer(Layer::Memory)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // Tamper with a file.
+        mem_file.as_file().write_all(&[0xbb]).unwrap();
+        match manifest.verify_file(Layer::Memory, &mem_path, mem_file.as_file()) {
+            Err(Error::LayerMismatch(Layer::Memory)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // Tamper with the manifest.
+        let mut bytes = fs::read(&manifest_path).unwrap();
+        bytes[0] = b' ';
+        fs::write(&manifest_path, &bytes).unwrap();
+        match keys.manifest(&state_path) {
+            Err(Error::InvalidSignature(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        fs::remove_file(signature_path(&manifest_path)).unwrap();
+        match keys.manifest(&state_path) {
+            Err(Error::ReadSignature(_, _)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        fs::remove_file(&manifest_path).unwrap();
+        match keys.manifest(&state_path) {
+            Err(Error::ReadManifest(_, _)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_no_keys() {
+        let keys = SnapshotKeys::default();
+
+        let state_file = TempFile::new().unwrap();
+        let path = state_file.as_path().to_path_buf();
+        keys.sign(&path, &[(Layer::State, path.as_path())]).unwrap();
+        assert!(!manifest_path(&path).exists());
+        assert!(keys.manifest(&path).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_invalid_key() {
+        let key_file = TempFile::new().unwrap();
+        key_file.as_file().write_all(&[1, 2, 3]).unwrap();
+        match SnapshotKeys::from_files(Some(key_file.as_path()), None) {
+            Err(Error::InvalidKey(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match SnapshotKeys::from_files(None, Some(Path::new("/does/not/exist"))) {
+            Err(Error::ReadKey(_, _)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
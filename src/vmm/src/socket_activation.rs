@@ -0,0 +1,290 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Listening sockets passed by systemd socket activation.
+//!
+//! systemd passes the sockets of an activated service as the file descriptors from 3 on, with
+//! their count in `LISTEN_FDS`, the PID they are meant for in `LISTEN_PID`, and the
+//! `FileDescriptorName=` of their socket units in `LISTEN_FDNAMES`. The socket named `api` is
+//! served by the API server instead of binding `--api-sock`, and the socket named `uffd` is the
+//! one the user page fault handler connects to instead of `sock_file_path`. A single socket
+//! without a name is the API socket.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::mem;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use logger::warn;
+use utils::syscall::SyscallReturnCode;
+
+/// Name of the activated API socket.
+pub const API_SOCKET_NAME: &str = "api";
+/// Name of the activated socket the user page fault handler connects to.
+pub const UFFD_SOCKET_NAME: &str = "uffd";
+
+// First file descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+// Name of the sockets of the units without `FileDescriptorName=`.
+const UNNAMED_SOCKET: &str = "unknown";
+
+lazy_static! {
+    /// Activated sockets of the process. Empty until it is initialized.
+    pub static ref SOCKET_ACTIVATION: ActivatedSockets = ActivatedSockets::default();
+}
+
+/// Errors associated with the socket activation.
+#[derive(Debug)]
+pub enum Error {
+    /// An activation variable is not a number.
+    InvalidVar(&'static str),
+    /// Failed to inspect a passed file descriptor.
+    Socket(RawFd, io::Error),
+    /// A passed file descriptor is not a listening Unix stream socket.
+    NotUnixListener(RawFd),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            InvalidVar(name) => write!(f, "{} is not a number", name),
+            Socket(fd, err) => write!(f, "Cannot inspect the activated fd {}: {}", fd, err),
+            NotUnixListener(fd) => write!(
+                f,
+                "The activated fd {} is not a listening Unix stream socket",
+                fd
+            ),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+// Returns the file descriptors and names of the sockets passed to the process `pid`, from the
+// values of `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES`.
+fn parse_listen_vars(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    listen_fdnames: Option<&str>,
+    pid: u32,
+) -> Result<Vec<(RawFd, String)>> {
+    let listen_pid = match listen_pid {
+        Some(listen_pid) => listen_pid
+            .parse::<u32>()
+            .map_err(|_| Error::InvalidVar("LISTEN_PID"))?,
+        None => return Ok(Vec::new()),
+    };
+    // The sockets are meant for a parent, which did not unset the variables.
+    if listen_pid != pid {
+        return Ok(Vec::new());
+    }
+    let count = listen_fds
+        .unwrap_or("0")
+        .parse::<RawFd>()
+        .map_err(|_| Error::InvalidVar("LISTEN_FDS"))?;
+    let mut names = listen_fdnames
+        .map(|names| names.split(':').map(str::to_string).collect())
+        .unwrap_or_else(Vec::new);
+    names.resize(count.max(0) as usize, UNNAMED_SOCKET.to_string());
+    if count == 1 && names[0] == UNNAMED_SOCKET {
+        names[0] = API_SOCKET_NAME.to_string();
+    }
+    Ok((LISTEN_FDS_START..).zip(names).collect())
+}
+
+// Returns the value of the `option` socket option of `fd`.
+fn socket_option(fd: RawFd, option: libc::c_int) -> Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // Safe because the kernel writes at most `len` bytes to `value`.
+    SyscallReturnCode(unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    })
+    .into_empty_result()
+    .map_err(|e| Error::Socket(fd, e))?;
+    Ok(value)
+}
+
+// Takes ownership of the passed socket `fd`, which must be a listening Unix stream socket.
+fn take_listener(fd: RawFd) -> Result<UnixListener> {
+    if socket_option(fd, libc::SO_DOMAIN)? != libc::AF_UNIX
+        || socket_option(fd, libc::SO_TYPE)? != libc::SOCK_STREAM
+        || socket_option(fd, libc::SO_ACCEPTCONN)? == 0
+    {
+        return Err(Error::NotUnixListener(fd));
+    }
+    // systemd passes the sockets without close-on-exec, keep them from the child processes.
+    // Safe because `fd` is a valid socket.
+    SyscallReturnCode(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })
+        .into_empty_result()
+        .map_err(|e| Error::Socket(fd, e))?;
+    // Safe because the socket is passed to this process only, which owns it from now on.
+    Ok(unsafe { UnixListener::from_raw_fd(fd) })
+}
+
+/// Listening sockets passed to the process, by name.
+#[derive(Default)]
+pub struct ActivatedSockets {
+    listeners: Mutex<HashMap<String, UnixListener>>,
+}
+
+impl ActivatedSockets {
+    /// Takes the sockets passed to the process by systemd, and unsets the activation variables
+    /// so that the child processes do not inherit them. Returns the number of sockets.
+    pub fn init(&self) -> Result<usize> {
+        let listen_pid = env::var("LISTEN_PID").ok();
+        let listen_fds = env::var("LISTEN_FDS").ok();
+        let listen_fdnames = env::var("LISTEN_FDNAMES").ok();
+        for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(name);
+        }
+        let sockets = parse_listen_vars(
+            listen_pid.as_deref(),
+            listen_fds.as_deref(),
+            listen_fdnames.as_deref(),
+            std::process::id(),
+        )?;
+
+        let mut listeners = self.listeners.lock().expect("Poisoned lock");
+        for (fd, name) in sockets {
+            if name != API_SOCKET_NAME && name != UFFD_SOCKET_NAME {
+                warn!("Ignoring the activated socket {} named {}.", fd, name);
+                continue;
+            }
+            listeners.insert(name, take_listener(fd)?);
+        }
+        Ok(listeners.len())
+    }
+
+    /// Takes the activated socket `name` out of the process sockets.
+    pub fn take(&self, name: &str) -> Option<UnixListener> {
+        self.listeners.lock().expect("Poisoned lock").remove(name)
+    }
+
+    /// Returns a handle to the activated socket `name`, which stays with the process sockets.
+    pub fn listener(&self, name: &str) -> Option<UnixListener> {
+        self.listeners
+            .lock()
+            .expect("Poisoned lock")
+            .get(name)
+            .and_then(|listener| listener.try_clone().ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    use utils::tempdir::TempDir;
+
+    #[test]
+    fn test_parse_listen_vars() {
+        let pid = std::process::id();
+        let own_pid = pid.to_string();
+
+        // Not activated.
+        assert!(parse_listen_vars(None, None, None, pid).unwrap().is_empty());
+        // Activated, but for another process.
+        let other_pid = (pid + 1).to_string();
+        assert!(parse_listen_vars(Some(&other_pid), Some("1"), None, pid)
+            .unwrap()
+            .is_empty());
+
+        // A single unnamed socket is the API socket.
+        assert_eq!(
+            parse_listen_vars(Some(&own_pid), Some("1"), None, pid).unwrap(),
+            vec![(3, API_SOCKET_NAME.to_string())]
+        );
+        assert_eq!(
+            parse_listen_vars(Some(&own_pid), Some("2"), Some("uffd:api"), pid).unwrap(),
+            vec![
+                (3, UFFD_SOCKET_NAME.to_string()),
+                (4, API_SOCKET_NAME.to_string())
+            ]
+        );
+        // Missing names are unknown.
+        assert_eq!(
+            parse_listen_vars(Some(&own_pid), Some("2"), Some("api"), pid).unwrap(),
+            vec![
+                (3, API_SOCKET_NAME.to_string()),
+                (4, UNNAMED_SOCKET.to_string())
+            ]
+        );
+
+        match parse_listen_vars(Some("x"), Some("1"), None, pid) {
+            Err(Error::InvalidVar("LISTEN_PID")) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match parse_listen_vars(Some(&own_pid), Some("x"), None, pid) {
+            Err(Error::InvalidVar("LISTEN_FDS")) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_take_listener() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("api.socket");
+        let fd = UnixListener::bind(&path).unwrap().into_raw_fd();
+
+        let listener = take_listener(fd).unwrap();
+        // Safe because `fd` is valid.
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        assert_ne!(flags & libc::FD_CLOEXEC, 0);
+        let _client = UnixStream::connect(&path).unwrap();
+        assert!(listener.accept().is_ok());
+
+        // Connected sockets are not listening.
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let fd = stream.into_raw_fd();
+        match take_listener(fd) {
+            Err(Error::NotUnixListener(err_fd)) => assert_eq!(err_fd, fd),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+        // Safe because `fd` is owned by the test.
+        unsafe { libc::close(fd) };
+    }
+
+    #[test]
+    fn test_listeners() {
+        let dir = TempDir::new().unwrap();
+        let sockets = ActivatedSockets::default();
+        let listener = UnixListener::bind(dir.as_path().join("uffd.socket")).unwrap();
+        sockets
+            .listeners
+            .lock()
+            .unwrap()
+            .insert(UFFD_SOCKET_NAME.to_string(), listener);
+
+        assert!(sockets.listener(UFFD_SOCKET_NAME).is_some());
+        assert!(sockets.listener(UFFD_SOCKET_NAME).is_some());
+        assert!(sockets.take(UFFD_SOCKET_NAME).is_some());
+        assert!(sockets.take(UFFD_SOCKET_NAME).is_none());
+        assert!(sockets.listener(API_SOCKET_NAME).is_none());
+    }
+
+    #[test]
+    fn test_error_display() {
+        let err = Error::InvalidVar("LISTEN_FDS");
+        assert_eq!(err.to_string(), "LISTEN_FDS is not a number");
+        let err = Error::NotUnixListener(3);
+        let _ = format!("{}{:?}", err, err);
+        let err = Error::Socket(3, io::Error::from_raw_os_error(libc::EBADF));
+        let _ = format!("{}{:?}", err, err);
+    }
+}
@@ -2,17 +2,51 @@
 // SPDX-License-Identifier: Apache-2.0
 
 //! Provides the VersionMap that deals with the microvm state versions.
+//!
+//! From data version 3 on, the microVM state starts with the `StateExtensions` it was written
+//! with, so that a loader can tell which of its features it lacks before deserializing the rest
+//! of the state. Later versions only append fields to `StateExtensions`, and keep it first.
 
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 
 use lazy_static::lazy_static;
-use versionize::VersionMap;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+
+use crate::memory_snapshot::GuestMemoryState;
+#[cfg(target_arch = "x86_64")]
+use crate::persist::MicrovmState;
+
+/// Revision of the faasnap extensions of the microVM state.
+pub const FAASNAP_EXT_VERSION: u16 = 1;
+
+/// Features of the microVM state. A new data version adds the features it introduces, so that
+/// older binaries can name the ones they lack.
+pub const STATE_FEATURES: &[&str] = &[
+    // v2: the guest memory state records the page size.
+    "guest_memory_page_size",
+    // v3: the state starts with the state extensions.
+    "state_extensions",
+];
+
+// First data version recording the state extensions.
+const EXTENSIONS_DATA_VERSION: u16 = 3;
 
 lazy_static! {
     // Note: until we have a better design, this needs to be updated when the version changes.
     /// Static instance used for handling microVM state versions.
     pub static ref VERSION_MAP: VersionMap = {
-        VersionMap::new()
+        let mut version_map = VersionMap::new();
+        // v2: the guest memory state records the page size.
+        version_map
+            .new_version()
+            .set_type_version(GuestMemoryState::type_id(), 2);
+        // v3: the state starts with the state extensions.
+        version_map.new_version();
+        #[cfg(target_arch = "x86_64")]
+        version_map.set_type_version(MicrovmState::type_id(), 2);
+        version_map
     };
 
     /// Static instance used for creating a 1:1 mapping between Firecracker release version
@@ -24,3 +58,127 @@ lazy_static! {
         mapping
     };
 }
+
+/// Extension version and features of the binary which wrote a microVM state.
+#[derive(Clone, Debug, PartialEq, Versionize)]
+pub struct StateExtensions {
+    /// Revision of the faasnap extensions of the writer.
+    pub ext_version: u16,
+    /// Features of the state, as named in `STATE_FEATURES`.
+    pub features: Vec<String>,
+}
+
+impl StateExtensions {
+    /// Returns the extensions of the states this binary writes.
+    pub fn current() -> Self {
+        StateExtensions {
+            ext_version: FAASNAP_EXT_VERSION,
+            features: STATE_FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+}
+
+/// A microVM state which this binary cannot deserialize.
+#[derive(Debug, PartialEq)]
+pub struct Incompatibility {
+    /// Data version of the state.
+    pub data_version: u16,
+    /// Extensions the state was written with, when it records them.
+    pub extensions: Option<StateExtensions>,
+    /// Features of the state this binary does not know.
+    pub unknown_features: Vec<String>,
+}
+
+impl Display for Incompatibility {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "Snapshot data version {}", self.data_version)?;
+        if let Some(extensions) = &self.extensions {
+            write!(
+                f,
+                ", written with faasnap extensions v{},",
+                extensions.ext_version
+            )?;
+        }
+        write!(
+            f,
+            " is not supported by this binary, with faasnap extensions v{} up to data version {}",
+            FAASNAP_EXT_VERSION,
+            VERSION_MAP.latest_version()
+        )?;
+        if !self.unknown_features.is_empty() {
+            write!(
+                f,
+                ". Unsupported features: {}",
+                self.unknown_features.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that this binary can deserialize the microVM state which `state` holds, written in
+/// `data_version`, from the snapshot header on.
+pub fn check_compatibility(state: &[u8], data_version: u16) -> Result<(), Incompatibility> {
+    let latest = VERSION_MAP.latest_version();
+    let extensions = match data_version {
+        // A state from a later data version still records its extensions first.
+        v if v >= EXTENSIONS_DATA_VERSION => {
+            StateExtensions::deserialize(&mut &state[..], &VERSION_MAP, std::cmp::min(v, latest))
+                .ok()
+        }
+        _ => None,
+    };
+    let unknown_features: Vec<String> = extensions
+        .iter()
+        .flat_map(|extensions| extensions.features.iter())
+        .filter(|feature| !STATE_FEATURES.contains(&feature.as_str()))
+        .cloned()
+        .collect();
+    if data_version == 0 || data_version > latest || !unknown_features.is_empty() {
+        return Err(Incompatibility {
+            data_version,
+            extensions,
+            unknown_features,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_compatibility() {
+        let latest = VERSION_MAP.latest_version();
+        let mut state = Vec::new();
+        StateExtensions::current()
+            .serialize(&mut state, &VERSION_MAP, latest)
+            .unwrap();
+        assert!(check_compatibility(&state, latest).is_ok());
+        // The states of older data versions do not record their extensions.
+        assert!(check_compatibility(&[], 1).is_ok());
+        assert!(check_compatibility(&[], 0).is_err());
+
+        // A later binary names the features this one lacks.
+        let mut later = StateExtensions::current();
+        later.ext_version = FAASNAP_EXT_VERSION + 1;
+        later.features.push("balloon_free_page_hints".to_string());
+        let mut state = Vec::new();
+        later.serialize(&mut state, &VERSION_MAP, latest).unwrap();
+        let err = check_compatibility(&state, latest + 1).unwrap_err();
+        assert_eq!(err.data_version, latest + 1);
+        assert_eq!(err.unknown_features, vec!["balloon_free_page_hints"]);
+        let msg = err.to_string();
+        assert!(msg.contains("written with faasnap extensions v2"));
+        assert!(msg.contains("Unsupported features: balloon_free_page_hints"));
+
+        // Unknown features are rejected within the supported data versions too.
+        assert!(check_compatibility(&state, latest).is_err());
+
+        // A later state which does not parse still reports its data version.
+        let err = check_compatibility(&[0xff], latest + 1).unwrap_err();
+        assert_eq!(err.extensions, None);
+        assert!(err.unknown_features.is_empty());
+    }
+}
@@ -12,7 +12,7 @@ use std::sync::{Arc, Mutex};
 use super::RateLimiterConfig;
 use devices::virtio::Block;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 type Result<T> = result::Result<T, DriveError>;
 
@@ -61,7 +61,7 @@ impl Display for DriveError {
 }
 
 /// Use this structure to set up the Block Device before booting the kernel.
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct BlockDeviceConfig {
     /// Unique identifier of the drive.
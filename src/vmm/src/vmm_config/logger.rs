@@ -8,7 +8,7 @@ use std::path::PathBuf;
 
 use super::{open_file_nonblock, FcLineWriter};
 use crate::vmm_config::instance_info::InstanceInfo;
-use logger::{LevelFilter, LOGGER};
+use logger::{DebugCategory, LevelFilter, LOGGER};
 
 /// Enum used for setting the log level.
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
@@ -72,6 +72,14 @@ where
     })
 }
 
+// Same as `case_insensitive`, for an optional `level` field.
+fn case_insensitive_option<'de, D>(deserializer: D) -> Result<Option<LoggerLevel>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    case_insensitive(deserializer).map(Some)
+}
+
 /// Strongly typed structure used to describe the logger.
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
@@ -109,6 +117,24 @@ impl LoggerConfig {
     }
 }
 
+/// Logger settings that can be changed after the logger has been configured. Only the fields
+/// that are set are updated.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggerUpdateConfig {
+    /// New named pipe or file used as output for logs.
+    pub log_path: Option<PathBuf>,
+    /// New level of the Logger.
+    #[serde(default, deserialize_with = "case_insensitive_option")]
+    pub level: Option<LoggerLevel>,
+    /// Whether to append the severity of the log entry to the output.
+    pub show_level: Option<bool>,
+    /// Whether to append the origin of the log entry to the output.
+    pub show_log_origin: Option<bool>,
+    /// Debug categories to log, every other category is disabled.
+    pub debug_categories: Option<Vec<DebugCategory>>,
+}
+
 /// Errors associated with actions on the `LoggerConfig`.
 #[derive(Debug)]
 pub enum LoggerConfigError {
@@ -150,6 +176,37 @@ pub fn init_logger(
         .map_err(|e| LoggerConfigError::InitializationFailure(e.to_string()))
 }
 
+/// Updates the logger as described in `logger_cfg`. The new output, if any, is opened before
+/// anything is changed, so a failed update leaves the logger as it was.
+pub fn update_logger(logger_cfg: LoggerUpdateConfig) -> std::result::Result<(), LoggerConfigError> {
+    let writer = match logger_cfg.log_path.as_ref() {
+        Some(log_path) => Some(FcLineWriter::new(
+            open_file_nonblock(log_path)
+                .map_err(|e| LoggerConfigError::InitializationFailure(e.to_string()))?,
+        )),
+        None => None,
+    };
+    if let Some(writer) = writer {
+        LOGGER
+            .redirect(Box::new(writer))
+            .map_err(|e| LoggerConfigError::InitializationFailure(e.to_string()))?;
+    }
+
+    if let Some(level) = logger_cfg.level {
+        LOGGER.set_max_level(level.into());
+    }
+    if let Some(show_level) = logger_cfg.show_level {
+        LOGGER.set_include_level(show_level);
+    }
+    if let Some(show_log_origin) = logger_cfg.show_log_origin {
+        LOGGER.set_include_origin(show_log_origin, show_log_origin);
+    }
+    if let Some(categories) = logger_cfg.debug_categories.as_ref() {
+        LOGGER.set_debug_categories(categories);
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::{BufRead, BufReader};
@@ -223,6 +280,32 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_update_logger() {
+        // Error case: redirecting the logger to an invalid pipe returns error.
+        let desc = LoggerUpdateConfig {
+            log_path: Some(PathBuf::from("not_found_file_log")),
+            ..Default::default()
+        };
+        assert!(update_logger(desc).is_err());
+
+        // The level is left untouched, other tests rely on it.
+        let desc = LoggerUpdateConfig {
+            debug_categories: Some(vec![DebugCategory::WsLoader]),
+            ..Default::default()
+        };
+        assert!(update_logger(desc).is_ok());
+        assert!(LOGGER.is_debug_category_enabled(DebugCategory::WsLoader));
+        assert!(!LOGGER.is_debug_category_enabled(DebugCategory::Uffd));
+
+        let desc = LoggerUpdateConfig {
+            debug_categories: Some(vec![]),
+            ..Default::default()
+        };
+        assert!(update_logger(desc).is_ok());
+        assert!(!LOGGER.is_debug_category_enabled(DebugCategory::WsLoader));
+    }
+
     #[test]
     fn test_error_display() {
         assert_eq!(
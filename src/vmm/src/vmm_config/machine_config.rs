@@ -16,6 +16,8 @@ pub enum VmConfigError {
     InvalidVcpuCount,
     /// The memory size is invalid. The memory can only be an unsigned integer.
     InvalidMemorySize,
+    /// The CPU topology does not match the vcpu count or cannot be exposed to the guest.
+    InvalidCpuTopology,
 }
 
 impl fmt::Display for VmConfigError {
@@ -28,6 +30,13 @@ impl fmt::Display for VmConfigError {
                  be 1 or an even number when hyperthreading is enabled.",
             ),
             InvalidMemorySize => write!(f, "The memory size (MiB) is invalid.",),
+            InvalidCpuTopology => write!(
+                f,
+                "The CPU topology is invalid! The product of sockets, cores per socket and \
+                 threads per core must equal the vCPU number, there can be at most 2 threads \
+                 per core, and the number of cores per socket must be a power of 2 when \
+                 there is more than one socket.",
+            ),
         }
     }
 }
@@ -53,9 +62,19 @@ pub struct VmConfig {
     /// A CPU template that it is used to filter the CPU features exposed to the guest.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cpu_template: Option<CpuFeaturesTemplate>,
+    /// Hyper-V enlightenments exposed to the guest on top of the CPU template.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hyperv: Option<HypervConfig>,
+    /// The CPU topology exposed to the guest. By default all the vCPUs are in one socket.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_topology: Option<CpuTopology>,
     /// Enables or disables dirty page tracking. Enabling allows incremental snapshots.
     #[serde(default)]
     pub track_dirty_pages: bool,
+    /// Exposes hardware virtualization extensions (VMX/SVM) to the guest. Snapshotting a
+    /// microVM with this enabled is rejected, as the nested hypervisor state is not saved.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nested_virt: Option<bool>,
 }
 
 impl Default for VmConfig {
@@ -65,7 +84,10 @@ impl Default for VmConfig {
             mem_size_mib: Some(128),
             ht_enabled: Some(false),
             cpu_template: None,
+            hyperv: None,
+            cpu_topology: None,
             track_dirty_pages: false,
+            nested_virt: Some(false),
         }
     }
 }
@@ -75,14 +97,15 @@ impl fmt::Display for VmConfig {
         let vcpu_count = self.vcpu_count.unwrap_or(1);
         let mem_size = self.mem_size_mib.unwrap_or(128);
         let ht_enabled = self.ht_enabled.unwrap_or(false);
+        let nested_virt = self.nested_virt.unwrap_or(false);
         let cpu_template = self
             .cpu_template
             .map_or("Uninitialized".to_string(), |c| c.to_string());
         write!(
             f,
             "{{ \"vcpu_count\": {:?}, \"mem_size_mib\": {:?}, \"ht_enabled\": {:?}, \
-             \"cpu_template\": {:?}, \"track_dirty_pages\": {:?} }}",
-            vcpu_count, mem_size, ht_enabled, cpu_template, self.track_dirty_pages
+             \"cpu_template\": {:?}, \"track_dirty_pages\": {:?}, \"nested_virt\": {:?} }}",
+            vcpu_count, mem_size, ht_enabled, cpu_template, self.track_dirty_pages, nested_virt
         )
     }
 }
@@ -122,6 +145,51 @@ impl fmt::Display for CpuFeaturesTemplate {
     }
 }
 
+/// Hyper-V synthetic features that can be exposed to the guest. Guests that read the clock
+/// often benefit from the reference TSC page and the synthetic timers.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HypervConfig {
+    /// Exposes the partition reference TSC page.
+    #[serde(default)]
+    pub reference_tsc: bool,
+    /// Exposes the synthetic timers, along with the synthetic interrupt controller.
+    #[serde(default)]
+    pub synthetic_timers: bool,
+}
+
+/// The CPU topology exposed to the guest. Runtimes that size their thread pools from the
+/// number of sockets and cores read it from CPUID.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CpuTopology {
+    /// Number of sockets.
+    pub sockets: u8,
+    /// Number of cores in each socket.
+    pub cores_per_socket: u8,
+    /// Number of threads in each core. Can be 1 or 2.
+    pub threads_per_core: u8,
+}
+
+impl CpuTopology {
+    /// Checks that the topology describes exactly `vcpu_count` vCPUs and that it can be
+    /// encoded in the guest CPUID.
+    pub fn validate(&self, vcpu_count: u8) -> std::result::Result<(), VmConfigError> {
+        let vcpus = u16::from(self.sockets)
+            * u16::from(self.cores_per_socket)
+            * u16::from(self.threads_per_core);
+        if vcpus != u16::from(vcpu_count)
+            || self.threads_per_core == 0
+            || self.threads_per_core > 2
+            || (self.sockets > 1 && !self.cores_per_socket.is_power_of_two())
+        {
+            return Err(VmConfigError::InvalidCpuTopology);
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -140,5 +208,34 @@ mod tests {
 
         let expected_str = "The memory size (MiB) is invalid.";
         assert_eq!(VmConfigError::InvalidMemorySize.to_string(), expected_str);
+
+        let expected_str = "The CPU topology is invalid! The product of sockets, cores per \
+                            socket and threads per core must equal the vCPU number, there \
+                            can be at most 2 threads per core, and the number of cores per \
+                            socket must be a power of 2 when there is more than one socket.";
+        assert_eq!(VmConfigError::InvalidCpuTopology.to_string(), expected_str);
+    }
+
+    #[test]
+    fn test_validate_cpu_topology() {
+        let topology = |sockets, cores_per_socket, threads_per_core| CpuTopology {
+            sockets,
+            cores_per_socket,
+            threads_per_core,
+        };
+
+        assert!(topology(1, 4, 1).validate(4).is_ok());
+        assert!(topology(1, 3, 2).validate(6).is_ok());
+        assert!(topology(2, 2, 2).validate(8).is_ok());
+
+        // Does not match the vcpu count.
+        assert!(topology(1, 4, 1).validate(2).is_err());
+        assert!(topology(0, 4, 1).validate(4).is_err());
+        // Too many threads per core.
+        assert!(topology(1, 1, 4).validate(4).is_err());
+        // The cores of a socket cannot be enumerated by the APIC id bits.
+        assert!(topology(2, 3, 1).validate(6).is_err());
+        // No overflow on large values.
+        assert!(topology(255, 255, 2).validate(32).is_err());
     }
 }
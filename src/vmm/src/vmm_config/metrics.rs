@@ -17,6 +17,14 @@ pub struct MetricsConfig {
     pub metrics_path: PathBuf,
 }
 
+/// Metrics settings that can be changed after the metrics system has been configured.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsUpdateConfig {
+    /// New named pipe or file used as output for metrics.
+    pub metrics_path: PathBuf,
+}
+
 /// Errors associated with actions on the `MetricsConfig`.
 #[derive(Debug)]
 pub enum MetricsConfigError {
@@ -44,6 +52,23 @@ pub fn init_metrics(metrics_cfg: MetricsConfig) -> std::result::Result<(), Metri
         .map_err(|e| MetricsConfigError::InitializationFailure(e.to_string()))
 }
 
+/// Redirects the metrics as described in `metrics_cfg`. The pending metrics are flushed to the
+/// previous output first, so that no value is lost across the switch.
+pub fn update_metrics(
+    metrics_cfg: MetricsUpdateConfig,
+) -> std::result::Result<(), MetricsConfigError> {
+    let writer = FcLineWriter::new(
+        open_file_nonblock(&metrics_cfg.metrics_path)
+            .map_err(|e| MetricsConfigError::InitializationFailure(e.to_string()))?,
+    );
+    METRICS
+        .write()
+        .map_err(|e| MetricsConfigError::InitializationFailure(e.to_string()))?;
+    METRICS
+        .redirect(Box::new(writer))
+        .map_err(|e| MetricsConfigError::InitializationFailure(e.to_string()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -67,6 +92,15 @@ mod tests {
         assert!(init_metrics(desc).is_err());
     }
 
+    #[test]
+    fn test_update_metrics() {
+        // Error case: redirecting metrics to an invalid pipe returns error.
+        let desc = MetricsUpdateConfig {
+            metrics_path: PathBuf::from("not_found_file_metrics"),
+        };
+        assert!(update_metrics(desc).is_err());
+    }
+
     #[test]
     fn test_error_display() {
         assert_eq!(
@@ -3,9 +3,12 @@
 
 //! Configurations used in the snapshotting context.
 
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::os::unix::io::RawFd;
 use std::path::PathBuf;
 
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Serialize};
 use std::collections::HashMap;
 /// The snapshot type options that are available when
 /// creating a new snapshot.
@@ -38,36 +41,552 @@ pub struct CreateSnapshotParams {
     /// Optional field for the microVM version. The default
     /// value is the current version.
     pub version: Option<String>,
+    /// Guest agent asked to prepare before the microVM pauses for the snapshot. The microVM
+    /// must then be running, and is paused by the request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_agent: Option<GuestAgentConfig>,
+    /// Copies the guest memory while the microVM keeps running, with KVM dirty page tracking
+    /// enabled, then pauses it only to write the pages dirtied meanwhile and its state. Full
+    /// snapshots of a running microVM only, which keeps running.
+    #[serde(default)]
+    pub background: bool,
+    /// Allocates the blocks of the memory file with `fallocate` before writing the guest memory:
+    /// the whole file for full snapshots, the dirty pages of each memory slot for diff snapshots.
+    #[serde(default)]
+    pub preallocate: bool,
 }
 
 /// Stores the configuration that will be used for loading a snapshot.
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct LoadSnapshotParams {
     /// Path to the file that contains the microVM state to be loaded.
     pub snapshot_path: PathBuf,
     /// Path to the file that contains the guest memory to be loaded.
+    #[serde(default)]
     pub mem_file_path: PathBuf,
+    /// Inherited file descriptor of the guest memory file, used instead of `mem_file_path`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mem_file_fd: Option<RawFd>,
     /// Setting this flag will enable KVM dirty page tracking and will
     /// allow taking subsequent incremental snapshots.
+    #[serde(default)]
     pub enable_diff_snapshots: bool,
     /// Setting this flag enables user page faults handling by a different process.
+    #[serde(default)]
     pub enable_user_page_faults: bool,
     /// Path to the passfd socket.
+    #[serde(default)]
     pub sock_file_path: PathBuf,
     /// overlay path
+    #[serde(default)]
     pub overlay_file_path: PathBuf,
-    /// Enable overlay regions mmap
+    /// Inherited file descriptor of the overlay file, used instead of `overlay_file_path`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overlay_file_fd: Option<RawFd>,
+    /// Enable overlay regions mmap: memory file page offset -> number of pages.
+    /// The overlay file uses the same layout as the memory file.
+    #[serde(default)]
     pub overlay_regions: HashMap<i64, i64>,
     /// ws file path
+    #[serde(default)]
     pub ws_file_path: PathBuf,
-    /// ws file mappings: 
+    /// Inherited file descriptor of the ws file, used instead of `ws_file_path`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ws_file_fd: Option<RawFd>,
+    /// ws file mappings: [memory file page offset, number of pages], stored back to back
+    /// in the ws file. Offsets are translated to guest memory regions, so they stay valid
+    /// for guests whose memory is split by the MMIO gap.
+    #[serde(default, deserialize_with = "validate_ws_regions")]
     pub ws_regions: Vec<Vec<i64>>,
     /// enable locally load ws
+    #[serde(default)]
     pub load_ws: bool,
+    /// Loads the working set by populating the extents as they are mapped from the ws file,
+    /// instead of touching their pages once mapped. Requires `load_ws` and a ws file.
+    #[serde(default)]
+    pub ws_populate: bool,
     #[serde(default)]
     /// fadvise for memfile
     pub fadvise: String,
+    /// Advice given to the kernel on the accesses to each of the layer files, replacing
+    /// `fadvise` on the memory file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer_fadvise: Option<LayerFadviseConfig>,
+    /// Path to the file the guest page faults are recorded to. The faults are then serviced
+    /// from the memory file by Firecracker, which rules out the other memory layers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fault_trace_path: Option<PathBuf>,
+    /// Accounts the accesses to the prefetched working set pages, reported in the load response
+    /// and then in the `ws_prefetch` metrics. Requires `load_ws` and a ws file.
+    #[serde(default)]
+    pub ws_accounting: bool,
+    /// Watchdog of the working set load and of the uffd handshake.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watchdog: Option<RestoreWatchdogConfig>,
+    /// Guest memory size in MiB, larger than the one of the snapshot. The memory past the
+    /// snapshot is anonymous and is onlined by the guest in memory blocks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mem_size_mib: Option<usize>,
+    /// Memory backend, as in the upstream Firecracker API. It replaces `mem_file_path`, or the
+    /// user page fault options with the upstream uffd handshake.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mem_backend: Option<MemBackendConfig>,
+    /// Resume the microVM once loaded, as in the upstream Firecracker API.
+    #[serde(default)]
+    pub resume_vm: bool,
+    /// Path to the network namespace the TAP devices of the microVM are opened in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub netns_path: Option<PathBuf>,
+    /// Inherited file descriptor of the network namespace, used instead of `netns_path`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub netns_fd: Option<RawFd>,
+    /// Host side changes of the network interfaces of the snapshot.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub network_overrides: Vec<NetworkOverride>,
+    /// Notification of the scheduler once the restored microVM is fully warmed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warm_notify: Option<WarmNotifyConfig>,
+    /// Pauses of the working set load while the host is under memory or IO pressure.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefetch_throttle: Option<PrefetchThrottleConfig>,
+    /// Concurrency and chunk size of the working set load, instead of the ones detected from
+    /// the storage of the snapshot files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefetch: Option<PrefetchConfig>,
+    /// Token bucket the working set loads of the restores of the host share, capping their
+    /// total bandwidth.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefetch_coordinator: Option<PrefetchCoordinatorConfig>,
+    /// Guest agent told once the restored microVM resumes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_agent: Option<GuestAgentConfig>,
+    /// Locks the working set extents in memory, for latency-critical microVMs. Cannot be
+    /// combined with `ws_accounting`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ws_lock: Option<WsLockMode>,
+    /// Marks the guest memory as mergeable by kernel samepage merging, so that its pages
+    /// identical to the ones of other microVMs are shared.
+    #[serde(default)]
+    pub ksm: bool,
+    /// Directory on a tmpfs or ramdisk the ws file is copied to, and mapped from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ws_staging: Option<WsStagingConfig>,
+    /// Time after which the working set load is abandoned, leaving the rest of the working set
+    /// to be faulted in lazily.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ws_load_deadline: Option<WsLoadDeadlineConfig>,
+    /// Charges the guest memory against the commit limit of the host as it is mapped, once
+    /// checked to fit in the memory available, so that a host short on memory fails the load
+    /// instead of the OOM killer stepping in later.
+    #[serde(default)]
+    pub strict_memory: bool,
+    /// Maps the memory file read-only, and copies its pages into anonymous guest memory as the
+    /// guest faults them in, so that no write can reach the golden snapshot file. Cannot be
+    /// combined with user page faults or a fault trace.
+    #[serde(default)]
+    pub protect_base: bool,
+    /// Fails the load when the overlay and working set extents overlap, rather than letting
+    /// the working set win over the overlay on the pages both map.
+    #[serde(default)]
+    pub reject_layer_conflicts: bool,
+    /// Backs the guest memory with transparent huge pages wherever it is anonymous.
+    #[serde(default)]
+    pub huge_pages: bool,
+    /// NUMA node the guest memory is placed on, the pages faulted in at restore being moved
+    /// to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub numa_node: Option<u32>,
+    /// Path to the JSON restore profile whose options replace the ones of these parameters,
+    /// relative to the directory of the snapshot file unless absolute.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile_path: Option<PathBuf>,
+    /// Name of the restore profile, defined at launch, whose options replace the ones of these
+    /// parameters, before the ones of `profile_path`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// Samples the guest page faults following the restore, attributing them to the layers
+    /// backing the faulting pages in the `fault_sources` metrics.
+    #[serde(default)]
+    pub fault_attribution: bool,
+    /// Pages out the guest memory outside of the working set progressively once the restored
+    /// microVM is idle.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_reclaim: Option<IdleReclaimConfig>,
+    /// Maps the memory, overlay and ws files from copies in a host-wide cache shared by the
+    /// restores.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot_cache: Option<SnapshotCacheConfig>,
+}
+
+impl LoadSnapshotParams {
+    /// Returns these parameters with `mem_backend` mapped onto the memory file, or onto the
+    /// user page fault options. The options already set must agree with the backend.
+    pub fn resolve_mem_backend(&self) -> Result<LoadSnapshotParams, MemBackendError> {
+        let mut params = self.clone();
+        let backend = match self.mem_backend.as_ref() {
+            Some(backend) => backend,
+            None => return Ok(params),
+        };
+        let path = &backend.backend_path;
+        if self.mem_file_fd.is_some() {
+            return Err(MemBackendError::Conflict("mem_file_fd"));
+        }
+        match backend.backend_type {
+            MemBackendType::File => {
+                if !self.mem_file_path.as_os_str().is_empty() && &self.mem_file_path != path {
+                    return Err(MemBackendError::Conflict("mem_file_path"));
+                }
+                if self.enable_user_page_faults {
+                    return Err(MemBackendError::Conflict("enable_user_page_faults"));
+                }
+                params.mem_file_path = path.clone();
+            }
+            MemBackendType::Uffd => {
+                // The handler serves all of the guest memory, which is anonymous.
+                if !self.mem_file_path.as_os_str().is_empty() {
+                    return Err(MemBackendError::Conflict("mem_file_path"));
+                }
+                if !self.sock_file_path.as_os_str().is_empty() && &self.sock_file_path != path {
+                    return Err(MemBackendError::Conflict("sock_file_path"));
+                }
+                params.enable_user_page_faults = true;
+                params.sock_file_path = path.clone();
+            }
+        }
+        Ok(params)
+    }
+}
+
+/// Host side of a network interface of a restored microVM.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkOverride {
+    /// ID of the network interface in the snapshot.
+    pub iface_id: String,
+    /// Name of the TAP device the interface is bound to, instead of the one of the snapshot.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_dev_name: Option<String>,
+    /// IPv4 address of the guest on this interface. The guest is announced on the TAP device
+    /// with a gratuitous ARP request before it resumes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_ipv4: Option<Ipv4Addr>,
+}
+
+/// How the working set of a restored microVM is locked in memory.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum WsLockMode {
+    /// The working set pages are locked as they are faulted in.
+    OnFault,
+    /// The working set pages are loaded and locked at restore.
+    Full,
+}
+
+/// Memory backend types of the upstream Firecracker API.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum MemBackendType {
+    /// The guest memory is mapped from the memory file.
+    File,
+    /// The guest memory page faults are served by the process listening on a Unix socket.
+    Uffd,
+}
+
+/// Memory backend of a snapshot load, as in the upstream Firecracker API.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemBackendConfig {
+    /// Type of the backend.
+    pub backend_type: MemBackendType,
+    /// Path to the memory file, or to the socket of the page fault handler.
+    pub backend_path: PathBuf,
+}
+
+/// Errors associated with the memory backend of a snapshot load.
+#[derive(Debug, PartialEq)]
+pub enum MemBackendError {
+    /// The memory backend is combined with a conflicting option.
+    Conflict(&'static str),
+}
+
+impl Display for MemBackendError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            MemBackendError::Conflict(field) => {
+                write!(f, "The memory backend cannot be combined with {}", field)
+            }
+        }
+    }
+}
+
+/// Configuration of the watchdog of a snapshot load.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RestoreWatchdogConfig {
+    /// Milliseconds without progress after which the working set load or the uffd handshake is
+    /// reported stalled.
+    pub timeout_ms: u64,
+    /// Exit Firecracker once a stall is reported.
+    #[serde(default)]
+    pub abort: bool,
+}
+
+/// Default period of the pressure checks of a throttled working set load.
+pub const DEFAULT_THROTTLE_CHECK_INTERVAL_MS: u64 = 10;
+/// Default longest pause of a throttled working set load.
+pub const DEFAULT_THROTTLE_MAX_PAUSE_MS: u64 = 1000;
+
+// Rejects the `ws_regions` entries other than a [page, number of pages] pair of non-negative
+// numbers.
+fn validate_ws_regions<'de, D>(d: D) -> std::result::Result<Vec<Vec<i64>>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let regions = Vec::<Vec<i64>>::deserialize(d)?;
+    let valid = |region: &Vec<i64>| region.len() == 2 && region.iter().all(|&n| n >= 0);
+    if !regions.iter().all(valid) {
+        return Err(de::Error::invalid_value(
+            de::Unexpected::Seq,
+            &"[page, number of pages] pairs of non-negative numbers",
+        ));
+    }
+    Ok(regions)
+}
+
+fn default_throttle_check_interval_ms() -> u64 {
+    DEFAULT_THROTTLE_CHECK_INTERVAL_MS
+}
+
+fn default_throttle_max_pause_ms() -> u64 {
+    DEFAULT_THROTTLE_MAX_PAUSE_MS
+}
+
+fn default_memory_pressure_path() -> PathBuf {
+    PathBuf::from("/proc/pressure/memory")
+}
+
+fn default_io_pressure_path() -> PathBuf {
+    PathBuf::from("/proc/pressure/io")
+}
+
+/// Throttling of the working set load by the pressure stall information (PSI) of the host, so
+/// that a burst of restores does not evict the hot pages of the running microVMs.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrefetchThrottleConfig {
+    /// Percentage of the time some tasks stall on memory or on IO above which the load pauses.
+    pub stall_percent: u8,
+    /// Milliseconds between two pressure checks, which is also the length of a pause.
+    #[serde(default = "default_throttle_check_interval_ms")]
+    pub check_interval_ms: u64,
+    /// Longest time, in milliseconds, a load pauses in total. It then goes on whatever the
+    /// pressure, since the guest cannot resume before it completes.
+    #[serde(default = "default_throttle_max_pause_ms")]
+    pub max_pause_ms: u64,
+    /// PSI file of the memory pressure, such as the `memory.pressure` file of a cgroup.
+    #[serde(default = "default_memory_pressure_path")]
+    pub memory_pressure_path: PathBuf,
+    /// PSI file of the IO pressure, such as the `io.pressure` file of a cgroup.
+    #[serde(default = "default_io_pressure_path")]
+    pub io_pressure_path: PathBuf,
+}
+
+/// Settings of the working set load, each detected from the storage of the snapshot files when
+/// not set.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrefetchConfig {
+    /// Number of helper threads loading the working set, at most the number of helper threads.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<usize>,
+    /// Largest number of pages a thread loads at once. Longer extents are split.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_pages: Option<u64>,
+}
+
+/// Deadline of the working set load, so that a slow storage of the snapshot files degrades to
+/// lazy faulting instead of stalling the resume.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WsLoadDeadlineConfig {
+    /// Milliseconds after the start of the working set load at which its remaining extents are
+    /// abandoned.
+    pub deadline_ms: u64,
+    /// Asks the kernel to read the abandoned extents ahead, in the background, so that the
+    /// guest faults on them are less likely to wait on the storage.
+    #[serde(default)]
+    pub readahead: bool,
+}
+
+/// Staging of the ws file in a memory-backed directory shared by the restores of the host.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WsStagingConfig {
+    /// Directory the ws files are copied to, on a tmpfs or a ramdisk.
+    pub dir: PathBuf,
+    /// Total length of the files in the directory, in MiB, past which no ws file is staged.
+    pub quota_mib: u64,
+}
+
+/// `posix_fadvise` advice on each of the layer files, as `normal`, `random`, `sequential`,
+/// `willneed`, `dontneed` or `noreuse`. The advices of a file are given in order.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LayerFadviseConfig {
+    /// Advice on the memory file.
+    #[serde(default)]
+    pub mem_file: Vec<String>,
+    /// Advice on the overlay file.
+    #[serde(default)]
+    pub overlay_file: Vec<String>,
+    /// Advice on the ws file.
+    #[serde(default)]
+    pub ws_file: Vec<String>,
+}
+
+/// Host-wide cache of the snapshot files, kept in a directory shared by the Firecracker processes.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotCacheConfig {
+    /// Directory the snapshot files are copied to, on a tmpfs or a ramfs.
+    pub dir: PathBuf,
+    /// Total length of the files in the directory, in MiB, past which the least recently used
+    /// files no microVM references are evicted.
+    pub capacity_mib: u64,
+}
+
+/// Host-wide token bucket of the working set loads, kept in a file shared by the restoring
+/// Firecracker processes.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrefetchCoordinatorConfig {
+    /// File holding the token bucket, created if missing. The restores sharing it share the
+    /// bandwidth.
+    pub token_file: PathBuf,
+    /// Total bandwidth of the working set loads, in MiB/s.
+    pub bandwidth_mib_s: u64,
+    /// MiB a load may read at once over the bandwidth, one second of bandwidth by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst_mib: Option<u64>,
+}
+
+/// Default percentage of resident pages at which a restored microVM is fully warmed.
+pub const DEFAULT_WARM_THRESHOLD_PERCENT: u8 = 95;
+/// Default period of the residency samples of a restored microVM warming up.
+pub const DEFAULT_WARM_SAMPLE_PERIOD_MS: u64 = 100;
+
+fn default_warm_threshold_percent() -> u8 {
+    DEFAULT_WARM_THRESHOLD_PERCENT
+}
+
+fn default_warm_sample_period_ms() -> u64 {
+    DEFAULT_WARM_SAMPLE_PERIOD_MS
+}
+
+/// Configuration of the notification sent once a restored microVM is fully warmed, that is once
+/// enough of its working set is resident.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WarmNotifyConfig {
+    /// Percentage of the working set pages, or of the snapshot memory without `ws_regions`, that
+    /// must be resident.
+    #[serde(default = "default_warm_threshold_percent")]
+    pub threshold_percent: u8,
+    /// Milliseconds between two residency samples.
+    #[serde(default = "default_warm_sample_period_ms")]
+    pub sample_period_ms: u64,
+    /// Path to the Unix socket the notification is written to, as a JSON line.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket_path: Option<PathBuf>,
+    /// URL the notification is posted to, as `http://<ip>:<port>/<path>`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_url: Option<String>,
+}
+
+/// Default period of the activity checks and page outs of an idle microVM.
+pub const DEFAULT_IDLE_RECLAIM_PERIOD_MS: u64 = 1000;
+/// Default MiB of guest memory paged out per period once a microVM is idle.
+pub const DEFAULT_IDLE_RECLAIM_STEP_MIB: u64 = 64;
+/// Default CPU usage, in percent of a CPU, above which a microVM is active.
+pub const DEFAULT_IDLE_RECLAIM_ACTIVE_CPU_PERCENT: u8 = 5;
+
+fn default_idle_reclaim_period_ms() -> u64 {
+    DEFAULT_IDLE_RECLAIM_PERIOD_MS
+}
+
+fn default_idle_reclaim_step_mib() -> u64 {
+    DEFAULT_IDLE_RECLAIM_STEP_MIB
+}
+
+fn default_idle_reclaim_active_cpu_percent() -> u8 {
+    DEFAULT_IDLE_RECLAIM_ACTIVE_CPU_PERCENT
+}
+
+/// Reclamation of the guest memory of a restored microVM once idle, keeping its working set
+/// resident.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdleReclaimConfig {
+    /// Milliseconds the microVM must be idle for before its guest memory is paged out.
+    pub idle_ms: u64,
+    /// Milliseconds between two activity checks, and between two page outs once idle.
+    #[serde(default = "default_idle_reclaim_period_ms")]
+    pub period_ms: u64,
+    /// MiB of guest memory paged out every period once idle.
+    #[serde(default = "default_idle_reclaim_step_mib")]
+    pub step_mib: u64,
+    /// CPU usage of the microVM, in percent of a CPU, above which it is active.
+    #[serde(default = "default_idle_reclaim_active_cpu_percent")]
+    pub active_cpu_percent: u8,
+}
+
+/// Default vsock port of the guest agent.
+pub const DEFAULT_GUEST_AGENT_PORT: u32 = 52;
+/// Default time the guest agent has to answer a message.
+pub const DEFAULT_GUEST_AGENT_TIMEOUT_MS: u64 = 1000;
+
+fn default_guest_agent_port() -> u32 {
+    DEFAULT_GUEST_AGENT_PORT
+}
+
+fn default_guest_agent_timeout_ms() -> u64 {
+    DEFAULT_GUEST_AGENT_TIMEOUT_MS
+}
+
+/// Agent in the guest, reached over the vsock device, that prepares for the snapshots and
+/// recovers once the microVM resumes.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GuestAgentConfig {
+    /// Host side Unix socket of the vsock device, its `uds_path`.
+    pub uds_path: PathBuf,
+    /// Vsock port the agent listens on.
+    #[serde(default = "default_guest_agent_port")]
+    pub port: u32,
+    /// Milliseconds the agent has to answer each message.
+    #[serde(default = "default_guest_agent_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Fails the snapshot creation when the agent does not prepare, instead of going on.
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// Guest physical memory range zeroed in the memory file of the snapshots.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScrubRange {
+    /// Guest physical address of the start of the range.
+    pub guest_addr: u64,
+    /// Length of the range in bytes.
+    pub len: u64,
+}
+
+/// Stores the guest memory ranges that must never reach the memory file of a snapshot, such as
+/// the pages holding key material reported by a guest agent.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScrubRangesConfig {
+    /// Ranges replacing the registered ones. An empty list disables scrubbing.
+    pub ranges: Vec<ScrubRange>,
 }
 
 /// The microVM state options.
@@ -18,17 +18,19 @@ use std::thread;
 
 use super::{FC_EXIT_CODE_GENERIC_ERROR, FC_EXIT_CODE_OK};
 
-use crate::vmm_config::machine_config::CpuFeaturesTemplate;
+use crate::vmm_config::machine_config::{CpuFeaturesTemplate, HypervConfig};
 #[cfg(target_arch = "aarch64")]
 use arch::aarch64::gic::GICDevice;
 #[cfg(target_arch = "x86_64")]
+use cpuid::hyperv::{self, HypervFeatures};
+#[cfg(target_arch = "x86_64")]
 use cpuid::{c3, filter_cpuid, t2, VmSpec};
 #[cfg(target_arch = "x86_64")]
 use kvm_bindings::{
-    kvm_clock_data, kvm_debugregs, kvm_irqchip, kvm_lapic_state, kvm_mp_state, kvm_pit_config,
-    kvm_pit_state2, kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs, kvm_xsave, CpuId, MsrList,
-    Msrs, KVM_CLOCK_TSC_STABLE, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
-    KVM_MAX_CPUID_ENTRIES, KVM_PIT_SPEAKER_DUMMY,
+    kvm_clock_data, kvm_debugregs, kvm_enable_cap, kvm_irqchip, kvm_lapic_state, kvm_mp_state,
+    kvm_pit_config, kvm_pit_state2, kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs, kvm_xsave,
+    CpuId, MsrList, Msrs, KVM_CAP_HYPERV_SYNIC, KVM_CLOCK_TSC_STABLE, KVM_IRQCHIP_IOAPIC,
+    KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE, KVM_MAX_CPUID_ENTRIES, KVM_PIT_SPEAKER_DUMMY,
 };
 use kvm_bindings::{kvm_userspace_memory_region, KVM_API_VERSION, KVM_MEM_LOG_DIRTY_PAGES};
 use kvm_ioctls::*;
@@ -102,6 +104,9 @@ pub enum Error {
     VcpuArmPreferredTarget(kvm_ioctls::Error),
     /// vCPU count is not initialized.
     VcpuCountNotInitialized,
+    #[cfg(target_arch = "x86_64")]
+    /// Failed to enable a KVM capability on the vcpu.
+    VcpuEnableCap(kvm_ioctls::Error),
     /// Cannot open the VCPU file descriptor.
     VcpuFd(kvm_ioctls::Error),
     #[cfg(target_arch = "x86_64")]
@@ -256,6 +261,8 @@ impl Display for Error {
             ),
             Irq(e) => write!(f, "Cannot configure the IRQ: {}", e),
             #[cfg(target_arch = "x86_64")]
+            VcpuEnableCap(e) => write!(f, "Failed to enable KVM vcpu capability: {}", e),
+            #[cfg(target_arch = "x86_64")]
             VcpuGetDebugRegs(e) => write!(f, "Failed to get KVM vcpu debug regs: {}", e),
             #[cfg(target_arch = "x86_64")]
             VcpuGetLapic(e) => write!(f, "Failed to get KVM vcpu lapic: {}", e),
@@ -587,6 +594,12 @@ pub struct VcpuConfig {
     pub ht_enabled: bool,
     /// CPUID template to use.
     pub cpu_template: Option<CpuFeaturesTemplate>,
+    /// Hyper-V enlightenments to expose.
+    pub hyperv: Option<HypervConfig>,
+    /// Number of sockets the vCPUs are spread over.
+    pub sockets: u8,
+    /// Expose VMX/SVM to the guest.
+    pub nested_virt: bool,
 }
 
 // Using this for easier explicit type-casting to help IDEs interpret the code.
@@ -798,8 +811,13 @@ impl Vcpu {
         vcpu_config: &VcpuConfig,
         mut cpuid: CpuId,
     ) -> Result<()> {
-        let cpuid_vm_spec = VmSpec::new(self.index, vcpu_config.vcpu_count, vcpu_config.ht_enabled)
-            .map_err(Error::CpuId)?;
+        let cpuid_vm_spec = VmSpec::with_sockets(
+            self.index,
+            vcpu_config.vcpu_count,
+            vcpu_config.ht_enabled,
+            vcpu_config.sockets,
+        )
+        .map_err(Error::CpuId)?;
 
         filter_cpuid(&mut cpuid, &cpuid_vm_spec).map_err(|e| {
             METRICS.vcpu.filter_cpuid.inc();
@@ -821,6 +839,19 @@ impl Vcpu {
             }
         }
 
+        cpuid::set_nested_virt(&mut cpuid, vcpu_config.nested_virt).map_err(Error::CpuId)?;
+
+        if let Some(hyperv_config) = vcpu_config.hyperv {
+            let features = HypervFeatures {
+                reference_tsc: hyperv_config.reference_tsc,
+                synthetic_timers: hyperv_config.synthetic_timers,
+            };
+            hyperv::set_hyperv_entries(&mut cpuid, &features).map_err(Error::CpuId)?;
+            if features.synthetic_timers {
+                self.enable_hyperv_synic()?;
+            }
+        }
+
         self.fd.set_cpuid2(&cpuid).map_err(Error::VcpuSetCpuid)?;
 
         arch::x86_64::msr::setup_msrs(&self.fd).map_err(Error::MSRSConfiguration)?;
@@ -873,6 +904,16 @@ impl Vcpu {
         Ok(())
     }
 
+    #[cfg(target_arch = "x86_64")]
+    /// Activates the Hyper-V synthetic interrupt controller for this vcpu.
+    fn enable_hyperv_synic(&self) -> Result<()> {
+        let cap = kvm_enable_cap {
+            cap: KVM_CAP_HYPERV_SYNIC,
+            ..Default::default()
+        };
+        self.fd.enable_cap(&cap).map_err(Error::VcpuEnableCap)
+    }
+
     /// Moves the vcpu to its own thread and constructs a VcpuHandle.
     /// The handle can be used to control the remote vcpu.
     pub fn start_threaded(mut self, seccomp_filter: BpfProgram) -> Result<VcpuHandle> {
@@ -985,7 +1026,13 @@ impl Vcpu {
          *
          * SET_LAPIC must come before SET_MSRS, because the TSC deadline MSR
          * only restores successfully, when the LAPIC is correctly configured.
+         *
+         * The Hyper-V SynIC must be enabled before SET_MSRS, otherwise the
+         * SynIC and synthetic timer MSRs are rejected.
          */
+        if hyperv::uses_synic(&state.cpuid) {
+            self.enable_hyperv_synic()?;
+        }
         self.fd
             .set_cpuid2(&state.cpuid)
             .map_err(Error::VcpuSetCpuid)?;
@@ -1585,6 +1632,9 @@ pub(crate) mod tests {
             vcpu_count: 1,
             ht_enabled: false,
             cpu_template: None,
+            hyperv: None,
+            sockets: 1,
+            nested_virt: false,
         };
 
         assert!(vcpu
@@ -1807,6 +1857,9 @@ pub(crate) mod tests {
             vcpu_count: 1,
             ht_enabled: false,
             cpu_template: None,
+            hyperv: None,
+            sockets: 1,
+            nested_virt: false,
         };
         vcpu.configure_x86_64_for_boot(
             &vm_mem,
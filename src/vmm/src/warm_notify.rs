@@ -0,0 +1,509 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Notification of the scheduler once a restored microVM is fully warmed.
+//!
+//! The guest memory of a lazy restore fills in after the guest resumes, from the memory file or
+//! from the page fault handler. The residency of the working set is sampled until it reaches the
+//! configured threshold, at which point the scheduler is told, once, that the microVM can take
+//! its full share of the load. The connection to the scheduler is opened along with the snapshot
+//! load, before the VMM is sandboxed.
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use logger::{error, info, warn};
+use polly::event_manager::{EventManager, Subscriber};
+use serde::Serialize;
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
+use utils::epoll::{EpollEvent, EventSet};
+use utils::time::{get_time_us, ClockType};
+use vm_memory::GuestMemoryMmap;
+
+use crate::lifecycle::{LifecycleEvent, LIFECYCLE};
+use crate::memory_snapshot::GuestMemoryState;
+use crate::vmm_config::snapshot::WarmNotifyConfig;
+use crate::ws_accounting;
+
+/// Time after which connecting to, writing to or reading from the scheduler is given up.
+const IO_TIMEOUT: Duration = Duration::from_secs(1);
+const HTTP_SCHEME: &str = "http://";
+
+/// Errors associated with the warm notification.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to connect to the scheduler.
+    Connect(String, io::Error),
+    /// The sample period is zero.
+    InvalidPeriod,
+    /// The threshold is not a percentage.
+    InvalidThreshold(u8),
+    /// The URL is not of the form `http://<ip>:<port>/<path>`.
+    InvalidUrl(String),
+    /// Both a socket and a URL are configured.
+    MultipleTargets,
+    /// Failed to create the sampling timer.
+    Timer(io::Error),
+    /// The working set is not part of the guest memory.
+    WsRegions(ws_accounting::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Connect(target, err) => write!(f, "Cannot connect to {}: {}", target, err),
+            InvalidPeriod => write!(f, "The warm sample period must not be zero"),
+            InvalidThreshold(percent) => write!(
+                f,
+                "The warm threshold must be between 1 and 100, not {}",
+                percent
+            ),
+            InvalidUrl(url) => write!(
+                f,
+                "Invalid warm notification URL {}, expected http://<ip>:<port>/<path>",
+                url
+            ),
+            MultipleTargets => write!(f, "Only one of socket_path and http_url can be set"),
+            Timer(err) => write!(f, "Cannot create the sampling timer: {}", err),
+            WsRegions(err) => write!(f, "Invalid working set: {}", err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Serialize)]
+struct WarmRecord {
+    timestamp_us: u64,
+    event: LifecycleEvent,
+    resident_pages: u64,
+    warm_pages: u64,
+    resident_ratio: f64,
+    elapsed_us: u64,
+}
+
+enum Target {
+    Socket(UnixStream),
+    Http {
+        stream: TcpStream,
+        host: String,
+        path: String,
+    },
+}
+
+impl Target {
+    fn connect(config: &WarmNotifyConfig) -> Result<Option<Self>> {
+        match (config.socket_path.as_ref(), config.http_url.as_ref()) {
+            (Some(_), Some(_)) => Err(Error::MultipleTargets),
+            (Some(path), None) => {
+                let connect_error = |e| Error::Connect(path.display().to_string(), e);
+                let stream = UnixStream::connect(path).map_err(connect_error)?;
+                stream
+                    .set_write_timeout(Some(IO_TIMEOUT))
+                    .map_err(connect_error)?;
+                Ok(Some(Target::Socket(stream)))
+            }
+            (None, Some(url)) => {
+                let (addr, host, path) = parse_http_url(url)?;
+                let connect_error = |e| Error::Connect(url.clone(), e);
+                let stream =
+                    TcpStream::connect_timeout(&addr, IO_TIMEOUT).map_err(connect_error)?;
+                stream
+                    .set_write_timeout(Some(IO_TIMEOUT))
+                    .map_err(connect_error)?;
+                stream
+                    .set_read_timeout(Some(IO_TIMEOUT))
+                    .map_err(connect_error)?;
+                Ok(Some(Target::Http { stream, host, path }))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn send(&mut self, body: &[u8]) -> io::Result<()> {
+        match self {
+            Target::Socket(stream) => {
+                stream.write_all(body)?;
+                stream.write_all(b"\n")
+            }
+            Target::Http { stream, host, path } => {
+                let head = format!(
+                    "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    path,
+                    host,
+                    body.len()
+                );
+                stream.write_all(head.as_bytes())?;
+                stream.write_all(body)?;
+
+                // The status line is `HTTP/1.1 <status> <reason>`.
+                let mut status_line = String::new();
+                BufReader::new(&*stream).read_line(&mut status_line)?;
+                match status_line.split(' ').nth(1) {
+                    Some(status) if status.starts_with('2') => Ok(()),
+                    _ => Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("unexpected response {:?}", status_line.trim_end()),
+                    )),
+                }
+            }
+        }
+    }
+}
+
+// Splits `url` into the address to connect to, the host header and the request path. Host names
+// are not resolved, the VMM cannot reach a resolver once sandboxed.
+fn parse_http_url(url: &str) -> Result<(SocketAddr, String, String)> {
+    let invalid = || Error::InvalidUrl(url.to_string());
+    if !url.starts_with(HTTP_SCHEME) {
+        return Err(invalid());
+    }
+    let rest = &url[HTTP_SCHEME.len()..];
+    let (authority, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    let addr = authority.parse::<SocketAddr>().map_err(|_| invalid())?;
+    Ok((addr, authority.to_string(), path.to_string()))
+}
+
+/// Samples the residency of the working set of a restored microVM, and notifies the scheduler
+/// once it reaches the threshold.
+pub struct WarmNotifier {
+    // Host ranges, as (address, length), of the pages that make the microVM warm.
+    ranges: Vec<(u64, u64)>,
+    page_size: u64,
+    threshold_percent: u8,
+    period: Duration,
+    target: Option<Target>,
+    start_us: u64,
+    timer: TimerFd,
+}
+
+impl WarmNotifier {
+    /// Samples the `ws_regions` extents of `guest_memory`, restored from `state`, or all of the
+    /// restored memory without extents. Connects to the scheduler configured in `config`; the
+    /// lifecycle socket is notified as well.
+    pub fn new(
+        guest_memory: &GuestMemoryMmap,
+        state: &GuestMemoryState,
+        ws_regions: &[Vec<i64>],
+        config: &WarmNotifyConfig,
+    ) -> Result<Self> {
+        if config.threshold_percent == 0 || config.threshold_percent > 100 {
+            return Err(Error::InvalidThreshold(config.threshold_percent));
+        }
+        if config.sample_period_ms == 0 {
+            return Err(Error::InvalidPeriod);
+        }
+        let ranges = if ws_regions.is_empty() {
+            ws_accounting::guest_host_ranges(guest_memory, state)
+        } else {
+            ws_accounting::ws_host_ranges(guest_memory, state, ws_regions)
+        }
+        .map_err(Error::WsRegions)?;
+
+        Ok(WarmNotifier {
+            ranges,
+            page_size: sysconf::page::pagesize() as u64,
+            threshold_percent: config.threshold_percent,
+            period: Duration::from_millis(config.sample_period_ms),
+            target: Target::connect(config)?,
+            start_us: get_time_us(ClockType::Monotonic),
+            timer: TimerFd::new_custom(ClockId::Monotonic, true, true).map_err(Error::Timer)?,
+        })
+    }
+
+    /// Returns the number of resident pages, along with the number of pages sampled.
+    pub fn sample(&self) -> io::Result<(u64, u64)> {
+        let mut resident_pages = 0;
+        let mut warm_pages = 0;
+        for (addr, len) in self.ranges.iter() {
+            let pages = (len + self.page_size - 1) / self.page_size;
+            let mut residency = vec![0u8; pages as usize];
+            // Safe because the range is part of the guest memory, which stays mapped for the
+            // lifetime of the VMM, and `residency` has a byte for each of its pages.
+            let ret = unsafe {
+                libc::mincore(
+                    *addr as *mut libc::c_void,
+                    *len as usize,
+                    residency.as_mut_ptr(),
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            resident_pages += residency.iter().filter(|page| *page & 1 != 0).count() as u64;
+            warm_pages += pages;
+        }
+        Ok((resident_pages, warm_pages))
+    }
+
+    /// Starts sampling periodically, beginning with a sample straight away.
+    pub fn start(&mut self) {
+        self.timer.set_state(
+            TimerState::Periodic {
+                current: self.period,
+                interval: self.period,
+            },
+            SetTimeFlags::Default,
+        );
+        self.check();
+    }
+
+    // Samples the residency, and notifies the scheduler if the threshold is reached. Returns true
+    // once notified.
+    fn check(&mut self) -> bool {
+        let (resident_pages, warm_pages) = match self.sample() {
+            Ok(sample) => sample,
+            Err(err) => {
+                error!("Cannot sample the working set residency: {}", err);
+                return false;
+            }
+        };
+        if resident_pages * 100 < warm_pages * u64::from(self.threshold_percent) {
+            return false;
+        }
+
+        self.timer
+            .set_state(TimerState::Disarmed, SetTimeFlags::Default);
+        let record = WarmRecord {
+            timestamp_us: get_time_us(ClockType::Monotonic),
+            event: LifecycleEvent::FullyWarmed,
+            resident_pages,
+            warm_pages,
+            resident_ratio: if warm_pages == 0 {
+                1.0
+            } else {
+                resident_pages as f64 / warm_pages as f64
+            },
+            elapsed_us: get_time_us(ClockType::Monotonic).saturating_sub(self.start_us),
+        };
+        info!(
+            "The microVM is fully warmed, {} of {} pages resident after {} us.",
+            resident_pages, warm_pages, record.elapsed_us
+        );
+        LIFECYCLE.notify(LifecycleEvent::FullyWarmed);
+        if let Some(mut target) = self.target.take() {
+            let sent = serde_json::to_vec(&record)
+                .map_err(io::Error::from)
+                .and_then(|body| target.send(&body));
+            if let Err(err) = sent {
+                error!("Cannot send the warm notification: {}", err);
+            }
+        }
+        true
+    }
+}
+
+impl Subscriber for WarmNotifier {
+    /// Handle a read event (EPOLLIN).
+    fn process(&mut self, event: &EpollEvent, _: &mut EventManager) {
+        let source = event.fd();
+        let event_set = event.event_set();
+
+        if !EventSet::IN.contains(event_set) {
+            warn!(
+                "Received unknown event: {:?} from source: {:?}",
+                event_set, source
+            );
+            return;
+        }
+
+        if source == self.timer.as_raw_fd() {
+            // Consume the timer expirations.
+            self.timer.read();
+            self.check();
+        } else {
+            error!("Spurious EventManager event for handler: WarmNotifier");
+        }
+    }
+
+    fn interest_list(&self) -> Vec<EpollEvent> {
+        vec![EpollEvent::new(EventSet::IN, self.timer.as_raw_fd() as u64)]
+    }
+}
+
+/// Starts sampling the residency of the restored microVM with `notifier`. The sampler is
+/// optional, failing to register it does not fail the restore.
+pub fn start(event_manager: &mut EventManager, mut notifier: WarmNotifier) {
+    if notifier.check() {
+        return;
+    }
+    notifier.start();
+    if let Err(err) = event_manager.add_subscriber(Arc::new(Mutex::new(notifier))) {
+        error!("Cannot register the warm notification: {:?}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use std::thread;
+
+    use utils::tempdir::TempDir;
+    use vm_memory::{GuestAddress, GuestMemory};
+
+    use crate::memory_snapshot::GuestMemoryRegionState;
+
+    fn config() -> WarmNotifyConfig {
+        WarmNotifyConfig {
+            threshold_percent: 50,
+            sample_period_ms: 10,
+            socket_path: None,
+            http_url: None,
+        }
+    }
+
+    fn guest_memory(page_size: usize) -> (GuestMemoryMmap, GuestMemoryState) {
+        let guest_memory =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 4 * page_size)]).unwrap();
+        let state = GuestMemoryState {
+            regions: vec![GuestMemoryRegionState {
+                base_address: 0,
+                size: 4 * page_size,
+                offset: 0,
+            }],
+            page_size,
+        };
+        (guest_memory, state)
+    }
+
+    #[test]
+    fn test_parse_http_url() {
+        let (addr, host, path) = parse_http_url("http://127.0.0.1:8080/warm?id=vm0").unwrap();
+        assert_eq!(addr, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(host, "127.0.0.1:8080");
+        assert_eq!(path, "/warm?id=vm0");
+
+        let (addr, _, path) = parse_http_url("http://[::1]:80").unwrap();
+        assert_eq!(addr, "[::1]:80".parse().unwrap());
+        assert_eq!(path, "/");
+
+        for url in [
+            "https://127.0.0.1:443/",
+            "http://localhost:80/",
+            "127.0.0.1:80",
+        ]
+        .iter()
+        {
+            match parse_http_url(url) {
+                Err(Error::InvalidUrl(_)) => (),
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let page_size = sysconf::page::pagesize();
+        let (guest_memory, state) = guest_memory(page_size);
+
+        let mut bad = config();
+        bad.threshold_percent = 101;
+        match WarmNotifier::new(&guest_memory, &state, &[], &bad) {
+            Err(Error::InvalidThreshold(101)) => (),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+
+        let mut bad = config();
+        bad.sample_period_ms = 0;
+        match WarmNotifier::new(&guest_memory, &state, &[], &bad) {
+            Err(Error::InvalidPeriod) => (),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+
+        let mut bad = config();
+        bad.socket_path = Some(PathBuf::from("/no/such/socket"));
+        bad.http_url = Some("http://127.0.0.1:80/".to_string());
+        match WarmNotifier::new(&guest_memory, &state, &[], &bad) {
+            Err(Error::MultipleTargets) => (),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+
+        match WarmNotifier::new(&guest_memory, &state, &[vec![8, 1]], &config()) {
+            Err(Error::WsRegions(_)) => (),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+    }
+
+    #[test]
+    fn test_notify_socket() {
+        let page_size = sysconf::page::pagesize();
+        let (guest_memory, state) = guest_memory(page_size);
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("warm.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let mut config = config();
+        config.socket_path = Some(path);
+        // The first two pages are the working set.
+        let mut notifier =
+            WarmNotifier::new(&guest_memory, &state, &[vec![0, 2]], &config).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        assert_eq!(notifier.sample().unwrap(), (0, 2));
+        assert!(!notifier.check());
+
+        // Touching a page outside of the working set does not warm it.
+        let host_addr = guest_memory.get_host_address(GuestAddress(0)).unwrap();
+        unsafe { *host_addr.add(3 * page_size) = 1 };
+        assert!(!notifier.check());
+
+        unsafe { *host_addr = 1 };
+        assert_eq!(notifier.sample().unwrap(), (1, 2));
+        assert!(notifier.check());
+        assert!(notifier.target.is_none());
+
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(record["event"], "fully-warmed");
+        assert_eq!(record["resident_pages"], 1);
+        assert_eq!(record["warm_pages"], 2);
+        assert_eq!(record["resident_ratio"], 0.5);
+    }
+
+    #[test]
+    fn test_notify_http() {
+        let page_size = sysconf::page::pagesize();
+        let (guest_memory, state) = guest_memory(page_size);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let scheduler = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            (&stream)
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            request_line
+        });
+
+        let mut config = config();
+        config.http_url = Some(format!("http://{}/warm/vm0", addr));
+        // The whole memory is sampled without a working set.
+        let notifier = WarmNotifier::new(&guest_memory, &state, &[], &config).unwrap();
+        let host_addr = guest_memory.get_host_address(GuestAddress(0)).unwrap();
+        unsafe {
+            *host_addr = 1;
+            *host_addr.add(page_size) = 1;
+        }
+        let mut event_manager = EventManager::new().unwrap();
+        start(&mut event_manager, notifier);
+
+        assert_eq!(scheduler.join().unwrap(), "POST /warm/vm0 HTTP/1.1\r\n");
+    }
+}
@@ -0,0 +1,297 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helper threads the VMM thread spreads independent tasks over, such as fetching the KVM dirty
+//! log of each memory slot.
+//!
+//! The VMM thread cannot spawn threads once its seccomp filter is installed, so the helper
+//! threads are started while building the microVM, and install the same filter. Until they are
+//! started, and once they are stopped, the tasks run on the calling thread.
+
+use std::fmt::{Display, Formatter};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, SendError, Sender};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+
+use lazy_static::lazy_static;
+use seccomp::{BpfProgram, SeccompFilter};
+
+/// Number of helper threads.
+pub const WORKER_COUNT: usize = 4;
+
+lazy_static! {
+    /// Helper threads of the process. Not started until `start` is called.
+    pub static ref WORKER_POOL: WorkerPool = WorkerPool::default();
+}
+
+/// Errors associated with the helper threads.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to install the seccomp filter of a helper thread.
+    Seccomp(seccomp::Error),
+    /// Failed to spawn a helper thread.
+    Spawn(std::io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Seccomp(err) => write!(f, "Cannot filter the helper thread syscalls: {}", err),
+            Spawn(err) => write!(f, "Cannot spawn a helper thread: {}", err),
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Task run by `WorkerPool::run`, which may borrow from the caller.
+pub type Task<'a, T> = Box<dyn FnOnce() -> T + Send + 'a>;
+
+/// Threads running the tasks handed over by the VMM thread.
+#[derive(Default)]
+pub struct WorkerPool {
+    workers: Mutex<Vec<Sender<Job>>>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl WorkerPool {
+    /// Starts the helper threads, which run under `seccomp_filter`. Does nothing if they are
+    /// already started.
+    pub fn start(&self, seccomp_filter: BpfProgram) -> Result<(), Error> {
+        let mut workers = self.workers.lock().expect("Poisoned lock");
+        if !workers.is_empty() {
+            return Ok(());
+        }
+        let mut started = Vec::with_capacity(WORKER_COUNT);
+        let mut threads = self.threads.lock().expect("Poisoned lock");
+        for index in 0..WORKER_COUNT {
+            let (jobs, job_receiver) = channel::<Job>();
+            let (ready_sender, ready) = channel();
+            let seccomp_filter = seccomp_filter.clone();
+            let thread = thread::Builder::new()
+                .name(format!("fc_worker {}", index))
+                .spawn(move || {
+                    let filtered = SeccompFilter::apply(seccomp_filter);
+                    let failed = filtered.is_err();
+                    let _ = ready_sender.send(filtered);
+                    if failed {
+                        return;
+                    }
+                    for job in job_receiver.iter() {
+                        job();
+                    }
+                })
+                .map_err(Error::Spawn)?;
+            threads.push(thread);
+            ready
+                .recv()
+                .expect("The helper thread exited")
+                .map_err(Error::Seccomp)?;
+            started.push(jobs);
+        }
+        *workers = started;
+        Ok(())
+    }
+
+    /// Stops the helper threads once they are done with the tasks handed over to them, and
+    /// joins them. The tasks handed over afterwards run on the calling thread.
+    pub fn stop(&self) {
+        self.workers.lock().expect("Poisoned lock").clear();
+        let threads = mem::replace(&mut *self.threads.lock().expect("Poisoned lock"), Vec::new());
+        for thread in threads {
+            let _ = thread.join();
+        }
+    }
+
+    /// Runs `tasks` over the helper threads, and returns their results, in order, once they are
+    /// all done. A task panicking makes `run` panic, once the other tasks are done.
+    pub fn run<'a, T: Send + 'a>(&self, tasks: Vec<Task<'a, T>>) -> Vec<T> {
+        let count = tasks.len();
+        self.run_with(tasks, |results| {
+            let mut ordered: Vec<Option<T>> = (0..count).map(|_| None).collect();
+            for (index, value) in results {
+                ordered[index] = Some(value);
+            }
+            ordered
+                .into_iter()
+                .map(|value| value.expect("A helper thread dropped a task"))
+                .collect()
+        })
+    }
+
+    /// Hands `tasks` over to the helper threads, then calls `f` on the calling thread, which
+    /// takes the results, along with the index of their task, as the tasks complete. Returns
+    /// what `f` returns, once all the tasks are done. A task panicking makes the results panic,
+    /// once the other tasks are done.
+    pub fn run_with<'a, T: Send + 'a, R>(
+        &self,
+        tasks: Vec<Task<'a, T>>,
+        f: impl FnOnce(&mut Results<T>) -> R,
+    ) -> R {
+        let workers = self.workers.lock().expect("Poisoned lock").clone();
+        let (result_sender, receiver) = channel();
+        for (index, task) in tasks.into_iter().enumerate() {
+            let result_sender = result_sender.clone();
+            let job: Box<dyn FnOnce() + Send + 'a> = Box::new(move || {
+                let res = panic::catch_unwind(AssertUnwindSafe(task));
+                let _ = result_sender.send((index, res));
+            });
+            if workers.is_empty() {
+                job();
+                continue;
+            }
+            // Safe because this function does not return, nor unwind, before every job either
+            // ran or was dropped, which the results channel tells once all its senders are gone,
+            // so the borrows of the tasks outlive the jobs.
+            let job: Job = unsafe { mem::transmute(job) };
+            if let Err(SendError(job)) = workers[index % workers.len()].send(job) {
+                job();
+            }
+        }
+        drop(result_sender);
+        f(&mut Results { receiver })
+    }
+}
+
+/// Results of the tasks handed over by `WorkerPool::run_with`, in the order they complete.
+/// Dropping them waits for the remaining tasks.
+pub struct Results<T> {
+    receiver: Receiver<(usize, thread::Result<T>)>,
+}
+
+impl<T> Iterator for Results<T> {
+    type Item = (usize, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.receiver.recv().ok()? {
+            (index, Ok(value)) => Some((index, value)),
+            (_, Err(payload)) => {
+                for _ in self.receiver.iter() {}
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+}
+
+impl<T> Drop for Results<T> {
+    fn drop(&mut self) {
+        for _ in self.receiver.iter() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn squares(pool: &WorkerPool, values: &[usize]) -> Vec<usize> {
+        let tasks = values
+            .iter()
+            .map(|value| Box::new(move || value * value) as Task<usize>)
+            .collect();
+        pool.run(tasks)
+    }
+
+    #[test]
+    fn test_run() {
+        let pool = WorkerPool::default();
+        let values: Vec<usize> = (0..3 * WORKER_COUNT).collect();
+        let expected: Vec<usize> = values.iter().map(|value| value * value).collect();
+
+        // Not started, the tasks run on the calling thread.
+        assert_eq!(squares(&pool, &values), expected);
+
+        pool.start(Vec::new()).unwrap();
+        pool.start(Vec::new()).unwrap();
+        assert_eq!(pool.workers.lock().unwrap().len(), WORKER_COUNT);
+        assert_eq!(squares(&pool, &values), expected);
+        assert!(squares(&pool, &[]).is_empty());
+
+        // The tasks run on the helper threads, and may borrow from the caller.
+        let calls = AtomicUsize::new(0);
+        let tasks = (0..WORKER_COUNT)
+            .map(|_| {
+                let calls = &calls;
+                Box::new(move || {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    thread::current().name().unwrap_or("").to_string()
+                }) as Task<String>
+            })
+            .collect();
+        let names = pool.run(tasks);
+        assert_eq!(calls.load(Ordering::SeqCst), WORKER_COUNT);
+        assert!(names.iter().all(|name| name.starts_with("fc_worker")));
+
+        // Once stopped, the tasks run on the calling thread again.
+        pool.stop();
+        assert!(pool.threads.lock().unwrap().is_empty());
+        assert_eq!(squares(&pool, &values), expected);
+    }
+
+    #[test]
+    fn test_run_with() {
+        let pool = WorkerPool::default();
+        pool.start(Vec::new()).unwrap();
+        let (release, released) = channel();
+        let released = Mutex::new(released);
+        let tasks = vec![
+            Box::new(|| {
+                released.lock().unwrap().recv().unwrap();
+                "slow"
+            }) as Task<&str>,
+            Box::new(|| "fast") as Task<&str>,
+        ];
+        // The results come as the tasks complete, while the caller goes on.
+        let order = pool.run_with(tasks, |results| {
+            let first = results.next().unwrap();
+            release.send(()).unwrap();
+            vec![first, results.next().unwrap()]
+        });
+        assert_eq!(order, vec![(1, "fast"), (0, "slow")]);
+
+        // The tasks the caller leaves are still waited for.
+        let done = AtomicUsize::new(0);
+        let tasks = (0..WORKER_COUNT)
+            .map(|_| {
+                let done = &done;
+                Box::new(move || {
+                    done.fetch_add(1, Ordering::SeqCst);
+                }) as Task<()>
+            })
+            .collect();
+        pool.run_with(tasks, |_| ());
+        assert_eq!(done.load(Ordering::SeqCst), WORKER_COUNT);
+    }
+
+    #[test]
+    fn test_run_panic() {
+        let pool = WorkerPool::default();
+        pool.start(Vec::new()).unwrap();
+        let done = AtomicUsize::new(0);
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            let tasks = vec![
+                Box::new(|| panic!("task failed")) as Task<()>,
+                Box::new(|| {
+                    done.fetch_add(1, Ordering::SeqCst);
+                }) as Task<()>,
+            ];
+            pool.run(tasks)
+        }));
+        assert!(res.is_err());
+        // The other tasks ran, and the helper threads still serve.
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+        assert_eq!(squares(&pool, &[3]), vec![9]);
+    }
+
+    #[test]
+    fn test_error_display() {
+        let err = Error::Spawn(std::io::Error::from_raw_os_error(libc::EAGAIN));
+        let _ = format!("{}{:?}", err, err);
+        let err = Error::Seccomp(seccomp::Error::EmptyRulesVector);
+        let _ = format!("{}{:?}", err, err);
+    }
+}
@@ -0,0 +1,384 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Accounting of the working set prefetch effectiveness.
+//!
+//! Once prefetched, the working set pages are unmapped from the guest memory again, while they
+//! stay in the page cache. Each page the guest accesses afterwards is mapped back by a minor
+//! fault, so the pages mapped in the working set extents are the prefetched pages the guest
+//! accessed, and the pages mapped anywhere else are the faults the working set did not cover.
+//! Mapped pages are read from `/proc/self/pagemap`, which reports them to unprivileged processes.
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use logger::{error, warn, METRICS};
+use polly::event_manager::{EventManager, Subscriber};
+use serde::{Deserialize, Serialize};
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
+use utils::epoll::{EpollEvent, EventSet};
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
+
+use crate::memory_snapshot::GuestMemoryState;
+
+/// Period of the working set accounting samples.
+pub const WS_ACCOUNTING_PERIOD_MS: u64 = 10000;
+
+pub(crate) const PAGEMAP_PATH: &str = "/proc/self/pagemap";
+pub(crate) const PAGEMAP_ENTRY_SIZE: u64 = 8;
+pub(crate) const PAGEMAP_PRESENT: u64 = 1 << 63;
+pub(crate) const PAGEMAP_SWAPPED: u64 = 1 << 62;
+// Pagemap entries read at once.
+pub(crate) const PAGEMAP_BATCH: u64 = 4096;
+
+/// Errors associated with the working set accounting.
+#[derive(Debug)]
+pub enum Error {
+    /// The working set is not prefetched, there is nothing to account.
+    NoPrefetch,
+    /// Failed to open the pagemap of the process.
+    OpenPagemap(io::Error),
+    /// Failed to read the pagemap of the process.
+    ReadPagemap(io::Error),
+    /// Failed to create the sampling timer.
+    Timer(io::Error),
+    /// Failed to unmap the prefetched pages.
+    Unmap(io::Error),
+    /// A working set extent is outside of the guest memory.
+    InvalidExtent(u64, u64),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            NoPrefetch => write!(
+                f,
+                "Working set accounting requires a working set file, load_ws and no uPF handling"
+            ),
+            OpenPagemap(err) => write!(f, "Cannot open {}: {}", PAGEMAP_PATH, err),
+            ReadPagemap(err) => write!(f, "Cannot read {}: {}", PAGEMAP_PATH, err),
+            Timer(err) => write!(f, "Cannot create the sampling timer: {}", err),
+            Unmap(err) => write!(f, "Cannot unmap the prefetched pages: {}", err),
+            InvalidExtent(offset, len) => write!(
+                f,
+                "Extent at file offset {:#x} of length {:#x} is outside guest memory",
+                offset, len
+            ),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Effectiveness of the working set prefetch of a restore.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct WsStats {
+    /// Number of pages prefetched from the working set file.
+    pub prefetched_pages: u64,
+    /// Number of prefetched pages accessed since the restore.
+    pub accessed_pages: u64,
+    /// Number of prefetched pages not accessed since the restore.
+    pub wasted_pages: u64,
+    /// Number of guest pages outside of the working set faulted in since the restore.
+    pub non_ws_faults: u64,
+    /// Ratio of the prefetched pages accessed since the restore.
+    pub hit_ratio: f64,
+    /// Ratio of the prefetched pages not accessed since the restore.
+    pub waste_ratio: f64,
+}
+
+impl WsStats {
+    fn new(prefetched_pages: u64, accessed_pages: u64, mapped_pages: u64) -> Self {
+        let wasted_pages = prefetched_pages.saturating_sub(accessed_pages);
+        let ratio = |pages: u64| {
+            if prefetched_pages == 0 {
+                0.0
+            } else {
+                pages as f64 / prefetched_pages as f64
+            }
+        };
+        WsStats {
+            prefetched_pages,
+            accessed_pages,
+            wasted_pages,
+            non_ws_faults: mapped_pages.saturating_sub(accessed_pages),
+            hit_ratio: ratio(accessed_pages),
+            waste_ratio: ratio(wasted_pages),
+        }
+    }
+
+    fn update_metrics(&self) {
+        let metrics = &METRICS.ws_prefetch;
+        metrics.prefetched_pages.set(self.prefetched_pages as usize);
+        metrics.accessed_pages.set(self.accessed_pages as usize);
+        metrics.wasted_pages.set(self.wasted_pages as usize);
+        metrics.non_ws_faults.set(self.non_ws_faults as usize);
+        metrics.hit_permille.set((self.hit_ratio * 1000.0) as usize);
+        metrics
+            .waste_permille
+            .set((self.waste_ratio * 1000.0) as usize);
+    }
+}
+
+/// Samples the accesses to the prefetched working set pages.
+pub struct WsAccounting {
+    pagemap: File,
+    page_size: u64,
+    // Host ranges, as (address, length), of the working set extents and of the guest memory.
+    ws_ranges: Vec<(u64, u64)>,
+    guest_ranges: Vec<(u64, u64)>,
+    timer: TimerFd,
+}
+
+impl WsAccounting {
+    /// Accounts the accesses to the `ws_regions` extents of `guest_memory`, restored from
+    /// `state`. The extents must have been prefetched already.
+    pub fn new(
+        guest_memory: &GuestMemoryMmap,
+        state: &GuestMemoryState,
+        ws_regions: &[Vec<i64>],
+    ) -> Result<Self> {
+        let ws_ranges = ws_host_ranges(guest_memory, state, ws_regions)?;
+        if ws_ranges.is_empty() {
+            return Err(Error::NoPrefetch);
+        }
+        let guest_ranges = guest_host_ranges(guest_memory, state)?;
+
+        Ok(WsAccounting {
+            // Opened now, the VMM may not be allowed to open it once sandboxed.
+            pagemap: File::open(PAGEMAP_PATH).map_err(Error::OpenPagemap)?,
+            page_size: sysconf::page::pagesize() as u64,
+            ws_ranges,
+            guest_ranges,
+            timer: TimerFd::new_custom(ClockId::Monotonic, true, true).map_err(Error::Timer)?,
+        })
+    }
+
+    /// Unmaps the prefetched pages, which stay in the page cache, so that the next guest access
+    /// to each of them maps it back. Must be called before resuming the guest, since the pages it
+    /// wrote to would be lost.
+    pub fn reset(&self) -> Result<()> {
+        for (addr, len) in self.ws_ranges.iter() {
+            // Safe because the range is part of the guest memory, backed by a file mapping whose
+            // contents are preserved by the page cache.
+            let ret = unsafe { libc::madvise(*addr as _, *len as usize, libc::MADV_DONTNEED) };
+            if ret < 0 {
+                return Err(Error::Unmap(io::Error::last_os_error()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts sampling periodically, the samples are reported in the metrics.
+    pub fn start(&mut self) {
+        let period = Duration::from_millis(WS_ACCOUNTING_PERIOD_MS);
+        self.timer.set_state(
+            TimerState::Periodic {
+                current: period,
+                interval: period,
+            },
+            SetTimeFlags::Default,
+        );
+    }
+
+    /// Counts the accessed working set pages and the other guest pages faulted in.
+    pub fn sample(&self) -> io::Result<WsStats> {
+        let mut prefetched_pages = 0;
+        let mut accessed_pages = 0;
+        for (addr, len) in self.ws_ranges.iter() {
+            prefetched_pages += len / self.page_size;
+            accessed_pages += count_mapped_pages(&self.pagemap, self.page_size, *addr, *len)?;
+        }
+        let mut mapped_pages = 0;
+        for (addr, len) in self.guest_ranges.iter() {
+            mapped_pages += count_mapped_pages(&self.pagemap, self.page_size, *addr, *len)?;
+        }
+        Ok(WsStats::new(prefetched_pages, accessed_pages, mapped_pages))
+    }
+}
+
+impl Subscriber for WsAccounting {
+    /// Handle a read event (EPOLLIN).
+    fn process(&mut self, event: &EpollEvent, _: &mut EventManager) {
+        let source = event.fd();
+        let event_set = event.event_set();
+
+        if !EventSet::IN.contains(event_set) {
+            warn!(
+                "Received unknown event: {:?} from source: {:?}",
+                event_set, source
+            );
+            return;
+        }
+
+        if source == self.timer.as_raw_fd() {
+            // Consume the timer expirations.
+            self.timer.read();
+            match self.sample() {
+                Ok(stats) => stats.update_metrics(),
+                Err(err) => error!("Cannot sample the working set accesses: {}", err),
+            }
+        } else {
+            error!("Spurious EventManager event for handler: WsAccounting");
+        }
+    }
+
+    fn interest_list(&self) -> Vec<EpollEvent> {
+        vec![EpollEvent::new(EventSet::IN, self.timer.as_raw_fd() as u64)]
+    }
+}
+
+/// Returns the host ranges, as (address, length), of the `ws_regions` extents of `guest_memory`,
+/// restored from `state`.
+pub(crate) fn ws_host_ranges(
+    guest_memory: &GuestMemoryMmap,
+    state: &GuestMemoryState,
+    ws_regions: &[Vec<i64>],
+) -> Result<Vec<(u64, u64)>> {
+    let page_size = sysconf::page::pagesize() as u64;
+    let mut ws_ranges = Vec::new();
+    for item in ws_regions {
+        let off = item[0] as u64 * page_size;
+        let len = item[1] as u64 * page_size;
+        let chunks = state
+            .translate_extent(off, len)
+            .map_err(|_| Error::InvalidExtent(off, len))?;
+        for chunk in chunks {
+            let region = &state.regions[chunk.region_index];
+            let addr = host_addr(guest_memory, region.base_address + chunk.region_offset)
+                .ok_or(Error::InvalidExtent(off, len))?;
+            ws_ranges.push((addr, chunk.len));
+        }
+    }
+    Ok(ws_ranges)
+}
+
+/// Returns the host ranges, as (address, length), of the regions of `guest_memory` restored from
+/// `state`.
+pub(crate) fn guest_host_ranges(
+    guest_memory: &GuestMemoryMmap,
+    state: &GuestMemoryState,
+) -> Result<Vec<(u64, u64)>> {
+    state
+        .regions
+        .iter()
+        .map(|region| {
+            host_addr(guest_memory, region.base_address)
+                .map(|addr| (addr, region.size as u64))
+                .ok_or(Error::InvalidExtent(region.offset, region.size as u64))
+        })
+        .collect()
+}
+
+/// Returns the number of pages of the `len` bytes at `addr` mapped in the process, as reported by
+/// `pagemap`, the opened pagemap of the process.
+pub(crate) fn count_mapped_pages(
+    pagemap: &File,
+    page_size: u64,
+    addr: u64,
+    len: u64,
+) -> io::Result<u64> {
+    let first_page = addr / page_size;
+    let page_count = len / page_size;
+    let mut entries = vec![0u8; (PAGEMAP_BATCH * PAGEMAP_ENTRY_SIZE) as usize];
+    let mut mapped = 0;
+    let mut page = 0;
+    while page < page_count {
+        let batch = std::cmp::min(PAGEMAP_BATCH, page_count - page);
+        let buf = &mut entries[..(batch * PAGEMAP_ENTRY_SIZE) as usize];
+        pagemap.read_exact_at(buf, (first_page + page) * PAGEMAP_ENTRY_SIZE)?;
+        mapped += buf
+            .chunks(PAGEMAP_ENTRY_SIZE as usize)
+            .filter(|entry| {
+                let mut bytes = [0u8; PAGEMAP_ENTRY_SIZE as usize];
+                bytes.copy_from_slice(entry);
+                u64::from_ne_bytes(bytes) & (PAGEMAP_PRESENT | PAGEMAP_SWAPPED) != 0
+            })
+            .count() as u64;
+        page += batch;
+    }
+    Ok(mapped)
+}
+
+fn host_addr(guest_memory: &GuestMemoryMmap, guest_addr: u64) -> Option<u64> {
+    guest_memory
+        .get_host_address(GuestAddress(guest_addr))
+        .map(|addr| addr as u64)
+        .ok()
+}
+
+/// Unmaps the prefetched pages of `accounting`, and returns the accounting right after the
+/// prefetch. Called before building the microVM, so that a failure leaves nothing to roll back.
+pub fn prepare(accounting: &WsAccounting) -> Result<WsStats> {
+    accounting.reset()?;
+    let stats = accounting.sample().map_err(Error::ReadPagemap)?;
+    stats.update_metrics();
+    Ok(stats)
+}
+
+/// Reports the accesses to the pages of the prepared `accounting` in the metrics from now on.
+pub fn start(event_manager: &mut EventManager, mut accounting: WsAccounting) {
+    accounting.start();
+    if let Err(err) = event_manager.add_subscriber(Arc::new(Mutex::new(accounting))) {
+        error!("Cannot register the working set accounting: {:?}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ws_stats() {
+        let stats = WsStats::new(100, 75, 90);
+        assert_eq!(stats.wasted_pages, 25);
+        assert_eq!(stats.non_ws_faults, 15);
+        assert!((stats.hit_ratio - 0.75).abs() < f64::EPSILON);
+        assert!((stats.waste_ratio - 0.25).abs() < f64::EPSILON);
+
+        let stats = WsStats::new(0, 0, 10);
+        assert_eq!(stats.non_ws_faults, 10);
+        assert_eq!(stats.hit_ratio, 0.0);
+        assert_eq!(stats.waste_ratio, 0.0);
+    }
+
+    #[test]
+    fn test_sample() {
+        let page_size = sysconf::page::pagesize();
+        let guest_memory =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 4 * page_size)]).unwrap();
+        let state = GuestMemoryState {
+            regions: vec![crate::memory_snapshot::GuestMemoryRegionState {
+                base_address: 0,
+                size: 4 * page_size,
+                offset: 0,
+            }],
+            page_size,
+        };
+        // The first two pages are the working set.
+        let accounting = WsAccounting::new(&guest_memory, &state, &[vec![0, 2]]).unwrap();
+        assert_eq!(accounting.sample().unwrap(), WsStats::new(2, 0, 0));
+
+        // One working set page and one other page are accessed.
+        let host_addr = guest_memory.get_host_address(GuestAddress(0)).unwrap();
+        unsafe {
+            *host_addr = 1;
+            *host_addr.add(3 * page_size) = 1;
+        }
+        assert_eq!(accounting.sample().unwrap(), WsStats::new(2, 1, 2));
+
+        accounting.reset().unwrap();
+        assert_eq!(accounting.sample().unwrap(), WsStats::new(2, 0, 1));
+
+        match WsAccounting::new(&guest_memory, &state, &[]) {
+            Err(Error::NoPrefetch) => (),
+            res => panic!("Unexpected result: {:?}", res.err()),
+        }
+    }
+}
@@ -0,0 +1,303 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Working set layouts built from fault traces.
+//!
+//! The pages faulted in after a restore are clustered into extents of the memory file, ordered
+//! by their first access, which become the `ws_regions` of the snapshot load request. The offline
+//! tools building working set files and those evaluating them share this clustering.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read};
+
+use crate::fault_trace::{parse_header, FaultRecord, HEADER_SIZE, RECORD_SIZE};
+
+/// Errors associated with working set layouts.
+#[derive(Debug)]
+pub enum Error {
+    /// The trace does not start with a header of a supported format version.
+    InvalidHeader,
+    /// A `ws_regions` entry is not a (page, number of pages) pair.
+    InvalidRegion(Vec<i64>),
+    /// Failed to read the fault trace.
+    ReadTrace(io::Error),
+    /// A fault is recorded at a memory file offset which is not page aligned.
+    UnalignedFault(u64),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            InvalidHeader => write!(f, "Not a fault trace, or an unsupported format version"),
+            InvalidRegion(region) => write!(f, "Invalid ws_regions entry {:?}", region),
+            ReadTrace(err) => write!(f, "Failed to read the fault trace: {}", err),
+            UnalignedFault(offset) => write!(
+                f,
+                "Fault at memory file offset {:#x} is not page aligned",
+                offset
+            ),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Page faults read from a fault trace.
+#[derive(Debug, PartialEq)]
+pub struct Trace {
+    /// Page size of the traced host, in bytes.
+    pub page_size: u64,
+    /// Faults, in the order they were serviced.
+    pub records: Vec<FaultRecord>,
+}
+
+impl Trace {
+    /// Returns the memory file pages of the faults, each on its first access only, in access
+    /// order. Faults recorded later than `until_us` microseconds after the restore are left out.
+    pub fn first_accesses(&self, until_us: Option<u64>) -> Result<Vec<u64>> {
+        let mut seen = HashSet::new();
+        let mut pages = Vec::new();
+        for record in &self.records {
+            if let Some(until_us) = until_us {
+                if u64::from(record.timestamp_us) > until_us {
+                    break;
+                }
+            }
+            if record.file_offset % self.page_size != 0 {
+                return Err(Error::UnalignedFault(record.file_offset));
+            }
+            let page = record.file_offset / self.page_size;
+            if seen.insert(page) {
+                pages.push(page);
+            }
+        }
+        Ok(pages)
+    }
+}
+
+/// Clustering of the faulted pages into working set extents.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ClusterConfig {
+    /// Largest run of pages not faulted in between two faulted pages of the same extent. The
+    /// gap pages are prefetched as well, trading working set size for fewer, larger extents.
+    pub max_gap_pages: u64,
+    /// Faults recorded later than this many microseconds after the restore are left out.
+    pub until_us: Option<u64>,
+}
+
+/// Working set extent, in pages of the memory file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Extent {
+    /// First page of the extent.
+    pub page: u64,
+    /// Number of pages of the extent.
+    pub pages: u64,
+}
+
+/// Reads the fault trace from `reader`. A record cut short, by a Firecracker process killed
+/// while recording, ends the trace.
+pub fn read_trace<R: Read>(reader: &mut R) -> Result<Trace> {
+    let mut header = [0u8; HEADER_SIZE];
+    reader.read_exact(&mut header).map_err(Error::ReadTrace)?;
+    let page_size = u64::from(parse_header(&header).ok_or(Error::InvalidHeader)?);
+
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).map_err(Error::ReadTrace)?;
+    let mut bytes = [0u8; RECORD_SIZE];
+    let records = data
+        .chunks_exact(RECORD_SIZE)
+        .map(|chunk| {
+            bytes.copy_from_slice(chunk);
+            FaultRecord::from_bytes(&bytes)
+        })
+        .collect();
+
+    Ok(Trace { page_size, records })
+}
+
+/// Clusters the faulted pages of `trace` into extents, ordered by their first access.
+pub fn cluster(trace: &Trace, config: &ClusterConfig) -> Result<Vec<Extent>> {
+    Ok(cluster_pages(
+        &trace.first_accesses(config.until_us)?,
+        config.max_gap_pages,
+    ))
+}
+
+/// Clusters `pages`, memory file pages each listed once in the order of their first access, into
+/// extents ordered by their first access. Extents span gaps of at most `max_gap_pages` pages.
+pub fn cluster_pages(pages: &[u64], max_gap_pages: u64) -> Vec<Extent> {
+    // Index of the first access to each page, by page.
+    let first_access: BTreeMap<u64, usize> = pages
+        .iter()
+        .copied()
+        .enumerate()
+        .map(|(index, page)| (page, index))
+        .collect();
+
+    // Extents with the index of their first access.
+    let mut extents: Vec<(usize, Extent)> = Vec::new();
+    for (page, index) in first_access {
+        if let Some((first, extent)) = extents.last_mut() {
+            let end = extent.page + extent.pages;
+            if page - end <= max_gap_pages {
+                extent.pages = page + 1 - extent.page;
+                *first = std::cmp::min(*first, index);
+                continue;
+            }
+        }
+        extents.push((index, Extent { page, pages: 1 }));
+    }
+    extents.sort_by_key(|(first, _)| *first);
+
+    extents.into_iter().map(|(_, extent)| extent).collect()
+}
+
+/// Returns the `ws_regions` of the snapshot load request for `extents`.
+pub fn ws_regions(extents: &[Extent]) -> Vec<Vec<i64>> {
+    extents
+        .iter()
+        .map(|extent| vec![extent.page as i64, extent.pages as i64])
+        .collect()
+}
+
+/// Returns the extents of the `ws_regions` of a snapshot load request.
+pub fn extents(ws_regions: &[Vec<i64>]) -> Result<Vec<Extent>> {
+    ws_regions
+        .iter()
+        .map(|region| match region.as_slice() {
+            [page, pages] if *page >= 0 && *pages > 0 => Ok(Extent {
+                page: *page as u64,
+                pages: *pages as u64,
+            }),
+            _ => Err(Error::InvalidRegion(region.clone())),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fault_trace::{header, NO_VCPU};
+
+    const PAGE_SIZE: u64 = 0x1000;
+
+    fn record(timestamp_us: u32, page: u64) -> FaultRecord {
+        FaultRecord {
+            timestamp_us,
+            vcpu_id: NO_VCPU,
+            write: false,
+            guest_addr: page * PAGE_SIZE,
+            file_offset: page * PAGE_SIZE,
+        }
+    }
+
+    fn trace(pages: &[u64]) -> Trace {
+        Trace {
+            page_size: PAGE_SIZE,
+            records: pages
+                .iter()
+                .enumerate()
+                .map(|(index, page)| record(index as u32 * 10, *page))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_read_trace() {
+        let mut bytes = header(PAGE_SIZE as u32).to_vec();
+        bytes.extend_from_slice(&record(1, 2).to_bytes());
+        bytes.extend_from_slice(&record(3, 4).to_bytes());
+        // A record cut short.
+        bytes.extend_from_slice(&[0u8; 5]);
+        assert_eq!(
+            read_trace(&mut bytes.as_slice()).unwrap(),
+            Trace {
+                page_size: PAGE_SIZE,
+                records: vec![record(1, 2), record(3, 4)],
+            }
+        );
+
+        bytes[0] = b'X';
+        match read_trace(&mut bytes.as_slice()) {
+            Err(Error::InvalidHeader) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match read_trace(&mut [0u8; 4].as_ref()) {
+            Err(Error::ReadTrace(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_first_accesses() {
+        let trace = trace(&[10, 3, 11, 3, 4, 10]);
+        assert_eq!(trace.first_accesses(None).unwrap(), vec![10, 3, 11, 4]);
+        assert_eq!(trace.first_accesses(Some(20)).unwrap(), vec![10, 3, 11]);
+    }
+
+    #[test]
+    fn test_cluster() {
+        let trace = trace(&[10, 3, 11, 4, 3, 20, 13, 5]);
+
+        // Without gaps, each run of faulted pages is an extent.
+        let extents = cluster(&trace, &ClusterConfig::default()).unwrap();
+        assert_eq!(
+            extents,
+            vec![
+                Extent { page: 10, pages: 2 },
+                Extent { page: 3, pages: 3 },
+                Extent { page: 20, pages: 1 },
+                Extent { page: 13, pages: 1 },
+            ]
+        );
+
+        // Gaps of a page are filled, merging pages 10 to 13.
+        let config = ClusterConfig {
+            max_gap_pages: 1,
+            until_us: None,
+        };
+        let extents = cluster(&trace, &config).unwrap();
+        assert_eq!(
+            extents,
+            vec![
+                Extent { page: 10, pages: 4 },
+                Extent { page: 3, pages: 3 },
+                Extent { page: 20, pages: 1 },
+            ]
+        );
+
+        // Only the faults of the first 30 us.
+        let config = ClusterConfig {
+            max_gap_pages: 0,
+            until_us: Some(30),
+        };
+        let extents = cluster(&trace, &config).unwrap();
+        assert_eq!(
+            extents,
+            vec![Extent { page: 10, pages: 2 }, Extent { page: 3, pages: 2 }]
+        );
+
+        let mut trace = trace;
+        trace.records[0].file_offset += 1;
+        match cluster(&trace, &ClusterConfig::default()) {
+            Err(Error::UnalignedFault(offset)) => assert_eq!(offset, 10 * PAGE_SIZE + 1),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_ws_regions() {
+        let extents_in = [Extent { page: 2, pages: 2 }, Extent { page: 0, pages: 1 }];
+        let regions = ws_regions(&extents_in);
+        assert_eq!(regions, vec![vec![2, 2], vec![0, 1]]);
+        assert_eq!(extents(&regions).unwrap(), extents_in.to_vec());
+        assert!(ws_regions(&[]).is_empty());
+        match extents(&[vec![1, 0]]) {
+            Err(Error::InvalidRegion(region)) => assert_eq!(region, vec![1, 0]),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
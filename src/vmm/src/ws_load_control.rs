@@ -0,0 +1,150 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Deadline and cancellation of the working set load.
+//!
+//! The load blocks the snapshot load request, so a slow mount of the snapshot files would stall
+//! the resume for as long as it takes to read the working set. The helper threads check
+//! `WS_LOAD` before each chunk, and stop once the deadline of the load passes or the API thread
+//! cancels it. The abandoned extents are then faulted in lazily by the guest, as with `load_ws`
+//! unset.
+
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use utils::time::{get_time_us, ClockType};
+
+lazy_static! {
+    /// Deadline and cancellation of the working set load of the process.
+    pub static ref WS_LOAD: WsLoadControl = WsLoadControl::new();
+}
+
+// No deadline.
+const NO_DEADLINE: u64 = u64::MAX;
+
+/// Reasons the working set load stopped short.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    /// The deadline of the load passed.
+    Deadline,
+    /// The load was cancelled through the API.
+    Cancelled,
+}
+
+impl Display for StopReason {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            StopReason::Deadline => write!(f, "deadline passed"),
+            StopReason::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// State of the working set load in progress, if any.
+pub struct WsLoadControl {
+    active: AtomicBool,
+    cancelled: AtomicBool,
+    deadline_us: AtomicU64,
+    // The reason of the first stop, once reported.
+    stopped: AtomicU8,
+}
+
+/// Marks the end of the working set load when dropped.
+pub struct LoadGuard<'a> {
+    control: &'a WsLoadControl,
+}
+
+impl Drop for LoadGuard<'_> {
+    fn drop(&mut self) {
+        self.control.active.store(false, Ordering::SeqCst);
+    }
+}
+
+impl WsLoadControl {
+    fn new() -> Self {
+        WsLoadControl {
+            active: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+            deadline_us: AtomicU64::new(NO_DEADLINE),
+            stopped: AtomicU8::new(0),
+        }
+    }
+
+    /// Starts a working set load which stops after `deadline`, if any, until the returned guard
+    /// is dropped.
+    pub fn begin(&self, deadline: Option<Duration>) -> LoadGuard {
+        let deadline_us = deadline.map_or(NO_DEADLINE, |deadline| {
+            get_time_us(ClockType::Monotonic).saturating_add(deadline.as_micros() as u64)
+        });
+        self.deadline_us.store(deadline_us, Ordering::SeqCst);
+        self.cancelled.store(false, Ordering::SeqCst);
+        self.stopped.store(0, Ordering::SeqCst);
+        self.active.store(true, Ordering::SeqCst);
+        LoadGuard { control: self }
+    }
+
+    /// Cancels the working set load in progress. Returns whether there was one.
+    pub fn cancel(&self) -> bool {
+        if !self.active.load(Ordering::SeqCst) {
+            return false;
+        }
+        self.cancelled.store(true, Ordering::SeqCst);
+        true
+    }
+
+    /// Returns why the load should stop, if it should.
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        let reason = if !self.active.load(Ordering::Relaxed) {
+            return None;
+        } else if self.cancelled.load(Ordering::Relaxed) {
+            StopReason::Cancelled
+        } else if get_time_us(ClockType::Monotonic) >= self.deadline_us.load(Ordering::Relaxed) {
+            StopReason::Deadline
+        } else {
+            return None;
+        };
+        // The helper threads all see the stop, keep the first reason.
+        let code = match reason {
+            StopReason::Deadline => 1,
+            StopReason::Cancelled => 2,
+        };
+        match self
+            .stopped
+            .compare_exchange(0, code, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(_) => Some(reason),
+            Err(1) => Some(StopReason::Deadline),
+            Err(_) => Some(StopReason::Cancelled),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stop_reason() {
+        let control = WsLoadControl::new();
+        assert!(!control.cancel());
+
+        let load = control.begin(None);
+        assert_eq!(control.stop_reason(), None);
+        assert!(control.cancel());
+        assert_eq!(control.stop_reason(), Some(StopReason::Cancelled));
+        drop(load);
+        assert_eq!(control.stop_reason(), None);
+        assert!(!control.cancel());
+
+        // A new load starts afresh, and the first reason sticks.
+        let _load = control.begin(Some(Duration::from_millis(0)));
+        assert_eq!(control.stop_reason(), Some(StopReason::Deadline));
+        assert!(control.cancel());
+        assert_eq!(control.stop_reason(), Some(StopReason::Deadline));
+
+        let _load = control.begin(Some(Duration::from_secs(3600)));
+        assert_eq!(control.stop_reason(), None);
+    }
+}
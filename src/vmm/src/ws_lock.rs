@@ -0,0 +1,190 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Locking of the working set of latency-critical microVMs in memory.
+//!
+//! Under host memory pressure, the working set pages of a restored microVM are reclaimed like any
+//! other page, and the guest then waits on a major fault the next time it touches them. Locking
+//! the working set extents keeps them resident, either as the guest faults them in, with
+//! `mlock2(MLOCK_ONFAULT)`, or all at once, with `mlock`, which also loads them.
+//!
+//! The locked bytes count against `RLIMIT_MEMLOCK`, which is raised to fit the working set when
+//! the process may. The extents past the limit are left unlocked.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use logger::{warn, Metric, METRICS};
+use vm_memory::GuestMemoryMmap;
+
+use crate::memory_snapshot::GuestMemoryState;
+use crate::vmm_config::snapshot::WsLockMode;
+use crate::ws_accounting;
+
+// Locks the pages of the range as they are faulted in, see mlock2(2).
+const MLOCK_ONFAULT: libc::c_int = 1;
+
+/// Errors associated with locking the working set.
+#[derive(Debug)]
+pub enum Error {
+    /// A working set extent is outside of the guest memory.
+    Extents(ws_accounting::Error),
+    /// The working set accounting unmaps the prefetched pages, which cannot be done once locked.
+    WithAccounting,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Extents(err) => write!(f, "Cannot locate the working set: {}", err),
+            WithAccounting => write!(f, "The working set cannot be both locked and accounted for"),
+        }
+    }
+}
+
+/// Locks the `ws_regions` extents of `guest_memory`, restored from `state`, in memory. Returns
+/// the number of locked bytes.
+///
+/// Locking stops at the first extent that does not fit in `RLIMIT_MEMLOCK` or fails to lock,
+/// without failing the restore.
+pub fn lock_working_set(
+    guest_memory: &GuestMemoryMmap,
+    state: &GuestMemoryState,
+    ws_regions: &[Vec<i64>],
+    mode: WsLockMode,
+) -> Result<u64, Error> {
+    let ranges =
+        ws_accounting::ws_host_ranges(guest_memory, state, ws_regions).map_err(Error::Extents)?;
+    let total: u64 = ranges.iter().map(|&(_, len)| len).sum();
+    let budget = match raise_memlock_limit(total) {
+        Ok(budget) => budget,
+        Err(e) => {
+            warn!("Cannot raise RLIMIT_MEMLOCK: {}", e);
+            total
+        }
+    };
+
+    let mut locked = 0;
+    for &(addr, len) in ranges.iter() {
+        if locked + len > budget {
+            warn!(
+                "RLIMIT_MEMLOCK leaves {} of the {} working set bytes unlocked",
+                total - locked,
+                total
+            );
+            METRICS.snapshot.ws_lock_fails.inc();
+            break;
+        }
+        if let Err(e) = lock_range(addr, len, mode) {
+            warn!(
+                "Cannot lock the working set at {:#x}, length {:#x}: {}",
+                addr, len, e
+            );
+            METRICS.snapshot.ws_lock_fails.inc();
+            break;
+        }
+        locked += len;
+    }
+    METRICS.snapshot.ws_bytes_locked.add(locked as usize);
+    Ok(locked)
+}
+
+fn lock_range(addr: u64, len: u64, mode: WsLockMode) -> io::Result<()> {
+    // Safe because locking pages does not modify memory.
+    let ret = unsafe {
+        match mode {
+            WsLockMode::OnFault => {
+                libc::syscall(libc::SYS_mlock2, addr, len, MLOCK_ONFAULT) as libc::c_int
+            }
+            WsLockMode::Full => libc::mlock(addr as _, len as usize),
+        }
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Raises the soft `RLIMIT_MEMLOCK` to `len` bytes if it is lower, along with the hard limit if
+// the process may raise it, and up to the hard limit otherwise. Returns how many of the `len`
+// bytes the limit then lets the process lock.
+fn raise_memlock_limit(len: u64) -> io::Result<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // Safe because `limit` is valid for the duration of the call.
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if limit.rlim_cur == libc::RLIM_INFINITY || limit.rlim_cur >= len {
+        return Ok(len);
+    }
+
+    let wanted = libc::rlimit {
+        rlim_cur: len,
+        rlim_max: std::cmp::max(len, limit.rlim_max),
+    };
+    // Safe because the limits are valid for the duration of the calls. Raising the hard limit
+    // takes CAP_SYS_RESOURCE.
+    if unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &wanted) } == 0 {
+        return Ok(len);
+    }
+    let raised = libc::rlimit {
+        rlim_cur: limit.rlim_max,
+        rlim_max: limit.rlim_max,
+    };
+    if unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &raised) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(std::cmp::min(len, raised.rlim_cur))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::GuestAddress;
+
+    use crate::memory_snapshot::SnapshotMemory;
+
+    #[test]
+    fn test_lock_working_set() {
+        let page_size = sysconf::page::pagesize();
+        let guest_memory =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 8 * page_size)]).unwrap();
+        let state = guest_memory.describe();
+
+        for mode in [WsLockMode::OnFault, WsLockMode::Full].iter() {
+            // Locking may be denied by a low RLIMIT_MEMLOCK, but never locks more than asked.
+            let locked =
+                lock_working_set(&guest_memory, &state, &[vec![1, 2], vec![4, 1]], *mode).unwrap();
+            assert!(locked <= 3 * page_size as u64);
+        }
+        assert_eq!(
+            lock_working_set(&guest_memory, &state, &[], WsLockMode::Full).unwrap(),
+            0
+        );
+
+        // Extents outside of the guest memory are rejected.
+        match lock_working_set(&guest_memory, &state, &[vec![7, 2]], WsLockMode::Full) {
+            Err(Error::Extents(_)) => (),
+            _ => panic!("Extents outside of the guest memory should be rejected."),
+        }
+    }
+
+    #[test]
+    fn test_raise_memlock_limit() {
+        assert_eq!(raise_memlock_limit(0).unwrap(), 0);
+        assert!(raise_memlock_limit(4096).unwrap() <= 4096);
+    }
+
+    #[test]
+    fn test_error_display() {
+        let err = Error::WithAccounting;
+        let _ = format!("{}{:?}", err, err);
+        let err = Error::Extents(ws_accounting::Error::InvalidExtent(0, 4096));
+        let _ = format!("{}{:?}", err, err);
+    }
+}
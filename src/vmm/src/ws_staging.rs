@@ -0,0 +1,266 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Staging of the working set files in a memory-backed directory.
+//!
+//! Loading the working set from a WS file still waits on storage for the parts evicted from the
+//! page cache. Staging copies the WS file to a directory on a tmpfs or ramdisk, at the restore or
+//! ahead of it, and the restore maps the copy instead. A staged file is named after the inode,
+//! length and modification time of the WS file, so that the restores of the same snapshot share
+//! it. The staged files of a directory are capped by a quota, checked under an exclusive `flock`
+//! of the directory, and the ones a process staged are removed when it stops.
+
+use std::cmp::min;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use logger::{warn, Metric, METRICS};
+
+use crate::prefetch_coordinator::FileLock;
+use crate::vmm_config::snapshot::{LoadSnapshotParams, WsStagingConfig};
+
+// Largest number of bytes copied by a `sendfile` call.
+const MAX_SENDFILE: u64 = 1 << 30;
+
+lazy_static! {
+    // Files staged by this process, removed when it stops.
+    static ref STAGED_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+}
+
+/// Errors associated with staging the WS files.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to copy the WS file to the staging directory.
+    Copy(PathBuf, io::Error),
+    /// Failed to inspect the WS file or the staging directory.
+    Metadata(PathBuf, io::Error),
+    /// The staged files would exceed the quota, as (WS file length, staged bytes, quota).
+    Quota(u64, u64, u64),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::Error::*;
+        match self {
+            Copy(path, err) => write!(f, "Cannot stage {}: {}", path.display(), err),
+            Metadata(path, err) => write!(f, "Cannot inspect {}: {}", path.display(), err),
+            Quota(len, staged, quota) => write!(
+                f,
+                "Staging {} bytes over the {} bytes staged exceeds the quota of {} bytes",
+                len, staged, quota
+            ),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Stages the ws file of the restore described by `params` if it sets `ws_staging`, and returns
+/// the path of the copy, opened. Returns `None`, counted in the metrics, if the ws file could not
+/// be staged. A ws file passed as an inherited file descriptor is not staged.
+pub fn stage_ws_file(params: &LoadSnapshotParams) -> Option<(PathBuf, File)> {
+    let config = params.ws_staging.as_ref()?;
+    if params.ws_file_fd.is_some() || params.ws_file_path.as_os_str().is_empty() {
+        return None;
+    }
+    stage(config, &params.ws_file_path)
+        .map_err(|e| {
+            METRICS.snapshot.ws_staging_fails.inc();
+            warn!("Cannot stage the ws file: {}", e);
+        })
+        .ok()
+}
+
+/// Returns the path of the copy of `ws_file_path` in the staging directory of `config`, opened,
+/// copying it there first unless another restore did.
+pub fn stage(config: &WsStagingConfig, ws_file_path: &Path) -> Result<(PathBuf, File)> {
+    let metadata =
+        fs::metadata(ws_file_path).map_err(|e| Error::Metadata(ws_file_path.to_path_buf(), e))?;
+    let name = staged_name(ws_file_path, &metadata);
+    let staged_path = config.dir.join(&name);
+    if let Some(file) = open_staged(&staged_path, metadata.len()) {
+        return Ok((staged_path, file));
+    }
+
+    let dir_error = |e| Error::Metadata(config.dir.clone(), e);
+    let dir = File::open(&config.dir).map_err(dir_error)?;
+    let _lock = FileLock::exclusive(&dir).map_err(dir_error)?;
+    // Another restore may have staged it meanwhile.
+    if let Some(file) = open_staged(&staged_path, metadata.len()) {
+        return Ok((staged_path, file));
+    }
+    let staged = staged_bytes(&config.dir).map_err(dir_error)?;
+    let quota = config.quota_mib << 20;
+    if staged + metadata.len() > quota {
+        return Err(Error::Quota(metadata.len(), staged, quota));
+    }
+
+    // The copy is only renamed in place once complete.
+    let tmp_path = config
+        .dir
+        .join(format!(".{}-{}.tmp", std::process::id(), name));
+    copy(ws_file_path, &tmp_path, metadata.len())
+        .and_then(|_| fs::rename(&tmp_path, &staged_path))
+        .map_err(|e| {
+            let _ = fs::remove_file(&tmp_path);
+            Error::Copy(ws_file_path.to_path_buf(), e)
+        })?;
+    STAGED_FILES
+        .lock()
+        .expect("Poisoned lock")
+        .push(staged_path.clone());
+    METRICS
+        .snapshot
+        .ws_staged_bytes
+        .add(metadata.len() as usize);
+    let file = File::open(&staged_path).map_err(|e| Error::Copy(staged_path.clone(), e))?;
+    Ok((staged_path, file))
+}
+
+/// Removes the files this process staged. The restores mapping them keep their content.
+pub fn cleanup() {
+    for path in STAGED_FILES.lock().expect("Poisoned lock").drain(..) {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Cannot remove the staged file {}: {}", path.display(), e);
+        }
+    }
+}
+
+// Names the copy of the file at `path` after its inode, length and modification time, so that a
+// modified file is copied again. The snapshot cache names its copies the same way.
+pub(crate) fn staged_name(path: &Path, metadata: &Metadata) -> String {
+    let mut hasher = DefaultHasher::new();
+    metadata.dev().hash(&mut hasher);
+    metadata.ino().hash(&mut hasher);
+    metadata.len().hash(&mut hasher);
+    metadata.mtime().hash(&mut hasher);
+    metadata.mtime_nsec().hash(&mut hasher);
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    format!("{:016x}-{}", hasher.finish(), file_name)
+}
+
+// Opens the staged file at `path`, if it is there whole.
+fn open_staged(path: &Path, len: u64) -> Option<File> {
+    let file = File::open(path).ok()?;
+    match file.metadata() {
+        Ok(metadata) if metadata.len() == len => Some(file),
+        _ => None,
+    }
+}
+
+// Returns the total length of the files in `dir`, including the copies in progress.
+fn staged_bytes(dir: &Path) -> io::Result<u64> {
+    let mut staged = 0;
+    for entry in fs::read_dir(dir)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            staged += metadata.len();
+        }
+    }
+    Ok(staged)
+}
+
+// Copies the `len` bytes of the file at `src` to a new file at `dst`, within the kernel.
+pub(crate) fn copy(src: &Path, dst: &Path, len: u64) -> io::Result<()> {
+    let src = File::open(src)?;
+    let dst = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dst)?;
+    let mut offset: libc::off_t = 0;
+    while (offset as u64) < len {
+        let count = min(len - offset as u64, MAX_SENDFILE) as usize;
+        // Safe because `sendfile` only writes to `offset`, which outlives the call.
+        let ret = unsafe { libc::sendfile(dst.as_raw_fd(), src.as_raw_fd(), &mut offset, count) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        } else if ret == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    use utils::tempdir::TempDir;
+    use utils::tempfile::TempFile;
+
+    fn config(dir: &TempDir, quota_mib: u64) -> WsStagingConfig {
+        WsStagingConfig {
+            dir: dir.as_path().to_path_buf(),
+            quota_mib,
+        }
+    }
+
+    #[test]
+    fn test_stage() {
+        let dir = TempDir::new().unwrap();
+        let ws_file = TempFile::new().unwrap();
+        ws_file.as_file().set_len(0x3000).unwrap();
+        std::os::unix::fs::FileExt::write_all_at(ws_file.as_file(), b"ws", 0x2000).unwrap();
+
+        let (path, mut file) = stage(&config(&dir, 1), ws_file.as_path()).unwrap();
+        assert_eq!(path.parent().unwrap(), dir.as_path());
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+        assert_eq!(content.len(), 0x3000);
+        assert_eq!(&content[0x2000..0x2002], b"ws");
+        // The next restores share the staged file.
+        let (again, _) = stage(&config(&dir, 1), ws_file.as_path()).unwrap();
+        assert_eq!(again, path);
+        assert_eq!(staged_bytes(dir.as_path()).unwrap(), 0x3000);
+
+        // A modified WS file is staged again, within the quota.
+        ws_file.as_file().set_len(1 << 20).unwrap();
+        match stage(&config(&dir, 1), ws_file.as_path()) {
+            Err(Error::Quota(len, staged, quota)) => {
+                assert_eq!((len, staged, quota), (1 << 20, 0x3000, 1 << 20))
+            }
+            res => panic!("Unexpected result: {:?}", res.map(|(path, _)| path)),
+        }
+        let (modified, _) = stage(&config(&dir, 2), ws_file.as_path()).unwrap();
+        assert_ne!(modified, path);
+
+        cleanup();
+        assert!(!path.exists());
+        assert!(!modified.exists());
+    }
+
+    #[test]
+    fn test_stage_errors() {
+        let dir = TempDir::new().unwrap();
+        match stage(&config(&dir, 1), Path::new("/no/such/ws_file")) {
+            Err(Error::Metadata(path, _)) => assert_eq!(path, PathBuf::from("/no/such/ws_file")),
+            res => panic!("Unexpected result: {:?}", res.map(|(path, _)| path)),
+        }
+        let ws_file = TempFile::new().unwrap();
+        let mut bad = config(&dir, 1);
+        bad.dir = PathBuf::from("/no/such/dir");
+        match stage(&bad, ws_file.as_path()) {
+            Err(Error::Metadata(path, _)) => assert_eq!(path, PathBuf::from("/no/such/dir")),
+            res => panic!("Unexpected result: {:?}", res.map(|(path, _)| path)),
+        }
+        let err = Error::Quota(1, 2, 3);
+        let _ = format!("{}{:?}", err, err);
+    }
+}
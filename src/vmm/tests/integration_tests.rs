@@ -7,7 +7,7 @@ mod test_utils;
 
 use std::io;
 #[cfg(target_arch = "x86_64")]
-use std::io::{Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
@@ -20,13 +20,15 @@ use utils::tempfile::TempFile;
 #[cfg(target_arch = "x86_64")]
 use vmm::builder::build_microvm_from_snapshot;
 use vmm::builder::{build_microvm_for_boot, setup_serial_device};
-use vmm::default_syscalls::get_seccomp_filter;
+use vmm::default_syscalls::{get_seccomp_filters, SeccompProfile, ThreadFilters};
 #[cfg(target_arch = "x86_64")]
 use vmm::persist;
 #[cfg(target_arch = "x86_64")]
 use vmm::persist::MicrovmState;
 use vmm::resources::VmResources;
 #[cfg(target_arch = "x86_64")]
+use vmm::snapshot_signing::SnapshotKeys;
+#[cfg(target_arch = "x86_64")]
 use vmm::version_map::VERSION_MAP;
 use vmm::vmm_config::boot_source::BootSourceConfig;
 #[cfg(target_arch = "x86_64")]
@@ -42,7 +44,8 @@ use crate::test_utils::{restore_stdin, set_panic_hook};
 
 fn default_vmm(_kernel_image: Option<&str>) -> (Arc<Mutex<Vmm>>, EventManager) {
     let mut event_manager = EventManager::new().unwrap();
-    let empty_seccomp_filter = get_seccomp_filter(SeccompLevel::None).unwrap();
+    let empty_seccomp_filters =
+        get_seccomp_filters(SeccompLevel::None, SeccompProfile::Default).unwrap();
 
     let boot_source_cfg = MockBootSourceConfig::new().with_default_boot_args();
     #[cfg(target_arch = "aarch64")]
@@ -57,7 +60,7 @@ fn default_vmm(_kernel_image: Option<&str>) -> (Arc<Mutex<Vmm>>, EventManager) {
         .into();
 
     (
-        build_microvm_for_boot(&resources, &mut event_manager, &empty_seccomp_filter).unwrap(),
+        build_microvm_for_boot(&resources, &mut event_manager, &empty_seccomp_filters).unwrap(),
         event_manager,
     )
 }
@@ -93,9 +96,11 @@ fn test_build_microvm() {
     {
         let resources: VmResources = MockVmResources::new().into();
         let mut event_manager = EventManager::new().unwrap();
-        let empty_seccomp_filter = get_seccomp_filter(SeccompLevel::None).unwrap();
+        let empty_seccomp_filters =
+            get_seccomp_filters(SeccompLevel::None, SeccompProfile::Default).unwrap();
 
-        let vmm_ret = build_microvm_for_boot(&resources, &mut event_manager, &empty_seccomp_filter);
+        let vmm_ret =
+            build_microvm_for_boot(&resources, &mut event_manager, &empty_seccomp_filters);
         assert_eq!(format!("{:?}", vmm_ret.err()), "Some(MissingKernelConfig)");
     }
 
@@ -144,7 +149,14 @@ fn test_vmm_seccomp() {
 
             // The customer "forgot" to whitelist the KVM_RUN ioctl.
             let filter: BpfProgram = MockSeccomp::new().without_kvm_run().into();
-            let vmm = build_microvm_for_boot(&resources, &mut event_manager, &filter).unwrap();
+            let filters = ThreadFilters {
+                vmm: filter.clone(),
+                vcpu: filter.clone(),
+                worker: filter.clone(),
+                dump_writer: filter.clone(),
+                uffd_handler: filter,
+            };
+            let vmm = build_microvm_for_boot(&resources, &mut event_manager, &filters).unwrap();
             // Give the vCPUs a chance to attempt KVM_RUN.
             thread::sleep(Duration::from_millis(200));
             // Should never get here.
@@ -294,12 +306,21 @@ fn verify_create_snapshot(is_diff: bool) -> (TempFile, TempFile) {
                 snapshot_path: snapshot_file.as_path().to_path_buf(),
                 mem_file_path: memory_file.as_path().to_path_buf(),
                 version: Some(String::from("0.23.0")),
+                guest_agent: None,
+                background: false,
+                preallocate: false,
             };
 
             {
                 let mut locked_vmm = vmm.lock().unwrap();
-                persist::create_snapshot(&mut locked_vmm, &snapshot_params, VERSION_MAP.clone())
-                    .unwrap();
+                persist::create_snapshot(
+                    &mut locked_vmm,
+                    &snapshot_params,
+                    VERSION_MAP.clone(),
+                    &SnapshotKeys::default(),
+                    &[],
+                )
+                .unwrap();
             }
 
             vmm.lock().unwrap().stop(0);
@@ -332,22 +353,28 @@ fn verify_create_snapshot(is_diff: bool) -> (TempFile, TempFile) {
 
 #[cfg(target_arch = "x86_64")]
 fn verify_load_snapshot(snapshot_file: TempFile, memory_file: TempFile) {
-    use vm_memory::GuestMemoryMmap;
-    use vmm::memory_snapshot::SnapshotMemory;
+    use vmm::memory_layers::{restore_from_buffers, BufferLayers};
 
     let pid = unsafe { libc::fork() };
     match pid {
         0 => {
             set_panic_hook();
             let mut event_manager = EventManager::new().unwrap();
-            let empty_seccomp_filter = get_seccomp_filter(SeccompLevel::None).unwrap();
+            let empty_seccomp_filters =
+                get_seccomp_filters(SeccompLevel::None, SeccompProfile::Default).unwrap();
 
             // Deserialize microVM state.
             snapshot_file.as_file().seek(SeekFrom::Start(0)).unwrap();
             let microvm_state: MicrovmState =
                 Snapshot::load(&mut snapshot_file.as_file(), VERSION_MAP.clone()).unwrap();
-            let mem = GuestMemoryMmap::restore(memory_file.as_file(), &microvm_state.memory_state)
-                .unwrap();
+            let mut memory = Vec::new();
+            memory_file.as_file().seek(SeekFrom::Start(0)).unwrap();
+            memory_file.as_file().read_to_end(&mut memory).unwrap();
+            let layers = BufferLayers {
+                mem: Some(memory.as_slice()),
+                ..Default::default()
+            };
+            let mem = restore_from_buffers(&microvm_state.memory_state, &layers).unwrap();
 
             // Build microVM from state.
             let vmm = build_microvm_from_snapshot(
@@ -355,7 +382,7 @@ fn verify_load_snapshot(snapshot_file: TempFile, memory_file: TempFile) {
                 microvm_state,
                 mem,
                 false,
-                &empty_seccomp_filter,
+                &empty_seccomp_filters,
             )
             .unwrap();
             // For now we're happy we got this far, we don't test what the guest is actually doing.
@@ -0,0 +1,85 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+
+use vmm::ws_layout::Extent;
+
+#[derive(Debug)]
+pub enum Error {
+    MemFileRead(u64, io::Error),
+    WriteWsFile(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            MemFileRead(offset, err) => write!(
+                f,
+                "Failed to read the memory file at offset {:#x}: {}",
+                offset, err
+            ),
+            WriteWsFile(err) => write!(f, "Failed to write the working set file: {}", err),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Copies the `extents` pages from `mem_file` to `ws_file`, back to back. Returns the number of
+/// bytes written.
+pub fn write_ws_file<W: Write>(
+    mem_file: &File,
+    ws_file: &mut W,
+    extents: &[Extent],
+    page_size: u64,
+) -> Result<u64> {
+    let mut buf = Vec::new();
+    let mut written = 0;
+    for extent in extents {
+        let offset = extent.page * page_size;
+        buf.resize((extent.pages * page_size) as usize, 0);
+        mem_file
+            .read_exact_at(&mut buf, offset)
+            .map_err(|e| Error::MemFileRead(offset, e))?;
+        ws_file.write_all(&buf).map_err(Error::WriteWsFile)?;
+        written += buf.len() as u64;
+    }
+    ws_file.flush().map_err(Error::WriteWsFile)?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempfile::TempFile;
+
+    const PAGE_SIZE: u64 = 0x1000;
+
+    #[test]
+    fn test_write_ws_file() {
+        let mem_file = TempFile::new().unwrap();
+        let mem: Vec<u8> = (0..4 * PAGE_SIZE).map(|i| (i / PAGE_SIZE) as u8).collect();
+        mem_file.as_file().write_all(&mem).unwrap();
+
+        let extents = [Extent { page: 2, pages: 2 }, Extent { page: 0, pages: 1 }];
+        let mut ws = Vec::new();
+        let written = write_ws_file(mem_file.as_file(), &mut ws, &extents, PAGE_SIZE).unwrap();
+        assert_eq!(written, 3 * PAGE_SIZE);
+        assert_eq!(ws[0], 2);
+        assert_eq!(ws[PAGE_SIZE as usize], 3);
+        assert_eq!(ws[2 * PAGE_SIZE as usize], 0);
+
+        let extents = [Extent { page: 4, pages: 1 }];
+        match write_ws_file(mem_file.as_file(), &mut ws, &extents, PAGE_SIZE) {
+            Err(Error::MemFileRead(offset, _)) => assert_eq!(offset, 4 * PAGE_SIZE),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}
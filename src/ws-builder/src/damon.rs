@@ -0,0 +1,377 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Working set tracking with DAMON, the data access monitor of the kernel.
+//!
+//! A kdamond samples the accesses to the memory file mappings of a Firecracker process, and a
+//! `stat` scheme reports the regions accessed during each aggregation interval. The pages of the
+//! reported regions are recorded in the order they are first reported, which approximates the
+//! order of the first accesses at the granularity of the aggregation interval.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Root of the DAMON sysfs interface.
+pub const DAMON_ADMIN: &str = "/sys/kernel/mm/damon/admin";
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidReport(PathBuf),
+    MemFile(io::Error),
+    NoMapping(u32),
+    ReadMaps(u32, io::Error),
+    Sysfs(PathBuf, io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidReport(path) => write!(f, "Invalid DAMON report in {}", path.display()),
+            MemFile(err) => write!(f, "Failed to stat the memory file: {}", err),
+            NoMapping(pid) => write!(f, "Process {} does not map the memory file", pid),
+            ReadMaps(pid, err) => write!(f, "Failed to read the mappings of {}: {}", pid, err),
+            Sysfs(path, err) => write!(f, "Failed to write {}: {}", path.display(), err),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Monitoring attributes of the kdamond.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Attrs {
+    /// Microseconds between two access checks of a region.
+    pub sample_us: u64,
+    /// Microseconds between two reports of the accessed regions.
+    pub aggr_us: u64,
+    /// Largest number of regions the monitored memory is split into.
+    pub max_regions: u64,
+}
+
+/// Mapping of the memory file in the address space of the monitored process.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mapping {
+    pub start: u64,
+    pub end: u64,
+    pub file_offset: u64,
+}
+
+// Returns the major and minor numbers of `dev`, encoded the way glibc does.
+fn dev_numbers(dev: u64) -> (u64, u64) {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    (major, minor)
+}
+
+fn parse_hex(value: &str) -> Option<u64> {
+    u64::from_str_radix(value, 16).ok()
+}
+
+/// Returns the mappings listed in `maps`, the contents of `/proc/<pid>/maps`, of the file on the
+/// `dev` device with the `ino` inode.
+pub fn parse_maps(maps: &str, dev: u64, ino: u64) -> Vec<Mapping> {
+    let device = dev_numbers(dev);
+    maps.lines()
+        .filter_map(|line| {
+            // <start>-<end> <perms> <offset> <major>:<minor> <inode> <path>
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 5 || fields[4].parse::<u64>().ok()? != ino {
+                return None;
+            }
+            let mut numbers = fields[3].splitn(2, ':');
+            if (parse_hex(numbers.next()?)?, parse_hex(numbers.next()?)?) != device {
+                return None;
+            }
+            let mut range = fields[0].splitn(2, '-');
+            Some(Mapping {
+                start: parse_hex(range.next()?)?,
+                end: parse_hex(range.next()?)?,
+                file_offset: parse_hex(fields[2])?,
+            })
+        })
+        .collect()
+}
+
+/// Returns the mappings of `mem_file` in the address space of the `pid` process. The file is
+/// matched by its device and inode, so that the path it is mapped from inside a jail does not
+/// matter.
+pub fn mem_file_mappings(pid: u32, mem_file: &File) -> Result<Vec<Mapping>> {
+    let metadata = mem_file.metadata().map_err(Error::MemFile)?;
+    let maps =
+        fs::read_to_string(format!("/proc/{}/maps", pid)).map_err(|e| Error::ReadMaps(pid, e))?;
+    let mappings = parse_maps(&maps, metadata.dev(), metadata.ino());
+    if mappings.is_empty() {
+        return Err(Error::NoMapping(pid));
+    }
+    Ok(mappings)
+}
+
+fn write<T: fmt::Display>(path: &Path, value: T) -> Result<()> {
+    fs::write(path, value.to_string()).map_err(|e| Error::Sysfs(path.to_path_buf(), e))
+}
+
+fn read_u64(path: &Path) -> Result<u64> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .ok_or_else(|| Error::InvalidReport(path.to_path_buf()))
+}
+
+/// A kdamond monitoring the memory file mappings of a process, stopped when dropped.
+pub struct Kdamond {
+    dir: PathBuf,
+}
+
+impl Kdamond {
+    /// Starts monitoring the `mappings` of the `pid` process through the DAMON sysfs interface
+    /// at `admin`. Takes over the interface, on which no other kdamond may be running.
+    pub fn start(admin: &Path, pid: u32, mappings: &[Mapping], attrs: &Attrs) -> Result<Self> {
+        let kdamonds = admin.join("kdamonds");
+        write(&kdamonds.join("nr_kdamonds"), 1)?;
+        let dir = kdamonds.join("0");
+        write(&dir.join("contexts/nr_contexts"), 1)?;
+
+        let context = dir.join("contexts/0");
+        write(&context.join("operations"), "vaddr")?;
+        let monitoring_attrs = context.join("monitoring_attrs");
+        write(
+            &monitoring_attrs.join("intervals/sample_us"),
+            attrs.sample_us,
+        )?;
+        write(&monitoring_attrs.join("intervals/aggr_us"), attrs.aggr_us)?;
+        write(&monitoring_attrs.join("nr_regions/max"), attrs.max_regions)?;
+
+        write(&context.join("targets/nr_targets"), 1)?;
+        let target = context.join("targets/0");
+        write(&target.join("pid_target"), pid)?;
+        // Only the memory file mappings are monitored, not the whole address space.
+        write(&target.join("regions/nr_regions"), mappings.len())?;
+        for (index, mapping) in mappings.iter().enumerate() {
+            let region = target.join("regions").join(index.to_string());
+            write(&region.join("start"), mapping.start)?;
+            write(&region.join("end"), mapping.end)?;
+        }
+
+        // The scheme does nothing but report the regions accessed during the last aggregation.
+        write(&context.join("schemes/nr_schemes"), 1)?;
+        let scheme = context.join("schemes/0");
+        write(&scheme.join("action"), "stat")?;
+        write(&scheme.join("access_pattern/nr_accesses/min"), 1)?;
+
+        write(&dir.join("state"), "on")?;
+        Ok(Kdamond { dir })
+    }
+
+    /// Returns the address ranges, as (start, end), accessed during the last aggregation
+    /// interval.
+    pub fn accessed_regions(&self) -> Result<Vec<(u64, u64)>> {
+        write(&self.dir.join("state"), "update_schemes_tried_regions")?;
+        let tried_regions = self.dir.join("contexts/0/schemes/0/tried_regions");
+        let mut regions = Vec::new();
+        loop {
+            let region = tried_regions.join(regions.len().to_string());
+            if !region.is_dir() {
+                return Ok(regions);
+            }
+            regions.push((
+                read_u64(&region.join("start"))?,
+                read_u64(&region.join("end"))?,
+            ));
+        }
+    }
+}
+
+impl Drop for Kdamond {
+    fn drop(&mut self) {
+        if let Err(err) = write(&self.dir.join("state"), "off") {
+            eprintln!("Failed to stop the kdamond: {}", err);
+        }
+    }
+}
+
+/// Memory file pages reported accessed, in the order they were first reported.
+pub struct WsTracker {
+    mappings: Vec<Mapping>,
+    page_size: u64,
+    seen: HashSet<u64>,
+    pages: Vec<u64>,
+}
+
+impl WsTracker {
+    /// Tracks the pages of the memory file accessed through `mappings`.
+    pub fn new(mappings: Vec<Mapping>, page_size: u64) -> Self {
+        WsTracker {
+            mappings,
+            page_size,
+            seen: HashSet::new(),
+            pages: Vec::new(),
+        }
+    }
+
+    /// Records the accessed `regions` of a report. The pages first reported together are ordered
+    /// by their memory file offset.
+    pub fn record(&mut self, regions: &[(u64, u64)]) {
+        let mut reported = Vec::new();
+        for (start, end) in regions {
+            for mapping in self.mappings.iter() {
+                let start = std::cmp::max(*start, mapping.start);
+                let end = std::cmp::min(*end, mapping.end);
+                if start >= end {
+                    continue;
+                }
+                let first_page = (start - mapping.start + mapping.file_offset) / self.page_size;
+                let end_page = (end - mapping.start + mapping.file_offset + self.page_size - 1)
+                    / self.page_size;
+                reported.extend(first_page..end_page);
+            }
+        }
+        reported.sort_unstable();
+        for page in reported {
+            if self.seen.insert(page) {
+                self.pages.push(page);
+            }
+        }
+    }
+
+    /// Returns the pages reported accessed, each once, in the order they were first reported.
+    pub fn pages(&self) -> &[u64] {
+        &self.pages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use utils::tempdir::TempDir;
+
+    const PAGE_SIZE: u64 = 0x1000;
+
+    #[test]
+    fn test_parse_maps() {
+        // Device 0xfd:0x01.
+        let dev = (0xfd << 8) | 0x01;
+        let maps = "\
+            55d4c000-55d4d000 r-xp 00000000 fd:01 42 /usr/bin/firecracker\n\
+            7f0000000000-7f0008000000 rw-p 00000000 fd:01 1234 /mem_file\n\
+            7f0009000000-7f0009001000 rw-p 00000000 00:00 0 \n\
+            7f0010000000-7f0018000000 rw-p 08000000 fd:01 1234 /mem_file\n\
+            7f0020000000-7f0020001000 rw-p 00000000 fd:02 1234 /other_file\n";
+        assert_eq!(
+            parse_maps(maps, dev, 1234),
+            vec![
+                Mapping {
+                    start: 0x7f00_0000_0000,
+                    end: 0x7f00_0800_0000,
+                    file_offset: 0,
+                },
+                Mapping {
+                    start: 0x7f00_1000_0000,
+                    end: 0x7f00_1800_0000,
+                    file_offset: 0x800_0000,
+                },
+            ]
+        );
+        assert!(parse_maps(maps, dev, 5678).is_empty());
+    }
+
+    #[test]
+    fn test_kdamond() {
+        let admin = TempDir::new().unwrap();
+        let kdamond_dir = admin.as_path().join("kdamonds/0");
+        // The directories created by the kernel as the interface is written.
+        for dir in [
+            "contexts/0/monitoring_attrs/intervals",
+            "contexts/0/monitoring_attrs/nr_regions",
+            "contexts/0/targets/0/regions/0",
+            "contexts/0/targets/0/regions/1",
+            "contexts/0/schemes/0/access_pattern/nr_accesses",
+            "contexts/0/schemes/0/tried_regions/0",
+        ]
+        .iter()
+        {
+            fs::create_dir_all(kdamond_dir.join(dir)).unwrap();
+        }
+        let tried_region = kdamond_dir.join("contexts/0/schemes/0/tried_regions/0");
+        fs::write(tried_region.join("start"), "4096\n").unwrap();
+        fs::write(tried_region.join("end"), "12288\n").unwrap();
+
+        let mappings = [
+            Mapping {
+                start: 0x1000,
+                end: 0x5000,
+                file_offset: 0,
+            },
+            Mapping {
+                start: 0x9000,
+                end: 0xa000,
+                file_offset: 0x4000,
+            },
+        ];
+        let attrs = Attrs {
+            sample_us: 5000,
+            aggr_us: 100_000,
+            max_regions: 1000,
+        };
+        let kdamond = Kdamond::start(admin.as_path(), 42, &mappings, &attrs).unwrap();
+        let read = |path: &str| fs::read_to_string(kdamond_dir.join(path)).unwrap();
+        assert_eq!(read("state"), "on");
+        assert_eq!(read("contexts/0/operations"), "vaddr");
+        assert_eq!(read("contexts/0/targets/0/pid_target"), "42");
+        assert_eq!(read("contexts/0/targets/0/regions/nr_regions"), "2");
+        assert_eq!(read("contexts/0/targets/0/regions/1/start"), "36864");
+        assert_eq!(read("contexts/0/schemes/0/action"), "stat");
+
+        assert_eq!(kdamond.accessed_regions().unwrap(), vec![(0x1000, 0x3000)]);
+        assert_eq!(read("state"), "update_schemes_tried_regions");
+
+        fs::write(tried_region.join("end"), "garbage").unwrap();
+        match kdamond.accessed_regions() {
+            Err(Error::InvalidReport(path)) => assert_eq!(path, tried_region.join("end")),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        drop(kdamond);
+        assert_eq!(read("state"), "off");
+
+        match Kdamond::start(&admin.as_path().join("missing"), 42, &mappings, &attrs) {
+            Err(Error::Sysfs(_, _)) => (),
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_ws_tracker() {
+        let mappings = vec![
+            Mapping {
+                start: 0x10000,
+                end: 0x14000,
+                file_offset: 0,
+            },
+            Mapping {
+                start: 0x20000,
+                end: 0x22000,
+                file_offset: 0x4000,
+            },
+        ];
+        let mut tracker = WsTracker::new(mappings, PAGE_SIZE);
+
+        // A region spanning both mappings, and part of the address space in between.
+        tracker.record(&[(0x13000, 0x21000)]);
+        assert_eq!(tracker.pages(), &[3, 4]);
+
+        // Already reported pages keep their place, unaligned regions cover their pages.
+        tracker.record(&[(0x21800, 0x21900), (0x10000, 0x11000), (0x13000, 0x14000)]);
+        assert_eq!(tracker.pages(), &[3, 4, 0, 5]);
+
+        // Regions outside of the mappings are ignored.
+        tracker.record(&[(0x0, 0x10000), (0x30000, 0x31000)]);
+        assert_eq!(tracker.pages(), &[3, 4, 0, 5]);
+    }
+}
@@ -0,0 +1,285 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Builds a working set file and its `ws_regions` from a fault trace recorded during a restore
+//! of the same snapshot, or from the accesses DAMON reports on a running restore.
+
+mod builder;
+mod damon;
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use utils::arg_parser::{ArgParser, Argument, Arguments, Error as ParsingError};
+use vmm::ws_layout::{self, cluster_pages, read_trace, ws_regions, Extent};
+
+use crate::builder::write_ws_file;
+use crate::damon::{Attrs, Kdamond, WsTracker, DAMON_ADMIN};
+
+const WS_BUILDER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug)]
+enum Error {
+    ArgumentParsing(ParsingError),
+    Build(builder::Error),
+    Damon(damon::Error),
+    InvalidArguments(&'static str),
+    InvalidValue(&'static str, String),
+    Layout(ws_layout::Error),
+    Open(PathBuf, io::Error),
+    WriteRegions(PathBuf, io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            ArgumentParsing(err) => write!(f, "Failed to parse arguments: {}", err),
+            Build(err) => write!(f, "{}", err),
+            Damon(err) => write!(f, "{}", err),
+            InvalidArguments(err) => write!(f, "{}", err),
+            InvalidValue(arg, value) => write!(f, "Invalid value for --{}: {}", arg, value),
+            Layout(err) => write!(f, "{}", err),
+            Open(path, err) => write!(f, "Failed to open {}: {}", path.display(), err),
+            WriteRegions(path, err) => write!(
+                f,
+                "Failed to write the working set regions to {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new()
+        .arg(
+            Argument::new("trace")
+                .takes_value(true)
+                .help("Path to the fault trace recorded during a restore of the snapshot."),
+        )
+        .arg(Argument::new("damon-pid").takes_value(true).help(
+            "PID of a Firecracker process restoring the snapshot from the memory file, whose \
+             accesses are tracked with DAMON instead of reading a fault trace.",
+        ))
+        .arg(
+            Argument::new("damon-sample-us")
+                .takes_value(true)
+                .default_value("5000")
+                .help("Microseconds between two DAMON access checks of a memory region."),
+        )
+        .arg(
+            Argument::new("damon-aggr-ms")
+                .takes_value(true)
+                .default_value("100")
+                .help("Milliseconds between two DAMON reports of the accessed memory regions."),
+        )
+        .arg(
+            Argument::new("damon-max-regions")
+                .takes_value(true)
+                .default_value("10000")
+                .help("Largest number of regions DAMON splits the guest memory into."),
+        )
+        .arg(
+            Argument::new("mem-file")
+                .required(true)
+                .takes_value(true)
+                .help("Path to the guest memory file of the snapshot."),
+        )
+        .arg(
+            Argument::new("ws-file")
+                .required(true)
+                .takes_value(true)
+                .help("Path of the working set file to write."),
+        )
+        .arg(Argument::new("ws-regions").takes_value(true).help(
+            "Path of the JSON file to write the ws_regions of the snapshot load request to. \
+             Printed to stdout if not set.",
+        ))
+        .arg(
+            Argument::new("max-gap")
+                .takes_value(true)
+                .default_value("0")
+                .help("Largest run of pages not faulted in to include between faulted pages."),
+        )
+        .arg(Argument::new("until-ms").takes_value(true).help(
+            "Ignore the faults recorded later than this many ms after the restore. With \
+             --damon-pid, the accesses are tracked for this many ms.",
+        ))
+}
+
+fn parse_u64(arguments: &Arguments, arg: &'static str) -> Result<Option<u64>> {
+    match arguments.value_as_string(arg) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::InvalidValue(arg, value)),
+        None => Ok(None),
+    }
+}
+
+fn open(path: &Path) -> Result<File> {
+    File::open(path).map_err(|e| Error::Open(path.to_path_buf(), e))
+}
+
+fn create(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map_err(|e| Error::Open(path.to_path_buf(), e))
+}
+
+// Replaces the JSON file at `path` with `regions`, so that it is never read half written.
+fn write_regions(path: &Path, regions: &str) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    create(&tmp_path)?
+        .write_all(regions.as_bytes())
+        .and_then(|_| std::fs::rename(&tmp_path, path))
+        .map_err(|e| Error::WriteRegions(path.to_path_buf(), e))
+}
+
+fn regions_json(extents: &[Extent]) -> String {
+    serde_json::Value::from(ws_regions(extents)).to_string()
+}
+
+// Tracks the memory file pages the `pid` process accesses, for `until_us` microseconds. The
+// `ws_regions` at `regions_path` are rewritten after each report.
+fn track_damon(
+    arguments: &Arguments,
+    pid: u32,
+    mem_file: &File,
+    until_us: u64,
+    regions_path: Option<&Path>,
+) -> Result<(Vec<u64>, usize)> {
+    let attrs = Attrs {
+        sample_us: parse_u64(arguments, "damon-sample-us")?.unwrap_or(5000),
+        aggr_us: parse_u64(arguments, "damon-aggr-ms")?.unwrap_or(100) * 1000,
+        max_regions: parse_u64(arguments, "damon-max-regions")?.unwrap_or(10000),
+    };
+    let max_gap_pages = parse_u64(arguments, "max-gap")?.unwrap_or(0);
+    let mappings = damon::mem_file_mappings(pid, mem_file).map_err(Error::Damon)?;
+    let mut tracker = WsTracker::new(mappings.clone(), sysconf::page::pagesize() as u64);
+    let kdamond =
+        Kdamond::start(Path::new(DAMON_ADMIN), pid, &mappings, &attrs).map_err(Error::Damon)?;
+
+    let start = Instant::now();
+    let mut reports = 0;
+    while start.elapsed() < Duration::from_micros(until_us) {
+        thread::sleep(Duration::from_micros(attrs.aggr_us));
+        tracker.record(&kdamond.accessed_regions().map_err(Error::Damon)?);
+        reports += 1;
+        if let Some(regions_path) = regions_path {
+            let extents = cluster_pages(tracker.pages(), max_gap_pages);
+            write_regions(regions_path, &regions_json(&extents))?;
+        }
+    }
+    Ok((tracker.pages().to_vec(), reports))
+}
+
+fn run(arguments: &Arguments) -> Result<()> {
+    // The required arguments are checked by the parser.
+    let path = |arg| PathBuf::from(arguments.value_as_string(arg).unwrap_or_default());
+    let max_gap_pages = parse_u64(arguments, "max-gap")?.unwrap_or(0);
+    let until_us = parse_u64(arguments, "until-ms")?.map(|ms| ms * 1000);
+    let regions_path = arguments.value_as_string("ws-regions").map(PathBuf::from);
+    let mem_file = open(&path("mem-file"))?;
+
+    let (pages, page_size, source) = match (
+        arguments.value_as_string("trace"),
+        parse_u64(arguments, "damon-pid")?,
+    ) {
+        (Some(trace_path), None) => {
+            let trace = read_trace(&mut BufReader::new(open(Path::new(&trace_path))?))
+                .map_err(Error::Layout)?;
+            let pages = trace.first_accesses(until_us).map_err(Error::Layout)?;
+            let source = format!("{} faults", trace.records.len());
+            (pages, trace.page_size, source)
+        }
+        (None, Some(pid)) => {
+            let until_us = until_us.ok_or(Error::InvalidArguments(
+                "--damon-pid requires --until-ms, the duration of the tracking",
+            ))?;
+            let (pages, reports) = track_damon(
+                arguments,
+                pid as u32,
+                &mem_file,
+                until_us,
+                regions_path.as_deref(),
+            )?;
+            let source = format!("{} DAMON reports", reports);
+            (pages, sysconf::page::pagesize() as u64, source)
+        }
+        _ => {
+            return Err(Error::InvalidArguments(
+                "Exactly one of --trace and --damon-pid must be set",
+            ))
+        }
+    };
+
+    let extents = cluster_pages(&pages, max_gap_pages);
+    let mut ws_file = BufWriter::new(create(&path("ws-file"))?);
+    let written =
+        write_ws_file(&mem_file, &mut ws_file, &extents, page_size).map_err(Error::Build)?;
+
+    let regions = regions_json(&extents);
+    match regions_path {
+        Some(regions_path) => write_regions(&regions_path, &regions)?,
+        None => println!("{}", regions),
+    }
+
+    eprintln!(
+        "{}, {} extents, {} pages written, {} bytes",
+        source,
+        extents.len(),
+        written / page_size,
+        written
+    );
+    Ok(())
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+
+    if let Err(err) = arg_parser.parse_from_cmdline() {
+        eprintln!(
+            "{} \n\n\
+             For more information try --help.",
+            Error::ArgumentParsing(err)
+        );
+        process::exit(1);
+    }
+    if arg_parser
+        .arguments()
+        .value_as_bool("help")
+        .unwrap_or(false)
+    {
+        println!("ws-builder v{}\n", WS_BUILDER_VERSION);
+        println!("{}", arg_parser.formatted_help());
+        process::exit(0);
+    }
+    if arg_parser
+        .arguments()
+        .value_as_bool("version")
+        .unwrap_or(false)
+    {
+        println!("ws-builder v{}\n", WS_BUILDER_VERSION);
+        process::exit(0);
+    }
+
+    if let Err(err) = run(arg_parser.arguments()) {
+        eprintln!("ws-builder error: {}", err);
+        process::exit(1);
+    }
+}
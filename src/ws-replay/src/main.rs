@@ -0,0 +1,374 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Replays the faults recorded during restores against candidate working set layouts and a
+//! storage profile, predicting the time the restored guests wait on memory without launching
+//! them.
+
+mod replay;
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use serde_json::json;
+use utils::arg_parser::{ArgParser, Argument, Arguments, Error as ParsingError};
+use vmm::ws_layout::{self, cluster, extents, read_trace, ClusterConfig, Extent, Trace};
+
+use crate::replay::{replay, Prediction, StorageProfile};
+
+const WS_REPLAY_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug)]
+enum Error {
+    ArgumentParsing(ParsingError),
+    Failed(usize),
+    InvalidValue(&'static str, String),
+    Layout(PathBuf, ws_layout::Error),
+    MissingTrace,
+    Open(PathBuf, io::Error),
+    WsRegions(PathBuf, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            ArgumentParsing(err) => write!(f, "Failed to parse arguments: {}", err),
+            Failed(count) => write!(f, "Failed to replay {} traces", count),
+            InvalidValue(arg, value) => write!(f, "Invalid value for --{}: {}", arg, value),
+            Layout(path, err) => write!(f, "{}: {}", path.display(), err),
+            MissingTrace => write!(f, "No fault trace, list them after --"),
+            Open(path, err) => write!(f, "Failed to open {}: {}", path.display(), err),
+            WsRegions(path, err) => write!(f, "Invalid ws_regions {}: {}", path.display(), err),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+// Working set layout evaluated on the traces.
+enum Layout {
+    // No working set, all pages are faulted in from the memory file.
+    Lazy,
+    // The faulted pages clustered with this largest gap.
+    Clustered(u64),
+    // The given ws_regions.
+    Fixed(Vec<Extent>),
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Layout::Lazy => write!(f, "lazy"),
+            Layout::Clustered(max_gap) => write!(f, "max-gap={}", max_gap),
+            Layout::Fixed(_) => write!(f, "ws-regions"),
+        }
+    }
+}
+
+// Sums of the predictions of a layout.
+#[derive(Default)]
+struct Summary {
+    traces: u64,
+    ws_pages: u64,
+    major_faults: u64,
+    total_us: f64,
+}
+
+impl Summary {
+    fn add(&mut self, prediction: &Prediction) {
+        self.traces += 1;
+        self.ws_pages += prediction.ws_pages;
+        self.major_faults += prediction.major_faults;
+        self.total_us += prediction.total_us;
+    }
+
+    fn mean(&self, sum: f64) -> f64 {
+        if self.traces == 0 {
+            return 0.0;
+        }
+        sum / self.traces as f64
+    }
+}
+
+fn build_arg_parser() -> ArgParser<'static> {
+    ArgParser::new()
+        .arg(
+            Argument::new("max-gap")
+                .takes_value(true)
+                .default_value("0")
+                .help(
+                    "Comma separated largest runs of pages not faulted in to include between \
+                     faulted pages, each evaluated as a layout.",
+                ),
+        )
+        .arg(
+            Argument::new("until-ms")
+                .takes_value(true)
+                .help("Cluster only the faults recorded in the first ms after the restore."),
+        )
+        .arg(Argument::new("layout-trace").takes_value(true).help(
+            "Path to the fault trace to cluster. Each replayed trace is clustered itself \
+             if not set.",
+        ))
+        .arg(
+            Argument::new("ws-regions")
+                .takes_value(true)
+                .help("Path to a JSON file holding ws_regions to evaluate as a layout."),
+        )
+        .arg(
+            Argument::new("lazy")
+                .takes_value(false)
+                .help("Evaluate restores without a working set as well."),
+        )
+        .arg(
+            Argument::new("storage")
+                .takes_value(true)
+                .default_value("nvme")
+                .help("Storage profile: cached, nvme, ssd, network or hdd."),
+        )
+        .arg(
+            Argument::new("latency-us")
+                .takes_value(true)
+                .help("Read latency of the storage, in microseconds."),
+        )
+        .arg(
+            Argument::new("bandwidth-mibps")
+                .takes_value(true)
+                .help("Read bandwidth of the storage, in MiB/s."),
+        )
+        .arg(
+            Argument::new("readahead-pages")
+                .takes_value(true)
+                .help("Pages read from the memory file on each fault."),
+        )
+        .arg(
+            Argument::new("minor-fault-us")
+                .takes_value(true)
+                .help("Cost of a fault on a cached page, in microseconds."),
+        )
+        .arg(
+            Argument::new("json")
+                .takes_value(false)
+                .help("Print the predictions as JSON."),
+        )
+}
+
+fn parse<T: std::str::FromStr>(arguments: &Arguments, arg: &'static str) -> Result<Option<T>> {
+    match arguments.value_as_string(arg) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::InvalidValue(arg, value)),
+        None => Ok(None),
+    }
+}
+
+fn storage_profile(arguments: &Arguments) -> Result<StorageProfile> {
+    let name = arguments.value_as_string("storage").unwrap_or_default();
+    let mut profile =
+        StorageProfile::preset(&name).ok_or_else(|| Error::InvalidValue("storage", name))?;
+    if let Some(latency_us) = parse(arguments, "latency-us")? {
+        profile.latency_us = latency_us;
+    }
+    if let Some(bandwidth_mibps) = parse(arguments, "bandwidth-mibps")? {
+        profile.bandwidth_mibps = bandwidth_mibps;
+    }
+    if let Some(readahead_pages) = parse(arguments, "readahead-pages")? {
+        profile.readahead_pages = readahead_pages;
+    }
+    if let Some(minor_fault_us) = parse(arguments, "minor-fault-us")? {
+        profile.minor_fault_us = minor_fault_us;
+    }
+    Ok(profile)
+}
+
+fn open(path: &Path) -> Result<File> {
+    File::open(path).map_err(|e| Error::Open(path.to_path_buf(), e))
+}
+
+fn load_trace(path: &Path) -> Result<Trace> {
+    read_trace(&mut BufReader::new(open(path)?)).map_err(|e| Error::Layout(path.to_path_buf(), e))
+}
+
+fn layouts(arguments: &Arguments) -> Result<Vec<Layout>> {
+    let mut layouts = Vec::new();
+    if arguments.value_as_bool("lazy").unwrap_or(false) {
+        layouts.push(Layout::Lazy);
+    }
+    let max_gaps = arguments.value_as_string("max-gap").unwrap_or_default();
+    for max_gap in max_gaps.split(',').filter(|value| !value.is_empty()) {
+        let max_gap = max_gap
+            .trim()
+            .parse()
+            .map_err(|_| Error::InvalidValue("max-gap", max_gaps.clone()))?;
+        layouts.push(Layout::Clustered(max_gap));
+    }
+    if let Some(path) = arguments.value_as_string("ws-regions") {
+        let path = PathBuf::from(path);
+        let regions: Vec<Vec<i64>> = serde_json::from_reader(BufReader::new(open(&path)?))
+            .map_err(|e| Error::WsRegions(path.clone(), e.to_string()))?;
+        let extents = extents(&regions).map_err(|e| Error::WsRegions(path, e.to_string()))?;
+        layouts.push(Layout::Fixed(extents));
+    }
+    Ok(layouts)
+}
+
+// Predicts the restore of each layout for the trace at `path`. The clustered layouts are built
+// from `layout_trace`, or the trace itself.
+fn replay_trace(
+    path: &Path,
+    layouts: &[Layout],
+    layout_trace: Option<&Trace>,
+    until_us: Option<u64>,
+    profile: &StorageProfile,
+) -> Result<Vec<Prediction>> {
+    let layout_error = |e| Error::Layout(path.to_path_buf(), e);
+    let trace = load_trace(path)?;
+    let accesses = trace.first_accesses(None).map_err(layout_error)?;
+    let layout_trace = layout_trace.unwrap_or(&trace);
+
+    let mut predictions = Vec::new();
+    for layout in layouts {
+        let extents = match layout {
+            Layout::Lazy => Vec::new(),
+            Layout::Clustered(max_gap_pages) => {
+                let config = ClusterConfig {
+                    max_gap_pages: *max_gap_pages,
+                    until_us,
+                };
+                cluster(layout_trace, &config).map_err(layout_error)?
+            }
+            Layout::Fixed(extents) => extents.clone(),
+        };
+        predictions.push(replay(&accesses, &extents, trace.page_size, profile));
+    }
+    Ok(predictions)
+}
+
+fn run(arguments: &Arguments) -> Result<()> {
+    let traces = arguments.extra_args();
+    if traces.is_empty() {
+        return Err(Error::MissingTrace);
+    }
+    let json = arguments.value_as_bool("json").unwrap_or(false);
+    let profile = storage_profile(arguments)?;
+    let layouts = layouts(arguments)?;
+    let until_us = parse::<u64>(arguments, "until-ms")?.map(|ms| ms * 1000);
+    let layout_trace = match arguments.value_as_string("layout-trace") {
+        Some(path) => Some(load_trace(Path::new(&path))?),
+        None => None,
+    };
+
+    // A trace failing to replay is left out of the summaries.
+    let mut failures = 0;
+    let mut summaries: Vec<Summary> = layouts.iter().map(|_| Summary::default()).collect();
+    for trace_path in traces.iter().map(PathBuf::from) {
+        let predictions = match replay_trace(
+            &trace_path,
+            &layouts,
+            layout_trace.as_ref(),
+            until_us,
+            &profile,
+        ) {
+            Ok(predictions) => predictions,
+            Err(err) => {
+                eprintln!("{}", err);
+                failures += 1;
+                continue;
+            }
+        };
+        for ((layout, prediction), summary) in layouts.iter().zip(&predictions).zip(&mut summaries)
+        {
+            summary.add(prediction);
+            if json {
+                println!(
+                    "{}",
+                    json!({
+                        "trace": trace_path,
+                        "layout": layout.to_string(),
+                        "prediction": prediction,
+                    })
+                );
+            } else {
+                println!(
+                    "{} {}: {:.0} us, {} working set pages ({} unused), {} major faults, \
+                     {} minor faults",
+                    trace_path.display(),
+                    layout,
+                    prediction.total_us,
+                    prediction.ws_pages,
+                    prediction.wasted_pages,
+                    prediction.major_faults,
+                    prediction.minor_faults
+                );
+            }
+        }
+    }
+
+    for (layout, summary) in layouts.iter().zip(&summaries) {
+        let mean_total_us = summary.mean(summary.total_us);
+        let mean_ws_pages = summary.mean(summary.ws_pages as f64);
+        let mean_major_faults = summary.mean(summary.major_faults as f64);
+        if json {
+            println!(
+                "{}",
+                json!({
+                    "layout": layout.to_string(),
+                    "traces": summary.traces,
+                    "mean_total_us": mean_total_us,
+                    "mean_ws_pages": mean_ws_pages,
+                    "mean_major_faults": mean_major_faults,
+                })
+            );
+        } else {
+            println!(
+                "{} over {} traces: {:.0} us, {:.0} working set pages, {:.1} major faults",
+                layout, summary.traces, mean_total_us, mean_ws_pages, mean_major_faults
+            );
+        }
+    }
+    if failures > 0 {
+        return Err(Error::Failed(failures));
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+
+    if let Err(err) = arg_parser.parse_from_cmdline() {
+        eprintln!(
+            "{} \n\n\
+             For more information try --help.",
+            Error::ArgumentParsing(err)
+        );
+        process::exit(1);
+    }
+    if arg_parser
+        .arguments()
+        .value_as_bool("help")
+        .unwrap_or(false)
+    {
+        println!("ws-replay v{}\n", WS_REPLAY_VERSION);
+        println!("{}", arg_parser.formatted_help());
+        process::exit(0);
+    }
+    if arg_parser
+        .arguments()
+        .value_as_bool("version")
+        .unwrap_or(false)
+    {
+        println!("ws-replay v{}\n", WS_REPLAY_VERSION);
+        process::exit(0);
+    }
+
+    if let Err(err) = run(arg_parser.arguments()) {
+        eprintln!("ws-replay error: {}", err);
+        process::exit(1);
+    }
+}
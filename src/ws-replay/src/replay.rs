@@ -0,0 +1,167 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashSet;
+
+use serde::Serialize;
+use vmm::ws_layout::Extent;
+
+const MIB: f64 = (1 << 20) as f64;
+
+/// Storage the snapshot files are read from.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct StorageProfile {
+    /// Latency of a read request, in microseconds.
+    pub latency_us: f64,
+    /// Sequential read bandwidth, in MiB/s.
+    pub bandwidth_mibps: f64,
+    /// Number of pages the kernel reads around a page of the memory file faulted in from storage.
+    pub readahead_pages: u64,
+    /// Cost of a fault on a page already in the page cache, in microseconds.
+    pub minor_fault_us: f64,
+}
+
+impl StorageProfile {
+    /// Names of the preset profiles.
+    pub const PRESETS: &'static [&'static str] = &["cached", "nvme", "ssd", "network", "hdd"];
+
+    /// Returns the preset profile `name`.
+    pub fn preset(name: &str) -> Option<Self> {
+        let (latency_us, bandwidth_mibps) = match name {
+            // The snapshot files are all in the page cache.
+            "cached" => (0.0, 8000.0),
+            "nvme" => (80.0, 2000.0),
+            "ssd" => (200.0, 500.0),
+            "network" => (600.0, 250.0),
+            "hdd" => (8000.0, 150.0),
+            _ => return None,
+        };
+        Some(StorageProfile {
+            latency_us,
+            bandwidth_mibps,
+            readahead_pages: 32,
+            minor_fault_us: 1.0,
+        })
+    }
+
+    // Time to read `bytes` in one request.
+    fn read_us(&self, bytes: u64) -> f64 {
+        self.latency_us + bytes as f64 / (self.bandwidth_mibps * MIB) * 1e6
+    }
+}
+
+/// Predicted cost of a restore.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Prediction {
+    /// Number of pages of the working set.
+    pub ws_pages: u64,
+    /// Time to load the working set, in microseconds.
+    pub prefetch_us: f64,
+    /// Number of pages accessed by the guest.
+    pub accesses: u64,
+    /// Number of accesses to pages loaded with the working set.
+    pub ws_hits: u64,
+    /// Number of faults on pages in the page cache.
+    pub minor_faults: u64,
+    /// Number of faults reading from storage.
+    pub major_faults: u64,
+    /// Time spent servicing faults, in microseconds.
+    pub fault_us: f64,
+    /// Number of pages of the working set the guest did not access.
+    pub wasted_pages: u64,
+    /// Time the restored guest waits on memory, in microseconds.
+    pub total_us: f64,
+}
+
+/// Predicts the cost of a restore whose guest accesses the memory file `accesses` pages, in
+/// order, with the `extents` loaded as the working set from `profile` storage.
+///
+/// The working set file is read sequentially in a single request before the guest runs. Each
+/// other page costs a minor fault if a previous read brought it into the page cache, or a read
+/// of the `readahead_pages` pages starting at it otherwise. Faults are serviced one at a time.
+pub fn replay(
+    accesses: &[u64],
+    extents: &[Extent],
+    page_size: u64,
+    profile: &StorageProfile,
+) -> Prediction {
+    let mut prediction = Prediction::default();
+    let mut ws = HashSet::new();
+    for extent in extents {
+        ws.extend(extent.page..extent.page + extent.pages);
+    }
+    prediction.ws_pages = ws.len() as u64;
+    if !ws.is_empty() {
+        prediction.prefetch_us = profile.read_us(prediction.ws_pages * page_size);
+    }
+
+    let readahead_pages = std::cmp::max(profile.readahead_pages, 1);
+    let mut page_cache = HashSet::new();
+    for page in accesses {
+        prediction.accesses += 1;
+        if ws.contains(page) {
+            prediction.ws_hits += 1;
+        } else if page_cache.contains(page) {
+            prediction.minor_faults += 1;
+            prediction.fault_us += profile.minor_fault_us;
+        } else {
+            prediction.major_faults += 1;
+            prediction.fault_us +=
+                profile.read_us(readahead_pages * page_size) + profile.minor_fault_us;
+            page_cache.extend(*page..*page + readahead_pages);
+        }
+    }
+    prediction.wasted_pages = prediction.ws_pages - prediction.ws_hits;
+    prediction.total_us = prediction.prefetch_us + prediction.fault_us;
+    prediction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE_SIZE: u64 = 0x1000;
+
+    fn profile() -> StorageProfile {
+        StorageProfile {
+            latency_us: 100.0,
+            // A page per microsecond.
+            bandwidth_mibps: 1e6 * PAGE_SIZE as f64 / MIB,
+            readahead_pages: 4,
+            minor_fault_us: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_presets() {
+        for name in StorageProfile::PRESETS {
+            assert!(StorageProfile::preset(name).is_some());
+        }
+        assert!(StorageProfile::preset("tape").is_none());
+    }
+
+    #[test]
+    fn test_replay() {
+        let accesses = [10, 11, 3, 20, 12];
+
+        // Without a working set, 10 brings 11 to 13 in, and 3 and 20 are read from storage.
+        let lazy = replay(&accesses, &[], PAGE_SIZE, &profile());
+        assert_eq!(lazy.prefetch_us, 0.0);
+        assert_eq!(lazy.major_faults, 3);
+        assert_eq!(lazy.minor_faults, 2);
+        assert_eq!(lazy.fault_us, 3.0 * (100.0 + 4.0 + 1.0) + 2.0);
+        assert_eq!(lazy.total_us, lazy.fault_us);
+
+        // The working set covers 10 to 12 and a page never accessed.
+        let extents = [Extent { page: 10, pages: 4 }];
+        let ws = replay(&accesses, &extents, PAGE_SIZE, &profile());
+        assert_eq!(ws.ws_pages, 4);
+        assert_eq!(ws.prefetch_us, 100.0 + 4.0);
+        assert_eq!(ws.ws_hits, 3);
+        assert_eq!(ws.wasted_pages, 1);
+        assert_eq!(ws.major_faults, 2);
+        assert_eq!(ws.minor_faults, 0);
+        assert_eq!(ws.total_us, 104.0 + 2.0 * 105.0);
+        assert_eq!(ws.accesses, 5);
+    }
+}
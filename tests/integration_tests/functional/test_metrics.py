@@ -31,21 +31,27 @@ def test_flush_metrics(test_microvm_with_api):
         'utc_timestamp_ms',
         'api_server',
         'block',
+        'fault_sources',
         'get_api_requests',
         'i8042',
+        'idle_reclaim',
         'latencies_us',
         'logger',
+        'memory_residency',
         'mmds',
         'net',
         'patch_api_requests',
         'put_api_requests',
+        'readiness',
         'rtc',
         'seccomp',
+        'snapshot',
         'vcpu',
         'vmm',
         'uart',
         'signals',
-        'vsock'
+        'vsock',
+        'ws_prefetch'
     ]
 
     assert set(metrics.keys()) == set(exp_keys)